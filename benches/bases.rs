@@ -0,0 +1,90 @@
+//! SIMD versus scalar sequence decoding and base coding
+//!
+//! `cargo bench --bench bases` decodes the sequences of 150 bp records and
+//! codes their bases 2 bits each, the per-read work in front of the kmer
+//! complexity, through htslib's `Seq::as_bytes`, the [`bases::scalar`]
+//! loops and the SIMD ones, then times the complexity of the decoded reads.
+//! `--no-default-features` builds the complexity on the scalar loops, for
+//! comparing the whole metric stage.
+
+use filter_bam_pairs::test_utils::{mapped_pair, random_sequence};
+use filter_bam_pairs::{bases, calculate_canonical_kmer_complexity};
+use rust_htslib::bam;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RECORDS: usize = 200_000;
+const ROUNDS: usize = 5;
+
+/// The best of `ROUNDS` runs of `work` over every record
+fn time(records: &[bam::Record], mut work: impl FnMut(&bam::Record) -> usize) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let started = Instant::now();
+            let mut total = 0;
+            for record in records {
+                total += work(record);
+            }
+            black_box(total);
+            started.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, time: Duration, baseline: Duration) {
+    println!(
+        "{:<22} {:>7.2} M records/s   ({:.2}x)",
+        name,
+        RECORDS as f64 / time.as_secs_f64() / 1e6,
+        baseline.as_secs_f64() / time.as_secs_f64()
+    );
+}
+
+fn main() {
+    let records: Vec<bam::Record> = (0..RECORDS)
+        .map(|i| {
+            let seq = random_sequence(150, i as u64);
+            mapped_pair(&format!("read{i:09}"), &seq, &seq).0.build()
+        })
+        .collect();
+
+    let mut decoded = Vec::new();
+    let htslib = time(&records, |record| record.seq().as_bytes().len());
+    let scalar = time(&records, |record| {
+        let seq = record.seq();
+        bases::scalar::decode_into(seq.encoded, seq.len(), &mut decoded);
+        decoded.len()
+    });
+    let simd = time(&records, |record| {
+        let seq = record.seq();
+        bases::decode_into(seq.encoded, seq.len(), &mut decoded);
+        decoded.len()
+    });
+    report("decode: as_bytes", htslib, htslib);
+    report("decode: scalar", scalar, htslib);
+    report("decode: simd", simd, htslib);
+
+    let sequences: Vec<Vec<u8>> = records.iter().map(bases::decode).collect();
+    let mut codes = Vec::new();
+    let mut next = 0;
+    let mut coded = |code: fn(&[u8], &mut Vec<u8>) -> bool| {
+        let sequence = &sequences[next % RECORDS];
+        next += 1;
+        code(sequence, &mut codes) as usize
+    };
+    let scalar = time(&records, |_| coded(bases::scalar::codes_into));
+    let simd = time(&records, |_| coded(bases::codes_into));
+    report("codes: scalar", scalar, scalar);
+    report("codes: simd", simd, scalar);
+
+    let stage = time(&records, |record| {
+        (calculate_canonical_kmer_complexity(&bases::decode(record), 21) * 1e6) as usize
+    });
+    println!(
+        "{:<22} {:>7.2} M records/s   (simd feature {})",
+        "decode + complexity",
+        RECORDS as f64 / stage.as_secs_f64() / 1e6,
+        if cfg!(feature = "simd") { "on" } else { "off" }
+    );
+}
@@ -0,0 +1,108 @@
+//! Fresh versus recycled record buffers while grouping templates
+//!
+//! `cargo bench --bench records` groups the same pairs twice: once dropping
+//! every template, so each record is a new allocation as before
+//! `recycle::RecordPool`, and once giving templates back to the grouper.
+//! Pairs are read from memory, which isolates the allocations, and from a
+//! BAM file, which shows their share of a real read loop.
+
+use filter_bam_pairs::grouping::Grouper;
+use filter_bam_pairs::recycle;
+use filter_bam_pairs::test_utils::{mapped_pair, random_sequence};
+use rust_htslib::bam::{self, Read};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const PAIRS: usize = 200_000;
+const ROUNDS: usize = 5;
+
+/// Reads the next record into its argument, like [`bam::Read::read`]
+type Source = Box<dyn FnMut(&mut bam::Record) -> Option<Result<(), rust_htslib::errors::Error>>>;
+
+fn pairs() -> Vec<bam::Record> {
+    let mut records = Vec::with_capacity(2 * PAIRS);
+    for i in 0..PAIRS {
+        let seq = random_sequence(150, (i % 1024) as u64);
+        let (record1, record2) = mapped_pair(&format!("pair{i:09}"), &seq, &seq);
+        records.push(record1.build());
+        records.push(record2.build());
+    }
+    records
+}
+
+/// Group every pair of `source`, recycling templates or not; the best of
+/// `ROUNDS` runs
+fn time_grouping(recycling: bool, mut source: impl FnMut() -> Source) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let mut next = source();
+            let mut grouper = Grouper::new();
+            let started = Instant::now();
+            let mut bases = 0;
+            while let Some(template) = grouper.next_template_from(&mut next) {
+                let template = template.unwrap();
+                bases += template.record1.seq_len() + template.record2.seq_len();
+                if recycling {
+                    grouper.recycle(template);
+                }
+            }
+            black_box(bases);
+            started.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, fresh: Duration, recycled: Duration) {
+    let rate = |time: Duration| 2.0 * PAIRS as f64 / time.as_secs_f64() / 1e6;
+    println!(
+        "{:<10} fresh {:>7.2} M records/s   recycled {:>7.2} M records/s   ({:.2}x)",
+        name,
+        rate(fresh),
+        rate(recycled),
+        fresh.as_secs_f64() / recycled.as_secs_f64()
+    );
+}
+
+fn main() {
+    let records = std::rc::Rc::new(pairs());
+
+    // Copying into the record being read is what htslib does with its buffer
+    let from_memory = || {
+        let records = std::rc::Rc::clone(&records);
+        let mut index = 0;
+        Box::new(move |record: &mut bam::Record| {
+            let source = records.get(index)?;
+            index += 1;
+            recycle::copy_into(record, source);
+            Some(Ok(()))
+        }) as Source
+    };
+    report(
+        "memory",
+        time_grouping(false, from_memory),
+        time_grouping(true, from_memory),
+    );
+
+    let path =
+        std::env::temp_dir().join(format!("filter_bam_pairs-bench.{}.bam", std::process::id()));
+    let mut header = bam::Header::new();
+    let mut sq = bam::header::HeaderRecord::new(b"SQ");
+    sq.push_tag(b"SN", "chr1").push_tag(b"LN", 100_000_000);
+    header.push_record(&sq);
+    let mut writer = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+    for record in records.iter() {
+        writer.write(record).unwrap();
+    }
+    drop(writer);
+    let from_file = || {
+        let mut reader = bam::Reader::from_path(&path).unwrap();
+        Box::new(move |record: &mut bam::Record| reader.read(record)) as Source
+    };
+    report(
+        "BAM file",
+        time_grouping(false, from_file),
+        time_grouping(true, from_file),
+    );
+    std::fs::remove_file(&path).unwrap();
+}
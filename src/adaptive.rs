@@ -0,0 +1,111 @@
+//! Nanopore adaptive-sampling rejects (`--adaptive-sampling-filter`)
+//!
+//! With read-until, MinKNOW ejects a molecule after its first second or so
+//! of sequence once it is judged off-target. The basecalled stub still lands
+//! in the BAM: a few hundred bases that are unmapped, or mapped over a small
+//! part with the rest clipped. A read shorter than `max_length` that is
+//! unmapped or clipped over at least `min_clip_fraction` of its length is
+//! taken as such a reject. Pairs, rare for nanopore data, are rejects when
+//! either mate is. `--adaptive-sampling-output` writes the rejects to a file
+//! of their own.
+
+use crate::metrics::{self, LengthBasis};
+use crate::sink::OutputSink;
+use anyhow::Result;
+use rust_htslib::bam;
+
+/// Longest reject stub when `--adaptive-sampling-max-length` is not given
+pub const DEFAULT_MAX_LENGTH: u32 = 1000;
+
+/// Clipped fraction of a mapped stub when `--adaptive-sampling-min-clip` is not given
+pub const DEFAULT_MIN_CLIP_FRACTION: f64 = 0.5;
+
+/// What makes a read look like an adaptive-sampling reject
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSampling {
+    pub max_length: u32,
+    pub min_clip_fraction: f64,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        AdaptiveSampling {
+            max_length: DEFAULT_MAX_LENGTH,
+            min_clip_fraction: DEFAULT_MIN_CLIP_FRACTION,
+        }
+    }
+}
+
+impl AdaptiveSampling {
+    /// Whether a read is short and unmapped or mostly clipped
+    pub fn is_reject(&self, record: &bam::Record) -> bool {
+        // Hard clips count too: the stub's full length is what was sequenced
+        let length = metrics::read_length(record, LengthBasis::Original);
+        if length >= self.max_length {
+            return false;
+        }
+        record.is_unmapped()
+            || metrics::fraction(
+                metrics::clipped_bases(record, LengthBasis::Original),
+                length,
+            ) >= self.min_clip_fraction
+    }
+
+    pub fn is_reject_pair(&self, record1: &bam::Record, record2: &bam::Record) -> bool {
+        self.is_reject(record1) || self.is_reject(record2)
+    }
+}
+
+/// Passes on only the adaptive-sampling rejects, whatever the other filters decided
+pub struct Rejects<S> {
+    sink: S,
+    sampling: AdaptiveSampling,
+}
+
+impl<S: OutputSink> Rejects<S> {
+    pub fn new(sampling: AdaptiveSampling, sink: S) -> Self {
+        Rejects { sink, sampling }
+    }
+}
+
+impl<S: OutputSink> OutputSink for Rejects<S> {
+    fn write_pair(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        kept: bool,
+    ) -> Result<()> {
+        if self.sampling.is_reject_pair(record1, record2) {
+            self.sink.write_pair(record1, record2, kept)?;
+        }
+        Ok(())
+    }
+
+    fn write_template(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        extras: &[bam::Record],
+        kept: bool,
+    ) -> Result<()> {
+        if self.sampling.is_reject_pair(record1, record2) {
+            self.sink.write_template(record1, record2, extras, kept)?;
+        }
+        Ok(())
+    }
+
+    fn write_read(&mut self, record: &bam::Record, kept: bool) -> Result<()> {
+        if self.sampling.is_reject(record) {
+            self.sink.write_read(record, kept)?;
+        }
+        Ok(())
+    }
+
+    fn relieve_memory(&mut self) -> Result<()> {
+        self.sink.relieve_memory()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.sink.finish()
+    }
+}
@@ -0,0 +1,56 @@
+//! Tagging every pair with its metrics and verdict instead of filtering
+//! (`--annotate`)
+//!
+//! Every pair goes to the output, its mates tagged with their exact kmer
+//! complexity ([`TAG_COMPLEXITY`]) and longest mapped stretch
+//! ([`TAG_LONGEST_MAPPED`]); a pair the run would have removed also gets the
+//! thresholds and filters it failed as [`TAG_FAILED`], comma-separated under
+//! the names of [`Thresholds::NAMES`]. `--use-cached-metrics` reads the first
+//! two tags back, so other thresholds can be tried on the annotated BAM
+//! without measuring again. Tags of an earlier annotation are replaced.
+
+use crate::filter::{Thresholds, TAG_COMPLEXITY, TAG_LONGEST_MAPPED};
+use anyhow::Result;
+use rust_htslib::bam::{self, record::Aux};
+
+/// Aux tag carrying the thresholds and filters a removed pair failed (`xf:Z`)
+pub const TAG_FAILED: &[u8] = b"xf";
+
+/// The names of the failed thresholds, then of the failed `run_filters`
+/// (name and whether the pair passes), comma-separated
+pub fn failures(failed: Thresholds, run_filters: &[(&'static str, bool)]) -> String {
+    failed
+        .names()
+        .chain(
+            run_filters
+                .iter()
+                .filter(|(_, pass)| !pass)
+                .map(|(name, _)| *name),
+        )
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Tag `record` with its metrics and, unless empty, `failures`
+pub fn tag(
+    record: &mut bam::Record,
+    complexity: f64,
+    longest_mapped: u32,
+    failures: &str,
+) -> Result<()> {
+    for tag in [TAG_COMPLEXITY, TAG_LONGEST_MAPPED, TAG_FAILED] {
+        // Absent unless the input was annotated before
+        let _ = record.remove_aux(tag);
+    }
+    if !complexity.is_nan() {
+        record.push_aux(TAG_COMPLEXITY, Aux::Float(complexity as f32))?;
+    }
+    record.push_aux(
+        TAG_LONGEST_MAPPED,
+        Aux::I32(i32::try_from(longest_mapped).unwrap_or(i32::MAX)),
+    )?;
+    if !failures.is_empty() {
+        record.push_aux(TAG_FAILED, Aux::String(failures))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+//! Per-read audit of metric values and verdicts (`--audit`)
+//!
+//! The TSV form has one line per read. The binary form stores one entry per
+//! pair in a BGZF stream, with the name once and fixed-width numbers, which
+//! keeps the audit of a very large BAM far smaller than the BAM itself:
+//!
+//! ```text
+//! file:  b"FBPA" version:u8  entry*
+//! entry: name_len:u16 name:[u8] flags:u8 complexity:[f32; 2] longest_mapped:[u32; 2]
+//! ```
+//!
+//! Numbers are little-endian. Flag bit 0 is set for kept pairs, bit 1 when
+//! the longest mapped stretches were computed. `dump-audit` converts a binary
+//! audit back to TSV.
+
+use crate::fastq::create_bgzf;
+use crate::filter::PairVerdict;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use rust_htslib::{bam, bgzf};
+use std::io::{BufWriter, Read, Write};
+
+const MAGIC: &[u8; 4] = b"FBPA";
+const VERSION: u8 = 1;
+
+const FLAG_KEPT: u8 = 0x1;
+const FLAG_MAPPED: u8 = 0x2;
+
+const TSV_HEADER: &str = "qname\tmate\tcomplexity\tlongest_mapped\tkept";
+
+/// Audit file encodings
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditFormat {
+    /// One tab-separated line per read
+    Tsv,
+    /// Compact BGZF-compressed binary, one entry per pair
+    Binary,
+}
+
+enum Sink {
+    Tsv(BufWriter<std::fs::File>),
+    Binary(bgzf::Writer),
+}
+
+/// Writes one audit entry per filtered pair
+pub struct AuditWriter {
+    sink: Sink,
+}
+
+/// One TSV line for a read
+fn write_tsv_line<W: Write>(
+    out: &mut W,
+    name: &[u8],
+    mate: u8,
+    complexity: f32,
+    longest_mapped: Option<u32>,
+    kept: bool,
+) -> std::io::Result<()> {
+    out.write_all(name)?;
+    match longest_mapped {
+        Some(mapped) => writeln!(
+            out,
+            "\t{}\t{:.4}\t{}\t{}",
+            mate, complexity, mapped, kept as u8
+        ),
+        None => writeln!(out, "\t{}\t{:.4}\tNA\t{}", mate, complexity, kept as u8),
+    }
+}
+
+impl AuditWriter {
+    pub fn create(path: &str, format: AuditFormat) -> Result<Self> {
+        let sink = match format {
+            AuditFormat::Tsv => {
+                let file = std::fs::File::create(path)
+                    .with_context(|| format!("Cannot create {}", path))?;
+                let mut out = BufWriter::new(file);
+                writeln!(out, "{}", TSV_HEADER)?;
+                Sink::Tsv(out)
+            }
+            AuditFormat::Binary => {
+                let mut out = create_bgzf(path)?;
+                out.write_all(MAGIC)?;
+                out.write_all(&[VERSION])?;
+                Sink::Binary(out)
+            }
+        };
+        Ok(AuditWriter { sink })
+    }
+
+    pub fn write_pair(
+        &mut self,
+        record1: &bam::Record,
+        verdict: &PairVerdict,
+        kept: bool,
+    ) -> Result<()> {
+        let name = record1.qname();
+        match &mut self.sink {
+            Sink::Tsv(out) => {
+                for mate in 0..2 {
+                    write_tsv_line(
+                        out,
+                        name,
+                        mate as u8 + 1,
+                        verdict.complexity[mate] as f32,
+                        verdict.longest_mapped.map(|mapped| mapped[mate]),
+                        kept,
+                    )?;
+                }
+            }
+            Sink::Binary(out) => {
+                let len = u16::try_from(name.len()).context("Read name too long for audit")?;
+                let mut flags = 0;
+                if kept {
+                    flags |= FLAG_KEPT;
+                }
+                if verdict.longest_mapped.is_some() {
+                    flags |= FLAG_MAPPED;
+                }
+                let mapped = verdict.longest_mapped.unwrap_or([0, 0]);
+
+                out.write_all(&len.to_le_bytes())?;
+                out.write_all(name)?;
+                out.write_all(&[flags])?;
+                for complexity in verdict.complexity {
+                    out.write_all(&(complexity as f32).to_le_bytes())?;
+                }
+                for mapped in mapped {
+                    out.write_all(&mapped.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush buffered entries
+    pub fn finish(self) -> Result<()> {
+        match self.sink {
+            Sink::Tsv(mut out) => out.flush()?,
+            Sink::Binary(mut out) => out.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Read exactly `buf.len()` bytes, or report a clean end of input
+fn read_entry_field<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..])? {
+            0 if filled == 0 => return Ok(false),
+            0 => bail!("Audit file is truncated"),
+            n => filled += n,
+        }
+    }
+    Ok(true)
+}
+
+/// Convert a binary audit to per-read TSV
+pub fn dump_binary<W: Write>(path: &str, out: &mut W) -> Result<u64> {
+    let mut input =
+        bgzf::Reader::from_path(path).with_context(|| format!("Cannot open {}", path))?;
+    let mut magic = [0u8; 5];
+    if !read_entry_field(&mut input, &mut magic)? || &magic[..4] != MAGIC {
+        bail!("{} is not a binary filter_bam_pairs audit", path);
+    }
+    if magic[4] != VERSION {
+        bail!("{}: unsupported audit version {}", path, magic[4]);
+    }
+
+    writeln!(out, "{}", TSV_HEADER)?;
+    let mut pairs = 0u64;
+    let mut len = [0u8; 2];
+    let mut name = Vec::new();
+    let mut fixed = [0u8; 17];
+    while read_entry_field(&mut input, &mut len)? {
+        name.resize(u16::from_le_bytes(len) as usize, 0);
+        if !read_entry_field(&mut input, &mut name)? || !read_entry_field(&mut input, &mut fixed)? {
+            bail!("{}: audit file is truncated", path);
+        }
+
+        let flags = fixed[0];
+        let word = |i: usize| {
+            let start = 1 + 4 * i;
+            [
+                fixed[start],
+                fixed[start + 1],
+                fixed[start + 2],
+                fixed[start + 3],
+            ]
+        };
+        for mate in 0..2 {
+            let complexity = f32::from_le_bytes(word(mate));
+            let mapped = (flags & FLAG_MAPPED != 0).then(|| u32::from_le_bytes(word(2 + mate)));
+            write_tsv_line(
+                out,
+                &name,
+                mate as u8 + 1,
+                complexity,
+                mapped,
+                flags & FLAG_KEPT != 0,
+            )?;
+        }
+        pairs += 1;
+    }
+    out.flush()?;
+    Ok(pairs)
+}
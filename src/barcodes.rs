@@ -0,0 +1,120 @@
+//! Linked-read molecule barcodes (10x `BX` tag)
+
+use crate::input;
+use crate::units::NumberFormat;
+use anyhow::{Context, Result};
+use rust_htslib::{bam, bam::record::Aux, bam::Read};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Aux tag carrying the corrected molecule barcode
+pub const TAG_BARCODE: &[u8] = b"BX";
+
+/// The record's `BX` barcode, if tagged
+pub fn barcode(record: &bam::Record) -> Option<&[u8]> {
+    match record.aux(TAG_BARCODE) {
+        Ok(Aux::String(bx)) => Some(bx.as_bytes()),
+        _ => None,
+    }
+}
+
+/// Count reads per barcode in a separate pass over the input
+pub fn count_barcode_reads(path: &str, reference: Option<&str>) -> Result<HashMap<Vec<u8>, u64>> {
+    let mut reader = input::reopen(path, reference)?;
+    let mut counts: HashMap<Vec<u8>, u64> = HashMap::new();
+    let mut record = bam::Record::new();
+
+    while let Some(result) = reader.read(&mut record) {
+        result?;
+        if let Some(bx) = barcode(&record) {
+            *counts.entry(bx.to_vec()).or_insert(0) += 1;
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Kept/total pair counts per barcode
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BarcodeStats {
+    pairs: HashMap<String, (u64, u64)>,
+    untagged_pairs: u64,
+}
+
+impl BarcodeStats {
+    pub fn record(&mut self, bx: Option<&[u8]>, kept: bool) {
+        let Some(bx) = bx else {
+            self.untagged_pairs += 1;
+            return;
+        };
+        let entry = self
+            .pairs
+            .entry(String::from_utf8_lossy(bx).into_owned())
+            .or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += kept as u64;
+    }
+
+    pub fn merge(&mut self, other: &BarcodeStats) {
+        for (bx, &(total, kept)) in &other.pairs {
+            let entry = self.pairs.entry(bx.clone()).or_insert((0, 0));
+            entry.0 += total;
+            entry.1 += kept;
+        }
+        self.untagged_pairs += other.untagged_pairs;
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        let barcodes = self.pairs.len();
+        let fully_removed = self.pairs.values().filter(|(_, kept)| *kept == 0).count();
+        let mean_rate = if barcodes > 0 {
+            self.pairs
+                .values()
+                .map(|&(total, kept)| kept as f64 / total as f64)
+                .sum::<f64>()
+                / barcodes as f64
+        } else {
+            0.0
+        };
+
+        println!("\n=== Barcodes (BX) ===");
+        println!("Barcodes seen: {}", format.count(barcodes as u64));
+        println!(
+            "Barcodes with no kept pairs: {}",
+            format.count(fully_removed as u64)
+        );
+        println!(
+            "Mean per-barcode pass rate: {}",
+            format.percent(mean_rate * 100.0)
+        );
+        println!(
+            "Pairs without BX tag: {}",
+            format.count(self.untagged_pairs)
+        );
+    }
+
+    /// Write one `barcode, pairs, kept, pass_rate` row per barcode
+    pub fn write_tsv(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+
+        let mut rows: Vec<_> = self.pairs.iter().collect();
+        rows.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        writeln!(out, "barcode\tpairs\tkept\tpass_rate")?;
+        for (bx, &(total, kept)) in rows {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{:.4}",
+                bx,
+                total,
+                kept,
+                kept as f64 / total as f64
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+//! Decoding BAM sequences and coding bases 2 bits each for the kmer filters
+//!
+//! A BAM record holds two bases per byte as 4-bit codes, and the kmer
+//! complexity packs every A, C, G and T into 2 bits. Both conversions run once
+//! per read over the whole sequence, so with the `simd` feature (on by
+//! default) they take 16 bytes at a time on x86_64: SSSE3 byte shuffles
+//! decode 32 bases per step when the CPU has them, and SSE2 compares turn 16
+//! bases into codes per step. Elsewhere, or without the feature, the [`scalar`]
+//! versions run; they define the results, and the SIMD ones must match them
+//! byte for byte.
+//!
+//! Rolling the 2-bit codes into kmers stays a loop over the codes, as each
+//! kmer is the one before shifted by a base.
+
+use rust_htslib::bam;
+
+/// Bases of the 4-bit codes, as htslib decodes them
+const DECODED: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// 2-bit code of A, C, G and T; [`AMBIGUOUS`] for every other byte
+pub const AMBIGUOUS: u8 = 4;
+
+/// The bases of `record`, as [`bam::record::Seq::as_bytes`] gives them
+pub fn decode(record: &bam::Record) -> Vec<u8> {
+    let seq = record.seq();
+    let mut bases = Vec::with_capacity(seq.len() + 32);
+    decode_into(seq.encoded, seq.len(), &mut bases);
+    bases
+}
+
+/// Decode the first `len` bases of `encoded` (two 4-bit codes per byte,
+/// high nibble first) into `bases`, replacing its contents
+pub fn decode_into(encoded: &[u8], len: usize, bases: &mut Vec<u8>) {
+    bases.clear();
+    bases.reserve(len + 32);
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if std::is_x86_feature_detected!("ssse3") {
+        // SAFETY: the CPU supports SSSE3
+        unsafe { x86::decode(encoded, len, bases) };
+        return;
+    }
+    scalar::decode_into(encoded, len, bases);
+}
+
+/// The 2-bit codes of the upper-case bases of `sequence` into `codes`,
+/// [`AMBIGUOUS`] for every other byte; whether every base is A, C, G or T
+pub fn codes_into(sequence: &[u8], codes: &mut Vec<u8>) -> bool {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        // SSE2 is part of x86_64
+        x86::codes_into(sequence, codes)
+    }
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    {
+        scalar::codes_into(sequence, codes)
+    }
+}
+
+/// Byte-at-a-time conversions, for other platforms and to check the SIMD ones against
+pub mod scalar {
+    use super::{AMBIGUOUS, DECODED};
+
+    /// Both bases of every byte, high nibble first
+    const PAIRS: [[u8; 2]; 256] = {
+        let mut table = [[0; 2]; 256];
+        let mut byte = 0;
+        while byte < 256 {
+            table[byte] = [DECODED[byte >> 4], DECODED[byte & 0x0f]];
+            byte += 1;
+        }
+        table
+    };
+
+    /// 2-bit codes by byte, a table rather than a `match`: random bases
+    /// defeat the branch predictor
+    const CODES: [u8; 256] = {
+        let mut table = [AMBIGUOUS; 256];
+        table[b'A' as usize] = 0;
+        table[b'C' as usize] = 1;
+        table[b'G' as usize] = 2;
+        table[b'T' as usize] = 3;
+        table
+    };
+
+    /// See [`super::decode_into`]
+    pub fn decode_into(encoded: &[u8], len: usize, bases: &mut Vec<u8>) {
+        bases.clear();
+        extend_decoded(encoded, len, bases);
+    }
+
+    /// Append the first `len` bases of `encoded` to `bases`
+    pub(super) fn extend_decoded(encoded: &[u8], len: usize, bases: &mut Vec<u8>) {
+        let start = bases.len();
+        bases.extend(
+            encoded[..len.div_ceil(2)]
+                .iter()
+                .flat_map(|&byte| PAIRS[byte as usize]),
+        );
+        bases.truncate(start + len);
+    }
+
+    /// See [`super::codes_into`]
+    pub fn codes_into(sequence: &[u8], codes: &mut Vec<u8>) -> bool {
+        codes.clear();
+        extend_codes(sequence, codes)
+    }
+
+    /// Append the codes of `sequence` to `codes`; whether none is ambiguous
+    pub(super) fn extend_codes(sequence: &[u8], codes: &mut Vec<u8>) -> bool {
+        let mut known = true;
+        codes.extend(sequence.iter().map(|&base| {
+            let code = CODES[base as usize];
+            known &= code != AMBIGUOUS;
+            code
+        }));
+        known
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod x86 {
+    use super::{scalar, AMBIGUOUS, DECODED};
+    use std::arch::x86_64::*;
+
+    /// Decode 16 bytes (32 bases) a step with a nibble shuffle
+    #[target_feature(enable = "ssse3")]
+    pub(super) unsafe fn decode(encoded: &[u8], len: usize, bases: &mut Vec<u8>) {
+        let encoded = &encoded[..len.div_ceil(2)];
+        // Full steps only; a last half byte is decoded with the tail
+        let steps = len / 32;
+        let table = _mm_loadu_si128(DECODED.as_ptr() as *const __m128i);
+        let low_nibbles = _mm_set1_epi8(0x0f);
+        bases.reserve(steps * 32);
+        let out = bases.as_mut_ptr();
+        for step in 0..steps {
+            let packed = _mm_loadu_si128(encoded.as_ptr().add(step * 16) as *const __m128i);
+            let high = _mm_and_si128(_mm_srli_epi16(packed, 4), low_nibbles);
+            let low = _mm_and_si128(packed, low_nibbles);
+            let high = _mm_shuffle_epi8(table, high);
+            let low = _mm_shuffle_epi8(table, low);
+            // Each byte's high-nibble base comes first
+            _mm_storeu_si128(
+                out.add(step * 32) as *mut __m128i,
+                _mm_unpacklo_epi8(high, low),
+            );
+            _mm_storeu_si128(
+                out.add(step * 32 + 16) as *mut __m128i,
+                _mm_unpackhi_epi8(high, low),
+            );
+        }
+        bases.set_len(steps * 32);
+        scalar::extend_decoded(&encoded[steps * 16..], len - steps * 32, bases);
+    }
+
+    /// Code 16 bases a step by comparing them with each base
+    pub(super) fn codes_into(sequence: &[u8], codes: &mut Vec<u8>) -> bool {
+        codes.clear();
+        codes.reserve(sequence.len());
+        let steps = sequence.len() / 16;
+        let mut ambiguous = 0;
+        // SAFETY: SSE2 is part of x86_64; loads and stores stay within the
+        // first `steps * 16` bytes of `sequence` and of `codes`' capacity
+        unsafe {
+            let [a, c, g, t] = [b'A', b'C', b'G', b'T'].map(|base| _mm_set1_epi8(base as i8));
+            let [one, two, three, other] =
+                [1, 2, 3, AMBIGUOUS].map(|code| _mm_set1_epi8(code as i8));
+            let out = codes.as_mut_ptr();
+            for step in 0..steps {
+                let bases = _mm_loadu_si128(sequence.as_ptr().add(step * 16) as *const __m128i);
+                let is_a = _mm_cmpeq_epi8(bases, a);
+                let is_c = _mm_cmpeq_epi8(bases, c);
+                let is_g = _mm_cmpeq_epi8(bases, g);
+                let is_t = _mm_cmpeq_epi8(bases, t);
+                let known = _mm_or_si128(_mm_or_si128(is_a, is_c), _mm_or_si128(is_g, is_t));
+                let coded = _mm_or_si128(
+                    _mm_or_si128(_mm_and_si128(is_c, one), _mm_and_si128(is_g, two)),
+                    _mm_or_si128(_mm_and_si128(is_t, three), _mm_andnot_si128(known, other)),
+                );
+                _mm_storeu_si128(out.add(step * 16) as *mut __m128i, coded);
+                ambiguous |= !_mm_movemask_epi8(known) & 0xffff;
+            }
+            codes.set_len(steps * 16);
+        }
+        let known = scalar::extend_codes(&sequence[steps * 16..], codes);
+        known && ambiguous == 0
+    }
+}
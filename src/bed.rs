@@ -0,0 +1,67 @@
+//! Reading the BED files of `--regions`, `--targets` and `--primers`
+//!
+//! Blank lines, `#` comments and `track` and `browser` header lines are
+//! skipped. Every other line needs tab-separated chrom, start and end, with
+//! `0 <= start < end` (0-based, half-open); columns after the third are kept
+//! for the caller.
+
+use anyhow::{bail, Context, Result};
+use std::io::BufRead;
+
+/// One interval line of a BED file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BedLine {
+    /// 1-based line number, for error messages
+    pub number: usize,
+    pub chrom: String,
+    pub start: i64,
+    pub end: i64,
+    /// The name column, when present and not blank
+    pub name: Option<String>,
+}
+
+/// Every interval line of the BED file at `path`, in file order
+pub fn read(path: &str) -> Result<Vec<BedLine>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Cannot open {}", path))?;
+    let mut lines = Vec::new();
+    for (number, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Cannot read {}", path))?;
+        if let Some(line) = parse_line(path, &line, number + 1)? {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+/// Line `number` of the BED file at `path`; `None` for lines without an interval
+fn parse_line(path: &str, line: &str, number: usize) -> Result<Option<BedLine>> {
+    if line.trim().is_empty()
+        || line.starts_with('#')
+        || line.starts_with("track")
+        || line.starts_with("browser")
+    {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.split('\t').collect();
+    let (Some(chrom), Some(start), Some(end)) = (
+        fields.first(),
+        fields.get(1).and_then(|s| s.trim().parse::<i64>().ok()),
+        fields.get(2).and_then(|s| s.trim().parse::<i64>().ok()),
+    ) else {
+        bail!("{} line {}: expected chrom, start and end", path, number);
+    };
+    if start < 0 || end <= start {
+        bail!("{} line {}: empty or negative interval", path, number);
+    }
+    Ok(Some(BedLine {
+        number,
+        chrom: chrom.to_string(),
+        start,
+        end,
+        name: fields
+            .get(3)
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string),
+    }))
+}
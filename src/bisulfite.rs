@@ -0,0 +1,61 @@
+//! Bisulfite/EM-seq support: collapsing converted bases before counting kmers
+
+use rust_htslib::{bam, bam::record::Aux};
+
+/// Which base change the conversion shows in reference orientation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conversion {
+    /// Original top strand: unmethylated C read as T
+    CToT,
+    /// Original bottom strand: the C->T change appears as G->A on the reference
+    GToA,
+}
+
+/// Collapse converted bases so conversion doesn't masquerade as low complexity
+pub fn collapse(seq: &mut [u8], conversion: Conversion) {
+    let (from, to) = match conversion {
+        Conversion::CToT => (b'C', b'T'),
+        Conversion::GToA => (b'G', b'A'),
+    };
+    for base in seq.iter_mut() {
+        if *base == from {
+            *base = to;
+        }
+    }
+}
+
+/// Conversion named by aligner tags: Bismark `XG:Z:CT|GA` or bwa-meth `YD:Z:f|r`
+fn tagged_conversion(record: &bam::Record) -> Option<Conversion> {
+    match record.aux(b"XG") {
+        Ok(Aux::String("CT")) => return Some(Conversion::CToT),
+        Ok(Aux::String("GA")) => return Some(Conversion::GToA),
+        _ => {}
+    }
+    match record.aux(b"YD") {
+        Ok(Aux::String("f")) => Some(Conversion::CToT),
+        Ok(Aux::String("r")) => Some(Conversion::GToA),
+        _ => None,
+    }
+}
+
+/// Strand-aware conversion for a pair
+///
+/// Uses aligner tags when present. Otherwise assumes a directional library:
+/// pairs whose first mate maps forward come from the original top strand
+/// (C->T), the others from the original bottom strand (G->A).
+pub fn pair_conversion(record1: &bam::Record, record2: &bam::Record) -> Conversion {
+    if let Some(conversion) = tagged_conversion(record1).or_else(|| tagged_conversion(record2)) {
+        return conversion;
+    }
+
+    let first = if record2.is_first_in_template() && !record1.is_first_in_template() {
+        record2
+    } else {
+        record1
+    };
+    if first.is_reverse() {
+        Conversion::GToA
+    } else {
+        Conversion::CToT
+    }
+}
@@ -0,0 +1,166 @@
+//! Short-circuit evaluation of the per-pair thresholds (`--short-circuit`)
+//!
+//! By default every enabled threshold is checked on every pair, so the
+//! report can say how many pairs failed each one. With `--short-circuit`
+//! the thresholds are checked one at a time, cheapest first, and a pair is
+//! rejected at the first failure: a pair below `--min-mapq` is never hashed
+//! for complexity. `--filter-order` moves thresholds to the front; the filter
+//! expression always comes last. The report then gives, per threshold, the
+//! pairs it was evaluated on and the pairs it rejected, which is where the
+//! order pays off.
+
+use crate::filter::Thresholds;
+use crate::units::NumberFormat;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+const FILTER_EXPR: usize = Thresholds::FILTER_EXPR;
+
+/// Thresholds by cost: MAPQ, the insert size, the references and the name
+/// hash are a field read, length and CIGAR walks come next, then the
+/// alignment tags, the base-quality scans, and kmer hashing last
+const COST_ORDER: [usize; FILTER_EXPR] = [
+    Thresholds::MIN_MAPQ,
+    Thresholds::INSERT_SIZE,
+    Thresholds::SAME_REFERENCE,
+    Thresholds::HASH_SAMPLE,
+    Thresholds::SHORT_READ_POLICY,
+    Thresholds::MAX_SPLICE_JUNCTIONS,
+    Thresholds::MAX_CLIP_FRACTION,
+    Thresholds::MIN_MAPPED,
+    Thresholds::MIN_MAPPED_FRACTION,
+    Thresholds::MAX_NM,
+    Thresholds::MAX_ERROR_RATE,
+    Thresholds::MAX_DE,
+    Thresholds::MIN_GAP_COMPRESSED_IDENTITY,
+    Thresholds::MIN_AVG_BASEQ,
+    Thresholds::MIN_BASEQ_FRACTION,
+    Thresholds::COMPLEXITY,
+];
+
+/// The order thresholds are evaluated in, by [`Thresholds::NAMES`] index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterOrder(Vec<usize>);
+
+impl Default for FilterOrder {
+    fn default() -> Self {
+        FilterOrder(COST_ORDER.to_vec())
+    }
+}
+
+impl FilterOrder {
+    /// The comma-separated thresholds of `--filter-order` first, the rest
+    /// after them by cost
+    pub fn parse(list: &str) -> Result<Self> {
+        let mut order = Vec::new();
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let name = name.trim_start_matches("--").replace('-', "_");
+            let Some(index) = Thresholds::NAMES.iter().position(|&known| known == name) else {
+                bail!(
+                    "Unknown filter {:?} in --filter-order; expected one of {}",
+                    name,
+                    Thresholds::NAMES[..FILTER_EXPR].join(", ")
+                );
+            };
+            if index == FILTER_EXPR {
+                bail!("--filter-order cannot move filter_expr, which is always evaluated last");
+            }
+            if order.contains(&index) {
+                bail!("{} is listed twice in --filter-order", name);
+            }
+            order.push(index);
+        }
+        let rest: Vec<usize> = COST_ORDER
+            .into_iter()
+            .filter(|index| !order.contains(index))
+            .collect();
+        order.extend(rest);
+        Ok(FilterOrder(order))
+    }
+
+    /// Threshold indices, the filter expression excluded
+    pub fn indices(&self) -> &[usize] {
+        &self.0
+    }
+}
+
+/// Pairs each threshold was evaluated on and rejected, by [`Thresholds::NAMES`] index
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EvaluationCounts {
+    evaluated: [u64; Thresholds::NAMES.len()],
+    rejected: [u64; Thresholds::NAMES.len()],
+}
+
+impl EvaluationCounts {
+    pub fn record(&mut self, evaluated: Thresholds, failed: Thresholds) {
+        for index in 0..Thresholds::NAMES.len() {
+            self.evaluated[index] += evaluated.contains(index) as u64;
+            self.rejected[index] += failed.contains(index) as u64;
+        }
+    }
+
+    /// One row per threshold in `enabled`, in evaluation order
+    pub fn rows(&self, order: &FilterOrder, enabled: Thresholds) -> Vec<FilterEvaluation> {
+        order
+            .indices()
+            .iter()
+            .chain(&[FILTER_EXPR])
+            .filter(|&&index| enabled.contains(index))
+            .map(|&index| FilterEvaluation {
+                filter: Thresholds::NAMES[index].to_string(),
+                evaluated: self.evaluated[index],
+                rejected: self.rejected[index],
+            })
+            .collect()
+    }
+}
+
+/// Pairs one threshold was evaluated on and rejected
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilterEvaluation {
+    pub filter: String,
+    pub evaluated: u64,
+    pub rejected: u64,
+}
+
+/// Add `other`'s counts to `into`, matching thresholds by name
+pub fn merge_evaluations(into: &mut Vec<FilterEvaluation>, other: &[FilterEvaluation]) {
+    for evaluation in other {
+        match into.iter_mut().find(|e| e.filter == evaluation.filter) {
+            Some(e) => {
+                e.evaluated += evaluation.evaluated;
+                e.rejected += evaluation.rejected;
+            }
+            None => into.push(evaluation.clone()),
+        }
+    }
+}
+
+pub fn print_evaluations(evaluations: &[FilterEvaluation], unit: &str, format: NumberFormat) {
+    println!("\n=== Filter Evaluation (short-circuit) ===");
+    println!(
+        "{:<28} {:>16} {:>12} {:>10}",
+        "Filter",
+        format!("Evaluated {}", unit),
+        "Rejected",
+        "Rejected %"
+    );
+    for evaluation in evaluations {
+        let share = if evaluation.evaluated > 0 {
+            format.share(evaluation.rejected, evaluation.evaluated)
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "{:<28} {:>16} {:>12} {:>10}",
+            evaluation.filter,
+            format.count(evaluation.evaluated),
+            format.count(evaluation.rejected),
+            share
+        );
+    }
+}
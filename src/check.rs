@@ -0,0 +1,462 @@
+//! `check-config`: validate options against the input before a long run
+
+use crate::complexity::{self, ComplexityMethod};
+use crate::filter::{MissingNm, ShortReadPolicy, TAG_COMPLEXITY, TAG_LONGEST_MAPPED};
+use crate::flags::FlagFilter;
+use crate::options::Args;
+use crate::{
+    barcodes, header, input, metric_cache, metrics, molecules, output, primers, quality, regions,
+    samples, targets, tmp,
+};
+use anyhow::Result;
+use rust_htslib::{bam, bam::Read};
+use std::path::Path;
+
+/// Records sampled from the start of the input
+const SAMPLE_RECORDS: usize = 10_000;
+
+#[derive(Default)]
+struct Findings {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Findings {
+    fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    fn warning(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+}
+
+/// Report an output path whose directory doesn't exist
+fn check_output_dir(path: &str, what: &str, findings: &mut Findings) {
+    let parent = Path::new(path).parent().unwrap_or(Path::new(""));
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    if !parent.is_dir() {
+        findings.error(format!(
+            "{} directory {} does not exist",
+            what,
+            parent.display()
+        ));
+    }
+}
+
+/// What the first records of the input look like
+#[derive(Default)]
+struct Sample {
+    records: usize,
+    lengths: Vec<usize>,
+    unpaired_name: Option<(String, String)>,
+    with_cached_metrics: usize,
+    with_barcode: usize,
+    with_molecule: usize,
+    mapped: usize,
+    /// Mapped records with an `NM` tag or an `=`/`X` CIGAR
+    mapped_with_edits: usize,
+    qualities: quality::QualitySample,
+}
+
+fn sample_input(source: &mut input::Source, flags: &mut FlagFilter) -> Result<Sample> {
+    let mut sample = Sample::default();
+    let mut pending: Option<Vec<u8>> = None;
+    let mut record = bam::Record::new();
+
+    while sample.records < SAMPLE_RECORDS {
+        match flags.read_from(|record| source.read(record), &mut record) {
+            Some(Ok(())) => {}
+            None => break,
+            Some(Err(e)) => {
+                return Err(match source {
+                    input::Source::Fastq(pairs) => pairs.take_error().unwrap_or(e.into()),
+                    _ => e.into(),
+                })
+            }
+        }
+        sample.records += 1;
+        sample.lengths.push(record.seq_len());
+        sample.qualities.observe(&record);
+
+        if record.aux(TAG_COMPLEXITY).is_ok() && record.aux(TAG_LONGEST_MAPPED).is_ok() {
+            sample.with_cached_metrics += 1;
+        }
+        if barcodes::barcode(&record).is_some() {
+            sample.with_barcode += 1;
+        }
+        if molecules::molecule(&record).is_some() {
+            sample.with_molecule += 1;
+        }
+        if !record.is_unmapped() {
+            sample.mapped += 1;
+            if metrics::edit_distance(&record).is_some() {
+                sample.mapped_with_edits += 1;
+            }
+        }
+
+        // Primary records must arrive in same-name pairs; the extra
+        // alignments between them are grouped with the pair
+        if record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        match pending.take() {
+            None => pending = Some(record.qname().to_vec()),
+            Some(name) => {
+                if name != record.qname() && sample.unpaired_name.is_none() {
+                    sample.unpaired_name = Some((
+                        String::from_utf8_lossy(&name).into_owned(),
+                        String::from_utf8_lossy(record.qname()).into_owned(),
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(sample)
+}
+
+/// Check the option combination against the input header and first records
+pub fn check_config(args: &Args) -> Result<()> {
+    let mut findings = Findings::default();
+
+    if let Err(e) = args.validate() {
+        findings.error(e.to_string());
+    }
+
+    if args.output.as_ref() == Some(&args.input) {
+        findings.error("Output path is the same as the input".to_string());
+    }
+    // Templated outputs create their directories
+    if let Some(path) = args
+        .output
+        .as_ref()
+        .filter(|path| !output::is_template(path))
+    {
+        check_output_dir(path, "Output", &mut findings);
+    }
+    if let Some(path) = &args.decisions {
+        check_output_dir(path, "--decisions", &mut findings);
+    }
+    if let Some(path) = args
+        .rejected_output
+        .as_ref()
+        .filter(|path| !output::is_template(path))
+    {
+        check_output_dir(path, "--rejected-output", &mut findings);
+    }
+    if let Some(path) = args
+        .adaptive_sampling_output
+        .as_ref()
+        .filter(|path| !output::is_template(path))
+    {
+        check_output_dir(path, "--adaptive-sampling-output", &mut findings);
+    }
+    if let Some(prefix) = &args.fastq_out {
+        check_output_dir(prefix, "--fastq-out", &mut findings);
+    }
+    if let Some(prefix) = &args.failed_fastq {
+        check_output_dir(prefix, "--failed-fastq", &mut findings);
+    }
+    if let Some(path) = &args.stats_sn {
+        check_output_dir(path, "--stats-sn", &mut findings);
+    }
+    if let Some(path) = &args.stats_out {
+        check_output_dir(path, "--stats-out", &mut findings);
+    }
+    if let Some(path) = &args.bx_stats {
+        check_output_dir(path, "--bx-stats", &mut findings);
+    }
+    if let Some(path) = &args.family_size_histogram {
+        check_output_dir(path, "--family-size-histogram", &mut findings);
+    }
+    if let Some(path) = &args.rejection_bedgraph {
+        check_output_dir(path, "--rejection-bedgraph", &mut findings);
+    }
+    if let Some(path) = &args.ont_stats {
+        check_output_dir(path, "--ont-stats", &mut findings);
+    }
+    if let Some(path) = &args.clip_profile {
+        check_output_dir(path, "--clip-profile", &mut findings);
+    }
+    if let Some(path) = &args.gc_profile {
+        check_output_dir(path, "--gc-profile", &mut findings);
+    }
+    if let Some(path) = &args.complexity_histogram {
+        check_output_dir(path, "--complexity-histogram", &mut findings);
+    }
+    if let Some(path) = &args.sample_stats {
+        check_output_dir(path, "--sample-stats", &mut findings);
+    }
+    if let Some(path) = &args.target_stats {
+        check_output_dir(path, "--target-stats", &mut findings);
+    }
+    // Name lists can be huge; only check they can be opened
+    for (path, option) in [
+        (&args.include_names, "--include-names"),
+        (&args.exclude_names, "--exclude-names"),
+    ] {
+        if let Some(path) = path {
+            if let Err(e) = std::fs::File::open(path) {
+                findings.error(format!("{}: cannot open {}: {}", option, path, e));
+            }
+        }
+    }
+    if let (Some(path), Ok(config)) = (&args.metric_cache, args.filter_config()) {
+        let checked = metric_cache::MetricCacheReader::open(path)
+            .and_then(|cache| cache.check_options(&config));
+        if let Err(e) = checked {
+            findings.error(format!("--metric-cache: {:#}", e));
+        }
+    }
+    let tmp_root = tmp::tmp_root(args.tmp_dir.as_deref());
+    if !tmp_root.is_dir() {
+        findings.error(format!(
+            "Temporary directory {} does not exist",
+            tmp_root.display()
+        ));
+    } else if let Err(e) = tmp::check_path_length(&tmp_root) {
+        findings.error(e.to_string());
+    }
+
+    if let Some(reference) = &args.reference {
+        if !Path::new(reference).is_file() {
+            findings.error(format!("Reference {} does not exist", reference));
+        } else if !Path::new(&format!("{}.fai", reference)).is_file() {
+            findings.warning(format!(
+                "Reference {} has no .fai index; htslib will try to build one",
+                reference
+            ));
+        }
+    }
+
+    if args.fastq_input().is_some() {
+        match args.open_input() {
+            Err(e) => findings.error(format!("Cannot open input {}: {:#}", args.input, e)),
+            // FASTQ mates come paired, without a header to check
+            Ok(mut source) => match sample_input(&mut source, &mut args.flag_filter()) {
+                Err(e) => findings.error(format!("Cannot read input {}: {:#}", args.input, e)),
+                Ok(sample) => check_sample(args, &sample, true, &mut findings),
+            },
+        }
+    } else {
+        match input::reopen(&args.input, args.reference.as_deref()) {
+            Err(e) => findings.error(format!("Cannot open input {}: {:#}", args.input, e)),
+            Ok(reader) => {
+                let header = bam::Header::from_template(reader.header());
+                for run in header::previous_runs(&header) {
+                    let message = format!(
+                        "Input was already filtered by {} (@PG ID:{})",
+                        header::PROGRAM_NAME,
+                        run.id
+                    );
+                    if args.force {
+                        findings.warning(message);
+                    } else {
+                        findings
+                            .error(format!("{}; the run would refuse without --force", message));
+                    }
+                }
+
+                let read_groups = samples::read_group_ids(&header);
+                for (read_group, _) in &args.read_group_configs {
+                    if !read_groups.contains(read_group) {
+                        findings.warning(format!(
+                            "Config section [read-group {}] names no @RG of the input header",
+                            read_group
+                        ));
+                    }
+                }
+
+                if let Some(path) = &args.targets {
+                    if let Err(e) = targets::Targets::read_bed(path, reader.header()) {
+                        findings.error(format!("--targets: {:#}", e));
+                    }
+                }
+                if let Some(path) = &args.primers {
+                    if let Err(e) = primers::PrimerScheme::read_bed(path, reader.header()) {
+                        findings.error(format!("--primers: {:#}", e));
+                    }
+                }
+
+                // Coordinate-sorted inputs are paired through the index for --regions
+                let mut through_index = false;
+                match args.regions(reader.header()) {
+                    Err(e) => findings.error(format!("--regions: {:#}", e)),
+                    Ok(Some(_)) if regions::is_coordinate_sorted(reader.header()) => {
+                        through_index = true;
+                        if let Err(e) = bam::IndexedReader::from_path(&args.input) {
+                            findings.error(format!(
+                            "--regions: cannot open the index of the coordinate-sorted input ({}); run samtools index",
+                            e
+                        ));
+                        }
+                    }
+                    Ok(_) => {}
+                }
+                if args.coordinate_sorted && !regions::is_coordinate_sorted(reader.header()) {
+                    findings.warning(
+                        "--coordinate-sorted: the input header does not declare coordinate order"
+                            .to_string(),
+                    );
+                }
+
+                // Mates are paired by name as they are read, whatever the order
+                let paired_on_read = through_index || args.coordinate_sorted;
+                let sample =
+                    sample_input(&mut input::Source::Direct(reader), &mut args.flag_filter())?;
+                check_sample(args, &sample, paired_on_read, &mut findings);
+            }
+        }
+    }
+
+    println!("Checked configuration for {}", args.input);
+    for warning in &findings.warnings {
+        println!("  WARNING: {}", warning);
+    }
+    for error in &findings.errors {
+        println!("  ERROR: {}", error);
+    }
+
+    if findings.errors.is_empty() {
+        println!("Configuration OK ({} warnings)", findings.warnings.len());
+        Ok(())
+    } else {
+        anyhow::bail!("Configuration has {} problem(s)", findings.errors.len())
+    }
+}
+
+fn check_sample(args: &Args, sample: &Sample, paired_on_read: bool, findings: &mut Findings) {
+    if sample.records == 0 {
+        findings.error("Input contains no records".to_string());
+        return;
+    }
+
+    let mut lengths = sample.lengths.clone();
+    lengths.sort_unstable();
+    let median = lengths[lengths.len() / 2];
+    let max = lengths[lengths.len() - 1];
+    println!(
+        "Sampled {} records: read length min {}, median {}, max {}",
+        sample.records, lengths[0], median, max
+    );
+
+    let shortest = complexity::min_read_length(
+        args.complexity_method,
+        args.kmer_size,
+        args.entropy_word_size,
+    );
+    let limit = match args.complexity_method {
+        ComplexityMethod::KmerUniqueness => format!("the kmer size ({})", shortest),
+        _ => format!("the {} bases the complexity method needs", shortest),
+    };
+    let short = lengths.iter().filter(|&&len| len < shortest).count();
+    let outcome = match args.short_read_policy {
+        ShortReadPolicy::Fail => "fail complexity",
+        ShortReadPolicy::Pass => "pass complexity unchecked",
+        ShortReadPolicy::Skip => "be skipped",
+    };
+    if short == sample.records && args.short_read_policy != ShortReadPolicy::Pass {
+        findings.error(format!(
+            "All sampled reads are shorter than {} and would {}",
+            limit, outcome
+        ));
+    } else if short > 0 {
+        findings.warning(format!(
+            "{:.1}% of sampled reads are shorter than {} and will {}",
+            short as f64 / sample.records as f64 * 100.0,
+            limit,
+            outcome
+        ));
+    }
+
+    if args.min_mapped as usize > max {
+        findings.error(format!(
+            "--min-mapped {} exceeds the longest sampled read ({} bp); no pair can pass",
+            args.min_mapped, max
+        ));
+    } else if args.min_mapped as usize > median {
+        findings.warning(format!(
+            "--min-mapped {} exceeds the median read length ({} bp)",
+            args.min_mapped, median
+        ));
+    }
+
+    if let (false, false, Some((name1, name2))) =
+        (args.single_end, paired_on_read, &sample.unpaired_name)
+    {
+        findings.error(format!(
+            "Input is not name-sorted into pairs (read {} followed by {})",
+            name1, name2
+        ));
+    }
+
+    if args.use_cached_metrics && sample.with_cached_metrics == 0 {
+        findings.warning(
+            "--use-cached-metrics: no sampled record has xc/xm tags; all metrics will be recomputed"
+                .to_string(),
+        );
+    }
+
+    if (args.min_bx_reads > 0 || args.bx_stats.is_some()) && sample.with_barcode == 0 {
+        findings.warning(
+            "No sampled record has a BX tag; barcode options will have no effect".to_string(),
+        );
+    }
+    if (args.min_family_size > 0 || args.family_size_histogram.is_some())
+        && sample.with_molecule == 0
+    {
+        findings.warning(
+            "No sampled record has an MI tag; family options will have no effect".to_string(),
+        );
+    }
+
+    if args.min_gap_compressed_identity.is_some()
+        && !args.regenerate_md
+        && sample.mapped_with_edits < sample.mapped
+    {
+        findings.warning(
+            "--min-gap-compressed-identity: some mapped reads lack NM tags and =/X CIGARs and will fail"
+                .to_string(),
+        );
+    }
+
+    if (args.max_nm.is_some() || args.max_error_rate.is_some())
+        && !args.regenerate_md
+        && sample.mapped_with_edits < sample.mapped
+    {
+        let missing = sample.mapped - sample.mapped_with_edits;
+        match args.missing_nm {
+            MissingNm::Error => findings.error(format!(
+                "--max-nm/--max-error-rate: {} sampled mapped reads lack NM tags and =/X CIGARs; \
+                 the run would stop at the first (see --regenerate-md and --missing-nm)",
+                missing
+            )),
+            policy => findings.warning(format!(
+                "--max-nm/--max-error-rate: {} sampled mapped reads lack NM tags and =/X CIGARs and will {}",
+                missing,
+                if policy == MissingNm::Pass { "pass" } else { "fail" }
+            )),
+        }
+    }
+
+    if let Some(problem) = sample.qualities.problem() {
+        let message = format!("Base qualities: {}", problem);
+        match args.quality_check {
+            quality::QualityCheck::Fail => findings.error(message),
+            quality::QualityCheck::Warn => findings.warning(message),
+            quality::QualityCheck::Off => {}
+        }
+    }
+
+    if args.stats_sn.is_some() && !args.regenerate_md && sample.mapped_with_edits < sample.mapped {
+        findings.warning(
+            "--stats-sn: some mapped reads lack NM tags and =/X CIGARs; the error rate will not be reported"
+                .to_string(),
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Read names reused by more than one pair (`--check-name-collisions`)
+//!
+//! Merging BAMs whose read names clash leaves pairs that look valid one at a
+//! time but mix mates from different fragments. Every pair's name goes into a
+//! Bloom filter of fixed size; a name already present was probably seen
+//! before. Bloom filters give false positives but never false negatives, so
+//! a run reporting no collisions has none, and the estimated false-positive
+//! rate says how much to trust a non-zero count.
+
+use crate::sample::name_hash;
+
+/// Bit positions set per name
+const HASHES: u64 = 4;
+
+/// Collisions named in warnings before only counting the rest
+const WARN_COLLISIONS: u64 = 10;
+
+/// Fixed-size Bloom filter over read names
+pub struct Bloom {
+    bits: Vec<u64>,
+    bit_count: u64,
+}
+
+impl Bloom {
+    /// A filter using `memory` bytes
+    pub fn new(memory: usize) -> Self {
+        let words = (memory / 8).max(1);
+        Bloom {
+            bits: vec![0; words],
+            bit_count: words as u64 * 64,
+        }
+    }
+
+    /// Bit positions of a name, by double hashing
+    fn positions(&self, name: &[u8]) -> impl Iterator<Item = (usize, u64)> {
+        let h1 = name_hash(name);
+        let h2 = name_hash(&h1.to_le_bytes()) | 1;
+        let bit_count = self.bit_count;
+        (0..HASHES).map(move |i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % bit_count;
+            ((bit / 64) as usize, 1u64 << (bit % 64))
+        })
+    }
+
+    /// Add a name, returning whether it was (probably) present already
+    pub fn insert(&mut self, name: &[u8]) -> bool {
+        let mut present = true;
+        for (word, mask) in self.positions(name) {
+            present &= self.bits[word] & mask != 0;
+            self.bits[word] |= mask;
+        }
+        present
+    }
+
+    /// Whether a name was (probably) added
+    pub fn contains(&self, name: &[u8]) -> bool {
+        self.positions(name)
+            .all(|(word, mask)| self.bits[word] & mask != 0)
+    }
+
+    /// Bytes held by the bits
+    pub fn memory(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Chance that a name never added is reported present, after `names` insertions
+    pub fn false_positive_rate(&self, names: u64) -> f64 {
+        let fill = 1.0 - (-(HASHES as f64) * names as f64 / self.bit_count as f64).exp();
+        fill.powi(HASHES as i32)
+    }
+}
+
+/// Bloom filter over the read names of all pairs seen so far
+pub struct NameCollisions {
+    bloom: Bloom,
+    names: u64,
+    collisions: u64,
+    previous: Vec<u8>,
+}
+
+impl NameCollisions {
+    /// A filter using `memory` bytes
+    pub fn new(memory: usize) -> Self {
+        NameCollisions {
+            bloom: Bloom::new(memory),
+            names: 0,
+            collisions: 0,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Record a pair's name, returning whether it was (probably) seen before
+    pub fn check(&mut self, name: &[u8]) -> bool {
+        let present = self.bloom.insert(name);
+        self.names += 1;
+
+        if present {
+            self.collisions += 1;
+            // Repeats of the previous pair are certain; others may be false positives
+            let certain = self.previous == name;
+            if self.collisions <= WARN_COLLISIONS {
+                eprintln!(
+                    "Warning: read name {} {} reused by another pair",
+                    String::from_utf8_lossy(name),
+                    if certain { "is" } else { "may be" }
+                );
+            } else if self.collisions == WARN_COLLISIONS + 1 {
+                eprintln!("Warning: further read name collisions are only counted");
+            }
+        }
+        self.previous.clear();
+        self.previous.extend_from_slice(name);
+        present
+    }
+
+    /// Pairs whose name was reported as seen before
+    pub fn collisions(&self) -> u64 {
+        self.collisions
+    }
+
+    /// Chance that a new, unique name is reported as a collision at the
+    /// filter's final fill
+    pub fn false_positive_rate(&self) -> f64 {
+        self.bloom.false_positive_rate(self.names)
+    }
+}
@@ -0,0 +1,646 @@
+//! The command line of the `filter_bam_pairs` binary and what each
+//! subcommand runs
+//!
+//! [`Cli`] is the whole command line: [`Args`] for a filtering run or one of
+//! the [`Command`] subcommands. `main.rs` expands config files with
+//! [`crate::config::expand_config_args`], parses [`Cli`] and calls the
+//! function here that the subcommand names; [`run_filter`] is a filtering
+//! run, with read-group sections, provenance and `--parallel-contigs` workers.
+
+use crate::filter::KMER_SIZE;
+use crate::options::Args;
+use crate::{
+    audit, check, config, contigs, decisions, filter, grouping, header, input, metric_cache,
+    notify, output, pipeline, provenance, regions, report, signals, tmp, units,
+};
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand};
+use rust_htslib::{bam, bam::Read};
+
+#[derive(Parser, Debug)]
+#[command(name = "filter_bam_pairs")]
+#[command(about = "Filter paired-end BAM reads by kmer complexity and mapped bases", long_about = None)]
+#[command(
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    args_override_self = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub args: Option<Args>,
+}
+
+// Parsed once per process, so the variant size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Validate options against the input header and first records without filtering
+    #[command(args_override_self = true)]
+    CheckConfig(Args),
+    /// Combine --stats-json files from parallel runs into one report
+    MergeStats(MergeStatsArgs),
+    /// Convert a binary --audit file to TSV
+    DumpAudit(DumpAuditArgs),
+    /// Measure every pair once and store the metrics for tune and --metric-cache
+    CacheMetrics(CacheMetricsArgs),
+    /// Report pass rates for complexity and min-mapped cutoffs from a metric cache
+    Tune(TuneArgs),
+    /// Write the input BAM without the pairs a --decisions file removes
+    Apply(ApplyArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ApplyArgs {
+    /// The BAM file the decisions were made from
+    #[arg(short, long, value_name = "FILE")]
+    pub input: String,
+
+    /// Decision file written with --decisions
+    #[arg(short, long, value_name = "FILE")]
+    pub decisions: String,
+
+    /// Output BAM file
+    #[arg(short, long, value_name = "FILE")]
+    pub output: String,
+
+    /// Format of the output (default: from its extension)
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub output_format: Option<output::OutputFormat>,
+
+    /// Reference FASTA for CRAM input or output
+    #[arg(long, value_name = "FASTA")]
+    pub reference: Option<String>,
+
+    /// Extra htslib threads for decompressing the input and compressing the output
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub threads: usize,
+
+    /// Read buffer for the input, in KiB
+    #[arg(long, value_name = "KIB", default_value_t = input::DEFAULT_BUFFER_KIB)]
+    pub input_buffer: usize,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheMetricsArgs {
+    /// Input BAM file (must be name-sorted)
+    #[arg(short, long, value_name = "FILE")]
+    pub input: String,
+
+    /// Metric cache to write
+    #[arg(short, long, value_name = "FILE")]
+    pub output: String,
+
+    /// Compute complexity on a C->T collapsed alphabet for bisulfite/EM-seq data
+    #[arg(long)]
+    pub bisulfite: bool,
+
+    /// With --bisulfite, collapse G->A for original-bottom-strand pairs
+    #[arg(long, requires = "bisulfite")]
+    pub bisulfite_strand_aware: bool,
+
+    /// Let N operations join exons into one mapped stretch
+    #[arg(long)]
+    pub splice_aware: bool,
+
+    /// Kmer length of the complexity metric
+    #[arg(long, value_name = "K", default_value_t = KMER_SIZE)]
+    pub kmer_size: usize,
+
+    /// Count a kmer and its reverse complement as the same kmer
+    #[arg(long)]
+    pub canonical: bool,
+
+    /// Read buffer for the input, in KiB (also sizes the kernel buffer of a pipe)
+    #[arg(long, value_name = "KIB", default_value_t = input::DEFAULT_BUFFER_KIB)]
+    pub input_buffer: usize,
+
+    /// Reference FASTA for CRAM input
+    #[arg(long, value_name = "FASTA")]
+    pub reference: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TuneArgs {
+    /// Metric cache written by cache-metrics
+    #[arg(value_name = "FILE")]
+    pub cache: String,
+
+    /// Complexity cutoffs to try, comma-separated
+    #[arg(
+        short,
+        long,
+        value_name = "C",
+        value_delimiter = ',',
+        default_value = "0.8"
+    )]
+    pub complexity: Vec<f64>,
+
+    /// Min-mapped cutoffs to try, comma-separated
+    #[arg(
+        short,
+        long,
+        value_name = "BP",
+        value_delimiter = ',',
+        default_value = "0"
+    )]
+    pub min_mapped: Vec<u32>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DumpAuditArgs {
+    /// Audit written with --audit-format binary
+    #[arg(value_name = "FILE")]
+    pub input: String,
+
+    /// Write the TSV here instead of standard output
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct MergeStatsArgs {
+    /// Stats JSON files written with --stats-json
+    #[arg(required = true, value_name = "FILE")]
+    pub inputs: Vec<String>,
+
+    /// Write the merged statistics as JSON
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+
+    /// Write the merged summary numbers as samtools-stats SN lines
+    #[arg(long, value_name = "FILE")]
+    pub stats_sn: Option<String>,
+
+    /// Layout version of the merged JSON
+    #[arg(long, value_name = "N", default_value_t = report::SCHEMA_VERSION)]
+    pub report_schema_version: u32,
+
+    /// How the console summary prints counts; stats files always hold plain digits
+    #[arg(long, value_enum, value_name = "UNITS", default_value = "raw")]
+    pub report_units: units::ReportUnits,
+
+    /// Decimal places of percentages (and human-readable counts) in the console summary
+    #[arg(long, value_name = "N", default_value_t = units::DEFAULT_DECIMALS)]
+    pub report_decimals: usize,
+}
+
+/// Subcommand names, which config arguments are inserted after
+pub const SUBCOMMANDS: &[&str] = &["check-config"];
+
+/// `check-config`: check the options of a filtering run given as `argv`
+pub fn check_config(mut args: Args, argv: &[String]) -> Result<()> {
+    args.read_group_configs = read_group_configs(argv)?;
+    args.name_fastq_input();
+    check::check_config(&args)
+}
+
+/// A filtering run with the options `argv` parsed into, returning the signal
+/// that interrupted it, if any
+///
+/// Outputs are finalized before it returns, so the caller can then exit with
+/// the signal's status.
+pub fn run_filter(mut args: Args, argv: &[String]) -> Result<Option<i32>> {
+    args.read_group_configs = read_group_configs(argv)?;
+    args.name_fastq_input();
+    if args.provenance {
+        args.parameters = provenance_parameters(argv);
+    }
+    if let Some(prefix) = args.contig_worker.clone() {
+        args = contig_worker_args(args, &prefix);
+    }
+    args.thread_pool = output::thread_pool(args.threads)?;
+    let result = if args.parallel_contigs > 0 {
+        run_parallel_contigs(&args, argv)
+    } else {
+        pipeline::run(&args)
+    };
+    if let (Err(e), Some(url)) = (&result, &args.notify_webhook) {
+        notify::notify(url, &notify::failed(&args.input, e), args.notify_timeout());
+    }
+    result
+}
+
+/// Filter settings per `[read-group ID]` config section, on top of the run's options
+pub fn read_group_configs(argv: &[String]) -> Result<Vec<(String, filter::FilterConfig)>> {
+    config::read_group_args(argv)?
+        .into_iter()
+        .map(|(read_group, overrides)| {
+            let context = || format!("[read-group {}] in the config file", read_group);
+            // Appended last, so they win over the command line too
+            let cli = Cli::try_parse_from(argv.iter().chain(&overrides)).with_context(context)?;
+            let args = match (cli.command, cli.args) {
+                (Some(Command::CheckConfig(args)), _) | (None, Some(args)) => args,
+                _ => unreachable!("read-group sections are only read for filtering"),
+            };
+            let config = args.filter_config().with_context(context)?;
+            config.validate().with_context(context)?;
+            Ok((read_group, config))
+        })
+        .collect()
+}
+
+/// The value of every filter option in `argv` and where it came from
+pub fn provenance_parameters(
+    argv: &[String],
+) -> std::collections::BTreeMap<String, provenance::Parameter> {
+    let command = Cli::command();
+    let matches = command.clone().get_matches_from(argv);
+    command
+        .get_arguments()
+        .filter_map(|arg| {
+            let id = arg.get_id().as_str();
+            let name = arg.get_long()?;
+            if name == "help" {
+                return None;
+            }
+            let values: Vec<String> = matches
+                .try_get_raw(id)
+                .ok()
+                .flatten()
+                .into_iter()
+                .flatten()
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect();
+            let source = match matches.value_source(id) {
+                Some(clap::parser::ValueSource::CommandLine) => "command line",
+                Some(clap::parser::ValueSource::EnvVariable) => "environment",
+                Some(_) => "default",
+                None => "not given",
+            };
+            Some((
+                format!("--{}", name),
+                provenance::Parameter { values, source },
+            ))
+        })
+        .collect()
+}
+
+/// Sum the statistics of several runs and report them as one
+pub fn merge_stats(args: &MergeStatsArgs) -> Result<()> {
+    report::check_schema_version(args.report_schema_version)?;
+    let mut merged = report::Report {
+        schema_version: args.report_schema_version,
+        ..report::Report::default()
+    };
+    for path in &args.inputs {
+        merged.merge(&report::Report::read_json(path)?);
+    }
+
+    println!("=== Merged Statistics ({} runs) ===", args.inputs.len());
+    if merged.interrupted {
+        println!("Interrupted: true (at least one run)");
+    }
+    if merged.preview {
+        println!("Preview: true (at least one run)");
+    }
+    merged.print(units::NumberFormat {
+        units: args.report_units,
+        decimals: args.report_decimals,
+    });
+
+    if let Some(path) = &args.output {
+        merged.write_json(path)?;
+        println!("\nJSON statistics: {}", path);
+    }
+    if let Some(path) = &args.stats_sn {
+        merged.sequences.write_sn(
+            path,
+            merged.unit(),
+            merged.total_pairs,
+            merged.kept_pairs,
+            merged.interrupted,
+        )?;
+        println!("SN statistics: {}", path);
+    }
+    Ok(())
+}
+
+/// Write a binary `--audit` file as TSV
+pub fn dump_audit(args: &DumpAuditArgs) -> Result<()> {
+    match &args.output {
+        Some(path) => {
+            let file =
+                std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+            let pairs = audit::dump_binary(&args.input, &mut std::io::BufWriter::new(file))?;
+            eprintln!("Wrote {} pairs to {}", pairs, path);
+        }
+        None => {
+            audit::dump_binary(&args.input, &mut std::io::stdout().lock())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the original BAM minus the pairs of a decision file
+pub fn apply(args: &ApplyArgs) -> Result<()> {
+    if args.output == args.input {
+        anyhow::bail!("The output must differ from the input");
+    }
+    let thread_pool = output::thread_pool(args.threads)?;
+    let mut reader = input::open(
+        &args.input,
+        args.input_buffer,
+        args.reference.as_deref(),
+        thread_pool.as_ref(),
+    )?;
+    let mut header = bam::Header::from_template(reader.header());
+    header::add_program_record(&mut header);
+    let encoding = output::Encoding {
+        format: args.output_format,
+        reference: args.reference.clone(),
+        thread_pool,
+    };
+    let mut writer = encoding.open(&args.output, &header)?;
+    let applied = decisions::apply(&args.decisions, &mut reader, |record| {
+        writer
+            .write(record)
+            .with_context(|| format!("Cannot write {}", args.output))
+    })?;
+    drop(writer);
+    println!(
+        "Wrote {} of {} records to {} ({} of {} decided pairs or reads removed)",
+        applied.written, applied.records, args.output, applied.removed, applied.units
+    );
+    Ok(())
+}
+
+/// Pass 1 of two-pass tuning: store every pair's metrics
+pub fn cache_metrics(args: &CacheMetricsArgs) -> Result<()> {
+    let config = filter::FilterConfig {
+        kmer_size: args.kmer_size,
+        canonical: args.canonical,
+        bisulfite: args.bisulfite,
+        bisulfite_strand_aware: args.bisulfite_strand_aware,
+        splice_aware: args.splice_aware,
+        ..filter::FilterConfig::default()
+    };
+    config.validate()?;
+    let mut reader = input::open(
+        &args.input,
+        args.input_buffer,
+        args.reference.as_deref(),
+        None,
+    )?;
+    let mut cache = metric_cache::MetricCacheWriter::create(&args.output, &config)?;
+
+    // Grouped as the filtering run groups them, so the cache has one entry per pair
+    let mut grouper = grouping::Grouper::new();
+    let mut pairs = 0u64;
+    while let Some(template) = grouper.next_template(&mut reader) {
+        let template = template.with_context(|| format!("Cannot read {}", args.input))?;
+        let (record1, record2) = (&template.record1, &template.record2);
+        cache.write_pair(record1, &config.measure(record1, record2))?;
+        pairs += 1;
+    }
+    grouper.finish()?;
+    cache.finish()?;
+    println!("Cached metrics of {} pairs in {}", pairs, args.output);
+    Ok(())
+}
+
+/// Pass rates for a grid of thresholds, from a metric cache alone
+pub fn tune(args: &TuneArgs) -> Result<()> {
+    let grid = metric_cache::tune(&args.cache, &args.complexity, &args.min_mapped)?;
+    println!(
+        "=== Pass Rates from Metric Cache ({} pairs) ===",
+        grid.pairs
+    );
+    println!(
+        "{:>10} {:>10} {:>12} {:>10}",
+        "Complexity", "Min mapped", "Kept", "Pass rate"
+    );
+    for (row, cutoff) in grid.complexity.iter().enumerate() {
+        for (column, min) in grid.min_mapped.iter().enumerate() {
+            let kept = grid.kept[row * grid.min_mapped.len() + column];
+            let pass_rate = if grid.pairs > 0 {
+                kept as f64 / grid.pairs as f64 * 100.0
+            } else {
+                0.0
+            };
+            println!(
+                "{:>10.3} {:>10} {:>12} {:>9.2}%",
+                cutoff, min, kept, pass_rate
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The options of a `--parallel-contigs` worker: its BAM and statistics go
+/// to part files under `prefix`, from which the coordinating run writes the
+/// real ones, and only the coordinating run notifies
+pub fn contig_worker_args(mut args: Args, prefix: &str) -> Args {
+    if args.output.is_some() {
+        args.output = Some(format!("{}.bam", prefix));
+        args.output_format = Some(output::OutputFormat::Bam);
+    }
+    args.stats_json = Some(format!("{}.json", prefix));
+    // Tables are still gathered, but written once from the merged report
+    for (table, name) in [
+        (&mut args.stats_sn, "sn"),
+        (&mut args.bx_stats, "bx.tsv"),
+        (&mut args.family_size_histogram, "families.tsv"),
+        (&mut args.sample_stats, "samples.tsv"),
+        (&mut args.ont_stats, "ont.tsv"),
+        (&mut args.clip_profile, "clips.tsv"),
+        (&mut args.gc_profile, "gc.tsv"),
+        (&mut args.target_stats, "targets.tsv"),
+    ] {
+        if table.is_some() {
+            *table = Some(format!("{}.{}", prefix, name));
+        }
+    }
+    args.parallel_contigs = 0;
+    args.notify_webhook = None;
+    args.provenance = false;
+    args
+}
+
+/// Filter the input by contig in `--parallel-contigs` worker processes and
+/// merge their outputs, returning the signal that interrupted the run, if any
+pub fn run_parallel_contigs(args: &Args, argv: &[String]) -> Result<Option<i32>> {
+    let started = std::time::Instant::now();
+    let started_at = std::time::SystemTime::now();
+    args.validate()?;
+    let output_path = args.output.as_deref();
+    if output_path == Some(output::STDOUT) || output_path.is_some_and(output::is_template) {
+        anyhow::bail!(
+            "--parallel-contigs needs a single output path, not standard output or a template"
+        );
+    }
+    let reader = input::open(
+        &args.input,
+        args.input_buffer,
+        args.reference.as_deref(),
+        None,
+    )?;
+    if !regions::is_coordinate_sorted(reader.header()) {
+        anyhow::bail!("--parallel-contigs reads a coordinate-sorted input; this one's header does not declare coordinate order");
+    }
+    let mut header = bam::Header::from_template(reader.header());
+    drop(reader);
+    // The workers list the runs; stopping here saves starting them
+    if !header::previous_runs(&header).is_empty() && !args.force && !args.dry_run {
+        anyhow::bail!(
+            "Input was already processed by {}; pass --force to filter it again",
+            header::PROGRAM_NAME
+        );
+    }
+    header::add_program_record(&mut header);
+
+    let unit = args.unit();
+    let jobs = contigs::plan(&args.input, args.parallel_contigs)?;
+    println!("Filtering {} by contig in parallel", args.input);
+    println!(
+        "  Workers: {} for {} job(s) of {} sequence(s)",
+        args.parallel_contigs,
+        jobs.len(),
+        jobs.iter().map(|job| job.sequences.len()).sum::<usize>()
+    );
+    match output_path {
+        Some(path) => println!("  Output BAM: {}", path),
+        None => println!("  Output BAM: none"),
+    }
+    let work_dir = tmp::WorkDir::create(&tmp::tmp_root(args.tmp_dir.as_deref()))?;
+    let interrupt = signals::Interrupt::install()?;
+
+    // Largest jobs first, so that the last to finish are small
+    let mut queue: Vec<usize> = (0..jobs.len()).collect();
+    queue.sort_by_key(|&index| std::cmp::Reverse(jobs[index].records));
+    let mut queue = queue.into_iter();
+    let worker_args = contigs::worker_args(argv);
+    let program = std::env::current_exe().context("Cannot find the running executable")?;
+    let mut workers = contigs::Workers::new(args.parallel_contigs);
+    let mut finished = vec![false; jobs.len()];
+    let mut forwarded = None;
+    let mut warnings: Vec<String> = Vec::new();
+    loop {
+        let stop = interrupt.received();
+        if let (Some(signal), None) = (stop, forwarded) {
+            // Workers of a terminal's Ctrl-C have it already; a kill reaches only this process
+            workers.signal(signal);
+            forwarded = Some(signal);
+        }
+        while stop.is_none() && workers.has_room() {
+            let Some(index) = queue.next() else {
+                break;
+            };
+            let prefix = contigs::part_prefix(work_dir.path(), index);
+            let mut command = std::process::Command::new(&program);
+            command
+                .args(&worker_args)
+                .arg("--contig-worker")
+                .arg(&prefix);
+            for sequence in &jobs[index].sequences {
+                command.arg("--region").arg(sequence);
+            }
+            workers.start(index, command, &prefix.with_extension("log"))?;
+        }
+        if workers.is_idle() {
+            break;
+        }
+        let done = workers.wait()?;
+        let log = contigs::part_prefix(work_dir.path(), done.index).with_extension("log");
+        let log = std::fs::read_to_string(&log).unwrap_or_default();
+        match done.status.code() {
+            Some(0) => finished[done.index] = true,
+            // Stopped by the signal, with whole pairs written
+            Some(code) if stop.is_some() && code >= 128 => finished[done.index] = true,
+            _ => {
+                // The run fails either way; the others need not finish
+                workers.signal(signal_hook::consts::SIGTERM);
+                eprint!("{}", log);
+                anyhow::bail!(
+                    "The --parallel-contigs worker for {} failed ({})",
+                    jobs[done.index].sequences.join(", "),
+                    done.status
+                );
+            }
+        }
+        // Warnings once, however many workers gave them
+        for line in log.lines() {
+            if (line.starts_with("Warning:") || line.starts_with("Note:"))
+                && !warnings.iter().any(|seen| seen == line)
+            {
+                warnings.push(line.to_string());
+            }
+        }
+    }
+    for warning in &warnings {
+        eprintln!("{}", warning);
+    }
+
+    // Parts in header order, each in the order its worker kept the pairs
+    let mut report = report::Report {
+        schema_version: args.report_schema_version,
+        ..report::Report::default()
+    };
+    let mut writer = output_path
+        .map(|path| args.encoding().open(path, &header))
+        .transpose()?;
+    let mut record = bam::Record::new();
+    for index in (0..jobs.len()).filter(|&index| finished[index]) {
+        let prefix = contigs::part_prefix(work_dir.path(), index);
+        let part = report::Report::read_json(&format!("{}.json", prefix.display()))?;
+        report.merge(&part);
+        if let Some(writer) = writer.as_mut() {
+            let path = format!("{}.bam", prefix.display());
+            let mut part = bam::Reader::from_path(&path)
+                .with_context(|| format!("Cannot open the worker output {}", path))?;
+            while let Some(result) = part.read(&mut record) {
+                result.with_context(|| format!("Cannot read the worker output {}", path))?;
+                writer.write(&record)?;
+            }
+        }
+    }
+    drop(writer);
+    let skipped = finished.iter().filter(|&&done| !done).count();
+    if skipped > 0 {
+        report.interrupted = true;
+        eprintln!(
+            "Warning: {} of {} contig job(s) did not run before the interruption",
+            skipped,
+            jobs.len()
+        );
+    }
+
+    println!("\n=== Filtering Statistics ===");
+    report.print(units::NumberFormat {
+        units: args.report_units,
+        decimals: args.report_decimals,
+    });
+    pipeline::write_report_files(args, &report)?;
+    match output_path {
+        Some(path) => println!("\nOutput file: {}", path),
+        None => println!("\nOutput BAM: none"),
+    }
+    if let Some(path) = &args.stats_json {
+        println!("JSON statistics: {}", path);
+    }
+    if let Some(path) = &args.stats_sn {
+        println!("SN statistics: {}", path);
+    }
+    let outputs: Vec<String> = output_path.map(str::to_string).into_iter().collect();
+    if let Some(path) = pipeline::write_provenance(args, &outputs, started_at, report.interrupted)?
+    {
+        println!("Provenance: {}", path);
+    }
+    println!(
+        "Filtered {} {} in {:.1} s over {} contig job(s)",
+        report.total_pairs,
+        unit,
+        started.elapsed().as_secs_f64(),
+        jobs.len()
+    );
+    if let Some(url) = &args.notify_webhook {
+        notify::notify(
+            url,
+            &notify::completed(&args.input, &report),
+            args.notify_timeout(),
+        );
+    }
+    Ok(interrupt.received())
+}
@@ -0,0 +1,181 @@
+//! Alternative read complexity metrics (`--complexity-method`)
+//!
+//! The unique/total kmer ratio needs reads well over twice the kmer size to
+//! tell repeats from unique sequence. DUST (as in sdust and prinseq) and
+//! Shannon entropy (as in bbduk's `entropy=`) work on short words instead,
+//! over sliding windows of `--complexity-window` bases; a read scores as its
+//! least complex window, so a low-complexity stretch inside an otherwise
+//! normal read still counts.
+//!
+//! Both are scaled to 0..1 with higher meaning more complex, so
+//! `--complexity`, the `xc` tag and the histograms work for every method:
+//!
+//! - DUST counts the triplets of a window; with `c_t` copies of triplet `t`
+//!   among `l`, the raw score `sum(c_t (c_t - 1) / 2) / (l - 1)` is at most
+//!   `l / 2`, reached by a homopolymer. The complexity is
+//!   `1 - score / (l / 2)`, the fraction of triplet pairs that differ. An
+//!   sdust threshold `T` over its 64-base windows is roughly
+//!   `--complexity 1 - T / 31`, a prinseq `-lc_threshold` of `P` roughly
+//!   `1 - P / 100`.
+//! - Entropy is the Shannon entropy of the window's words of
+//!   `--entropy-word-size` bases, divided by its maximum for that many words,
+//!   as bbduk does.
+//!
+//! Words containing a base other than A, C, G or T are not counted. A window
+//! with fewer than two countable words has complexity 0.
+
+use clap::ValueEnum;
+
+/// Window length when `--complexity-window` is not given, as in sdust
+pub const DEFAULT_WINDOW: usize = 64;
+
+/// Entropy word length when `--entropy-word-size` is not given, as in bbduk
+pub const DEFAULT_ENTROPY_WORD_SIZE: usize = 5;
+
+/// Longest entropy word; counts for every word of this length are kept
+pub const MAX_ENTROPY_WORD_SIZE: usize = 8;
+
+/// Word length of DUST
+const DUST_WORD_SIZE: usize = 3;
+
+/// How the complexity of a read is measured
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ComplexityMethod {
+    /// Unique kmers over total kmers, kmers of --kmer-size
+    #[default]
+    KmerUniqueness,
+    /// DUST triplet score of the least complex window, scaled to 0..1
+    Dust,
+    /// Normalized Shannon entropy of --entropy-word-size words, least complex window
+    ShannonEntropy,
+}
+
+/// 2-bit code of a base, `None` for anything but A, C, G and T
+fn base_code(base: u8) -> Option<usize> {
+    match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+/// Code of every word of length `w` of `sequence`
+fn word_codes(sequence: &[u8], w: usize) -> Vec<Option<usize>> {
+    if sequence.len() < w {
+        return Vec::new();
+    }
+    sequence
+        .windows(w)
+        .map(|word| {
+            word.iter()
+                .try_fold(0, |code, &base| Some(code << 2 | base_code(base)?))
+        })
+        .collect()
+}
+
+/// A metric derived from a running sum over the word counts of a window
+trait WindowScore {
+    /// Change of the sum when a word's count goes from `count` to `count + 1`
+    fn delta(count: u32) -> f64;
+    /// Complexity of a window of `words` countable words, at least two
+    fn complexity(sum: f64, words: u32, w: usize) -> f64;
+}
+
+/// Sum of `c (c - 1) / 2`, the pairs of identical triplets
+struct Dust;
+
+impl WindowScore for Dust {
+    fn delta(count: u32) -> f64 {
+        count as f64
+    }
+
+    fn complexity(sum: f64, words: u32, _: usize) -> f64 {
+        let words = words as f64;
+        1.0 - 2.0 * sum / (words * (words - 1.0))
+    }
+}
+
+/// Sum of `c ln c`; the entropy of `n` words is `ln n - sum / n`
+struct Entropy;
+
+impl WindowScore for Entropy {
+    fn delta(count: u32) -> f64 {
+        let next = (count + 1) as f64;
+        let current = if count == 0 {
+            0.0
+        } else {
+            count as f64 * (count as f64).ln()
+        };
+        next * next.ln() - current
+    }
+
+    fn complexity(sum: f64, words: u32, w: usize) -> f64 {
+        let n = words as f64;
+        // The most distinct words a window of n can hold
+        let max = n.min(4f64.powi(w as i32)).ln();
+        ((n.ln() - sum / n) / max).clamp(0.0, 1.0)
+    }
+}
+
+/// The lowest window complexity over `sequence`, for words of length `w`
+fn worst_window<S: WindowScore>(sequence: &[u8], w: usize, window: usize) -> f64 {
+    let codes = word_codes(sequence, w);
+    let per_window = if window == 0 {
+        codes.len()
+    } else {
+        window.saturating_sub(w - 1).max(1).min(codes.len())
+    };
+    if per_window == 0 {
+        return 0.0;
+    }
+
+    let mut counts = vec![0u32; 1 << (2 * w)];
+    let (mut sum, mut words) = (0.0, 0u32);
+    let mut worst = f64::INFINITY;
+    for (end, code) in codes.iter().enumerate() {
+        if let Some(code) = *code {
+            sum += S::delta(counts[code]);
+            counts[code] += 1;
+            words += 1;
+        }
+        if end >= per_window {
+            if let Some(code) = codes[end - per_window] {
+                counts[code] -= 1;
+                sum -= S::delta(counts[code]);
+                words -= 1;
+            }
+        }
+        if end + 1 >= per_window {
+            let complexity = if words < 2 {
+                0.0
+            } else {
+                S::complexity(sum, words, w)
+            };
+            worst = worst.min(complexity);
+        }
+    }
+    worst
+}
+
+/// DUST complexity of `sequence`: 1 minus the scaled triplet score of its
+/// least complex window of `window` bases (0 = the whole read)
+pub fn dust_complexity(sequence: &[u8], window: usize) -> f64 {
+    worst_window::<Dust>(sequence, DUST_WORD_SIZE, window)
+}
+
+/// Normalized Shannon entropy of the words of length `w` in the least
+/// complex window of `window` bases (0 = the whole read)
+pub fn entropy_complexity(sequence: &[u8], w: usize, window: usize) -> f64 {
+    worst_window::<Entropy>(sequence, w, window)
+}
+
+/// Shortest read the method can score: one kmer, or two DUST or entropy words
+pub fn min_read_length(method: ComplexityMethod, kmer_size: usize, entropy_word: usize) -> usize {
+    match method {
+        ComplexityMethod::KmerUniqueness => kmer_size,
+        ComplexityMethod::Dust => DUST_WORD_SIZE + 1,
+        ComplexityMethod::ShannonEntropy => entropy_word + 1,
+    }
+}
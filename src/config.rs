@@ -0,0 +1,204 @@
+//! Config files holding option defaults
+//!
+//! A config file has one `key = value` per line, where the key is a long
+//! option name (`min-mapped = 90`, or `min_mapped`). Boolean flags take
+//! `true`/`false`. Blank lines and `#` comments, at the start of a line or
+//! after whitespace, are ignored; a value can be quoted to keep ` #` in it.
+//! Options given on the command line take precedence over the config file.
+//!
+//! Lines after a `[read-group ID]` header apply only to pairs whose first
+//! mate has that `RG` tag, and override the options of the whole run, so a
+//! merged BAM can hold HiFi and Illumina read groups with their own
+//! thresholds. Only [`READ_GROUP_OPTIONS`] can be set there.
+
+use anyhow::{bail, Context, Result};
+
+/// Per-pair thresholds that a `[read-group ID]` section may set
+pub const READ_GROUP_OPTIONS: &[&str] = &[
+    "complexity",
+    "min-mapped",
+    "max-splice-junctions",
+    "min-mapped-fraction",
+    "max-clip-fraction",
+    "min-gap-compressed-identity",
+    "max-de",
+    "length-basis",
+    "min-mapq",
+    "min-avg-baseq",
+    "min-baseq-fraction",
+    "min-insert",
+    "max-insert",
+    "require-same-reference",
+    "max-nm",
+    "max-error-rate",
+    "missing-nm",
+    "filter-expr",
+];
+
+/// Arguments of a config file: the whole run's, then each read group's
+#[derive(Debug, Default)]
+struct ConfigFile {
+    args: Vec<String>,
+    read_groups: Vec<(String, Vec<String>)>,
+}
+
+/// `line` without its comment: a `#` at the start of the line or after
+/// whitespace, outside double quotes, so a value can hold `#` (`a#b`, `"a #b"`)
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut after_space = true;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if after_space && !quoted => return &line[..index],
+            _ => {}
+        }
+        after_space = c.is_whitespace();
+    }
+    line
+}
+
+fn parse_config(path: &str) -> Result<ConfigFile> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("Cannot read config {}", path))?;
+    let mut config = ConfigFile::default();
+    // Index into `config.read_groups` of the current section
+    let mut section: Option<usize> = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let mut words = header.split_whitespace();
+            let (Some("read-group" | "read_group"), Some(id), None) =
+                (words.next(), words.next(), words.next())
+            else {
+                bail!(
+                    "{}:{}: expected a `[read-group ID]` section header",
+                    path,
+                    number + 1
+                );
+            };
+            section = Some(
+                match config.read_groups.iter().position(|(seen, _)| seen == id) {
+                    Some(index) => index,
+                    None => {
+                        config.read_groups.push((id.to_string(), Vec::new()));
+                        config.read_groups.len() - 1
+                    }
+                },
+            );
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("{}:{}: expected `key = value`", path, number + 1);
+        };
+        let key = key.trim().replace('_', "-");
+        let value = value.trim().trim_matches('"');
+
+        if key == "config" {
+            bail!(
+                "{}:{}: config files cannot include other configs",
+                path,
+                number + 1
+            );
+        }
+
+        let args = match section {
+            Some(index) => {
+                if !READ_GROUP_OPTIONS.contains(&key.as_str()) {
+                    bail!(
+                        "{}:{}: {} cannot be set per read group (allowed: {})",
+                        path,
+                        number + 1,
+                        key,
+                        READ_GROUP_OPTIONS.join(", ")
+                    );
+                }
+                &mut config.read_groups[index].1
+            }
+            None => &mut config.args,
+        };
+        match value {
+            "true" => args.push(format!("--{}", key)),
+            "false" => {}
+            _ => args.push(format!("--{}={}", key, value)),
+        }
+    }
+
+    Ok(config)
+}
+
+/// Translate a config file into the equivalent command-line arguments
+pub fn config_args(path: &str) -> Result<Vec<String>> {
+    Ok(parse_config(path)?.args)
+}
+
+/// The `[read-group ID]` sections of the `--config` in `args`, as arguments per read group
+pub fn read_group_args(args: &[String]) -> Result<Vec<(String, Vec<String>)>> {
+    match option_value(args, "config") {
+        Some(path) => Ok(parse_config(&path)?.read_groups),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Options implied by a `--preset`
+///
+/// Presets are expanded ahead of config files and the command line, so any of
+/// their values can still be overridden.
+pub fn preset_args(name: &str) -> Result<Vec<String>> {
+    match name {
+        // Hi-C contacts are judged on the mapping confidence of both mates.
+        // Trans and long-range pairs are valid contacts, so no insert-size,
+        // orientation or proper-pair requirements are added.
+        "hic" => Ok(vec!["--min-mapq=30".to_string()]),
+        _ => bail!("Unknown preset {}", name),
+    }
+}
+
+/// Value of the last `--name VALUE` or `--name=VALUE` in `args`
+fn option_value(args: &[String], name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let prefix = format!("--{}=", name);
+    let mut value = None;
+    for (i, arg) in args.iter().enumerate() {
+        if let Some(v) = arg.strip_prefix(&prefix) {
+            value = Some(v.to_string());
+        } else if *arg == flag {
+            value = args.get(i + 1).cloned();
+        }
+    }
+    value
+}
+
+/// Expand `--preset NAME` and `--config FILE` into arguments placed before the
+/// user's own
+///
+/// The expanded arguments go right after the program name (or subcommand) in
+/// the order preset, config, command line, so later values override earlier
+/// ones when the parser lets the last occurrence of an option win.
+pub fn expand_config_args(args: Vec<String>, subcommands: &[&str]) -> Result<Vec<String>> {
+    let config = match option_value(&args, "config") {
+        Some(path) => config_args(&path)?,
+        None => Vec::new(),
+    };
+
+    // A preset may also be chosen in the config file
+    let preset = match option_value(&args, "preset").or_else(|| option_value(&config, "preset")) {
+        Some(name) => preset_args(&name)?,
+        None => Vec::new(),
+    };
+
+    let insert_at = match args.get(1) {
+        Some(first) if subcommands.contains(&first.as_str()) => 2,
+        _ => 1,
+    };
+
+    let mut expanded = args;
+    expanded.splice(insert_at..insert_at, preset.into_iter().chain(config));
+    Ok(expanded)
+}
@@ -0,0 +1,230 @@
+//! Filtering a coordinate-sorted, indexed input in parallel by contig
+//! (`--parallel-contigs`)
+//!
+//! The sequences of the index are split into jobs of roughly even record
+//! counts, whole sequences each, with the records without a position as a
+//! sequence of their own. Every job runs as a worker process of the binary,
+//! reading its sequences through the index as `--region` does and pairing
+//! mates there, independent of the other jobs, into a BAM and a stats JSON in
+//! the work directory. Up to N workers run at once, largest jobs first.
+//! The parts are then concatenated into the output in sequence order and the
+//! reports merged as `merge-stats` does.
+//!
+//! A pair with mates on two sequences is read by the jobs of both; it
+//! belongs to the job of the lower tid, or of the unplaced records when a
+//! mate has no position, and the other job leaves it out (see [`Owned`]).
+
+use crate::regions::Regions;
+use anyhow::{bail, Context, Result};
+use rust_htslib::{bam, bam::Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::Duration;
+
+/// How often the running workers are checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Jobs per worker to aim for, so that small sequences even out the big ones
+const JOBS_PER_WORKER: u64 = 4;
+
+/// Sequences filtered by one worker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    /// Names as `--region` takes them; `*` for the unplaced records
+    pub sequences: Vec<String>,
+    /// Records of the sequences in the index
+    pub records: u64,
+}
+
+/// Split `sequences` (name and record count, in header order) into jobs for
+/// `workers` workers: a sequence of at least a share of the records is a job
+/// of its own, and smaller neighbours are packed together up to that share
+///
+/// Jobs come in header order; sequences without records are left out.
+pub fn pack(sequences: &[(String, u64)], workers: usize) -> Vec<Job> {
+    let total: u64 = sequences.iter().map(|(_, records)| records).sum();
+    let share = (total / (workers as u64 * JOBS_PER_WORKER).max(1)).max(1);
+    let mut jobs: Vec<Job> = Vec::new();
+    let mut packing: Option<Job> = None;
+    for (name, records) in sequences {
+        if *records == 0 {
+            continue;
+        }
+        if *records >= share {
+            jobs.extend(packing.take());
+            jobs.push(Job {
+                sequences: vec![name.clone()],
+                records: *records,
+            });
+            continue;
+        }
+        let job = packing.get_or_insert_with(|| Job {
+            sequences: Vec::new(),
+            records: 0,
+        });
+        job.sequences.push(name.clone());
+        job.records += records;
+        if job.records >= share {
+            jobs.extend(packing.take());
+        }
+    }
+    jobs.extend(packing);
+    jobs
+}
+
+/// Jobs for the sequences in the index of `path`
+pub fn plan(path: &str, workers: usize) -> Result<Vec<Job>> {
+    let mut reader = bam::IndexedReader::from_path(path).with_context(|| {
+        format!(
+            "Cannot open the index of {}; index it with samtools index for --parallel-contigs",
+            path
+        )
+    })?;
+    let stats = reader
+        .index_stats()
+        .with_context(|| format!("Cannot read the index statistics of {}", path))?;
+    let header = reader.header();
+    let sequences: Vec<(String, u64)> = stats
+        .iter()
+        .map(|&(tid, _, mapped, unmapped)| {
+            let name = match u32::try_from(tid) {
+                Ok(tid) => String::from_utf8_lossy(header.tid2name(tid)).into_owned(),
+                Err(_) => "*".to_string(),
+            };
+            (name, mapped + unmapped)
+        })
+        .collect();
+    Ok(pack(&sequences, workers))
+}
+
+/// The pairs a worker keeps: those whose owning sequence is one of its jobs
+pub struct Owned {
+    tids: Vec<bool>,
+    unplaced: bool,
+}
+
+impl Owned {
+    /// Ownership for a worker reading `regions`
+    pub fn new(regions: &Regions, header: &bam::HeaderView) -> Self {
+        let mut tids = vec![false; header.target_count() as usize];
+        for tid in regions.tids() {
+            tids[tid as usize] = true;
+        }
+        Owned {
+            tids,
+            unplaced: regions.unplaced,
+        }
+    }
+
+    fn owns_tid(&self, tid: i32) -> bool {
+        match usize::try_from(tid) {
+            Ok(tid) => self.tids.get(tid).copied().unwrap_or(false),
+            Err(_) => self.unplaced,
+        }
+    }
+
+    /// Whether a record filtered on its own belongs to this worker
+    pub fn owns(&self, record: &bam::Record) -> bool {
+        self.owns_tid(record.tid())
+    }
+
+    /// Whether a pair belongs to this worker: the unplaced job's when a mate
+    /// has no position, as only that job reads both mates, else the job of
+    /// the lower tid
+    pub fn owns_pair(&self, record1: &bam::Record, record2: &bam::Record) -> bool {
+        let owner = if record1.tid() < 0 || record2.tid() < 0 {
+            -1
+        } else {
+            record1.tid().min(record2.tid())
+        };
+        self.owns_tid(owner)
+    }
+}
+
+/// The arguments of a worker: those of the run after the program name,
+/// without `--parallel-contigs`, which rules out the `--region` it is given
+pub fn worker_args(argv: &[String]) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut rest = argv.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        if arg == "--parallel-contigs" {
+            rest.next();
+        } else if !arg.starts_with("--parallel-contigs=") {
+            args.push(arg.clone());
+        }
+    }
+    args
+}
+
+/// Where job `index` writes its BAM and stats JSON, without the extension
+pub fn part_prefix(work_dir: &Path, index: usize) -> PathBuf {
+    work_dir.join(format!("contigs.{}", index))
+}
+
+/// A worker's end
+pub struct Finished {
+    pub index: usize,
+    pub status: ExitStatus,
+}
+
+/// Runs jobs as worker processes, `workers` at a time
+pub struct Workers {
+    workers: usize,
+    running: Vec<(usize, Child)>,
+}
+
+impl Workers {
+    pub fn new(workers: usize) -> Self {
+        Workers {
+            workers: workers.max(1),
+            running: Vec::new(),
+        }
+    }
+
+    /// Whether another worker can start
+    pub fn has_room(&self) -> bool {
+        self.running.len() < self.workers
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Start job `index` as `command`, with its output to `log`
+    pub fn start(&mut self, index: usize, mut command: Command, log: &Path) -> Result<()> {
+        let log = std::fs::File::create(log)
+            .with_context(|| format!("Cannot create {}", log.display()))?;
+        let child = command
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()
+            .context("Cannot start a --parallel-contigs worker")?;
+        self.running.push((index, child));
+        Ok(())
+    }
+
+    /// Wait for the next worker to end
+    pub fn wait(&mut self) -> Result<Finished> {
+        if self.running.is_empty() {
+            bail!("No --parallel-contigs worker is running");
+        }
+        loop {
+            for at in 0..self.running.len() {
+                if let Some(status) = self.running[at].1.try_wait()? {
+                    let (index, _) = self.running.swap_remove(at);
+                    return Ok(Finished { index, status });
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Pass a termination signal on to every running worker
+    pub fn signal(&self, signal: i32) {
+        for (_, child) in &self.running {
+            // SAFETY: kill only sends a signal to the worker
+            unsafe { libc::kill(child.id() as i32, signal) };
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Decision files: the verdicts of a run without the BAM (`--decisions`, `apply`)
+//!
+//! Most pairs of a typical run are kept, so rewriting the whole input to
+//! drop a few percent of it mostly copies bytes. A decision file stores only
+//! the difference, the names of the removed pairs (or reads) in input order,
+//! in a BGZF stream that is a small fraction of the BAM's size. `apply` later
+//! streams the original BAM and writes every record except the removed ones:
+//!
+//! ```text
+//! file:  b"FBPD" version:u8 entry* end
+//! entry: records:u8 name_len:u16 name:[u8]
+//! end:   0:u8 units:u64 removed:u64
+//! ```
+//!
+//! Numbers are little-endian. `records` is 2 for a pair plus its secondary
+//! and supplementary records, and 1 for a read of a `--single-end` run; the
+//! end gives the pairs (or reads) the run decided on and how many it
+//! removed, so a truncated file is caught. Records the run skipped without
+//! a verdict, such as orphans under `--resync`, are not listed and `apply`
+//! writes them.
+
+use crate::fastq::create_bgzf;
+use crate::sink::OutputSink;
+use anyhow::{bail, Context, Result};
+use rust_htslib::{bam, bgzf};
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 4] = b"FBPD";
+const VERSION: u8 = 1;
+
+/// Records one decision per pair (or read) it is offered, as an output sink
+pub struct DecisionWriter {
+    out: bgzf::Writer,
+    units: u64,
+    removed: u64,
+}
+
+impl DecisionWriter {
+    pub fn create(path: &str) -> Result<Self> {
+        let mut out = create_bgzf(path)?;
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(DecisionWriter {
+            out,
+            units: 0,
+            removed: 0,
+        })
+    }
+
+    fn decide(&mut self, name: &[u8], records: u8, kept: bool) -> Result<()> {
+        self.units += 1;
+        if kept {
+            return Ok(());
+        }
+        let len = u16::try_from(name.len()).context("Read name too long for a decision file")?;
+        self.out.write_all(&[records])?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(name)?;
+        self.removed += 1;
+        Ok(())
+    }
+
+    /// Pairs (or reads) decided on so far
+    pub fn units(&self) -> u64 {
+        self.units
+    }
+
+    /// Pairs (or reads) removed so far
+    pub fn removed(&self) -> u64 {
+        self.removed
+    }
+}
+
+impl OutputSink for DecisionWriter {
+    fn write_pair(&mut self, record1: &bam::Record, _: &bam::Record, kept: bool) -> Result<()> {
+        self.decide(record1.qname(), 2, kept)
+    }
+
+    fn write_template(
+        &mut self,
+        record1: &bam::Record,
+        _: &bam::Record,
+        extras: &[bam::Record],
+        kept: bool,
+    ) -> Result<()> {
+        let records = u8::try_from(2 + extras.len())
+            .context("Too many secondary and supplementary records for a decision file")?;
+        self.decide(record1.qname(), records, kept)
+    }
+
+    fn write_read(&mut self, record: &bam::Record, kept: bool) -> Result<()> {
+        self.decide(record.qname(), 1, kept)
+    }
+
+    /// Write the end marker; the file is complete only after this
+    fn finish(&mut self) -> Result<()> {
+        self.out.write_all(&[0])?;
+        self.out.write_all(&self.units.to_le_bytes())?;
+        self.out.write_all(&self.removed.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Fill `buf`, or fail on a file that ends early
+fn read_exact<R: Read>(input: &mut R, buf: &mut [u8], path: &str) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..])? {
+            0 => bail!("{}: decision file is truncated", path),
+            n => filled += n,
+        }
+    }
+    Ok(())
+}
+
+/// Reads the removed pairs (or reads) of a decision file in order
+pub struct DecisionReader {
+    input: bgzf::Reader,
+    path: String,
+    /// `(units, removed)` from the end marker, once it is reached
+    end: Option<(u64, u64)>,
+    entries: u64,
+}
+
+impl DecisionReader {
+    pub fn open(path: &str) -> Result<Self> {
+        let mut input =
+            bgzf::Reader::from_path(path).with_context(|| format!("Cannot open {}", path))?;
+        let mut header = [0u8; 5];
+        read_exact(&mut input, &mut header, path)
+            .with_context(|| format!("{} is not a filter_bam_pairs decision file", path))?;
+        if &header[..4] != MAGIC {
+            bail!("{} is not a filter_bam_pairs decision file", path);
+        }
+        if header[4] != VERSION {
+            bail!("{}: unsupported decision file version {}", path, header[4]);
+        }
+        Ok(DecisionReader {
+            input,
+            path: path.to_string(),
+            end: None,
+            entries: 0,
+        })
+    }
+
+    /// The next removed unit as its record count, with its name in `name`,
+    /// or `None` after the last
+    pub fn next_removed(&mut self, name: &mut Vec<u8>) -> Result<Option<u8>> {
+        if self.end.is_some() {
+            return Ok(None);
+        }
+        let mut records = [0u8];
+        read_exact(&mut self.input, &mut records, &self.path)?;
+        if records[0] == 0 {
+            let mut end = [0u8; 16];
+            read_exact(&mut self.input, &mut end, &self.path)?;
+            let units = u64::from_le_bytes(end[..8].try_into().expect("8 bytes"));
+            let removed = u64::from_le_bytes(end[8..].try_into().expect("8 bytes"));
+            if removed != self.entries {
+                bail!(
+                    "{}: the end marker counts {} removals but the file lists {}",
+                    self.path,
+                    removed,
+                    self.entries
+                );
+            }
+            self.end = Some((units, removed));
+            return Ok(None);
+        }
+        let mut len = [0u8; 2];
+        read_exact(&mut self.input, &mut len, &self.path)?;
+        name.resize(u16::from_le_bytes(len) as usize, 0);
+        read_exact(&mut self.input, name, &self.path)?;
+        self.entries += 1;
+        Ok(Some(records[0]))
+    }
+
+    /// Pairs (or reads) the run decided on, known after the last entry
+    pub fn units(&self) -> Option<u64> {
+        self.end.map(|(units, _)| units)
+    }
+}
+
+/// Counts from applying a decision file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Applied {
+    /// Records read from the input
+    pub records: u64,
+    /// Records written
+    pub written: u64,
+    /// Pairs (or reads) removed
+    pub removed: u64,
+    /// Pairs (or reads) the filtering run decided on
+    pub units: u64,
+}
+
+/// Pass every record of `reader` to `write` except those the decision file
+/// at `path` removes
+pub fn apply<R: bam::Read>(
+    path: &str,
+    reader: &mut R,
+    mut write: impl FnMut(&bam::Record) -> Result<()>,
+) -> Result<Applied> {
+    let mut decisions = DecisionReader::open(path)?;
+    let mut applied = Applied::default();
+    let mut name = Vec::new();
+    let mut records = decisions.next_removed(&mut name)?.unwrap_or(0);
+    let mut remaining = records;
+    let mismatch = |name: &[u8]| {
+        anyhow::anyhow!(
+            "{} removes {}, which the input does not have at that point; \
+             apply a decision file to the BAM it was made from",
+            path,
+            String::from_utf8_lossy(name)
+        )
+    };
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.read(&mut record) {
+        result.context("Cannot read the input BAM")?;
+        applied.records += 1;
+        if remaining > 0 && record.qname() == name.as_slice() {
+            remaining -= 1;
+            if remaining == 0 {
+                applied.removed += 1;
+                records = decisions.next_removed(&mut name)?.unwrap_or(0);
+                remaining = records;
+            }
+            continue;
+        }
+        // A removed pair whose mate is missing
+        if remaining < records {
+            return Err(mismatch(&name));
+        }
+        write(&record)?;
+        applied.written += 1;
+    }
+    if remaining > 0 {
+        return Err(mismatch(&name));
+    }
+    applied.units = decisions
+        .units()
+        .with_context(|| format!("{}: decision file is truncated", path))?;
+    Ok(applied)
+}
@@ -0,0 +1,94 @@
+//! Coarse coverage from a quick pass, for removing pairs in pathologically
+//! deep regions (`--max-region-depth`)
+//!
+//! Collapsed repeats pile far more reads onto a locus than the genome-wide
+//! depth, yet their reads can be complex and uniquely placed. A pass before
+//! filtering adds each mapped read's reference span to fixed-size bins; a
+//! bin's mean depth is its covered bases divided by the bin size.
+
+use crate::input;
+use anyhow::Result;
+use rust_htslib::{bam, bam::Read};
+
+/// Mean depth per fixed-size bin of every reference sequence
+pub struct DepthSketch {
+    bin_size: i64,
+    /// Covered bases per bin, by reference id
+    bins: Vec<Vec<u32>>,
+}
+
+impl DepthSketch {
+    /// Read `path` once, counting primary mapped reads
+    pub fn build(path: &str, reference: Option<&str>, bin_size: u32) -> Result<Self> {
+        let mut reader = input::reopen(path, reference)?;
+        let header = reader.header().clone();
+        let bin_size = bin_size.max(1) as i64;
+        let mut sketch = DepthSketch {
+            bin_size,
+            bins: (0..header.target_count())
+                .map(|tid| {
+                    let len = header.target_len(tid).unwrap_or(0) as i64;
+                    vec![0; ((len + bin_size - 1) / bin_size) as usize]
+                })
+                .collect(),
+        };
+
+        let mut record = bam::Record::new();
+        while let Some(result) = reader.read(&mut record) {
+            result?;
+            if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
+                continue;
+            }
+            sketch.add(&record);
+        }
+        Ok(sketch)
+    }
+
+    fn add(&mut self, record: &bam::Record) {
+        let Some(bins) = usize::try_from(record.tid())
+            .ok()
+            .and_then(|tid| self.bins.get_mut(tid))
+        else {
+            return;
+        };
+        let (start, end) = (record.pos(), record.cigar().end_pos());
+        let mut pos = start;
+        while pos < end {
+            let bin = pos / self.bin_size;
+            let bin_end = ((bin + 1) * self.bin_size).min(end);
+            if let Some(covered) = bins.get_mut(bin as usize) {
+                *covered = covered.saturating_add((bin_end - pos) as u32);
+            }
+            pos = bin_end;
+        }
+    }
+
+    /// Highest mean depth among the bins a mapped record spans
+    pub fn max_depth(&self, record: &bam::Record) -> f64 {
+        if record.is_unmapped() {
+            return 0.0;
+        }
+        let Some(bins) = usize::try_from(record.tid())
+            .ok()
+            .and_then(|tid| self.bins.get(tid))
+        else {
+            return 0.0;
+        };
+        let first = (record.pos() / self.bin_size) as usize;
+        let last = ((record.cigar().end_pos() - 1).max(record.pos()) / self.bin_size) as usize;
+        bins.get(first..=last.min(bins.len().saturating_sub(1)))
+            .into_iter()
+            .flatten()
+            .map(|&covered| covered as f64 / self.bin_size as f64)
+            .fold(0.0, f64::max)
+    }
+
+    /// Bins with a mean depth above `max`
+    pub fn deep_bins(&self, max: f64) -> usize {
+        self.bins
+            .iter()
+            .flatten()
+            .filter(|&&covered| covered as f64 / self.bin_size as f64 > max)
+            .count()
+    }
+}
@@ -0,0 +1,176 @@
+//! Nanopore duplex and simplex reads, from dorado's `dx` tag
+//!
+//! dorado duplex basecalling writes every read with `dx:i`: `1` for a duplex
+//! read, called from both strands of a molecule, `0` for a simplex read and
+//! `-1` for a simplex read whose strand went into a duplex read (a duplex
+//! parent). Duplex reads are far more accurate, so their quality and
+//! complexity profiles differ from the simplex ones: the report gives each
+//! class apart, `--duplex-reads` keeps one class or drops the parents, which
+//! repeat the bases of their duplex read, and `{dx}` in an output path
+//! writes `duplex` and `simplex` reads to their own files. Reads without a
+//! `dx` tag come from simplex basecalling and count as simplex, but are left
+//! out of the per-class statistics. Pairs are attributed to their first mate.
+
+use crate::quality;
+use crate::stats::aux_integer;
+use crate::units::NumberFormat;
+use clap::ValueEnum;
+use rust_htslib::bam;
+use serde::{Deserialize, Serialize};
+
+/// Aux tag of dorado's duplex class (`dx:i`)
+pub const TAG_DUPLEX: &[u8] = b"dx";
+
+/// A read's class by its `dx` tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplexClass {
+    /// `dx:i:1`, called from both strands
+    Duplex,
+    /// `dx:i:0` or no tag
+    Simplex,
+    /// `dx:i:-1`, a simplex read whose strand is part of a duplex read
+    Parent,
+}
+
+impl DuplexClass {
+    pub fn of(record: &bam::Record) -> Self {
+        match aux_integer(record, TAG_DUPLEX) {
+            Some(1) => DuplexClass::Duplex,
+            Some(-1) => DuplexClass::Parent,
+            _ => DuplexClass::Simplex,
+        }
+    }
+
+    /// The `{dx}` path component: parents are simplex reads
+    pub fn path_component(self) -> &'static str {
+        match self {
+            DuplexClass::Duplex => "duplex",
+            DuplexClass::Simplex | DuplexClass::Parent => "simplex",
+        }
+    }
+}
+
+/// Which reads `--duplex-reads` keeps
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DuplexReads {
+    /// Every read, whatever its class
+    #[default]
+    All,
+    /// Only duplex reads (dx:i:1)
+    Duplex,
+    /// Only simplex reads, parents included (dx:i:0, dx:i:-1 or no tag)
+    Simplex,
+    /// Every read but the simplex parents of duplex reads (dx:i:-1)
+    NoParents,
+}
+
+impl DuplexReads {
+    pub fn keeps(self, class: DuplexClass) -> bool {
+        match self {
+            DuplexReads::All => true,
+            DuplexReads::Duplex => class == DuplexClass::Duplex,
+            DuplexReads::Simplex => class != DuplexClass::Duplex,
+            DuplexReads::NoParents => class != DuplexClass::Parent,
+        }
+    }
+}
+
+/// Counts and sums of one class
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ClassStats {
+    pub reads: u64,
+    pub kept: u64,
+    /// Reads that failed the complexity threshold
+    pub low_complexity: u64,
+    pub bases: u64,
+    /// Sum of the per-read mean base qualities, over `with_quality` reads
+    pub quality_sum: f64,
+    pub with_quality: u64,
+}
+
+impl ClassStats {
+    fn merge(&mut self, other: &ClassStats) {
+        self.reads += other.reads;
+        self.kept += other.kept;
+        self.low_complexity += other.low_complexity;
+        self.bases += other.bases;
+        self.quality_sum += other.quality_sum;
+        self.with_quality += other.with_quality;
+    }
+
+    fn mean_length(&self) -> Option<f64> {
+        (self.reads > 0).then(|| self.bases as f64 / self.reads as f64)
+    }
+
+    fn mean_quality(&self) -> Option<f64> {
+        (self.with_quality > 0).then(|| self.quality_sum / self.with_quality as f64)
+    }
+}
+
+/// Per-class statistics of the reads carrying a `dx` tag
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DuplexStats {
+    pub duplex: ClassStats,
+    pub simplex: ClassStats,
+    pub parents: ClassStats,
+}
+
+impl DuplexStats {
+    /// Whether no read carried a `dx` tag
+    pub fn is_empty(&self) -> bool {
+        self.duplex.reads + self.simplex.reads + self.parents.reads == 0
+    }
+
+    pub fn record(&mut self, record: &bam::Record, low_complexity: bool, kept: bool) {
+        if aux_integer(record, TAG_DUPLEX).is_none() {
+            return;
+        }
+        let class = match DuplexClass::of(record) {
+            DuplexClass::Duplex => &mut self.duplex,
+            DuplexClass::Simplex => &mut self.simplex,
+            DuplexClass::Parent => &mut self.parents,
+        };
+        class.reads += 1;
+        class.kept += kept as u64;
+        class.low_complexity += low_complexity as u64;
+        class.bases += record.seq_len() as u64;
+        if let Some(mean) = quality::mean_base_quality(record) {
+            class.quality_sum += mean;
+            class.with_quality += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &DuplexStats) {
+        self.duplex.merge(&other.duplex);
+        self.simplex.merge(&other.simplex);
+        self.parents.merge(&other.parents);
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        println!("\n=== Duplex and Simplex Reads (dx) ===");
+        println!(
+            "{:<16} {:>12} {:>12} {:>10} {:>14} {:>12} {:>8}",
+            "Class", "Reads", "Kept", "Pass rate", "Low complexity", "Mean length", "Mean Q"
+        );
+        for (name, class) in [
+            ("duplex", &self.duplex),
+            ("simplex", &self.simplex),
+            ("simplex parent", &self.parents),
+        ] {
+            if class.reads == 0 {
+                continue;
+            }
+            let mean = |value: Option<f64>| value.map_or("-".to_string(), |v| format!("{:.1}", v));
+            println!(
+                "{:<16} {:>12} {:>12} {:>10} {:>14} {:>12} {:>8}",
+                name,
+                format.count(class.reads),
+                format.count(class.kept),
+                format.share(class.kept, class.reads),
+                format.count(class.low_complexity),
+                mean(class.mean_length()),
+                mean(class.mean_quality())
+            );
+        }
+    }
+}
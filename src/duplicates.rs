@@ -0,0 +1,218 @@
+//! Positional duplicate structure: library complexity extrapolation and
+//! sampled duplicate-rate estimation
+
+use rust_htslib::bam;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+/// Unclipped 5' position and strand of a mapped read
+fn five_prime_end(record: &bam::Record) -> (i32, i64, bool) {
+    let cigar = record.cigar();
+    let pos = if record.is_reverse() {
+        cigar.end_pos() + cigar.trailing_softclips() - 1
+    } else {
+        record.pos() - cigar.leading_softclips()
+    };
+    (record.tid(), pos, record.is_reverse())
+}
+
+/// Hash of a pair's unclipped 5' ends, independent of mate order
+///
+/// Pairs with either mate unmapped have no positional identity and yield `None`.
+pub fn fragment_key(record1: &bam::Record, record2: &bam::Record) -> Option<u64> {
+    if record1.is_unmapped() || record2.is_unmapped() {
+        return None;
+    }
+
+    let a = five_prime_end(record1);
+    let b = five_prime_end(record2);
+    let mut hasher = DefaultHasher::new();
+    (a.min(b), a.max(b)).hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Sequencing depths (as multiples of the observed depth) shown in the curve
+const CURVE_DEPTHS: [f64; 8] = [0.25, 0.5, 0.75, 1.0, 2.0, 5.0, 10.0, 20.0];
+
+/// Counts how often each fragment position is seen to extrapolate complexity
+#[derive(Debug, Default)]
+pub struct LibraryComplexity {
+    fragments: HashMap<u64, u32>,
+}
+
+impl LibraryComplexity {
+    pub fn record(&mut self, record1: &bam::Record, record2: &bam::Record) {
+        if let Some(key) = fragment_key(record1, record2) {
+            *self.fragments.entry(key).or_insert(0) += 1;
+        }
+    }
+
+    /// Number of fragments observed `j` times, indexed by `j`
+    fn duplicate_histogram(&self) -> Vec<u64> {
+        let max = self.fragments.values().copied().max().unwrap_or(0) as usize;
+        let mut histogram = vec![0u64; max + 1];
+        for &count in self.fragments.values() {
+            histogram[count as usize] += 1;
+        }
+        histogram
+    }
+
+    /// Lander-Waterman library size: solves `C/X = 1 - exp(-N/X)` for X
+    ///
+    /// `total` is N, the pairs observed, and `distinct` C, the distinct
+    /// fragments among them. The size is truncated to whole fragments, as
+    /// Picard's `EstimateLibraryComplexity` and `MarkDuplicates` report it.
+    /// Returns `None` when there are no duplicates, i.e. the library is not
+    /// saturated enough to bound its size.
+    pub fn estimated_library_size(total: u64, distinct: u64) -> Option<u64> {
+        if distinct == 0 || distinct >= total {
+            return None;
+        }
+        let (total, distinct) = (total as f64, distinct as f64);
+
+        let f = |x: f64| distinct / x - 1.0 + (-total / x).exp();
+        let mut lo = 1.0;
+        let mut hi = 100.0;
+        while f(hi * distinct) > 0.0 {
+            hi *= 10.0;
+        }
+
+        for _ in 0..100 {
+            let mid = (lo + hi) / 2.0;
+            if f(mid * distinct) > 0.0 {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Some((distinct * (lo + hi) / 2.0) as u64)
+    }
+
+    pub fn print(&self) {
+        let histogram = self.duplicate_histogram();
+        let distinct = self.fragments.len() as f64;
+        let total: f64 = histogram
+            .iter()
+            .enumerate()
+            .map(|(j, &n)| j as f64 * n as f64)
+            .sum();
+
+        println!("\n=== Library Complexity (pairs with both mates mapped) ===");
+        println!("Observed fragments: {}", total as u64);
+        println!("Distinct fragments: {}", distinct as u64);
+        if total == 0.0 {
+            return;
+        }
+        println!(
+            "Duplicate fraction: {:.2}%",
+            (1.0 - distinct / total) * 100.0
+        );
+
+        let library_size = Self::estimated_library_size(total as u64, distinct as u64);
+        match library_size {
+            Some(size) => println!("Estimated library size: {}", size),
+            None => println!("Estimated library size: n/a (no duplicates observed)"),
+        }
+
+        println!(
+            "{:>8} {:>16} {:>20}",
+            "Depth", "Fragments", "Expected distinct"
+        );
+        for t in CURVE_DEPTHS {
+            let expected = if t <= 1.0 {
+                // Exact expectation when subsampling the observed fragments
+                histogram
+                    .iter()
+                    .enumerate()
+                    .map(|(j, &n)| n as f64 * (1.0 - (1.0 - t).powi(j as i32)))
+                    .sum::<f64>()
+            } else if let Some(size) = library_size {
+                let size = size as f64;
+                size * (1.0 - (-t * total / size).exp())
+            } else {
+                continue;
+            };
+            println!("{:>7.2}x {:>16.0} {:>20.0}", t, t * total, expected);
+        }
+    }
+}
+
+/// Observed and distinct fragments among the sampled positions
+#[derive(Debug, Default)]
+struct SampledFragments {
+    observed: u64,
+    distinct: HashSet<u64>,
+}
+
+impl SampledFragments {
+    fn add(&mut self, key: u64) {
+        self.observed += 1;
+        self.distinct.insert(key);
+    }
+
+    fn duplicate_rate(&self) -> Option<f64> {
+        (self.observed > 0).then(|| 1.0 - self.distinct.len() as f64 / self.observed as f64)
+    }
+}
+
+/// Estimates the PCR duplicate rate from a hash-selected subset of positions
+///
+/// Selecting by fragment key keeps every copy of a sampled fragment, so the
+/// duplicate rate within the sample is an unbiased estimate of the overall
+/// rate while memory shrinks by the sampling factor.
+#[derive(Debug)]
+pub struct DuplicateRateEstimator {
+    sample_rate: u64,
+    input: SampledFragments,
+    kept: SampledFragments,
+}
+
+impl DuplicateRateEstimator {
+    /// Sample one in `sample_rate` fragment positions
+    pub fn new(sample_rate: u64) -> Self {
+        DuplicateRateEstimator {
+            sample_rate: sample_rate.max(1),
+            input: SampledFragments::default(),
+            kept: SampledFragments::default(),
+        }
+    }
+
+    pub fn record(&mut self, record1: &bam::Record, record2: &bam::Record, kept: bool) {
+        let Some(key) = fragment_key(record1, record2) else {
+            return;
+        };
+        if key % self.sample_rate != 0 {
+            return;
+        }
+
+        self.input.add(key);
+        if kept {
+            self.kept.add(key);
+        }
+    }
+
+    pub fn print(&self) {
+        let format_rate = |sample: &SampledFragments| match sample.duplicate_rate() {
+            Some(rate) => format!("{:.2}%", rate * 100.0),
+            None => "-".to_string(),
+        };
+
+        println!(
+            "\n=== Estimated Duplicate Rate (sampled 1/{} positions) ===",
+            self.sample_rate
+        );
+        println!(
+            "Sampled fragments: {} input, {} kept",
+            self.input.observed, self.kept.observed
+        );
+        println!(
+            "Input duplicate rate (estimate): {}",
+            format_rate(&self.input)
+        );
+        println!(
+            "Kept duplicate rate (estimate): {}",
+            format_rate(&self.kept)
+        );
+    }
+}
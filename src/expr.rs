@@ -0,0 +1,591 @@
+//! `--filter-expr`: a small expression language over both mates' fields
+//!
+//! Expressions combine numbers, mate fields (`r1.mapq`, `r2.complexity`)
+//! and numeric aux tags (`r1.tag.AS`) with arithmetic, comparisons and
+//! `&&`/`||`/`!`, plus `abs`, `min`, `max` and `has`. Every value is a
+//! number; comparisons and logic yield 1 or 0, and a pair passes when the
+//! expression is non-zero.
+//!
+//! A field or tag without a mate (`mapq`, `tag.NM`) refers to the read being
+//! judged: the expression is then evaluated once per mate and the pair
+//! passes when it holds for both, like the threshold options.
+//!
+//! ```text
+//! r1.mapq >= 20 && r2.mapq >= 20 && abs(r1.pos - r2.pos) < 1000
+//! min(r1.complexity, r2.complexity) >= 0.7 || r1.tid != r2.tid
+//! complexity >= 0.8 && (mapq >= 20 || tag.AS >= 100)
+//! ```
+
+use anyhow::{bail, Result};
+
+/// Which mate a field is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mate {
+    R1,
+    R2,
+}
+
+impl Mate {
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Per-mate values an expression can refer to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// Mapping quality
+    Mapq,
+    /// 1-based leftmost position (0 when unplaced)
+    Pos,
+    /// 1-based rightmost aligned position
+    End,
+    /// Reference index (-1 when unplaced)
+    Tid,
+    /// SAM FLAG
+    Flag,
+    /// Template length (TLEN, signed)
+    Tlen,
+    /// Stored sequence length
+    Length,
+    /// Exact kmer complexity
+    Complexity,
+    /// Longest contiguous mapped stretch
+    LongestMapped,
+    /// Clipped bases under `--length-basis`
+    Clipped,
+    /// 1 when unmapped
+    Unmapped,
+    /// 1 when on the reverse strand
+    Reverse,
+    /// Aligned identity, exact from an `=`/`X` CIGAR, else from `NM`
+    Identity,
+    /// Mismatched bases, from an `=`/`X` CIGAR, else from `NM`
+    Mismatches,
+}
+
+impl Field {
+    pub const COUNT: usize = 14;
+
+    const NAMES: [(&'static str, Field); Field::COUNT] = [
+        ("mapq", Field::Mapq),
+        ("pos", Field::Pos),
+        ("end", Field::End),
+        ("tid", Field::Tid),
+        ("flag", Field::Flag),
+        ("tlen", Field::Tlen),
+        ("length", Field::Length),
+        ("complexity", Field::Complexity),
+        ("longest_mapped", Field::LongestMapped),
+        ("clipped", Field::Clipped),
+        ("unmapped", Field::Unmapped),
+        ("reverse", Field::Reverse),
+        ("identity", Field::Identity),
+        ("mismatches", Field::Mismatches),
+    ];
+
+    /// Other names of fields, as other tools call them
+    const ALIASES: [(&'static str, Field); 1] = [("longest_match", Field::LongestMapped)];
+
+    fn from_name(name: &str) -> Option<Field> {
+        Field::NAMES
+            .iter()
+            .chain(&Field::ALIASES)
+            .find(|(n, _)| *n == name)
+            .map(|&(_, field)| field)
+    }
+
+    fn names() -> String {
+        Field::NAMES
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Source of field values while evaluating an expression
+pub trait Fields {
+    fn value(&mut self, mate: Mate, field: Field) -> f64;
+
+    /// Numeric value of an aux tag; NaN when it is missing or not a number
+    fn tag(&mut self, _mate: Mate, _tag: [u8; 2]) -> f64 {
+        f64::NAN
+    }
+
+    /// Whether the mate carries the aux tag, of any type
+    fn has_tag(&mut self, _mate: Mate, _tag: [u8; 2]) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Abs,
+    Min,
+    Max,
+}
+
+/// The mate a field is read from; `None` is the read being judged
+type MateRef = Option<Mate>;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f64),
+    Field(MateRef, Field),
+    Tag(MateRef, [u8; 2]),
+    HasTag(MateRef, [u8; 2]),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+/// A parsed filter expression
+#[derive(Debug, Clone)]
+pub struct Expr {
+    source: String,
+    root: Node,
+    /// Some field has no mate, so the expression is judged per read
+    per_read: bool,
+}
+
+fn truth(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+impl Node {
+    /// The value with mate-less fields read from `read`
+    fn eval(&self, fields: &mut dyn Fields, read: Mate) -> f64 {
+        match self {
+            Node::Number(v) => *v,
+            Node::Field(mate, field) => fields.value(mate.unwrap_or(read), *field),
+            Node::Tag(mate, tag) => fields.tag(mate.unwrap_or(read), *tag),
+            Node::HasTag(mate, tag) => truth(fields.has_tag(mate.unwrap_or(read), *tag)),
+            Node::Not(node) => truth(node.eval(fields, read) == 0.0),
+            Node::Negate(node) => -node.eval(fields, read),
+            // Logic short-circuits so expensive fields on the right are only
+            // computed when they matter
+            Node::Binary(BinaryOp::And, a, b) => {
+                truth(a.eval(fields, read) != 0.0 && b.eval(fields, read) != 0.0)
+            }
+            Node::Binary(BinaryOp::Or, a, b) => {
+                truth(a.eval(fields, read) != 0.0 || b.eval(fields, read) != 0.0)
+            }
+            Node::Binary(op, a, b) => {
+                let (a, b) = (a.eval(fields, read), b.eval(fields, read));
+                match op {
+                    BinaryOp::Eq => truth(a == b),
+                    BinaryOp::Ne => truth(a != b),
+                    BinaryOp::Lt => truth(a < b),
+                    BinaryOp::Le => truth(a <= b),
+                    BinaryOp::Gt => truth(a > b),
+                    BinaryOp::Ge => truth(a >= b),
+                    BinaryOp::Add => a + b,
+                    BinaryOp::Sub => a - b,
+                    BinaryOp::Mul => a * b,
+                    BinaryOp::Div => a / b,
+                    BinaryOp::And | BinaryOp::Or => unreachable!("handled above"),
+                }
+            }
+            Node::Call(function, args) => {
+                let mut values = args.iter().map(|arg| arg.eval(fields, read));
+                match function {
+                    Function::Abs => values.next().unwrap_or(0.0).abs(),
+                    Function::Min => values.fold(f64::INFINITY, f64::min),
+                    Function::Max => values.fold(f64::NEG_INFINITY, f64::max),
+                }
+            }
+        }
+    }
+
+    /// Whether some field or tag is read without a mate
+    fn is_per_read(&self) -> bool {
+        match self {
+            Node::Number(_) => false,
+            Node::Field(mate, _) | Node::Tag(mate, _) | Node::HasTag(mate, _) => mate.is_none(),
+            Node::Not(node) | Node::Negate(node) => node.is_per_read(),
+            Node::Binary(_, a, b) => a.is_per_read() || b.is_per_read(),
+            Node::Call(_, args) => args.iter().any(Node::is_per_read),
+        }
+    }
+
+    fn visit_fields(&self, visit: &mut dyn FnMut(MateRef, Field)) {
+        match self {
+            Node::Number(_) | Node::Tag(..) | Node::HasTag(..) => {}
+            Node::Field(mate, field) => visit(*mate, *field),
+            Node::Not(node) | Node::Negate(node) => node.visit_fields(visit),
+            Node::Binary(_, a, b) => {
+                a.visit_fields(visit);
+                b.visit_fields(visit);
+            }
+            Node::Call(_, args) => args.iter().for_each(|arg| arg.visit_fields(visit)),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            next: 0,
+        };
+        let root = parser.or()?;
+        if let Some(token) = parser.peek() {
+            bail!("--filter-expr: unexpected {} at the end", token.describe());
+        }
+        Ok(Expr {
+            source: source.to_string(),
+            per_read: root.is_per_read(),
+            root,
+        })
+    }
+
+    /// Whether the pair passes: the expression is non-zero, for both mates
+    /// when it is judged per read
+    pub fn matches(&self, fields: &mut dyn Fields) -> bool {
+        if self.per_read {
+            self.root.eval(fields, Mate::R1) != 0.0 && self.root.eval(fields, Mate::R2) != 0.0
+        } else {
+            self.root.eval(fields, Mate::R1) != 0.0
+        }
+    }
+
+    /// The value, with mate-less fields read from `read`
+    pub fn eval(&self, fields: &mut dyn Fields, read: Mate) -> f64 {
+        self.root.eval(fields, read)
+    }
+
+    /// Whether some field or tag has no mate, so each mate is judged alone
+    pub fn is_per_read(&self) -> bool {
+        self.per_read
+    }
+
+    /// Whether the expression reads `field` of either mate
+    pub fn uses(&self, field: Field) -> bool {
+        let mut used = false;
+        self.root.visit_fields(&mut |_, f| used |= f == field);
+        used
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Number(v) => format!("number {}", v),
+            Token::Ident(name) => format!("`{}`", name),
+            Token::Op(op) => format!("`{}`", op),
+            Token::LParen => "`(`".to_string(),
+            Token::RParen => "`)`".to_string(),
+            Token::Comma => "`,`".to_string(),
+        }
+    }
+}
+
+/// Operators, longest first so `<=` wins over `<`
+const OPERATORS: [&str; 14] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "+", "-", "*", "/", "=",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit()
+            || (c == b'.' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+        {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                i += 1;
+            }
+            // Exponents, e.g. 1e-3
+            if i < bytes.len() && (bytes[i] == b'e' || bytes[i] == b'E') {
+                i += 1;
+                if i < bytes.len() && (bytes[i] == b'-' || bytes[i] == b'+') {
+                    i += 1;
+                }
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let text = &source[start..i];
+            let value = text
+                .parse()
+                .map_err(|_| anyhow::anyhow!("--filter-expr: invalid number {}", text))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            while i < bytes.len()
+                && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || bytes[i] == b'.')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Ident(source[start..i].to_string()));
+        } else if c == b'(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == b')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else if c == b',' {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else if let Some(op) = OPERATORS.iter().find(|op| source[i..].starts_with(*op)) {
+            if *op == "=" {
+                bail!("--filter-expr: use `==` to compare (at offset {})", i);
+            }
+            tokens.push(Token::Op(op));
+            i += op.len();
+        } else {
+            bail!(
+                "--filter-expr: unexpected character `{}` at offset {}",
+                source[i..].chars().next().unwrap_or('?'),
+                i
+            );
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive-descent parser; each level handles one precedence tier
+struct Parser<'a> {
+    tokens: &'a [Token],
+    next: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn advance(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.next);
+        self.next += 1;
+        token
+    }
+
+    fn eat_op(&mut self, ops: &[(&str, BinaryOp)]) -> Option<BinaryOp> {
+        if let Some(Token::Op(op)) = self.peek() {
+            if let Some(&(_, binary)) = ops.iter().find(|(o, _)| o == op) {
+                self.next += 1;
+                return Some(binary);
+            }
+        }
+        None
+    }
+
+    fn binary_level(
+        &mut self,
+        ops: &[(&str, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Node>,
+    ) -> Result<Node> {
+        let mut node = operand(self)?;
+        while let Some(op) = self.eat_op(ops) {
+            node = Node::Binary(op, Box::new(node), Box::new(operand(self)?));
+        }
+        Ok(node)
+    }
+
+    fn or(&mut self) -> Result<Node> {
+        self.binary_level(&[("||", BinaryOp::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Node> {
+        self.binary_level(&[("&&", BinaryOp::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Node> {
+        const COMPARISONS: [(&str, BinaryOp); 6] = [
+            ("==", BinaryOp::Eq),
+            ("!=", BinaryOp::Ne),
+            ("<=", BinaryOp::Le),
+            (">=", BinaryOp::Ge),
+            ("<", BinaryOp::Lt),
+            (">", BinaryOp::Gt),
+        ];
+        let node = self.additive()?;
+        match self.eat_op(&COMPARISONS) {
+            Some(op) => {
+                let rhs = self.additive()?;
+                if self.eat_op(&COMPARISONS).is_some() {
+                    bail!("--filter-expr: comparisons can't be chained; combine them with &&");
+                }
+                Ok(Node::Binary(op, Box::new(node), Box::new(rhs)))
+            }
+            None => Ok(node),
+        }
+    }
+
+    fn additive(&mut self) -> Result<Node> {
+        self.binary_level(
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+            Self::multiplicative,
+        )
+    }
+
+    fn multiplicative(&mut self) -> Result<Node> {
+        self.binary_level(&[("*", BinaryOp::Mul), ("/", BinaryOp::Div)], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Node> {
+        match self.peek() {
+            Some(Token::Op("!")) => {
+                self.next += 1;
+                Ok(Node::Not(Box::new(self.unary()?)))
+            }
+            Some(Token::Op("-")) => {
+                self.next += 1;
+                Ok(Node::Negate(Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node> {
+        match self.advance().cloned() {
+            Some(Token::Number(v)) => Ok(Node::Number(v)),
+            Some(Token::LParen) => {
+                let node = self.or()?;
+                match self.advance() {
+                    Some(Token::RParen) => Ok(node),
+                    _ => bail!("--filter-expr: missing `)`"),
+                }
+            }
+            Some(Token::Ident(name)) if name == "has" && self.peek() == Some(&Token::LParen) => {
+                self.next += 1;
+                self.has()
+            }
+            Some(Token::Ident(name)) if self.peek() == Some(&Token::LParen) => {
+                self.next += 1;
+                self.call(&name)
+            }
+            Some(Token::Ident(name)) => variable(&name),
+            Some(token) => bail!("--filter-expr: unexpected {}", token.describe()),
+            None => bail!("--filter-expr: unexpected end of expression"),
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<Node> {
+        let (function, arity) = match name {
+            "abs" => (Function::Abs, 1..=1),
+            "min" => (Function::Min, 2..=usize::MAX),
+            "max" => (Function::Max, 2..=usize::MAX),
+            _ => bail!(
+                "--filter-expr: unknown function {}; use abs, min, max or has",
+                name
+            ),
+        };
+
+        let mut args = Vec::new();
+        if self.peek() != Some(&Token::RParen) {
+            loop {
+                args.push(self.or()?);
+                if self.peek() == Some(&Token::Comma) {
+                    self.next += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+        if self.advance() != Some(&Token::RParen) {
+            bail!("--filter-expr: missing `)` after arguments of {}", name);
+        }
+        if !arity.contains(&args.len()) {
+            bail!(
+                "--filter-expr: {} takes {} argument(s), got {}",
+                name,
+                if arity.end() == &1 { "1" } else { "2 or more" },
+                args.len()
+            );
+        }
+        Ok(Node::Call(function, args))
+    }
+
+    /// `has(tag.XX)`, after the `(`
+    fn has(&mut self) -> Result<Node> {
+        let node = match self.advance().cloned() {
+            Some(Token::Ident(name)) => variable(&name)?,
+            _ => bail!("--filter-expr: has takes a tag, e.g. has(tag.SA)"),
+        };
+        let Node::Tag(mate, tag) = node else {
+            bail!("--filter-expr: has takes a tag, e.g. has(tag.SA)");
+        };
+        if self.advance() != Some(&Token::RParen) {
+            bail!("--filter-expr: missing `)` after the tag of has");
+        }
+        Ok(Node::HasTag(mate, tag))
+    }
+}
+
+/// `<field>` or `tag.<XX>`, optionally after `r1.` or `r2.`
+fn variable(name: &str) -> Result<Node> {
+    let (mate, field) = match name.split_once('.') {
+        Some(("r1", field)) => (Some(Mate::R1), field),
+        Some(("r2", field)) => (Some(Mate::R2), field),
+        Some(("tag", _)) => (None, name),
+        None => (None, name),
+        _ => bail!(
+            "--filter-expr: unknown name {}; fields are <field>, r1.<field> or r2.<field>",
+            name
+        ),
+    };
+    if let Some(tag) = field.strip_prefix("tag.") {
+        let valid = tag.len() == 2
+            && tag.as_bytes()[0].is_ascii_alphabetic()
+            && tag.as_bytes()[1].is_ascii_alphanumeric();
+        if !valid {
+            bail!(
+                "--filter-expr: {} is not a SAM tag name (two characters, e.g. tag.NM)",
+                tag
+            );
+        }
+        return Ok(Node::Tag(mate, [tag.as_bytes()[0], tag.as_bytes()[1]]));
+    }
+    match Field::from_name(field) {
+        Some(field) => Ok(Node::Field(mate, field)),
+        None => bail!(
+            "--filter-expr: unknown field {} (available: {})",
+            field,
+            Field::names()
+        ),
+    }
+}
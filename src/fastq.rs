@@ -0,0 +1,137 @@
+//! FASTQ output for read pairs
+
+use crate::quality::has_qualities;
+use anyhow::{Context, Result};
+use rust_htslib::{bam, bgzf};
+use std::io::Write;
+
+/// Phred score written when a record has no stored qualities (as `samtools fastq`)
+const DEFAULT_QUALITY: u8 = 1;
+
+/// Complement of an IUPAC nucleotide, preserving case
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'T' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'a' => b't',
+        b't' => b'a',
+        b'c' => b'g',
+        b'g' => b'c',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        other => other,
+    }
+}
+
+/// Reverse complement of a sequence
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|&b| complement(b)).collect()
+}
+
+/// Orientation of reverse-strand reads in FASTQ output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Orientation {
+    /// As sequenced: reverse-strand reads are reverse-complemented back
+    #[default]
+    Sequenced,
+    /// As stored in the BAM, matching the reference strand
+    Aligned,
+}
+
+/// Write one record as a FASTQ entry
+///
+/// Reverse-strand alignments are stored reverse-complemented in BAM; with
+/// [`Orientation::Sequenced`] they are flipped back and their qualities
+/// reversed, so re-mapping sees the reads as they came off the sequencer.
+pub fn write_fastq_record<W: Write>(
+    out: &mut W,
+    record: &bam::Record,
+    orientation: Orientation,
+) -> std::io::Result<()> {
+    let mut seq = record.seq().as_bytes();
+    let mut qual: Vec<u8> = if has_qualities(record) {
+        record.qual().iter().map(|q| q + 33).collect()
+    } else {
+        vec![DEFAULT_QUALITY + 33; seq.len()]
+    };
+
+    if record.is_reverse() && orientation == Orientation::Sequenced {
+        seq = reverse_complement(&seq);
+        qual.reverse();
+    }
+
+    out.write_all(b"@")?;
+    out.write_all(record.qname())?;
+    out.write_all(b"\n")?;
+    out.write_all(&seq)?;
+    out.write_all(b"\n+\n")?;
+    out.write_all(&qual)?;
+    out.write_all(b"\n")
+}
+
+/// Open a BGZF-compressed file, reporting failures with the path
+pub(crate) fn create_bgzf(path: &str) -> Result<bgzf::Writer> {
+    // bgzf_open doesn't report why it failed, so surface errors up front
+    std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+    bgzf::Writer::from_path(path).with_context(|| format!("Cannot open {}", path))
+}
+
+/// Writes pairs to `{prefix}_R1.fastq.gz` and `{prefix}_R2.fastq.gz`
+pub struct FastqPairWriter {
+    r1: bgzf::Writer,
+    r2: bgzf::Writer,
+    orientation: Orientation,
+    /// Both entries of a pair are formatted before either is written
+    entries: [Vec<u8>; 2],
+}
+
+impl FastqPairWriter {
+    pub fn create(prefix: &str, orientation: Orientation) -> Result<Self> {
+        Ok(FastqPairWriter {
+            r1: create_bgzf(&format!("{}_R1.fastq.gz", prefix))?,
+            r2: create_bgzf(&format!("{}_R2.fastq.gz", prefix))?,
+            orientation,
+            entries: [Vec::new(), Vec::new()],
+        })
+    }
+
+    /// Write a pair, routing each mate by its first/second-in-template flag
+    pub fn write_pair(&mut self, record1: &bam::Record, record2: &bam::Record) -> Result<()> {
+        let (first, second) = if record2.is_first_in_template() && !record1.is_first_in_template() {
+            (record2, record1)
+        } else {
+            (record1, record2)
+        };
+
+        let [entry1, entry2] = &mut self.entries;
+        entry1.clear();
+        entry2.clear();
+        write_fastq_record(entry1, first, self.orientation)?;
+        write_fastq_record(entry2, second, self.orientation)?;
+        self.r1.write_all(entry1)?;
+        self.r2
+            .write_all(entry2)
+            .context("FASTQ mate 2 write failed after mate 1; outputs are out of step")?;
+        Ok(())
+    }
+
+    /// Flush both files, so that each holds every pair written
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Push buffered entries of both files to disk
+    pub fn flush(&mut self) -> Result<()> {
+        self.r1.flush()?;
+        self.r2.flush()?;
+        Ok(())
+    }
+}
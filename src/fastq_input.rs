@@ -0,0 +1,245 @@
+//! Paired FASTQ input (`--input-r1`, `--input-r2`)
+//!
+//! The kmer complexity needs no alignment, so junk pairs can be filtered
+//! before they are aligned. [`FastqPairs`] reads the two files in step and
+//! hands out each pair as two unmapped records (FLAG 77 and 141, as
+//! `samtools import` makes them) under the header of [`header`], so the rest
+//! of the run sees a name-grouped unaligned BAM. Files may be plain or
+//! gzip-compressed, BGZF or not.
+//!
+//! Read names end at the first space, and a `/1` or `/2` suffix is dropped;
+//! the mates of a pair must then have the same name. Comments after the name
+//! are not kept. Malformed entries and files out of step end the run.
+
+use anyhow::{bail, Context, Result};
+use rust_htslib::{bam, bgzf, errors::Error as HtsError};
+use std::io::{BufRead, BufReader};
+
+/// Encoding offset of FASTQ quality characters
+const PHRED_OFFSET: u8 = 33;
+
+/// Header of the records read from FASTQ: unsorted, mates next to each other
+pub fn header() -> bam::Header {
+    let mut header = bam::Header::new();
+    header.push_record(
+        bam::header::HeaderRecord::new(b"HD")
+            .push_tag(b"VN", "1.6")
+            .push_tag(b"SO", "unsorted")
+            .push_tag(b"GO", "query"),
+    );
+    header
+}
+
+/// Read a line into `buffer` without its line ending; false at the end of the file
+fn read_line(lines: &mut impl BufRead, buffer: &mut Vec<u8>) -> std::io::Result<bool> {
+    buffer.clear();
+    if lines.read_until(b'\n', buffer)? == 0 {
+        return Ok(false);
+    }
+    if buffer.last() == Some(&b'\n') {
+        buffer.pop();
+    }
+    if buffer.last() == Some(&b'\r') {
+        buffer.pop();
+    }
+    Ok(true)
+}
+
+/// One FASTQ file, read entry by entry
+struct FastqFile {
+    path: String,
+    lines: BufReader<bgzf::Reader>,
+    line: u64,
+    name: Vec<u8>,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+    /// The `+` line
+    separator: Vec<u8>,
+}
+
+impl FastqFile {
+    fn open(path: &str) -> Result<Self> {
+        // bgzf_open doesn't report why it failed, so surface errors up front
+        std::fs::File::open(path).with_context(|| format!("Cannot open {}", path))?;
+        let reader = bgzf::Reader::from_path(path)
+            .with_context(|| format!("Cannot open {} as FASTQ", path))?;
+        Ok(FastqFile {
+            path: path.to_string(),
+            lines: BufReader::with_capacity(1 << 16, reader),
+            line: 0,
+            name: Vec::new(),
+            seq: Vec::new(),
+            qual: Vec::new(),
+            separator: Vec::new(),
+        })
+    }
+
+    /// Read the next entry; false at the end of the file
+    fn next_entry(&mut self) -> Result<bool> {
+        let start = self.line + 1;
+        let context = || format!("Cannot read {} at line {}", self.path, start);
+        if !read_line(&mut self.lines, &mut self.name).with_context(context)? {
+            return Ok(false);
+        }
+        if self.name.first() != Some(&b'@') {
+            bail!(
+                "{} line {}: a FASTQ entry starts with '@'",
+                self.path,
+                start
+            );
+        }
+        let complete = read_line(&mut self.lines, &mut self.seq).with_context(context)?
+            && read_line(&mut self.lines, &mut self.separator).with_context(context)?
+            && read_line(&mut self.lines, &mut self.qual).with_context(context)?;
+        if !complete {
+            bail!("{} ends inside the entry at line {}", self.path, start);
+        }
+        self.line += 4;
+        if self.separator.first() != Some(&b'+') {
+            bail!(
+                "{} line {}: the third line of a FASTQ entry starts with '+'",
+                self.path,
+                start + 2
+            );
+        }
+        if self.seq.len() != self.qual.len() {
+            bail!(
+                "{} line {}: {} bases but {} qualities",
+                self.path,
+                start,
+                self.seq.len(),
+                self.qual.len()
+            );
+        }
+        if let Some(&byte) = self.qual.iter().find(|&&byte| byte < PHRED_OFFSET) {
+            bail!(
+                "{} line {}: quality character {:?} is below '!'",
+                self.path,
+                start + 3,
+                byte as char
+            );
+        }
+        Ok(true)
+    }
+
+    /// The name of the current entry, without '@', comment or mate suffix
+    fn read_name(&self) -> &[u8] {
+        let name = &self.name[1..];
+        let end = name
+            .iter()
+            .position(|byte| byte.is_ascii_whitespace())
+            .unwrap_or(name.len());
+        let name = &name[..end];
+        match name {
+            [rest @ .., b'/', b'1' | b'2'] => rest,
+            name => name,
+        }
+    }
+
+    /// The current entry as an unmapped record with `flags`
+    fn record(&self, flags: u16) -> bam::Record {
+        let qual: Vec<u8> = self.qual.iter().map(|q| q - PHRED_OFFSET).collect();
+        let mut record = bam::Record::new();
+        record.set(self.read_name(), None, &self.seq, &qual);
+        record.set_flags(flags);
+        record.set_tid(-1);
+        record.set_pos(-1);
+        record.set_mtid(-1);
+        record.set_mpos(-1);
+        record.set_mapq(0);
+        record
+    }
+}
+
+/// Reads the pairs of an R1 and an R2 file as unmapped records, mate 1 first
+pub struct FastqPairs {
+    r1: FastqFile,
+    r2: FastqFile,
+    mate2: Option<bam::Record>,
+    pairs: u64,
+    /// Why reading stopped, for [`take_error`](Self::take_error)
+    error: Option<anyhow::Error>,
+}
+
+impl FastqPairs {
+    pub fn open(r1: &str, r2: &str) -> Result<Self> {
+        Ok(FastqPairs {
+            r1: FastqFile::open(r1)?,
+            r2: FastqFile::open(r2)?,
+            mate2: None,
+            pairs: 0,
+            error: None,
+        })
+    }
+
+    /// Pairs read so far
+    pub fn pairs(&self) -> u64 {
+        self.pairs
+    }
+
+    /// Read the next record into `record`, like [`bam::Read::read`]
+    ///
+    /// A malformed entry reads as [`HtsError::BamInvalidRecord`]; the
+    /// reason is kept for [`take_error`](Self::take_error).
+    pub fn read(&mut self, record: &mut bam::Record) -> Option<Result<(), HtsError>> {
+        if let Some(mate2) = self.mate2.take() {
+            *record = mate2;
+            return Some(Ok(()));
+        }
+        if self.error.is_some() {
+            return Some(Err(HtsError::BamInvalidRecord));
+        }
+        match self.next_pair() {
+            Ok(Some((mate1, mate2))) => {
+                *record = mate1;
+                self.mate2 = Some(mate2);
+                Some(Ok(()))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.error = Some(e);
+                Some(Err(HtsError::BamInvalidRecord))
+            }
+        }
+    }
+
+    /// Why the last read failed
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
+
+    fn next_pair(&mut self) -> Result<Option<(bam::Record, bam::Record)>> {
+        match (self.r1.next_entry()?, self.r2.next_entry()?) {
+            (true, true) => {}
+            (false, false) => return Ok(None),
+            (more1, _) => {
+                let (longer, shorter) = if more1 {
+                    (&self.r1, &self.r2)
+                } else {
+                    (&self.r2, &self.r1)
+                };
+                bail!(
+                    "{} ends after {} reads, but {} has more",
+                    shorter.path,
+                    self.pairs,
+                    longer.path
+                );
+            }
+        }
+        if self.r1.read_name() != self.r2.read_name() {
+            bail!(
+                "Mates out of step at pair {}: {} in {}, {} in {}",
+                self.pairs + 1,
+                String::from_utf8_lossy(self.r1.read_name()),
+                self.r1.path,
+                String::from_utf8_lossy(self.r2.read_name()),
+                self.r2.path
+            );
+        }
+        self.pairs += 1;
+        Ok(Some((
+            self.r1.record(0x1 | 0x4 | 0x8 | 0x40),
+            self.r2.record(0x1 | 0x4 | 0x8 | 0x80),
+        )))
+    }
+}
@@ -0,0 +1,1208 @@
+//! Per-pair filter decisions: kmer complexity, mapped stretches and the
+//! alignment-based checks that are applied to both mates
+
+use crate::chain::FilterOrder;
+use crate::complexity::{self, ComplexityMethod};
+use crate::expr::{self, Expr, Field, Mate};
+use crate::fastq::reverse_complement;
+use crate::quality::{self, BaseqFraction};
+use crate::sample::HashSample;
+use crate::{bases, bisulfite, metrics};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use rust_htslib::bam;
+use rust_htslib::bam::record::{Aux, Cigar};
+use std::collections::HashSet;
+
+/// Kmer length when `--kmer-size` is not given
+pub const KMER_SIZE: usize = 21;
+
+/// What happens to a read shorter than the kmer length, which has no kmers
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ShortReadPolicy {
+    /// Fails the complexity filter (complexity 0)
+    #[default]
+    Fail,
+    /// Passes the complexity filter; every other filter still applies
+    Pass,
+    /// Removes the pair, counted apart from low-complexity pairs
+    Skip,
+}
+
+/// What the edit-distance thresholds do with a mapped read that has neither
+/// an `NM` tag nor an `=`/`X` CIGAR to count mismatches from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MissingNm {
+    /// Stops the run at the first such read
+    #[default]
+    Error,
+    /// Fails the threshold
+    Fail,
+    /// Passes the threshold
+    Pass,
+}
+
+/// Aux tag carrying a read's kmer complexity (`xc:f`)
+pub const TAG_COMPLEXITY: &[u8] = b"xc";
+
+/// Aux tag carrying a read's longest contiguous mapped stretch (`xm:i`)
+pub const TAG_LONGEST_MAPPED: &[u8] = b"xm";
+
+/// The `i`th kmer of `sequence`, or with `reverse` (the sequence's reverse
+/// complement) the smaller of it and its reverse complement
+fn kmer_at<'a>(sequence: &'a [u8], reverse: Option<&'a [u8]>, k: usize, i: usize) -> &'a [u8] {
+    let forward = &sequence[i..i + k];
+    match reverse {
+        Some(reverse) => {
+            let end = sequence.len() - i;
+            forward.min(&reverse[end - k..end])
+        }
+        None => forward,
+    }
+}
+
+/// Longest kmer that packs into a `u64` at 2 bits per base
+const MAX_PACKED_K: usize = 32;
+
+/// A set of packed kmers that is emptied in O(1) by bumping a stamp, so one
+/// table per thread serves every read without clearing or allocating
+struct KmerSet {
+    keys: Vec<u64>,
+    stamps: Vec<u32>,
+    stamp: u32,
+}
+
+impl KmerSet {
+    const fn new() -> Self {
+        KmerSet {
+            keys: Vec::new(),
+            stamps: Vec::new(),
+            stamp: 0,
+        }
+    }
+
+    /// Empty the set, with room for `kmers` entries at most half full
+    fn reset(&mut self, kmers: usize) {
+        let slots = (kmers * 2).next_power_of_two().max(64);
+        if slots > self.keys.len() {
+            self.keys = vec![0; slots];
+            self.stamps = vec![0; slots];
+            self.stamp = 0;
+        }
+        self.stamp = self.stamp.wrapping_add(1);
+        if self.stamp == 0 {
+            self.stamps.fill(0);
+            self.stamp = 1;
+        }
+    }
+
+    /// Add `kmer`, returning whether it is new
+    fn insert(&mut self, kmer: u64) -> bool {
+        let mask = self.keys.len() - 1;
+        // Fibonacci hashing spreads the packed bits over the table
+        let mut slot = (kmer.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize & mask;
+        loop {
+            if self.stamps[slot] != self.stamp {
+                self.stamps[slot] = self.stamp;
+                self.keys[slot] = kmer;
+                return true;
+            }
+            if self.keys[slot] == kmer {
+                return false;
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+}
+
+thread_local! {
+    static KMER_SET: std::cell::RefCell<KmerSet> = const { std::cell::RefCell::new(KmerSet::new()) };
+    /// 2-bit codes of the read being counted
+    static BASE_CODES: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Unique over total kmers; with a `cutoff`, stop once `>= cutoff` is decided
+///
+/// Kmers of A, C, G and T are packed 2 bits per base and rolled along the
+/// sequence, forward and (for `canonical`) reverse complement at once. The
+/// rare kmers with any other byte (lower case, N, IUPAC codes) are compared
+/// as slices, apart from them, so that counts match byte comparison.
+fn kmer_complexity(sequence: &[u8], k: usize, canonical: bool, cutoff: Option<f64>) -> f64 {
+    if sequence.len() < k {
+        return 0.0;
+    }
+    if k > MAX_PACKED_K {
+        return kmer_complexity_by_slices(sequence, k, canonical, cutoff);
+    }
+
+    let total_kmers = sequence.len() - k + 1;
+    let total = total_kmers as f64;
+    let mask = if k == MAX_PACKED_K {
+        u64::MAX
+    } else {
+        (1u64 << (2 * k)) - 1
+    };
+    let shift = 2 * (k as u64 - 1);
+
+    BASE_CODES.with_borrow_mut(|codes| {
+        let known = bases::codes_into(sequence, codes);
+        let reverse = (canonical && !known).then(|| reverse_complement(sequence));
+        let mut others: HashSet<&[u8]> = HashSet::new();
+        KMER_SET.with_borrow_mut(|set| {
+            set.reset(total_kmers);
+            let (mut forward, mut backward, mut run) = (0u64, 0u64, 0usize);
+            let mut unique = 0usize;
+            for (end, &code) in codes.iter().enumerate() {
+                if code == bases::AMBIGUOUS {
+                    run = 0;
+                } else {
+                    let code = code as u64;
+                    forward = ((forward << 2) | code) & mask;
+                    backward = (backward >> 2) | ((3 - code) << shift);
+                    run += 1;
+                }
+                if end + 1 < k {
+                    continue;
+                }
+                let i = end + 1 - k;
+                let new = if run >= k {
+                    set.insert(if canonical {
+                        forward.min(backward)
+                    } else {
+                        forward
+                    })
+                } else {
+                    others.insert(kmer_at(sequence, reverse.as_deref(), k, i))
+                };
+                unique += new as usize;
+
+                if let Some(cutoff) = cutoff {
+                    // Already enough unique kmers: the ratio can only grow from here
+                    let lower = unique as f64 / total;
+                    if lower >= cutoff {
+                        return lower;
+                    }
+                    // Even if every remaining kmer were new, the cutoff is out of reach
+                    let upper = (unique + total_kmers - i - 1) as f64 / total;
+                    if upper < cutoff {
+                        return upper;
+                    }
+                }
+            }
+            unique as f64 / total
+        })
+    })
+}
+
+/// [`kmer_complexity`] for kmers too long to pack, counted as slices
+fn kmer_complexity_by_slices(
+    sequence: &[u8],
+    k: usize,
+    canonical: bool,
+    cutoff: Option<f64>,
+) -> f64 {
+    let reverse = canonical.then(|| reverse_complement(sequence));
+    let mut kmers: HashSet<&[u8]> = HashSet::new();
+    let total_kmers = sequence.len() - k + 1;
+    let total = total_kmers as f64;
+
+    for i in 0..total_kmers {
+        kmers.insert(kmer_at(sequence, reverse.as_deref(), k, i));
+        if let Some(cutoff) = cutoff {
+            let lower = kmers.len() as f64 / total;
+            if lower >= cutoff {
+                return lower;
+            }
+            let upper = (kmers.len() + total_kmers - i - 1) as f64 / total;
+            if upper < cutoff {
+                return upper;
+            }
+        }
+    }
+    kmers.len() as f64 / total
+}
+
+/// Calculate kmer complexity: unique_kmers / total_kmers, for kmers of length `k`
+pub fn calculate_kmer_complexity(sequence: &[u8], k: usize) -> f64 {
+    kmer_complexity(sequence, k, false, None)
+}
+
+/// [`calculate_kmer_complexity`] with a kmer and its reverse complement
+/// counted as the same kmer, so a read and its reverse complement score alike
+pub fn calculate_canonical_kmer_complexity(sequence: &[u8], k: usize) -> f64 {
+    kmer_complexity(sequence, k, true, None)
+}
+
+/// Calculate kmer complexity, stopping early once the comparison against
+/// `cutoff` can no longer change.
+///
+/// The result is exact when counting runs to completion. After an early exit
+/// it is the bound that decided the outcome, so `result >= cutoff` always
+/// agrees with the exact value.
+pub fn calculate_kmer_complexity_bounded(sequence: &[u8], k: usize, cutoff: f64) -> f64 {
+    kmer_complexity(sequence, k, false, Some(cutoff))
+}
+
+/// [`calculate_kmer_complexity_bounded`] counting canonical kmers, as
+/// [`calculate_canonical_kmer_complexity`] does
+pub fn calculate_canonical_kmer_complexity_bounded(sequence: &[u8], k: usize, cutoff: f64) -> f64 {
+    kmer_complexity(sequence, k, true, Some(cutoff))
+}
+
+/// Longest run of M/= bases; with `splice_aware`, N operations don't break it
+fn longest_mapped_bases(record: &bam::Record, splice_aware: bool) -> u32 {
+    let mut longest = 0u32;
+    let mut current = 0u32;
+
+    for cigar_op in record.cigar().iter() {
+        match cigar_op {
+            // Match and SequenceMatch count as mapped bases
+            Cigar::Match(len) | Cigar::Equal(len) => {
+                current += len;
+            }
+            Cigar::RefSkip(_) if splice_aware => {}
+            // Other operations break the contiguous stretch
+            _ => {
+                if current > longest {
+                    longest = current;
+                }
+                current = 0;
+            }
+        }
+    }
+
+    // Check the last stretch
+    if current > longest {
+        longest = current;
+    }
+
+    longest
+}
+
+/// Get longest contiguous mapped bases from CIGAR
+pub fn get_longest_mapped_bases(record: &bam::Record) -> u32 {
+    longest_mapped_bases(record, false)
+}
+
+/// [`get_longest_mapped_bases`] with N (reference skip) operations taken as
+/// introns: they join the exons on either side into one stretch without
+/// adding mapped bases
+pub fn get_longest_spliced_mapped_bases(record: &bam::Record) -> u32 {
+    longest_mapped_bases(record, true)
+}
+
+/// Number of splice junctions (N operations) in the CIGAR
+pub fn count_splice_junctions(record: &bam::Record) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .filter(|op| matches!(op, Cigar::RefSkip(_)))
+        .count() as u32
+}
+
+/// Complexity stored in the record's `xc` tag by a previous run
+pub fn cached_complexity(record: &bam::Record) -> Option<f64> {
+    match record.aux(TAG_COMPLEXITY) {
+        Ok(Aux::Float(v)) => Some(v as f64),
+        Ok(Aux::Double(v)) => Some(v),
+        _ => None,
+    }
+}
+
+/// Longest mapped stretch stored in the record's `xm` tag by a previous run
+pub fn cached_longest_mapped(record: &bam::Record) -> Option<u32> {
+    match record.aux(TAG_LONGEST_MAPPED) {
+        Ok(Aux::U8(v)) => Some(v as u32),
+        Ok(Aux::U16(v)) => Some(v as u32),
+        Ok(Aux::U32(v)) => Some(v),
+        Ok(Aux::I8(v)) => u32::try_from(v).ok(),
+        Ok(Aux::I16(v)) => u32::try_from(v).ok(),
+        Ok(Aux::I32(v)) => u32::try_from(v).ok(),
+        _ => None,
+    }
+}
+
+/// Complexity of one read, honouring cached tags and the early-exit setting
+///
+/// With a bisulfite `conversion`, converted bases are collapsed first.
+fn read_complexity(
+    record: &bam::Record,
+    config: &FilterConfig,
+    conversion: Option<bisulfite::Conversion>,
+    cache_hits: &mut u64,
+) -> f64 {
+    if config.use_cached_metrics {
+        if let Some(complexity) = cached_complexity(record) {
+            *cache_hits += 1;
+            return complexity;
+        }
+    }
+
+    let mut seq = bases::decode(record);
+    if let Some(conversion) = conversion {
+        bisulfite::collapse(&mut seq, conversion);
+    }
+    if config.exact_complexity || config.complexity_method != ComplexityMethod::KmerUniqueness {
+        config.sequence_complexity(&seq)
+    } else {
+        kmer_complexity(
+            &seq,
+            config.kmer_size,
+            config.canonical,
+            Some(config.complexity),
+        )
+    }
+}
+
+/// Longest mapped stretch of one read, honouring cached tags
+fn read_longest_mapped(record: &bam::Record, config: &FilterConfig, cache_hits: &mut u64) -> u32 {
+    if config.use_cached_metrics {
+        if let Some(mapped) = cached_longest_mapped(record) {
+            *cache_hits += 1;
+            return mapped;
+        }
+    }
+
+    config.longest_mapped(record)
+}
+
+/// Thresholds and metric options applied to every pair
+#[derive(Debug, Clone)]
+pub struct FilterConfig {
+    /// Kmer complexity cutoff, both mates
+    pub complexity: f64,
+    /// How read complexity is measured
+    pub complexity_method: ComplexityMethod,
+    /// Kmer length of the kmer-uniqueness metric
+    pub kmer_size: usize,
+    /// Window of the DUST and entropy metrics, in bases (0 = the whole read)
+    pub complexity_window: usize,
+    /// Word length of the entropy metric
+    pub entropy_word_size: usize,
+    /// How reads shorter than `kmer_size` are judged
+    pub short_reads: ShortReadPolicy,
+    /// Count a kmer and its reverse complement as one
+    pub canonical: bool,
+    /// Minimum longest contiguous mapped stretch, both mates (0 = disabled)
+    pub min_mapped: u32,
+    /// Collapse bisulfite conversions before counting kmers
+    pub bisulfite: bool,
+    /// With `bisulfite`, pick C->T or G->A by the pair's strand
+    pub bisulfite_strand_aware: bool,
+    /// Let N operations join exons into one mapped stretch
+    pub splice_aware: bool,
+    pub max_splice_junctions: Option<u32>,
+    pub min_mapped_fraction: Option<f64>,
+    pub max_clip_fraction: Option<f64>,
+    /// Minimum gap-compressed identity, both mates (needs `NM`)
+    pub min_gap_compressed_identity: Option<f64>,
+    /// Maximum divergence (`de:f`, else from `NM`), both mates
+    pub max_divergence: Option<f64>,
+    /// Read length used by the fraction filters
+    pub length_basis: metrics::LengthBasis,
+    /// Minimum MAPQ, both mates (0 = disabled)
+    pub min_mapq: u8,
+    /// Minimum mean base quality, both mates
+    pub min_avg_baseq: Option<f64>,
+    /// Minimum fraction of bases at or above a base quality, both mates
+    pub min_baseq_fraction: Option<BaseqFraction>,
+    /// Minimum absolute template length (TLEN) of a pair
+    pub min_insert: Option<u64>,
+    /// Maximum absolute template length (TLEN) of a pair
+    pub max_insert: Option<u64>,
+    /// Both mates mapped to the same reference
+    pub require_same_reference: bool,
+    /// Maximum edit distance (`NM`), both mates
+    pub max_nm: Option<u32>,
+    /// Maximum edit distance per aligned read base, both mates
+    pub max_error_rate: Option<f64>,
+    /// How `max_nm` and `max_error_rate` judge mapped reads without `NM`
+    pub missing_nm: MissingNm,
+    /// Count every kmer instead of stopping once the cutoff is decided
+    pub exact_complexity: bool,
+    /// Take complexity and mapped bases from `xc`/`xm` tags when present
+    pub use_cached_metrics: bool,
+    /// Additional pair-level condition (`--filter-expr`)
+    pub expression: Option<Expr>,
+    /// Keep only pairs whose name hashes into these buckets
+    pub hash_sample: Option<HashSample>,
+    /// Let a mate that fails complexity pass on the strength of the other
+    pub mate_rescue: Option<MateRescue>,
+    /// Check thresholds in this order and stop at the first failure;
+    /// complexity is then only measured when it is reached
+    pub short_circuit: Option<FilterOrder>,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            complexity: 0.8,
+            complexity_method: ComplexityMethod::KmerUniqueness,
+            kmer_size: KMER_SIZE,
+            complexity_window: complexity::DEFAULT_WINDOW,
+            entropy_word_size: complexity::DEFAULT_ENTROPY_WORD_SIZE,
+            short_reads: ShortReadPolicy::Fail,
+            canonical: false,
+            min_mapped: 0,
+            bisulfite: false,
+            bisulfite_strand_aware: false,
+            splice_aware: false,
+            max_splice_junctions: None,
+            min_mapped_fraction: None,
+            max_clip_fraction: None,
+            min_gap_compressed_identity: None,
+            max_divergence: None,
+            length_basis: metrics::LengthBasis::Query,
+            min_mapq: 0,
+            min_avg_baseq: None,
+            min_baseq_fraction: None,
+            min_insert: None,
+            max_insert: None,
+            require_same_reference: false,
+            max_nm: None,
+            max_error_rate: None,
+            missing_nm: MissingNm::Error,
+            exact_complexity: false,
+            use_cached_metrics: false,
+            expression: None,
+            hash_sample: None,
+            mate_rescue: None,
+            short_circuit: None,
+        }
+    }
+}
+
+/// Field values of a pair for expression evaluation, computed on first use
+struct PairFields<'a> {
+    records: [&'a bam::Record; 2],
+    config: &'a FilterConfig,
+    conversion: Option<bisulfite::Conversion>,
+    cache_hits: &'a mut u64,
+    values: [[Option<f64>; Field::COUNT]; 2],
+}
+
+impl PairFields<'_> {
+    fn compute(&mut self, mate: Mate, field: Field) -> f64 {
+        let record = self.records[mate.index()];
+        let config = self.config;
+        match field {
+            Field::Mapq => record.mapq() as f64,
+            Field::Pos => (record.pos() + 1) as f64,
+            Field::End => record.cigar().end_pos() as f64,
+            Field::Tid => record.tid() as f64,
+            Field::Flag => record.flags() as f64,
+            Field::Tlen => record.insert_size() as f64,
+            Field::Length => record.seq_len() as f64,
+            Field::Complexity => {
+                if config.use_cached_metrics {
+                    if let Some(complexity) = cached_complexity(record) {
+                        *self.cache_hits += 1;
+                        return complexity;
+                    }
+                }
+                // Expressions see exact values, never an early-exit bound
+                let mut seq = bases::decode(record);
+                if let Some(conversion) = self.conversion {
+                    bisulfite::collapse(&mut seq, conversion);
+                }
+                config.sequence_complexity(&seq)
+            }
+            Field::LongestMapped => read_longest_mapped(record, config, self.cache_hits) as f64,
+            Field::Clipped => metrics::clipped_bases(record, config.length_basis) as f64,
+            Field::Unmapped => record.is_unmapped() as u8 as f64,
+            Field::Reverse => record.is_reverse() as u8 as f64,
+            // NaN compares false, so a read without the value fails a bound on it
+            Field::Identity => metrics::identity(record).unwrap_or(f64::NAN),
+            Field::Mismatches => metrics::mismatches(record).map_or(f64::NAN, |m| m as f64),
+        }
+    }
+}
+
+impl expr::Fields for PairFields<'_> {
+    fn value(&mut self, mate: Mate, field: Field) -> f64 {
+        if let Some(value) = self.values[mate.index()][field as usize] {
+            return value;
+        }
+        let value = self.compute(mate, field);
+        self.values[mate.index()][field as usize] = Some(value);
+        value
+    }
+
+    fn tag(&mut self, mate: Mate, tag: [u8; 2]) -> f64 {
+        aux_number(self.records[mate.index()], &tag).unwrap_or(f64::NAN)
+    }
+
+    fn has_tag(&mut self, mate: Mate, tag: [u8; 2]) -> bool {
+        self.records[mate.index()].aux(&tag).is_ok()
+    }
+}
+
+/// Value of a numeric aux tag, whatever its stored type
+pub fn aux_number(record: &bam::Record, tag: &[u8]) -> Option<f64> {
+    match record.aux(tag).ok()? {
+        Aux::I8(v) => Some(v as f64),
+        Aux::U8(v) => Some(v as f64),
+        Aux::I16(v) => Some(v as f64),
+        Aux::U16(v) => Some(v as f64),
+        Aux::I32(v) => Some(v as f64),
+        Aux::U32(v) => Some(v as f64),
+        Aux::Float(v) => Some(v as f64),
+        Aux::Double(v) => Some(v),
+        _ => None,
+    }
+}
+
+/// Exact metric values of a pair, as stored by `cache-metrics`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PairMetrics {
+    pub complexity: [f64; 2],
+    pub longest_mapped: [u32; 2],
+}
+
+/// When a low-complexity mate is rescued by the other (`--rescue-by-mate`)
+///
+/// The other mate must pass complexity, be mapped with at least `min_mapq`
+/// and start within `max_distance` bp of the low-complexity mate on the same
+/// reference. An unmapped mate placed at its partner's position qualifies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MateRescue {
+    pub min_mapq: u8,
+    pub max_distance: u32,
+}
+
+impl MateRescue {
+    /// Whether `anchor` vouches for its low-complexity mate `read`
+    fn rescues(&self, anchor: &bam::Record, read: &bam::Record) -> bool {
+        !anchor.is_unmapped()
+            && anchor.mapq() >= self.min_mapq
+            && anchor.tid() >= 0
+            && read.tid() == anchor.tid()
+            && read.pos().abs_diff(anchor.pos()) <= self.max_distance as u64
+    }
+}
+
+/// A set of the thresholds [`FilterConfig`] applies, one bit per
+/// [`Thresholds::NAMES`] entry
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds(u32);
+
+impl Thresholds {
+    /// Threshold names, after the options that set them
+    pub const NAMES: [&'static str; 17] = [
+        "short_read_policy",
+        "complexity",
+        "min_mapped",
+        "min_mapped_fraction",
+        "max_clip_fraction",
+        "min_gap_compressed_identity",
+        "max_de",
+        "min_mapq",
+        "max_splice_junctions",
+        "hash_sample",
+        "min_avg_baseq",
+        "min_baseq_fraction",
+        "insert_size",
+        "same_reference",
+        "max_nm",
+        "max_error_rate",
+        "filter_expr",
+    ];
+
+    pub const SHORT_READ_POLICY: usize = 0;
+    pub const COMPLEXITY: usize = 1;
+    pub const MIN_MAPPED: usize = 2;
+    pub const MIN_MAPPED_FRACTION: usize = 3;
+    pub const MAX_CLIP_FRACTION: usize = 4;
+    pub const MIN_GAP_COMPRESSED_IDENTITY: usize = 5;
+    pub const MAX_DE: usize = 6;
+    pub const MIN_MAPQ: usize = 7;
+    pub const MAX_SPLICE_JUNCTIONS: usize = 8;
+    pub const HASH_SAMPLE: usize = 9;
+    pub const MIN_AVG_BASEQ: usize = 10;
+    pub const MIN_BASEQ_FRACTION: usize = 11;
+    pub const INSERT_SIZE: usize = 12;
+    pub const SAME_REFERENCE: usize = 13;
+    pub const MAX_NM: usize = 14;
+    pub const MAX_ERROR_RATE: usize = 15;
+    pub const FILTER_EXPR: usize = 16;
+
+    fn from_bits(bits: [bool; 17]) -> Self {
+        Thresholds(
+            bits.iter()
+                .enumerate()
+                .fold(0, |set, (index, &bit)| set | (bit as u32) << index),
+        )
+    }
+
+    pub fn contains(self, index: usize) -> bool {
+        self.0 & 1 << index != 0
+    }
+
+    fn insert(&mut self, index: usize) {
+        self.0 |= 1 << index;
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn union(self, other: Thresholds) -> Self {
+        Thresholds(self.0 | other.0)
+    }
+
+    /// The names of the thresholds in the set, in [`Thresholds::NAMES`] order
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .into_iter()
+            .enumerate()
+            .filter(move |&(index, _)| self.contains(index))
+            .map(|(_, name)| name)
+    }
+}
+
+/// Outcome of filtering one pair
+#[derive(Debug, Clone, Copy)]
+pub struct PairVerdict {
+    pub keep: bool,
+    /// Complexity of each mate (a deciding bound unless exact)
+    pub complexity: [f64; 2],
+    /// Longest mapped stretch of each mate, when a mapped filter needed it
+    pub longest_mapped: Option<[u32; 2]>,
+    /// One mate failed complexity and was let through by the other
+    pub rescued: bool,
+    /// Removed because a mate is shorter than the kmer length (`--short-read-policy skip`)
+    pub skipped_short: bool,
+    /// A mate has MAPQ below `min_mapq`
+    pub low_mapq: bool,
+    /// Every threshold the pair failed; the filter expression only counts
+    /// when the others pass, as it is only evaluated then. With
+    /// `short_circuit`, only the first failure
+    pub failed: Thresholds,
+    /// The thresholds checked on the pair: all enabled ones unless
+    /// `short_circuit` stopped early
+    pub evaluated: Thresholds,
+}
+
+impl FilterConfig {
+    /// Reject option values that can never make sense
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.complexity) {
+            bail!("Complexity cutoff must be between 0 and 1");
+        }
+        if self.kmer_size == 0 {
+            bail!("--kmer-size must be at least 1");
+        }
+        if self.complexity_method == ComplexityMethod::ShannonEntropy
+            && !(1..=complexity::MAX_ENTROPY_WORD_SIZE).contains(&self.entropy_word_size)
+        {
+            bail!(
+                "--entropy-word-size must be between 1 and {}",
+                complexity::MAX_ENTROPY_WORD_SIZE
+            );
+        }
+        if self.complexity_method != ComplexityMethod::KmerUniqueness {
+            if self.canonical {
+                bail!("--canonical only applies to --complexity-method kmer-uniqueness");
+            }
+            let shortest = self.min_read_length();
+            if self.complexity_window != 0 && self.complexity_window < shortest {
+                bail!(
+                    "--complexity-window must be 0 (the whole read) or at least {} bases",
+                    shortest
+                );
+            }
+        }
+        for (name, value) in [
+            ("--min-mapped-fraction", self.min_mapped_fraction),
+            ("--max-clip-fraction", self.max_clip_fraction),
+            (
+                "--min-gap-compressed-identity",
+                self.min_gap_compressed_identity,
+            ),
+            ("--max-de", self.max_divergence),
+            ("--max-error-rate", self.max_error_rate),
+        ] {
+            if value.is_some_and(|v| !(0.0..=1.0).contains(&v)) {
+                bail!("{} must be between 0 and 1", name);
+            }
+        }
+        if self.min_avg_baseq.is_some_and(|q| q < 0.0) {
+            bail!("--min-avg-baseq cannot be negative");
+        }
+        if let (Some(min), Some(max)) = (self.min_insert, self.max_insert) {
+            if min > max {
+                bail!("--min-insert {} is above --max-insert {}", min, max);
+            }
+        }
+        Ok(())
+    }
+
+    /// The thresholds this configuration applies
+    pub fn thresholds(&self) -> Thresholds {
+        Thresholds::from_bits([
+            self.short_reads == ShortReadPolicy::Skip,
+            true,
+            self.min_mapped > 0,
+            self.min_mapped_fraction.is_some(),
+            self.max_clip_fraction.is_some(),
+            self.min_gap_compressed_identity.is_some(),
+            self.max_divergence.is_some(),
+            self.min_mapq > 0,
+            self.max_splice_junctions.is_some(),
+            self.hash_sample.is_some(),
+            self.min_avg_baseq.is_some(),
+            self.min_baseq_fraction.is_some(),
+            self.min_insert.is_some() || self.max_insert.is_some(),
+            self.require_same_reference,
+            self.max_nm.is_some(),
+            self.max_error_rate.is_some(),
+            self.expression.is_some(),
+        ])
+    }
+
+    /// With `missing_nm` at [`MissingNm::Error`], fail on a mapped read the
+    /// edit-distance thresholds cannot measure
+    pub fn check_edit_distances(&self, records: &[&bam::Record]) -> Result<()> {
+        if self.missing_nm != MissingNm::Error
+            || (self.max_nm.is_none() && self.max_error_rate.is_none())
+        {
+            return Ok(());
+        }
+        for record in records {
+            if !record.is_unmapped() && metrics::edit_distance(record).is_none() {
+                bail!(
+                    "Read {} is mapped but has no NM tag or =/X CIGAR for --max-nm/--max-error-rate; \
+                     add NM with --regenerate-md --reference FASTA, or set --missing-nm fail or pass",
+                    String::from_utf8_lossy(record.qname())
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a read's edit distance satisfies `within`; unmapped reads
+    /// fail, mapped ones without `NM` go by `missing_nm`
+    fn edits_pass(&self, record: &bam::Record, within: impl Fn(&bam::Record, i64) -> bool) -> bool {
+        if record.is_unmapped() {
+            return false;
+        }
+        match metrics::edit_distance(record) {
+            Some(nm) => within(record, nm),
+            None => self.missing_nm == MissingNm::Pass,
+        }
+    }
+
+    /// Longest mapped stretch of a record, spliced with `splice_aware`
+    pub fn longest_mapped(&self, record: &bam::Record) -> u32 {
+        if self.splice_aware {
+            get_longest_spliced_mapped_bases(record)
+        } else {
+            get_longest_mapped_bases(record)
+        }
+    }
+
+    /// Exact complexity of a sequence by the configured method
+    pub fn sequence_complexity(&self, sequence: &[u8]) -> f64 {
+        match self.complexity_method {
+            ComplexityMethod::KmerUniqueness => {
+                kmer_complexity(sequence, self.kmer_size, self.canonical, None)
+            }
+            ComplexityMethod::Dust => complexity::dust_complexity(sequence, self.complexity_window),
+            ComplexityMethod::ShannonEntropy => complexity::entropy_complexity(
+                sequence,
+                self.entropy_word_size,
+                self.complexity_window,
+            ),
+        }
+    }
+
+    /// Reads shorter than this have no complexity and fall under `short_reads`
+    pub fn min_read_length(&self) -> usize {
+        complexity::min_read_length(
+            self.complexity_method,
+            self.kmer_size,
+            self.entropy_word_size,
+        )
+    }
+
+    /// Bisulfite conversion collapsed before counting a pair's kmers
+    fn conversion(
+        &self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+    ) -> Option<bisulfite::Conversion> {
+        match (self.bisulfite, self.bisulfite_strand_aware) {
+            (false, _) => None,
+            (true, false) => Some(bisulfite::Conversion::CToT),
+            (true, true) => Some(bisulfite::pair_conversion(record1, record2)),
+        }
+    }
+
+    /// Exact complexity and longest mapped stretch of both mates
+    ///
+    /// The values depend on the bisulfite and splice options, not on any threshold.
+    pub fn measure(&self, record1: &bam::Record, record2: &bam::Record) -> PairMetrics {
+        let conversion = self.conversion(record1, record2);
+        let complexity = |record: &bam::Record| {
+            let mut seq = bases::decode(record);
+            if let Some(conversion) = conversion {
+                bisulfite::collapse(&mut seq, conversion);
+            }
+            self.sequence_complexity(&seq)
+        };
+        PairMetrics {
+            complexity: [complexity(record1), complexity(record2)],
+            longest_mapped: [self.longest_mapped(record1), self.longest_mapped(record2)],
+        }
+    }
+
+    /// Decide whether a pair is kept: both mates must pass every filter
+    ///
+    /// `cache_hits` counts metric values taken from cached tags.
+    pub fn evaluate(
+        &self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        cache_hits: &mut u64,
+    ) -> PairVerdict {
+        let conversion = self.conversion(record1, record2);
+        self.decide(PairCheck::new(
+            self,
+            [record1, record2],
+            conversion,
+            cache_hits,
+        ))
+    }
+
+    /// Decide whether a single-end read is kept (`--single-end`)
+    ///
+    /// Every threshold applies to the read alone; in a filter expression,
+    /// `mate1` and `mate2` both refer to it.
+    pub fn evaluate_read(&self, record: &bam::Record, cache_hits: &mut u64) -> PairVerdict {
+        let conversion = self.conversion(record, record);
+        self.decide(PairCheck::new(
+            self,
+            [record, record],
+            conversion,
+            cache_hits,
+        ))
+    }
+
+    /// [`FilterConfig::evaluate`] with metric values measured earlier
+    pub fn evaluate_measured(
+        &self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        metrics: &PairMetrics,
+        cache_hits: &mut u64,
+    ) -> PairVerdict {
+        let conversion = self.conversion(record1, record2);
+        let mut check = PairCheck::new(self, [record1, record2], conversion, cache_hits);
+        check.complexity = Some(metrics.complexity);
+        check.longest_mapped = Some(metrics.longest_mapped);
+        self.decide(check)
+    }
+
+    /// Apply the thresholds: every enabled one, or with `short_circuit` each
+    /// in turn up to the first that fails
+    fn decide(&self, mut check: PairCheck) -> PairVerdict {
+        let enabled = self.thresholds();
+        let (mut evaluated, mut failed) = (Thresholds::default(), Thresholds::default());
+        let all: [usize; Thresholds::FILTER_EXPR] = std::array::from_fn(|index| index);
+        let order = match &self.short_circuit {
+            Some(order) => order.indices(),
+            None => &all,
+        };
+        for &threshold in order {
+            if !enabled.contains(threshold) {
+                continue;
+            }
+            evaluated.insert(threshold);
+            if !check.passes(threshold) {
+                failed.insert(threshold);
+                if self.short_circuit.is_some() {
+                    break;
+                }
+            }
+        }
+
+        // Only evaluated when the thresholds pass, so it can't change rejections
+        if failed.is_empty() {
+            if let Some(expression) = &self.expression {
+                evaluated.insert(Thresholds::FILTER_EXPR);
+                let matches = expression.matches(&mut PairFields {
+                    records: check.records,
+                    config: self,
+                    conversion: check.conversion,
+                    cache_hits: check.cache_hits,
+                    values: [[None; Field::COUNT]; 2],
+                });
+                if !matches {
+                    failed.insert(Thresholds::FILTER_EXPR);
+                }
+            }
+        }
+
+        PairVerdict {
+            keep: failed.is_empty(),
+            // Only a short-circuited pair can be rejected before complexity
+            complexity: check.complexity.unwrap_or([f64::NAN; 2]),
+            longest_mapped: check.longest_mapped,
+            rescued: check.rescued,
+            skipped_short: failed.contains(Thresholds::SHORT_READ_POLICY),
+            low_mapq: failed.contains(Thresholds::MIN_MAPQ),
+            failed,
+            evaluated,
+        }
+    }
+}
+
+/// One pair's metrics, computed the first time a threshold needs them
+struct PairCheck<'a> {
+    config: &'a FilterConfig,
+    records: [&'a bam::Record; 2],
+    conversion: Option<bisulfite::Conversion>,
+    cache_hits: &'a mut u64,
+    complexity: Option<[f64; 2]>,
+    longest_mapped: Option<[u32; 2]>,
+    rescued: bool,
+}
+
+impl<'a> PairCheck<'a> {
+    fn new(
+        config: &'a FilterConfig,
+        records: [&'a bam::Record; 2],
+        conversion: Option<bisulfite::Conversion>,
+        cache_hits: &'a mut u64,
+    ) -> Self {
+        PairCheck {
+            config,
+            records,
+            conversion,
+            cache_hits,
+            complexity: None,
+            longest_mapped: None,
+            rescued: false,
+        }
+    }
+
+    /// Both records are the one single-end read
+    fn single(&self) -> bool {
+        std::ptr::eq(self.records[0], self.records[1])
+    }
+
+    fn complexity(&mut self) -> [f64; 2] {
+        if let Some(complexity) = self.complexity {
+            return complexity;
+        }
+        let [record1, record2] = self.records;
+        let first = read_complexity(record1, self.config, self.conversion, self.cache_hits);
+        let complexity = if self.single() {
+            [first; 2]
+        } else {
+            [
+                first,
+                read_complexity(record2, self.config, self.conversion, self.cache_hits),
+            ]
+        };
+        *self.complexity.insert(complexity)
+    }
+
+    fn longest_mapped(&mut self) -> [u32; 2] {
+        if let Some(mapped) = self.longest_mapped {
+            return mapped;
+        }
+        let [record1, record2] = self.records;
+        let first = read_longest_mapped(record1, self.config, self.cache_hits);
+        let mapped = if self.single() {
+            [first; 2]
+        } else {
+            [
+                first,
+                read_longest_mapped(record2, self.config, self.cache_hits),
+            ]
+        };
+        *self.longest_mapped.insert(mapped)
+    }
+
+    /// Whether both mates pass the threshold at `threshold` in [`Thresholds::NAMES`]
+    fn passes(&mut self, threshold: usize) -> bool {
+        let config = self.config;
+        let [record1, record2] = self.records;
+        let shortest = config.min_read_length();
+        let short = self.records.map(|record| record.seq_len() < shortest);
+        match threshold {
+            Thresholds::SHORT_READ_POLICY => {
+                config.short_reads != ShortReadPolicy::Skip || !short.contains(&true)
+            }
+            Thresholds::COMPLEXITY => {
+                let mut low = self.complexity().map(|c| c < config.complexity);
+                if config.short_reads == ShortReadPolicy::Pass {
+                    for (low, short) in low.iter_mut().zip(short) {
+                        *low &= !short;
+                    }
+                }
+                self.rescued = match (config.mate_rescue, low) {
+                    (Some(rescue), [true, false]) => rescue.rescues(record2, record1),
+                    (Some(rescue), [false, true]) => rescue.rescues(record1, record2),
+                    _ => false,
+                };
+                !low.contains(&true) || self.rescued
+            }
+            Thresholds::MIN_MAPPED => self
+                .longest_mapped()
+                .iter()
+                .all(|&m| m >= config.min_mapped),
+            Thresholds::MIN_MAPPED_FRACTION => {
+                let min = config.min_mapped_fraction.unwrap_or(0.0);
+                let mapped = self.longest_mapped();
+                [(record1, mapped[0]), (record2, mapped[1])]
+                    .iter()
+                    .all(|(record, mapped)| {
+                        metrics::fraction(
+                            *mapped,
+                            metrics::read_length(record, config.length_basis),
+                        ) >= min
+                    })
+            }
+            Thresholds::MAX_CLIP_FRACTION => {
+                let max = config.max_clip_fraction.unwrap_or(1.0);
+                self.records.iter().all(|record| {
+                    metrics::fraction(
+                        metrics::clipped_bases(record, config.length_basis),
+                        metrics::read_length(record, config.length_basis),
+                    ) <= max
+                })
+            }
+            // Reads without a measurable identity (unmapped, no NM) fail
+            Thresholds::MIN_GAP_COMPRESSED_IDENTITY => {
+                let min = config.min_gap_compressed_identity.unwrap_or(0.0);
+                self.records.iter().all(|record| {
+                    metrics::gap_compressed_divergence(record)
+                        .is_some_and(|divergence| 1.0 - divergence >= min)
+                })
+            }
+            Thresholds::MAX_DE => {
+                let max = config.max_divergence.unwrap_or(1.0);
+                self.records.iter().all(|record| {
+                    metrics::divergence(record).is_some_and(|divergence| divergence <= max)
+                })
+            }
+            Thresholds::MIN_MAPQ => {
+                record1.mapq() >= config.min_mapq && record2.mapq() >= config.min_mapq
+            }
+            Thresholds::MAX_SPLICE_JUNCTIONS => {
+                let max = config.max_splice_junctions.unwrap_or(u32::MAX);
+                count_splice_junctions(record1) <= max && count_splice_junctions(record2) <= max
+            }
+            Thresholds::HASH_SAMPLE => config
+                .hash_sample
+                .is_none_or(|sample| sample.contains(record1.qname())),
+            // Reads without base qualities (QUAL `*`) fail
+            Thresholds::MIN_AVG_BASEQ => {
+                let min = config.min_avg_baseq.unwrap_or(0.0);
+                self.records.iter().all(|record| {
+                    quality::mean_base_quality(record).is_some_and(|mean| mean >= min)
+                })
+            }
+            Thresholds::INSERT_SIZE => {
+                let insert = record1.insert_size().unsigned_abs();
+                config.min_insert.is_none_or(|min| insert >= min)
+                    && config.max_insert.is_none_or(|max| insert <= max)
+            }
+            // An unmapped mate has no reference to share
+            Thresholds::SAME_REFERENCE => {
+                !record1.is_unmapped()
+                    && !record2.is_unmapped()
+                    && record1.tid() >= 0
+                    && record1.tid() == record2.tid()
+            }
+            Thresholds::MIN_BASEQ_FRACTION => config
+                .min_baseq_fraction
+                .is_none_or(|threshold| self.records.iter().all(|record| threshold.passes(record))),
+            Thresholds::MAX_NM => {
+                let max = config.max_nm.unwrap_or(u32::MAX) as i64;
+                self.records
+                    .iter()
+                    .all(|record| config.edits_pass(record, |_, nm| nm <= max))
+            }
+            // Reads with no aligned bases have no rate and fail
+            Thresholds::MAX_ERROR_RATE => {
+                let max = config.max_error_rate.unwrap_or(1.0);
+                self.records.iter().all(|record| {
+                    config.edits_pass(record, |record, _| {
+                        metrics::error_rate(record).is_some_and(|rate| rate <= max)
+                    })
+                })
+            }
+            _ => unreachable!("the filter expression is evaluated on its own"),
+        }
+    }
+}
+
+/// A keep-or-remove decision on a pair, as [`crate::sink::filter_into`] applies it
+///
+/// [`FilterConfig`] is the filter of the binary. A closure over the two
+/// mates is one too, so a pipeline embedding the crate can add its own
+/// conditions:
+///
+/// ```
+/// use filter_bam_pairs::test_utils::{mapped_pair, random_sequence};
+/// use filter_bam_pairs::{FilterConfig, PairFilter};
+/// use rust_htslib::bam::Record;
+///
+/// let config = FilterConfig {
+///     complexity: 0.7,
+///     ..FilterConfig::default()
+/// };
+/// let proper = |record1: &Record, record2: &Record| {
+///     record1.is_proper_pair() && config.keep(record1, record2)
+/// };
+/// let seq = random_sequence(100, 1);
+/// let (r1, r2) = mapped_pair("read1", &seq, &seq);
+/// assert!(proper.keep(&r1.build(), &r2.build()));
+/// ```
+pub trait PairFilter {
+    /// Whether the pair is kept
+    fn keep(&self, record1: &bam::Record, record2: &bam::Record) -> bool;
+
+    /// Reject settings that can never make sense, before the first pair
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl PairFilter for FilterConfig {
+    fn keep(&self, record1: &bam::Record, record2: &bam::Record) -> bool {
+        self.evaluate(record1, record2, &mut 0).keep
+    }
+
+    fn validate(&self) -> Result<()> {
+        FilterConfig::validate(self)
+    }
+}
+
+impl<F: Fn(&bam::Record, &bam::Record) -> bool> PairFilter for F {
+    fn keep(&self, record1: &bam::Record, record2: &bam::Record) -> bool {
+        self(record1, record2)
+    }
+}
+
+/// Fail unless two consecutive records are mates (input must be name-sorted)
+pub fn check_pair_names(record1: &bam::Record, record2: &bam::Record) -> Result<()> {
+    if record1.qname() != record2.qname() {
+        let hint = if !record1.is_paired() && !record2.is_paired() {
+            "\n  Neither read is flagged as paired; use --single-end for single-end BAMs"
+        } else {
+            ""
+        };
+        bail!(
+            "BAM file not properly name-sorted!\n  Read 1: {}\n  Read 2: {}\n\
+             Please sort: samtools sort -n input.bam -o name_sorted.bam{}",
+            String::from_utf8_lossy(record1.qname()),
+            String::from_utf8_lossy(record2.qname()),
+            hint
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Record selection by SAM flag before pairing (`--require-flags`, `--exclude-flags`)
+//!
+//! As with `samtools view -f/-F`, a record is read only when it has every
+//! required flag bit and none of the excluded ones; the rest are skipped as
+//! if absent from the input. Excluding `SECONDARY,SUPPLEMENTARY` leaves the
+//! primary records a name-sorted BAM pairs up from, which extra alignments
+//! would otherwise break apart.
+//!
+//! Flags are given as a number (`256`, `0x900`, `04` in octal) or as
+//! comma-separated names, which may be mixed: `SECONDARY,0x800`.
+
+use rust_htslib::{bam, bam::Read, errors::Error as HtsError};
+
+/// Flag bits by their `samtools flags` names
+const NAMES: [(&str, u16); 12] = [
+    ("PAIRED", 0x1),
+    ("PROPER_PAIR", 0x2),
+    ("UNMAP", 0x4),
+    ("MUNMAP", 0x8),
+    ("REVERSE", 0x10),
+    ("MREVERSE", 0x20),
+    ("READ1", 0x40),
+    ("READ2", 0x80),
+    ("SECONDARY", 0x100),
+    ("QCFAIL", 0x200),
+    ("DUP", 0x400),
+    ("SUPPLEMENTARY", 0x800),
+];
+
+fn parse_number(value: &str) -> Option<u16> {
+    if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        u16::from_str_radix(hex, 16).ok()
+    } else if value.len() > 1 && value.starts_with('0') {
+        u16::from_str_radix(&value[1..], 8).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+/// Parse a flag value: a number, or comma-separated names and numbers
+pub fn parse_flags(value: &str) -> Result<u16, String> {
+    value.split(',').try_fold(0u16, |flags, part| {
+        let part = part.trim();
+        let bits = parse_number(part).or_else(|| {
+            NAMES
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(part))
+                .map(|&(_, bit)| bit)
+        });
+        match bits {
+            Some(bits) if bits < 0x1000 => Ok(flags | bits),
+            _ => Err(format!(
+                "'{}' is not a flag; use a number below 4096 or one of {}",
+                part,
+                NAMES.map(|(name, _)| name).join(", ")
+            )),
+        }
+    })
+}
+
+/// The names of the bits set in `flags`
+pub fn describe(flags: u16) -> String {
+    NAMES
+        .iter()
+        .filter(|(_, bit)| flags & bit != 0)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Required and excluded flag bits, with the count of records skipped
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlagFilter {
+    pub require: u16,
+    pub exclude: u16,
+    skipped: u64,
+}
+
+impl FlagFilter {
+    pub fn new(require: u16, exclude: u16) -> Self {
+        FlagFilter {
+            require,
+            exclude,
+            skipped: 0,
+        }
+    }
+
+    /// Whether the filter lets every record through
+    pub fn is_open(&self) -> bool {
+        self.require == 0 && self.exclude == 0
+    }
+
+    pub fn passes(&self, record: &bam::Record) -> bool {
+        let flags = record.flags();
+        flags & self.require == self.require && flags & self.exclude == 0
+    }
+
+    /// Read the next record that passes into `record`, counting those skipped
+    pub fn read<R: Read>(
+        &mut self,
+        reader: &mut R,
+        record: &mut bam::Record,
+    ) -> Option<Result<(), HtsError>> {
+        self.read_from(|record| reader.read(record), record)
+    }
+
+    /// [`read`](Self::read) with records from `read` rather than a reader
+    pub fn read_from(
+        &mut self,
+        mut read: impl FnMut(&mut bam::Record) -> Option<Result<(), HtsError>>,
+        record: &mut bam::Record,
+    ) -> Option<Result<(), HtsError>> {
+        loop {
+            match read(record) {
+                Some(Ok(())) if !self.passes(record) => self.skipped += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// Records skipped so far
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
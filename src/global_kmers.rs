@@ -0,0 +1,280 @@
+//! Dataset-wide kmer frequencies (`--max-global-kmer-percentile`)
+//!
+//! Per-read complexity can't see a contaminant, adapter dimer or spike-in
+//! that is complex within each read but makes up a large share of the
+//! library. A pass before filtering counts every canonical kmer of the
+//! input's primary reads in a counting Bloom filter of fixed size; kmers
+//! counted more often than the given percentile of distinct kmers are
+//! high-frequency, and a pair is removed when either mate consists mostly of
+//! them.
+//!
+//! The filter updates conservatively (only the lowest of a kmer's counters
+//! grows), so a count is never underestimated and rarely overestimated while
+//! the filter has room. The percentile is taken over a uniform sample of the
+//! distinct kmers seen, chosen by hash. Instead of the pass, counts can come
+//! from a Jellyfish or KMC dump of the same data ([`GlobalKmers::load`]).
+
+use crate::{input, kmer_db};
+use anyhow::{bail, Result};
+use rust_htslib::{bam, bam::Read};
+use std::collections::HashSet;
+
+/// Counting Bloom filter size when `--global-kmer-memory` is not given, in MiB
+pub const DEFAULT_MEMORY_MIB: usize = 1024;
+
+/// Counter positions per kmer
+const HASHES: u64 = 4;
+
+/// Distinct kmers kept for the percentile before the sample is thinned
+const MAX_SAMPLE: usize = 1 << 20;
+
+/// Salt that makes the sample hash independent of the counter positions
+const SAMPLE_SALT: u64 = 0x5151_5151_5151_5151;
+
+/// A well-mixed 64-bit hash of a packed kmer (splitmix64 finalizer)
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+/// Whether a kmer belongs to the percentile sample at one in `rate` kmers
+fn in_sample(kmer: u64, rate: u64) -> bool {
+    mix(kmer ^ SAMPLE_SALT).is_multiple_of(rate)
+}
+
+fn base_code(base: u8) -> Option<u64> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// The canonical (smaller of forward and reverse complement) 2-bit packed
+/// kmers of a sequence; windows containing N or other ambiguity codes are skipped
+///
+/// `k` must be between 1 and 32.
+pub fn canonical_kmers(sequence: &[u8], k: usize) -> impl Iterator<Item = u64> + '_ {
+    let mask = if k >= 32 {
+        u64::MAX
+    } else {
+        (1u64 << (2 * k)) - 1
+    };
+    let shift = 2 * (k as u64 - 1);
+    let (mut forward, mut reverse, mut valid) = (0u64, 0u64, 0usize);
+    sequence.iter().filter_map(move |&base| {
+        let Some(code) = base_code(base) else {
+            valid = 0;
+            return None;
+        };
+        forward = ((forward << 2) | code) & mask;
+        reverse = (reverse >> 2) | ((3 - code) << shift);
+        valid += 1;
+        (valid >= k).then(|| forward.min(reverse))
+    })
+}
+
+/// Fixed-size counting Bloom filter of 16-bit saturating counters
+pub struct CountingBloom {
+    counters: Vec<u16>,
+}
+
+impl CountingBloom {
+    /// A filter using `memory` bytes
+    pub fn new(memory: usize) -> Self {
+        CountingBloom {
+            counters: vec![0; (memory / 2).max(1)],
+        }
+    }
+
+    fn positions(&self, kmer: u64) -> impl Iterator<Item = usize> {
+        let h1 = mix(kmer);
+        let h2 = mix(h1) | 1;
+        let len = self.counters.len() as u64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// Count `times` occurrences of `kmer`
+    pub fn add(&mut self, kmer: u64, times: u64) {
+        let positions: [usize; HASHES as usize] = {
+            let mut positions = self.positions(kmer);
+            std::array::from_fn(|_| positions.next().expect("HASHES positions"))
+        };
+        let lowest = positions
+            .iter()
+            .map(|&position| self.counters[position])
+            .min()
+            .unwrap_or(0);
+        let raised = u16::try_from(lowest as u64 + times).unwrap_or(u16::MAX);
+        for position in positions {
+            let counter = &mut self.counters[position];
+            *counter = (*counter).max(raised);
+        }
+    }
+
+    /// Occurrences of `kmer`, possibly overestimated
+    pub fn count(&self, kmer: u64) -> u16 {
+        self.positions(kmer)
+            .map(|position| self.counters[position])
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Bytes held by the counters
+    pub fn memory(&self) -> usize {
+        self.counters.len() * 2
+    }
+}
+
+/// Counts of every kmer of the input, and the count that makes one high-frequency
+pub struct GlobalKmers {
+    k: usize,
+    counts: CountingBloom,
+    /// Kmers counted more often than this are high-frequency
+    threshold: u16,
+    /// Reads with more than this fraction of high-frequency kmers fail
+    max_fraction: f64,
+    /// Kmer occurrences counted
+    pub kmers: u64,
+    /// Distinct kmers in the percentile sample
+    pub sampled: usize,
+}
+
+/// Counts being gathered, with the sample of distinct kmers for the percentile
+struct Tally {
+    counts: CountingBloom,
+    sample: HashSet<u64>,
+    sample_rate: u64,
+    kmers: u64,
+}
+
+impl Tally {
+    fn new(memory: usize) -> Self {
+        Tally {
+            counts: CountingBloom::new(memory),
+            sample: HashSet::new(),
+            sample_rate: 1,
+            kmers: 0,
+        }
+    }
+
+    fn add(&mut self, kmer: u64, times: u64) {
+        self.counts.add(kmer, times);
+        self.kmers += times;
+        // Keep the kmers whose hash falls in the sampled fraction, halving it when full
+        if in_sample(kmer, self.sample_rate) && self.sample.insert(kmer) {
+            while self.sample.len() > MAX_SAMPLE {
+                self.sample_rate *= 2;
+                let rate = self.sample_rate;
+                self.sample.retain(|&kmer| in_sample(kmer, rate));
+            }
+        }
+    }
+
+    /// Set the threshold at `percentile` (0-100) of the sampled kmers' counts
+    fn finish(self, k: usize, percentile: f64, max_fraction: f64) -> GlobalKmers {
+        let mut sampled: Vec<u16> = self
+            .sample
+            .iter()
+            .map(|&kmer| self.counts.count(kmer))
+            .collect();
+        sampled.sort_unstable();
+        let threshold = match sampled.len() {
+            0 => u16::MAX,
+            len => {
+                let rank = (percentile / 100.0 * (len - 1) as f64).round() as usize;
+                sampled[rank.min(len - 1)]
+            }
+        };
+        GlobalKmers {
+            k,
+            counts: self.counts,
+            threshold,
+            max_fraction,
+            kmers: self.kmers,
+            sampled: sampled.len(),
+        }
+    }
+}
+
+fn check_percentile(percentile: f64) -> Result<()> {
+    if !(0.0..=100.0).contains(&percentile) {
+        bail!("--max-global-kmer-percentile must be between 0 and 100");
+    }
+    Ok(())
+}
+
+impl GlobalKmers {
+    /// Count the kmers of every primary read of `path`, then set the
+    /// threshold at `percentile` (0-100) of the distinct kmers' counts
+    pub fn build(
+        path: &str,
+        reference: Option<&str>,
+        k: usize,
+        percentile: f64,
+        max_fraction: f64,
+        memory: usize,
+    ) -> Result<Self> {
+        check_percentile(percentile)?;
+        let mut reader = input::reopen(path, reference)?;
+        let mut tally = Tally::new(memory);
+        let mut record = bam::Record::new();
+        while let Some(result) = reader.read(&mut record) {
+            result?;
+            if record.is_secondary() || record.is_supplementary() {
+                continue;
+            }
+            let seq = record.seq().as_bytes();
+            for kmer in canonical_kmers(&seq, k) {
+                tally.add(kmer, 1);
+            }
+        }
+        Ok(tally.finish(k, percentile, max_fraction))
+    }
+
+    /// Take the counts from a kmer count dump (see [`kmer_db`]) instead of the input
+    pub fn load(
+        path: &str,
+        k: usize,
+        percentile: f64,
+        max_fraction: f64,
+        memory: usize,
+    ) -> Result<Self> {
+        check_percentile(percentile)?;
+        let mut tally = Tally::new(memory);
+        kmer_db::read_counts(path, k, |kmer, count| tally.add(kmer, count))?;
+        Ok(tally.finish(k, percentile, max_fraction))
+    }
+
+    /// Kmers counted more often than this are high-frequency
+    pub fn threshold(&self) -> u16 {
+        self.threshold
+    }
+
+    pub fn memory(&self) -> usize {
+        self.counts.memory()
+    }
+
+    /// Share of a read's kmers that are high-frequency (0 for reads shorter than k)
+    pub fn high_frequency_fraction(&self, record: &bam::Record) -> f64 {
+        let seq = record.seq().as_bytes();
+        let (mut total, mut high) = (0u64, 0u64);
+        for kmer in canonical_kmers(&seq, self.k) {
+            total += 1;
+            high += (self.counts.count(kmer) > self.threshold) as u64;
+        }
+        if total == 0 {
+            0.0
+        } else {
+            high as f64 / total as f64
+        }
+    }
+
+    /// Whether a read is not predominantly made of high-frequency kmers
+    pub fn passes(&self, record: &bam::Record) -> bool {
+        self.high_frequency_fraction(record) <= self.max_fraction
+    }
+}
@@ -0,0 +1,198 @@
+//! Grouping the records of one read name into a pair (`--extra-alignments`)
+//!
+//! A name-sorted BAM from `bwa mem -M` or minimap2 holds more than two
+//! records for a template with split or multi-mapped reads: the primary first
+//! and last mates plus secondary (0x100) and supplementary (0x800)
+//! alignments, in any order within the name. Records are collected while the
+//! name stays the same; the pair is judged on its two primary records and
+//! the extra alignments share its verdict, written along with it (`carry`)
+//! or left out of every output (`drop`).
+//!
+//! A name whose two primary records are complete starts a new group at its
+//! next primary record, so names reused by merged inputs still pair up as
+//! before and are left to `--check-name-collisions`.
+//!
+//! Records are read into buffers from a [`RecordPool`]; templates given back
+//! with [`Grouper::recycle`] supply the records of later ones.
+
+use crate::filter::check_pair_names;
+use crate::recycle::RecordPool;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use rust_htslib::bam;
+
+/// What happens to the secondary and supplementary records of a pair
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ExtraAlignments {
+    /// Write them with their pair to the BAM outputs
+    #[default]
+    Carry,
+    /// Leave them out of every output
+    Drop,
+}
+
+/// The records of one read name
+#[derive(Debug)]
+pub struct Template {
+    /// Primary first mate (the first primary record when flags don't say)
+    pub record1: bam::Record,
+    /// Primary last mate
+    pub record2: bam::Record,
+    /// Secondary and supplementary records, in input order
+    pub extras: Vec<bam::Record>,
+}
+
+fn is_extra(record: &bam::Record) -> bool {
+    record.is_secondary() || record.is_supplementary()
+}
+
+/// Collects consecutive same-name records into [`Template`]s
+#[derive(Default)]
+pub struct Grouper {
+    /// First record of the next group, read past the end of the last one
+    next: Option<bam::Record>,
+    /// Primary record left without a mate at the end of the input
+    unpaired: Option<bam::Record>,
+    extras: u64,
+    pool: RecordPool,
+    /// Name and primary records of the group being read, kept for their capacity
+    name: Vec<u8>,
+    primaries: Vec<bam::Record>,
+}
+
+impl Grouper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Secondary and supplementary records grouped so far
+    pub fn extras(&self) -> u64 {
+        self.extras
+    }
+
+    /// The lone primary record of the last name, once `next_template_from`
+    /// has returned `None`
+    pub fn take_unpaired(&mut self) -> Option<bam::Record> {
+        self.unpaired.take()
+    }
+
+    /// Give a template's records back to be read into again
+    pub fn recycle(&mut self, template: Template) {
+        self.pool.give(template.record1);
+        self.pool.give(template.record2);
+        self.pool.give_vec(template.extras);
+    }
+
+    /// The next template with records from `read`, which has the signature
+    /// of [`bam::Read::read`] (with any error type), or `None` at the end of
+    /// the input
+    pub fn next_template_from<E: Into<anyhow::Error>>(
+        &mut self,
+        mut read: impl FnMut(&mut bam::Record) -> Option<Result<(), E>>,
+    ) -> Option<Result<Template>> {
+        let first = match self.next.take() {
+            Some(record) => record,
+            None => {
+                let mut record = self.pool.take();
+                match read(&mut record)? {
+                    Ok(()) => record,
+                    Err(e) => return Some(Err(e.into())),
+                }
+            }
+        };
+
+        self.name.clear();
+        self.name.extend_from_slice(first.qname());
+        let mut primaries = std::mem::take(&mut self.primaries);
+        let mut extras = self.pool.take_vec();
+        let mut record = first;
+        loop {
+            if is_extra(&record) {
+                extras.push(record);
+            } else {
+                primaries.push(record);
+            }
+            record = self.pool.take();
+            match read(&mut record) {
+                Some(Ok(())) => {}
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => {
+                    self.pool.give(record);
+                    break;
+                }
+            }
+            if record.qname() != self.name || (primaries.len() == 2 && !is_extra(&record)) {
+                self.next = Some(record);
+                break;
+            }
+        }
+        self.extras += extras.len() as u64;
+
+        let result = self.template_of(&mut primaries, extras);
+        self.primaries = primaries;
+        result
+    }
+
+    /// The template of one name's records, or why they don't make one
+    fn template_of(
+        &mut self,
+        primaries: &mut Vec<bam::Record>,
+        extras: Vec<bam::Record>,
+    ) -> Option<Result<Template>> {
+        if primaries.len() == 2 {
+            let record2 = primaries.pop().unwrap();
+            let record1 = primaries.pop().unwrap();
+            let (record1, record2) =
+                if record1.is_last_in_template() && record2.is_first_in_template() {
+                    (record2, record1)
+                } else {
+                    (record1, record2)
+                };
+            return Some(Ok(Template {
+                record1,
+                record2,
+                extras,
+            }));
+        }
+
+        match (primaries.pop(), &self.next) {
+            // One record, then a differently named one: an unsorted input
+            (Some(record), Some(next)) if extras.is_empty() => Some(Err(check_pair_names(
+                &record, next,
+            )
+            .expect_err("the names differ"))),
+            (Some(record), None) => {
+                self.unpaired = Some(record);
+                None
+            }
+            (primary, _) => Some(Err(unpaired_error(&self.name, primary.is_some(), &extras))),
+        }
+    }
+
+    /// Fail on a primary record left without a mate at the end of the input
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(record) = self.take_unpaired() {
+            bail!(
+                "Unpaired read {} at end of input",
+                String::from_utf8_lossy(record.qname())
+            );
+        }
+        Ok(())
+    }
+
+    /// Read until the next complete template, or `None` at the end of the input
+    pub fn next_template<R: bam::Read>(&mut self, reader: &mut R) -> Option<Result<Template>> {
+        self.next_template_from(|record| reader.read(record))
+    }
+}
+
+fn unpaired_error(name: &[u8], has_primary: bool, extras: &[bam::Record]) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Read {} has {} primary record(s) and {} secondary or supplementary ones; \
+         its mate is missing or the BAM is not properly name-sorted\n  \
+         Please sort: samtools sort -n input.bam -o name_sorted.bam",
+        String::from_utf8_lossy(name),
+        has_primary as usize,
+        extras.len()
+    )
+}
@@ -0,0 +1,97 @@
+//! SAM header helpers: @PG provenance for runs of this tool
+
+use rust_htslib::bam;
+
+/// Program name recorded in @PG lines
+pub const PROGRAM_NAME: &str = "filter_bam_pairs";
+
+/// A @PG entry left in a header by an earlier run of this tool
+pub struct PreviousRun {
+    pub id: String,
+    pub version: Option<String>,
+    pub command_line: Option<String>,
+}
+
+/// Whether a @PG ID was generated by this tool (`filter_bam_pairs` or `filter_bam_pairs.N`)
+fn is_own_id(id: &str) -> bool {
+    id == PROGRAM_NAME
+        || id
+            .strip_prefix(PROGRAM_NAME)
+            .and_then(|rest| rest.strip_prefix('.'))
+            .is_some_and(|n| n.parse::<u32>().is_ok())
+}
+
+/// Find @PG entries written by earlier runs of this tool
+pub fn previous_runs(header: &bam::Header) -> Vec<PreviousRun> {
+    let records = header.to_hashmap();
+    let Some(programs) = records.get("PG") else {
+        return Vec::new();
+    };
+
+    programs
+        .iter()
+        .filter(|pg| {
+            pg.get("PN").map(String::as_str) == Some(PROGRAM_NAME)
+                || pg.get("ID").is_some_and(|id| is_own_id(id))
+        })
+        .map(|pg| PreviousRun {
+            id: pg.get("ID").cloned().unwrap_or_default(),
+            version: pg.get("VN").cloned(),
+            command_line: pg.get("CL").cloned(),
+        })
+        .collect()
+}
+
+/// Append a @PG line for this run, chained to the last existing @PG via PP
+pub fn add_program_record(header: &mut bam::Header) {
+    let records = header.to_hashmap();
+    let existing: Vec<String> = records
+        .get("PG")
+        .map(|pgs| pgs.iter().filter_map(|pg| pg.get("ID").cloned()).collect())
+        .unwrap_or_default();
+
+    // IDs must be unique within a header, so number repeated runs
+    let mut id = PROGRAM_NAME.to_string();
+    let mut n = 1;
+    while existing.contains(&id) {
+        id = format!("{}.{}", PROGRAM_NAME, n);
+        n += 1;
+    }
+
+    let command_line = std::env::args().collect::<Vec<_>>().join(" ");
+
+    let mut record = bam::header::HeaderRecord::new(b"PG");
+    record
+        .push_tag(b"ID", &id)
+        .push_tag(b"PN", PROGRAM_NAME)
+        .push_tag(b"VN", env!("CARGO_PKG_VERSION"));
+    if let Some(previous) = existing.last() {
+        record.push_tag(b"PP", previous);
+    }
+    record.push_tag(b"CL", &command_line);
+
+    header.push_record(&record);
+}
+
+/// Copy of `header` whose @HD line declares sort order `order` (e.g. `coordinate`)
+pub fn with_sort_order(header: &bam::Header, order: &str) -> bam::Header {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+
+    match lines.first_mut() {
+        Some(hd) if hd.starts_with("@HD") => {
+            let mut fields: Vec<String> = hd
+                .split('\t')
+                .filter(|field| !field.starts_with("SO:") && !field.starts_with("GO:"))
+                .map(str::to_string)
+                .collect();
+            fields.push(format!("SO:{}", order));
+            *hd = fields.join("\t");
+        }
+        _ => lines.insert(0, format!("@HD\tVN:1.6\tSO:{}", order)),
+    }
+
+    let mut bytes = lines.join("\n").into_bytes();
+    bytes.push(b'\n');
+    bam::Header::from_template(&bam::HeaderView::from_bytes(&bytes))
+}
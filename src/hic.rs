@@ -0,0 +1,82 @@
+//! Hi-C helpers: ligation junction detection
+
+use crate::fastq;
+use rust_htslib::bam;
+use std::fmt;
+
+/// Aux tag marking the offset of a ligation junction within a read (`xj:i`)
+pub const TAG_JUNCTION: &[u8] = b"xj";
+
+/// The junction motifs of `--ligation-motif`
+///
+/// Several motifs, as a multi-enzyme kit leaves (Arima's `GATCGATC,GANTGATC`),
+/// are given comma-separated, and `N` matches any base. A read's stored
+/// sequence runs along the reference, so a motif that is not palindromic
+/// shows up reverse-complemented in reverse-strand reads; every motif is
+/// searched for on both strands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LigationMotifs {
+    motifs: Vec<Vec<u8>>,
+    /// The motifs and the reverse complements that differ from them
+    patterns: Vec<Vec<u8>>,
+}
+
+/// Parse `--ligation-motif`: comma-separated motifs of A, C, G, T and N
+pub fn parse_motifs(value: &str) -> Result<LigationMotifs, String> {
+    let mut motifs = Vec::new();
+    for motif in value.split(',') {
+        let motif = motif.trim().to_ascii_uppercase().into_bytes();
+        if motif.is_empty() {
+            return Err("expected comma-separated motifs, e.g. GATCGATC,GANTGATC".to_string());
+        }
+        if let Some(&base) = motif.iter().find(|base| !b"ACGTN".contains(base)) {
+            return Err(format!(
+                "'{}' is not a motif base; use A, C, G, T or N",
+                base as char
+            ));
+        }
+        if motif.iter().all(|&base| base == b'N') {
+            return Err("a motif of only N would match every read".to_string());
+        }
+        motifs.push(motif);
+    }
+    let mut patterns: Vec<Vec<u8>> = Vec::new();
+    for motif in &motifs {
+        for pattern in [motif.clone(), fastq::reverse_complement(motif)] {
+            if !patterns.contains(&pattern) {
+                patterns.push(pattern);
+            }
+        }
+    }
+    Ok(LigationMotifs { motifs, patterns })
+}
+
+impl LigationMotifs {
+    /// Offset of the first junction on either strand in the read's stored
+    /// sequence
+    pub fn find_junction(&self, record: &bam::Record) -> Option<usize> {
+        let seq = record.seq().as_bytes();
+        self.patterns
+            .iter()
+            .filter_map(|pattern| {
+                seq.windows(pattern.len()).position(|window| {
+                    window
+                        .iter()
+                        .zip(pattern)
+                        .all(|(&base, &motif)| motif == b'N' || base == motif)
+                })
+            })
+            .min()
+    }
+}
+
+impl fmt::Display for LigationMotifs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let motifs: Vec<&str> = self
+            .motifs
+            .iter()
+            .map(|motif| std::str::from_utf8(motif).unwrap_or_default())
+            .collect();
+        write!(f, "{}", motifs.join(","))
+    }
+}
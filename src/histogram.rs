@@ -0,0 +1,104 @@
+//! Complexity histograms: per read (`--complexity-histogram`) and per pair
+//! for the `--stats-out` percentiles
+//!
+//! The 0.8 default cutoff suits typical short-read libraries, but where a
+//! library's complexity sits depends on its read length, kmer size and
+//! source. `--complexity-histogram` counts every read (both mates of a pair)
+//! in bins of 0.01, so the cutoff can be put at the valley between the
+//! low-complexity peak and the bulk of the reads. Alongside each bin is the
+//! fraction of reads below it, which `--complexity` set to the bin's lower
+//! edge would remove. Reads shorter than the kmer size have no complexity
+//! and are left out.
+//!
+//! Both uses bin alike, only the bin width differs: a value goes to the bin
+//! whose lower edge is at or below it, and 1.0 has a bin of its own.
+
+use anyhow::{Context, Result};
+use std::io::Write;
+
+/// Bins per unit of complexity of `--complexity-histogram`
+pub const READ_BINS: usize = 100;
+
+/// Complexity values in bins `[i / bins, (i + 1) / bins)`; 1.0 has its own
+#[derive(Debug, Clone)]
+pub struct ComplexityHistogram {
+    bins: Vec<u64>,
+    total: u64,
+}
+
+impl ComplexityHistogram {
+    /// An empty histogram of `bins` bins per unit of complexity
+    pub fn new(bins: usize) -> Self {
+        ComplexityHistogram {
+            bins: vec![0; bins + 1],
+            total: 0,
+        }
+    }
+
+    /// Bins per unit of complexity
+    fn bins_per_unit(&self) -> usize {
+        self.bins.len() - 1
+    }
+
+    pub fn record(&mut self, complexity: f64) {
+        let bins = self.bins_per_unit();
+        // The small offset keeps values such as 0.29 out of the bin below
+        let bin = (complexity.clamp(0.0, 1.0) * bins as f64 + 1e-9).floor() as usize;
+        self.bins[bin.min(bins)] += 1;
+        self.total += 1;
+    }
+
+    /// Values recorded so far
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Values in bin `bin`, the one starting at `bin / bins`
+    pub fn count(&self, bin: usize) -> u64 {
+        self.bins[bin]
+    }
+
+    /// The lower edge of the bin holding the value `percentile` percent of
+    /// the values are at or below; `None` before any value
+    pub fn percentile(&self, percentile: u32) -> Option<f64> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = (self.total * percentile as u64).div_ceil(100).max(1);
+        let mut seen = 0;
+        self.bins.iter().enumerate().find_map(|(bin, &count)| {
+            seen += count;
+            (seen >= rank).then_some(bin as f64 / self.bins_per_unit() as f64)
+        })
+    }
+
+    /// One row per bin, empty ones included, so the file plots as is
+    pub fn write_tsv(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+
+        // As many decimals as the bin width has
+        let decimals = (self.bins_per_unit() as f64).log10().ceil() as usize;
+        writeln!(out, "complexity\treads\tfraction_below")?;
+        let mut below = 0;
+        for (bin, &reads) in self.bins.iter().enumerate() {
+            let fraction = if self.total == 0 {
+                0.0
+            } else {
+                below as f64 / self.total as f64
+            };
+            writeln!(
+                out,
+                "{:.*}\t{}\t{:.6}",
+                decimals,
+                bin as f64 / self.bins_per_unit() as f64,
+                reads,
+                fraction
+            )?;
+            below += reads;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
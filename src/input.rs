@@ -0,0 +1,205 @@
+//! Opening the input, which may be a pipe (`samtools collate -O`, `<(...)`,
+//! or standard input given as `-`)
+//!
+//! htslib reads pipes like files as long as nothing seeks, and the filtering
+//! pass never does. Options that read the input a second time cannot work on
+//! a pipe and are refused before anything is consumed.
+//!
+//! BAM, SAM and CRAM are all read; CRAM inputs decode their sequences
+//! against the `--reference` FASTA, or the reference htslib finds itself
+//! (`REF_PATH`, the `@SQ UR` field) when none is given. Paired FASTQ files
+//! are read by [`crate::fastq_input`] instead.
+
+use crate::fastq_input::FastqPairs;
+use crate::prefetch::Prefetcher;
+use crate::progress;
+use crate::read_errors::ReadErrors;
+use crate::regions::RegionReader;
+use anyhow::{bail, Context, Result};
+use rust_htslib::{bam, bam::Read, errors::Error as HtsError, htslib, tpool::ThreadPool};
+
+/// Read buffer used when `--input-buffer` is not given, in KiB
+pub const DEFAULT_BUFFER_KIB: usize = 1024;
+
+/// Input path meaning standard input
+pub const STDIN: &str = "-";
+
+/// Whether `path` is a FIFO, character device or socket rather than a file
+pub fn is_pipe(path: &str) -> bool {
+    if path == STDIN {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata(path).is_ok_and(|meta| {
+            let kind = meta.file_type();
+            kind.is_fifo() || kind.is_char_device() || kind.is_socket()
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Fail when options needing an extra pass over the input meet a pipe
+///
+/// `passes` lists the options given that re-read the input.
+pub fn check_rereadable(path: &str, passes: &[&str]) -> Result<()> {
+    if let (true, Some(option)) = (is_pipe(path), passes.first()) {
+        bail!(
+            "{} reads the input twice and cannot be used with a pipe ({}); write it to a file first",
+            option,
+            path
+        );
+    }
+    Ok(())
+}
+
+/// Grow the kernel buffer of the pipe at `path` towards `bytes`
+///
+/// Returns the capacity now in effect, or `None` where it can't be queried.
+/// Unprivileged processes are capped at `/proc/sys/fs/pipe-max-size` (1 MiB by
+/// default); larger requests fall back to the current size.
+pub fn raise_pipe_buffer(path: &str, bytes: usize) -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        let path = if path == STDIN { "/dev/stdin" } else { path };
+        use std::os::unix::io::AsRawFd;
+        // A second, non-blocking read end refers to the same pipe
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+            .ok()?;
+        let fd = file.as_raw_fd();
+        let wanted = libc::c_int::try_from(bytes).unwrap_or(libc::c_int::MAX);
+        // SAFETY: fcntl on an open descriptor; failures are reported by return value
+        unsafe {
+            libc::fcntl(fd, libc::F_SETPIPE_SZ, wanted);
+            usize::try_from(libc::fcntl(fd, libc::F_GETPIPE_SZ)).ok()
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (path, bytes);
+        None
+    }
+}
+
+fn use_reference(reader: &mut bam::Reader, reference: Option<&str>) -> Result<()> {
+    if let Some(reference) = reference {
+        reader
+            .set_reference(reference)
+            .with_context(|| format!("Cannot use reference {}", reference))?;
+    }
+    Ok(())
+}
+
+/// Open the input again for a prepass over it
+pub fn reopen(path: &str, reference: Option<&str>) -> Result<bam::Reader> {
+    let mut reader =
+        bam::Reader::from_path(path).with_context(|| format!("Cannot reopen {}", path))?;
+    use_reference(&mut reader, reference)?;
+    Ok(reader)
+}
+
+/// Read `path` through `reader` in blocks of `buffer_kib` KiB
+pub fn set_read_buffer(reader: &mut bam::Reader, buffer_kib: usize, path: &str) -> Result<()> {
+    let size = i32::try_from(buffer_kib.max(1) << 10)
+        .with_context(|| format!("A {} KiB read buffer is too large", buffer_kib))?;
+    // SAFETY: the file handle is open; HTS_OPT_BLOCK_SIZE takes one int
+    let status = unsafe {
+        htslib::hts_set_opt(
+            reader.htsfile(),
+            htslib::hts_fmt_option_HTS_OPT_BLOCK_SIZE,
+            size,
+        )
+    };
+    if status != 0 {
+        bail!("Cannot set the read buffer of {}", path);
+    }
+    Ok(())
+}
+
+/// Open the input, reading it in `buffer_kib` KiB blocks, decompressed by
+/// the threads of `thread_pool` if given
+///
+/// The block is the most htslib asks for per read call: the read-ahead for
+/// files, and for pipes the most it takes of what the writer has queued.
+pub fn open(
+    path: &str,
+    buffer_kib: usize,
+    reference: Option<&str>,
+    thread_pool: Option<&ThreadPool>,
+) -> Result<bam::Reader> {
+    let mut reader = if path == STDIN {
+        bam::Reader::from_stdin().context("Cannot read standard input")?
+    } else {
+        bam::Reader::from_path(path).with_context(|| format!("Cannot open {}", path))?
+    };
+    use_reference(&mut reader, reference)?;
+    set_read_buffer(&mut reader, buffer_kib, path)?;
+    if let Some(pool) = thread_pool {
+        reader
+            .set_thread_pool(pool)
+            .with_context(|| format!("Cannot share decompression threads with {}", path))?;
+    }
+    Ok(reader)
+}
+
+/// The records of the filter loop
+pub enum Source {
+    Direct(bam::Reader),
+    /// Read ahead on a thread of its own (`--prefetch-batches`)
+    Prefetched(Prefetcher),
+    /// The pairs in the `--regions` intervals, read through the index
+    Regions(RegionReader),
+    /// Pairs of `--input-r1` and `--input-r2` as unmapped records
+    Fastq(Box<FastqPairs>),
+}
+
+impl Source {
+    /// Read `reader` ahead with `queued` batches waiting; inline when 0
+    pub fn new(reader: bam::Reader, queued: usize) -> Self {
+        if queued == 0 {
+            Source::Direct(reader)
+        } else {
+            Source::Prefetched(Prefetcher::new(reader, queued))
+        }
+    }
+
+    pub fn read(&mut self, record: &mut bam::Record) -> Option<Result<(), HtsError>> {
+        match self {
+            Source::Direct(reader) => reader.read(record),
+            Source::Prefetched(prefetcher) => prefetcher.read(record),
+            Source::Regions(regions) => regions.read(record),
+            Source::Fastq(pairs) => pairs.read(record),
+        }
+    }
+
+    /// Deal with a failed read as [`ReadErrors::handle`] does
+    ///
+    /// Reads through the index have no position to skip from, and FASTQ
+    /// files out of step can't be paired again, so their errors end the run.
+    pub fn handle_error(&mut self, errors: &mut ReadErrors, error: anyhow::Error) -> Result<bool> {
+        match self {
+            Source::Direct(reader) => errors.handle(reader, error),
+            Source::Prefetched(prefetcher) => errors.handle(prefetcher.reader(), error),
+            Source::Regions(_) => Err(error.context("Cannot read the --regions intervals")),
+            Source::Fastq(pairs) => Err(pairs.take_error().unwrap_or(error)),
+        }
+    }
+
+    /// Input consumed so far, for progress lines; unknown for index and FASTQ reads
+    pub fn bytes_read(&self) -> Option<u64> {
+        match self {
+            Source::Direct(reader) => progress::bytes_read(reader),
+            Source::Prefetched(prefetcher) => prefetcher.bytes_read(),
+            Source::Regions(_) | Source::Fastq(_) => None,
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Kmer sets and counts made by other tools (`--kmer-counts`, `--kmer-blacklist`)
+//!
+//! Both read the text dumps that Jellyfish and KMC write, optionally gzip or
+//! BGZF compressed:
+//!
+//! ```text
+//! ACGTACGTACGTACGTACGTA 12     jellyfish dump -c, kmc_tools ... dump
+//! >12                          jellyfish dump (FASTA: count, then kmer)
+//! ACGTACGTACGTACGTACGTA
+//! ACGTACGTACGTACGTACGTA        a bare list; each kmer counts once
+//! ```
+//!
+//! Empty lines and lines starting with `#` are skipped. Kmers must have the
+//! filter's kmer length, and a kmer and its reverse complement are the same
+//! entry, so dumps made with or without canonical counting (`jellyfish -C`)
+//! both work.
+
+use crate::global_kmers::canonical_kmers;
+use anyhow::{bail, Context, Result};
+use rust_htslib::{bam, bgzf};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader};
+
+/// The canonical packed form of a single kmer of length `k`
+fn pack(kmer: &[u8], k: usize) -> Option<u64> {
+    if kmer.len() != k {
+        return None;
+    }
+    canonical_kmers(kmer, k).next()
+}
+
+/// Call `visit(kmer, count)` for every entry of the dump at `path`,
+/// returning the number of entries
+pub fn read_counts(path: &str, k: usize, mut visit: impl FnMut(u64, u64)) -> Result<u64> {
+    let reader = bgzf::Reader::from_path(path).with_context(|| format!("Cannot open {}", path))?;
+    let reader = BufReader::with_capacity(1 << 20, reader);
+
+    let mut entries = 0u64;
+    // Count from a FASTA header, waiting for its kmer
+    let mut pending: Option<u64> = None;
+    for (index, line) in reader.split(b'\n').enumerate() {
+        let line = line.with_context(|| format!("Cannot read {}", path))?;
+        let at = || format!("{}:{}", path, index + 1);
+        let line = line.trim_ascii();
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        if let Some(count) = line.strip_prefix(b">") {
+            let count = std::str::from_utf8(count)
+                .ok()
+                .and_then(|c| c.trim().parse().ok());
+            match count {
+                Some(count) => pending = Some(count),
+                None => bail!("{}: expected a count after '>'", at()),
+            }
+            continue;
+        }
+
+        let mut fields = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|f| !f.is_empty());
+        let kmer = fields.next().unwrap_or_default();
+        let count = match fields.next() {
+            Some(count) => std::str::from_utf8(count)
+                .ok()
+                .and_then(|count| count.parse().ok())
+                .with_context(|| format!("{}: invalid count", at()))?,
+            None => pending.take().unwrap_or(1),
+        };
+        let Some(packed) = pack(kmer, k) else {
+            bail!(
+                "{}: '{}' is not a {}-mer of A, C, G and T; dump the counts with k = {}",
+                at(),
+                String::from_utf8_lossy(kmer),
+                k,
+                k
+            );
+        };
+        visit(packed, count);
+        entries += 1;
+    }
+    Ok(entries)
+}
+
+/// Kmers whose presence marks a read as contamination (`--kmer-blacklist`)
+pub struct KmerBlacklist {
+    k: usize,
+    kmers: HashSet<u64>,
+    /// Reads with more blacklisted kmers than this fail
+    max_hits: u32,
+}
+
+impl KmerBlacklist {
+    /// Read the kmers of a dump or list; counts are ignored
+    pub fn load(path: &str, k: usize, max_hits: u32) -> Result<Self> {
+        let mut kmers = HashSet::new();
+        read_counts(path, k, |kmer, _| {
+            kmers.insert(kmer);
+        })?;
+        Ok(KmerBlacklist { k, kmers, max_hits })
+    }
+
+    /// Distinct kmers in the blacklist
+    pub fn len(&self) -> usize {
+        self.kmers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kmers.is_empty()
+    }
+
+    /// Blacklisted kmers in a read, counting repeats
+    pub fn hits(&self, record: &bam::Record) -> u32 {
+        let seq = record.seq().as_bytes();
+        canonical_kmers(&seq, self.k)
+            .filter(|kmer| self.kmers.contains(kmer))
+            .count() as u32
+    }
+
+    pub fn passes(&self, record: &bam::Record) -> bool {
+        self.hits(record) <= self.max_hits
+    }
+}
@@ -0,0 +1,209 @@
+//! Read-length sanity checks against the length options (`--length-check`)
+//!
+//! A kmer size or `--min-mapped` longer than the reads doesn't fail any
+//! validation, it just removes nearly every pair. The lengths of the first
+//! reads are compared with both; a threshold above the median read length is
+//! reported, or with `adjust` lowered to half the median before filtering.
+
+use crate::complexity::ComplexityMethod;
+use crate::filter::{FilterConfig, ShortReadPolicy};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use rust_htslib::bam;
+use std::fmt;
+
+/// Reads whose lengths are sampled
+pub const SAMPLE_READS: usize = 10_000;
+
+/// What to do about length options that don't fit the reads
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LengthCheck {
+    /// Print a warning and carry on
+    #[default]
+    Warn,
+    /// Lower the kmer size and --min-mapped to half the median read length
+    Adjust,
+    /// Don't sample read lengths
+    Off,
+}
+
+/// Lengths of the first reads of the input
+#[derive(Debug, Default)]
+pub struct LengthSample {
+    lengths: Vec<usize>,
+}
+
+/// Shortest, median and longest of the sampled read lengths
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadLengths {
+    pub reads: usize,
+    pub min: usize,
+    pub median: usize,
+    pub max: usize,
+}
+
+impl LengthSample {
+    pub fn observe(&mut self, record: &bam::Record) {
+        if self.lengths.len() < SAMPLE_READS {
+            self.lengths.push(record.seq_len());
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.lengths.len() >= SAMPLE_READS
+    }
+
+    /// `None` before any read
+    pub fn lengths(&self) -> Option<ReadLengths> {
+        let mut lengths = self.lengths.clone();
+        lengths.sort_unstable();
+        Some(ReadLengths {
+            reads: lengths.len(),
+            min: *lengths.first()?,
+            median: lengths[lengths.len() / 2],
+            max: *lengths.last()?,
+        })
+    }
+}
+
+/// A length option that doesn't fit the sampled reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthProblem {
+    KmerSize {
+        kmer_size: usize,
+        lengths: ReadLengths,
+        policy: ShortReadPolicy,
+    },
+    MinMapped {
+        min_mapped: u32,
+        lengths: ReadLengths,
+    },
+}
+
+impl fmt::Display for LengthProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LengthProblem::KmerSize {
+                kmer_size,
+                lengths,
+                policy,
+            } => {
+                let outcome = match policy {
+                    ShortReadPolicy::Fail => "fail complexity",
+                    ShortReadPolicy::Pass => "pass complexity unchecked",
+                    ShortReadPolicy::Skip => "be skipped",
+                };
+                let share = if kmer_size > lengths.max {
+                    "every read"
+                } else {
+                    "most reads"
+                };
+                write!(
+                    f,
+                    "kmer size {} is longer than the median read length ({} bp of {} sampled reads); {} will {}",
+                    kmer_size, lengths.median, lengths.reads, share, outcome
+                )
+            }
+            LengthProblem::MinMapped {
+                min_mapped,
+                lengths,
+            } => {
+                let share = if min_mapped as usize > lengths.max {
+                    "no pair"
+                } else {
+                    "few pairs"
+                };
+                write!(
+                    f,
+                    "--min-mapped {} is longer than the median read length ({} bp of {} sampled reads); {} can pass",
+                    min_mapped, lengths.median, lengths.reads, share
+                )
+            }
+        }
+    }
+}
+
+impl LengthProblem {
+    /// The value `adjust` sets: half the median read length, at least 1
+    pub fn adjusted(&self) -> usize {
+        let lengths = match self {
+            LengthProblem::KmerSize { lengths, .. } | LengthProblem::MinMapped { lengths, .. } => {
+                lengths
+            }
+        };
+        (lengths.median / 2).max(1)
+    }
+}
+
+/// The length options of `config` that don't fit `lengths`
+pub fn problems(config: &FilterConfig, lengths: ReadLengths) -> Vec<LengthProblem> {
+    let mut problems = Vec::new();
+    if config.complexity_method == ComplexityMethod::KmerUniqueness
+        && config.kmer_size > lengths.median
+    {
+        problems.push(LengthProblem::KmerSize {
+            kmer_size: config.kmer_size,
+            lengths,
+            policy: config.short_reads,
+        });
+    }
+    if config.min_mapped as usize > lengths.median {
+        problems.push(LengthProblem::MinMapped {
+            min_mapped: config.min_mapped,
+            lengths,
+        });
+    }
+    problems
+}
+
+/// Warn about every problem of `config`, and of each read group's config,
+/// with the sampled lengths
+pub fn warn(
+    config: &FilterConfig,
+    read_group_configs: &[(String, FilterConfig)],
+    sample: &LengthSample,
+) {
+    let Some(lengths) = sample.lengths() else {
+        return;
+    };
+    for problem in problems(config, lengths) {
+        eprintln!("Warning: {}; see --length-check adjust", problem);
+    }
+    for (read_group, config) in read_group_configs {
+        for problem in problems(config, lengths) {
+            eprintln!(
+                "Warning: [read-group {}] {}; see --length-check adjust",
+                read_group, problem
+            );
+        }
+    }
+}
+
+/// Lower the length options of `config` that don't fit `lengths`, returning
+/// a line per change
+pub fn adjust(config: &mut FilterConfig, lengths: ReadLengths) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+    for problem in problems(config, lengths) {
+        let value = problem.adjusted();
+        match problem {
+            LengthProblem::KmerSize { kmer_size, .. } => {
+                if value < 2 {
+                    bail!("{}; the reads are too short to lower it", problem);
+                }
+                config.kmer_size = value;
+                changes.push(format!(
+                    "kmer size {} -> {} (median read length {} bp)",
+                    kmer_size, value, lengths.median
+                ));
+            }
+            LengthProblem::MinMapped { min_mapped, .. } => {
+                config.min_mapped = value as u32;
+                changes.push(format!(
+                    "--min-mapped {} -> {} (median read length {} bp)",
+                    min_mapped, value, lengths.median
+                ));
+            }
+        }
+    }
+    Ok(changes)
+}
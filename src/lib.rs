@@ -0,0 +1,87 @@
+//! Filter name-sorted paired-end BAM records by kmer complexity and mapped bases
+//!
+//! The `filter_bam_pairs` binary is built on these modules; [`filter`] holds
+//! the per-pair decision. A pipeline can filter without the binary: build a
+//! [`FilterConfig`] (or any other [`PairFilter`]) and hand it a reader and
+//! an [`sink::OutputSink`] through [`sink::filter_into`], or call
+//! [`calculate_kmer_complexity`] and [`get_longest_mapped_bases`] on records
+//! directly. [`pipeline::run`] is the binary's whole run, driven by the
+//! [`options::Args`] its command line parses into; [`commands`] holds that
+//! command line, config files included, and every subcommand.
+
+pub mod adaptive;
+pub mod annotate;
+pub mod audit;
+pub mod barcodes;
+pub mod bases;
+pub mod bed;
+pub mod bisulfite;
+pub mod chain;
+pub mod check;
+pub mod collisions;
+pub mod commands;
+pub mod complexity;
+pub mod config;
+pub mod contigs;
+pub mod decisions;
+pub mod depth;
+pub mod duplex;
+pub mod duplicates;
+pub mod expr;
+pub mod fastq;
+pub mod fastq_input;
+pub mod filter;
+pub mod flags;
+pub mod global_kmers;
+pub mod grouping;
+pub mod header;
+pub mod hic;
+pub mod histogram;
+pub mod input;
+pub mod kmer_db;
+pub mod lengths;
+pub mod mates;
+pub mod md;
+pub mod memory;
+pub mod metric_cache;
+pub mod metrics;
+pub mod molecules;
+pub mod names;
+pub mod nanopore;
+pub mod notify;
+pub mod options;
+pub mod output;
+pub mod pipeline;
+pub mod prefetch;
+pub mod primers;
+pub mod progress;
+pub mod provenance;
+pub mod quality;
+pub mod read_errors;
+pub mod recycle;
+pub mod regions;
+pub mod rejections;
+pub mod report;
+pub mod resync;
+pub mod sample;
+pub mod samples;
+pub mod signals;
+pub mod sink;
+pub mod sort;
+pub mod stats;
+pub mod summary;
+pub mod targets;
+pub mod timing;
+pub mod tmp;
+pub mod trace;
+pub mod units;
+pub mod validation;
+pub mod verify;
+
+#[cfg(feature = "test_utils")]
+pub mod test_utils;
+
+pub use filter::{
+    calculate_canonical_kmer_complexity, calculate_kmer_complexity, get_longest_mapped_bases,
+    get_longest_spliced_mapped_bases, FilterConfig, PairFilter, PairVerdict,
+};
@@ -0,0 +1,295 @@
+//! Pairing the mates of a coordinate-sorted input (`--coordinate-sorted`)
+//!
+//! Mates of a coordinate-sorted BAM are apart by the insert size, or on
+//! different sequences. A [`MatePairer`] keeps each primary record by name
+//! until its mate comes and then hands both out next to each other, so the
+//! rest of the run sees pairs as from a name-sorted input, in the order the
+//! second mates arrive.
+//!
+//! Records waiting for a mate are held up to a memory budget. Above it, the
+//! longest-waiting half goes to one of [`SPILL_PARTITIONS`] temporary BAMs in
+//! the work directory by a hash of the name, and a later mate of a spilled
+//! record follows it there. At the end of the input whatever still waits is
+//! spilled too, and each partition is read back and paired on its own, so
+//! memory stays within about one partition. Records whose mate never comes
+//! are counted and left out.
+//!
+//! Secondary and supplementary records are not paired, as their pair can't
+//! be told apart from reads of the whole input; they are counted and left
+//! out of every output.
+
+use crate::memory;
+use crate::prefetch;
+use anyhow::{Context, Result};
+use rust_htslib::{bam, errors::Error as HtsError};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::path::{Path, PathBuf};
+
+/// Files that records waiting past the memory budget are spread over
+pub const SPILL_PARTITIONS: usize = 64;
+
+/// Per-record bookkeeping on top of the record data, for the memory budget
+const RECORD_OVERHEAD: usize = std::mem::size_of::<bam::Record>() + 96;
+
+/// What a waiting record costs of the memory budget
+fn footprint(record: &bam::Record) -> usize {
+    record.inner().l_data as usize + record.qname().len() + RECORD_OVERHEAD
+}
+
+/// Records that waited on disk, or were not paired at all
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PairingCounts {
+    pub spilled: u64,
+    /// Records whose mate never came
+    pub unmatched: u64,
+    pub extras_skipped: u64,
+}
+
+/// Where the pairer is in the input
+enum Stage {
+    Reading,
+    /// Pairing the spill partition at this index
+    Partition(usize),
+    Done,
+}
+
+/// Pairs the mates of a coordinate-sorted record stream by name
+pub struct MatePairer {
+    header: bam::Header,
+    spill_dir: PathBuf,
+    memory_limit: usize,
+    /// Records waiting for their mate, with the serial number of their arrival
+    waiting: HashMap<Vec<u8>, (u64, bam::Record)>,
+    /// Names in arrival order; entries of records paired since are skipped
+    order: VecDeque<(u64, Vec<u8>)>,
+    serial: u64,
+    waiting_bytes: usize,
+    /// Hashes of the names sent to a partition
+    spilled_names: HashSet<u64>,
+    hasher: RandomState,
+    partitions: Vec<Option<bam::Writer>>,
+    /// Records to hand out, mates next to each other
+    ready: VecDeque<bam::Record>,
+    stage: Stage,
+    pub counts: PairingCounts,
+}
+
+impl MatePairer {
+    /// Pair within `memory_limit` bytes, spilling to `spill_dir` as BAMs
+    /// with `header`
+    pub fn new(header: &bam::Header, spill_dir: &Path, memory_limit: usize) -> Self {
+        MatePairer {
+            header: header.clone(),
+            spill_dir: spill_dir.to_path_buf(),
+            memory_limit,
+            waiting: HashMap::new(),
+            order: VecDeque::new(),
+            serial: 0,
+            waiting_bytes: 0,
+            spilled_names: HashSet::new(),
+            hasher: RandomState::new(),
+            partitions: (0..SPILL_PARTITIONS).map(|_| None).collect(),
+            ready: VecDeque::new(),
+            stage: Stage::Reading,
+            counts: PairingCounts::default(),
+        }
+    }
+
+    /// Records waiting for their mate in memory
+    pub fn waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Spill the longest-waiting records and keep half the budget from now
+    /// on (`--max-rss`)
+    pub fn relieve(&mut self) -> Result<()> {
+        self.memory_limit = memory::halved(self.memory_limit);
+        self.spill_oldest()?;
+        self.waiting.shrink_to_fit();
+        Ok(())
+    }
+
+    /// The next record with its mate next to it, from records `read` with
+    /// the signature of [`bam::Read::read`]
+    ///
+    /// Read errors are passed on; reading resumes on the next call.
+    pub fn read_from(
+        &mut self,
+        mut read: impl FnMut(&mut bam::Record) -> Option<Result<(), HtsError>>,
+        record: &mut bam::Record,
+    ) -> Option<Result<()>> {
+        loop {
+            if let Some(next) = self.ready.pop_front() {
+                *record = next;
+                return Some(Ok(()));
+            }
+            let result = match self.stage {
+                Stage::Reading => {
+                    let mut incoming = bam::Record::new();
+                    match read(&mut incoming) {
+                        Some(Ok(())) => self.take(incoming),
+                        Some(Err(e)) => return Some(Err(e.into())),
+                        None => self.finish_reading(),
+                    }
+                }
+                Stage::Partition(index) => self.pair_partition(index),
+                Stage::Done => return None,
+            };
+            if let Err(e) = result {
+                return Some(Err(e));
+            }
+        }
+    }
+
+    fn name_hash(&self, name: &[u8]) -> u64 {
+        self.hasher.hash_one(name)
+    }
+
+    fn take(&mut self, record: bam::Record) -> Result<()> {
+        if record.is_secondary() || record.is_supplementary() {
+            self.counts.extras_skipped += 1;
+            return Ok(());
+        }
+        if let Some((_, mate)) = self.waiting.get(record.qname()) {
+            if mate.is_first_in_template() != record.is_first_in_template() {
+                let (_, mate) = self.waiting.remove(record.qname()).unwrap();
+                self.waiting_bytes -= footprint(&mate);
+                self.ready.push_back(mate);
+                self.ready.push_back(record);
+                return Ok(());
+            }
+        }
+        // The mate went to disk before this record came
+        let hash = self.name_hash(record.qname());
+        if !self.spilled_names.is_empty() && self.spilled_names.contains(&hash) {
+            return self.spill(record, hash);
+        }
+
+        self.serial += 1;
+        self.waiting_bytes += footprint(&record);
+        self.order.push_back((self.serial, record.qname().to_vec()));
+        if let Some((_, replaced)) = self
+            .waiting
+            .insert(record.qname().to_vec(), (self.serial, record))
+        {
+            // Same name and segment twice: the first is left without a mate
+            self.waiting_bytes -= footprint(&replaced);
+            self.counts.unmatched += 1;
+        }
+        if self.waiting_bytes >= self.memory_limit {
+            self.spill_oldest()?;
+        }
+        // Drop the entries of records paired since
+        if self.order.len() > 2 * self.waiting.len() + 1024 {
+            let waiting = &self.waiting;
+            self.order.retain(|(serial, name)| {
+                matches!(waiting.get(name), Some((waiting, _)) if waiting == serial)
+            });
+        }
+        Ok(())
+    }
+
+    /// Send the longest-waiting records to disk until half the budget is free
+    fn spill_oldest(&mut self) -> Result<()> {
+        while self.waiting_bytes > self.memory_limit / 2 {
+            let Some((serial, name)) = self.order.pop_front() else {
+                break;
+            };
+            if !matches!(self.waiting.get(&name), Some((waiting, _)) if *waiting == serial) {
+                continue;
+            }
+            let (_, record) = self.waiting.remove(&name).unwrap();
+            self.waiting_bytes -= footprint(&record);
+            let hash = self.name_hash(&name);
+            self.spilled_names.insert(hash);
+            self.spill(record, hash)?;
+        }
+        Ok(())
+    }
+
+    fn partition_path(&self, index: usize) -> PathBuf {
+        self.spill_dir.join(format!("mates.{}.bam", index))
+    }
+
+    fn spill(&mut self, record: bam::Record, hash: u64) -> Result<()> {
+        let index = (hash % SPILL_PARTITIONS as u64) as usize;
+        if self.partitions[index].is_none() {
+            let path = self.partition_path(index);
+            let mut writer = bam::Writer::from_path(&path, &self.header, bam::Format::Bam)
+                .with_context(|| format!("Cannot create {}", path.display()))?;
+            // Partitions are read back once; speed matters more than size
+            writer.set_compression_level(bam::CompressionLevel::Fastest)?;
+            self.partitions[index] = Some(writer);
+        }
+        self.partitions[index].as_mut().unwrap().write(&record)?;
+        self.counts.spilled += 1;
+        Ok(())
+    }
+
+    /// At the end of the input: spill what still waits, or give up on it
+    /// when nothing went to disk
+    fn finish_reading(&mut self) -> Result<()> {
+        if self.spilled_names.is_empty() {
+            self.counts.unmatched += self.waiting.len() as u64;
+            self.waiting.clear();
+            self.order.clear();
+            self.stage = Stage::Done;
+            return Ok(());
+        }
+        self.order.clear();
+        for (name, (_, record)) in std::mem::take(&mut self.waiting) {
+            let hash = self.name_hash(&name);
+            self.spill(record, hash)?;
+        }
+        self.waiting_bytes = 0;
+        // Closing flushes the partitions
+        for writer in &mut self.partitions {
+            writer.take();
+        }
+        self.stage = Stage::Partition(0);
+        Ok(())
+    }
+
+    /// Pair the records of one partition, then move on to the next
+    fn pair_partition(&mut self, index: usize) -> Result<()> {
+        self.stage = if index + 1 < SPILL_PARTITIONS {
+            Stage::Partition(index + 1)
+        } else {
+            Stage::Done
+        };
+        let path = self.partition_path(index);
+        if !path.exists() {
+            return Ok(());
+        }
+        let reader = bam::Reader::from_path(&path)
+            .with_context(|| format!("Cannot reopen {}", path.display()))?;
+        let mut waiting: HashMap<Vec<u8>, bam::Record> = HashMap::new();
+        loop {
+            // Like input records, these may go on to the reader thread of --prefetch-batches
+            let mut record = bam::Record::new();
+            match prefetch::read_headerless(&reader, &mut record) {
+                Some(result) => result?,
+                None => break,
+            }
+            match waiting.remove(record.qname()) {
+                Some(mate) if mate.is_first_in_template() != record.is_first_in_template() => {
+                    self.ready.push_back(mate);
+                    self.ready.push_back(record);
+                }
+                Some(mate) => {
+                    self.counts.unmatched += 1;
+                    waiting.insert(record.qname().to_vec(), record);
+                    drop(mate);
+                }
+                None => {
+                    waiting.insert(record.qname().to_vec(), record);
+                }
+            }
+        }
+        self.counts.unmatched += waiting.len() as u64;
+        drop(reader);
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+}
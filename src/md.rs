@@ -0,0 +1,189 @@
+//! Filling in missing `NM` and `MD` tags from the reference (`--regenerate-md`)
+//!
+//! Some aligners and conversion tools leave out `NM` and `MD`, which the
+//! identity, divergence and error-rate numbers depend on. With the reference
+//! FASTA given by `--reference`, each mapped record lacking either tag has
+//! the reference under its alignment fetched through the `.fai` index and
+//! the tags computed as `samtools calmd` does: a base matches only when both
+//! are the same non-`N` base, ignoring case. Tags already present are kept.
+
+use anyhow::{bail, Result};
+use rust_htslib::bam::record::{Aux, Cigar};
+use rust_htslib::{bam, htslib};
+use std::ffi::CString;
+
+/// `NM` and `MD` of an alignment, given the reference under it
+///
+/// `reference` starts at the record's position and covers its aligned span.
+pub fn compute_md_nm(record: &bam::Record, reference: &[u8]) -> (String, u32) {
+    let seq = record.seq();
+    let (mut ref_at, mut query_at) = (0usize, 0usize);
+    let (mut md, mut matched, mut nm) = (String::new(), 0u32, 0u32);
+    for op in record.cigar().iter() {
+        match *op {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                for _ in 0..len {
+                    let ref_base = reference[ref_at].to_ascii_uppercase();
+                    let read_base = seq[query_at].to_ascii_uppercase();
+                    if ref_base == read_base && ref_base != b'N' {
+                        matched += 1;
+                    } else {
+                        md.push_str(&matched.to_string());
+                        md.push(ref_base as char);
+                        matched = 0;
+                        nm += 1;
+                    }
+                    ref_at += 1;
+                    query_at += 1;
+                }
+            }
+            Cigar::Ins(len) => {
+                query_at += len as usize;
+                nm += len;
+            }
+            Cigar::Del(len) => {
+                md.push_str(&matched.to_string());
+                md.push('^');
+                for base in &reference[ref_at..ref_at + len as usize] {
+                    md.push(base.to_ascii_uppercase() as char);
+                }
+                matched = 0;
+                ref_at += len as usize;
+                nm += len;
+            }
+            Cigar::SoftClip(len) => query_at += len as usize,
+            Cigar::RefSkip(len) => ref_at += len as usize,
+            Cigar::HardClip(_) | Cigar::Pad(_) => {}
+        }
+    }
+    md.push_str(&matched.to_string());
+    (md, nm)
+}
+
+/// Bases of the query the CIGAR consumes
+fn query_len(record: &bam::Record) -> usize {
+    record
+        .cigar()
+        .iter()
+        .map(|op| match *op {
+            Cigar::Match(len)
+            | Cigar::Equal(len)
+            | Cigar::Diff(len)
+            | Cigar::Ins(len)
+            | Cigar::SoftClip(len) => len as usize,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// An indexed FASTA, read through htslib's `faidx`
+struct Fasta {
+    inner: *mut htslib::faidx_t,
+}
+
+impl Fasta {
+    fn open(path: &str) -> Result<Self> {
+        let c_path = CString::new(path)?;
+        // SAFETY: fai_load takes a NUL-terminated path and returns NULL on failure
+        let inner = unsafe { htslib::fai_load(c_path.as_ptr()) };
+        if inner.is_null() {
+            bail!("Cannot open {} with its .fai index", path);
+        }
+        Ok(Fasta { inner })
+    }
+
+    /// Bases `begin..end` (0-based, end exclusive) of `contig`
+    fn fetch(&self, contig: &str, begin: i64, end: i64) -> Result<Vec<u8>> {
+        let c_contig = CString::new(contig)?;
+        let mut len: htslib::hts_pos_t = 0;
+        // SAFETY: the index stays loaded while self lives; htslib returns a
+        // malloc'd buffer of len bases, or NULL, and the caller frees it
+        unsafe {
+            let bases =
+                htslib::faidx_fetch_seq64(self.inner, c_contig.as_ptr(), begin, end - 1, &mut len);
+            if bases.is_null() {
+                bail!("The reference has no sequence {}", contig);
+            }
+            let fetched =
+                std::slice::from_raw_parts(bases as *const u8, len.max(0) as usize).to_vec();
+            libc::free(bases.cast());
+            if fetched.len() as i64 != end - begin {
+                bail!(
+                    "{}:{}-{} runs past the end of the reference sequence; is --reference the one the reads were aligned to?",
+                    contig,
+                    begin + 1,
+                    end
+                );
+            }
+            Ok(fetched)
+        }
+    }
+}
+
+impl Drop for Fasta {
+    fn drop(&mut self) {
+        // SAFETY: inner came from fai_load and is destroyed once
+        unsafe { htslib::fai_destroy(self.inner) }
+    }
+}
+
+/// Adds `NM` and `MD` to the records that lack them
+pub struct MdRegenerator {
+    fasta: Fasta,
+    /// Reference names by target id, from the input header
+    contigs: Vec<String>,
+    regenerated: u64,
+}
+
+impl MdRegenerator {
+    pub fn new(reference: &str, header: &bam::HeaderView) -> Result<Self> {
+        let contigs = header
+            .target_names()
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        Ok(MdRegenerator {
+            fasta: Fasta::open(reference)?,
+            contigs,
+            regenerated: 0,
+        })
+    }
+
+    /// Records given a tag so far
+    pub fn regenerated(&self) -> u64 {
+        self.regenerated
+    }
+
+    /// Add whichever of `NM` and `MD` the record lacks, if it is mapped and has
+    /// a sequence its CIGAR covers
+    pub fn fill(&mut self, record: &mut bam::Record) -> Result<()> {
+        let has_nm = record.aux(b"NM").is_ok();
+        let has_md = record.aux(b"MD").is_ok();
+        if (has_nm && has_md) || record.is_unmapped() || record.tid() < 0 {
+            return Ok(());
+        }
+        let (begin, end) = (record.pos(), record.cigar().end_pos());
+        // SEQ `*`, or a CIGAR that doesn't cover the sequence, has nothing to
+        // compare with the reference
+        if end <= begin || record.seq_len() == 0 || query_len(record) != record.seq_len() {
+            return Ok(());
+        }
+        let Some(contig) = self.contigs.get(record.tid() as usize) else {
+            bail!(
+                "Record {} is on target {}, which the header does not list",
+                String::from_utf8_lossy(record.qname()),
+                record.tid()
+            );
+        };
+        let reference = self.fasta.fetch(contig, begin, end)?;
+        let (md, nm) = compute_md_nm(record, &reference);
+        if !has_nm {
+            record.push_aux(b"NM", Aux::I32(nm as i32))?;
+        }
+        if !has_md {
+            record.push_aux(b"MD", Aux::String(&md))?;
+        }
+        self.regenerated += 1;
+        Ok(())
+    }
+}
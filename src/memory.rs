@@ -0,0 +1,135 @@
+//! Keeping the run under a resident memory limit (`--max-rss`)
+//!
+//! A scheduler that kills a job over its memory request leaves truncated
+//! outputs behind. With `--max-rss MIB` the filter loop measures the
+//! resident set size of the process every [`CHECK_INTERVAL`] pairs. Past
+//! [`SOFT_FRACTION`] of the limit, the buffers that can give memory back are
+//! asked to ([`Pressure::Relieve`]): the mates waiting under
+//! `--coordinate-sorted` and the sort buffer of `--sort-output` go to disk,
+//! and each keeps half its budget from then on, down to [`MIN_BUDGET`]. At
+//! the limit itself the run stops between pairs as on an interruption, so
+//! every output is finished and holds the pairs before the stop, and then
+//! fails with what held the memory.
+//!
+//! The resident size comes from `/proc/self/statm` on Linux; elsewhere the
+//! peak of `getrusage` stands in, which never goes down after a relief.
+
+/// Pairs between two measurements
+pub const CHECK_INTERVAL: u64 = 4096;
+
+/// Share of the limit at which buffers are relieved
+pub const SOFT_FRACTION: f64 = 0.85;
+
+/// Smallest budget a relieved buffer is cut to, in bytes
+pub const MIN_BUDGET: usize = 16 << 20;
+
+/// Resident memory of the process now, in bytes
+pub fn resident_bytes() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        // SAFETY: sysconf only reads a configuration value
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        Some(pages * u64::try_from(page_size).ok()?)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        peak_resident_bytes()
+    }
+}
+
+/// Most resident memory the process has had, in bytes
+pub fn peak_resident_bytes() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        // SAFETY: getrusage only fills the struct
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+            return None;
+        }
+        let peak = u64::try_from(usage.ru_maxrss).ok()?;
+        // KiB on Linux, bytes on macOS
+        Some(if cfg!(target_os = "macos") {
+            peak
+        } else {
+            peak << 10
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+/// Hand memory freed by the allocator back to the system, so that a relief
+/// shows in the resident size
+pub fn release_free_memory() {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    // SAFETY: malloc_trim only returns free heap pages to the system
+    unsafe {
+        libc::malloc_trim(0);
+    }
+}
+
+/// Half of `budget`, but not below [`MIN_BUDGET`]
+pub fn halved(budget: usize) -> usize {
+    (budget / 2).max(MIN_BUDGET).min(budget)
+}
+
+/// What a measurement calls for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pressure {
+    Fine,
+    /// Past the soft limit: buffers should give memory back
+    Relieve(u64),
+    /// At the limit: the run should stop
+    Exceeded(u64),
+}
+
+/// Measures the resident size every [`CHECK_INTERVAL`] pairs against a limit
+#[derive(Debug)]
+pub struct RssGuard {
+    limit: u64,
+    next_check: u64,
+    /// Times buffers were relieved
+    pub reliefs: u32,
+}
+
+impl RssGuard {
+    pub fn new(limit_mib: u64) -> Self {
+        RssGuard {
+            limit: limit_mib << 20,
+            next_check: 0,
+            reliefs: 0,
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Measure if `pairs` have gone by since the last measurement
+    pub fn check(&mut self, pairs: u64) -> Pressure {
+        if pairs < self.next_check {
+            return Pressure::Fine;
+        }
+        self.next_check = pairs + CHECK_INTERVAL;
+        match resident_bytes() {
+            Some(resident) => self.judge(resident),
+            None => Pressure::Fine,
+        }
+    }
+
+    /// What `resident` bytes call for
+    pub fn judge(&mut self, resident: u64) -> Pressure {
+        if resident >= self.limit {
+            Pressure::Exceeded(resident)
+        } else if resident as f64 >= self.limit as f64 * SOFT_FRACTION {
+            self.reliefs += 1;
+            Pressure::Relieve(resident)
+        } else {
+            Pressure::Fine
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Per-pair metric sidecar for two-pass threshold tuning
+//!
+//! `cache-metrics` reads the input once and stores the exact complexity and
+//! longest mapped stretch of both mates of every pair. `tune` then reports
+//! pass rates for any grid of `-c`/`-m` thresholds from the sidecar alone,
+//! and a filtering run given `--metric-cache` takes the metrics from it
+//! instead of recomputing them. The layout, in a BGZF stream:
+//!
+//! ```text
+//! file:  b"FBPM" version:u8 options:u8 kmer_size:u8 entry*
+//! entry: name_hash:u64 complexity:[f64; 2] longest_mapped:[u32; 2]
+//! ```
+//!
+//! Numbers are little-endian. Option bit 0 is `--bisulfite`, bit 1
+//! `--bisulfite-strand-aware`, bit 2 `--splice-aware` and bit 3
+//! `--canonical`, which change the metric values, as does the kmer size.
+//! Version 1 caches have no kmer size byte and were written with the default
+//! of 21. The name hash ([`name_hash`] of the read name) keeps the sidecar
+//! and the input in step.
+
+use crate::fastq::create_bgzf;
+use crate::filter::{FilterConfig, PairMetrics, KMER_SIZE};
+use crate::sample::name_hash;
+use anyhow::{bail, Context, Result};
+use rust_htslib::{bam, bgzf};
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 4] = b"FBPM";
+const VERSION: u8 = 2;
+
+const OPTION_BISULFITE: u8 = 0x1;
+const OPTION_STRAND_AWARE: u8 = 0x2;
+const OPTION_SPLICE_AWARE: u8 = 0x4;
+const OPTION_CANONICAL: u8 = 0x8;
+
+/// Bytes per pair after the header
+const ENTRY_LEN: usize = 8 + 16 + 8;
+
+/// The metric options of a configuration, as stored in the header
+fn option_bits(config: &FilterConfig) -> u8 {
+    let mut bits = 0;
+    if config.bisulfite {
+        bits |= OPTION_BISULFITE;
+    }
+    if config.bisulfite && config.bisulfite_strand_aware {
+        bits |= OPTION_STRAND_AWARE;
+    }
+    if config.splice_aware {
+        bits |= OPTION_SPLICE_AWARE;
+    }
+    if config.canonical {
+        bits |= OPTION_CANONICAL;
+    }
+    bits
+}
+
+fn describe_options(bits: u8) -> String {
+    let names: Vec<&str> = [
+        (OPTION_BISULFITE, "--bisulfite"),
+        (OPTION_STRAND_AWARE, "--bisulfite-strand-aware"),
+        (OPTION_SPLICE_AWARE, "--splice-aware"),
+        (OPTION_CANONICAL, "--canonical"),
+    ]
+    .iter()
+    .filter(|(bit, _)| bits & bit != 0)
+    .map(|(_, name)| *name)
+    .collect();
+    if names.is_empty() {
+        "no metric options".to_string()
+    } else {
+        names.join(" ")
+    }
+}
+
+/// Writes one sidecar entry per pair
+pub struct MetricCacheWriter {
+    out: bgzf::Writer,
+    entry: Vec<u8>,
+}
+
+impl MetricCacheWriter {
+    pub fn create(path: &str, config: &FilterConfig) -> Result<Self> {
+        let kmer_size = u8::try_from(config.kmer_size)
+            .ok()
+            .context("Metric caches store kmer sizes up to 255")?;
+        let mut out = create_bgzf(path)?;
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION, option_bits(config), kmer_size])?;
+        Ok(MetricCacheWriter {
+            out,
+            entry: Vec::with_capacity(ENTRY_LEN),
+        })
+    }
+
+    pub fn write_pair(&mut self, record1: &bam::Record, metrics: &PairMetrics) -> Result<()> {
+        self.entry.clear();
+        self.entry
+            .extend_from_slice(&name_hash(record1.qname()).to_le_bytes());
+        for complexity in metrics.complexity {
+            self.entry.extend_from_slice(&complexity.to_le_bytes());
+        }
+        for mapped in metrics.longest_mapped {
+            self.entry.extend_from_slice(&mapped.to_le_bytes());
+        }
+        self.out.write_all(&self.entry)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Fill `buf`, or report a clean end of input before its first byte
+fn read_full<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..])? {
+            0 if filled == 0 => return Ok(false),
+            0 => bail!("Metric cache is truncated"),
+            n => filled += n,
+        }
+    }
+    Ok(true)
+}
+
+/// Reads sidecar entries in input order
+pub struct MetricCacheReader {
+    input: bgzf::Reader,
+    path: String,
+    options: u8,
+    kmer_size: usize,
+    pairs: u64,
+}
+
+impl MetricCacheReader {
+    pub fn open(path: &str) -> Result<Self> {
+        let mut input =
+            bgzf::Reader::from_path(path).with_context(|| format!("Cannot open {}", path))?;
+        let mut header = [0u8; 6];
+        if !read_full(&mut input, &mut header)? || &header[..4] != MAGIC {
+            bail!("{} is not a filter_bam_pairs metric cache", path);
+        }
+        let kmer_size = match header[4] {
+            1 => KMER_SIZE,
+            VERSION => {
+                let mut kmer_size = [0u8];
+                if !read_full(&mut input, &mut kmer_size)? {
+                    bail!("Metric cache is truncated");
+                }
+                kmer_size[0] as usize
+            }
+            version => bail!("{}: unsupported metric cache version {}", path, version),
+        };
+        Ok(MetricCacheReader {
+            input,
+            path: path.to_string(),
+            options: header[5],
+            kmer_size,
+            pairs: 0,
+        })
+    }
+
+    /// Fail unless the cache was written with the metric options of `config`
+    pub fn check_options(&self, config: &FilterConfig) -> Result<()> {
+        let wanted = option_bits(config);
+        if self.options != wanted {
+            bail!(
+                "{} was written with {}, but this run uses {}; rerun cache-metrics with the same options",
+                self.path,
+                describe_options(self.options),
+                describe_options(wanted)
+            );
+        }
+        if self.kmer_size != config.kmer_size {
+            bail!(
+                "{} was written with --kmer-size {}, but this run uses {}",
+                self.path,
+                self.kmer_size,
+                config.kmer_size
+            );
+        }
+        Ok(())
+    }
+
+    /// The next entry with its name hash, or `None` at the end
+    fn next_entry(&mut self) -> Result<Option<(u64, PairMetrics)>> {
+        let mut entry = [0u8; ENTRY_LEN];
+        if !read_full(&mut self.input, &mut entry).with_context(|| self.path.clone())? {
+            return Ok(None);
+        }
+        let bytes = |start: usize| -> [u8; 8] { entry[start..start + 8].try_into().unwrap() };
+        let word = |start: usize| -> [u8; 4] { entry[start..start + 4].try_into().unwrap() };
+        self.pairs += 1;
+        Ok(Some((
+            u64::from_le_bytes(bytes(0)),
+            PairMetrics {
+                complexity: [f64::from_le_bytes(bytes(8)), f64::from_le_bytes(bytes(16))],
+                longest_mapped: [u32::from_le_bytes(word(24)), u32::from_le_bytes(word(28))],
+            },
+        )))
+    }
+
+    /// Metrics of the pair whose first mate is `record1`
+    pub fn next_pair(&mut self, record1: &bam::Record) -> Result<PairMetrics> {
+        match self.next_entry()? {
+            Some((hash, metrics)) if hash == name_hash(record1.qname()) => Ok(metrics),
+            Some(_) => bail!(
+                "{} is out of step with the input at pair {} ({}); was it made from another file?",
+                self.path,
+                self.pairs,
+                String::from_utf8_lossy(record1.qname())
+            ),
+            None => bail!(
+                "{} ends after {} pairs, before the input does",
+                self.path,
+                self.pairs
+            ),
+        }
+    }
+
+    /// Fail if entries are left once the whole input has been read
+    pub fn finish(mut self) -> Result<()> {
+        let read = self.pairs;
+        if self.next_entry()?.is_some() {
+            bail!(
+                "{} has more entries than the input's {} pairs; was it made from another file?",
+                self.path,
+                read
+            );
+        }
+        Ok(())
+    }
+
+    /// Each remaining entry's metrics, in input order
+    pub fn for_each(mut self, mut visit: impl FnMut(&PairMetrics)) -> Result<u64> {
+        while let Some((_, metrics)) = self.next_entry()? {
+            visit(&metrics);
+        }
+        Ok(self.pairs)
+    }
+}
+
+/// Kept pairs per (complexity cutoff, min mapped) combination
+pub struct TuningGrid {
+    pub complexity: Vec<f64>,
+    pub min_mapped: Vec<u32>,
+    /// Row-major: one row per complexity cutoff
+    pub kept: Vec<u64>,
+    pub pairs: u64,
+}
+
+/// Count the pairs each threshold combination keeps, reading only the cache
+pub fn tune(path: &str, complexity: &[f64], min_mapped: &[u32]) -> Result<TuningGrid> {
+    let cache = MetricCacheReader::open(path)?;
+    let mut kept = vec![0u64; complexity.len() * min_mapped.len()];
+    let pairs = cache.for_each(|metrics| {
+        let lowest_complexity = metrics.complexity[0].min(metrics.complexity[1]);
+        let shortest_mapped = metrics.longest_mapped[0].min(metrics.longest_mapped[1]);
+        for (row, &cutoff) in complexity.iter().enumerate() {
+            if lowest_complexity < cutoff {
+                continue;
+            }
+            for (column, &min) in min_mapped.iter().enumerate() {
+                kept[row * min_mapped.len() + column] += (shortest_mapped >= min) as u64;
+            }
+        }
+    })?;
+    Ok(TuningGrid {
+        complexity: complexity.to_vec(),
+        min_mapped: min_mapped.to_vec(),
+        kept,
+        pairs,
+    })
+}
@@ -0,0 +1,243 @@
+//! Length-normalized alignment metrics, and identity from `NM` or `=`/`X` CIGARs
+
+use crate::stats::aux_integer;
+use clap::ValueEnum;
+use rust_htslib::{bam, bam::record::Aux, bam::record::Cigar};
+
+/// Which read length normalized metrics divide by
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthBasis {
+    /// Length of the stored sequence (SEQ)
+    Query,
+    /// Original read length: an `ln:i` tag if present, else SEQ plus hard clips
+    Original,
+}
+
+/// Bases removed by hard clipping (H operations)
+fn hard_clipped_bases(record: &bam::Record) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::HardClip(len) => *len,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Read length under the chosen basis
+pub fn read_length(record: &bam::Record, basis: LengthBasis) -> u32 {
+    let query = record.seq_len() as u32;
+    match basis {
+        LengthBasis::Query => query,
+        LengthBasis::Original => match aux_integer(record, b"ln") {
+            Some(len) if len > 0 => len as u32,
+            _ => query + hard_clipped_bases(record),
+        },
+    }
+}
+
+/// Clipped bases counted against the read: soft clips, plus hard clips when
+/// measuring against the original length
+pub fn clipped_bases(record: &bam::Record, basis: LengthBasis) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .map(|op| match (op, basis) {
+            (Cigar::SoftClip(len), _) => *len,
+            (Cigar::HardClip(len), LengthBasis::Original) => *len,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Aligned-base events of a CIGAR that spells out matches (`=`) and
+/// mismatches (`X`), which need no `MD` tag or reference to count
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BaseEvents {
+    pub matches: u32,
+    pub mismatches: u32,
+    pub inserted: u32,
+    pub deleted: u32,
+    /// Insertions and deletions, each counted once
+    pub gaps: u32,
+}
+
+impl BaseEvents {
+    /// `NM` as an aligner computes it: mismatched, inserted and deleted bases
+    pub fn edit_distance(&self) -> u32 {
+        self.mismatches + self.inserted + self.deleted
+    }
+
+    /// Matches over all alignment columns, as BLAST reports identity
+    pub fn identity(&self) -> f64 {
+        fraction(self.matches, self.matches + self.edit_distance())
+    }
+}
+
+/// Read-level events from an `=`/`X` CIGAR; `None` for unmapped reads and
+/// for CIGARs with `M`, which leaves matches and mismatches apart to `MD`
+pub fn base_events(record: &bam::Record) -> Option<BaseEvents> {
+    if record.is_unmapped() {
+        return None;
+    }
+    let mut events = BaseEvents::default();
+    let mut explicit = false;
+    for op in record.cigar().iter() {
+        match op {
+            Cigar::Match(_) => return None,
+            Cigar::Equal(len) => {
+                events.matches += len;
+                explicit = true;
+            }
+            Cigar::Diff(len) => {
+                events.mismatches += len;
+                explicit = true;
+            }
+            Cigar::Ins(len) => {
+                events.inserted += len;
+                events.gaps += 1;
+            }
+            Cigar::Del(len) => {
+                events.deleted += len;
+                events.gaps += 1;
+            }
+            _ => {}
+        }
+    }
+    explicit.then_some(events)
+}
+
+/// Positions of the mismatched bases of an `=`/`X` CIGAR, as 0-based
+/// `(reference position, query offset)` pairs in alignment order; `None`
+/// when [`base_events`] is
+pub fn mismatch_positions(record: &bam::Record) -> Option<Vec<(i64, u32)>> {
+    base_events(record)?;
+    let (mut reference, mut query) = (record.pos(), 0u32);
+    let mut positions = Vec::new();
+    for op in record.cigar().iter() {
+        match op {
+            Cigar::Diff(len) => {
+                positions.extend((0..*len).map(|i| (reference + i as i64, query + i)));
+                reference += *len as i64;
+                query += len;
+            }
+            Cigar::Equal(len) => {
+                reference += *len as i64;
+                query += len;
+            }
+            Cigar::Ins(len) | Cigar::SoftClip(len) => query += len,
+            Cigar::Del(len) | Cigar::RefSkip(len) => reference += *len as i64,
+            _ => {}
+        }
+    }
+    Some(positions)
+}
+
+/// Edit distance from the `NM` tag, or counted from an `=`/`X` CIGAR when
+/// the tag is missing; `None` for unmapped reads and when neither is there
+pub fn edit_distance(record: &bam::Record) -> Option<i64> {
+    if record.is_unmapped() {
+        return None;
+    }
+    aux_integer(record, b"NM")
+        .or_else(|| base_events(record).map(|events| events.edit_distance() as i64))
+}
+
+/// Read bases placed on the reference: `M`, `=`, `X` and `I` operations, as
+/// `samtools stats` counts bases mapped (cigar)
+pub fn aligned_query_bases(record: &bam::Record) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) | Cigar::Ins(len) => *len,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Edit distance per aligned read base, [`edit_distance`] over
+/// [`aligned_query_bases`]; `None` when either is missing or zero bases align
+pub fn error_rate(record: &bam::Record) -> Option<f64> {
+    let nm = edit_distance(record)?;
+    let aligned = aligned_query_bases(record);
+    (aligned > 0).then(|| nm.max(0) as f64 / aligned as f64)
+}
+
+/// Mismatched bases: the `X` of an `=`/`X` CIGAR, else `NM` minus the
+/// inserted and deleted bases
+pub fn mismatches(record: &bam::Record) -> Option<u32> {
+    if let Some(events) = base_events(record) {
+        return Some(events.mismatches);
+    }
+    let nm = edit_distance(record)?;
+    let gap_bases: i64 = record
+        .cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::Ins(len) | Cigar::Del(len) => *len as i64,
+            _ => 0,
+        })
+        .sum();
+    Some((nm - gap_bases).max(0) as u32)
+}
+
+/// Exact identity of an `=`/`X` CIGAR, else `1 - NM / alignment columns`;
+/// `None` when neither is available
+pub fn identity(record: &bam::Record) -> Option<f64> {
+    if let Some(events) = base_events(record) {
+        return Some(events.identity());
+    }
+    let nm = edit_distance(record)?;
+    let columns: i64 = record
+        .cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::Match(len) | Cigar::Ins(len) | Cigar::Del(len) => *len as i64,
+            _ => 0,
+        })
+        .sum();
+    (columns > 0).then(|| 1.0 - (nm.clamp(0, columns) as f64 / columns as f64))
+}
+
+/// Gap-compressed per-base divergence, as minimap2 reports in `de:f`
+///
+/// Each indel counts once regardless of its length:
+/// `(mismatches + gap opens) / (aligned bases + gap opens)`, with the
+/// mismatches of [`mismatches`]. `None` for unmapped reads and reads with
+/// neither an `NM` tag nor an `=`/`X` CIGAR.
+pub fn gap_compressed_divergence(record: &bam::Record) -> Option<f64> {
+    let mismatches = mismatches(record)? as i64;
+    let (mut aligned, mut gaps) = (0i64, 0i64);
+    for op in record.cigar().iter() {
+        match op {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => aligned += *len as i64,
+            Cigar::Ins(_) | Cigar::Del(_) => gaps += 1,
+            _ => {}
+        }
+    }
+    if aligned + gaps == 0 {
+        return None;
+    }
+    Some((mismatches + gaps) as f64 / (aligned + gaps) as f64)
+}
+
+/// Per-base divergence from minimap2's `de:f` tag, or computed from `NM` and
+/// the CIGAR the same way when the tag is missing
+pub fn divergence(record: &bam::Record) -> Option<f64> {
+    match record.aux(b"de") {
+        Ok(Aux::Float(de)) => Some(de as f64),
+        Ok(Aux::Double(de)) => Some(de),
+        _ => gap_compressed_divergence(record),
+    }
+}
+
+/// `value / length`, with zero-length reads scoring 0
+pub fn fraction(value: u32, length: u32) -> f64 {
+    if length == 0 {
+        0.0
+    } else {
+        value as f64 / length as f64
+    }
+}
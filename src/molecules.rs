@@ -0,0 +1,186 @@
+//! Molecular families by fgbio's `MI` tag
+//!
+//! `fgbio GroupReadsByUmi` gives every read of a source molecule the same
+//! `MI:Z` identifier; duplex grouping appends `/A` or `/B` for the strand.
+//! The pairs of one identifier, both strands together, form a family, and the
+//! family size (its number of pairs) is what consensus calling needs: a
+//! singleton cannot be error-corrected. The report gives the distribution of
+//! family sizes before and after filtering, and `--min-family-size` drops the
+//! pairs of small families, counted in a separate pass over the input. Pairs
+//! without an `MI` tag are left to the other filters. Pairs are attributed to
+//! their first mate.
+
+use crate::input;
+use crate::units::NumberFormat;
+use anyhow::{Context, Result};
+use rust_htslib::{bam, bam::record::Aux, bam::Read};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+/// Aux tag carrying the molecular identifier
+pub const TAG_MOLECULE: &[u8] = b"MI";
+
+/// Family sizes listed one by one in the report; larger ones share a row
+const REPORTED_SIZES: u64 = 10;
+
+/// The record's molecule: its `MI` identifier without a duplex strand suffix
+pub fn molecule(record: &bam::Record) -> Option<&[u8]> {
+    match record.aux(TAG_MOLECULE) {
+        Ok(Aux::String(mi)) => {
+            let mi = mi.as_bytes();
+            Some(match mi {
+                [id @ .., b'/', b'A' | b'B'] => id,
+                _ => mi,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Count pairs per molecule in a separate pass over the input
+///
+/// A pair is counted on its first mate's primary record.
+pub fn count_family_pairs(path: &str, reference: Option<&str>) -> Result<HashMap<Vec<u8>, u64>> {
+    let mut reader = input::reopen(path, reference)?;
+    let mut counts: HashMap<Vec<u8>, u64> = HashMap::new();
+    let mut record = bam::Record::new();
+
+    while let Some(result) = reader.read(&mut record) {
+        result?;
+        if record.is_secondary() || record.is_supplementary() || !record.is_first_in_template() {
+            continue;
+        }
+        if let Some(mi) = molecule(&record) {
+            *counts.entry(mi.to_vec()).or_insert(0) += 1;
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Kept/total pair counts per molecule
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FamilyStats {
+    families: HashMap<String, (u64, u64)>,
+    untagged_pairs: u64,
+    /// Pairs removed by `--min-family-size`
+    small_family_pairs: u64,
+}
+
+impl FamilyStats {
+    pub fn record(&mut self, mi: Option<&[u8]>, small_family: bool, kept: bool) {
+        let Some(mi) = mi else {
+            self.untagged_pairs += 1;
+            return;
+        };
+        let entry = self
+            .families
+            .entry(String::from_utf8_lossy(mi).into_owned())
+            .or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += kept as u64;
+        self.small_family_pairs += small_family as u64;
+    }
+
+    pub fn merge(&mut self, other: &FamilyStats) {
+        for (mi, &(total, kept)) in &other.families {
+            let entry = self.families.entry(mi.clone()).or_insert((0, 0));
+            entry.0 += total;
+            entry.1 += kept;
+        }
+        self.untagged_pairs += other.untagged_pairs;
+        self.small_family_pairs += other.small_family_pairs;
+    }
+
+    /// Families per size, in the input and among the kept pairs; families
+    /// with no kept pair are left out of the latter
+    pub fn size_distribution(&self) -> BTreeMap<u64, (u64, u64)> {
+        let mut sizes: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+        for &(total, kept) in self.families.values() {
+            sizes.entry(total).or_default().0 += 1;
+            if kept > 0 {
+                sizes.entry(kept).or_default().1 += 1;
+            }
+        }
+        sizes
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        let families = self.families.len() as u64;
+        let pairs: u64 = self.families.values().map(|&(total, _)| total).sum();
+        let kept_families = self.families.values().filter(|(_, kept)| *kept > 0).count() as u64;
+        let kept_pairs: u64 = self.families.values().map(|&(_, kept)| kept).sum();
+        let mean = |pairs: u64, families: u64| {
+            if families > 0 {
+                format!("{:.2}", pairs as f64 / families as f64)
+            } else {
+                "-".to_string()
+            }
+        };
+
+        println!("\n=== Molecular Families (MI) ===");
+        println!("Families seen: {}", format.count(families));
+        println!("Families with kept pairs: {}", format.count(kept_families));
+        println!(
+            "Mean family size: {} before filtering, {} after",
+            mean(pairs, families),
+            mean(kept_pairs, kept_families)
+        );
+        if self.small_family_pairs > 0 {
+            println!(
+                "Pairs removed by --min-family-size: {}",
+                format.count(self.small_family_pairs)
+            );
+        }
+        println!(
+            "Pairs without MI tag: {}",
+            format.count(self.untagged_pairs)
+        );
+        if families == 0 {
+            return;
+        }
+
+        let mut rows = [(0u64, 0u64); REPORTED_SIZES as usize + 1];
+        for (size, (before, after)) in self.size_distribution() {
+            let row = &mut rows[(size.min(REPORTED_SIZES + 1) - 1) as usize];
+            row.0 += before;
+            row.1 += after;
+        }
+        println!(
+            "{:<12} {:>14} {:>14}",
+            "Family size", "Families", "After filter"
+        );
+        for (index, (before, after)) in rows.into_iter().enumerate() {
+            if before + after == 0 {
+                continue;
+            }
+            let size = index as u64 + 1;
+            let label = if size > REPORTED_SIZES {
+                format!(">{}", REPORTED_SIZES)
+            } else {
+                size.to_string()
+            };
+            println!(
+                "{:<12} {:>14} {:>14}",
+                label,
+                format.count(before),
+                format.count(after)
+            );
+        }
+    }
+
+    /// Write one `family_size, families, kept_families` row per size
+    pub fn write_tsv(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+
+        writeln!(out, "family_size\tfamilies\tkept_families")?;
+        for (size, (before, after)) in self.size_distribution() {
+            writeln!(out, "{}\t{}\t{}", size, before, after)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
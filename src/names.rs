@@ -0,0 +1,138 @@
+//! Read-name lists for `--include-names` and `--exclude-names`
+//!
+//! Lists can hold hundreds of millions of names, far more than fit in a
+//! `HashSet` of strings. By default only a sorted array of 64-bit name hashes
+//! is kept (8 bytes per name); two names sharing a hash are the only way to
+//! misclassify a pair, with odds of about one in 2^64 / list size per pair.
+//! `--name-match bloom` goes further, to a Bloom filter of fixed size with a
+//! reported false-positive rate, and `--name-match exact` stores the names.
+
+use crate::collisions::Bloom;
+use crate::sample::name_hash;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use rust_htslib::bgzf;
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader};
+
+/// Bloom filter size when `--name-set-memory` is not given, in MiB
+pub const DEFAULT_BLOOM_MIB: usize = 1024;
+
+/// How names from a list are stored and matched
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum NameMatch {
+    /// The names themselves; exact, but needs memory for every name
+    Exact,
+    /// 64-bit hashes of the names; 8 bytes per name, collisions practically never occur
+    #[default]
+    Hashed,
+    /// A Bloom filter of --name-set-memory MiB; fixed memory, some false positives
+    Bloom,
+}
+
+enum Store {
+    Exact(HashSet<Box<[u8]>>),
+    Hashed(Vec<u64>),
+    Bloom(Bloom),
+}
+
+/// The read name of a list line: the first word, without a FASTQ `@` or a `/1`/`/2` suffix
+pub fn list_name(line: &[u8]) -> &[u8] {
+    let name = line
+        .split(|byte| byte.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default();
+    let name = name.strip_prefix(b"@").unwrap_or(name);
+    match name {
+        [stem @ .., b'/', b'1' | b'2'] => stem,
+        _ => name,
+    }
+}
+
+/// A set of read names read from a file
+pub struct NameSet {
+    store: Store,
+    names: u64,
+}
+
+impl NameSet {
+    /// Read one name per line from `path`, which may be gzip/BGZF-compressed
+    pub fn read(path: &str, matching: NameMatch, bloom_bytes: usize) -> Result<Self> {
+        let reader =
+            bgzf::Reader::from_path(path).with_context(|| format!("Cannot open {}", path))?;
+        let mut reader = BufReader::with_capacity(1 << 20, reader);
+        let mut store = match matching {
+            NameMatch::Exact => Store::Exact(HashSet::new()),
+            NameMatch::Hashed => Store::Hashed(Vec::new()),
+            NameMatch::Bloom => Store::Bloom(Bloom::new(bloom_bytes)),
+        };
+
+        let mut names = 0u64;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader
+                .read_until(b'\n', &mut line)
+                .with_context(|| format!("Cannot read {}", path))?
+                == 0
+            {
+                break;
+            }
+            let name = list_name(&line);
+            if name.is_empty() {
+                continue;
+            }
+            names += 1;
+            match &mut store {
+                Store::Exact(set) => {
+                    set.insert(name.into());
+                }
+                Store::Hashed(hashes) => hashes.push(name_hash(name)),
+                Store::Bloom(bloom) => {
+                    bloom.insert(name);
+                }
+            }
+        }
+
+        if let Store::Hashed(hashes) = &mut store {
+            hashes.sort_unstable();
+            hashes.dedup();
+            hashes.shrink_to_fit();
+        }
+        Ok(NameSet { store, names })
+    }
+
+    /// Names read from the list, including repeats
+    pub fn names(&self) -> u64 {
+        self.names
+    }
+
+    pub fn contains(&self, name: &[u8]) -> bool {
+        match &self.store {
+            Store::Exact(set) => set.contains(name),
+            Store::Hashed(hashes) => hashes.binary_search(&name_hash(name)).is_ok(),
+            Store::Bloom(bloom) => bloom.contains(name),
+        }
+    }
+
+    /// Chance that a name not in the list matches it
+    pub fn false_positive_rate(&self) -> f64 {
+        match &self.store {
+            Store::Exact(_) => 0.0,
+            Store::Hashed(hashes) => hashes.len() as f64 / 2f64.powi(64),
+            Store::Bloom(bloom) => bloom.false_positive_rate(self.names),
+        }
+    }
+
+    /// Approximate memory held, in bytes
+    pub fn memory(&self) -> usize {
+        match &self.store {
+            Store::Exact(set) => set
+                .iter()
+                .map(|name| name.len() + std::mem::size_of::<Box<[u8]>>() + 8)
+                .sum(),
+            Store::Hashed(hashes) => hashes.len() * 8,
+            Store::Bloom(bloom) => bloom.memory(),
+        }
+    }
+}
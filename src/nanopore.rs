@@ -0,0 +1,253 @@
+//! Pass rates by nanopore channel and run time, from dorado read tags
+//!
+//! dorado tags each read with its channel (`ch:i`) and start time (`st:Z`,
+//! ISO 8601), so no fast5/pod5 files are needed. Failing pores and late-run
+//! degradation show up as channels or time windows with low pass rates.
+//! Pairs are attributed to the tags of their first mate.
+
+use crate::stats::aux_integer;
+use crate::units::NumberFormat;
+use anyhow::{Context, Result};
+use rust_htslib::{bam, bam::record::Aux};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Width of run-time buckets when `--ont-time-bucket` is not given, in minutes
+pub const DEFAULT_TIME_BUCKET_MINUTES: u32 = 60;
+
+/// Channels listed in the report, lowest pass rate first
+const WORST_CHANNELS: usize = 5;
+
+/// Channels with fewer pairs are too noisy to rank
+const MIN_CHANNEL_PAIRS: u64 = 20;
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+struct Counts {
+    pairs: u64,
+    kept: u64,
+}
+
+impl Counts {
+    fn add(&mut self, kept: bool) {
+        self.pairs += 1;
+        self.kept += kept as u64;
+    }
+
+    fn merge(&mut self, other: &Counts) {
+        self.pairs += other.pairs;
+        self.kept += other.kept;
+    }
+
+    fn pass_rate(&self) -> f64 {
+        if self.pairs > 0 {
+            self.kept as f64 / self.pairs as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Days from 1970-01-01 to a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Seconds since the Unix epoch of an `st` tag such as `2023-06-01T12:34:56.789+00:00`
+pub fn parse_start_time(value: &str) -> Option<i64> {
+    let (date, time) = value.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Split off the zone: `Z`, `+HH:MM` or `-HH:MM`
+    let (clock, offset) = match time.find(['Z', '+', '-']) {
+        Some(at) => {
+            let (clock, zone) = time.split_at(at);
+            let offset = match zone {
+                "Z" => 0,
+                _ => {
+                    let sign = if zone.starts_with('-') { -1 } else { 1 };
+                    let (hours, minutes) = zone[1..].split_once(':').unwrap_or((&zone[1..], "0"));
+                    sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60)
+                }
+            };
+            (clock, offset)
+        }
+        None => (time, 0),
+    };
+    let mut clock = clock.splitn(3, ':');
+    let hours: i64 = clock.next()?.parse().ok()?;
+    let minutes: i64 = clock.next()?.parse().ok()?;
+    // Fractional seconds don't matter at bucket resolution
+    let seconds: i64 = clock.next()?.split('.').next()?.parse().ok()?;
+
+    Some(days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds - offset)
+}
+
+/// Pair counts per channel and per run-time bucket
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct NanoporeStats {
+    bucket_minutes: u32,
+    channels: BTreeMap<u32, Counts>,
+    /// Keyed by bucket start, in minutes since the Unix epoch
+    buckets: BTreeMap<i64, Counts>,
+    /// Pairs with a channel but no readable start time
+    without_time: u64,
+}
+
+impl NanoporeStats {
+    pub fn new(bucket_minutes: u32) -> Self {
+        NanoporeStats {
+            bucket_minutes: bucket_minutes.max(1),
+            ..NanoporeStats::default()
+        }
+    }
+
+    /// Whether no pair carried nanopore tags
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty() && self.buckets.is_empty()
+    }
+
+    pub fn record(&mut self, record: &bam::Record, kept: bool) {
+        let Some(channel) = aux_integer(record, b"ch").and_then(|ch| u32::try_from(ch).ok()) else {
+            return;
+        };
+        self.channels.entry(channel).or_default().add(kept);
+
+        let start = match record.aux(b"st") {
+            Ok(Aux::String(st)) => parse_start_time(st),
+            _ => None,
+        };
+        match start {
+            Some(seconds) => {
+                let width = self.bucket_minutes as i64;
+                let bucket = seconds.div_euclid(60).div_euclid(width) * width;
+                self.buckets.entry(bucket).or_default().add(kept);
+            }
+            None => self.without_time += 1,
+        }
+    }
+
+    /// Add another run's counts; buckets line up only when both used the same width
+    pub fn merge(&mut self, other: &NanoporeStats) {
+        if self.bucket_minutes == 0 {
+            self.bucket_minutes = other.bucket_minutes;
+        }
+        for (channel, counts) in &other.channels {
+            self.channels.entry(*channel).or_default().merge(counts);
+        }
+        for (bucket, counts) in &other.buckets {
+            self.buckets.entry(*bucket).or_default().merge(counts);
+        }
+        self.without_time += other.without_time;
+    }
+
+    /// Buckets as (minutes since the first bucket, counts)
+    fn run_time(&self) -> impl Iterator<Item = (i64, &Counts)> {
+        let first = self.buckets.keys().next().copied().unwrap_or(0);
+        self.buckets
+            .iter()
+            .map(move |(bucket, counts)| (bucket - first, counts))
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        let pairs: u64 = self.channels.values().map(|counts| counts.pairs).sum();
+        println!("\n=== Nanopore Channels and Run Time ===");
+        println!(
+            "{} pairs from {} channels",
+            format.count(pairs),
+            self.channels.len()
+        );
+        if self.without_time > 0 {
+            println!(
+                "Pairs without a start time (st): {}",
+                format.count(self.without_time)
+            );
+        }
+
+        let mut ranked: Vec<(&u32, &Counts)> = self
+            .channels
+            .iter()
+            .filter(|(_, counts)| counts.pairs >= MIN_CHANNEL_PAIRS)
+            .collect();
+        ranked.sort_by(|a, b| a.1.pass_rate().total_cmp(&b.1.pass_rate()));
+        if !ranked.is_empty() {
+            println!(
+                "Lowest pass rates (channels with at least {} pairs):",
+                MIN_CHANNEL_PAIRS
+            );
+            println!(
+                "{:<10} {:>12} {:>12} {:>10}",
+                "Channel", "Pairs", "Kept", "Pass rate"
+            );
+            for (channel, counts) in ranked.into_iter().take(WORST_CHANNELS) {
+                println!(
+                    "{:<10} {:>12} {:>12} {:>10}",
+                    channel,
+                    format.count(counts.pairs),
+                    format.count(counts.kept),
+                    format.percent(counts.pass_rate() * 100.0)
+                );
+            }
+        }
+
+        if !self.buckets.is_empty() {
+            println!(
+                "Pass rate by run time ({}-minute buckets):",
+                self.bucket_minutes
+            );
+            println!(
+                "{:<10} {:>12} {:>12} {:>10}",
+                "Start (h)", "Pairs", "Kept", "Pass rate"
+            );
+            for (minutes, counts) in self.run_time() {
+                println!(
+                    "{:<10.1} {:>12} {:>12} {:>9.2}%",
+                    minutes as f64 / 60.0,
+                    counts.pairs,
+                    counts.kept,
+                    counts.pass_rate() * 100.0
+                );
+            }
+        }
+    }
+
+    /// Write one row per channel, then one per time bucket (start in minutes into the run)
+    pub fn write_tsv(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+
+        writeln!(out, "level\tid\tpairs\tkept\tpass_rate")?;
+        for (channel, counts) in &self.channels {
+            writeln!(
+                out,
+                "channel\t{}\t{}\t{}\t{:.4}",
+                channel,
+                counts.pairs,
+                counts.kept,
+                counts.pass_rate()
+            )?;
+        }
+        for (minutes, counts) in self.run_time() {
+            writeln!(
+                out,
+                "time\t{}\t{}\t{}\t{:.4}",
+                minutes,
+                counts.pairs,
+                counts.kept,
+                counts.pass_rate()
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! Reporting the end of a run to a webhook (`--notify-webhook`)
+//!
+//! Long unattended runs can report back to Slack or a monitoring endpoint
+//! without a wrapper script. The run POSTs one JSON object when it ends:
+//! `text`, a one-line summary that chat webhooks display, `status`
+//! (`completed`, `interrupted` or `failed`), `input`, and the statistics of
+//! `--stats-json` under `stats` or the error under `error`.
+//!
+//! Notification is best effort. A webhook that is unreachable, slower than
+//! `--notify-timeout` or answers with an error status gets a warning on
+//! stderr and never changes the run's exit status. The URL is left out of
+//! the warning, as webhook URLs often embed their secret.
+
+use crate::header::PROGRAM_NAME;
+use crate::report::Report;
+use crate::units::NumberFormat;
+use anyhow::{bail, Result};
+use curl_sys as curl;
+use serde_json::{json, Value};
+use std::ffi::{c_char, c_long, c_void, CStr, CString};
+use std::time::Duration;
+
+/// Seconds `--notify-timeout` allows when not given
+pub const DEFAULT_TIMEOUT_SECONDS: f64 = 10.0;
+
+/// The payload of a run that finished, or stopped on a signal
+pub fn completed(input: &str, report: &Report) -> Value {
+    let (status, verb) = if report.interrupted {
+        ("interrupted", "was interrupted")
+    } else {
+        ("completed", "completed")
+    };
+    // Plain digits, as chat clients and log searches read them
+    let share = if report.total_pairs > 0 {
+        format!(
+            " ({})",
+            NumberFormat::default().share(report.kept_pairs, report.total_pairs)
+        )
+    } else {
+        String::new()
+    };
+    json!({
+        "text": format!(
+            "{} {} {}: kept {} of {} {}{}",
+            PROGRAM_NAME, input, verb, report.kept_pairs, report.total_pairs, report.unit(), share
+        ),
+        "status": status,
+        "input": input,
+        "stats": report,
+    })
+}
+
+/// The payload of a run that failed
+pub fn failed(input: &str, error: &anyhow::Error) -> Value {
+    json!({
+        "text": format!("{} {} failed: {:#}", PROGRAM_NAME, input, error),
+        "status": "failed",
+        "input": input,
+        "error": format!("{:#}", error),
+    })
+}
+
+/// Throw away the response body, which libcurl would print otherwise
+extern "C" fn discard(_data: *mut c_char, size: usize, count: usize, _: *mut c_void) -> usize {
+    size * count
+}
+
+/// POST `body` as JSON to `url`, returning the HTTP status
+pub fn post_json(url: &str, body: &str, timeout: Duration) -> Result<u32> {
+    let c_url = CString::new(url)?;
+    let header = CString::new("Content-Type: application/json")?;
+    // SAFETY: every pointer handed to libcurl outlives curl_easy_perform,
+    // and the handle and header list are freed once, on every path
+    unsafe {
+        let handle = curl::curl_easy_init();
+        if handle.is_null() {
+            bail!("cannot start libcurl");
+        }
+        let headers = curl::curl_slist_append(std::ptr::null_mut(), header.as_ptr());
+        let timeout_ms = timeout.as_millis().min(c_long::MAX as u128) as c_long;
+        let write: curl::curl_write_callback = discard;
+        let options = [
+            curl::curl_easy_setopt(handle, curl::CURLOPT_URL, c_url.as_ptr()),
+            curl::curl_easy_setopt(handle, curl::CURLOPT_POSTFIELDS, body.as_ptr()),
+            curl::curl_easy_setopt(
+                handle,
+                curl::CURLOPT_POSTFIELDSIZE_LARGE,
+                body.len() as curl::curl_off_t,
+            ),
+            curl::curl_easy_setopt(handle, curl::CURLOPT_HTTPHEADER, headers),
+            curl::curl_easy_setopt(handle, curl::CURLOPT_TIMEOUT_MS, timeout_ms),
+            // No SIGALRM for the timeout; the run has its own signal handling
+            curl::curl_easy_setopt(handle, curl::CURLOPT_NOSIGNAL, 1 as c_long),
+            curl::curl_easy_setopt(handle, curl::CURLOPT_WRITEFUNCTION, write),
+        ];
+        let mut code = options
+            .into_iter()
+            .find(|&code| code != curl::CURLE_OK)
+            .unwrap_or(curl::CURLE_OK);
+        if code == curl::CURLE_OK {
+            code = curl::curl_easy_perform(handle);
+        }
+        let mut status: c_long = 0;
+        if code == curl::CURLE_OK {
+            code = curl::curl_easy_getinfo(handle, curl::CURLINFO_RESPONSE_CODE, &mut status);
+        }
+        curl::curl_slist_free_all(headers);
+        curl::curl_easy_cleanup(handle);
+        if code != curl::CURLE_OK {
+            bail!(
+                "{}",
+                CStr::from_ptr(curl::curl_easy_strerror(code)).to_string_lossy()
+            );
+        }
+        Ok(status as u32)
+    }
+}
+
+/// POST `payload` to `url`, warning instead of failing when that doesn't work
+pub fn notify(url: &str, payload: &Value, timeout: Duration) {
+    match post_json(url, &payload.to_string(), timeout) {
+        Ok(status) if (200..300).contains(&status) => {}
+        Ok(status) => eprintln!(
+            "Warning: --notify-webhook: the webhook answered HTTP {}",
+            status
+        ),
+        Err(e) => eprintln!("Warning: --notify-webhook: {}", e),
+    }
+}
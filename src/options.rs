@@ -0,0 +1,1036 @@
+//! The options of a filtering run (`filter_bam_pairs` and `check-config`)
+//!
+//! [`Args`] is what the binary parses its command line into, and what
+//! [`crate::pipeline::run`] filters by. A program embedding the run builds it
+//! the same way, with `clap::Parser` on a struct that flattens it, so every
+//! option keeps its default.
+
+use crate::filter::KMER_SIZE;
+use crate::{
+    adaptive, audit, chain, complexity, duplex, expr, fastq_input, filter, flags, global_kmers,
+    grouping, hic, input, lengths, metrics, names, nanopore, notify, output, provenance, quality,
+    read_errors, regions, report, sample, sort, summary, units, validation,
+};
+use anyhow::Result;
+use clap::ValueEnum;
+use rust_htslib::bam;
+
+/// Named bundles of options for specific library types
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Preset {
+    /// Hi-C: MAPQ >= 30 on both mates, trans and long-range pairs kept
+    Hic,
+}
+
+/// Every option of a filtering run, as the command line and config files
+/// give them
+#[derive(clap::Args, Debug, Clone)]
+pub struct Args {
+    /// Read option defaults from a config file (`key = value` per line)
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<String>,
+
+    /// Apply a bundle of options for a library type (overridable by later options)
+    #[arg(long, value_enum)]
+    pub preset: Option<Preset>,
+
+    /// Input BAM file (must be name-sorted), or - for standard input
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required_unless_present = "input_r1",
+        default_value = "",
+        hide_default_value = true
+    )]
+    pub input: String,
+
+    /// Mate-1 FASTQ(.gz) of paired reads, instead of a BAM; filters before alignment
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "input_r2",
+        conflicts_with_all = [
+            "input", "single_end", "regions", "region", "coordinate_sorted", "parallel_contigs",
+            "resync", "prefetch_batches", "metric_cache", "min_mapped", "min_mapped_fraction",
+            "max_clip_fraction", "min_gap_compressed_identity", "max_divergence", "min_mapq",
+            "min_insert", "max_insert", "require_same_reference", "max_nm", "max_error_rate",
+            "splice_aware", "max_splice_junctions", "rescue_by_mate", "regenerate_md", "targets",
+            "primers", "max_region_depth", "rejection_bedgraph", "clip_profile",
+            "library_complexity", "estimate_duplicates", "bx_stats", "min_bx_reads",
+            "min_family_size", "family_size_histogram",
+        ]
+    )]
+    pub input_r1: Option<String>,
+
+    /// Mate-2 FASTQ(.gz) matching --input-r1, read for read
+    #[arg(long, value_name = "FILE", requires = "input_r1")]
+    pub input_r2: Option<String>,
+
+    /// Output BAM file, or - for standard output (the report then goes to stderr)
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required_unless_present_any = ["decisions", "dry_run", "fastq_out"]
+    )]
+    pub output: Option<String>,
+
+    /// Format of the written files (default: from each file's extension)
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub output_format: Option<output::OutputFormat>,
+
+    /// Reference FASTA for CRAM input and output
+    #[arg(long, value_name = "FASTA")]
+    pub reference: Option<String>,
+
+    /// Add NM and MD to mapped records lacking them, from --reference
+    #[arg(long, requires = "reference")]
+    pub regenerate_md: bool,
+
+    /// Filter each record on its own, for BAMs that are not paired-end
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "resync", "fastq_out", "failed_fastq", "shards", "audit", "metric_cache", "ligation_motif",
+            "library_complexity", "estimate_duplicates", "rejection_bedgraph", "verify_output",
+            "min_bx_reads", "max_region_depth", "bx_stats", "clip_profile", "targets", "primers", "rescue_by_mate",
+            "min_insert", "max_insert", "require_same_reference", "min_family_size",
+            "family_size_histogram", "coordinate_sorted",
+        ]
+    )]
+    pub single_end: bool,
+
+    /// Complexity cutoff (0.0-1.0, default: 0.8)
+    #[arg(short, long, default_value = "0.8")]
+    pub complexity: f64,
+
+    /// How read complexity is measured; -c applies to each method's 0..1 score
+    #[arg(
+        long,
+        value_enum,
+        value_name = "METHOD",
+        default_value = "kmer-uniqueness"
+    )]
+    pub complexity_method: complexity::ComplexityMethod,
+
+    /// Kmer length of the complexity metric and the kmer filters
+    #[arg(long, value_name = "K", default_value_t = KMER_SIZE)]
+    pub kmer_size: usize,
+
+    /// Window of the dust and shannon-entropy methods, in bases (0 = the whole read)
+    #[arg(long, value_name = "BASES", default_value_t = complexity::DEFAULT_WINDOW)]
+    pub complexity_window: usize,
+
+    /// Word length of the shannon-entropy method
+    #[arg(long, value_name = "N", default_value_t = complexity::DEFAULT_ENTROPY_WORD_SIZE)]
+    pub entropy_word_size: usize,
+
+    /// How a read shorter than the kmer length is judged by the complexity filter
+    #[arg(long, value_enum, value_name = "POLICY", default_value = "fail")]
+    pub short_read_policy: filter::ShortReadPolicy,
+
+    /// Count a kmer and its reverse complement as the same kmer, so both strands score alike
+    #[arg(long)]
+    pub canonical: bool,
+
+    /// Minimum contiguous mapped bases (default: 0 = disabled)
+    #[arg(short, long, default_value = "0")]
+    pub min_mapped: u32,
+
+    /// Compute complexity on a C->T collapsed alphabet for bisulfite/EM-seq data
+    #[arg(long)]
+    pub bisulfite: bool,
+
+    /// With --bisulfite, collapse G->A for original-bottom-strand pairs
+    #[arg(long, requires = "bisulfite")]
+    pub bisulfite_strand_aware: bool,
+
+    /// Let N (intron) CIGAR operations join exons into one mapped stretch
+    #[arg(long)]
+    pub splice_aware: bool,
+
+    /// Maximum number of splice junctions (N operations) per read
+    #[arg(long, value_name = "N")]
+    pub max_splice_junctions: Option<u32>,
+
+    /// Minimum longest mapped stretch as a fraction of read length, both mates
+    #[arg(long, value_name = "F")]
+    pub min_mapped_fraction: Option<f64>,
+
+    /// Maximum clipped bases as a fraction of read length, both mates
+    #[arg(long, value_name = "F")]
+    pub max_clip_fraction: Option<f64>,
+
+    /// Minimum gap-compressed identity (minimap2 definition, from NM and CIGAR), both mates
+    #[arg(long, value_name = "F")]
+    pub min_gap_compressed_identity: Option<f64>,
+
+    /// Maximum per-base divergence from minimap2's de:f tag (else from NM and CIGAR), both mates
+    #[arg(long = "max-de", value_name = "F")]
+    pub max_divergence: Option<f64>,
+
+    /// Read length used by the fraction filters
+    #[arg(long, value_enum, default_value = "query")]
+    pub length_basis: metrics::LengthBasis,
+
+    /// Minimum MAPQ required for both mates (default: 0 = disabled)
+    #[arg(long, value_name = "Q", default_value = "0")]
+    pub min_mapq: u8,
+
+    /// Minimum mean base quality (Phred, from QUAL), both mates
+    #[arg(long, value_name = "Q")]
+    pub min_avg_baseq: Option<f64>,
+
+    /// Minimum fraction F of bases with quality at least Q, both mates, e.g. 20:0.9
+    #[arg(long, value_name = "Q:F")]
+    pub min_baseq_fraction: Option<quality::BaseqFraction>,
+
+    /// Minimum absolute template length (TLEN) of a pair
+    #[arg(long, value_name = "BP")]
+    pub min_insert: Option<u64>,
+
+    /// Maximum absolute template length (TLEN) of a pair
+    #[arg(long, value_name = "BP")]
+    pub max_insert: Option<u64>,
+
+    /// Require both mates mapped to the same reference
+    #[arg(long)]
+    pub require_same_reference: bool,
+
+    /// Maximum edit distance (NM tag), both mates
+    #[arg(long, value_name = "N")]
+    pub max_nm: Option<u32>,
+
+    /// Maximum edit distance per aligned read base (NM / M+=+X+I bases), both mates
+    #[arg(long, value_name = "F")]
+    pub max_error_rate: Option<f64>,
+
+    /// What --max-nm and --max-error-rate do with mapped reads that have no NM tag
+    #[arg(long, value_enum, default_value = "error")]
+    pub missing_nm: filter::MissingNm,
+
+    /// Keep a pair whose one failing-complexity mate has a complex, confidently mapped mate nearby
+    #[arg(long)]
+    pub rescue_by_mate: bool,
+
+    /// Minimum MAPQ of the rescuing mate with --rescue-by-mate
+    #[arg(
+        long,
+        value_name = "Q",
+        default_value = "30",
+        requires = "rescue_by_mate"
+    )]
+    pub rescue_min_mapq: u8,
+
+    /// Read only records with all of these flag bits (number or names, as samtools view -f)
+    #[arg(long, value_name = "FLAGS", value_parser = flags::parse_flags, default_value = "0")]
+    pub require_flags: u16,
+
+    /// Skip records with any of these flag bits, e.g. SECONDARY,SUPPLEMENTARY (as samtools view -F)
+    #[arg(long, value_name = "FLAGS", value_parser = flags::parse_flags, default_value = "0")]
+    pub exclude_flags: u16,
+
+    /// Secondary and supplementary records of a pair: written with it, or left out
+    #[arg(long, value_enum, value_name = "ACTION", default_value = "carry")]
+    pub extra_alignments: grouping::ExtraAlignments,
+
+    /// Maximum distance between the mates' start positions with --rescue-by-mate, in bp
+    #[arg(
+        long,
+        value_name = "BP",
+        default_value = "1000",
+        requires = "rescue_by_mate"
+    )]
+    pub rescue_max_distance: u32,
+
+    /// Tag reads containing these ligation junction motifs, comma-separated with N for any base, with xj:i
+    #[arg(long, value_name = "SEQ", value_parser = hic::parse_motifs)]
+    pub ligation_motif: Option<hic::LigationMotifs>,
+
+    /// Write every pair, tagged with xc:f complexity, xm:i longest mapped stretch and, if it fails, xf:Z reasons
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "short_circuit", "rejected_output", "failed_fastq", "fastq_out",
+            "adaptive_sampling_output", "decisions", "dry_run",
+        ]
+    )]
+    pub annotate: bool,
+
+    /// Always count every kmer instead of stopping once the cutoff is decided
+    #[arg(long)]
+    pub exact_complexity: bool,
+
+    /// Check thresholds cheapest first and stop at a pair's first failure, reporting evaluations per filter
+    #[arg(
+        long,
+        conflicts_with_all = ["stats_out", "complexity_histogram", "targets", "audit"]
+    )]
+    pub short_circuit: bool,
+
+    /// Comma-separated thresholds to check first under --short-circuit, e.g. min_mapq,complexity
+    #[arg(long, value_name = "LIST", requires = "short_circuit")]
+    pub filter_order: Option<String>,
+
+    /// Take complexity and mapped bases from existing xc/xm tags when present
+    #[arg(long)]
+    pub use_cached_metrics: bool,
+
+    /// Take complexity and mapped bases from a cache-metrics sidecar of this input
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["resync", "use_cached_metrics"])]
+    pub metric_cache: Option<String>,
+
+    /// Skip reads whose mate is missing instead of aborting on a name mismatch
+    #[arg(long)]
+    pub resync: bool,
+
+    /// Pair the mates of a coordinate-sorted input by name instead of requiring name-sorted pairs
+    #[arg(long, conflicts_with_all = ["resync", "metric_cache"])]
+    pub coordinate_sorted: bool,
+
+    /// Memory for records waiting for their mate with --coordinate-sorted, in MiB; more spill to disk
+    #[arg(
+        long,
+        value_name = "MIB",
+        default_value = "1024",
+        requires = "coordinate_sorted"
+    )]
+    pub mate_buffer_memory: usize,
+
+    /// Filter a coordinate-sorted, indexed input by contig in N worker processes, merging their outputs
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "0",
+        conflicts_with_all = [
+            "resync", "coordinate_sorted", "regions", "region", "metric_cache", "prefetch_batches",
+            "shards", "sort_output", "index_output", "rejected_output", "fastq_out", "failed_fastq",
+            "decisions", "audit", "stats_out", "library_complexity", "estimate_duplicates",
+            "rejection_bedgraph", "complexity_histogram", "verify_output", "check_name_collisions",
+            "preview_pairs", "preview_seconds", "max_global_kmer_percentile",
+            "adaptive_sampling_output", "trace_qname",
+        ]
+    )]
+    pub parallel_contigs: usize,
+
+    /// Run as a --parallel-contigs worker writing PREFIX.bam and PREFIX.json
+    #[arg(long, value_name = "PREFIX", hide = true)]
+    pub contig_worker: Option<String>,
+
+    /// Records to look ahead for a mate in --resync mode
+    #[arg(long, value_name = "N", default_value = "10000", requires = "resync")]
+    pub resync_window: u64,
+
+    /// What a damaged input record does: fail the run, or skip past it (paired runs need --resync)
+    #[arg(long, value_enum, value_name = "ACTION", default_value = "fail")]
+    pub on_read_error: read_errors::OnReadError,
+
+    /// What a malformed record (bad CIGAR, invalid position, inconsistent flags) does: fail the run, warn and count it, or only count it
+    #[arg(long, value_enum, value_name = "LEVEL", default_value = "lenient")]
+    pub validation: validation::Validation,
+
+    /// What to do when the first reads' base qualities look mis-encoded or corrupt
+    #[arg(long, value_enum, default_value = "warn")]
+    pub quality_check: quality::QualityCheck,
+
+    /// What to do when the kmer size or --min-mapped is longer than most of the first reads
+    #[arg(long, value_enum, value_name = "ACTION", default_value = "warn")]
+    pub length_check: lengths::LengthCheck,
+
+    /// Report read names used by more than one pair anywhere in the input (Bloom filter)
+    #[arg(long)]
+    pub check_name_collisions: bool,
+
+    /// Memory for --check-name-collisions, in MiB
+    #[arg(
+        long,
+        value_name = "MIB",
+        default_value = "256",
+        requires = "check_name_collisions"
+    )]
+    pub name_check_memory: usize,
+
+    /// Input read size in KiB; for pipes, also the kernel pipe buffer to request
+    #[arg(long, value_name = "KIB", default_value_t = input::DEFAULT_BUFFER_KIB)]
+    pub input_buffer: usize,
+
+    /// Extra htslib threads, shared by the decompression of the input and the compression of every output
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub threads: usize,
+
+    /// Read the input ahead on a separate thread, with up to N batches of records waiting (2 double-buffers; 0 reads inline)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub prefetch_batches: usize,
+
+    /// Filter even if the input header shows it was already filtered by this tool
+    #[arg(long)]
+    pub force: bool,
+
+    /// Estimate library complexity from duplicate positions (keeps one entry per fragment in memory)
+    #[arg(long)]
+    pub library_complexity: bool,
+
+    /// Estimate the PCR duplicate rate from a sample of fragment positions
+    #[arg(long)]
+    pub estimate_duplicates: bool,
+
+    /// Sample one in N fragment positions for --estimate-duplicates
+    #[arg(long, value_name = "N", default_value = "64")]
+    pub dup_sample_rate: u64,
+
+    /// Write kept pairs to PREFIX_R1.fastq.gz and PREFIX_R2.fastq.gz, as sequenced
+    #[arg(long, value_name = "PREFIX")]
+    pub fastq_out: Option<String>,
+
+    /// Write rejected pairs to PREFIX_R1.fastq.gz and PREFIX_R2.fastq.gz
+    #[arg(long, value_name = "PREFIX")]
+    pub failed_fastq: Option<String>,
+
+    /// Write reverse-strand reads to FASTQ as aligned instead of as sequenced
+    #[arg(long)]
+    pub keep_alignment_orientation: bool,
+
+    /// Write rejected pairs to this BAM (may contain {contig}, {rg} or {dx})
+    #[arg(long, value_name = "FILE")]
+    pub rejected_output: Option<String>,
+
+    /// Split kept pairs round-robin over N output BAMs (out.0.bam, ...), one writer thread each
+    #[arg(long, value_name = "N", default_value = "1")]
+    pub shards: usize,
+
+    /// Sort the output (external merge sort in the temp directory)
+    #[arg(long, value_enum, value_name = "ORDER", conflicts_with = "shards")]
+    pub sort_output: Option<sort::SortOrder>,
+
+    /// Memory for buffering records while sorting, in MiB
+    #[arg(long, value_name = "MIB", default_value = "768")]
+    pub sort_memory: usize,
+
+    /// Write a BAI (CRAI for CRAM) index for the sorted output
+    #[arg(long, requires = "sort_output")]
+    pub index_output: bool,
+
+    /// Keep only pairs that also satisfy EXPR over the mates, or each read (e.g. 'abs(r1.pos - r2.pos) < 1000')
+    #[arg(long, value_name = "EXPR")]
+    pub filter_expr: Option<String>,
+
+    /// Keep only pairs whose name hashes into bucket K (or buckets A-B) of D, e.g. 1/10
+    #[arg(long, value_name = "K/D")]
+    pub hash_sample: Option<sample::HashSample>,
+
+    /// Write rejected reads per genomic bin as a bedGraph (e.g. for IGV)
+    #[arg(long, value_name = "FILE")]
+    pub rejection_bedgraph: Option<String>,
+
+    /// Bin size for --rejection-bedgraph, in bp
+    #[arg(
+        long,
+        value_name = "BP",
+        default_value = "10000",
+        requires = "rejection_bedgraph"
+    )]
+    pub rejection_bin_size: u32,
+
+    /// Print every filter, value and the verdict for the pair of this read name to stderr (repeatable)
+    #[arg(long, value_name = "READNAME")]
+    pub trace_qname: Vec<String>,
+
+    /// Write each read's complexity, longest mapped stretch and verdict to FILE
+    #[arg(long, value_name = "FILE")]
+    pub audit: Option<String>,
+
+    /// Encoding of --audit
+    #[arg(long, value_enum, default_value = "tsv", requires = "audit")]
+    pub audit_format: audit::AuditFormat,
+
+    /// Write the names of removed pairs to FILE for `apply`; -o becomes optional
+    #[arg(long, value_name = "FILE")]
+    pub decisions: Option<String>,
+
+    /// Stop after the first N pairs, finishing outputs and the report as usual
+    #[arg(long, value_name = "N")]
+    pub preview_pairs: Option<u64>,
+
+    /// Stop filtering after S seconds, finishing outputs and the report as usual
+    #[arg(long, value_name = "S")]
+    pub preview_seconds: Option<f64>,
+
+    /// Run every filter and write the report and statistics files, but no reads
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "output",
+            "rejected_output",
+            "fastq_out",
+            "failed_fastq",
+            "decisions",
+            "adaptive_sampling_output"
+        ]
+    )]
+    pub dry_run: bool,
+
+    /// Re-read the output after writing and fail if pairs, counts, EOF or @PG are wrong
+    #[arg(long)]
+    pub verify_output: bool,
+
+    /// Write summary numbers as samtools-stats SN lines
+    #[arg(long, value_name = "FILE")]
+    pub stats_sn: Option<String>,
+
+    /// Write mergeable run statistics as JSON (combine runs with merge-stats)
+    #[arg(long, value_name = "FILE")]
+    pub stats_json: Option<String>,
+
+    /// Write OUTPUT.provenance.json with the command line, every option, input checksums, environment and threads
+    #[arg(long)]
+    pub provenance: bool,
+
+    /// Write a summary for workflow managers: totals, failures per filter, complexity percentiles, runtime
+    #[arg(long, value_name = "FILE")]
+    pub stats_out: Option<String>,
+
+    /// Layout of the --stats-out file
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value = "json",
+        requires = "stats_out"
+    )]
+    pub stats_format: summary::StatsFormat,
+
+    /// Layout version of the --stats-json file, for parsers written against an older one
+    #[arg(long, value_name = "N", default_value_t = report::SCHEMA_VERSION)]
+    pub report_schema_version: u32,
+
+    /// POST the run's statistics as JSON to this URL when it completes or fails
+    #[arg(long, value_name = "URL")]
+    pub notify_webhook: Option<String>,
+
+    /// Seconds to wait for the webhook before giving up with a warning
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = notify::DEFAULT_TIMEOUT_SECONDS,
+        requires = "notify_webhook"
+    )]
+    pub notify_timeout: f64,
+
+    /// How the console summary prints counts; stats files always hold plain digits
+    #[arg(long, value_enum, value_name = "UNITS", default_value = "raw")]
+    pub report_units: units::ReportUnits,
+
+    /// Decimal places of percentages (and human-readable counts) in the console summary
+    #[arg(long, value_name = "N", default_value_t = units::DEFAULT_DECIMALS)]
+    pub report_decimals: usize,
+
+    /// Report wall time, CPU time and peak RSS per pipeline stage
+    #[arg(long)]
+    pub stage_timing: bool,
+
+    /// Directory for temporary files (default: $TMPDIR or the system temp dir)
+    #[arg(long, value_name = "DIR")]
+    pub tmp_dir: Option<String>,
+
+    /// Raise the soft open-file limit to N (capped at the hard limit)
+    #[arg(long, value_name = "N")]
+    pub max_open_files: Option<u64>,
+
+    /// Keep resident memory under MIB: spill buffers near it, stop cleanly at it
+    #[arg(long, value_name = "MIB")]
+    pub max_rss: Option<u64>,
+
+    /// Read-ahead per spill file while merging sorted output, in KiB (default: htslib's)
+    #[arg(long, value_name = "KIB", requires = "sort_output")]
+    pub spill_buffer: Option<usize>,
+
+    /// Drop pairs whose BX barcode has fewer than N reads in the input (extra pass)
+    #[arg(long, value_name = "N", default_value = "0")]
+    pub min_bx_reads: u64,
+
+    /// Drop pairs whose MI molecule has fewer than N pairs in the input (extra pass)
+    #[arg(long, value_name = "N", default_value = "0")]
+    pub min_family_size: u64,
+
+    /// Drop pairs with a mate in a bin whose mean depth exceeds N (extra pass)
+    #[arg(long, value_name = "N")]
+    pub max_region_depth: Option<u32>,
+
+    /// Bin size for --max-region-depth, in bp
+    #[arg(
+        long,
+        value_name = "BP",
+        default_value = "1000",
+        requires = "max_region_depth"
+    )]
+    pub depth_bin_size: u32,
+
+    /// Drop pairs with a mate made mostly of kmers above this percentile of dataset-wide counts (extra pass)
+    #[arg(long, value_name = "P")]
+    pub max_global_kmer_percentile: Option<f64>,
+
+    /// Fraction of a read's kmers above the percentile that makes it fail
+    #[arg(
+        long,
+        value_name = "F",
+        default_value = "0.5",
+        requires = "max_global_kmer_percentile"
+    )]
+    pub max_high_frequency_kmers: f64,
+
+    /// Memory for dataset-wide kmer counts, in MiB
+    #[arg(
+        long,
+        value_name = "MIB",
+        default_value_t = global_kmers::DEFAULT_MEMORY_MIB,
+        requires = "max_global_kmer_percentile"
+    )]
+    pub global_kmer_memory: usize,
+
+    /// Take the dataset-wide kmer counts from a Jellyfish/KMC dump instead of an extra pass
+    #[arg(long, value_name = "FILE", requires = "max_global_kmer_percentile")]
+    pub kmer_counts: Option<String>,
+
+    /// Drop pairs with a mate containing kmers listed in FILE (Jellyfish/KMC dump or one per line)
+    #[arg(long, value_name = "FILE")]
+    pub kmer_blacklist: Option<String>,
+
+    /// Blacklisted kmers a read may contain before it fails
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "0",
+        requires = "kmer_blacklist"
+    )]
+    pub max_blacklist_kmers: u32,
+
+    /// Keep only pairs whose read name is listed in FILE (one per line, may be gzipped)
+    #[arg(long, value_name = "FILE", conflicts_with = "exclude_names")]
+    pub include_names: Option<String>,
+
+    /// Remove pairs whose read name is listed in FILE (one per line, may be gzipped)
+    #[arg(long, value_name = "FILE")]
+    pub exclude_names: Option<String>,
+
+    /// How --include-names/--exclude-names lists are held in memory
+    #[arg(long, value_enum, default_value = "hashed")]
+    pub name_match: names::NameMatch,
+
+    /// Bloom filter size for --name-match bloom, in MiB
+    #[arg(long, value_name = "MIB", default_value_t = names::DEFAULT_BLOOM_MIB)]
+    pub name_set_memory: usize,
+
+    /// Write per-barcode pass rates (TSV) and report BX statistics
+    #[arg(long, value_name = "FILE")]
+    pub bx_stats: Option<String>,
+
+    /// Write the number of MI families per family size, before and after filtering, as TSV
+    #[arg(long, value_name = "FILE")]
+    pub family_size_histogram: Option<String>,
+
+    /// Write pass rates per sample (SM of the header's @RG lines) and read group as TSV
+    #[arg(long, value_name = "FILE")]
+    pub sample_stats: Option<String>,
+
+    /// Write pass rates per nanopore channel and run-time bucket (dorado ch/st tags) as TSV
+    #[arg(long, value_name = "FILE")]
+    pub ont_stats: Option<String>,
+
+    /// Width of the run-time buckets of the nanopore statistics, in minutes
+    #[arg(long, value_name = "MIN", default_value_t = nanopore::DEFAULT_TIME_BUCKET_MINUTES)]
+    pub ont_time_bucket: u32,
+
+    /// Nanopore reads to keep by dorado's dx tag: duplex, simplex or all but the duplex parents
+    #[arg(long, value_enum, value_name = "CLASS", default_value = "all")]
+    pub duplex_reads: duplex::DuplexReads,
+
+    /// Remove nanopore adaptive-sampling rejects: short reads that are unmapped or mostly clipped
+    #[arg(long)]
+    pub adaptive_sampling_filter: bool,
+
+    /// Reads at least this long are never adaptive-sampling rejects, in bp
+    #[arg(
+        long,
+        value_name = "BP",
+        default_value_t = adaptive::DEFAULT_MAX_LENGTH,
+        requires = "adaptive_sampling_filter"
+    )]
+    pub adaptive_sampling_max_length: u32,
+
+    /// Clipped fraction from which a short mapped read is an adaptive-sampling reject
+    #[arg(
+        long,
+        value_name = "F",
+        default_value_t = adaptive::DEFAULT_MIN_CLIP_FRACTION,
+        requires = "adaptive_sampling_filter"
+    )]
+    pub adaptive_sampling_min_clip: f64,
+
+    /// Write the adaptive-sampling rejects to this BAM (may contain {contig}, {rg} or {dx})
+    #[arg(long, value_name = "FILE", requires = "adaptive_sampling_filter")]
+    pub adaptive_sampling_output: Option<String>,
+
+    /// Write soft-clip lengths at the 5' and 3' read ends, kept vs removed, as TSV
+    #[arg(long, value_name = "FILE")]
+    pub clip_profile: Option<String>,
+
+    /// Write the number of reads per complexity bin of 0.01 as TSV, to choose --complexity
+    #[arg(long, value_name = "FILE")]
+    pub complexity_histogram: Option<String>,
+
+    /// Report GC content of kept vs removed fragments and write it per GC percent as TSV
+    #[arg(long, value_name = "FILE")]
+    pub gc_profile: Option<String>,
+
+    /// Report kept and removed pairs per target interval in this BED file
+    #[arg(long, value_name = "BED")]
+    pub targets: Option<String>,
+
+    /// Write the per-target pass rates and mean metrics as TSV
+    #[arg(long, value_name = "FILE", requires = "targets")]
+    pub target_stats: Option<String>,
+
+    /// Only consider pairs with a mate overlapping an interval of this BED file
+    #[arg(long, value_name = "BED", conflicts_with = "metric_cache")]
+    pub regions: Option<String>,
+
+    /// Only consider pairs with a mate in this region (chr or chr:start-end, 1-based); repeatable
+    #[arg(long, value_name = "REGION", conflicts_with = "metric_cache")]
+    pub region: Vec<String>,
+
+    /// Soft-clip primer bases from alignment ends, using this ARTIC-style primer BED
+    #[arg(long, value_name = "BED")]
+    pub primers: Option<String>,
+
+    /// With --primers, reject pairs that don't span one amplicon from primer to primer
+    #[arg(long, requires = "primers")]
+    pub require_amplicon: bool,
+
+    /// Filter settings of the config file's `[read-group ID]` sections
+    #[arg(skip)]
+    pub read_group_configs: Vec<(String, filter::FilterConfig)>,
+
+    /// Every option's value, for --provenance
+    #[arg(skip)]
+    pub parameters: std::collections::BTreeMap<String, provenance::Parameter>,
+
+    /// The `--threads` every input and output file of the run shares
+    #[arg(skip)]
+    pub thread_pool: Option<rust_htslib::tpool::ThreadPool>,
+}
+
+impl Args {
+    /// The per-pair filter settings among the options
+    pub fn filter_config(&self) -> Result<filter::FilterConfig> {
+        Ok(filter::FilterConfig {
+            complexity: self.complexity,
+            complexity_method: self.complexity_method,
+            kmer_size: self.kmer_size,
+            complexity_window: self.complexity_window,
+            entropy_word_size: self.entropy_word_size,
+            short_reads: self.short_read_policy,
+            canonical: self.canonical,
+            min_mapped: self.min_mapped,
+            bisulfite: self.bisulfite,
+            bisulfite_strand_aware: self.bisulfite_strand_aware,
+            splice_aware: self.splice_aware,
+            max_splice_junctions: self.max_splice_junctions,
+            min_mapped_fraction: self.min_mapped_fraction,
+            max_clip_fraction: self.max_clip_fraction,
+            min_gap_compressed_identity: self.min_gap_compressed_identity,
+            max_divergence: self.max_divergence,
+            length_basis: self.length_basis,
+            min_mapq: self.min_mapq,
+            min_avg_baseq: self.min_avg_baseq,
+            min_baseq_fraction: self.min_baseq_fraction,
+            min_insert: self.min_insert,
+            max_insert: self.max_insert,
+            require_same_reference: self.require_same_reference,
+            max_nm: self.max_nm,
+            max_error_rate: self.max_error_rate,
+            missing_nm: self.missing_nm,
+            // Per-target means, percentiles and audits need exact values, not
+            // early-exit bounds
+            exact_complexity: self.exact_complexity
+                || self.annotate
+                || self.targets.is_some()
+                || self.stats_out.is_some()
+                || self.complexity_histogram.is_some()
+                || self.audit.is_some(),
+            use_cached_metrics: self.use_cached_metrics,
+            expression: self
+                .filter_expr
+                .as_deref()
+                .map(expr::Expr::parse)
+                .transpose()?,
+            hash_sample: self.hash_sample,
+            mate_rescue: self.rescue_by_mate.then_some(filter::MateRescue {
+                min_mapq: self.rescue_min_mapq,
+                max_distance: self.rescue_max_distance,
+            }),
+            short_circuit: match (&self.filter_order, self.short_circuit) {
+                (Some(list), _) => Some(chain::FilterOrder::parse(list)?),
+                (None, true) => Some(chain::FilterOrder::default()),
+                (None, false) => None,
+            },
+        })
+    }
+
+    /// The `--input-r1` and `--input-r2` FASTQ files, if given
+    pub fn fastq_input(&self) -> Option<(&str, &str)> {
+        self.input_r1.as_deref().zip(self.input_r2.as_deref())
+    }
+
+    /// Name both FASTQ files where progress, warnings and notifications name the input
+    pub fn name_fastq_input(&mut self) {
+        if let Some((r1, r2)) = self.fastq_input() {
+            self.input = format!("{} + {}", r1, r2);
+        }
+    }
+
+    /// The records of the input: the BAM, or the pairs of the FASTQ files
+    pub fn open_input(&self) -> Result<input::Source> {
+        Ok(match self.fastq_input() {
+            Some((r1, r2)) => {
+                input::Source::Fastq(Box::new(fastq_input::FastqPairs::open(r1, r2)?))
+            }
+            None => input::Source::Direct(input::open(
+                &self.input,
+                self.input_buffer,
+                self.reference.as_deref(),
+                self.thread_pool.as_ref(),
+            )?),
+        })
+    }
+
+    /// Records read before pairing, by `--require-flags`/`--exclude-flags`
+    pub fn flag_filter(&self) -> flags::FlagFilter {
+        flags::FlagFilter::new(self.require_flags, self.exclude_flags)
+    }
+
+    /// The `--regions` and `--region` intervals, if given
+    pub fn regions(&self, header: &bam::HeaderView) -> Result<Option<regions::Regions>> {
+        if self.regions.is_none() && self.region.is_empty() {
+            return Ok(None);
+        }
+        regions::Regions::read(self.regions.as_deref(), &self.region, header).map(Some)
+    }
+
+    /// What `--adaptive-sampling-filter` takes for a reject, if given
+    pub fn adaptive_sampling(&self) -> Option<adaptive::AdaptiveSampling> {
+        self.adaptive_sampling_filter
+            .then_some(adaptive::AdaptiveSampling {
+                max_length: self.adaptive_sampling_max_length,
+                min_clip_fraction: self.adaptive_sampling_min_clip,
+            })
+    }
+
+    /// Format and reference of the output files
+    pub fn encoding(&self) -> output::Encoding {
+        output::Encoding {
+            format: self.output_format,
+            reference: self.reference.clone(),
+            thread_pool: self.thread_pool.clone(),
+        }
+    }
+
+    /// What the run counts: reads with `--single-end`, pairs otherwise
+    pub fn unit(&self) -> &'static str {
+        if self.single_end {
+            "reads"
+        } else {
+            "pairs"
+        }
+    }
+
+    /// How long the webhook of --notify-webhook gets to answer
+    pub fn notify_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.notify_timeout)
+    }
+
+    /// Reject option values that can never make sense
+    pub fn validate(&self) -> Result<()> {
+        self.filter_config()?.validate()?;
+        report::check_schema_version(self.report_schema_version)?;
+        if self.metric_cache.is_some()
+            && self.complexity_method != complexity::ComplexityMethod::KmerUniqueness
+        {
+            anyhow::bail!("--metric-cache holds kmer-uniqueness complexities; it cannot be used with another --complexity-method");
+        }
+        if self.max_rss == Some(0) {
+            anyhow::bail!("--max-rss must be at least 1 MiB");
+        }
+        if !(self.notify_timeout > 0.0 && self.notify_timeout.is_finite()) {
+            anyhow::bail!("--notify-timeout must be a positive number of seconds");
+        }
+        if self.keep_alignment_orientation
+            && self.fastq_out.is_none()
+            && self.failed_fastq.is_none()
+        {
+            anyhow::bail!(
+                "--keep-alignment-orientation needs a FASTQ output (--fastq-out or --failed-fastq)"
+            );
+        }
+        if self.fastq_out.is_some() && self.fastq_out == self.failed_fastq {
+            anyhow::bail!("--fastq-out and --failed-fastq need different prefixes");
+        }
+        let output_path = self.output.as_deref().unwrap_or_default();
+        if self.output.is_none() {
+            for (given, option) in [
+                (self.sort_output.is_some(), "--sort-output"),
+                (self.shards > 1, "--shards"),
+                (self.verify_output, "--verify-output"),
+                (self.provenance, "--provenance"),
+            ] {
+                if given {
+                    anyhow::bail!("{} needs an output BAM (-o)", option);
+                }
+            }
+        }
+        if self.sort_output.is_some() && output::is_template(output_path) {
+            anyhow::bail!("--sort-output needs a single output path, not a template");
+        }
+        if self.provenance && output::is_template(output_path) {
+            anyhow::bail!(
+                "--provenance needs a single output path to write its record next to, not a template"
+            );
+        }
+        let mut passes = Vec::new();
+        if self.min_bx_reads > 0 {
+            passes.push("--min-bx-reads");
+        }
+        if self.min_family_size > 0 {
+            passes.push("--min-family-size");
+        }
+        if self.max_region_depth.is_some() {
+            passes.push("--max-region-depth");
+        }
+        if self.max_global_kmer_percentile.is_some() && self.kmer_counts.is_none() {
+            passes.push("--max-global-kmer-percentile");
+        }
+        if self
+            .max_global_kmer_percentile
+            .is_some_and(|percentile| !(0.0..=100.0).contains(&percentile))
+        {
+            anyhow::bail!("--max-global-kmer-percentile must be between 0 and 100");
+        }
+        if self.on_read_error == read_errors::OnReadError::Skip && !self.single_end && !self.resync
+        {
+            anyhow::bail!(
+                "--on-read-error skip loses the mates of skipped records; add --resync to pair around them"
+            );
+        }
+        if self.kmer_size > 32 {
+            for (given, option) in [
+                (
+                    self.max_global_kmer_percentile.is_some(),
+                    "--max-global-kmer-percentile",
+                ),
+                (self.kmer_blacklist.is_some(), "--kmer-blacklist"),
+            ] {
+                if given {
+                    anyhow::bail!("{} needs --kmer-size of at most 32", option);
+                }
+            }
+        }
+        if !(0.0..=1.0).contains(&self.max_high_frequency_kmers) {
+            anyhow::bail!("--max-high-frequency-kmers must be between 0 and 1");
+        }
+        if let (Some(_), Some(option)) = (self.fastq_input(), passes.first()) {
+            anyhow::bail!(
+                "{} reads the input twice and cannot be used with --input-r1/--input-r2",
+                option
+            );
+        }
+        input::check_rereadable(&self.input, &passes)?;
+        if self.preview_pairs == Some(0) {
+            anyhow::bail!("--preview-pairs must be at least 1");
+        }
+        if let Some(seconds) = self.preview_seconds {
+            if !(seconds.is_finite() && seconds > 0.0) {
+                anyhow::bail!("--preview-seconds must be a positive number of seconds");
+            }
+        }
+        if self.shards > 1 && output::splits_by_content(output_path) {
+            anyhow::bail!(
+                "--shards cannot be combined with {{contig}}, {{rg}} or {{dx}} in the output path"
+            );
+        }
+        if let Some(rejected) = &self.rejected_output {
+            if self.output.as_ref() == Some(rejected) || rejected == &self.input {
+                anyhow::bail!("--rejected-output must differ from the input and output paths");
+            }
+            if rejected == output::STDOUT {
+                anyhow::bail!("--rejected-output cannot be standard output; only -o can be -");
+            }
+        }
+        if !(0.0..=1.0).contains(&self.adaptive_sampling_min_clip) {
+            anyhow::bail!("--adaptive-sampling-min-clip must be a fraction between 0 and 1");
+        }
+        if let Some(path) = &self.adaptive_sampling_output {
+            if self.output.as_ref() == Some(path)
+                || self.rejected_output.as_ref() == Some(path)
+                || path == &self.input
+            {
+                anyhow::bail!(
+                    "--adaptive-sampling-output must differ from the input, output and rejected output paths"
+                );
+            }
+            if path == output::STDOUT {
+                anyhow::bail!(
+                    "--adaptive-sampling-output cannot be standard output; only -o can be -"
+                );
+            }
+        }
+        if let Some(path) = &self.decisions {
+            if self.output.as_ref() == Some(path) || path == &self.input || path == output::STDOUT {
+                anyhow::bail!("--decisions must be a file apart from the input and output");
+            }
+            if self.extra_alignments == grouping::ExtraAlignments::Drop {
+                anyhow::bail!(
+                    "--decisions cannot record --extra-alignments drop; apply would keep the extra records of kept pairs"
+                );
+            }
+            if !self.flag_filter().is_open() {
+                anyhow::bail!(
+                    "--decisions cannot record --require-flags/--exclude-flags; apply would keep the records they skip"
+                );
+            }
+        }
+        if output_path == output::STDOUT {
+            for (given, option) in [
+                (self.shards > 1, "--shards"),
+                (self.index_output, "--index-output"),
+                (self.verify_output, "--verify-output"),
+                (self.provenance, "--provenance"),
+            ] {
+                if given {
+                    anyhow::bail!(
+                        "{} needs an output file, not standard output (-o -)",
+                        option
+                    );
+                }
+            }
+        }
+        let encoding = self.encoding();
+        let format = encoding.format_of(output_path);
+        for path in self
+            .output
+            .iter()
+            .chain(&self.rejected_output)
+            .chain(&self.adaptive_sampling_output)
+        {
+            if encoding.format_of(path) == output::OutputFormat::Cram && self.reference.is_none() {
+                anyhow::bail!("CRAM output ({}) needs --reference FASTA", path);
+            }
+        }
+        if self.verify_output && format != output::OutputFormat::Bam {
+            anyhow::bail!("--verify-output checks BAM output only");
+        }
+        if self.index_output && format == output::OutputFormat::Sam {
+            anyhow::bail!("--index-output needs BAM or CRAM output, not SAM");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,538 @@
+//! BAM output: a single writer, round-robin shards written in parallel,
+//! per-contig, per-read-group or duplex/simplex splits, or an external sort
+//!
+//! Output paths may be templates: `{shard}`, `{contig}`, `{rg}` and `{dx}`
+//! are replaced per file, and missing directories are created. Files are BAM,
+//! SAM or CRAM as their extension says, unless an [`Encoding`] names the
+//! format; CRAM needs a reference FASTA.
+
+use crate::duplex::DuplexClass;
+use crate::recycle;
+use crate::sort::ExternalSorter;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use rust_htslib::{bam, bam::record::Aux, tpool::ThreadPool};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::JoinHandle;
+
+/// Pairs buffered per shard before the producer blocks
+const SHARD_QUEUE_PAIRS: usize = 1024;
+
+/// Output path meaning standard output
+pub const STDOUT: &str = "-";
+
+/// Placeholder for the shard number
+const SHARD: &str = "{shard}";
+
+/// Placeholder for the first mate's reference name (`unmapped` if unplaced)
+const CONTIG: &str = "{contig}";
+
+/// Placeholder for the first mate's read group (`none` without an RG tag)
+const READ_GROUP: &str = "{rg}";
+
+/// Placeholder for the first mate's duplex class (`duplex` or `simplex`, by `dx`)
+const DUPLEX: &str = "{dx}";
+
+/// Point the process's own stdout at stderr, so each report line goes
+/// there, and return a path that writes to the original stdout for `-o -`
+pub fn claim_stdout() -> Result<String> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        std::io::stdout().flush()?;
+        // SAFETY: duplicating the standard descriptors; failures are reported by return value
+        let saved = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if saved < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+            bail!(
+                "Cannot redirect standard output: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(format!("/dev/fd/{}", saved))
+    }
+    #[cfg(not(unix))]
+    {
+        bail!("Writing to standard output (-o -) needs a Unix system")
+    }
+}
+
+/// Whether `output` is a template that splits the output
+pub fn is_template(output: &str) -> bool {
+    [SHARD, CONTIG, READ_GROUP, DUPLEX]
+        .iter()
+        .any(|placeholder| output.contains(placeholder))
+}
+
+/// Whether `output` splits by pair content (contig, read group or duplex class)
+pub fn splits_by_content(output: &str) -> bool {
+    output.contains(CONTIG) || output.contains(READ_GROUP) || output.contains(DUPLEX)
+}
+
+/// Open files an open-file limit keeps back from split outputs, for the
+/// input and its index, the reference, the other outputs and reports
+pub const RESERVED_FILES: u64 = 64;
+
+/// Files splitting by `output` creates at most for the references and read
+/// groups `header` lists, counting `unmapped` and `none`; 1 for an output
+/// that doesn't split by content
+pub fn split_files(output: &str, header: &bam::Header) -> u64 {
+    let records = header.to_hashmap();
+    let listed = |tag: &str| records.get(tag).map_or(0, Vec::len) as u64;
+    let mut files = 1;
+    if output.contains(CONTIG) {
+        files *= listed("SQ") + 1;
+    }
+    if output.contains(READ_GROUP) {
+        files *= listed("RG") + 1;
+    }
+    if output.contains(DUPLEX) {
+        files *= 2;
+    }
+    files
+}
+
+/// A value safe to use as a single path component
+fn path_component(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Create the directories leading to a templated output path
+fn create_parent_dir(path: &str) -> Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent)
+            .with_context(|| format!("Cannot create directory {}", parent.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Path of shard `index`: `{shard}` in the output is replaced, otherwise
+/// `out.bam` becomes `out.<index>.bam`
+pub fn shard_path(output: &str, index: usize) -> String {
+    if output.contains(SHARD) {
+        return output.replace(SHARD, &index.to_string());
+    }
+
+    let path = Path::new(output);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => path
+            .with_file_name(format!(
+                "{}.{}.{}",
+                stem.to_string_lossy(),
+                index,
+                ext.to_string_lossy()
+            ))
+            .to_string_lossy()
+            .into_owned(),
+        _ => format!("{}.{}", output, index),
+    }
+}
+
+/// File formats the output can be written in
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Bam,
+    Sam,
+    /// Reference-compressed; needs --reference
+    Cram,
+}
+
+impl OutputFormat {
+    /// The format a path's extension names: `.sam`, `.cram`, otherwise BAM
+    pub fn from_path(path: &str) -> Self {
+        let extension = Path::new(path)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("sam") => OutputFormat::Sam,
+            Some("cram") => OutputFormat::Cram,
+            _ => OutputFormat::Bam,
+        }
+    }
+
+    fn htslib(self) -> bam::Format {
+        match self {
+            OutputFormat::Bam => bam::Format::Bam,
+            OutputFormat::Sam => bam::Format::Sam,
+            OutputFormat::Cram => bam::Format::Cram,
+        }
+    }
+}
+
+/// How every file of an output is written
+#[derive(Debug, Clone, Default)]
+pub struct Encoding {
+    /// `None` takes each file's format from its extension
+    pub format: Option<OutputFormat>,
+    /// Reference FASTA (with a `.fai` index) for CRAM
+    pub reference: Option<String>,
+    /// htslib threads compressing the files, shared with the rest of the
+    /// run (`--threads`)
+    pub thread_pool: Option<ThreadPool>,
+}
+
+/// One pool of `threads` htslib threads for every input and output file of
+/// a run; `None` for 0, which leaves each file to the calling thread
+pub fn thread_pool(threads: usize) -> Result<Option<ThreadPool>> {
+    if threads == 0 {
+        return Ok(None);
+    }
+    let threads = u32::try_from(threads).context("Too many --threads")?;
+    ThreadPool::new(threads)
+        .map(Some)
+        .context("Cannot start the htslib thread pool")
+}
+
+impl Encoding {
+    /// The format `path` is written in
+    pub fn format_of(&self, path: &str) -> OutputFormat {
+        self.format.unwrap_or_else(|| OutputFormat::from_path(path))
+    }
+
+    /// Create a writer for `path`
+    pub fn open(&self, path: &str, header: &bam::Header) -> Result<bam::Writer> {
+        let format = self.format_of(path);
+        if format == OutputFormat::Cram && self.reference.is_none() {
+            bail!("{} is written as CRAM, which needs --reference", path);
+        }
+        let mut writer = bam::Writer::from_path(path, header, format.htslib())
+            .with_context(|| format!("Cannot create {}", path))?;
+        if let (OutputFormat::Cram, Some(reference)) = (format, &self.reference) {
+            writer
+                .set_reference(reference)
+                .with_context(|| format!("Cannot use reference {} for {}", reference, path))?;
+        }
+        if let Some(pool) = &self.thread_pool {
+            writer
+                .set_thread_pool(pool)
+                .with_context(|| format!("Cannot share compression threads with {}", path))?;
+        }
+        Ok(writer)
+    }
+}
+
+/// Writers opened on demand, one per rendered output path
+struct Split {
+    template: String,
+    header: bam::Header,
+    encoding: Encoding,
+    /// Reference names by tid
+    contigs: Vec<String>,
+    writers: HashMap<String, bam::Writer>,
+    /// Writers allowed open at once, by [`BamOutput::limit_files`]
+    max_writers: usize,
+}
+
+impl Split {
+    /// The writer of a record's output path, opened and added to `paths` on first use
+    fn writer_for(
+        &mut self,
+        record: &bam::Record,
+        paths: &mut Vec<String>,
+    ) -> Result<&mut bam::Writer> {
+        let path = self.path_for(record);
+        if !self.writers.contains_key(&path) {
+            if self.writers.len() >= self.max_writers {
+                bail!(
+                    "Splitting the output needs more than {} open files, at {}; raise --max-open-files",
+                    self.max_writers,
+                    path
+                );
+            }
+            create_parent_dir(&path)?;
+            let writer = self.encoding.open(&path, &self.header)?;
+            self.writers.insert(path.clone(), writer);
+            paths.push(path.clone());
+        }
+        Ok(self.writers.get_mut(&path).expect("opened above"))
+    }
+
+    /// Output path for a pair, decided by its first mate
+    fn path_for(&self, record: &bam::Record) -> String {
+        let contig = usize::try_from(record.tid())
+            .ok()
+            .and_then(|tid| self.contigs.get(tid))
+            .map_or("unmapped", String::as_str);
+        let read_group = match record.aux(b"RG") {
+            Ok(Aux::String(rg)) => rg,
+            _ => "none",
+        };
+        self.template
+            .replace(CONTIG, &path_component(contig))
+            .replace(READ_GROUP, &path_component(read_group))
+            .replace(DUPLEX, DuplexClass::of(record).path_component())
+    }
+}
+
+/// A pair and its extra alignments on their way to a shard writer
+type ShardBatch = (bam::Record, bam::Record, Vec<bam::Record>);
+
+/// One shard: a writer thread fed through a bounded queue
+struct Shard {
+    sender: SyncSender<ShardBatch>,
+    handle: JoinHandle<Result<()>>,
+}
+
+enum Writers {
+    Single(bam::Writer),
+    /// Pairs are distributed round-robin; each shard gets whole pairs
+    Sharded {
+        shards: Vec<Shard>,
+        next: usize,
+        /// Batches the writers are done with, to copy the next pairs into
+        written: Receiver<ShardBatch>,
+    },
+    /// Both mates go to the file of the first mate's contig / read group / duplex class
+    Split(Split),
+    Sorted(ExternalSorter),
+    /// After [`BamOutput::close`]
+    Closed,
+}
+
+/// Where kept pairs go
+pub struct BamOutput {
+    writers: Writers,
+    /// Files created so far, in creation order
+    paths: Vec<String>,
+}
+
+impl BamOutput {
+    /// Open `output`, `shards` numbered outputs when `shards > 1`, or split
+    /// outputs when `output` contains `{contig}`, `{rg}` or `{dx}`
+    pub fn create(
+        output: &str,
+        header: &bam::Header,
+        shards: usize,
+        encoding: &Encoding,
+    ) -> Result<Self> {
+        if splits_by_content(output) {
+            if shards > 1 {
+                bail!(
+                    "--shards cannot be combined with {}, {} or {} in the output path",
+                    CONTIG,
+                    READ_GROUP,
+                    DUPLEX
+                );
+            }
+            let contigs = header
+                .to_hashmap()
+                .get("SQ")
+                .map(|sqs| sqs.iter().filter_map(|sq| sq.get("SN").cloned()).collect())
+                .unwrap_or_default();
+            return Ok(BamOutput {
+                writers: Writers::Split(Split {
+                    template: output.to_string(),
+                    header: header.clone(),
+                    encoding: encoding.clone(),
+                    contigs,
+                    writers: HashMap::new(),
+                    max_writers: usize::MAX,
+                }),
+                paths: Vec::new(),
+            });
+        }
+
+        if shards <= 1 && !output.contains(SHARD) {
+            return Ok(BamOutput {
+                writers: Writers::Single(encoding.open(output, header)?),
+                paths: vec![output.to_string()],
+            });
+        }
+
+        let paths: Vec<String> = (0..shards.max(1))
+            .map(|index| shard_path(output, index))
+            .collect();
+        // Enough room for every batch in flight, so returning one never blocks
+        let (written_sender, written) = sync_channel::<ShardBatch>(SHARD_QUEUE_PAIRS * paths.len());
+        let shards = paths
+            .iter()
+            .map(|path| {
+                if is_template(output) {
+                    create_parent_dir(path)?;
+                }
+                let mut writer = encoding.open(path, header)?;
+                let (sender, receiver) = sync_channel::<ShardBatch>(SHARD_QUEUE_PAIRS);
+                let written_sender = written_sender.clone();
+                let handle = std::thread::spawn(move || {
+                    for batch in receiver {
+                        let (record1, record2, extras) = &batch;
+                        writer.write(record1)?;
+                        writer.write(record2)?;
+                        for extra in extras {
+                            writer.write(extra)?;
+                        }
+                        // Dropped instead when the producer has enough spares
+                        let _ = written_sender.try_send(batch);
+                    }
+                    Ok(())
+                });
+                Ok(Shard { sender, handle })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(BamOutput {
+            writers: Writers::Sharded {
+                shards,
+                next: 0,
+                written,
+            },
+            paths,
+        })
+    }
+
+    /// Sort kept records into coordinate order before writing `output`
+    pub fn sorted(
+        output: &str,
+        header: &bam::Header,
+        encoding: &Encoding,
+        sorter_dir: &Path,
+        memory: usize,
+        spill_buffer_kib: Option<usize>,
+    ) -> Self {
+        BamOutput {
+            writers: Writers::Sorted(ExternalSorter::new(
+                output,
+                header,
+                encoding,
+                sorter_dir,
+                memory,
+                spill_buffer_kib,
+            )),
+            paths: vec![output.to_string()],
+        }
+    }
+
+    /// Fail instead of opening more than `files` split outputs at once; no
+    /// limit unless the output splits by content
+    pub fn limit_files(&mut self, files: usize) {
+        if let Writers::Split(split) = &mut self.writers {
+            split.max_writers = files;
+        }
+    }
+
+    pub fn write_pair(&mut self, record1: &bam::Record, record2: &bam::Record) -> Result<()> {
+        self.write_template(record1, record2, &[])
+    }
+
+    /// Write a pair followed by its secondary and supplementary records,
+    /// all to the pair's file
+    pub fn write_template(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        extras: &[bam::Record],
+    ) -> Result<()> {
+        match &mut self.writers {
+            Writers::Single(writer) => {
+                writer.write(record1)?;
+                writer.write(record2)?;
+                for extra in extras {
+                    writer.write(extra)?;
+                }
+            }
+            Writers::Sharded {
+                shards,
+                next,
+                written,
+            } => {
+                // Copies don't share the reader's header handle, so they can
+                // safely move to the writer thread; the records of written
+                // batches only ever come from earlier copies
+                let (mut copy1, mut copy2, mut copies) = written.try_recv().unwrap_or_default();
+                recycle::copy_into(&mut copy1, record1);
+                recycle::copy_into(&mut copy2, record2);
+                recycle::copy_all_into(&mut copies, extras);
+                shards[*next]
+                    .sender
+                    .send((copy1, copy2, copies))
+                    .map_err(|_| anyhow!("Shard {} writer stopped", next))?;
+                *next = (*next + 1) % shards.len();
+            }
+            Writers::Split(split) => {
+                let writer = split.writer_for(record1, &mut self.paths)?;
+                writer.write(record1)?;
+                writer.write(record2)?;
+                for extra in extras {
+                    writer.write(extra)?;
+                }
+            }
+            Writers::Sorted(sorter) => {
+                sorter.push(record1)?;
+                sorter.push(record2)?;
+                for extra in extras {
+                    sorter.push(extra)?;
+                }
+            }
+            Writers::Closed => bail!("Output {} is already closed", self.paths.join(", ")),
+        }
+        Ok(())
+    }
+
+    /// Write one single-end read; sharded outputs only take pairs
+    pub fn write_record(&mut self, record: &bam::Record) -> Result<()> {
+        match &mut self.writers {
+            Writers::Single(writer) => writer.write(record)?,
+            Writers::Split(split) => split.writer_for(record, &mut self.paths)?.write(record)?,
+            Writers::Sorted(sorter) => sorter.push(record)?,
+            Writers::Sharded { .. } => bail!("Sharded outputs cannot take single-end reads"),
+            Writers::Closed => bail!("Output {} is already closed", self.paths.join(", ")),
+        }
+        Ok(())
+    }
+
+    /// Spill the records a sorting output buffers, for `--max-rss`; other
+    /// outputs buffer no more than a BGZF block
+    pub fn relieve_memory(&mut self) -> Result<()> {
+        match &mut self.writers {
+            Writers::Sorted(sorter) => sorter.relieve(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Files created so far; complete once the output is closed
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// Close all outputs, waiting for shard threads and surfacing their errors
+    ///
+    /// A sorting output is merged and written here. Returns every file
+    /// written.
+    pub fn finish(mut self) -> Result<Vec<String>> {
+        self.close()?;
+        Ok(self.paths)
+    }
+
+    /// [`BamOutput::finish`] in place; later writes fail
+    pub fn close(&mut self) -> Result<()> {
+        match std::mem::replace(&mut self.writers, Writers::Closed) {
+            Writers::Single(_) | Writers::Split(_) | Writers::Closed => {}
+            Writers::Sharded { shards, .. } => {
+                for (index, shard) in shards.into_iter().enumerate() {
+                    drop(shard.sender);
+                    shard
+                        .handle
+                        .join()
+                        .map_err(|_| anyhow!("Shard {} writer panicked", index))?
+                        .with_context(|| format!("Shard {} writer failed", index))?;
+                }
+            }
+            Writers::Sorted(sorter) => {
+                let spills = sorter.spill_count();
+                if spills > 0 {
+                    println!("Merging {} sorted spill files...", spills);
+                }
+                sorter.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1966 @@
+//! One filtering run, from the input to its outputs and report
+//!
+//! [`run`] does what the binary does with its options: it reads the input
+//! (BAM, CRAM, SAM or paired FASTQ, whole or by `--regions`), groups its
+//! records into pairs, runs every pair through the filter chain of
+//! [`crate::filter::FilterConfig`] and the filters beside it, writes each
+//! verdict to the sinks the options name (outputs, rejected reads, FASTQ,
+//! decisions, audits) and prints and writes the statistics. `--parallel-contigs`
+//! runs one of these per worker process.
+
+use crate::options::Args;
+use crate::sink::OutputSink;
+use crate::{
+    adaptive, annotate, audit, barcodes, chain, collisions, complexity, contigs, decisions, depth,
+    duplex, duplicates, fastq, fastq_input, filter, flags, global_kmers, grouping, header, hic,
+    histogram, input, kmer_db, lengths, mates, md, memory, metric_cache, molecules, names,
+    nanopore, notify, output, prefetch, primers, progress, provenance, quality, read_errors,
+    regions, rejections, report, resync, samples, signals, sink, sort, stats, summary, targets,
+    timing, tmp, trace, units, validation, verify,
+};
+use anyhow::Result;
+use clap::ValueEnum;
+use rust_htslib::{bam, bam::record::Aux, bam::Read};
+
+/// Write the `--provenance` record of a run that wrote `outputs` and
+/// started at `started`
+pub fn write_provenance(
+    args: &Args,
+    outputs: &[String],
+    started: std::time::SystemTime,
+    interrupted: bool,
+) -> Result<Option<String>> {
+    let Some(output) = args.output.as_deref().filter(|_| args.provenance) else {
+        return Ok(None);
+    };
+    let inputs = match args.fastq_input() {
+        Some((r1, r2)) => vec![r1, r2],
+        None => vec![args.input.as_str()],
+    };
+    let path = provenance::path_for(output);
+    provenance::Provenance {
+        command_line: std::env::args().collect(),
+        parameters: args.parameters.clone(),
+        inputs: inputs
+            .into_iter()
+            .filter_map(|input| provenance::FileDigest::of(input).transpose())
+            .collect::<Result<_>>()?,
+        reference: args
+            .reference
+            .as_deref()
+            .map(provenance::FileDigest::of)
+            .transpose()?
+            .flatten(),
+        outputs: outputs.to_vec(),
+        environment: provenance::Environment::current(),
+        threads: provenance::Threads {
+            htslib: args.threads,
+            shards: args.shards,
+            prefetch: args.prefetch_batches > 0,
+            parallel_contigs: args.parallel_contigs,
+            available: std::thread::available_parallelism().ok().map(usize::from),
+        },
+        started_unix: provenance::unix_seconds(started),
+        finished_unix: provenance::unix_seconds(std::time::SystemTime::now()),
+        interrupted,
+    }
+    .write_json(&path)?;
+    Ok(Some(path))
+}
+
+/// A copy of `args` with the length options lowered to fit the first reads
+/// of the input, and a line per change
+fn adjust_to_read_lengths(args: &Args) -> Result<(Args, Vec<String>)> {
+    if input::is_pipe(&args.input) {
+        anyhow::bail!(
+            "--length-check adjust reads the start of the input before filtering and needs a file, not a pipe"
+        );
+    }
+    let mut reader = args.open_input()?;
+    let mut flag_filter = args.flag_filter();
+    let mut sample = lengths::LengthSample::default();
+    let mut record = bam::Record::new();
+    while !sample.is_complete() {
+        match flag_filter.read_from(|record| reader.read(record), &mut record) {
+            Some(Ok(())) => sample.observe(&record),
+            None => break,
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+
+    let mut adjusted = args.clone();
+    let Some(read_lengths) = sample.lengths() else {
+        return Ok((adjusted, Vec::new()));
+    };
+    let mut config = args.filter_config()?;
+    let mut changes = lengths::adjust(&mut config, read_lengths)?;
+    adjusted.kmer_size = config.kmer_size;
+    adjusted.min_mapped = config.min_mapped;
+    for (read_group, config) in &mut adjusted.read_group_configs {
+        for change in lengths::adjust(config, read_lengths)? {
+            changes.push(format!("[read-group {}] {}", read_group, change));
+        }
+    }
+    Ok((adjusted, changes))
+}
+
+/// The window of the DUST and entropy methods, for the banner
+fn window_description(args: &Args) -> String {
+    match args.complexity_window {
+        0 => "whole reads".to_string(),
+        window => format!("least complex {}-base window", window),
+    }
+}
+
+/// Give a finished pair's buffers back, so the next template is read into them
+fn recycle_pair(
+    resync: Option<&mut resync::Resync>,
+    grouper: &mut grouping::Grouper,
+    record1: bam::Record,
+    record2: bam::Record,
+    extras: &mut Vec<bam::Record>,
+) {
+    match resync {
+        Some(resync) => resync.recycle(record1, record2),
+        None => grouper.recycle(grouping::Template {
+            record1,
+            record2,
+            extras: std::mem::take(extras),
+        }),
+    }
+}
+
+/// Write the tables and statistics files drawn from the report
+pub fn write_report_files(args: &Args, report: &report::Report) -> Result<()> {
+    if let (Some(path), Some(barcode_stats)) = (&args.bx_stats, &report.barcodes) {
+        barcode_stats.write_tsv(path)?;
+    }
+    if let (Some(path), Some(family_stats)) = (&args.family_size_histogram, &report.families) {
+        family_stats.write_tsv(path)?;
+    }
+    if let Some(path) = &args.ont_stats {
+        match &report.nanopore {
+            Some(nanopore_stats) => nanopore_stats.write_tsv(path)?,
+            None => {
+                eprintln!("Warning: --ont-stats: no pair has a ch tag; writing an empty table");
+                nanopore::NanoporeStats::new(args.ont_time_bucket).write_tsv(path)?;
+            }
+        }
+    }
+    if let (Some(path), Some(clip_stats)) = (&args.clip_profile, &report.clips) {
+        clip_stats.write_tsv(path)?;
+    }
+    if let (Some(path), Some(gc_stats)) = (&args.gc_profile, &report.gc) {
+        gc_stats.write_tsv(path)?;
+    }
+    if let (Some(path), Some(sample_stats)) = (&args.sample_stats, &report.samples) {
+        sample_stats.write_tsv(path)?;
+    }
+    if let (Some(path), Some(target_stats)) = (&args.target_stats, &report.targets) {
+        target_stats.write_tsv(path)?;
+    }
+    if let Some(path) = &args.stats_sn {
+        report.sequences.write_sn(
+            path,
+            report.unit(),
+            report.total_pairs,
+            report.kept_pairs,
+            report.interrupted,
+        )?;
+    }
+    if let Some(path) = &args.stats_json {
+        report.write_json(path)?;
+    }
+    Ok(())
+}
+
+/// One value per filter applied outside [`filter::FilterConfig`]: whether
+/// the run applies it, or whether a pair passes it
+#[derive(Debug, Clone, Copy)]
+struct RunFilters<T> {
+    global_kmers: T,
+    kmer_blacklist: T,
+    name_list: T,
+    barcode: T,
+    family: T,
+    region_depth: T,
+    amplicon: T,
+    duplex: T,
+    adaptive: T,
+}
+
+impl<T: Copy> RunFilters<T> {
+    /// The values under the filters' names, in the order reports list them
+    fn named(&self) -> [(&'static str, T); 9] {
+        [
+            ("max_global_kmer_percentile", self.global_kmers),
+            ("kmer_blacklist", self.kmer_blacklist),
+            ("name_list", self.name_list),
+            ("min_bx_reads", self.barcode),
+            ("min_family_size", self.family),
+            ("max_region_depth", self.region_depth),
+            ("require_amplicon", self.amplicon),
+            ("duplex_reads", self.duplex),
+            ("adaptive_sampling", self.adaptive),
+        ]
+    }
+}
+
+impl RunFilters<bool> {
+    /// Every filter off, or every filter passed
+    fn all(value: bool) -> Self {
+        RunFilters {
+            global_kmers: value,
+            kmer_blacklist: value,
+            name_list: value,
+            barcode: value,
+            family: value,
+            region_depth: value,
+            amplicon: value,
+            duplex: value,
+            adaptive: value,
+        }
+    }
+
+    fn pass(&self) -> bool {
+        self.named().iter().all(|&(_, pass)| pass)
+    }
+
+    /// The names and verdicts of the filters `enabled` applies
+    fn verdicts(&self, enabled: &RunFilters<bool>) -> Vec<(&'static str, bool)> {
+        enabled
+            .named()
+            .into_iter()
+            .zip(self.named())
+            .filter_map(|((name, enabled), (_, pass))| enabled.then_some((name, pass)))
+            .collect()
+    }
+}
+
+/// Print the settings of the run before it starts, and the length options
+/// `--length-check adjust` lowered
+fn print_settings(args: &Args, length_changes: &[String]) -> Result<()> {
+    let unit = args.unit();
+    if args.single_end {
+        println!("Filtering single-end BAM by kmer complexity and mapped bases");
+    } else if args.fastq_input().is_some() {
+        println!("Filtering paired FASTQ by kmer complexity");
+    } else {
+        println!("Filtering paired-end BAM by kmer complexity and mapped bases");
+    }
+    match args.fastq_input() {
+        Some((r1, r2)) => {
+            println!("  Input FASTQ: {} and {}", r1, r2);
+            println!("  Mapped-bases and other alignment filters: off (reads not aligned)");
+        }
+        None => println!("  Input BAM: {}", args.input),
+    }
+    match args.output.as_deref() {
+        Some(output::STDOUT) => println!("  Output BAM: standard output"),
+        Some(path) => println!("  Output BAM: {}", path),
+        None if args.dry_run => println!("  Output BAM: none (dry run)"),
+        None => println!("  Output BAM: none"),
+    }
+    if let Some(path) = &args.decisions {
+        println!("  Decision file: {}", path);
+    }
+    match args
+        .output
+        .as_deref()
+        .map(|path| args.encoding().format_of(path))
+    {
+        None | Some(output::OutputFormat::Bam) => {}
+        Some(output::OutputFormat::Sam) => println!("  Output format: SAM"),
+        Some(output::OutputFormat::Cram) => println!(
+            "  Output format: CRAM (reference {})",
+            args.reference.as_deref().unwrap_or("?")
+        ),
+    }
+    for change in length_changes {
+        println!("  Adjusted to the read lengths: {}", change);
+    }
+    if args.shards > 1 {
+        println!("  Output shards: {}", args.shards);
+    }
+    if args.threads > 0 {
+        println!("  htslib threads: {}, shared by every file", args.threads);
+    }
+    if args.prefetch_batches > 0 {
+        println!(
+            "  Prefetch: {} batches of {} records",
+            args.prefetch_batches,
+            prefetch::BATCH_RECORDS
+        );
+    }
+    if args.coordinate_sorted {
+        println!(
+            "  Coordinate-sorted input: mates paired by name ({} MiB buffer)",
+            args.mate_buffer_memory
+        );
+    }
+    if let Some(order) = args.sort_output {
+        println!(
+            "  Output sort order: {:?} ({} MiB buffer)",
+            order, args.sort_memory
+        );
+    }
+    println!("  Complexity cutoff: {:.3}", args.complexity);
+    if let Some(order) = &args.filter_config()?.short_circuit {
+        let enabled = args.filter_config()?.thresholds();
+        let names: Vec<&str> = order
+            .indices()
+            .iter()
+            .chain(&[filter::Thresholds::FILTER_EXPR])
+            .filter(|&&index| enabled.contains(index))
+            .map(|&index| filter::Thresholds::NAMES[index])
+            .collect();
+        println!("  Short-circuit order: {}", names.join(", "));
+    }
+    match args.complexity_method {
+        complexity::ComplexityMethod::KmerUniqueness => {}
+        complexity::ComplexityMethod::Dust => {
+            println!("  Complexity method: DUST, {}", window_description(args))
+        }
+        complexity::ComplexityMethod::ShannonEntropy => println!(
+            "  Complexity method: Shannon entropy of {}-mers, {}",
+            args.entropy_word_size,
+            window_description(args)
+        ),
+    }
+    if args.canonical {
+        println!("  Canonical kmers: a kmer and its reverse complement count as one");
+    }
+    if args.min_mapped > 0 {
+        println!("  Min contiguous mapped bases: {} bp", args.min_mapped);
+    }
+    if args.bisulfite {
+        let mode = if args.bisulfite_strand_aware {
+            "C->T / G->A by strand"
+        } else {
+            "C->T"
+        };
+        println!("  Bisulfite complexity: {} collapsed", mode);
+    }
+    if args.splice_aware {
+        println!("  Splice-aware mapped stretches: N operations join exons");
+    }
+    if let Some(max) = args.max_splice_junctions {
+        println!("  Max splice junctions per read: {}", max);
+    }
+    if let Some(min) = args.min_mapped_fraction {
+        println!(
+            "  Min mapped fraction: {:.3} ({:?} length)",
+            min, args.length_basis
+        );
+    }
+    if let Some(max) = args.max_clip_fraction {
+        println!(
+            "  Max clip fraction: {:.3} ({:?} length)",
+            max, args.length_basis
+        );
+    }
+    if let Some(min) = args.min_gap_compressed_identity {
+        println!("  Min gap-compressed identity: {:.3}", min);
+    }
+    if let Some(max) = args.max_divergence {
+        println!("  Max divergence (de): {:.3}", max);
+    }
+    if args.require_flags != 0 {
+        println!(
+            "  Required flags: {:#x} ({})",
+            args.require_flags,
+            flags::describe(args.require_flags)
+        );
+    }
+    if args.exclude_flags != 0 {
+        println!(
+            "  Excluded flags: {:#x} ({})",
+            args.exclude_flags,
+            flags::describe(args.exclude_flags)
+        );
+    }
+    if args.extra_alignments == grouping::ExtraAlignments::Drop && !args.single_end {
+        println!("  Secondary and supplementary records: dropped");
+    }
+    if let (true, Some(reference)) = (args.regenerate_md, &args.reference) {
+        println!("  Missing NM/MD regenerated from: {}", reference);
+    }
+    if args.min_mapq > 0 {
+        println!("  Min MAPQ (both mates): {}", args.min_mapq);
+    }
+    if let Some(min) = args.min_avg_baseq {
+        println!("  Min mean base quality (both mates): {}", min);
+    }
+    match (args.min_insert, args.max_insert) {
+        (Some(min), Some(max)) => println!("  Insert size (|TLEN|): {}-{} bp", min, max),
+        (Some(min), None) => println!("  Min insert size (|TLEN|): {} bp", min),
+        (None, Some(max)) => println!("  Max insert size (|TLEN|): {} bp", max),
+        (None, None) => {}
+    }
+    if args.require_same_reference {
+        println!("  Both mates on the same reference");
+    }
+    if let Some(max) = args.max_nm {
+        println!("  Max edit distance (NM, both mates): {}", max);
+    }
+    if let Some(max) = args.max_error_rate {
+        println!(
+            "  Max error rate (NM per aligned base, both mates): {}",
+            max
+        );
+    }
+    if let Some(threshold) = args.min_baseq_fraction {
+        println!(
+            "  Min base-quality fraction (both mates): {:.3} of bases >= Q{}",
+            threshold.fraction, threshold.quality
+        );
+    }
+    if args.rescue_by_mate {
+        println!(
+            "  Mate rescue: mate with MAPQ >= {} within {} bp",
+            args.rescue_min_mapq, args.rescue_max_distance
+        );
+    }
+    if let Some(motif) = &args.ligation_motif {
+        println!("  Ligation junction motif: {}", motif);
+    }
+    if let Some(expression) = &args.filter_expr {
+        println!("  Filter expression: {}", expression);
+    }
+    if args.resync {
+        println!(
+            "  Resync on name mismatch: mates looked up within {} records",
+            args.resync_window
+        );
+    }
+    if args.duplex_reads != duplex::DuplexReads::All {
+        println!(
+            "  Duplex reads (dx): keeping {}",
+            args.duplex_reads
+                .to_possible_value()
+                .expect("every class is listed")
+                .get_name()
+        );
+    }
+    if let Some(sampling) = args.adaptive_sampling() {
+        println!(
+            "  Adaptive-sampling rejects: removing reads under {} bp that are unmapped or >= {} clipped",
+            sampling.max_length, sampling.min_clip_fraction
+        );
+        if let Some(path) = &args.adaptive_sampling_output {
+            println!("  Adaptive-sampling rejects BAM: {}", path);
+        }
+    }
+    if let Some(sample) = args.hash_sample {
+        println!(
+            "  Hash sample: buckets {} ({:.1}% of pairs)",
+            sample,
+            sample.fraction() * 100.0
+        );
+    }
+    if let Some(path) = &args.targets {
+        println!("  Target intervals: {}", path);
+    }
+    if let Some(path) = &args.primers {
+        let mode = if args.require_amplicon {
+            "clip, and require pairs to span an amplicon"
+        } else {
+            "clip"
+        };
+        println!("  Primers: {} ({})", path, mode);
+    }
+    if args.check_name_collisions {
+        println!(
+            "  Checking read names for collisions ({} MiB Bloom filter)",
+            args.name_check_memory
+        );
+    }
+    if let Some(path) = &args.include_names {
+        println!("  Include names: {} ({:?})", path, args.name_match);
+    }
+    if let Some(path) = &args.exclude_names {
+        println!("  Exclude names: {} ({:?})", path, args.name_match);
+    }
+    if let Some(max) = args.max_region_depth {
+        println!(
+            "  Max region depth: {} ({} bp bins)",
+            max, args.depth_bin_size
+        );
+    }
+    if let Some(percentile) = args.max_global_kmer_percentile {
+        println!(
+            "  Max high-frequency kmers: {:.2} of a read above the {} percentile ({} MiB of counts)",
+            args.max_high_frequency_kmers, percentile, args.global_kmer_memory
+        );
+    }
+    if let Some(path) = &args.kmer_counts {
+        println!("  Kmer counts: {}", path);
+    }
+    if let Some(path) = &args.kmer_blacklist {
+        println!(
+            "  Kmer blacklist: {} (at most {} hits per read)",
+            path, args.max_blacklist_kmers
+        );
+    }
+    if let Some(count) = args.preview_pairs {
+        println!("  Preview: first {} {} only", count, unit);
+    }
+    if let Some(seconds) = args.preview_seconds {
+        println!("  Preview: stop after {} s", seconds);
+    }
+    if args.min_bx_reads > 0 {
+        println!("  Min reads per BX barcode: {}", args.min_bx_reads);
+    }
+    if args.min_family_size > 0 {
+        println!("  Min pairs per MI family: {}", args.min_family_size);
+    }
+    if args.use_cached_metrics {
+        println!("  Using cached xc/xm metric tags when present");
+    }
+    if args.annotate {
+        println!("  Annotating: every pair is written, tagged xc/xm and xf when it fails");
+    }
+    if let Some(path) = &args.metric_cache {
+        println!("  Metric cache: {}", path);
+    }
+    for (read_group, config) in &args.read_group_configs {
+        println!(
+            "  Read group {}: complexity cutoff {:.3}, min mapped {} bp (config section)",
+            read_group, config.complexity, config.min_mapped
+        );
+    }
+    if let Some(prefix) = &args.fastq_out {
+        println!("  Kept pairs FASTQ: {}_R1/R2.fastq.gz", prefix);
+    }
+    if let Some(prefix) = &args.failed_fastq {
+        println!("  Rejected pairs FASTQ: {}_R1/R2.fastq.gz", prefix);
+    }
+    if let Some(path) = &args.rejected_output {
+        println!("  Rejected {} BAM: {}", unit, path);
+    }
+    match args.short_read_policy {
+        filter::ShortReadPolicy::Fail => println!("  Kmer size: {}", args.kmer_size),
+        policy => println!(
+            "  Kmer size: {} (shorter reads: {})",
+            args.kmer_size,
+            policy
+                .to_possible_value()
+                .expect("every policy is listed")
+                .get_name()
+        ),
+    }
+    Ok(())
+}
+
+/// Filter the input of `args` into its outputs and report on it, returning
+/// the signal that interrupted the run, if any
+pub fn run(args: &Args) -> Result<Option<i32>> {
+    let started = std::time::Instant::now();
+    let started_at = std::time::SystemTime::now();
+
+    // Lower length options the reads can't meet before anything uses them
+    let adjusted;
+    let (args, length_changes) = if args.length_check == lengths::LengthCheck::Adjust {
+        adjusted = adjust_to_read_lengths(args)?;
+        (&adjusted.0, adjusted.1.as_slice())
+    } else {
+        (args, &[][..])
+    };
+
+    // Validate arguments
+    args.validate()?;
+
+    // The report moves to stderr when the BAM goes to stdout
+    let output_path = match args.output.as_deref() {
+        Some(output::STDOUT) => Some(output::claim_stdout()?),
+        path => path.map(str::to_string),
+    };
+
+    let unit = args.unit();
+    print_settings(args, length_changes)?;
+
+    // Scratch space is removed on exit, including when the run fails
+    let work_dir = tmp::WorkDir::create(&tmp::tmp_root(args.tmp_dir.as_deref()))?;
+    let open_file_limit = tmp::raise_open_file_limit(args.max_open_files)?;
+    println!("  Temp directory: {}", work_dir.path().display());
+    println!("  Open-file limit: {}\n", open_file_limit);
+    if let Some(limit) = args.max_rss {
+        println!("Resident memory limit: {} MiB (--max-rss)\n", limit);
+        // Budgets that alone come near the limit are cut back from the start
+        let budgets: Vec<(&str, usize)> = [
+            (
+                args.sort_output.is_some(),
+                "--sort-memory",
+                args.sort_memory,
+            ),
+            (
+                args.coordinate_sorted,
+                "--mate-buffer-memory",
+                args.mate_buffer_memory,
+            ),
+            (
+                args.max_global_kmer_percentile.is_some(),
+                "--global-kmer-memory",
+                args.global_kmer_memory,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(given, option, mib)| given.then_some((option, mib)))
+        .collect();
+        let budgeted: usize = budgets.iter().map(|(_, mib)| mib).sum();
+        if budgeted as f64 >= limit as f64 * memory::SOFT_FRACTION {
+            eprintln!(
+                "Warning: buffers of {} MiB ({}) come near --max-rss {} MiB; they will spill early",
+                budgeted,
+                budgets
+                    .iter()
+                    .map(|(option, mib)| format!("{} {}", option, mib))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                limit
+            );
+        }
+    }
+
+    // Open the input BAM file, or the FASTQ files
+    let input_source = args.open_input()?;
+    let input_header = match &input_source {
+        input::Source::Direct(reader) => reader.header().clone(),
+        _ => bam::HeaderView::from_header(&fastq_input::header()),
+    };
+    if input::is_pipe(&args.input) {
+        match input::raise_pipe_buffer(&args.input, args.input_buffer << 10) {
+            Some(bytes) => println!("Reading from a pipe ({} KiB pipe buffer)\n", bytes >> 10),
+            None => println!("Reading from a pipe\n"),
+        }
+    }
+
+    // Get header
+    let mut header = bam::Header::from_template(&input_header);
+
+    let header_read_groups = samples::read_group_ids(&header);
+    for (read_group, _) in &args.read_group_configs {
+        if !header_read_groups.contains(read_group) {
+            eprintln!(
+                "Warning: config section [read-group {}] names no @RG of the input header",
+                read_group
+            );
+        }
+    }
+
+    // Catch inputs that were already filtered by this tool
+    let previous_runs = header::previous_runs(&header);
+    if !previous_runs.is_empty() {
+        eprintln!("Warning: input was already processed by {}:", header::PROGRAM_NAME);
+        for run in &previous_runs {
+            eprintln!(
+                "  @PG ID:{} VN:{} CL:{}",
+                run.id,
+                run.version.as_deref().unwrap_or("?"),
+                run.command_line.as_deref().unwrap_or("?")
+            );
+        }
+        // A dry run writes nothing that could be filtered twice
+        if !args.force && !args.dry_run {
+            anyhow::bail!("Refusing to filter the same data twice; pass --force to override");
+        }
+    }
+    header::add_program_record(&mut header);
+
+    // Every file of a split output stays open to the end, so they must fit
+    // under the open-file limit along with the rest
+    let split_outputs: Vec<&str> = [
+        output_path.as_deref(),
+        args.rejected_output.as_deref(),
+        args.adaptive_sampling_output.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|path| output::splits_by_content(path))
+    .collect();
+    let split_budget = open_file_limit.saturating_sub(output::RESERVED_FILES);
+    let split_files: u64 = split_outputs
+        .iter()
+        .map(|path| output::split_files(path, &header))
+        .sum();
+    if split_files > split_budget {
+        anyhow::bail!(
+            "Splitting the output can open {} files, more than the {} an open-file limit of {} leaves; raise it with --max-open-files",
+            split_files,
+            split_budget,
+            open_file_limit
+        );
+    }
+    let split_share = (split_budget / split_outputs.len().max(1) as u64) as usize;
+
+    // Open output BAM file, unless only the decisions are wanted
+    let encoding = args.encoding();
+    let mut bam_output = output_path
+        .as_deref()
+        .map(|output_path| match args.sort_output {
+            Some(sort::SortOrder::Coordinate) => Ok(output::BamOutput::sorted(
+                output_path,
+                &header::with_sort_order(&header, "coordinate"),
+                &encoding,
+                work_dir.path(),
+                args.sort_memory << 20,
+                args.spill_buffer,
+            )),
+            None => output::BamOutput::create(output_path, &header, args.shards, &encoding),
+        })
+        .transpose()?;
+    if let Some(output) = bam_output.as_mut() {
+        output.limit_files(split_share);
+    }
+    let mut decisions = args
+        .decisions
+        .as_deref()
+        .map(decisions::DecisionWriter::create)
+        .transpose()?;
+
+    // Optional BAM of rejected pairs, with the same header as the output
+    let mut rejected_output = args
+        .rejected_output
+        .as_deref()
+        .map(|path| {
+            let mut output = output::BamOutput::create(path, &header, 1, &encoding)?;
+            output.limit_files(split_share);
+            anyhow::Ok(output)
+        })
+        .transpose()?;
+
+    // Optional BAM of adaptive-sampling rejects, whatever else they failed
+    let adaptive_sampling = args.adaptive_sampling();
+    let mut adaptive_output = args
+        .adaptive_sampling_output
+        .as_deref()
+        .map(|path| {
+            let mut output = output::BamOutput::create(path, &header, 1, &encoding)?;
+            output.limit_files(split_share);
+            anyhow::Ok(output)
+        })
+        .transpose()?;
+
+    // Optional FASTQ outputs of kept pairs, and of rejected pairs for re-mapping
+    let orientation = if args.keep_alignment_orientation {
+        fastq::Orientation::Aligned
+    } else {
+        fastq::Orientation::Sequenced
+    };
+    let mut fastq_out = args
+        .fastq_out
+        .as_deref()
+        .map(|prefix| fastq::FastqPairWriter::create(prefix, orientation))
+        .transpose()?;
+    let mut failed_fastq = args
+        .failed_fastq
+        .as_deref()
+        .map(|prefix| fastq::FastqPairWriter::create(prefix, orientation))
+        .transpose()?;
+
+    let mut audit = args
+        .audit
+        .as_deref()
+        .map(|path| audit::AuditWriter::create(path, args.audit_format))
+        .transpose()?;
+
+    // Process pairs
+    let mut total_pairs = 0u64;
+    let mut filtered_pairs = 0u64;
+    let mut cached_metrics = 0u64;
+    let mut insert_stats = stats::InsertSizeStats::default();
+    let mut chimera_stats = stats::ChimeraStats::default();
+    let mut sequence_stats = stats::SequenceStats::default();
+    let mut library_complexity = args
+        .library_complexity
+        .then(duplicates::LibraryComplexity::default);
+    let mut duplicate_rate = args
+        .estimate_duplicates
+        .then(|| duplicates::DuplicateRateEstimator::new(args.dup_sample_rate));
+
+    let mut timer = timing::StageTimer::new(args.stage_timing);
+
+    // Molecule sizes are only known after seeing the whole file
+    let barcode_reads = if args.min_bx_reads > 0 {
+        println!("Counting reads per BX barcode...");
+        let counts = barcodes::count_barcode_reads(&args.input, args.reference.as_deref())?;
+        println!("  {} barcodes\n", counts.len());
+        Some(counts)
+    } else {
+        None
+    };
+    let family_pairs = if args.min_family_size > 0 {
+        println!("Counting pairs per MI family...");
+        let counts = molecules::count_family_pairs(&args.input, args.reference.as_deref())?;
+        println!("  {} families\n", counts.len());
+        Some(counts)
+    } else {
+        None
+    };
+    let depth_sketch = match args.max_region_depth {
+        Some(max) => {
+            println!("Measuring coverage in {} bp bins...", args.depth_bin_size);
+            let sketch = depth::DepthSketch::build(
+                &args.input,
+                args.reference.as_deref(),
+                args.depth_bin_size,
+            )?;
+            println!(
+                "  {} bins deeper than {}\n",
+                sketch.deep_bins(max as f64),
+                max
+            );
+            Some(sketch)
+        }
+        None => None,
+    };
+    let mut deep_region_pairs = 0u64;
+    let global_kmers = match args.max_global_kmer_percentile {
+        Some(percentile) => {
+            let memory = args.global_kmer_memory << 20;
+            let model = match &args.kmer_counts {
+                Some(path) => {
+                    println!("Loading kmer counts from {}...", path);
+                    global_kmers::GlobalKmers::load(
+                        path,
+                        args.kmer_size,
+                        percentile,
+                        args.max_high_frequency_kmers,
+                        memory,
+                    )?
+                }
+                None => {
+                    println!("Counting kmers across the input...");
+                    global_kmers::GlobalKmers::build(
+                        &args.input,
+                        args.reference.as_deref(),
+                        args.kmer_size,
+                        percentile,
+                        args.max_high_frequency_kmers,
+                        memory,
+                    )?
+                }
+            };
+            println!(
+                "  {} kmers counted; above {} occurrences is high-frequency ({} distinct kmers sampled)\n",
+                model.kmers,
+                model.threshold(),
+                model.sampled
+            );
+            Some(model)
+        }
+        None => None,
+    };
+    let mut high_frequency_pairs = 0u64;
+    let kmer_blacklist = args
+        .kmer_blacklist
+        .as_deref()
+        .map(|path| {
+            let blacklist =
+                kmer_db::KmerBlacklist::load(path, args.kmer_size, args.max_blacklist_kmers)?;
+            println!("Kmer blacklist: {} distinct kmers\n", blacklist.len());
+            anyhow::Ok(blacklist)
+        })
+        .transpose()?;
+    let mut blacklisted_pairs = 0u64;
+    let name_list = match (&args.include_names, &args.exclude_names) {
+        (Some(path), _) | (None, Some(path)) => {
+            println!("Reading read names from {}...", path);
+            let set = names::NameSet::read(path, args.name_match, args.name_set_memory << 20)?;
+            println!(
+                "  {} names, {:.1} MiB, false-positive rate {:.2e}\n",
+                set.names(),
+                set.memory() as f64 / (1 << 20) as f64,
+                set.false_positive_rate()
+            );
+            Some((set, args.include_names.is_some()))
+        }
+        (None, None) => None,
+    };
+    let mut name_list_removed = 0u64;
+    timer.lap(timing::Stage::Prepass);
+    let mut barcode_stats =
+        (args.bx_stats.is_some() || args.min_bx_reads > 0).then(barcodes::BarcodeStats::default);
+    let mut family_stats = (args.family_size_histogram.is_some() || args.min_family_size > 0)
+        .then(molecules::FamilyStats::default);
+    let mut sample_stats = args
+        .sample_stats
+        .is_some()
+        .then(|| samples::SampleStats::from_header(&header));
+    let mut nanopore_stats = nanopore::NanoporeStats::new(args.ont_time_bucket);
+    let mut duplex_stats = duplex::DuplexStats::default();
+    let mut duplex_removed = 0u64;
+    let duplex_filtered = args.duplex_reads != duplex::DuplexReads::All;
+    let mut adaptive_removed = 0u64;
+    let mut clip_stats = args.clip_profile.is_some().then(stats::ClipStats::default);
+    let mut gc_stats = args.gc_profile.is_some().then(stats::GcStats::default);
+    let mut read_complexity = args
+        .complexity_histogram
+        .is_some()
+        .then(|| histogram::ComplexityHistogram::new(histogram::READ_BINS));
+    let mut rejection_density = args
+        .rejection_bedgraph
+        .is_some()
+        .then(|| rejections::RejectionDensity::new(&input_header, args.rejection_bin_size));
+    let (target_index, mut target_stats) = match &args.targets {
+        Some(path) => {
+            let (index, stats) = targets::Targets::read_bed(path, &input_header)?;
+            (Some(index), Some(stats))
+        }
+        None => (None, None),
+    };
+    let primer_scheme = args
+        .primers
+        .as_deref()
+        .map(|path| primers::PrimerScheme::read_bed(path, &input_header))
+        .transpose()?;
+    if let Some(scheme) = &primer_scheme {
+        println!(
+            "Primer scheme: {} primers in {} amplicons",
+            scheme.primers(),
+            scheme.amplicons()
+        );
+    }
+    let mut primer_stats = primer_scheme
+        .as_ref()
+        .map(|_| primers::PrimerStats::default());
+
+    let mut junction_pairs = 0u64;
+    let mut rescued_pairs = 0u64;
+    let mut short_pairs = 0u64;
+    let mut low_mapq_pairs = 0u64;
+    // Only --stats-out reports these
+    let mut failure_counts = summary::FailureCounts::default();
+    let mut rejection_counts = summary::RejectionCounts::default();
+    let mut complexity_histogram = histogram::ComplexityHistogram::new(summary::PERCENTILE_BINS);
+    let mut tracer = (!args.trace_qname.is_empty()).then(|| trace::Tracer::new(&args.trace_qname));
+    let enabled_filters = RunFilters {
+        global_kmers: global_kmers.is_some(),
+        kmer_blacklist: kmer_blacklist.is_some(),
+        name_list: name_list.is_some(),
+        barcode: args.min_bx_reads > 0,
+        family: args.min_family_size > 0,
+        region_depth: args.max_region_depth.is_some(),
+        amplicon: args.require_amplicon,
+        duplex: duplex_filtered,
+        adaptive: adaptive_sampling.is_some(),
+    };
+    // Barcodes, families, depth and amplicons are judged on pairs only
+    let enabled_read_filters = RunFilters {
+        barcode: false,
+        family: false,
+        region_depth: false,
+        amplicon: false,
+        ..enabled_filters
+    };
+    // Only reported with --short-circuit
+    let mut evaluation_counts = chain::EvaluationCounts::default();
+    let (mut barcode_removed, mut amplicon_removed) = (0u64, 0u64);
+    let mut family_removed = 0u64;
+
+    let filter_config = args.filter_config()?;
+    let read_group_configs: std::collections::HashMap<&str, &filter::FilterConfig> = args
+        .read_group_configs
+        .iter()
+        .map(|(read_group, config)| (read_group.as_str(), config))
+        .collect();
+
+    let mut metric_cache = args
+        .metric_cache
+        .as_deref()
+        .map(metric_cache::MetricCacheReader::open)
+        .transpose()?;
+    if let Some(cache) = &metric_cache {
+        cache.check_options(&filter_config)?;
+    }
+    let interrupt = signals::Interrupt::install()?;
+    let preview_deadline = args
+        .preview_seconds
+        .map(|seconds| std::time::Instant::now() + std::time::Duration::from_secs_f64(seconds));
+    let mut preview_stopped = false;
+    let mut quality_sample =
+        (args.quality_check != quality::QualityCheck::Off).then(quality::QualitySample::default);
+    let mut length_sample =
+        (args.length_check == lengths::LengthCheck::Warn).then(lengths::LengthSample::default);
+    let mut resync = args.resync.then(|| resync::Resync::new(args.resync_window));
+    let mut read_errors = read_errors::ReadErrors::new(&args.input, args.on_read_error);
+    let mut validator = validation::Validator::new(args.validation, &input_header);
+    let mut flag_filter = args.flag_filter();
+    let mut md_regenerator = match (args.regenerate_md, &args.reference) {
+        (true, Some(reference)) => Some(md::MdRegenerator::new(reference, &input_header)?),
+        _ => None,
+    };
+    let mut grouper = grouping::Grouper::new();
+    let mut extras = Vec::new();
+    // Secondary and supplementary records written with kept pairs, for --verify-output
+    let mut extra_records = 0u64;
+    let mut name_collisions = args
+        .check_name_collisions
+        .then(|| collisions::NameCollisions::new(args.name_check_memory << 20));
+
+    // Kept pairs go to the BAM output, rejected ones to the rejected BAM and FASTQ files
+    let mut sinks = sink::Tee::default();
+    if let Some(bam_output) = bam_output.as_mut() {
+        sinks.push(sink::Only::kept(bam_output));
+    }
+    if let Some(decisions) = decisions.as_mut() {
+        sinks.push(decisions);
+    }
+    if let Some(rejected_output) = rejected_output.as_mut() {
+        sinks.push(sink::Only::rejected(rejected_output));
+    }
+    if let Some(fastq_out) = fastq_out.as_mut() {
+        sinks.push(sink::Only::kept(fastq_out));
+    }
+    if let Some(failed_fastq) = failed_fastq.as_mut() {
+        sinks.push(sink::Only::rejected(failed_fastq));
+    }
+    if let (Some(sampling), Some(adaptive_output)) = (adaptive_sampling, adaptive_output.as_mut()) {
+        sinks.push(adaptive::Rejects::new(sampling, adaptive_output));
+    }
+
+    // Coordinate-sorted inputs are read through their index, others skip
+    // the pairs outside the regions as they stream by
+    let regions = args.regions(&input_header)?;
+    let coordinate_sorted_input = regions::is_coordinate_sorted(&input_header);
+    // A --parallel-contigs worker leaves out the pairs another worker owns
+    let contig_owner = args
+        .contig_worker
+        .as_ref()
+        .zip(regions.as_ref())
+        .map(|(_, regions)| contigs::Owned::new(regions, &input_header));
+    let (mut input_reader, region_filter) = match &regions {
+        Some(regions) if coordinate_sorted_input => {
+            if args.prefetch_batches > 0 {
+                anyhow::bail!("--prefetch-batches reads the input front to back and cannot read --regions through the index");
+            }
+            if args.on_read_error == read_errors::OnReadError::Skip {
+                anyhow::bail!("--on-read-error skip cannot skip damage in reads through the index for --regions");
+            }
+            println!(
+                "Regions: {} interval(s), {} bp, read through the index\n",
+                regions.intervals().len(),
+                regions.bases()
+            );
+            let reader = regions::RegionReader::open(
+                &args.input,
+                regions,
+                args.reference.as_deref(),
+                args.thread_pool.as_ref(),
+                !args.single_end,
+            )?;
+            (input::Source::Regions(reader), None)
+        }
+        regions => {
+            if let Some(regions) = regions {
+                println!(
+                    "Regions: {} interval(s), {} bp; other {} are skipped\n",
+                    regions.intervals().len(),
+                    regions.bases(),
+                    unit
+                );
+            }
+            let source = match input_source {
+                input::Source::Direct(reader) => input::Source::new(reader, args.prefetch_batches),
+                source => source,
+            };
+            (source, regions.as_ref())
+        }
+    };
+    let mut outside_regions = 0u64;
+
+    // Reads through the index come paired already
+    let through_index = matches!(input_reader, input::Source::Regions(_));
+    if coordinate_sorted_input && !args.coordinate_sorted && !args.single_end && !through_index {
+        eprintln!("Warning: the input header declares coordinate order; pass --coordinate-sorted to pair its mates without name-sorting first");
+    }
+    let mut mate_pairer = (args.coordinate_sorted && !through_index)
+        .then(|| mates::MatePairer::new(&header, work_dir.path(), args.mate_buffer_memory << 20));
+    let mut rss_guard = args.max_rss.map(memory::RssGuard::new);
+    let mut memory_exceeded = None;
+    // Set when --quality-check fail stops the run; its outputs are removed
+    let mut quality_failure = None;
+    let mut progress = progress::Progress::new(&args.input, unit);
+    let mut record = bam::Record::new();
+    loop {
+        // Stop between pairs so every output stays pair-complete
+        if interrupt.received().is_some() {
+            break;
+        }
+        if let Some(guard) = rss_guard.as_mut() {
+            match guard.check(total_pairs) {
+                memory::Pressure::Fine => {}
+                memory::Pressure::Relieve(resident) => {
+                    if guard.reliefs == 1 {
+                        eprintln!(
+                            "Warning: resident memory {} MiB is near --max-rss {} MiB after {} {}; spilling buffers and halving their budgets",
+                            resident >> 20,
+                            guard.limit() >> 20,
+                            total_pairs,
+                            unit
+                        );
+                    }
+                    if let Some(pairer) = mate_pairer.as_mut() {
+                        pairer.relieve()?;
+                    }
+                    sinks.relieve_memory()?;
+                    memory::release_free_memory();
+                }
+                memory::Pressure::Exceeded(resident) => {
+                    memory_exceeded = Some(resident);
+                    break;
+                }
+            }
+        }
+        if args.preview_pairs.is_some_and(|pairs| total_pairs >= pairs)
+            || preview_deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline)
+        {
+            preview_stopped = true;
+            break;
+        }
+
+        let read_group_config = |record: &bam::Record| match record.aux(b"RG") {
+            Ok(Aux::String(read_group)) if !read_group_configs.is_empty() => read_group_configs
+                .get(read_group)
+                .copied()
+                .unwrap_or(&filter_config),
+            _ => &filter_config,
+        };
+
+        // Reads of a single-end run share the counters, but not the pair-only steps
+        if args.single_end {
+            match flag_filter.read_from(|record| input_reader.read(record), &mut record) {
+                Some(Ok(())) => read_errors.ok(),
+                None => break, // EOF
+                Some(Err(e)) => {
+                    if input_reader.handle_error(&mut read_errors, e.into())? {
+                        continue;
+                    }
+                    break;
+                }
+            }
+            if region_filter.is_some_and(|regions| !regions.overlaps(&record)) {
+                outside_regions += 1;
+                continue;
+            }
+            if contig_owner
+                .as_ref()
+                .is_some_and(|owner| !owner.owns(&record))
+            {
+                continue;
+            }
+            total_pairs += 1;
+            validator.check(&record)?;
+            if let Some(regenerator) = md_regenerator.as_mut() {
+                regenerator.fill(&mut record)?;
+            }
+            timer.lap(timing::Stage::Read);
+
+            if let Some(sample) = quality_sample.as_mut() {
+                sample.observe(&record);
+                if sample.is_complete() {
+                    if let Err(error) = sample.enforce(args.quality_check) {
+                        quality_failure = Some(error);
+                        break;
+                    }
+                    quality_sample = None;
+                }
+            }
+            if let Some(sample) = length_sample.as_mut() {
+                sample.observe(&record);
+                if sample.is_complete() {
+                    lengths::warn(&filter_config, &args.read_group_configs, sample);
+                    length_sample = None;
+                }
+            }
+            if let Some(name_collisions) = name_collisions.as_mut() {
+                name_collisions.check(record.qname());
+            }
+
+            let read_config = read_group_config(&record);
+            read_config.check_edit_distances(&[&record])?;
+            let verdict = read_config.evaluate_read(&record, &mut cached_metrics);
+            if let Some(histogram) = read_complexity.as_mut() {
+                if record.seq_len() >= read_config.min_read_length() {
+                    histogram.record(verdict.complexity[0]);
+                }
+            }
+            short_pairs += verdict.skipped_short as u64;
+            low_mapq_pairs += verdict.low_mapq as u64;
+            failure_counts.record(verdict.failed);
+            evaluation_counts.record(verdict.evaluated, verdict.failed);
+            complexity_histogram.record(verdict.complexity[0]);
+            let pass_global_kmers = global_kmers
+                .as_ref()
+                .is_none_or(|model| model.passes(&record));
+            high_frequency_pairs += !pass_global_kmers as u64;
+            let pass_blacklist = kmer_blacklist
+                .as_ref()
+                .is_none_or(|blacklist| blacklist.passes(&record));
+            blacklisted_pairs += !pass_blacklist as u64;
+            let pass_names = name_list
+                .as_ref()
+                .is_none_or(|(set, include)| set.contains(record.qname()) == *include);
+            name_list_removed += !pass_names as u64;
+            let pass_duplex = args.duplex_reads.keeps(duplex::DuplexClass::of(&record));
+            duplex_removed += !pass_duplex as u64;
+            let pass_adaptive = adaptive_sampling.is_none_or(|s| !s.is_reject(&record));
+            adaptive_removed += !pass_adaptive as u64;
+            let passes = RunFilters {
+                global_kmers: pass_global_kmers,
+                kmer_blacklist: pass_blacklist,
+                name_list: pass_names,
+                duplex: pass_duplex,
+                adaptive: pass_adaptive,
+                ..RunFilters::all(true)
+            };
+            let keep = verdict.keep && passes.pass();
+            let traced = tracer.as_mut().filter(|t| t.wants(record.qname()));
+            if traced.is_some() || args.annotate || !keep {
+                let run_filters = passes.verdicts(&enabled_read_filters);
+                if !keep {
+                    rejection_counts.record(verdict.failed, &run_filters);
+                }
+                if let Some(tracer) = traced {
+                    tracer.trace(read_config, &[&record], &verdict, &run_filters, keep);
+                }
+                if args.annotate {
+                    let longest_mapped = verdict
+                        .longest_mapped
+                        .map_or_else(|| read_config.longest_mapped(&record), |mapped| mapped[0]);
+                    annotate::tag(
+                        &mut record,
+                        verdict.complexity[0],
+                        longest_mapped,
+                        &annotate::failures(verdict.failed, &run_filters),
+                    )?;
+                }
+            }
+            if let Some(sample_stats) = sample_stats.as_mut() {
+                sample_stats.record(&record, keep);
+            }
+            nanopore_stats.record(&record, keep);
+            duplex_stats.record(
+                &record,
+                verdict.failed.contains(filter::Thresholds::COMPLEXITY),
+                keep,
+            );
+            if let Some(gc_stats) = gc_stats.as_mut() {
+                gc_stats.record(&[&record], keep);
+            }
+            sequence_stats.record(&record);
+            timer.lap(timing::Stage::Metrics);
+
+            sinks.write_read(&record, keep || args.annotate)?;
+            filtered_pairs += keep as u64;
+            timer.lap(timing::Stage::Write);
+            progress.update(total_pairs, filtered_pairs, input_reader.bytes_read());
+            continue;
+        }
+
+        let (mut record1, mut record2) = match resync.as_mut() {
+            Some(resync) => {
+                match resync.next_pair_from(|record| {
+                    flag_filter.read_from(|r| input_reader.read(r), record)
+                }) {
+                    Some(Ok(pair)) => {
+                        read_errors.ok();
+                        pair
+                    }
+                    None => break, // EOF
+                    Some(Err(e)) => {
+                        if input_reader.handle_error(&mut read_errors, e)? {
+                            continue;
+                        }
+                        break;
+                    }
+                }
+            }
+            None => match grouper.next_template_from(|record| {
+                let mut read =
+                    |r: &mut bam::Record| flag_filter.read_from(|r| input_reader.read(r), r);
+                match mate_pairer.as_mut() {
+                    Some(pairer) => pairer.read_from(read, record),
+                    None => read(record).map(|result| result.map_err(anyhow::Error::from)),
+                }
+            }) {
+                Some(Ok(template)) => {
+                    read_errors.ok();
+                    extras = template.extras;
+                    (template.record1, template.record2)
+                }
+                None => {
+                    if let Some(record) = grouper.take_unpaired() {
+                        eprintln!("Warning: unpaired read at end of file");
+                        if !record.is_paired() {
+                            eprintln!("  It is not flagged as paired; use --single-end for single-end BAMs");
+                        }
+                    }
+                    break; // EOF
+                }
+                Some(Err(e)) => {
+                    if input_reader.handle_error(&mut read_errors, e)? {
+                        continue;
+                    }
+                    break;
+                }
+            },
+        };
+        if args.extra_alignments == grouping::ExtraAlignments::Drop {
+            extras.clear();
+        }
+        // Pairs outside --regions are not part of the run
+        if region_filter.is_some_and(|regions| !regions.overlaps_pair(&record1, &record2)) {
+            outside_regions += 1;
+            recycle_pair(resync.as_mut(), &mut grouper, record1, record2, &mut extras);
+            continue;
+        }
+        if contig_owner
+            .as_ref()
+            .is_some_and(|owner| !owner.owns_pair(&record1, &record2))
+        {
+            recycle_pair(resync.as_mut(), &mut grouper, record1, record2, &mut extras);
+            continue;
+        }
+
+        total_pairs += 1;
+        validator.check_pair(&record1, &record2)?;
+        for extra in &extras {
+            validator.check(extra)?;
+        }
+        if let Some(regenerator) = md_regenerator.as_mut() {
+            regenerator.fill(&mut record1)?;
+            regenerator.fill(&mut record2)?;
+        }
+        timer.lap(timing::Stage::Read);
+
+        if let Some(sample) = quality_sample.as_mut() {
+            sample.observe(&record1);
+            sample.observe(&record2);
+            if sample.is_complete() {
+                if let Err(error) = sample.enforce(args.quality_check) {
+                    quality_failure = Some(error);
+                    break;
+                }
+                quality_sample = None;
+            }
+        }
+        if let Some(sample) = length_sample.as_mut() {
+            sample.observe(&record1);
+            sample.observe(&record2);
+            if sample.is_complete() {
+                lengths::warn(&filter_config, &args.read_group_configs, sample);
+                length_sample = None;
+            }
+        }
+
+        if let Some(name_collisions) = name_collisions.as_mut() {
+            name_collisions.check(record1.qname());
+        }
+
+        let pair_config = read_group_config(&record1);
+        pair_config.check_edit_distances(&[&record1, &record2])?;
+        let verdict = match metric_cache.as_mut() {
+            Some(cache) => {
+                let metrics = cache.next_pair(&record1)?;
+                pair_config.evaluate_measured(&record1, &record2, &metrics, &mut cached_metrics)
+            }
+            None => pair_config.evaluate(&record1, &record2, &mut cached_metrics),
+        };
+
+        // Barcodeless pairs aren't part of a molecule and are left to the other filters
+        let bx = barcodes::barcode(&record1);
+        let pass_barcode = match (&barcode_reads, bx) {
+            (Some(counts), Some(bx)) => counts.get(bx).copied().unwrap_or(0) >= args.min_bx_reads,
+            _ => true,
+        };
+        // Likewise pairs without a molecular identifier
+        let mi = molecules::molecule(&record1);
+        let pass_family = match (&family_pairs, mi) {
+            (Some(counts), Some(mi)) => {
+                counts.get(mi).copied().unwrap_or(0) >= args.min_family_size
+            }
+            _ => true,
+        };
+
+        let pass_depth = match (&depth_sketch, args.max_region_depth) {
+            (Some(sketch), Some(max)) => {
+                let deep = sketch.max_depth(&record1).max(sketch.max_depth(&record2)) > max as f64;
+                deep_region_pairs += deep as u64;
+                !deep
+            }
+            _ => true,
+        };
+
+        let pass_global_kmers = global_kmers
+            .as_ref()
+            .is_none_or(|model| model.passes(&record1) && model.passes(&record2));
+        high_frequency_pairs += !pass_global_kmers as u64;
+        let pass_blacklist = kmer_blacklist
+            .as_ref()
+            .is_none_or(|blacklist| blacklist.passes(&record1) && blacklist.passes(&record2));
+        blacklisted_pairs += !pass_blacklist as u64;
+
+        let pass_names = name_list
+            .as_ref()
+            .is_none_or(|(set, include)| set.contains(record1.qname()) == *include);
+        name_list_removed += !pass_names as u64;
+
+        // Judged on the alignments as given, before primers are clipped
+        let pass_amplicon = match (&primer_scheme, primer_stats.as_mut()) {
+            (Some(scheme), Some(primer_stats)) => {
+                let spans = scheme.amplicon_of(&record1, &record2).is_some();
+                primer_stats.off_amplicon_pairs += !spans as u64;
+                spans || !args.require_amplicon
+            }
+            _ => true,
+        };
+
+        let pass_duplex = args.duplex_reads.keeps(duplex::DuplexClass::of(&record1));
+        duplex_removed += !pass_duplex as u64;
+        let pass_adaptive = adaptive_sampling.is_none_or(|s| !s.is_reject_pair(&record1, &record2));
+        adaptive_removed += !pass_adaptive as u64;
+
+        let passes = RunFilters {
+            global_kmers: pass_global_kmers,
+            kmer_blacklist: pass_blacklist,
+            name_list: pass_names,
+            barcode: pass_barcode,
+            family: pass_family,
+            region_depth: pass_depth,
+            amplicon: pass_amplicon,
+            duplex: pass_duplex,
+            adaptive: pass_adaptive,
+        };
+        let keep = verdict.keep && passes.pass();
+        // Longest mapped stretches and failures to tag the mates with
+        let mut annotation = None;
+        let traced = tracer.as_mut().filter(|t| t.wants(record1.qname()));
+        // Removed pairs are broken down by the filters they failed
+        if traced.is_some() || args.annotate || !keep {
+            let run_filters = passes.verdicts(&enabled_filters);
+            if !keep {
+                rejection_counts.record(verdict.failed, &run_filters);
+            }
+            if let Some(tracer) = traced {
+                tracer.trace(
+                    pair_config,
+                    &[&record1, &record2],
+                    &verdict,
+                    &run_filters,
+                    keep,
+                );
+            }
+            if args.annotate {
+                let longest_mapped = verdict.longest_mapped.unwrap_or_else(|| {
+                    [&record1, &record2].map(|record| pair_config.longest_mapped(record))
+                });
+                annotation = Some((
+                    longest_mapped,
+                    annotate::failures(verdict.failed, &run_filters),
+                ));
+            }
+        }
+        rescued_pairs += (verdict.rescued && keep) as u64;
+        short_pairs += verdict.skipped_short as u64;
+        low_mapq_pairs += verdict.low_mapq as u64;
+        failure_counts.record(verdict.failed);
+        evaluation_counts.record(verdict.evaluated, verdict.failed);
+        complexity_histogram.record(verdict.complexity[0].min(verdict.complexity[1]));
+        if let Some(histogram) = read_complexity.as_mut() {
+            for (record, complexity) in [&record1, &record2].into_iter().zip(verdict.complexity) {
+                if record.seq_len() >= pair_config.min_read_length() {
+                    histogram.record(complexity);
+                }
+            }
+        }
+        barcode_removed += !pass_barcode as u64;
+        family_removed += !pass_family as u64;
+        amplicon_removed += !pass_amplicon as u64;
+        if let Some(audit) = audit.as_mut() {
+            audit.write_pair(&record1, &verdict, keep)?;
+        }
+        if let Some(barcode_stats) = barcode_stats.as_mut() {
+            barcode_stats.record(bx, keep);
+        }
+        if let Some(family_stats) = family_stats.as_mut() {
+            family_stats.record(mi, !pass_family, keep);
+        }
+        nanopore_stats.record(&record1, keep);
+        duplex_stats.record(
+            &record1,
+            verdict.failed.contains(filter::Thresholds::COMPLEXITY),
+            keep,
+        );
+        if let Some(clip_stats) = clip_stats.as_mut() {
+            clip_stats.record(&record1, &record2, keep);
+        }
+        if let Some(gc_stats) = gc_stats.as_mut() {
+            gc_stats.record(&[&record1, &record2], keep);
+        }
+        if let Some(sample_stats) = sample_stats.as_mut() {
+            sample_stats.record(&record1, keep);
+        }
+        if let (false, Some(rejection_density)) = (keep, rejection_density.as_mut()) {
+            rejection_density.record(&record1, &record2);
+        }
+        if let (Some(index), Some(target_stats)) = (&target_index, target_stats.as_mut()) {
+            target_stats.record(index, &record1, &record2, verdict.complexity, keep);
+        }
+        insert_stats.record(stats::InsertSizeBin::of_pair(&record1, &record2), keep);
+        chimera_stats.record(&record1, &record2, keep);
+        sequence_stats.record(&record1);
+        sequence_stats.record(&record2);
+        if let Some(library_complexity) = library_complexity.as_mut() {
+            library_complexity.record(&record1, &record2);
+        }
+        if let Some(duplicate_rate) = duplicate_rate.as_mut() {
+            duplicate_rate.record(&record1, &record2, keep);
+        }
+
+        if let Some(motifs) = &args.ligation_motif {
+            let mut has_junction = false;
+            for record in [&mut record1, &mut record2] {
+                // Absent unless the input was tagged by an earlier run
+                let _ = record.remove_aux(hic::TAG_JUNCTION);
+                if let Some(offset) = motifs.find_junction(record) {
+                    record.push_aux(hic::TAG_JUNCTION, Aux::I32(offset as i32))?;
+                    has_junction = true;
+                }
+            }
+            junction_pairs += has_junction as u64;
+        }
+
+        if let (Some(scheme), Some(primer_stats)) = (&primer_scheme, primer_stats.as_mut()) {
+            let clipped = [scheme.clip(&mut record1), scheme.clip(&mut record2)];
+            for bases in clipped {
+                primer_stats.record_clip(bases);
+            }
+            if clipped
+                .iter()
+                .any(|bases| bases.is_some_and(|bases| bases > 0))
+            {
+                primers::sync_mates(&mut record1, &mut record2)?;
+            }
+        }
+
+        if let Some((longest_mapped, failures)) = &annotation {
+            for (i, record) in [&mut record1, &mut record2].into_iter().enumerate() {
+                annotate::tag(record, verdict.complexity[i], longest_mapped[i], failures)?;
+            }
+        }
+
+        timer.lap(timing::Stage::Metrics);
+
+        // Annotated runs write the pairs they would remove as well
+        let written = keep || args.annotate;
+        sinks.write_template(&record1, &record2, &extras, written)?;
+        filtered_pairs += keep as u64;
+        extra_records += (written as u64) * extras.len() as u64;
+        timer.lap(timing::Stage::Write);
+        progress.update(total_pairs, filtered_pairs, input_reader.bytes_read());
+
+        recycle_pair(resync.as_mut(), &mut grouper, record1, record2, &mut extras);
+    }
+
+    // A cache with pairs left over was made from a different input
+    if let (Some(cache), None, false) = (metric_cache, interrupt.received(), preview_stopped) {
+        cache.finish()?;
+    }
+
+    // Inputs shorter than the sample are judged on what there was
+    if let (None, Some(sample)) = (&quality_failure, &quality_sample) {
+        quality_failure = sample.enforce(args.quality_check).err();
+    }
+    if let Some(sample) = &length_sample {
+        lengths::warn(&filter_config, &args.read_group_configs, sample);
+    }
+
+    // Flush every output before reporting
+    sinks.finish()?;
+    drop(sinks);
+    let output_paths = bam_output
+        .map(|bam_output| bam_output.finish())
+        .transpose()?
+        .unwrap_or_default();
+    let rejected_paths = rejected_output
+        .map(|rejected_output| rejected_output.finish())
+        .transpose()?;
+    let adaptive_paths = adaptive_output
+        .map(|adaptive_output| adaptive_output.finish())
+        .transpose()?;
+    if let Some(fastq_out) = fastq_out {
+        fastq_out.finish()?;
+    }
+    if let Some(failed_fastq) = failed_fastq {
+        failed_fastq.finish()?;
+    }
+    if let Some(audit) = audit {
+        audit.finish()?;
+    }
+
+    // Outputs of data that failed the quality check are not left behind
+    if let Some(error) = quality_failure {
+        let fastq_paths = [&args.fastq_out, &args.failed_fastq]
+            .into_iter()
+            .flatten()
+            .flat_map(|prefix| ["R1", "R2"].map(|mate| format!("{}_{}.fastq.gz", prefix, mate)));
+        let bam_paths = output_paths
+            .iter()
+            .filter(|_| args.output.as_deref() != Some(output::STDOUT))
+            .chain(rejected_paths.iter().flatten())
+            .chain(adaptive_paths.iter().flatten())
+            .cloned();
+        for path in bam_paths
+            .chain(fastq_paths)
+            .chain(args.decisions.clone())
+            .chain(args.audit.clone())
+        {
+            let _ = std::fs::remove_file(path);
+        }
+        return Err(error);
+    }
+    if args.index_output {
+        sort::index_bam(output_path.as_deref().unwrap_or_default())?;
+    }
+    timer.lap(timing::Stage::Finish);
+
+    // Final report
+    let interrupted = interrupt.received();
+    match interrupted {
+        None if memory_exceeded.is_some() => {
+            println!("\n=== Filtering Stopped at --max-rss ===");
+            println!("Interrupted: true (resident memory limit)");
+        }
+        None if preview_stopped => {
+            println!("\n=== Preview Complete ===");
+            println!("Preview: stopped after the first {} {}", total_pairs, unit);
+        }
+        None => println!("\n=== Filtering Complete ==="),
+        Some(signal) => {
+            eprintln!(
+                "\nInterrupted by signal {}; finalizing partial output",
+                signal
+            );
+            println!("\n=== Filtering Interrupted ===");
+            println!("Interrupted: true (signal {})", signal);
+        }
+    }
+    if let Some(pairer) = &mate_pairer {
+        let counts = pairer.counts;
+        if counts.spilled > 0 {
+            println!(
+                "Mates paired through {} record(s) spilled past --mate-buffer-memory",
+                counts.spilled
+            );
+        }
+        if counts.extras_skipped > 0 {
+            eprintln!(
+                "Note: {} secondary/supplementary record(s) are not paired with --coordinate-sorted and were left out",
+                counts.extras_skipped
+            );
+        }
+        if counts.unmatched > 0 {
+            eprintln!(
+                "Warning: {} record(s) have no mate in the input and were left out",
+                counts.unmatched
+            );
+        }
+    }
+    if let input::Source::Regions(reader) = &input_reader {
+        if reader.extras_skipped > 0 {
+            eprintln!(
+                "Note: {} secondary/supplementary record(s) in the --regions intervals were not read with their pair",
+                reader.extras_skipped
+            );
+        }
+        if reader.mates_missing > 0 {
+            eprintln!(
+                "Warning: {} record(s) in the --regions intervals have no mate at their mate position and were left out",
+                reader.mates_missing
+            );
+        }
+    }
+    if let Some(tracer) = &tracer {
+        for name in tracer.unseen() {
+            let trace_unit = if args.single_end { "read" } else { "pair" };
+            eprintln!(
+                "Warning: --trace-qname {}: no {} of the input has this name",
+                name, trace_unit
+            );
+        }
+    }
+
+    // Read-group sections can enable thresholds the command line doesn't
+    let enabled_thresholds = read_group_configs
+        .values()
+        .fold(filter_config.thresholds(), |enabled, config| {
+            enabled.union(config.thresholds())
+        });
+    let report = report::Report {
+        schema_version: args.report_schema_version,
+        total_pairs,
+        kept_pairs: filtered_pairs,
+        interrupted: interrupted.is_some() || memory_exceeded.is_some(),
+        preview: preview_stopped,
+        single_end: args.single_end,
+        cached_metrics: args.use_cached_metrics.then_some(cached_metrics),
+        junction_pairs: args.ligation_motif.is_some().then_some(junction_pairs),
+        rescued_pairs: args.rescue_by_mate.then_some(rescued_pairs),
+        short_pairs: (args.short_read_policy == filter::ShortReadPolicy::Skip)
+            .then_some(short_pairs),
+        low_mapq_pairs: (args.min_mapq > 0).then_some(low_mapq_pairs),
+        flag_skipped_records: (!flag_filter.is_open()).then(|| flag_filter.skipped()),
+        carried_extra_records: (args.extra_alignments == grouping::ExtraAlignments::Carry
+            && grouper.extras() > 0)
+            .then(|| grouper.extras()),
+        dropped_extra_records: (args.extra_alignments == grouping::ExtraAlignments::Drop
+            && grouper.extras() > 0)
+            .then(|| grouper.extras()),
+        regenerated_md_records: md_regenerator.as_ref().map(|md| md.regenerated()),
+        deep_region_pairs: args.max_region_depth.map(|_| deep_region_pairs),
+        high_frequency_pairs: args
+            .max_global_kmer_percentile
+            .map(|_| high_frequency_pairs),
+        blacklisted_pairs: args.kmer_blacklist.as_ref().map(|_| blacklisted_pairs),
+        name_list_removed: name_list.as_ref().map(|_| name_list_removed),
+        duplex_removed: duplex_filtered.then_some(duplex_removed),
+        adaptive_sampling_removed: adaptive_sampling.map(|_| adaptive_removed),
+        orphan_reads: resync.as_ref().map(resync::Resync::orphans),
+        outside_regions: region_filter.map(|_| outside_regions),
+        read_errors: (args.on_read_error == read_errors::OnReadError::Skip)
+            .then_some(read_errors.counts),
+        validation: (validator.counts.total() > 0).then_some(validator.counts),
+        name_collisions: name_collisions
+            .as_ref()
+            .map(collisions::NameCollisions::collisions),
+        insert_size: insert_stats,
+        chimeras: chimera_stats,
+        sequences: sequence_stats,
+        barcodes: barcode_stats,
+        families: family_stats,
+        samples: sample_stats,
+        clips: clip_stats,
+        gc: gc_stats,
+        nanopore: (!nanopore_stats.is_empty()).then_some(nanopore_stats),
+        duplex: (!duplex_stats.is_empty()).then_some(duplex_stats),
+        primers: primer_stats,
+        targets: target_stats,
+        rejections: Some(rejection_counts),
+        filter_evaluations: filter_config
+            .short_circuit
+            .as_ref()
+            .map(|order| evaluation_counts.rows(order, enabled_thresholds)),
+        stages: timer.stage_times(),
+    };
+    report.print(units::NumberFormat {
+        units: args.report_units,
+        decimals: args.report_decimals,
+    });
+    if total_pairs > 0 {
+        if let Some(library_complexity) = &library_complexity {
+            library_complexity.print();
+        }
+        if let Some(duplicate_rate) = &duplicate_rate {
+            duplicate_rate.print();
+        }
+        if let Some(name_collisions) = &name_collisions {
+            println!(
+                "Name collision false-positive rate: {:.3}%",
+                name_collisions.false_positive_rate() * 100.0
+            );
+        }
+    }
+    if let (Some(path), Some(rejection_density)) = (&args.rejection_bedgraph, &rejection_density) {
+        rejection_density.write_bedgraph(path)?;
+    }
+    if let (Some(path), Some(histogram)) = (&args.complexity_histogram, &read_complexity) {
+        histogram.write_tsv(path)?;
+    }
+    write_report_files(args, &report)?;
+    if let Some(path) = &args.stats_out {
+        let mut failed: std::collections::BTreeMap<_, _> =
+            failure_counts.of(enabled_thresholds).collect();
+        for (filter, count) in [
+            (
+                "min_bx_reads",
+                (args.min_bx_reads > 0).then_some(barcode_removed),
+            ),
+            (
+                "min_family_size",
+                (args.min_family_size > 0).then_some(family_removed),
+            ),
+            ("max_region_depth", report.deep_region_pairs),
+            ("max_global_kmer_percentile", report.high_frequency_pairs),
+            ("kmer_blacklist", report.blacklisted_pairs),
+            ("name_list", report.name_list_removed),
+            (
+                "require_amplicon",
+                args.require_amplicon.then_some(amplicon_removed),
+            ),
+        ] {
+            if let Some(count) = count {
+                failed.insert(filter, count);
+            }
+        }
+        summary::Summary {
+            unit,
+            total: total_pairs,
+            kept: filtered_pairs,
+            removed: total_pairs - filtered_pairs,
+            pass_rate: if total_pairs > 0 {
+                filtered_pairs as f64 / total_pairs as f64
+            } else {
+                0.0
+            },
+            failed,
+            complexity: summary::Summary::complexity_percentiles(&complexity_histogram),
+            runtime_seconds: started.elapsed().as_secs_f64(),
+            interrupted: interrupted.is_some() || memory_exceeded.is_some(),
+            preview: preview_stopped,
+        }
+        .write(path, args.stats_format)?;
+    }
+
+    match output_paths.as_slice() {
+        _ if args.dry_run => println!("\nOutput BAM: none (dry run)"),
+        _ if args.output.is_none() => println!("\nOutput BAM: none"),
+        _ if args.output.as_deref() == Some(output::STDOUT) => {
+            println!("\nOutput: standard output")
+        }
+        [path] => println!("\nOutput file: {}", path),
+        [] => println!("\nOutput files: none (no pairs kept)"),
+        [first, .., last] => println!(
+            "\nOutput files ({}): {} .. {}",
+            output_paths.len(),
+            first,
+            last
+        ),
+    }
+    if let (true, Some(path)) = (args.index_output, &args.output) {
+        let suffix = match encoding.format_of(path) {
+            output::OutputFormat::Cram => "crai",
+            _ => "bai",
+        };
+        println!("Index: {}.{}", path, suffix);
+    }
+    if let Some(paths) = &rejected_paths {
+        println!("Rejected {} BAM: {}", unit, paths.join(", "));
+    }
+    if let Some(paths) = &adaptive_paths {
+        println!("Adaptive-sampling rejects BAM: {}", paths.join(", "));
+    }
+    if let (Some(path), Some(decisions)) = (&args.decisions, &decisions) {
+        println!(
+            "Decision file: {} ({} of {} {} removed)",
+            path,
+            decisions.removed(),
+            decisions.units(),
+            unit
+        );
+    }
+    if let Some(prefix) = &args.fastq_out {
+        println!("Kept pairs: {}_R1.fastq.gz, {}_R2.fastq.gz", prefix, prefix);
+    }
+    if let Some(prefix) = &args.failed_fastq {
+        println!(
+            "Rejected pairs: {}_R1.fastq.gz, {}_R2.fastq.gz",
+            prefix, prefix
+        );
+    }
+    if let Some(path) = &args.stats_sn {
+        println!("SN statistics: {}", path);
+    }
+    if let Some(path) = &args.rejection_bedgraph {
+        println!("Rejection density: {}", path);
+    }
+    if let Some(path) = &args.ont_stats {
+        println!("Nanopore statistics: {}", path);
+    }
+    if let Some(path) = &args.clip_profile {
+        println!("Soft-clip profile: {}", path);
+    }
+    if let Some(path) = &args.gc_profile {
+        println!("GC profile: {}", path);
+    }
+    if let Some(path) = &args.complexity_histogram {
+        println!("Complexity histogram: {}", path);
+    }
+    if let Some(path) = &args.sample_stats {
+        println!("Sample statistics: {}", path);
+    }
+    if let Some(path) = &args.target_stats {
+        println!("Target statistics: {}", path);
+    }
+    if let Some(path) = &args.audit {
+        println!("Audit: {}", path);
+    }
+    if let Some(path) = &args.stats_json {
+        println!("JSON statistics: {}", path);
+    }
+    if let Some(path) = &args.stats_out {
+        println!("Run summary: {}", path);
+    }
+
+    if args.verify_output {
+        verify::verify_outputs(
+            &output_paths,
+            &verify::Expectation {
+                pairs: if args.annotate {
+                    total_pairs
+                } else {
+                    filtered_pairs
+                },
+                extra_records,
+                program_records: previous_runs.len() + 1,
+                mates_adjacent: args.sort_output.is_none(),
+            },
+        )?;
+        println!(
+            "Output verified: {} pairs in {} file(s)",
+            filtered_pairs,
+            output_paths.len()
+        );
+    }
+    let outputs: Vec<String> = output_paths
+        .iter()
+        .chain(rejected_paths.iter().flatten())
+        .chain(adaptive_paths.iter().flatten())
+        .cloned()
+        .collect();
+    let stopped = interrupted.is_some() || memory_exceeded.is_some();
+    if let Some(path) = write_provenance(args, &outputs, started_at, stopped)? {
+        println!("Provenance: {}", path);
+    }
+
+    if let (Some(resident), Some(guard)) = (memory_exceeded, &rss_guard) {
+        eprintln!("\nResident memory when stopped: {} MiB", resident >> 20);
+        if let Some(pairer) = &mate_pairer {
+            eprintln!("  Records waiting for their mate: {}", pairer.waiting());
+        }
+        if guard.reliefs > 0 {
+            eprintln!(
+                "  Buffers relieved {} time(s) before the limit",
+                guard.reliefs
+            );
+        }
+        eprintln!("  Lower --sort-memory, --mate-buffer-memory, --global-kmer-memory, --prefetch-batches or --threads, or raise --max-rss");
+        anyhow::bail!(
+            "Resident memory reached --max-rss {} MiB after {} {}; the outputs hold the {} before it",
+            guard.limit() >> 20,
+            total_pairs,
+            unit,
+            unit
+        );
+    }
+
+    if let Some(url) = &args.notify_webhook {
+        notify::notify(
+            url,
+            &notify::completed(&args.input, &report),
+            args.notify_timeout(),
+        );
+    }
+
+    Ok(interrupted)
+}
@@ -0,0 +1,232 @@
+//! Reading the input ahead on a thread of its own (`--prefetch-batches`)
+//!
+//! Without prefetching the filter loop waits for every read call, which on a
+//! network filesystem means a round trip whenever htslib's read-ahead runs
+//! dry. With `--prefetch-batches N` a reader thread reads and decompresses
+//! batches of [`BATCH_RECORDS`] records while the loop filters and writes
+//! the batch before; up to N full batches wait for the loop, so two is
+//! double buffering. Records cross to the loop without a header attached
+//! (the header's reference count must not be shared between threads), which
+//! the filters never need, and spent batches go back to be read into again.
+//!
+//! A read error ends the thread after the records before it. The error
+//! reaches the loop in order, `--on-read-error` gets the reader back at the
+//! position where it happened, and the next read starts a new thread from
+//! wherever the error handling left the reader.
+
+use crate::progress;
+use rust_htslib::{bam, bam::Read, errors::Error as HtsError, htslib};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::JoinHandle;
+
+/// Records the reader thread reads before handing them on
+pub const BATCH_RECORDS: usize = 4096;
+
+/// Records read ahead, ending early at a read error or the end of the input
+struct Batch {
+    records: Vec<bam::Record>,
+    error: Option<HtsError>,
+    /// Input consumed once the batch was read, for progress lines
+    bytes_read: Option<u64>,
+}
+
+struct ReaderThread {
+    batches: Receiver<Batch>,
+    /// Batches the loop is done with
+    spent: SyncSender<Vec<bam::Record>>,
+    handle: JoinHandle<bam::Reader>,
+}
+
+impl ReaderThread {
+    fn spawn(reader: bam::Reader, queued: usize) -> Self {
+        let (sender, batches) = sync_channel::<Batch>(queued);
+        // Room for every batch in flight, so giving one back never blocks
+        let (spent, spares) = sync_channel::<Vec<bam::Record>>(queued + 2);
+        let handle = std::thread::spawn(move || {
+            loop {
+                let mut records = spares
+                    .try_recv()
+                    .unwrap_or_else(|_| Vec::with_capacity(BATCH_RECORDS));
+                let mut filled = 0;
+                let mut error = None;
+                let mut end = false;
+                while filled < BATCH_RECORDS {
+                    if filled == records.len() {
+                        records.push(bam::Record::new());
+                    }
+                    match read_headerless(&reader, &mut records[filled]) {
+                        Some(Ok(())) => filled += 1,
+                        Some(Err(e)) => {
+                            error = Some(e);
+                            break;
+                        }
+                        None => {
+                            end = true;
+                            break;
+                        }
+                    }
+                }
+                records.truncate(filled);
+                let stop = end || error.is_some();
+                let batch = Batch {
+                    records,
+                    error,
+                    bytes_read: progress::bytes_read(&reader),
+                };
+                // A closed queue means the loop stopped reading
+                if (!batch.records.is_empty() || batch.error.is_some())
+                    && sender.send(batch).is_err()
+                {
+                    break;
+                }
+                if stop {
+                    break;
+                }
+            }
+            reader
+        });
+        ReaderThread {
+            batches,
+            spent,
+            handle,
+        }
+    }
+
+    /// Wait for the thread to give the reader back
+    fn join(self) -> bam::Reader {
+        // Unblocks a thread still waiting to queue a batch
+        drop(self.batches);
+        match self.handle.join() {
+            Ok(reader) => reader,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// [`bam::Reader::read`] without attaching the reader's header
+pub(crate) fn read_headerless(
+    reader: &bam::Reader,
+    record: &mut bam::Record,
+) -> Option<Result<(), HtsError>> {
+    // SAFETY: the handle and header stay open for the reader's lifetime;
+    // sam_read1 only writes the record
+    match unsafe {
+        htslib::sam_read1(
+            reader.htsfile(),
+            reader.header().inner_ptr() as *mut htslib::sam_hdr_t,
+            record.inner_mut(),
+        )
+    } {
+        -1 => None,
+        -2 => Some(Err(HtsError::BamTruncatedRecord)),
+        -4 => Some(Err(HtsError::BamInvalidRecord)),
+        _ => Some(Ok(())),
+    }
+}
+
+/// Reads the input ahead in batches
+pub struct Prefetcher {
+    /// While no thread is reading
+    reader: Option<bam::Reader>,
+    thread: Option<ReaderThread>,
+    queued: usize,
+    current: Vec<bam::Record>,
+    next: usize,
+    /// Ends the current batch
+    error: Option<HtsError>,
+    bytes_read: Option<u64>,
+    end: bool,
+}
+
+impl Prefetcher {
+    /// Read `reader` ahead with up to `queued` batches waiting
+    pub fn new(reader: bam::Reader, queued: usize) -> Self {
+        Prefetcher {
+            reader: Some(reader),
+            thread: None,
+            queued: queued.max(1),
+            current: Vec::new(),
+            next: 0,
+            error: None,
+            bytes_read: None,
+            end: false,
+        }
+    }
+
+    /// Read the next record into `record`, like [`bam::Read::read`]
+    ///
+    /// `record` is swapped with a record of the batch; it must never have
+    /// been given a header, as the batch goes back to the reader thread.
+    pub fn read(&mut self, record: &mut bam::Record) -> Option<Result<(), HtsError>> {
+        loop {
+            if let Some(next) = self.current.get_mut(self.next) {
+                std::mem::swap(record, next);
+                self.next += 1;
+                return Some(Ok(()));
+            }
+            if let Some(error) = self.error.take() {
+                return Some(Err(error));
+            }
+            if self.end {
+                return None;
+            }
+            self.receive();
+        }
+    }
+
+    /// Hand the spent batch back and take the next one
+    fn receive(&mut self) {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => {
+                let reader = self
+                    .reader
+                    .take()
+                    .expect("a stopped thread returns the reader");
+                ReaderThread::spawn(reader, self.queued)
+            }
+        };
+        let _ = thread.spent.try_send(std::mem::take(&mut self.current));
+        self.next = 0;
+        match thread.batches.recv() {
+            Ok(batch) => {
+                self.current = batch.records;
+                self.bytes_read = batch.bytes_read;
+                match batch.error {
+                    // The thread stopped at the error
+                    Some(error) => {
+                        self.error = Some(error);
+                        self.reader = Some(thread.join());
+                    }
+                    None => self.thread = Some(thread),
+                }
+            }
+            Err(_) => {
+                self.end = true;
+                self.reader = Some(thread.join());
+            }
+        }
+    }
+
+    /// The reader, after stopping the thread; records it read ahead are lost
+    ///
+    /// After a read error the thread has stopped at it, so nothing is lost.
+    pub fn reader(&mut self) -> &mut bam::Reader {
+        if let Some(thread) = self.thread.take() {
+            self.reader = Some(thread.join());
+            self.current.clear();
+            self.next = 0;
+        }
+        self.reader
+            .as_mut()
+            .expect("a stopped thread returns the reader")
+    }
+
+    /// Input consumed by the reader thread so far
+    pub fn bytes_read(&self) -> Option<u64> {
+        match &self.reader {
+            Some(reader) if self.thread.is_none() => progress::bytes_read(reader),
+            _ => self.bytes_read,
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! Primer clipping for tiled amplicon schemes (`--primers`)
+//!
+//! The BED file is the ARTIC layout: `chrom start end name pool strand`, with
+//! primer names such as `nCoV-2019_12_LEFT` or `nCoV-2019_12_RIGHT_alt1`.
+//! Everything before `_LEFT`/`_RIGHT` names the amplicon, and alternative
+//! primers widen its primer regions. Aligned bases at either end of a read
+//! that fall inside a primer become soft clips, as `ivar trim` does, so
+//! primer sequence never reaches the variant caller. `--require-amplicon`
+//! also rejects pairs whose fragment doesn't run from the left primer region
+//! of one amplicon to its right primer region.
+
+use crate::bed;
+use crate::units::NumberFormat;
+use anyhow::{bail, Result};
+use rust_htslib::bam;
+use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Bases a read end may lie outside a primer and still count as starting in it
+pub const PRIMER_SLOP: i64 = 5;
+
+/// Reference interval, 0-based and half-open
+type Span = (i64, i64);
+
+fn widen(span: &mut Option<Span>, (start, end): Span) {
+    *span = Some(match *span {
+        Some((s, e)) => (s.min(start), e.max(end)),
+        None => (start, end),
+    });
+}
+
+/// One amplicon: where its left and right primers bind
+#[derive(Debug, Clone)]
+pub struct Amplicon {
+    pub name: String,
+    pub left: Span,
+    pub right: Span,
+}
+
+/// Primers and amplicons of one reference sequence
+#[derive(Debug, Default)]
+struct ContigScheme {
+    /// Sorted by start
+    primers: Vec<Span>,
+    /// Longest primer, bounding how far left an overlap can start
+    max_primer: i64,
+    /// Sorted by left primer start
+    amplicons: Vec<Amplicon>,
+    /// Widest left primer region
+    max_left: i64,
+}
+
+impl ContigScheme {
+    /// Primers overlapping `[start, end)`
+    fn overlapping(&self, start: i64, end: i64) -> impl Iterator<Item = &Span> {
+        let before_end = self
+            .primers
+            .partition_point(|&(primer_start, _)| primer_start < end);
+        self.primers[..before_end]
+            .iter()
+            .rev()
+            .take_while(move |&&(primer_start, _)| primer_start + self.max_primer > start)
+            .filter(move |&&(_, primer_end)| primer_end > start)
+    }
+}
+
+/// Split a primer name into its amplicon and side (true for `LEFT`)
+fn primer_side(name: &str) -> Option<(&str, bool)> {
+    for (marker, left) in [("_LEFT", true), ("_RIGHT", false)] {
+        if let Some(at) = name.find(marker) {
+            let rest = &name[at + marker.len()..];
+            if rest.is_empty() || rest.starts_with('_') {
+                return Some((&name[..at], left));
+            }
+        }
+    }
+    None
+}
+
+/// A primer scheme indexed by reference id
+#[derive(Debug)]
+pub struct PrimerScheme {
+    by_tid: Vec<ContigScheme>,
+    primers: usize,
+}
+
+impl PrimerScheme {
+    /// Read an ARTIC primer BED, resolving chromosome names against the input header
+    pub fn read_bed(path: &str, header: &bam::HeaderView) -> Result<Self> {
+        let mut by_tid: Vec<ContigScheme> = (0..header.target_count())
+            .map(|_| ContigScheme::default())
+            .collect();
+        // (tid, amplicon name) -> (left region, right region)
+        let mut sides: BTreeMap<(u32, String), (Option<Span>, Option<Span>)> = BTreeMap::new();
+        let (mut primers, mut unknown) = (0, 0);
+
+        for line in bed::read(path)? {
+            let (start, end) = (line.start, line.end);
+            let Some(name) = &line.name else {
+                bail!("{} line {}: expected a primer name", path, line.number);
+            };
+            let Some((amplicon, left)) = primer_side(name) else {
+                bail!(
+                    "{} line {}: primer name {} has no _LEFT or _RIGHT",
+                    path,
+                    line.number,
+                    name
+                );
+            };
+            let Some(tid) = header.tid(line.chrom.as_bytes()) else {
+                unknown += 1;
+                continue;
+            };
+
+            let contig = &mut by_tid[tid as usize];
+            contig.primers.push((start, end));
+            contig.max_primer = contig.max_primer.max(end - start);
+            primers += 1;
+            let regions = sides.entry((tid, amplicon.to_string())).or_default();
+            widen(
+                if left { &mut regions.0 } else { &mut regions.1 },
+                (start, end),
+            );
+        }
+
+        if unknown > 0 {
+            eprintln!(
+                "Warning: {} primer(s) in {} are on sequences not in the input header",
+                unknown, path
+            );
+        }
+        if primers == 0 {
+            bail!("{} contains no usable primers", path);
+        }
+        let mut incomplete = 0;
+        for ((tid, name), regions) in sides {
+            let (Some(left), Some(right)) = regions else {
+                incomplete += 1;
+                continue;
+            };
+            let contig = &mut by_tid[tid as usize];
+            contig.max_left = contig.max_left.max(left.1 - left.0);
+            contig.amplicons.push(Amplicon { name, left, right });
+        }
+        if incomplete > 0 {
+            eprintln!(
+                "Warning: {} amplicon(s) in {} lack a left or a right primer",
+                incomplete, path
+            );
+        }
+        for contig in &mut by_tid {
+            contig.primers.sort_unstable();
+            contig.amplicons.sort_by_key(|amplicon| amplicon.left);
+        }
+        Ok(PrimerScheme { by_tid, primers })
+    }
+
+    pub fn primers(&self) -> usize {
+        self.primers
+    }
+
+    pub fn amplicons(&self) -> usize {
+        self.by_tid
+            .iter()
+            .map(|contig| contig.amplicons.len())
+            .sum()
+    }
+
+    fn contig(&self, record: &bam::Record) -> Option<&ContigScheme> {
+        if record.is_unmapped() || record.tid() < 0 {
+            return None;
+        }
+        self.by_tid.get(record.tid() as usize)
+    }
+
+    /// The amplicon whose primers both ends of the pair's fragment lie in
+    ///
+    /// Both mates must be mapped to the same sequence; the fragment runs from
+    /// the leftmost aligned base of either mate to the rightmost.
+    pub fn amplicon_of(&self, record1: &bam::Record, record2: &bam::Record) -> Option<&Amplicon> {
+        let contig = self.contig(record1)?;
+        if record2.is_unmapped() || record2.tid() != record1.tid() {
+            return None;
+        }
+        let start = record1.pos().min(record2.pos());
+        let end = record1.cigar().end_pos().max(record2.cigar().end_pos());
+
+        let candidates = contig
+            .amplicons
+            .partition_point(|amplicon| amplicon.left.0 - PRIMER_SLOP <= start);
+        contig.amplicons[..candidates]
+            .iter()
+            .rev()
+            .take_while(|amplicon| amplicon.left.0 + contig.max_left + PRIMER_SLOP > start)
+            .find(|amplicon| {
+                start < amplicon.left.1 + PRIMER_SLOP
+                    && end > amplicon.right.0 - PRIMER_SLOP
+                    && end <= amplicon.right.1 + PRIMER_SLOP
+            })
+    }
+
+    /// Soft-clip primer bases from both alignment ends of a read
+    ///
+    /// Returns the bases clipped, or `None` when the whole alignment lies in
+    /// primers; such reads are left unchanged.
+    pub fn clip(&self, record: &mut bam::Record) -> Option<u32> {
+        let Some(contig) = self.contig(record) else {
+            return Some(0);
+        };
+        let (start, end) = (record.pos(), record.cigar().end_pos());
+        if end <= start {
+            return Some(0);
+        }
+        let keep_from = contig
+            .overlapping(start - PRIMER_SLOP, start + PRIMER_SLOP + 1)
+            .map(|&(_, primer_end)| primer_end)
+            .fold(start, i64::max);
+        let keep_until = contig
+            .overlapping(end - 1 - PRIMER_SLOP, end + PRIMER_SLOP)
+            .map(|&(primer_start, _)| primer_start)
+            .fold(end, i64::min);
+        if keep_from >= keep_until {
+            return None;
+        }
+        if keep_from == start && keep_until == end {
+            return Some(0);
+        }
+        soft_clip_outside(record, keep_from, keep_until)
+    }
+}
+
+/// Bin of a reference interval, as in the SAM specification
+fn reg2bin(start: i64, end: i64) -> u16 {
+    let end = end - 1;
+    for (shift, offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+        if start >> shift == end >> shift {
+            return (offset + (start >> shift)) as u16;
+        }
+    }
+    0
+}
+
+/// Turn bases aligned outside `[start, end)` on the reference into soft clips
+///
+/// Deletions and skips next to the new clips are dropped, and the position
+/// and bin follow the first base still aligned. Returns the bases newly
+/// clipped, or `None` if none would stay aligned, leaving the record as it
+/// was.
+pub fn soft_clip_outside(record: &mut bam::Record, start: i64, end: i64) -> Option<u32> {
+    let cigar = record.cigar();
+    let last = cigar.len().saturating_sub(1);
+    let mut position = record.pos();
+    let (mut leading_hard, mut trailing_hard) = (None, None);
+    let (mut left, mut right, mut clipped) = (0u32, 0u32, 0u32);
+    let mut kept: Vec<Cigar> = Vec::with_capacity(cigar.len());
+    let mut new_pos = None;
+
+    for (index, op) in cigar.iter().enumerate() {
+        match *op {
+            Cigar::HardClip(n) if index == 0 => leading_hard = Some(Cigar::HardClip(n)),
+            Cigar::HardClip(n) if index == last => trailing_hard = Some(Cigar::HardClip(n)),
+            Cigar::SoftClip(n) if new_pos.is_none() => left += n,
+            Cigar::SoftClip(n) => right += n,
+            Cigar::Match(n) | Cigar::Equal(n) | Cigar::Diff(n) => {
+                let before = (start - position).clamp(0, n as i64) as u32;
+                let after = ((position + n as i64 - end).clamp(0, n as i64) as u32).min(n - before);
+                let inside = n - before - after;
+                if inside > 0 {
+                    new_pos.get_or_insert(position + before as i64);
+                    kept.push(match op {
+                        Cigar::Match(_) => Cigar::Match(inside),
+                        Cigar::Equal(_) => Cigar::Equal(inside),
+                        _ => Cigar::Diff(inside),
+                    });
+                }
+                left += before;
+                right += after;
+                clipped += before + after;
+                position += n as i64;
+            }
+            Cigar::Ins(n) if new_pos.is_none() => {
+                left += n;
+                clipped += n;
+            }
+            Cigar::Ins(n) if position >= end => {
+                right += n;
+                clipped += n;
+            }
+            Cigar::Del(n) | Cigar::RefSkip(n) => {
+                if new_pos.is_some() && position < end {
+                    kept.push(*op);
+                }
+                position += n as i64;
+            }
+            Cigar::Pad(_) if new_pos.is_none() || position >= end => {}
+            _ => kept.push(*op),
+        }
+    }
+    // Gaps between the last aligned base and the clip
+    while let Some(op) = kept.last() {
+        match *op {
+            Cigar::Del(_) | Cigar::RefSkip(_) | Cigar::Pad(_) => {}
+            Cigar::Ins(n) => {
+                right += n;
+                clipped += n;
+            }
+            _ => break,
+        }
+        kept.pop();
+    }
+
+    let new_pos = new_pos?;
+    if clipped == 0 {
+        return Some(0);
+    }
+    let mut ops = Vec::with_capacity(kept.len() + 4);
+    ops.extend(leading_hard);
+    if left > 0 {
+        ops.push(Cigar::SoftClip(left));
+    }
+    ops.extend(kept);
+    if right > 0 {
+        ops.push(Cigar::SoftClip(right));
+    }
+    ops.extend(trailing_hard);
+
+    let cigar = CigarString(ops);
+    let new_end = new_pos + cigar.iter().map(reference_len).sum::<i64>();
+    let (qname, seq, qual) = (
+        record.qname().to_vec(),
+        record.seq().as_bytes(),
+        record.qual().to_vec(),
+    );
+    record.set(&qname, Some(&cigar), &seq, &qual);
+    record.set_pos(new_pos);
+    record.set_bin(reg2bin(new_pos, new_end));
+    Some(clipped)
+}
+
+fn reference_len(op: &Cigar) -> i64 {
+    match *op {
+        Cigar::Match(n) | Cigar::Equal(n) | Cigar::Diff(n) | Cigar::Del(n) | Cigar::RefSkip(n) => {
+            n as i64
+        }
+        _ => 0,
+    }
+}
+
+/// Point each mate's MPOS and MC tag at the other's clipped alignment
+pub fn sync_mates(record1: &mut bam::Record, record2: &mut bam::Record) -> Result<()> {
+    point_at_mate(record1, record2)?;
+    point_at_mate(record2, record1)
+}
+
+fn point_at_mate(record: &mut bam::Record, mate: &bam::Record) -> Result<()> {
+    if mate.is_unmapped() || record.mtid() != mate.tid() {
+        return Ok(());
+    }
+    record.set_mpos(mate.pos());
+    if record.aux(b"MC").is_ok() {
+        let cigar = mate.cigar().to_string();
+        record.remove_aux(b"MC")?;
+        record.push_aux(b"MC", Aux::String(&cigar))?;
+    }
+    Ok(())
+}
+
+/// Clipping and amplicon counts of a run
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PrimerStats {
+    pub clipped_reads: u64,
+    pub clipped_bases: u64,
+    /// Reads aligned entirely within primers, left unclipped
+    pub primer_only_reads: u64,
+    /// Pairs not spanning any one amplicon
+    pub off_amplicon_pairs: u64,
+}
+
+impl PrimerStats {
+    pub fn record_clip(&mut self, clipped: Option<u32>) {
+        match clipped {
+            Some(0) => {}
+            Some(bases) => {
+                self.clipped_reads += 1;
+                self.clipped_bases += bases as u64;
+            }
+            None => self.primer_only_reads += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &PrimerStats) {
+        self.clipped_reads += other.clipped_reads;
+        self.clipped_bases += other.clipped_bases;
+        self.primer_only_reads += other.primer_only_reads;
+        self.off_amplicon_pairs += other.off_amplicon_pairs;
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        println!("\n=== Primer Clipping ===");
+        println!(
+            "Reads with primer bases clipped: {}",
+            format.count(self.clipped_reads)
+        );
+        println!("Primer bases clipped: {}", format.count(self.clipped_bases));
+        if self.clipped_reads > 0 {
+            println!(
+                "Mean bases clipped per clipped read: {:.1}",
+                self.clipped_bases as f64 / self.clipped_reads as f64
+            );
+        }
+        println!(
+            "Reads entirely within primers: {}",
+            format.count(self.primer_only_reads)
+        );
+        println!(
+            "Pairs not spanning one amplicon: {}",
+            format.count(self.off_amplicon_pairs)
+        );
+    }
+}
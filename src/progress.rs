@@ -0,0 +1,120 @@
+//! Progress lines while filtering
+//!
+//! Record counts alone say little when the total is unknown, so each line
+//! also gives the compressed input bytes consumed so far. That comes from
+//! the position of htslib's BGZF reader and works alike for files and pipes
+//! of BAM or bgzipped SAM. For a file of known size the line adds the
+//! fraction read and an ETA extrapolated from it.
+
+use rust_htslib::{bam, bam::Read, htslib};
+use std::time::{Duration, Instant};
+
+/// Records between progress lines
+const EVERY_RECORDS: u64 = 100_000;
+
+/// Longest wait between lines, for inputs with few, long records
+const EVERY: Duration = Duration::from_secs(30);
+
+/// Records between clock checks
+const CLOCK_RECORDS: u64 = 1024;
+
+/// Compressed bytes of a BGZF input (BAM, or bgzipped SAM) htslib has
+/// consumed, from a file or a pipe
+///
+/// This is the start of the block being read, as `bgzf_tell` gives it. CRAM
+/// and uncompressed SAM give `None`: htslib's public API has no position for
+/// their streams.
+pub fn bytes_read(reader: &bam::Reader) -> Option<u64> {
+    // SAFETY: the handle stays open for the reader's lifetime, and
+    // hts_get_bgzfp returns its BGZF handle or null; BGZF is public in bgzf.h
+    let bgzf = unsafe { htslib::hts_get_bgzfp(reader.htsfile()).as_ref()? };
+    // bgzf_tell without the offset within the block
+    u64::try_from(bgzf.block_address).ok()
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// Prints a line every [`EVERY_RECORDS`] records or [`EVERY`], whichever comes first
+pub struct Progress {
+    unit: &'static str,
+    /// Size of the input, when it is a regular file
+    input_size: Option<u64>,
+    started: Instant,
+    last_line: Instant,
+}
+
+impl Progress {
+    pub fn new(path: &str, unit: &'static str) -> Self {
+        let input_size = std::fs::metadata(path)
+            .ok()
+            .filter(|metadata| metadata.is_file() && metadata.len() > 0)
+            .map(|metadata| metadata.len());
+        let now = Instant::now();
+        Progress {
+            unit,
+            input_size,
+            started: now,
+            last_line: now,
+        }
+    }
+
+    /// Called once per pair (or read) with the running totals
+    ///
+    /// `bytes_read` is the input consumed so far, from [`bytes_read`].
+    pub fn update(&mut self, total: u64, kept: u64, bytes_read: Option<u64>) {
+        let due = total.is_multiple_of(EVERY_RECORDS)
+            || (total.is_multiple_of(CLOCK_RECORDS) && self.last_line.elapsed() >= EVERY);
+        if due && total > 0 {
+            self.print(total, kept, bytes_read);
+            self.last_line = Instant::now();
+        }
+    }
+
+    fn print(&self, total: u64, kept: u64, bytes: Option<u64>) {
+        let pass_rate = kept as f64 / total as f64 * 100.0;
+        let mut line = format!(
+            "Processed {} {}, kept {} ({:.1}%)",
+            total, self.unit, kept, pass_rate
+        );
+        match (bytes, self.input_size) {
+            (Some(bytes), Some(size)) => {
+                let fraction = (bytes as f64 / size as f64).min(1.0);
+                line += &format!(
+                    "; {} of {} read ({:.1}%)",
+                    format_bytes(bytes),
+                    format_bytes(size),
+                    fraction * 100.0
+                );
+                if fraction > 0.0 {
+                    let elapsed = self.started.elapsed().as_secs_f64();
+                    let remaining = elapsed * (1.0 - fraction) / fraction;
+                    line += &format!(
+                        ", ETA {}",
+                        format_duration(Duration::from_secs_f64(remaining))
+                    );
+                }
+            }
+            (Some(bytes), None) => line += &format!("; {} read", format_bytes(bytes)),
+            (None, _) => {}
+        }
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,278 @@
+//! A record of how an output was made, for audits (`--provenance`)
+//!
+//! `OUTPUT.provenance.json` is written next to the output once it is
+//! complete. It holds the command line, every option with its value and
+//! whether it was given or defaulted, the tool and htslib versions, the host,
+//! user and working directory, the environment variables htslib reads, the
+//! threads the run used, its start and end, and digests of the input files:
+//! size, modification time and the SHA-256 of the first and last
+//! [`HASHED_BYTES`] of each. The end digest covers the last BGZF blocks with
+//! data, not only the fixed EOF block, and both ends are quick to check on a
+//! large file: `head -c 1048576 in.bam | sha256sum` gives the first.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes hashed at each end of an input file
+pub const HASHED_BYTES: u64 = 1 << 20;
+
+/// Environment variables kept in the record: those htslib and the temp
+/// directory follow
+const VARIABLES: [&str; 4] = ["HTS_PATH", "REF_PATH", "REF_CACHE", "TMPDIR"];
+
+/// Where the provenance record of `output` goes
+pub fn path_for(output: &str) -> String {
+    format!("{}.provenance.json", output)
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// One option's value as the run saw it
+#[derive(Debug, Clone, Serialize)]
+pub struct Parameter {
+    pub values: Vec<String>,
+    /// `command line`, `environment`, `default` or `not given`
+    pub source: &'static str,
+}
+
+/// Size, age and end hashes of an input file
+#[derive(Debug, Clone, Serialize)]
+pub struct FileDigest {
+    pub path: String,
+    pub size: u64,
+    pub modified_unix: Option<u64>,
+    /// SHA-256 of the first `HASHED_BYTES` (or the whole file), hex
+    pub head_sha256: String,
+    /// SHA-256 of the last `HASHED_BYTES` (or the whole file), hex
+    pub tail_sha256: String,
+}
+
+impl FileDigest {
+    /// The digest of `path`; `None` for standard input, which can't be read twice
+    pub fn of(path: &str) -> Result<Option<Self>> {
+        if path == "-" {
+            return Ok(None);
+        }
+        let context = || format!("Cannot hash {} for --provenance", path);
+        let mut file = std::fs::File::open(path).with_context(context)?;
+        let metadata = file.metadata().with_context(context)?;
+        let size = metadata.len();
+        let mut end = |from: u64| -> Result<String> {
+            let mut bytes = Vec::new();
+            file.seek(SeekFrom::Start(from))?;
+            (&mut file).take(HASHED_BYTES).read_to_end(&mut bytes)?;
+            Ok(to_hex(&sha256(&bytes)))
+        };
+        let head_sha256 = end(0).with_context(context)?;
+        let tail_sha256 = end(size.saturating_sub(HASHED_BYTES)).with_context(context)?;
+        Ok(Some(FileDigest {
+            path: path.to_string(),
+            size,
+            modified_unix: metadata.modified().ok().map(unix_seconds),
+            head_sha256,
+            tail_sha256,
+        }))
+    }
+}
+
+/// Where and with what the run ran
+#[derive(Debug, Clone, Serialize)]
+pub struct Environment {
+    pub version: String,
+    pub htslib_version: String,
+    pub os: String,
+    pub arch: String,
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub uid: Option<u32>,
+    pub working_directory: Option<String>,
+    /// The set ones of the variables htslib and the temp directory follow
+    pub variables: BTreeMap<String, String>,
+}
+
+impl Environment {
+    pub fn current() -> Self {
+        // SAFETY: hts_version returns a static C string
+        let htslib_version =
+            unsafe { std::ffi::CStr::from_ptr(rust_htslib::htslib::hts_version()) }
+                .to_string_lossy()
+                .into_owned();
+        Environment {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            htslib_version,
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            hostname: hostname(),
+            user: std::env::var("USER")
+                .or_else(|_| std::env::var("LOGNAME"))
+                .ok(),
+            uid: uid(),
+            working_directory: std::env::current_dir()
+                .ok()
+                .map(|dir| dir.display().to_string()),
+            variables: VARIABLES
+                .iter()
+                .filter_map(|&name| Some((name.to_string(), std::env::var(name).ok()?)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn uid() -> Option<u32> {
+    // SAFETY: getuid cannot fail
+    Some(unsafe { libc::getuid() })
+}
+
+/// Outside Unix there are no numeric user ids
+#[cfg(not(unix))]
+fn uid() -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    // SAFETY: gethostname writes at most `name.len()` bytes
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return None;
+    }
+    let end = name
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..end]).into_owned())
+}
+
+/// Windows names the machine in the environment instead
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Threads the run used
+#[derive(Debug, Clone, Serialize)]
+pub struct Threads {
+    /// Extra htslib threads the input and output files share (`--threads`)
+    pub htslib: usize,
+    /// Output writer threads (`--shards`)
+    pub shards: usize,
+    /// Whether a thread read the input ahead (`--prefetch-batches`)
+    pub prefetch: bool,
+    /// Worker processes (`--parallel-contigs`); 0 for a single process
+    pub parallel_contigs: usize,
+    /// Threads the machine offers the process
+    pub available: Option<usize>,
+}
+
+/// The provenance record of one run
+#[derive(Debug, Clone, Serialize)]
+pub struct Provenance {
+    /// The arguments as given, before config files were read
+    pub command_line: Vec<String>,
+    /// Every option with the value it had, under its long name
+    pub parameters: BTreeMap<String, Parameter>,
+    pub inputs: Vec<FileDigest>,
+    pub reference: Option<FileDigest>,
+    pub outputs: Vec<String>,
+    pub environment: Environment,
+    pub threads: Threads,
+    pub started_unix: u64,
+    pub finished_unix: u64,
+    /// The run stopped early, by a signal or at `--max-rss`
+    pub interrupted: bool,
+}
+
+impl Provenance {
+    pub fn write_json(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)
+            .with_context(|| format!("Cannot write {}", path))
+    }
+}
+
+/// Lowercase hex of `bytes`
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// SHA-256 round constants
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 of `data` (FIPS 180-4); audits compare it with `sha256sum`
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    // The message, a 1 bit, zeros to 56 bytes mod 64, and the length in bits
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
@@ -0,0 +1,201 @@
+//! Base-quality encoding checks (`--quality-check`)
+//!
+//! BAM stores raw Phred scores, with 0xFF throughout QUAL when qualities are
+//! absent (`*` in SAM). Converters that assumed the wrong FASTQ offset leave
+//! every score shifted by 31, and broken writers leave values no tool can
+//! print. The first reads of the input are inspected for either.
+//!
+//! The same scores drive the base-quality filters, `--min-avg-baseq` and
+//! `--min-baseq-fraction`, measured per mate as fastp does on FASTQ.
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use rust_htslib::bam;
+use std::fmt;
+use std::str::FromStr;
+
+/// Reads inspected before the encoding is judged
+pub const SAMPLE_READS: u64 = 1000;
+
+/// Highest Phred score printable in Phred+33 FASTQ
+const MAX_PRINTABLE: u8 = 93;
+
+/// Lowest score of Phred+64 data read as Phred+33 (`@`, Phred 0, plus 31)
+const PHRED64_MIN: u8 = 31;
+
+/// Scores this high alongside [`PHRED64_MIN`] don't come from ordinary short reads
+const PHRED64_MAX: u8 = 50;
+
+/// What to do about a suspicious quality encoding
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum QualityCheck {
+    /// Print a warning and carry on
+    #[default]
+    Warn,
+    /// Stop with an error
+    Fail,
+    /// Don't inspect qualities
+    Off,
+}
+
+/// Whether a record carries base qualities (QUAL is not `*`)
+pub fn has_qualities(record: &bam::Record) -> bool {
+    record.seq_len() > 0 && record.qual().first() != Some(&0xff)
+}
+
+/// Mean Phred score of a read's bases; `None` without qualities
+pub fn mean_base_quality(record: &bam::Record) -> Option<f64> {
+    if !has_qualities(record) {
+        return None;
+    }
+    let qual = record.qual();
+    let sum: u64 = qual.iter().map(|&q| q as u64).sum();
+    Some(sum as f64 / qual.len() as f64)
+}
+
+/// Fraction of a read's bases scoring at least `quality`; `None` without qualities
+pub fn fraction_at_least(record: &bam::Record, quality: u8) -> Option<f64> {
+    if !has_qualities(record) {
+        return None;
+    }
+    let qual = record.qual();
+    let passing = qual.iter().filter(|&&q| q >= quality).count();
+    Some(passing as f64 / qual.len() as f64)
+}
+
+/// At least `fraction` of a read's bases score `quality` or higher (`Q:F`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BaseqFraction {
+    pub quality: u8,
+    pub fraction: f64,
+}
+
+impl BaseqFraction {
+    /// Whether the read passes; a read without qualities never does
+    pub fn passes(&self, record: &bam::Record) -> bool {
+        fraction_at_least(record, self.quality).is_some_and(|f| f >= self.fraction)
+    }
+}
+
+impl FromStr for BaseqFraction {
+    type Err = String;
+
+    /// `Q:F`, a Phred score and a fraction between 0 and 1
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("expected Q:F, e.g. 20:0.9, got '{}'", s);
+        let (quality, fraction) = s.split_once(':').ok_or_else(usage)?;
+        let threshold = BaseqFraction {
+            quality: quality.trim().parse().map_err(|_| usage())?,
+            fraction: fraction.trim().parse().map_err(|_| usage())?,
+        };
+        if !(0.0..=1.0).contains(&threshold.fraction) {
+            return Err(format!("the fraction of '{}' must be between 0 and 1", s));
+        }
+        Ok(threshold)
+    }
+}
+
+impl fmt::Display for BaseqFraction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.quality, self.fraction)
+    }
+}
+
+/// A quality encoding that is obviously wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingProblem {
+    /// No inspected read has qualities
+    Missing,
+    /// Every score is at least 31: likely Phred+64 data stored with offset 33
+    Phred64 { min: u8, max: u8 },
+    /// Scores above 93 can't be written as FASTQ
+    OutOfRange { max: u8 },
+}
+
+impl fmt::Display for EncodingProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodingProblem::Missing => {
+                write!(f, "no inspected read has base qualities (QUAL is '*')")
+            }
+            EncodingProblem::Phred64 { min, max } => write!(
+                f,
+                "base qualities range from {} to {}; this looks like Phred+64 data converted as Phred+33",
+                min, max
+            ),
+            EncodingProblem::OutOfRange { max } => write!(
+                f,
+                "base quality {} is above the Phred+33 maximum of {}; QUAL looks corrupt",
+                max, MAX_PRINTABLE
+            ),
+        }
+    }
+}
+
+/// Score range of the first reads of the input
+#[derive(Debug, Default)]
+pub struct QualitySample {
+    reads: u64,
+    with_qualities: u64,
+    min: u8,
+    max: u8,
+}
+
+impl QualitySample {
+    pub fn observe(&mut self, record: &bam::Record) {
+        self.reads += 1;
+        if !has_qualities(record) {
+            return;
+        }
+        let qual = record.qual();
+        let (min, max) = qual
+            .iter()
+            .fold((u8::MAX, 0), |(min, max), &q| (min.min(q), max.max(q)));
+        if self.with_qualities == 0 {
+            (self.min, self.max) = (min, max);
+        } else {
+            self.min = self.min.min(min);
+            self.max = self.max.max(max);
+        }
+        self.with_qualities += 1;
+    }
+
+    /// Whether enough reads have been seen to judge the encoding
+    pub fn is_complete(&self) -> bool {
+        self.reads >= SAMPLE_READS
+    }
+
+    pub fn problem(&self) -> Option<EncodingProblem> {
+        if self.reads == 0 {
+            None
+        } else if self.with_qualities == 0 {
+            Some(EncodingProblem::Missing)
+        } else if self.max > MAX_PRINTABLE {
+            Some(EncodingProblem::OutOfRange { max: self.max })
+        } else if self.min >= PHRED64_MIN && self.max >= PHRED64_MAX {
+            Some(EncodingProblem::Phred64 {
+                min: self.min,
+                max: self.max,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Warn about or fail on a problem, as `check` asks
+    pub fn enforce(&self, check: QualityCheck) -> Result<()> {
+        let Some(problem) = self.problem() else {
+            return Ok(());
+        };
+        match check {
+            QualityCheck::Off => {}
+            QualityCheck::Warn => eprintln!("Warning: {}", problem),
+            QualityCheck::Fail => bail!(
+                "{} (in the first {} reads); use --quality-check warn to filter anyway",
+                problem,
+                self.reads
+            ),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+//! Counting and surviving htslib read errors (`--on-read-error`)
+//!
+//! By default a read error ends the run with the input position where it
+//! happened. With `skip`, errors are counted by kind and reading goes on:
+//!
+//! - An invalid record (`sam_read1` returns -4) was read in full, so the
+//!   next record starts where it ended.
+//! - A truncated record or corrupt BGZF block leaves the stream mid-record.
+//!   For a BAM file on disk the reader seeks to the next intact BGZF block
+//!   and then to the first offset in it where two consecutive plausible
+//!   records start; the records in between are lost.
+//!
+//! Records lost this way separate mates, so paired runs need `--resync` to
+//! pair around the gap.
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use rust_htslib::{bam, bam::Read, errors::Error as HtsError, htslib};
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::io::{Read as _, Seek, SeekFrom};
+
+/// Errors named in warnings before only counting the rest
+const WARN_ERRORS: u64 = 10;
+
+/// Consecutive errors after which the input is taken as unreadable
+const MAX_CONSECUTIVE: u32 = 100;
+
+/// Largest uncompressed BGZF block
+const MAX_BLOCK: usize = 1 << 16;
+
+/// Bytes of a BAM record before its read name
+const RECORD_FIXED: usize = 36;
+
+/// What a failed record read does to the run
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OnReadError {
+    /// Stop with an error naming the position
+    #[default]
+    Fail,
+    /// Count the error, skip past the damage and read on
+    Skip,
+}
+
+/// Read errors so far, by kind
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ErrorCounts {
+    /// Records cut short, including by a corrupt BGZF block
+    pub truncated: u64,
+    /// Records with inconsistent fields
+    pub invalid: u64,
+    pub other: u64,
+    /// BGZF blocks skipped to resume
+    pub skipped_blocks: u64,
+}
+
+impl ErrorCounts {
+    pub fn total(&self) -> u64 {
+        self.truncated + self.invalid + self.other
+    }
+}
+
+/// Handles the errors of one input
+pub struct ReadErrors {
+    policy: OnReadError,
+    path: String,
+    pub counts: ErrorCounts,
+    consecutive: u32,
+}
+
+impl ReadErrors {
+    pub fn new(path: &str, policy: OnReadError) -> Self {
+        ReadErrors {
+            policy,
+            path: path.to_string(),
+            counts: ErrorCounts::default(),
+            consecutive: 0,
+        }
+    }
+
+    /// Note a successful read, which ends a run of consecutive errors
+    pub fn ok(&mut self) {
+        self.consecutive = 0;
+    }
+
+    /// Deal with a failed read: `Ok(true)` to read on, `Ok(false)` when the
+    /// rest of the input is lost, or the error itself under `fail`
+    pub fn handle(&mut self, reader: &mut bam::Reader, error: anyhow::Error) -> Result<bool> {
+        let position = position(reader);
+        if self.policy == OnReadError::Fail {
+            return Err(error.context(format!(
+                "Cannot read {} at {}; --on-read-error skip reads past damaged records",
+                self.path, position
+            )));
+        }
+
+        let hts_error = error.downcast_ref::<HtsError>();
+        let kind = match hts_error {
+            Some(HtsError::BamTruncatedRecord) => {
+                self.counts.truncated += 1;
+                "truncated record"
+            }
+            Some(HtsError::BamInvalidRecord) => {
+                self.counts.invalid += 1;
+                "invalid record"
+            }
+            _ => {
+                self.counts.other += 1;
+                "read error"
+            }
+        };
+        let total = self.counts.total();
+        if total <= WARN_ERRORS {
+            eprintln!(
+                "Warning: {} in {} at {}: {}",
+                kind, self.path, position, error
+            );
+        } else if total == WARN_ERRORS + 1 {
+            eprintln!("Warning: further read errors are only counted");
+        }
+
+        self.consecutive += 1;
+        if self.consecutive > MAX_CONSECUTIVE {
+            bail!(
+                "{} consecutive read errors in {} at {}; the input looks unreadable from here",
+                self.consecutive,
+                self.path,
+                position
+            );
+        }
+
+        // The record was consumed whole, or the format is line-based
+        if matches!(hts_error, Some(HtsError::BamInvalidRecord)) || !is_bam(reader) {
+            return Ok(true);
+        }
+        if crate::input::is_pipe(&self.path) {
+            bail!(
+                "Cannot skip past the damage at {} in a pipe; filter a copy of {} on disk",
+                position,
+                self.path
+            );
+        }
+        match resume_point(&self.path, reader)? {
+            Some((offset, blocks)) => {
+                self.counts.skipped_blocks += blocks;
+                eprintln!(
+                    "  Resuming at {} after skipping {} BGZF block(s)",
+                    format_offset(offset),
+                    blocks
+                );
+                clear_error(reader);
+                reader.seek(offset)?;
+                Ok(true)
+            }
+            None => {
+                eprintln!("  No intact records after {}; stopping there", position);
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn format_offset(offset: i64) -> String {
+    format!("virtual offset {}:{}", offset >> 16, offset & 0xffff)
+}
+
+fn position(reader: &bam::Reader) -> String {
+    if is_bam(reader) {
+        format_offset(reader.tell())
+    } else {
+        "an unknown position".to_string()
+    }
+}
+
+fn is_bam(reader: &bam::Reader) -> bool {
+    // SAFETY: the handle stays open for the reader's lifetime
+    unsafe { (*reader.htsfile()).format.format == htslib::htsExactFormat_bam }
+}
+
+/// Forget the BGZF error state so the stream can be read after seeking
+fn clear_error(reader: &mut bam::Reader) {
+    // SAFETY: a BAM handle's stream is BGZF
+    unsafe {
+        let bgzf = (*reader.htsfile()).fp.bgzf;
+        if !bgzf.is_null() {
+            (*bgzf).set_errcode(0);
+        }
+    }
+}
+
+/// Whether `bytes` start with a BGZF block header
+fn is_block_header(bytes: &[u8]) -> bool {
+    bytes.len() >= 16
+        && bytes[..4] == [0x1f, 0x8b, 0x08, 0x04]
+        && bytes[10..16] == [6, 0, b'B', b'C', 2, 0]
+}
+
+fn le_i32(bytes: &[u8], at: usize) -> Option<i32> {
+    Some(i32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+/// Length of the plausible BAM record at `at`, if one starts there
+fn plausible_record(data: &[u8], at: usize, targets: i32) -> Option<usize> {
+    let block_size = usize::try_from(le_i32(data, at)?).ok()?;
+    let tid = le_i32(data, at + 4)?;
+    let pos = le_i32(data, at + 8)?;
+    let name_len = *data.get(at + 12)? as usize;
+    let cigar_ops = le_u16(data, at + 16)? as usize;
+    let seq_len = usize::try_from(le_i32(data, at + 20)?).ok()?;
+    let mate_tid = le_i32(data, at + 24)?;
+    let mate_pos = le_i32(data, at + 28)?;
+
+    let fixed = RECORD_FIXED - 4 + name_len + 4 * cigar_ops + seq_len.div_ceil(2) + seq_len;
+    let valid_tid = |tid: i32| (-1..targets).contains(&tid);
+    if !(valid_tid(tid) && valid_tid(mate_tid) && pos >= -1 && mate_pos >= -1)
+        || name_len < 2
+        || block_size < fixed
+        || block_size > 1 << 28
+    {
+        return None;
+    }
+    let name = data.get(at + RECORD_FIXED..at + RECORD_FIXED + name_len)?;
+    let (nul, name) = name.split_last()?;
+    if *nul != 0 || !name.iter().all(|&c| c.is_ascii_graphic() && c != b'@') {
+        return None;
+    }
+    Some(4 + block_size)
+}
+
+/// First offset in `data` (below `limit`) where two plausible records start,
+/// or one that runs past the end of `data`
+fn first_record(data: &[u8], limit: usize, targets: i32) -> Option<usize> {
+    (0..limit.min(data.len())).find(|&at| {
+        plausible_record(data, at, targets).is_some_and(|len| {
+            at + len + RECORD_FIXED > data.len()
+                || plausible_record(data, at + len, targets).is_some()
+        })
+    })
+}
+
+/// Uncompressed length of the block at `address` and up to two blocks of
+/// data from its start, or `None` if it doesn't decompress
+fn read_block(path: &CString, address: i64) -> Option<(usize, Vec<u8>)> {
+    // SAFETY: the handle is checked, used and closed here
+    unsafe {
+        let bgzf = htslib::bgzf_open(path.as_ptr(), c"r".as_ptr());
+        if bgzf.is_null() {
+            return None;
+        }
+        let mut result = None;
+        if htslib::bgzf_seek(bgzf, address << 16, libc::SEEK_SET) >= 0
+            && htslib::bgzf_read_block(bgzf) == 0
+        {
+            let block_len = (*bgzf).block_length as usize;
+            let mut data = vec![0u8; 2 * MAX_BLOCK];
+            let read = htslib::bgzf_read(bgzf, data.as_mut_ptr().cast(), data.len());
+            if let Ok(read) = usize::try_from(read) {
+                data.truncate(read);
+                result = Some((block_len, data));
+            }
+        }
+        htslib::bgzf_close(bgzf);
+        result
+    }
+}
+
+/// The virtual offset of the first record after the damaged block the
+/// reader stopped in, with the number of blocks skipped to reach it
+fn resume_point(path: &str, reader: &bam::Reader) -> Result<Option<(i64, u64)>> {
+    let damaged = reader.tell() >> 16;
+    let targets = reader.header().target_count() as i32;
+    let c_path = CString::new(path)?;
+    let mut file = std::fs::File::open(path)?;
+    let mut buffer = vec![0u8; 1 << 20];
+    let mut start = damaged + 1;
+    let mut blocks = 1u64;
+    loop {
+        file.seek(SeekFrom::Start(start as u64))?;
+        let read = file.read(&mut buffer)?;
+        if read < 16 {
+            return Ok(None);
+        }
+        let Some(found) = (0..read - 15).find(|&at| is_block_header(&buffer[at..])) else {
+            // Keep the tail in case a header straddles the buffers
+            start += (read - 15) as i64;
+            continue;
+        };
+        let address = start + found as i64;
+        if let Some((block_len, data)) = read_block(&c_path, address) {
+            if block_len == 0 {
+                // The empty EOF marker block
+                return Ok(None);
+            }
+            if let Some(at) = first_record(&data, block_len, targets) {
+                return Ok(Some(((address << 16) | at as i64, blocks)));
+            }
+        }
+        blocks += 1;
+        start = address + 1;
+    }
+}
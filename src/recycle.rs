@@ -0,0 +1,81 @@
+//! Reusing record buffers in the hot loop
+//!
+//! Every `bam::Record` owns one heap buffer for its name, CIGAR, sequence,
+//! qualities and tags. Creating a record per read and freeing it once the
+//! pair is written costs an allocation, a reallocation as htslib grows the
+//! buffer to the read and a free, for every record. A [`RecordPool`] keeps
+//! the records of finished templates and hands them out again: htslib reads
+//! into the buffer a record already has and only grows it for a longer one,
+//! so after the first templates the loop stops allocating. The
+//! [`Grouper`](crate::grouping::Grouper) and [`Resync`](crate::resync::Resync)
+//! draw from their own pool and take templates back with `recycle`; sharded
+//! output writers send written records back to be copied into with
+//! [`copy_into`]. `cargo bench --bench records` compares fresh and recycled
+//! records.
+
+use rust_htslib::{bam, htslib};
+
+/// Spare records a pool keeps; more are freed rather than held
+pub const MAX_SPARE_RECORDS: usize = 256;
+
+/// Spare records, and vectors for extra alignments, to read into
+#[derive(Default)]
+pub struct RecordPool {
+    records: Vec<bam::Record>,
+    vecs: Vec<Vec<bam::Record>>,
+}
+
+impl RecordPool {
+    /// A spare record, or a new one when none is left
+    pub fn take(&mut self) -> bam::Record {
+        self.records.pop().unwrap_or_default()
+    }
+
+    /// Keep `record` for a later [`take`](Self::take)
+    pub fn give(&mut self, record: bam::Record) {
+        if self.records.len() < MAX_SPARE_RECORDS {
+            self.records.push(record);
+        }
+    }
+
+    /// An empty vector, with the capacity of one given back earlier
+    pub fn take_vec(&mut self) -> Vec<bam::Record> {
+        self.vecs.pop().unwrap_or_default()
+    }
+
+    /// Keep the records of `vec`, and `vec` itself
+    pub fn give_vec(&mut self, mut vec: Vec<bam::Record>) {
+        for record in vec.drain(..) {
+            self.give(record);
+        }
+        if vec.capacity() > 0 && self.vecs.len() < MAX_SPARE_RECORDS {
+            self.vecs.push(vec);
+        }
+    }
+
+    /// Records and vectors held
+    pub fn spare(&self) -> (usize, usize) {
+        (self.records.len(), self.vecs.len())
+    }
+}
+
+/// Make `dst` a copy of `src`, reusing `dst`'s buffer when it is large enough
+///
+/// Unlike [`Clone`], the record keeps whatever header `dst` had, so a record
+/// meant for another thread must only ever be copied into, never read into.
+pub fn copy_into(dst: &mut bam::Record, src: &bam::Record) {
+    // bam_copy1 reallocates dst's data as needed and copies the core fields
+    let copied = unsafe { htslib::bam_copy1(dst.inner_mut(), src.inner()) };
+    assert!(!copied.is_null(), "Out of memory copying a BAM record");
+}
+
+/// Make `dst` a copy of `src` record by record, reusing `dst`'s records
+pub fn copy_all_into(dst: &mut Vec<bam::Record>, src: &[bam::Record]) {
+    dst.truncate(src.len());
+    for (dst, src) in dst.iter_mut().zip(src) {
+        copy_into(dst, src);
+    }
+    for src in &src[dst.len()..] {
+        dst.push(src.clone());
+    }
+}
@@ -0,0 +1,371 @@
+//! Filtering only the pairs in given intervals (`--regions`, `--region`)
+//!
+//! A pair is considered when either mate overlaps an interval (an unmapped
+//! mate placed next to its partner counts as one base at its position);
+//! other pairs are left out of the run altogether: not counted, filtered or
+//! written. The region `*` stands for the records without a position, as in
+//! samtools.
+//!
+//! A name-sorted input is read in full and the pairs outside the intervals
+//! are skipped as they go by, which saves subsetting and name-sorting the
+//! input first. A coordinate-sorted input with an index is read through the
+//! index instead, by a [`RegionReader`]: only the records in the intervals
+//! are read, mates are paired by name as they arrive, and a mate outside
+//! every interval is looked up at its mate position afterwards. Secondary
+//! and supplementary records are not read there, as they cannot be grouped
+//! with their pair without reading the whole input.
+
+use crate::bed;
+use anyhow::{bail, Context, Result};
+use rust_htslib::{bam, bam::Read, errors::Error as HtsError, tpool::ThreadPool};
+use std::collections::{HashMap, VecDeque};
+
+/// Merged intervals per reference sequence
+#[derive(Debug, Default)]
+pub struct Regions {
+    /// 0-based, half-open, sorted and non-overlapping; indexed by tid
+    by_tid: Vec<Vec<(i64, i64)>>,
+    /// Whether the records without a position (`*`) are included
+    pub unplaced: bool,
+}
+
+impl Regions {
+    /// Intervals of a BED file and of `chr`, `chr:start-end` region strings
+    /// (1-based, inclusive, as samtools takes them), resolved against the
+    /// input header
+    pub fn read(bed: Option<&str>, specs: &[String], header: &bam::HeaderView) -> Result<Regions> {
+        let mut intervals = Vec::new();
+        if let Some(path) = bed {
+            read_bed(path, header, &mut intervals)?;
+        }
+        let mut unplaced = false;
+        for spec in specs {
+            match spec.as_str() {
+                "*" => unplaced = true,
+                spec => intervals.push(parse_region(spec, header)?),
+            }
+        }
+        if intervals.is_empty() && !unplaced {
+            bail!(
+                "The --regions and --region intervals are all on sequences not in the input header"
+            );
+        }
+
+        let mut regions = Regions {
+            by_tid: vec![Vec::new(); header.target_count() as usize],
+            unplaced,
+        };
+        intervals.sort_unstable();
+        for (tid, start, end) in intervals {
+            let contig = &mut regions.by_tid[tid as usize];
+            match contig.last_mut() {
+                Some((_, last_end)) if start <= *last_end => *last_end = (*last_end).max(end),
+                _ => contig.push((start, end)),
+            }
+        }
+        Ok(regions)
+    }
+
+    /// `(tid, start, end)` of every merged interval, in coordinate order
+    pub fn intervals(&self) -> Vec<(u32, i64, i64)> {
+        self.by_tid
+            .iter()
+            .enumerate()
+            .flat_map(|(tid, contig)| {
+                contig
+                    .iter()
+                    .map(move |&(start, end)| (tid as u32, start, end))
+            })
+            .collect()
+    }
+
+    /// Bases covered by the intervals
+    pub fn bases(&self) -> i64 {
+        self.by_tid
+            .iter()
+            .flatten()
+            .map(|(start, end)| end - start)
+            .sum()
+    }
+
+    /// Sequences with an interval, by tid
+    pub fn tids(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.by_tid.len() as u32).filter(|&tid| !self.by_tid[tid as usize].is_empty())
+    }
+
+    /// Whether a placed record overlaps an interval, or an unplaced one is
+    /// included by `*`
+    pub fn overlaps(&self, record: &bam::Record) -> bool {
+        if record.tid() < 0 {
+            return self.unplaced;
+        }
+        let Some(contig) = usize::try_from(record.tid())
+            .ok()
+            .and_then(|tid| self.by_tid.get(tid))
+        else {
+            return false;
+        };
+        let start = record.pos();
+        let end = if record.is_unmapped() {
+            start + 1
+        } else {
+            record.cigar().end_pos()
+        };
+        let after = contig.partition_point(|&(_, interval_end)| interval_end <= start);
+        contig
+            .get(after)
+            .is_some_and(|&(interval_start, _)| interval_start < end)
+    }
+
+    pub fn overlaps_pair(&self, record1: &bam::Record, record2: &bam::Record) -> bool {
+        self.overlaps(record1) || self.overlaps(record2)
+    }
+}
+
+fn read_bed(
+    path: &str,
+    header: &bam::HeaderView,
+    intervals: &mut Vec<(u32, i64, i64)>,
+) -> Result<()> {
+    let mut unknown = 0;
+    for line in bed::read(path)? {
+        match header.tid(line.chrom.as_bytes()) {
+            Some(tid) => intervals.push((tid, line.start, line.end)),
+            None => unknown += 1,
+        }
+    }
+    if unknown > 0 {
+        eprintln!(
+            "Warning: {} interval(s) in {} are on sequences not in the input header",
+            unknown, path
+        );
+    }
+    Ok(())
+}
+
+/// `chr` or `chr:start-end`, 1-based and inclusive; `chr:start` runs to the end
+fn parse_region(spec: &str, header: &bam::HeaderView) -> Result<(u32, i64, i64)> {
+    let tid_of = |name: &str| header.tid(name.as_bytes());
+    let sequence_length = |tid: u32| {
+        header
+            .target_len(tid)
+            .map_or(i64::MAX, |length| length as i64)
+    };
+
+    // A whole sequence whose name contains a colon
+    if let Some(tid) = tid_of(spec) {
+        return Ok((tid, 0, sequence_length(tid)));
+    }
+    let Some((name, range)) = spec.rsplit_once(':') else {
+        bail!("--region {}: no such sequence in the input header", spec);
+    };
+    let Some(tid) = tid_of(name) else {
+        bail!(
+            "--region {}: no sequence {} in the input header",
+            spec,
+            name
+        );
+    };
+    let number = |text: &str| {
+        text.replace(',', "")
+            .parse::<i64>()
+            .with_context(|| format!("--region {}: {} is not a position", spec, text))
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (number(start)?, number(end)?),
+        None => (number(range)?, sequence_length(tid)),
+    };
+    if start < 1 || end < start {
+        bail!(
+            "--region {}: expected chr:start-end with 1 <= start <= end",
+            spec
+        );
+    }
+    Ok((tid, start - 1, end))
+}
+
+/// Whether the header declares coordinate order
+pub fn is_coordinate_sorted(header: &bam::HeaderView) -> bool {
+    String::from_utf8_lossy(header.as_bytes())
+        .lines()
+        .find(|line| line.starts_with("@HD"))
+        .is_some_and(|hd| hd.split('\t').any(|field| field == "SO:coordinate"))
+}
+
+/// What a [`RegionReader`] is doing
+enum Stage {
+    /// Reading the interval at this index; one past the last interval, the
+    /// records without a position
+    Interval(usize),
+    /// Looking up the mates the intervals left waiting
+    Mates,
+    Done,
+}
+
+/// Reads the pairs overlapping the intervals of a coordinate-sorted,
+/// indexed input, mate after mate
+pub struct RegionReader {
+    reader: bam::IndexedReader,
+    intervals: Vec<(u32, i64, i64)>,
+    unplaced: bool,
+    stage: Stage,
+    paired: bool,
+    /// Primary records whose mate has not been read yet, by name
+    waiting: HashMap<Vec<u8>, bam::Record>,
+    /// Records to hand out, mates next to each other
+    ready: VecDeque<bam::Record>,
+    /// Waiting records once the intervals are read, in reverse mate order
+    lookups: Vec<bam::Record>,
+    /// Extra alignments passed over
+    pub extras_skipped: u64,
+    /// Records whose mate was not found at its mate position
+    pub mates_missing: u64,
+}
+
+impl RegionReader {
+    /// Open `path` through its index; `paired` pairs mates up, otherwise
+    /// records are handed out one by one
+    pub fn open(
+        path: &str,
+        regions: &Regions,
+        reference: Option<&str>,
+        thread_pool: Option<&ThreadPool>,
+        paired: bool,
+    ) -> Result<Self> {
+        let mut reader = bam::IndexedReader::from_path(path).with_context(|| {
+            format!(
+                "Cannot open the index of {}; index it with samtools index to read --regions from a coordinate-sorted input",
+                path
+            )
+        })?;
+        if let Some(reference) = reference {
+            reader
+                .set_reference(reference)
+                .with_context(|| format!("Cannot use reference {}", reference))?;
+        }
+        if let Some(pool) = thread_pool {
+            reader
+                .set_thread_pool(pool)
+                .with_context(|| format!("Cannot share decompression threads with {}", path))?;
+        }
+        let intervals = regions.intervals();
+        let mut reader = RegionReader {
+            reader,
+            intervals,
+            unplaced: regions.unplaced,
+            stage: Stage::Done,
+            paired,
+            waiting: HashMap::new(),
+            ready: VecDeque::new(),
+            lookups: Vec::new(),
+            extras_skipped: 0,
+            mates_missing: 0,
+        };
+        reader.start_interval(0)?;
+        Ok(reader)
+    }
+
+    fn start_interval(&mut self, index: usize) -> Result<(), HtsError> {
+        match self.intervals.get(index) {
+            Some(&(tid, start, end)) => {
+                self.reader.fetch((tid as i32, start, end))?;
+                self.stage = Stage::Interval(index);
+            }
+            None if self.unplaced && index == self.intervals.len() => {
+                self.reader.fetch(bam::FetchDefinition::Unmapped)?;
+                self.stage = Stage::Interval(index);
+            }
+            None => {
+                let mut lookups: Vec<bam::Record> = self.waiting.drain().map(|(_, r)| r).collect();
+                lookups.sort_unstable_by_key(|record| {
+                    std::cmp::Reverse((record.mtid(), record.mpos()))
+                });
+                self.lookups = lookups;
+                self.stage = Stage::Mates;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the next record into `record`, like [`bam::Read::read`]
+    pub fn read(&mut self, record: &mut bam::Record) -> Option<Result<(), HtsError>> {
+        loop {
+            if let Some(next) = self.ready.pop_front() {
+                *record = next;
+                return Some(Ok(()));
+            }
+            let result = match self.stage {
+                Stage::Interval(index) => self.read_interval(index),
+                Stage::Mates => match self.lookups.pop() {
+                    Some(waiting) => self.look_up_mate(waiting),
+                    None => {
+                        self.stage = Stage::Done;
+                        Ok(())
+                    }
+                },
+                Stage::Done => return None,
+            };
+            if let Err(e) = result {
+                return Some(Err(e));
+            }
+        }
+    }
+
+    /// Take the interval's next record, or move on to the next interval
+    fn read_interval(&mut self, index: usize) -> Result<(), HtsError> {
+        let mut record = bam::Record::new();
+        match self.reader.read(&mut record) {
+            None => return self.start_interval(index + 1),
+            Some(result) => result?,
+        }
+        if record.is_secondary() || record.is_supplementary() {
+            self.extras_skipped += 1;
+            return Ok(());
+        }
+        // Records overlapping the interval before were read with it
+        if let Some(&(tid, _, end)) = index.checked_sub(1).and_then(|i| self.intervals.get(i)) {
+            if record.tid() == tid as i32 && record.pos() < end {
+                return Ok(());
+            }
+        }
+        if !self.paired {
+            self.ready.push_back(record);
+            return Ok(());
+        }
+        match self.waiting.remove(record.qname()) {
+            Some(mate) if mate.is_first_in_template() != record.is_first_in_template() => {
+                self.ready.push_back(mate);
+                self.ready.push_back(record);
+            }
+            _ => {
+                self.waiting.insert(record.qname().to_vec(), record);
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the mate of `waiting` at its mate position
+    fn look_up_mate(&mut self, waiting: bam::Record) -> Result<(), HtsError> {
+        if waiting.mtid() < 0 {
+            self.mates_missing += 1;
+            return Ok(());
+        }
+        self.reader
+            .fetch((waiting.mtid(), waiting.mpos(), waiting.mpos() + 1))?;
+        let mut record = bam::Record::new();
+        while let Some(result) = self.reader.read(&mut record) {
+            result?;
+            if record.pos() == waiting.mpos()
+                && record.qname() == waiting.qname()
+                && !(record.is_secondary() || record.is_supplementary())
+                && record.is_first_in_template() != waiting.is_first_in_template()
+            {
+                self.ready.push_back(waiting);
+                self.ready.push_back(record);
+                return Ok(());
+            }
+        }
+        self.mates_missing += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,485 @@
+//! Machine-readable run statistics that can be merged across runs
+//!
+//! Scatter/gather workflows filter shards or regions in parallel, each writing
+//! `--stats-json`. Every counter and histogram here is additive, so
+//! `merge-stats` can combine them into the report a single run would have
+//! produced. Library complexity and duplicate-rate estimates depend on the set
+//! of fragment positions and are not included.
+//!
+//! The JSON layout is versioned by [`SCHEMA_VERSION`]. Within a version,
+//! changes are additive only: new fields may appear, and are missing from
+//! older files, but no field is renamed, removed, or changes type or meaning.
+//! Anything else bumps the version, and `--report-schema-version` keeps
+//! writing the older layout for parsers that ask for it.
+
+use crate::barcodes::BarcodeStats;
+use crate::chain::{self, FilterEvaluation};
+use crate::duplex::DuplexStats;
+use crate::molecules::FamilyStats;
+use crate::nanopore::NanoporeStats;
+use crate::primers::PrimerStats;
+use crate::read_errors::ErrorCounts;
+use crate::samples::SampleStats;
+use crate::stats::{ChimeraStats, ClipStats, GcStats, InsertSizeStats, SequenceStats};
+use crate::summary::RejectionCounts;
+use crate::targets::TargetStats;
+use crate::timing::{self, StageTime};
+use crate::units::NumberFormat;
+use crate::validation::ValidationCounts;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Version of the JSON layout written by default
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Oldest version that can still be written and read
+pub const MIN_SCHEMA_VERSION: u32 = 1;
+
+/// Files written before the version field existed have the version 1 layout
+fn unversioned() -> u32 {
+    1
+}
+
+/// Fail unless this build can write and read `version`
+pub fn check_schema_version(version: u32) -> Result<()> {
+    if !(MIN_SCHEMA_VERSION..=SCHEMA_VERSION).contains(&version) {
+        bail!(
+            "Stats schema version {} is not supported; this build handles versions {} to {}",
+            version,
+            MIN_SCHEMA_VERSION,
+            SCHEMA_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Aggregate statistics of one or more filtering runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Report {
+    /// Layout version of the JSON, see [`SCHEMA_VERSION`]
+    #[serde(default = "unversioned")]
+    pub schema_version: u32,
+    pub total_pairs: u64,
+    pub kept_pairs: u64,
+    /// Whether any contributing run was interrupted
+    pub interrupted: bool,
+    /// Counts are of single-end reads (`--single-end`), not pairs
+    #[serde(default)]
+    pub single_end: bool,
+    /// Whether any contributing run stopped early at `--preview-pairs`/`--preview-seconds`
+    #[serde(default)]
+    pub preview: bool,
+    /// Only present when `--use-cached-metrics` was given
+    pub cached_metrics: Option<u64>,
+    /// Only present when `--ligation-motif` was given
+    pub junction_pairs: Option<u64>,
+    /// Kept pairs with a low-complexity mate; only present when `--rescue-by-mate` was given
+    #[serde(default)]
+    pub rescued_pairs: Option<u64>,
+    /// Pairs removed for a mate shorter than the kmer length; only present
+    /// with `--short-read-policy skip`
+    #[serde(default)]
+    pub short_pairs: Option<u64>,
+    /// Pairs with a mate below `--min-mapq`; only present when it was given
+    #[serde(default)]
+    pub low_mapq_pairs: Option<u64>,
+    /// Records skipped before pairing by `--require-flags`/`--exclude-flags`;
+    /// only present when either was given
+    #[serde(default)]
+    pub flag_skipped_records: Option<u64>,
+    /// Secondary and supplementary records grouped with their pair and
+    /// written with it; only present when there were any
+    #[serde(default)]
+    pub carried_extra_records: Option<u64>,
+    /// As `carried_extra_records`, under `--extra-alignments drop`
+    #[serde(default)]
+    pub dropped_extra_records: Option<u64>,
+    /// Records given NM and/or MD from the reference; only present with
+    /// `--regenerate-md`
+    #[serde(default)]
+    pub regenerated_md_records: Option<u64>,
+    /// Only present when `--max-region-depth` was given
+    #[serde(default)]
+    pub deep_region_pairs: Option<u64>,
+    /// Only present when `--max-global-kmer-percentile` was given
+    #[serde(default)]
+    pub high_frequency_pairs: Option<u64>,
+    /// Only present when `--kmer-blacklist` was given
+    #[serde(default)]
+    pub blacklisted_pairs: Option<u64>,
+    /// Only present when `--include-names` or `--exclude-names` was given
+    #[serde(default)]
+    pub name_list_removed: Option<u64>,
+    /// Only present when `--duplex-reads` was given
+    #[serde(default)]
+    pub duplex_removed: Option<u64>,
+    /// Only present when `--adaptive-sampling-filter` was given
+    #[serde(default)]
+    pub adaptive_sampling_removed: Option<u64>,
+    /// Only present when `--resync` was given
+    #[serde(default)]
+    pub orphan_reads: Option<u64>,
+    /// Only present when `--regions` or `--region` skipped pairs of a streamed input
+    #[serde(default)]
+    pub outside_regions: Option<u64>,
+    /// Only present with `--on-read-error skip`
+    #[serde(default)]
+    pub read_errors: Option<ErrorCounts>,
+    /// Only present when `--validation` found malformed records
+    #[serde(default)]
+    pub validation: Option<ValidationCounts>,
+    /// Only present when `--check-name-collisions` was given
+    #[serde(default)]
+    pub name_collisions: Option<u64>,
+    pub insert_size: InsertSizeStats,
+    pub chimeras: ChimeraStats,
+    pub sequences: SequenceStats,
+    pub barcodes: Option<BarcodeStats>,
+    /// Only present when `--min-family-size` or `--family-size-histogram` was given
+    #[serde(default)]
+    pub families: Option<FamilyStats>,
+    /// Only present when `--sample-stats` was given
+    #[serde(default)]
+    pub samples: Option<SampleStats>,
+    /// Only present when `--clip-profile` was given
+    #[serde(default)]
+    pub clips: Option<ClipStats>,
+    /// Only present when `--gc-profile` was given
+    #[serde(default)]
+    pub gc: Option<GcStats>,
+    /// Only present when pairs carried nanopore channel tags
+    #[serde(default)]
+    pub nanopore: Option<NanoporeStats>,
+    /// Only present when reads carried dorado's duplex tag
+    #[serde(default)]
+    pub duplex: Option<DuplexStats>,
+    /// Only present when `--primers` was given
+    #[serde(default)]
+    pub primers: Option<PrimerStats>,
+    /// Only present when `--targets` was given
+    #[serde(default)]
+    pub targets: Option<TargetStats>,
+    /// Removed pairs by the thresholds and filters they failed; absent in
+    /// reports of older versions
+    #[serde(default)]
+    pub rejections: Option<RejectionCounts>,
+    /// Pairs each threshold was evaluated on and rejected, in evaluation
+    /// order; only present when `--short-circuit` was given
+    #[serde(default)]
+    pub filter_evaluations: Option<Vec<FilterEvaluation>>,
+    /// Only present when `--stage-timing` was given
+    #[serde(default)]
+    pub stages: Option<Vec<StageTime>>,
+}
+
+/// Sum two optional counters, keeping `None` only when both are absent
+fn merge_count(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+    }
+}
+
+impl Report {
+    pub fn read_json(path: &str) -> Result<Report> {
+        let file = std::fs::File::open(path).with_context(|| format!("Cannot open {}", path))?;
+        let report: Report = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("{} is not a filter_bam_pairs stats file", path))?;
+        check_schema_version(report.schema_version)
+            .with_context(|| format!("Cannot read {}", path))?;
+        Ok(report)
+    }
+
+    pub fn write_json(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    pub fn merge(&mut self, other: &Report) {
+        self.total_pairs += other.total_pairs;
+        self.kept_pairs += other.kept_pairs;
+        self.interrupted |= other.interrupted;
+        self.preview |= other.preview;
+        self.single_end |= other.single_end;
+        self.cached_metrics = merge_count(self.cached_metrics, other.cached_metrics);
+        self.junction_pairs = merge_count(self.junction_pairs, other.junction_pairs);
+        self.rescued_pairs = merge_count(self.rescued_pairs, other.rescued_pairs);
+        self.short_pairs = merge_count(self.short_pairs, other.short_pairs);
+        self.low_mapq_pairs = merge_count(self.low_mapq_pairs, other.low_mapq_pairs);
+        self.flag_skipped_records =
+            merge_count(self.flag_skipped_records, other.flag_skipped_records);
+        self.carried_extra_records =
+            merge_count(self.carried_extra_records, other.carried_extra_records);
+        self.dropped_extra_records =
+            merge_count(self.dropped_extra_records, other.dropped_extra_records);
+        self.regenerated_md_records =
+            merge_count(self.regenerated_md_records, other.regenerated_md_records);
+        self.deep_region_pairs = merge_count(self.deep_region_pairs, other.deep_region_pairs);
+        self.high_frequency_pairs =
+            merge_count(self.high_frequency_pairs, other.high_frequency_pairs);
+        self.blacklisted_pairs = merge_count(self.blacklisted_pairs, other.blacklisted_pairs);
+        self.name_list_removed = merge_count(self.name_list_removed, other.name_list_removed);
+        self.duplex_removed = merge_count(self.duplex_removed, other.duplex_removed);
+        self.adaptive_sampling_removed = merge_count(
+            self.adaptive_sampling_removed,
+            other.adaptive_sampling_removed,
+        );
+        self.orphan_reads = merge_count(self.orphan_reads, other.orphan_reads);
+        self.outside_regions = merge_count(self.outside_regions, other.outside_regions);
+        self.read_errors = match (self.read_errors, other.read_errors) {
+            (Some(a), Some(b)) => Some(ErrorCounts {
+                truncated: a.truncated + b.truncated,
+                invalid: a.invalid + b.invalid,
+                other: a.other + b.other,
+                skipped_blocks: a.skipped_blocks + b.skipped_blocks,
+            }),
+            (a, b) => a.or(b),
+        };
+        match (&mut self.validation, &other.validation) {
+            (Some(a), Some(b)) => a.merge(b),
+            (a, b) => *a = a.or(*b),
+        }
+        self.name_collisions = merge_count(self.name_collisions, other.name_collisions);
+        self.insert_size.merge(&other.insert_size);
+        self.chimeras.merge(&other.chimeras);
+        self.sequences.merge(&other.sequences);
+        match (&mut self.barcodes, &other.barcodes) {
+            (Some(barcodes), Some(other)) => barcodes.merge(other),
+            (None, Some(other)) => {
+                let mut barcodes = BarcodeStats::default();
+                barcodes.merge(other);
+                self.barcodes = Some(barcodes);
+            }
+            (_, None) => {}
+        }
+        if let Some(other) = &other.families {
+            self.families
+                .get_or_insert_with(FamilyStats::default)
+                .merge(other);
+        }
+        if let Some(other) = &other.clips {
+            self.clips
+                .get_or_insert_with(ClipStats::default)
+                .merge(other);
+        }
+        if let Some(other) = &other.gc {
+            self.gc.get_or_insert_with(GcStats::default).merge(other);
+        }
+        if let Some(other) = &other.nanopore {
+            self.nanopore
+                .get_or_insert_with(NanoporeStats::default)
+                .merge(other);
+        }
+        if let Some(other) = &other.duplex {
+            self.duplex
+                .get_or_insert_with(DuplexStats::default)
+                .merge(other);
+        }
+        if let Some(other) = &other.samples {
+            self.samples
+                .get_or_insert_with(SampleStats::default)
+                .merge(other);
+        }
+        if let Some(other) = &other.primers {
+            self.primers
+                .get_or_insert_with(PrimerStats::default)
+                .merge(other);
+        }
+        if let Some(other) = &other.targets {
+            self.targets
+                .get_or_insert_with(TargetStats::default)
+                .merge(other);
+        }
+        match (&mut self.rejections, &other.rejections) {
+            (Some(rejections), Some(other)) => rejections.merge(other),
+            (rejections @ None, Some(other)) => *rejections = Some(other.clone()),
+            (_, None) => {}
+        }
+        if let Some(other) = &other.filter_evaluations {
+            chain::merge_evaluations(self.filter_evaluations.get_or_insert_with(Vec::new), other);
+        }
+        if let Some(other) = &other.stages {
+            timing::merge_stage_times(self.stages.get_or_insert_with(Vec::new), other);
+        }
+    }
+
+    /// What the totals count
+    pub fn unit(&self) -> &'static str {
+        if self.single_end {
+            "reads"
+        } else {
+            "pairs"
+        }
+    }
+
+    /// Print pair totals and the additive breakdowns, with counts and
+    /// percentages in `format`
+    pub fn print(&self, format: NumberFormat) {
+        let unit = self.unit();
+        // The unit starting a line
+        let noun = unit[..1].to_uppercase() + &unit[1..];
+        let count = |count: u64| format.count(count);
+        println!("Total {}: {}", unit, count(self.total_pairs));
+        println!("Filtered {}: {}", unit, count(self.kept_pairs));
+        println!(
+            "Removed {}: {}",
+            unit,
+            count(self.total_pairs - self.kept_pairs)
+        );
+        if self.total_pairs > 0 {
+            println!(
+                "Pass rate: {}",
+                format.share(self.kept_pairs, self.total_pairs)
+            );
+        }
+        if let Some(cached) = self.cached_metrics {
+            println!("Cached metric values used: {}", count(cached));
+        }
+        if let Some(junctions) = self.junction_pairs {
+            println!("{} with a ligation junction: {}", noun, count(junctions));
+        }
+        if let Some(rescued) = self.rescued_pairs {
+            println!("{} kept by mate rescue: {}", noun, count(rescued));
+        }
+        if let Some(short) = self.short_pairs {
+            println!(
+                "{} skipped as shorter than the kmer size: {}",
+                noun,
+                count(short)
+            );
+        }
+        if let Some(low) = self.low_mapq_pairs {
+            println!("{} below the minimum MAPQ: {}", noun, count(low));
+        }
+        if let Some(deep) = self.deep_region_pairs {
+            println!("{} in over-deep regions: {}", noun, count(deep));
+        }
+        if let Some(high) = self.high_frequency_pairs {
+            println!(
+                "{} made mostly of high-frequency kmers: {}",
+                noun,
+                count(high)
+            );
+        }
+        if let Some(blacklisted) = self.blacklisted_pairs {
+            println!("{} with blacklisted kmers: {}", noun, count(blacklisted));
+        }
+        if let Some(removed) = self.name_list_removed {
+            println!("{} removed by the name list: {}", noun, count(removed));
+        }
+        if let Some(removed) = self.duplex_removed {
+            println!("{} removed by --duplex-reads: {}", noun, count(removed));
+        }
+        if let Some(removed) = self.adaptive_sampling_removed {
+            println!(
+                "{} removed as adaptive-sampling rejects: {}",
+                noun,
+                count(removed)
+            );
+        }
+        if let Some(skipped) = self.flag_skipped_records {
+            println!("Records skipped by flag: {}", count(skipped));
+        }
+        if let Some(outside) = self.outside_regions {
+            println!(
+                "{} outside --regions, not considered: {}",
+                noun,
+                count(outside)
+            );
+        }
+        if let Some(carried) = self.carried_extra_records {
+            println!(
+                "Secondary/supplementary records carried with their pair: {}",
+                count(carried)
+            );
+        }
+        if let Some(dropped) = self.dropped_extra_records {
+            println!(
+                "Secondary/supplementary records dropped: {}",
+                count(dropped)
+            );
+        }
+        if let Some(regenerated) = self.regenerated_md_records {
+            println!(
+                "Records given NM/MD from the reference: {}",
+                count(regenerated)
+            );
+        }
+        if let Some(orphans) = self.orphan_reads {
+            println!("Orphan reads skipped: {}", count(orphans));
+        }
+        if let Some(errors) = self.read_errors {
+            println!(
+                "Read errors skipped: {} ({} truncated, {} invalid, {} other; {} BGZF blocks skipped)",
+                count(errors.total()),
+                count(errors.truncated),
+                count(errors.invalid),
+                count(errors.other),
+                count(errors.skipped_blocks)
+            );
+        }
+        if let Some(violations) = self.validation {
+            println!(
+                "Malformed records: {} ({} unaligned CIGAR, {} missing CIGAR, {} invalid position, {} outside reference, {} inconsistent flags, {} mate mismatch)",
+                count(violations.total()),
+                count(violations.unaligned_cigar),
+                count(violations.missing_cigar),
+                count(violations.invalid_position),
+                count(violations.outside_reference),
+                count(violations.inconsistent_flags),
+                count(violations.mate_mismatch)
+            );
+        }
+        if let Some(collisions) = self.name_collisions {
+            println!("Read names reused by another pair: {}", count(collisions));
+        }
+        if self.total_pairs > 0 {
+            if !self.single_end {
+                self.insert_size.print(format);
+                self.chimeras.print(format);
+            }
+            if let Some(clips) = &self.clips {
+                clips.print(format);
+            }
+            if let Some(gc) = &self.gc {
+                gc.print(format);
+            }
+            if let Some(barcodes) = &self.barcodes {
+                barcodes.print(format);
+            }
+            if let Some(families) = &self.families {
+                families.print(format);
+            }
+            if let Some(samples) = &self.samples {
+                samples.print(format);
+            }
+            if let Some(nanopore) = &self.nanopore {
+                nanopore.print(format);
+            }
+            if let Some(duplex) = &self.duplex {
+                duplex.print(format);
+            }
+            if let Some(primers) = &self.primers {
+                primers.print(format);
+            }
+            if let Some(targets) = &self.targets {
+                targets.print(format);
+            }
+        }
+        if let Some(rejections) = self
+            .rejections
+            .as_ref()
+            .filter(|_| self.total_pairs > self.kept_pairs)
+        {
+            rejections.print(unit, self.total_pairs - self.kept_pairs, format);
+        }
+        if let Some(evaluations) = &self.filter_evaluations {
+            chain::print_evaluations(evaluations, self.unit(), format);
+        }
+        if let Some(stages) = &self.stages {
+            timing::print_stage_times(stages);
+        }
+    }
+}
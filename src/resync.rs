@@ -0,0 +1,132 @@
+//! Recovering mate pairing after a break in name order (`--resync`)
+//!
+//! Normally two consecutive records with different names abort the run. In
+//! resync mode unmatched records wait in a window of the most recent input
+//! records; a record whose mate arrives within the window is paired as usual,
+//! and one that falls out of the window unmatched is skipped as an orphan.
+
+use crate::recycle::RecordPool;
+use anyhow::Result;
+use rust_htslib::{bam, errors::Error as HtsError};
+use std::collections::VecDeque;
+
+/// Orphans named in warnings before only counting the rest
+const WARN_ORPHANS: u64 = 10;
+
+/// Pairs records whose mates arrive within `window` records of each other
+pub struct Resync {
+    window: u64,
+    records_seen: u64,
+    /// Unmatched records with their input index, oldest first
+    pending: VecDeque<(u64, bam::Record)>,
+    orphans: u64,
+    pool: RecordPool,
+}
+
+impl Resync {
+    pub fn new(window: u64) -> Self {
+        Resync {
+            window,
+            records_seen: 0,
+            pending: VecDeque::new(),
+            orphans: 0,
+            pool: RecordPool::default(),
+        }
+    }
+
+    /// Records skipped so far because no mate turned up in the window
+    pub fn orphans(&self) -> u64 {
+        self.orphans
+    }
+
+    /// Give a pair's records back to be read into again
+    pub fn recycle(&mut self, record1: bam::Record, record2: bam::Record) {
+        self.pool.give(record1);
+        self.pool.give(record2);
+    }
+
+    fn skip_orphan(&mut self, record: &bam::Record) {
+        self.orphans += 1;
+        if self.orphans <= WARN_ORPHANS {
+            eprintln!(
+                "Warning: skipping orphan read {} (mate not found)",
+                String::from_utf8_lossy(record.qname())
+            );
+        } else if self.orphans == WARN_ORPHANS + 1 {
+            eprintln!("Warning: further orphan reads are only counted");
+        }
+    }
+
+    /// Add the next input record, returning a pair once its mate is found
+    ///
+    /// The pair is ordered first mate first when the flags say which is which,
+    /// otherwise in input order.
+    pub fn push(&mut self, record: bam::Record) -> Option<(bam::Record, bam::Record)> {
+        self.records_seen += 1;
+        while let Some((seen, _)) = self.pending.front() {
+            if seen + self.window >= self.records_seen {
+                break;
+            }
+            let (_, orphan) = self.pending.pop_front().unwrap();
+            self.skip_orphan(&orphan);
+            self.pool.give(orphan);
+        }
+
+        // Usually at most one record is pending, so a scan is cheapest
+        match self
+            .pending
+            .iter()
+            .position(|(_, pending)| pending.qname() == record.qname())
+        {
+            Some(index) => {
+                let (_, mate) = self.pending.remove(index).unwrap();
+                if mate.is_last_in_template() && record.is_first_in_template() {
+                    Some((record, mate))
+                } else {
+                    Some((mate, record))
+                }
+            }
+            None => {
+                self.pending.push_back((self.records_seen, record));
+                None
+            }
+        }
+    }
+
+    /// Count the records still waiting for a mate at the end of the input
+    pub fn finish(&mut self) {
+        while let Some((_, orphan)) = self.pending.pop_front() {
+            self.skip_orphan(&orphan);
+        }
+    }
+
+    /// Read until the next complete pair, or `None` at the end of the input
+    pub fn next_pair<R: bam::Read>(
+        &mut self,
+        reader: &mut R,
+    ) -> Option<Result<(bam::Record, bam::Record)>> {
+        self.next_pair_from(|record| reader.read(record))
+    }
+
+    /// [`next_pair`](Self::next_pair) with records from `read`, which has
+    /// the signature of [`bam::Read::read`]
+    pub fn next_pair_from(
+        &mut self,
+        mut read: impl FnMut(&mut bam::Record) -> Option<Result<(), HtsError>>,
+    ) -> Option<Result<(bam::Record, bam::Record)>> {
+        loop {
+            let mut record = self.pool.take();
+            match read(&mut record) {
+                Some(Ok(())) => {}
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => {
+                    self.finish();
+                    return None;
+                }
+            }
+            if let Some(pair) = self.push(record) {
+                return Some(Ok(pair));
+            }
+        }
+    }
+}
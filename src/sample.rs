@@ -0,0 +1,77 @@
+//! Deterministic pair sampling by read-name hash (`--hash-sample`)
+//!
+//! Each read name hashes to one of `denominator` buckets. The bucket depends
+//! only on the name, never on record order, the input file or the run, so
+//! runs that select different buckets produce disjoint subsets whose union is
+//! the whole input.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Buckets `first..=last` (1-based) out of `denominator`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashSample {
+    pub first: u64,
+    pub last: u64,
+    pub denominator: u64,
+}
+
+/// Stable 64-bit hash of a read name: FNV-1a, then a splitmix64 finalizer
+/// so that names differing only in their last characters spread evenly
+pub fn name_hash(name: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in name {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+impl HashSample {
+    /// 1-based bucket of a read name
+    pub fn bucket(&self, name: &[u8]) -> u64 {
+        name_hash(name) % self.denominator + 1
+    }
+
+    pub fn contains(&self, name: &[u8]) -> bool {
+        (self.first..=self.last).contains(&self.bucket(name))
+    }
+
+    /// Expected fraction of pairs kept
+    pub fn fraction(&self) -> f64 {
+        (self.last - self.first + 1) as f64 / self.denominator as f64
+    }
+}
+
+impl FromStr for HashSample {
+    type Err = String;
+
+    /// `K/D` for bucket K of D, or `A-B/D` for buckets A through B
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("expected K/D or A-B/D with 1 <= A <= B <= D, got '{}'", s);
+        let (buckets, denominator) = s.split_once('/').ok_or_else(usage)?;
+        let (first, last) = buckets.split_once('-').unwrap_or((buckets, buckets));
+        let parse = |n: &str| n.trim().parse::<u64>().map_err(|_| usage());
+        let sample = HashSample {
+            first: parse(first)?,
+            last: parse(last)?,
+            denominator: parse(denominator)?,
+        };
+        if sample.first == 0 || sample.first > sample.last || sample.last > sample.denominator {
+            return Err(usage());
+        }
+        Ok(sample)
+    }
+}
+
+impl fmt::Display for HashSample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}/{}", self.first, self.denominator)
+        } else {
+            write!(f, "{}-{}/{}", self.first, self.last, self.denominator)
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Pass rates per read group and per sample (`--sample-stats`)
+//!
+//! Pairs are attributed to the first mate's `RG` tag, and read groups to
+//! samples through the `SM` field of the header's `@RG` lines, so merged
+//! multi-sample BAMs get per-sample numbers.
+
+use crate::units::NumberFormat;
+use anyhow::{Context, Result};
+use rust_htslib::{bam, bam::record::Aux};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Read group of pairs without an `RG` tag
+const NO_READ_GROUP: &str = "none";
+
+/// Sample of read groups missing from the header or without `SM`
+const UNKNOWN_SAMPLE: &str = "unknown";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ReadGroupCounts {
+    sample: String,
+    pairs: u64,
+    kept: u64,
+}
+
+/// Kept/total pair counts per read group, with each group's sample
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SampleStats {
+    read_groups: BTreeMap<String, ReadGroupCounts>,
+}
+
+fn pass_rate(pairs: u64, kept: u64) -> f64 {
+    if pairs > 0 {
+        kept as f64 / pairs as f64
+    } else {
+        0.0
+    }
+}
+
+/// IDs of the header's `@RG` lines
+pub fn read_group_ids(header: &bam::Header) -> Vec<String> {
+    header
+        .to_hashmap()
+        .get("RG")
+        .map(|read_groups| {
+            read_groups
+                .iter()
+                .filter_map(|read_group| read_group.get("ID").cloned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl SampleStats {
+    /// Start with every `@RG` of the header, so groups without pairs are listed
+    pub fn from_header(header: &bam::Header) -> Self {
+        let mut stats = SampleStats::default();
+        if let Some(read_groups) = header.to_hashmap().get("RG") {
+            for read_group in read_groups {
+                let Some(id) = read_group.get("ID") else {
+                    continue;
+                };
+                stats.read_groups.insert(
+                    id.clone(),
+                    ReadGroupCounts {
+                        sample: read_group
+                            .get("SM")
+                            .cloned()
+                            .unwrap_or_else(|| UNKNOWN_SAMPLE.to_string()),
+                        pairs: 0,
+                        kept: 0,
+                    },
+                );
+            }
+        }
+        stats
+    }
+
+    pub fn record(&mut self, record: &bam::Record, kept: bool) {
+        let read_group = match record.aux(b"RG") {
+            Ok(Aux::String(rg)) => rg,
+            _ => NO_READ_GROUP,
+        };
+        // Look up before inserting, so known groups cost no allocation
+        if !self.read_groups.contains_key(read_group) {
+            self.read_groups.insert(
+                read_group.to_string(),
+                ReadGroupCounts {
+                    sample: UNKNOWN_SAMPLE.to_string(),
+                    ..ReadGroupCounts::default()
+                },
+            );
+        }
+        let counts = self
+            .read_groups
+            .get_mut(read_group)
+            .expect("inserted above");
+        counts.pairs += 1;
+        counts.kept += kept as u64;
+    }
+
+    pub fn merge(&mut self, other: &SampleStats) {
+        for (id, theirs) in &other.read_groups {
+            let ours = self
+                .read_groups
+                .entry(id.clone())
+                .or_insert_with(|| ReadGroupCounts {
+                    sample: theirs.sample.clone(),
+                    ..ReadGroupCounts::default()
+                });
+            ours.pairs += theirs.pairs;
+            ours.kept += theirs.kept;
+        }
+    }
+
+    /// `(pairs, kept)` per sample
+    fn by_sample(&self) -> BTreeMap<&str, (u64, u64)> {
+        let mut samples: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+        for counts in self.read_groups.values() {
+            let entry = samples.entry(&counts.sample).or_default();
+            entry.0 += counts.pairs;
+            entry.1 += counts.kept;
+        }
+        samples
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        let samples = self.by_sample();
+        println!("\n=== Samples ===");
+        println!(
+            "{} samples in {} read groups",
+            samples.len(),
+            self.read_groups.len()
+        );
+        println!(
+            "{:<24} {:>12} {:>12} {:>10}",
+            "Sample", "Pairs", "Kept", "Pass rate"
+        );
+        for (sample, &(pairs, kept)) in &samples {
+            println!(
+                "{:<24} {:>12} {:>12} {:>10}",
+                sample,
+                format.count(pairs),
+                format.count(kept),
+                format.percent(pass_rate(pairs, kept) * 100.0)
+            );
+        }
+    }
+
+    /// Write one row per sample, then one per read group
+    pub fn write_tsv(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+
+        writeln!(out, "level\tid\tsample\tpairs\tkept\tpass_rate")?;
+        for (sample, (pairs, kept)) in self.by_sample() {
+            writeln!(
+                out,
+                "sample\t{}\t{}\t{}\t{}\t{:.4}",
+                sample,
+                sample,
+                pairs,
+                kept,
+                pass_rate(pairs, kept)
+            )?;
+        }
+        for (id, counts) in &self.read_groups {
+            writeln!(
+                out,
+                "read_group\t{}\t{}\t{}\t{}\t{:.4}",
+                id,
+                counts.sample,
+                counts.pairs,
+                counts.kept,
+                pass_rate(counts.pairs, counts.kept)
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+//! SIGINT/SIGTERM handling that lets the main loop finish outputs cleanly
+
+use anyhow::Result;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Records the first termination signal so the main loop can stop between pairs
+pub struct Interrupt {
+    signal: Arc<AtomicUsize>,
+}
+
+impl Interrupt {
+    /// Install handlers for SIGINT and SIGTERM
+    ///
+    /// The first signal only sets a flag; a second one terminates immediately,
+    /// so a stuck run can still be killed with a repeated Ctrl-C.
+    pub fn install() -> Result<Self> {
+        let signal = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::new(AtomicBool::new(false));
+
+        for sig in [SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(sig, 128 + sig, Arc::clone(&seen))?;
+            signal_hook::flag::register(sig, Arc::clone(&seen))?;
+            signal_hook::flag::register_usize(sig, Arc::clone(&signal), sig as usize)?;
+        }
+
+        Ok(Interrupt { signal })
+    }
+
+    /// The signal received so far, if any
+    pub fn received(&self) -> Option<i32> {
+        match self.signal.load(Ordering::Relaxed) {
+            0 => None,
+            sig => Some(sig as i32),
+        }
+    }
+}
+
+/// Exit status for a run stopped by `signal`, following the shell's 128+N convention
+pub fn exit_code(signal: i32) -> i32 {
+    128 + signal
+}
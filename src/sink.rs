@@ -0,0 +1,360 @@
+//! Destinations for filtered pairs, pluggable through [`OutputSink`]
+//!
+//! Every pair is offered to the sink with its final verdict, so a sink
+//! decides for itself what to write: [`Only`] passes on kept or rejected
+//! pairs, [`Tee`] fans out to several sinks and [`Counter`] just counts.
+//! Library users can implement the trait to feed an aligner, a socket or a
+//! database, and drive it with [`filter_into`] or their own loop.
+//!
+//! ```
+//! use filter_bam_pairs::sink::{Counter, Only, OutputSink, Tee};
+//! use filter_bam_pairs::test_utils::{mapped_pair, random_sequence};
+//!
+//! let seq = random_sequence(100, 1);
+//! let (r1, r2) = mapped_pair("read1", &seq, &seq);
+//! let (mut all, mut kept) = (Counter::default(), Counter::default());
+//! let mut sinks = Tee::default();
+//! sinks.push(&mut all);
+//! sinks.push(Only::kept(&mut kept));
+//! sinks.write_pair(&r1.build(), &r2.build(), true).unwrap();
+//! sinks.finish().unwrap();
+//! drop(sinks);
+//! assert_eq!((all.pairs(), kept.kept), (1, 1));
+//! ```
+
+use crate::fastq::FastqPairWriter;
+use crate::filter::PairFilter;
+use crate::grouping::Grouper;
+use crate::output::BamOutput;
+use anyhow::{bail, Result};
+use rust_htslib::{bam, bam::Read};
+
+/// Something that receives every pair along with whether it was kept
+pub trait OutputSink {
+    /// Take one pair; `kept` is the final decision of all filters
+    fn write_pair(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        kept: bool,
+    ) -> Result<()>;
+
+    /// Take one pair with its secondary and supplementary records; sinks of
+    /// reads rather than alignments, such as FASTQ, leave the extras out
+    fn write_template(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        extras: &[bam::Record],
+        kept: bool,
+    ) -> Result<()> {
+        let _ = extras;
+        self.write_pair(record1, record2, kept)
+    }
+
+    /// Take one read of a `--single-end` run; sinks that need pairs refuse
+    fn write_read(&mut self, record: &bam::Record, kept: bool) -> Result<()> {
+        let _ = (record, kept);
+        bail!("This output only takes pairs, not single-end reads")
+    }
+
+    /// Give back memory held in buffers, when the run nears `--max-rss`
+    fn relieve_memory(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Flush anything buffered, once after the last pair
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: OutputSink + ?Sized> OutputSink for &mut S {
+    fn write_pair(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        kept: bool,
+    ) -> Result<()> {
+        (**self).write_pair(record1, record2, kept)
+    }
+
+    fn write_template(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        extras: &[bam::Record],
+        kept: bool,
+    ) -> Result<()> {
+        (**self).write_template(record1, record2, extras, kept)
+    }
+
+    fn write_read(&mut self, record: &bam::Record, kept: bool) -> Result<()> {
+        (**self).write_read(record, kept)
+    }
+
+    fn relieve_memory(&mut self) -> Result<()> {
+        (**self).relieve_memory()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+impl<S: OutputSink + ?Sized> OutputSink for Box<S> {
+    fn write_pair(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        kept: bool,
+    ) -> Result<()> {
+        (**self).write_pair(record1, record2, kept)
+    }
+
+    fn write_template(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        extras: &[bam::Record],
+        kept: bool,
+    ) -> Result<()> {
+        (**self).write_template(record1, record2, extras, kept)
+    }
+
+    fn write_read(&mut self, record: &bam::Record, kept: bool) -> Result<()> {
+        (**self).write_read(record, kept)
+    }
+
+    fn relieve_memory(&mut self) -> Result<()> {
+        (**self).relieve_memory()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Writes every pair it is given; wrap it in [`Only`] to select
+impl OutputSink for BamOutput {
+    fn write_pair(&mut self, record1: &bam::Record, record2: &bam::Record, _: bool) -> Result<()> {
+        BamOutput::write_pair(self, record1, record2)
+    }
+
+    fn write_template(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        extras: &[bam::Record],
+        _: bool,
+    ) -> Result<()> {
+        BamOutput::write_template(self, record1, record2, extras)
+    }
+
+    fn write_read(&mut self, record: &bam::Record, _: bool) -> Result<()> {
+        self.write_record(record)
+    }
+
+    fn relieve_memory(&mut self) -> Result<()> {
+        BamOutput::relieve_memory(self)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.close()
+    }
+}
+
+/// Writes every pair it is given; wrap it in [`Only`] to select
+impl OutputSink for FastqPairWriter {
+    fn write_pair(&mut self, record1: &bam::Record, record2: &bam::Record, _: bool) -> Result<()> {
+        FastqPairWriter::write_pair(self, record1, record2)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// Passes on only kept, or only rejected, pairs
+pub struct Only<S> {
+    sink: S,
+    kept: bool,
+}
+
+impl<S: OutputSink> Only<S> {
+    pub fn kept(sink: S) -> Self {
+        Only { sink, kept: true }
+    }
+
+    pub fn rejected(sink: S) -> Self {
+        Only { sink, kept: false }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S: OutputSink> OutputSink for Only<S> {
+    fn write_pair(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        kept: bool,
+    ) -> Result<()> {
+        if kept == self.kept {
+            self.sink.write_pair(record1, record2, kept)?;
+        }
+        Ok(())
+    }
+
+    fn write_template(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        extras: &[bam::Record],
+        kept: bool,
+    ) -> Result<()> {
+        if kept == self.kept {
+            self.sink.write_template(record1, record2, extras, kept)?;
+        }
+        Ok(())
+    }
+
+    fn write_read(&mut self, record: &bam::Record, kept: bool) -> Result<()> {
+        if kept == self.kept {
+            self.sink.write_read(record, kept)?;
+        }
+        Ok(())
+    }
+
+    fn relieve_memory(&mut self) -> Result<()> {
+        self.sink.relieve_memory()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.sink.finish()
+    }
+}
+
+/// Counts pairs without writing them
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Counter {
+    pub kept: u64,
+    pub rejected: u64,
+}
+
+impl Counter {
+    pub fn pairs(&self) -> u64 {
+        self.kept + self.rejected
+    }
+}
+
+impl OutputSink for Counter {
+    fn write_pair(&mut self, _: &bam::Record, _: &bam::Record, kept: bool) -> Result<()> {
+        if kept {
+            self.kept += 1;
+        } else {
+            self.rejected += 1;
+        }
+        Ok(())
+    }
+
+    fn write_read(&mut self, record: &bam::Record, kept: bool) -> Result<()> {
+        self.write_pair(record, record, kept)
+    }
+}
+
+/// Offers each pair to several sinks, in the order they were added
+#[derive(Default)]
+pub struct Tee<'a> {
+    sinks: Vec<Box<dyn OutputSink + 'a>>,
+}
+
+impl<'a> Tee<'a> {
+    pub fn push(&mut self, sink: impl OutputSink + 'a) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+
+impl OutputSink for Tee<'_> {
+    fn write_pair(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        kept: bool,
+    ) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.write_pair(record1, record2, kept)?;
+        }
+        Ok(())
+    }
+
+    fn write_template(
+        &mut self,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        extras: &[bam::Record],
+        kept: bool,
+    ) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.write_template(record1, record2, extras, kept)?;
+        }
+        Ok(())
+    }
+
+    fn write_read(&mut self, record: &bam::Record, kept: bool) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.write_read(record, kept)?;
+        }
+        Ok(())
+    }
+
+    fn relieve_memory(&mut self) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.relieve_memory()?;
+        }
+        Ok(())
+    }
+
+    /// Finish every sink, even after one fails, and report the first failure
+    fn finish(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for sink in &mut self.sinks {
+            let finished = sink.finish();
+            if result.is_ok() {
+                result = finished;
+            }
+        }
+        result
+    }
+}
+
+/// Filter a name-sorted reader pair by pair into `sink`, then finish it
+///
+/// Only the decision of `filter` (usually a [`crate::FilterConfig`]) is applied;
+/// the binary's prepasses, statistics and signal handling are left to the
+/// caller. Secondary and supplementary records go to the sink with their
+/// pair. Returns the number of pairs read.
+pub fn filter_into<R: Read>(
+    reader: &mut R,
+    filter: &dyn PairFilter,
+    sink: &mut dyn OutputSink,
+) -> Result<u64> {
+    filter.validate()?;
+    let mut grouper = Grouper::new();
+    let mut pairs = 0u64;
+    while let Some(template) = grouper.next_template(reader) {
+        let template = template?;
+        let keep = filter.keep(&template.record1, &template.record2);
+        sink.write_template(&template.record1, &template.record2, &template.extras, keep)?;
+        pairs += 1;
+    }
+    grouper.finish()?;
+    sink.finish()?;
+    Ok(pairs)
+}
@@ -0,0 +1,181 @@
+//! External merge sort of kept records into coordinate order
+//!
+//! Records are buffered up to a memory budget, sorted and spilled to
+//! temporary BAMs in the work directory; finishing merges the spills (or
+//! writes the buffer directly when nothing spilled) into the final output.
+
+use crate::input;
+use crate::memory;
+use crate::output::Encoding;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use rust_htslib::{bam, bam::Read};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::path::{Path, PathBuf};
+
+/// Output orders `--sort-output` can produce
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    /// By reference, position and strand, unmapped reads last (samtools sort)
+    Coordinate,
+}
+
+/// Per-record bookkeeping on top of the record data, for the memory budget
+const RECORD_OVERHEAD: usize = std::mem::size_of::<bam::Record>() + 64;
+
+/// Coordinate sort key: unplaced reads (tid -1) sort after every reference
+pub fn coordinate_key(record: &bam::Record) -> (u32, i64, bool) {
+    (record.tid() as u32, record.pos(), record.is_reverse())
+}
+
+/// Sorts records into coordinate order within a memory budget
+pub struct ExternalSorter {
+    output: String,
+    header: bam::Header,
+    encoding: Encoding,
+    spill_dir: PathBuf,
+    memory_limit: usize,
+    /// Read-ahead per spill while merging, in KiB; `None` keeps htslib's
+    spill_buffer_kib: Option<usize>,
+    buffer: Vec<bam::Record>,
+    buffered_bytes: usize,
+    spills: Vec<PathBuf>,
+}
+
+impl ExternalSorter {
+    /// Sort into `output`, spilling to `spill_dir` whenever `memory_limit` bytes are buffered
+    pub fn new(
+        output: &str,
+        header: &bam::Header,
+        encoding: &Encoding,
+        spill_dir: &Path,
+        memory_limit: usize,
+        spill_buffer_kib: Option<usize>,
+    ) -> Self {
+        ExternalSorter {
+            output: output.to_string(),
+            header: header.clone(),
+            encoding: encoding.clone(),
+            spill_dir: spill_dir.to_path_buf(),
+            memory_limit,
+            spill_buffer_kib,
+            buffer: Vec::new(),
+            buffered_bytes: 0,
+            spills: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, record: &bam::Record) -> Result<()> {
+        self.buffered_bytes += record.inner().l_data as usize + RECORD_OVERHEAD;
+        // Clones don't keep the reader's header handle alive
+        self.buffer.push(record.clone());
+        if self.buffered_bytes >= self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Spill what is buffered and keep half the budget from now on
+    /// (`--max-rss`)
+    pub fn relieve(&mut self) -> Result<()> {
+        self.memory_limit = memory::halved(self.memory_limit);
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        // The spilled records' memory, not just their slots
+        self.buffer = Vec::new();
+        Ok(())
+    }
+
+    /// Temporary files written so far
+    pub fn spill_count(&self) -> usize {
+        self.spills.len()
+    }
+
+    fn sort_buffer(&mut self) {
+        // Stable, so mates at the same position keep their input order
+        self.buffer.sort_by_key(coordinate_key);
+    }
+
+    fn write_buffer(&mut self, mut writer: bam::Writer) -> Result<()> {
+        self.sort_buffer();
+        for record in self.buffer.drain(..) {
+            writer.write(&record)?;
+        }
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let path = self
+            .spill_dir
+            .join(format!("sort.{}.bam", self.spills.len()));
+        let mut writer = bam::Writer::from_path(&path, &self.header, bam::Format::Bam)
+            .with_context(|| format!("Cannot create {}", path.display()))?;
+        // Spills are read back once; speed matters more than size
+        writer.set_compression_level(bam::CompressionLevel::Fastest)?;
+        self.write_buffer(writer)?;
+        self.spills.push(path);
+        Ok(())
+    }
+
+    /// Write the sorted output and remove the spill files
+    pub fn finish(mut self) -> Result<()> {
+        if self.spills.is_empty() {
+            let writer = self.encoding.open(&self.output, &self.header)?;
+            return self.write_buffer(writer);
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+
+        let mut readers = self
+            .spills
+            .iter()
+            .map(|path| {
+                let mut reader = bam::Reader::from_path(path)
+                    .with_context(|| format!("Cannot reopen {}", path.display()))?;
+                if let Some(kib) = self.spill_buffer_kib {
+                    input::set_read_buffer(&mut reader, kib, &path.display().to_string())?;
+                }
+                Ok(reader)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut writer = self.encoding.open(&self.output, &self.header)?;
+
+        // Heap of each spill's next record; the spill index breaks ties so
+        // equal keys keep their input order
+        let mut heads: Vec<bam::Record> = Vec::with_capacity(readers.len());
+        let mut heap = BinaryHeap::new();
+        for (index, reader) in readers.iter_mut().enumerate() {
+            let mut record = bam::Record::new();
+            if let Some(result) = reader.read(&mut record) {
+                result?;
+                heap.push(Reverse((coordinate_key(&record), index)));
+            }
+            heads.push(record);
+        }
+        while let Some(Reverse((_, index))) = heap.pop() {
+            writer.write(&heads[index])?;
+            if let Some(result) = readers[index].read(&mut heads[index]) {
+                result?;
+                heap.push(Reverse((coordinate_key(&heads[index]), index)));
+            }
+        }
+        drop(writer);
+
+        // Open files can't be removed on Windows
+        drop(readers);
+        for path in &self.spills {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(())
+    }
+}
+
+/// Write a BAI index next to a coordinate-sorted BAM (`out.bam.bai`)
+pub fn index_bam(path: &str) -> Result<()> {
+    bam::index::build(path, None, bam::index::Type::Bai, 1)
+        .with_context(|| format!("Cannot index {}", path))
+}
@@ -0,0 +1,750 @@
+//! Breakdown statistics reported alongside the pass/fail totals
+
+use crate::metrics;
+use crate::quality::has_qualities;
+use crate::units::NumberFormat;
+use anyhow::{Context, Result};
+use rust_htslib::bam;
+use rust_htslib::bam::record::{Aux, Cigar};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+/// Insert-size classes used to stratify pass rates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertSizeBin {
+    Under100,
+    From100To300,
+    From300To1000,
+    Over1000,
+    /// Mates on different references
+    Trans,
+    /// At least one mate unmapped
+    Unmapped,
+}
+
+impl InsertSizeBin {
+    pub const ALL: [InsertSizeBin; 6] = [
+        InsertSizeBin::Under100,
+        InsertSizeBin::From100To300,
+        InsertSizeBin::From300To1000,
+        InsertSizeBin::Over1000,
+        InsertSizeBin::Trans,
+        InsertSizeBin::Unmapped,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            InsertSizeBin::Under100 => "<100",
+            InsertSizeBin::From100To300 => "100-300",
+            InsertSizeBin::From300To1000 => "300-1000",
+            InsertSizeBin::Over1000 => ">1000",
+            InsertSizeBin::Trans => "trans",
+            InsertSizeBin::Unmapped => "unmapped",
+        }
+    }
+
+    /// Classify a pair by the first mate's alignment and template length
+    pub fn of_pair(record1: &bam::Record, record2: &bam::Record) -> Self {
+        if record1.is_unmapped() || record2.is_unmapped() {
+            return InsertSizeBin::Unmapped;
+        }
+        if record1.tid() != record2.tid() {
+            return InsertSizeBin::Trans;
+        }
+
+        // Some aligners leave TLEN at 0; fall back to the mate distance
+        let insert = match record1.insert_size() {
+            0 => (record2.pos() - record1.pos()).abs(),
+            tlen => tlen.abs(),
+        };
+
+        match insert {
+            i if i < 100 => InsertSizeBin::Under100,
+            i if i < 300 => InsertSizeBin::From100To300,
+            i if i <= 1000 => InsertSizeBin::From300To1000,
+            _ => InsertSizeBin::Over1000,
+        }
+    }
+}
+
+/// Pair counts per insert-size bin, in `InsertSizeBin::ALL` order
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InsertSizeStats {
+    total: [u64; 6],
+    kept: [u64; 6],
+}
+
+impl InsertSizeStats {
+    pub fn record(&mut self, bin: InsertSizeBin, kept: bool) {
+        let i = bin as usize;
+        self.total[i] += 1;
+        if kept {
+            self.kept[i] += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &InsertSizeStats) {
+        for i in 0..self.total.len() {
+            self.total[i] += other.total[i];
+            self.kept[i] += other.kept[i];
+        }
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        println!("\n=== Pass Rate by Insert Size ===");
+        println!(
+            "{:<10} {:>12} {:>12} {:>12} {:>10}",
+            "Bin", "Total", "Kept", "Removed", "Pass rate"
+        );
+        for bin in InsertSizeBin::ALL {
+            let i = bin as usize;
+            let (total, kept) = (self.total[i], self.kept[i]);
+            let rate = if total > 0 {
+                format.share(kept, total)
+            } else {
+                "-".to_string()
+            };
+            println!(
+                "{:<10} {:>12} {:>12} {:>12} {:>10}",
+                bin.label(),
+                format.count(total),
+                format.count(kept),
+                format.count(total - kept),
+                rate
+            );
+        }
+    }
+}
+
+/// Whether a same-reference pair deviates from forward/reverse orientation
+///
+/// Proper Illumina pairs have the leftmost mate on the forward strand and the
+/// other mate on the reverse strand; FF, RR and RF pairs are abnormal.
+pub fn is_abnormal_orientation(record1: &bam::Record, record2: &bam::Record) -> bool {
+    let (left, right) = if record1.pos() <= record2.pos() {
+        (record1, record2)
+    } else {
+        (record2, record1)
+    };
+    left.is_reverse() || !right.is_reverse()
+}
+
+/// Chimera indicators over a set of pairs
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+struct ChimeraCounts {
+    /// Pairs with both mates mapped (the denominator)
+    mapped_pairs: u64,
+    inter_chromosomal: u64,
+    abnormal_orientation: u64,
+}
+
+impl ChimeraCounts {
+    fn add(&mut self, trans: bool, abnormal: bool) {
+        self.mapped_pairs += 1;
+        self.inter_chromosomal += trans as u64;
+        self.abnormal_orientation += abnormal as u64;
+    }
+
+    fn merge(&mut self, other: &ChimeraCounts) {
+        self.mapped_pairs += other.mapped_pairs;
+        self.inter_chromosomal += other.inter_chromosomal;
+        self.abnormal_orientation += other.abnormal_orientation;
+    }
+
+    fn fraction(&self, count: u64, format: NumberFormat) -> String {
+        if self.mapped_pairs == 0 {
+            return "-".to_string();
+        }
+        format!(
+            "{} ({})",
+            format.count(count),
+            format.share(count, self.mapped_pairs)
+        )
+    }
+}
+
+/// Inter-chromosomal and abnormal-orientation rates before and after filtering
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChimeraStats {
+    input: ChimeraCounts,
+    kept: ChimeraCounts,
+}
+
+impl ChimeraStats {
+    pub fn record(&mut self, record1: &bam::Record, record2: &bam::Record, kept: bool) {
+        if record1.is_unmapped() || record2.is_unmapped() {
+            return;
+        }
+
+        let trans = record1.tid() != record2.tid();
+        let abnormal = !trans && is_abnormal_orientation(record1, record2);
+
+        self.input.add(trans, abnormal);
+        if kept {
+            self.kept.add(trans, abnormal);
+        }
+    }
+
+    pub fn merge(&mut self, other: &ChimeraStats) {
+        self.input.merge(&other.input);
+        self.kept.merge(&other.kept);
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        println!("\n=== Chimeric Pairs (of pairs with both mates mapped) ===");
+        println!("{:<22} {:>20} {:>20}", "", "Input", "Kept");
+        println!(
+            "{:<22} {:>20} {:>20}",
+            "Mapped pairs",
+            format.count(self.input.mapped_pairs),
+            format.count(self.kept.mapped_pairs)
+        );
+        println!(
+            "{:<22} {:>20} {:>20}",
+            "Inter-chromosomal",
+            self.input.fraction(self.input.inter_chromosomal, format),
+            self.kept.fraction(self.kept.inter_chromosomal, format)
+        );
+        println!(
+            "{:<22} {:>20} {:>20}",
+            "Abnormal orientation",
+            self.input.fraction(self.input.abnormal_orientation, format),
+            self.kept.fraction(self.kept.abnormal_orientation, format)
+        );
+    }
+}
+
+/// Clip lengths at or above this share the last histogram bin
+pub const MAX_CLIP_BIN: usize = 1000;
+
+/// Read ends, in the orientation the read was sequenced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadEnd {
+    FivePrime,
+    ThreePrime,
+}
+
+impl ReadEnd {
+    pub fn label(self) -> &'static str {
+        match self {
+            ReadEnd::FivePrime => "5'",
+            ReadEnd::ThreePrime => "3'",
+        }
+    }
+}
+
+/// Soft-clipped bases at the 5' and 3' ends of a mapped read
+///
+/// Reverse-strand alignments are stored reverse-complemented, so their
+/// leading clip is at the 3' end.
+pub fn soft_clips(record: &bam::Record) -> (u32, u32) {
+    let cigar = record.cigar();
+    let soft = |op: Option<&Cigar>| match op {
+        Some(Cigar::SoftClip(len)) => *len,
+        _ => 0,
+    };
+    // Hard clips, if any, sit outside the soft clips
+    let mut ops = cigar.iter().filter(|op| !matches!(op, Cigar::HardClip(_)));
+    let leading = soft(ops.next());
+    let trailing = soft(ops.next_back());
+    if record.is_reverse() {
+        (trailing, leading)
+    } else {
+        (leading, trailing)
+    }
+}
+
+/// Reads per clip length at one end; index 0 counts unclipped reads
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct ClipHistogram {
+    reads: Vec<u64>,
+}
+
+impl ClipHistogram {
+    fn add(&mut self, len: u32) {
+        let bin = (len as usize).min(MAX_CLIP_BIN);
+        if self.reads.len() <= bin {
+            self.reads.resize(bin + 1, 0);
+        }
+        self.reads[bin] += 1;
+    }
+
+    fn merge(&mut self, other: &ClipHistogram) {
+        if self.reads.len() < other.reads.len() {
+            self.reads.resize(other.reads.len(), 0);
+        }
+        for (ours, theirs) in self.reads.iter_mut().zip(&other.reads) {
+            *ours += theirs;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.reads.iter().sum()
+    }
+
+    fn clipped(&self) -> u64 {
+        self.reads.iter().skip(1).sum()
+    }
+
+    /// Mean length of the clips present, with the last bin at its floor
+    fn mean_clip(&self) -> f64 {
+        let bases: u64 = self
+            .reads
+            .iter()
+            .enumerate()
+            .map(|(len, &reads)| len as u64 * reads)
+            .sum();
+        match self.clipped() {
+            0 => 0.0,
+            clipped => bases as f64 / clipped as f64,
+        }
+    }
+}
+
+/// Soft-clip length distributions at both read ends, kept vs removed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ClipStats {
+    /// Indexed by [`ReadEnd`], then kept (0) or removed (1)
+    ends: [[ClipHistogram; 2]; 2],
+}
+
+impl ClipStats {
+    /// Count the mapped mates of a pair
+    pub fn record(&mut self, record1: &bam::Record, record2: &bam::Record, kept: bool) {
+        let fate = !kept as usize;
+        for record in [record1, record2] {
+            if record.is_unmapped() {
+                continue;
+            }
+            let (five, three) = soft_clips(record);
+            self.ends[ReadEnd::FivePrime as usize][fate].add(five);
+            self.ends[ReadEnd::ThreePrime as usize][fate].add(three);
+        }
+    }
+
+    pub fn merge(&mut self, other: &ClipStats) {
+        for (ours, theirs) in self
+            .ends
+            .iter_mut()
+            .flatten()
+            .zip(other.ends.iter().flatten())
+        {
+            ours.merge(theirs);
+        }
+    }
+
+    fn histogram(&self, end: ReadEnd, kept: bool) -> &ClipHistogram {
+        &self.ends[end as usize][!kept as usize]
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        let clipped = |histogram: &ClipHistogram| match histogram.total() {
+            0 => "-".to_string(),
+            total => format!(
+                "{}, mean {:.1} bp",
+                format.share(histogram.clipped(), total),
+                histogram.mean_clip()
+            ),
+        };
+        println!("\n=== Soft Clips (of mapped reads) ===");
+        println!("{:<14} {:>24} {:>24}", "Clipped", "Kept", "Removed");
+        for end in [ReadEnd::FivePrime, ReadEnd::ThreePrime] {
+            println!(
+                "{:<14} {:>24} {:>24}",
+                format!("{} end", end.label()),
+                clipped(self.histogram(end, true)),
+                clipped(self.histogram(end, false))
+            );
+        }
+
+        // A clear excess at one end points at the cause
+        let clipped_at =
+            |end| self.histogram(end, true).clipped() + self.histogram(end, false).clipped();
+        let (five, three) = (
+            clipped_at(ReadEnd::FivePrime),
+            clipped_at(ReadEnd::ThreePrime),
+        );
+        if three > 2 * five && three > 0 {
+            println!("Clipping is 3'-biased, as from adapter read-through");
+        } else if five > 2 * three && five > 0 {
+            println!("Clipping is 5'-biased, as from barcode or UMI remnants");
+        }
+    }
+
+    /// Write one row per end and clip length with any reads
+    pub fn write_tsv(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+
+        writeln!(out, "end\tclip_length\tkept\tremoved")?;
+        for end in [ReadEnd::FivePrime, ReadEnd::ThreePrime] {
+            let (kept, removed) = (self.histogram(end, true), self.histogram(end, false));
+            for len in 0..kept.reads.len().max(removed.reads.len()) {
+                let count =
+                    |histogram: &ClipHistogram| histogram.reads.get(len).copied().unwrap_or(0);
+                let (k, r) = (count(kept), count(removed));
+                if k + r == 0 {
+                    continue;
+                }
+                let label = if len == MAX_CLIP_BIN {
+                    format!("{}+", MAX_CLIP_BIN)
+                } else {
+                    len.to_string()
+                };
+                writeln!(out, "{}\t{}\t{}\t{}", end.label(), label, k, r)?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// GC content of a fragment's called bases (both mates), or `None` when it
+/// has only `N`s
+pub fn fragment_gc(records: &[&bam::Record]) -> Option<f64> {
+    let (mut gc, mut called) = (0u64, 0u64);
+    for record in records {
+        let seq = record.seq();
+        for i in 0..seq.len() {
+            // 4-bit codes: A=1, C=2, G=4, T=8
+            match seq.encoded_base(i) {
+                2 | 4 => {
+                    gc += 1;
+                    called += 1;
+                }
+                1 | 8 => called += 1,
+                _ => {}
+            }
+        }
+    }
+    (called > 0).then(|| gc as f64 / called as f64)
+}
+
+/// GC percentages grouped into one row of the printed table
+const GC_TABLE_STEP: usize = 10;
+
+/// Fragments per whole GC percentage (0-100), kept vs removed
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GcStats {
+    kept: Vec<u64>,
+    removed: Vec<u64>,
+}
+
+impl GcStats {
+    /// Count a fragment from its mates (one record for single-end reads)
+    pub fn record(&mut self, records: &[&bam::Record], kept: bool) {
+        let Some(gc) = fragment_gc(records) else {
+            return;
+        };
+        let bins = if kept {
+            &mut self.kept
+        } else {
+            &mut self.removed
+        };
+        bins.resize(101, 0);
+        bins[(gc * 100.0).round() as usize] += 1;
+    }
+
+    pub fn merge(&mut self, other: &GcStats) {
+        for (ours, theirs) in [
+            (&mut self.kept, &other.kept),
+            (&mut self.removed, &other.removed),
+        ] {
+            if ours.len() < theirs.len() {
+                ours.resize(theirs.len(), 0);
+            }
+            for (ours, theirs) in ours.iter_mut().zip(theirs) {
+                *ours += theirs;
+            }
+        }
+    }
+
+    fn count(bins: &[u64], percent: usize) -> u64 {
+        bins.get(percent).copied().unwrap_or(0)
+    }
+
+    /// Mean GC percentage of the fragments in `bins`
+    fn mean(bins: &[u64]) -> Option<f64> {
+        let total: u64 = bins.iter().sum();
+        let sum: u64 = bins
+            .iter()
+            .enumerate()
+            .map(|(percent, &count)| percent as u64 * count)
+            .sum();
+        (total > 0).then(|| sum as f64 / total as f64)
+    }
+
+    /// How far filtering moves the mean GC, in percentage points
+    pub fn mean_shift(&self) -> Option<f64> {
+        let input: Vec<u64> = (0..=100)
+            .map(|percent| Self::count(&self.kept, percent) + Self::count(&self.removed, percent))
+            .collect();
+        Some(Self::mean(&self.kept)? - Self::mean(&input)?)
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        println!("\n=== GC Content of Fragments ===");
+        println!(
+            "{:<10} {:>12} {:>12} {:>10}",
+            "GC", "Kept", "Removed", "Pass rate"
+        );
+        for start in (0..100).step_by(GC_TABLE_STEP) {
+            // The last row takes 100% as well
+            let end = if start + GC_TABLE_STEP == 100 {
+                101
+            } else {
+                start + GC_TABLE_STEP
+            };
+            let sum = |bins: &[u64]| (start..end).map(|p| Self::count(bins, p)).sum::<u64>();
+            let (kept, removed) = (sum(&self.kept), sum(&self.removed));
+            let rate = match kept + removed {
+                0 => "-".to_string(),
+                total => format.share(kept, total),
+            };
+            println!(
+                "{:<10} {:>12} {:>12} {:>10}",
+                format!("{}-{}%", start, start + GC_TABLE_STEP),
+                format.count(kept),
+                format.count(removed),
+                rate
+            );
+        }
+        let mean = |bins: &[u64]| match Self::mean(bins) {
+            Some(mean) => format.percent(mean),
+            None => "-".to_string(),
+        };
+        println!(
+            "Mean GC: kept {}, removed {}",
+            mean(&self.kept),
+            mean(&self.removed)
+        );
+        if let Some(shift) = self.mean_shift() {
+            if shift.abs() >= 1.0 {
+                println!(
+                    "Filtering moves the mean GC by {:+.1} points; check coverage GC bias before CNV calling",
+                    shift
+                );
+            }
+        }
+    }
+
+    /// Write one row per GC percentage, empty ones included
+    pub fn write_tsv(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+
+        writeln!(out, "gc_percent\tkept\tremoved")?;
+        for percent in 0..=100 {
+            writeln!(
+                out,
+                "{}\t{}\t{}",
+                percent,
+                Self::count(&self.kept, percent),
+                Self::count(&self.removed, percent)
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Integer value of an aux tag, whatever its stored width
+pub fn aux_integer(record: &bam::Record, tag: &[u8]) -> Option<i64> {
+    match record.aux(tag) {
+        Ok(Aux::I8(v)) => Some(v as i64),
+        Ok(Aux::U8(v)) => Some(v as i64),
+        Ok(Aux::I16(v)) => Some(v as i64),
+        Ok(Aux::U16(v)) => Some(v as i64),
+        Ok(Aux::I32(v)) => Some(v as i64),
+        Ok(Aux::U32(v)) => Some(v as i64),
+        _ => None,
+    }
+}
+
+/// Per-record summary numbers in the spirit of `samtools stats` SN lines
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SequenceStats {
+    raw_total: u64,
+    first_fragments: u64,
+    last_fragments: u64,
+    mapped: u64,
+    mapped_and_paired: u64,
+    paired: u64,
+    properly_paired: u64,
+    mq0: u64,
+    duplicated: u64,
+    qc_failed: u64,
+    total_length: u64,
+    bases_mapped_cigar: u64,
+    mismatches: u64,
+    /// Mapped reads with neither an NM tag nor an `=`/`X` CIGAR; the error
+    /// rate is only derivable when 0
+    mapped_without_nm: u64,
+    quality_sum: u64,
+    quality_bases: u64,
+    /// Reads whose QUAL is `*`; they are left out of the average quality
+    #[serde(default)]
+    without_quality: u64,
+    different_chromosomes: u64,
+}
+
+impl SequenceStats {
+    pub fn record(&mut self, record: &bam::Record) {
+        self.raw_total += 1;
+        self.total_length += record.seq_len() as u64;
+        self.first_fragments += record.is_first_in_template() as u64;
+        self.last_fragments += record.is_last_in_template() as u64;
+        self.paired += record.is_paired() as u64;
+        self.duplicated += record.is_duplicate() as u64;
+        self.qc_failed += record.is_quality_check_failed() as u64;
+
+        if has_qualities(record) {
+            let qual = record.qual();
+            self.quality_sum += qual.iter().map(|&q| q as u64).sum::<u64>();
+            self.quality_bases += qual.len() as u64;
+        } else {
+            self.without_quality += 1;
+        }
+
+        if record.is_unmapped() {
+            return;
+        }
+
+        self.mapped += 1;
+        self.properly_paired += record.is_proper_pair() as u64;
+        self.mq0 += (record.mapq() == 0) as u64;
+        self.mapped_and_paired += (record.is_paired() && !record.is_mate_unmapped()) as u64;
+        if record.is_paired() && !record.is_mate_unmapped() && record.tid() != record.mtid() {
+            self.different_chromosomes += 1;
+        }
+
+        for op in record.cigar().iter() {
+            if let Cigar::Match(len) | Cigar::Ins(len) | Cigar::Equal(len) | Cigar::Diff(len) = op {
+                self.bases_mapped_cigar += *len as u64;
+            }
+        }
+
+        match metrics::edit_distance(record) {
+            Some(nm) => self.mismatches += nm.max(0) as u64,
+            None => self.mapped_without_nm += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &SequenceStats) {
+        self.raw_total += other.raw_total;
+        self.first_fragments += other.first_fragments;
+        self.last_fragments += other.last_fragments;
+        self.mapped += other.mapped;
+        self.mapped_and_paired += other.mapped_and_paired;
+        self.paired += other.paired;
+        self.properly_paired += other.properly_paired;
+        self.mq0 += other.mq0;
+        self.duplicated += other.duplicated;
+        self.qc_failed += other.qc_failed;
+        self.total_length += other.total_length;
+        self.bases_mapped_cigar += other.bases_mapped_cigar;
+        self.mismatches += other.mismatches;
+        self.mapped_without_nm += other.mapped_without_nm;
+        self.quality_sum += other.quality_sum;
+        self.quality_bases += other.quality_bases;
+        self.without_quality += other.without_quality;
+        self.different_chromosomes += other.different_chromosomes;
+    }
+
+    /// Write SN lines, followed by this tool's own counts of `unit` (pairs or reads)
+    pub fn write_sn(
+        &self,
+        path: &str,
+        unit: &str,
+        total_pairs: u64,
+        kept_pairs: u64,
+        interrupted: bool,
+    ) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+
+        let ratio = |num: u64, den: u64| {
+            if den > 0 {
+                num as f64 / den as f64
+            } else {
+                0.0
+            }
+        };
+
+        writeln!(
+            out,
+            "# Summary Numbers. Use `grep ^SN | cut -f 2-` to extract this part."
+        )?;
+        let mut sn = |key: &str, value: String| writeln!(out, "SN\t{}:\t{}", key, value);
+        sn("raw total sequences", self.raw_total.to_string())?;
+        sn("1st fragments", self.first_fragments.to_string())?;
+        sn("last fragments", self.last_fragments.to_string())?;
+        sn("reads mapped", self.mapped.to_string())?;
+        sn(
+            "reads mapped and paired",
+            self.mapped_and_paired.to_string(),
+        )?;
+        sn("reads unmapped", (self.raw_total - self.mapped).to_string())?;
+        sn("reads properly paired", self.properly_paired.to_string())?;
+        sn("reads paired", self.paired.to_string())?;
+        sn("reads duplicated", self.duplicated.to_string())?;
+        sn("reads MQ0", self.mq0.to_string())?;
+        sn("reads QC failed", self.qc_failed.to_string())?;
+        sn("total length", self.total_length.to_string())?;
+        sn("bases mapped (cigar)", self.bases_mapped_cigar.to_string())?;
+        if self.mapped_without_nm == 0 {
+            sn("mismatches", self.mismatches.to_string())?;
+            sn(
+                "error rate",
+                format!("{:.6e}", ratio(self.mismatches, self.bases_mapped_cigar)),
+            )?;
+        }
+        sn(
+            "average length",
+            format!("{:.0}", ratio(self.total_length, self.raw_total)),
+        )?;
+        // An average over no qualities is meaningless, like the error rate without NM
+        if self.quality_bases > 0 {
+            sn(
+                "average quality",
+                format!("{:.1}", ratio(self.quality_sum, self.quality_bases)),
+            )?;
+        }
+        sn(
+            "pairs on different chromosomes",
+            (self.different_chromosomes / 2).to_string(),
+        )?;
+
+        // Tool-specific lines, namespaced so they can't clash with samtools keys
+        sn(
+            &format!("filter_bam_pairs total {}", unit),
+            total_pairs.to_string(),
+        )?;
+        sn(
+            &format!("filter_bam_pairs kept {}", unit),
+            kept_pairs.to_string(),
+        )?;
+        sn(
+            &format!("filter_bam_pairs removed {}", unit),
+            (total_pairs - kept_pairs).to_string(),
+        )?;
+        sn(
+            "filter_bam_pairs pass rate",
+            format!("{:.6}", ratio(kept_pairs, total_pairs)),
+        )?;
+        sn(
+            "filter_bam_pairs reads without quality",
+            self.without_quality.to_string(),
+        )?;
+        sn(
+            "filter_bam_pairs interrupted",
+            (interrupted as u8).to_string(),
+        )?;
+
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! A flat summary of one run for workflow managers (`--stats-out`)
+//!
+//! Snakemake and Nextflow rules need numbers they can read without parsing
+//! the console report. The summary gives the totals and pass rate, the pairs
+//! (or reads) failing each filter that was enabled, percentiles of pair
+//! complexity and the runtime. `--stats-format json` writes one object,
+//! `tsv` a `metric<TAB>value` line per number with the same names, nested
+//! ones joined by a dot (`failed.complexity`, `complexity.p50`).
+//!
+//! A pair failing several filters is counted under each of them, so the
+//! failures can add up to more than the removed pairs. Unlike `--stats-json`
+//! the summary is not meant to be merged. [`RejectionCounts`] tells the
+//! removed pairs apart by cause for the report, which is.
+
+use crate::filter::Thresholds;
+use crate::histogram::ComplexityHistogram;
+use crate::units::NumberFormat;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Layout of the `--stats-out` file
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum StatsFormat {
+    #[default]
+    Json,
+    Tsv,
+}
+
+/// Bins per unit of the pair complexity histogram, whose percentiles are
+/// reported to 0.001
+pub const PERCENTILE_BINS: usize = 1000;
+
+/// Percentiles reported, as `p05` .. `p95`
+pub const PERCENTILES: [u32; 5] = [5, 25, 50, 75, 95];
+
+/// Pairs failing each threshold of [`Thresholds::NAMES`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FailureCounts([u64; Thresholds::NAMES.len()]);
+
+impl FailureCounts {
+    pub fn record(&mut self, failed: Thresholds) {
+        if failed.is_empty() {
+            return;
+        }
+        for (index, count) in self.0.iter_mut().enumerate() {
+            *count += failed.contains(index) as u64;
+        }
+    }
+
+    /// The counts of the thresholds in `enabled`, by name
+    pub fn of(&self, enabled: Thresholds) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        Thresholds::NAMES
+            .into_iter()
+            .zip(self.0)
+            .enumerate()
+            .filter(move |&(index, _)| enabled.contains(index))
+            .map(|(_, entry)| entry)
+    }
+}
+
+/// Removed pairs by the criteria they failed: complexity against the
+/// mapped-bases thresholds, and each threshold and filter both overall and as
+/// the only cause
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RejectionCounts {
+    /// Failing complexity but neither mapped-bases threshold
+    pub complexity_only: u64,
+    /// Failing `min_mapped` or `min_mapped_fraction` but not complexity
+    pub mapped_only: u64,
+    pub complexity_and_mapped: u64,
+    /// Removed pairs failing each threshold or filter; a pair failing
+    /// several is counted under each
+    pub failed: BTreeMap<String, u64>,
+    /// Removed pairs that failed this threshold or filter and nothing else
+    pub only: BTreeMap<String, u64>,
+    /// Removed pairs that failed more than one
+    pub several: u64,
+}
+
+impl RejectionCounts {
+    /// Count a removed pair that failed the thresholds in `failed` and the
+    /// `filters` (name and whether the pair passes) that say it fails
+    pub fn record(&mut self, failed: Thresholds, filters: &[(&'static str, bool)]) {
+        let complexity = failed.contains(Thresholds::COMPLEXITY);
+        let mapped = failed.contains(Thresholds::MIN_MAPPED)
+            || failed.contains(Thresholds::MIN_MAPPED_FRACTION);
+        match (complexity, mapped) {
+            (true, false) => self.complexity_only += 1,
+            (false, true) => self.mapped_only += 1,
+            (true, true) => self.complexity_and_mapped += 1,
+            (false, false) => {}
+        }
+        let mut causes = failed.names().chain(
+            filters
+                .iter()
+                .filter(|(_, pass)| !pass)
+                .map(|(name, _)| *name),
+        );
+        let Some(first) = causes.next() else {
+            return;
+        };
+        *self.failed.entry(first.to_string()).or_default() += 1;
+        let mut alone = true;
+        for cause in causes {
+            *self.failed.entry(cause.to_string()).or_default() += 1;
+            alone = false;
+        }
+        if alone {
+            *self.only.entry(first.to_string()).or_default() += 1;
+        } else {
+            self.several += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &RejectionCounts) {
+        self.complexity_only += other.complexity_only;
+        self.mapped_only += other.mapped_only;
+        self.complexity_and_mapped += other.complexity_and_mapped;
+        for (counts, others) in [
+            (&mut self.failed, &other.failed),
+            (&mut self.only, &other.only),
+        ] {
+            for (name, count) in others {
+                *counts.entry(name.clone()).or_default() += count;
+            }
+        }
+        self.several += other.several;
+    }
+
+    /// Print the breakdown of `removed` pairs, with shares of them
+    pub fn print(&self, unit: &str, removed: u64, format: NumberFormat) {
+        let title = if unit == "reads" { "Reads" } else { "Pairs" };
+        println!("\n=== Removed {} by Filter ===", title);
+        let share = |count: u64| {
+            if removed > 0 {
+                format.share(count, removed)
+            } else {
+                "-".to_string()
+            }
+        };
+        for (label, count) in [
+            ("Complexity only", self.complexity_only),
+            ("Mapped bases only", self.mapped_only),
+            ("Complexity and mapped bases", self.complexity_and_mapped),
+        ] {
+            println!(
+                "{:<28} {:>12} {:>10}",
+                label,
+                format.count(count),
+                share(count)
+            );
+        }
+        println!(
+            "\n{:<28} {:>12} {:>10} {:>12} {:>10}",
+            "Filter", "Failed", "Failed %", "Only this", "Only %"
+        );
+        for (name, &failed) in &self.failed {
+            let only = self.only.get(name).copied().unwrap_or(0);
+            println!(
+                "{:<28} {:>12} {:>10} {:>12} {:>10}",
+                name,
+                format.count(failed),
+                share(failed),
+                format.count(only),
+                share(only)
+            );
+        }
+        println!(
+            "{:<28} {:>12} {:>10} {:>12} {:>10}",
+            "(more than one)",
+            "",
+            "",
+            format.count(self.several),
+            share(self.several)
+        );
+    }
+}
+
+/// The numbers of `--stats-out`
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    /// "pairs", or "reads" for `--single-end`
+    pub unit: &'static str,
+    pub total: u64,
+    pub kept: u64,
+    pub removed: u64,
+    /// Kept as a fraction of the total (0 for an empty input)
+    pub pass_rate: f64,
+    /// Pairs failing each enabled filter, by option name
+    pub failed: BTreeMap<&'static str, u64>,
+    /// Pair complexity (the lower of the two mates) percentiles, `p05` ..
+    /// `p95`; empty for an empty input
+    pub complexity: BTreeMap<String, f64>,
+    pub runtime_seconds: f64,
+    pub interrupted: bool,
+    pub preview: bool,
+}
+
+impl Summary {
+    pub fn complexity_percentiles(histogram: &ComplexityHistogram) -> BTreeMap<String, f64> {
+        PERCENTILES
+            .iter()
+            .filter_map(|&percentile| {
+                let value = histogram.percentile(percentile)?;
+                Some((format!("p{:02}", percentile), value))
+            })
+            .collect()
+    }
+
+    pub fn write(&self, path: &str, format: StatsFormat) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+        match format {
+            StatsFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, self)?;
+                writeln!(out)?;
+            }
+            StatsFormat::Tsv => {
+                writeln!(out, "metric\tvalue")?;
+                writeln!(out, "unit\t{}", self.unit)?;
+                writeln!(out, "total\t{}", self.total)?;
+                writeln!(out, "kept\t{}", self.kept)?;
+                writeln!(out, "removed\t{}", self.removed)?;
+                writeln!(out, "pass_rate\t{:.6}", self.pass_rate)?;
+                for (filter, count) in &self.failed {
+                    writeln!(out, "failed.{}\t{}", filter, count)?;
+                }
+                for (percentile, value) in &self.complexity {
+                    writeln!(out, "complexity.{}\t{:.3}", percentile, value)?;
+                }
+                writeln!(out, "runtime_seconds\t{:.3}", self.runtime_seconds)?;
+                writeln!(out, "interrupted\t{}", self.interrupted)?;
+                writeln!(out, "preview\t{}", self.preview)?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
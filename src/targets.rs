@@ -0,0 +1,287 @@
+//! Per-interval pass rates for target panels (`--targets`)
+//!
+//! A pair counts towards every BED interval that either mapped mate overlaps,
+//! once per interval, so the removed pairs of a failing amplicon show up even
+//! when neighbouring targets pass.
+
+use crate::bed;
+use crate::units::NumberFormat;
+use anyhow::{bail, Context, Result};
+use rust_htslib::bam;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Targets listed with the lowest pass rates in the printed summary
+const WORST_TARGETS: usize = 5;
+
+/// Counts and metric sums of the pairs overlapping one interval
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetCounts {
+    pub name: String,
+    pub chrom: String,
+    /// 0-based, half-open as in the BED file
+    pub start: i64,
+    pub end: i64,
+    pub pairs: u64,
+    pub kept: u64,
+    /// Sum over pairs of the mean complexity of both mates
+    complexity_sum: f64,
+    /// Sum over pairs of the mean MAPQ of both mates
+    mapq_sum: f64,
+}
+
+impl TargetCounts {
+    fn key(&self) -> (String, String, i64, i64) {
+        (self.name.clone(), self.chrom.clone(), self.start, self.end)
+    }
+
+    fn mean(&self, sum: f64) -> f64 {
+        if self.pairs > 0 {
+            sum / self.pairs as f64
+        } else {
+            0.0
+        }
+    }
+
+    pub fn pass_rate(&self) -> f64 {
+        if self.pairs > 0 {
+            self.kept as f64 / self.pairs as f64
+        } else {
+            0.0
+        }
+    }
+
+    pub fn mean_complexity(&self) -> f64 {
+        self.mean(self.complexity_sum)
+    }
+
+    pub fn mean_mapq(&self) -> f64 {
+        self.mean(self.mapq_sum)
+    }
+}
+
+/// Pass rates per target interval, in BED order
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TargetStats {
+    targets: Vec<TargetCounts>,
+    off_target_pairs: u64,
+}
+
+/// Interval lookup for one reference sequence
+#[derive(Debug, Default)]
+struct ContigTargets {
+    /// `(start, end, index into TargetStats)`, sorted by start
+    intervals: Vec<(i64, i64, usize)>,
+    /// Longest interval, bounding how far left an overlap can start
+    max_len: i64,
+}
+
+/// BED intervals indexed by reference id
+#[derive(Debug)]
+pub struct Targets {
+    by_tid: Vec<ContigTargets>,
+}
+
+impl Targets {
+    /// Read a BED file, resolving chromosome names against the input header
+    ///
+    /// Intervals on sequences missing from the header are dropped with a
+    /// warning.
+    pub fn read_bed(path: &str, header: &bam::HeaderView) -> Result<(Targets, TargetStats)> {
+        let mut targets = Targets {
+            by_tid: (0..header.target_count())
+                .map(|_| ContigTargets::default())
+                .collect(),
+        };
+        let mut stats = TargetStats::default();
+        let mut unknown = 0;
+
+        for bed::BedLine {
+            chrom,
+            start,
+            end,
+            name,
+            ..
+        } in bed::read(path)?
+        {
+            let Some(tid) = header.tid(chrom.as_bytes()) else {
+                unknown += 1;
+                continue;
+            };
+
+            let contig = &mut targets.by_tid[tid as usize];
+            contig.intervals.push((start, end, stats.targets.len()));
+            contig.max_len = contig.max_len.max(end - start);
+            stats.targets.push(TargetCounts {
+                name: name.unwrap_or_else(|| format!("{}:{}-{}", chrom, start, end)),
+                chrom,
+                start,
+                end,
+                pairs: 0,
+                kept: 0,
+                complexity_sum: 0.0,
+                mapq_sum: 0.0,
+            });
+        }
+
+        if unknown > 0 {
+            eprintln!(
+                "Warning: {} target interval(s) in {} are on sequences not in the input header",
+                unknown, path
+            );
+        }
+        if stats.targets.is_empty() {
+            bail!("{} contains no usable target intervals", path);
+        }
+        for contig in &mut targets.by_tid {
+            contig.intervals.sort_unstable();
+        }
+        Ok((targets, stats))
+    }
+
+    /// Add the indices of the intervals a mapped record overlaps to `hits`
+    fn overlapping(&self, record: &bam::Record, hits: &mut Vec<usize>) {
+        if record.is_unmapped() || record.tid() < 0 {
+            return;
+        }
+        let Some(contig) = self.by_tid.get(record.tid() as usize) else {
+            return;
+        };
+        let (start, end) = (record.pos(), record.cigar().end_pos());
+        let before_end = contig
+            .intervals
+            .partition_point(|&(interval_start, _, _)| interval_start < end);
+        for &(interval_start, interval_end, index) in contig.intervals[..before_end].iter().rev() {
+            if interval_start + contig.max_len <= start {
+                break;
+            }
+            if interval_end > start {
+                hits.push(index);
+            }
+        }
+    }
+}
+
+impl TargetStats {
+    /// Count a pair towards every target either mate overlaps
+    pub fn record(
+        &mut self,
+        targets: &Targets,
+        record1: &bam::Record,
+        record2: &bam::Record,
+        complexity: [f64; 2],
+        kept: bool,
+    ) {
+        let mut hits = Vec::new();
+        targets.overlapping(record1, &mut hits);
+        targets.overlapping(record2, &mut hits);
+        if hits.is_empty() {
+            self.off_target_pairs += 1;
+            return;
+        }
+        hits.sort_unstable();
+        hits.dedup();
+
+        let mean_complexity = (complexity[0] + complexity[1]) / 2.0;
+        let mean_mapq = (record1.mapq() as f64 + record2.mapq() as f64) / 2.0;
+        for index in hits {
+            let target = &mut self.targets[index];
+            target.pairs += 1;
+            target.kept += kept as u64;
+            target.complexity_sum += mean_complexity;
+            target.mapq_sum += mean_mapq;
+        }
+    }
+
+    /// Add another run's counts, matching targets by name and coordinates
+    pub fn merge(&mut self, other: &TargetStats) {
+        let mut index: HashMap<(String, String, i64, i64), usize> = self
+            .targets
+            .iter()
+            .enumerate()
+            .map(|(i, target)| (target.key(), i))
+            .collect();
+        for theirs in &other.targets {
+            match index.get(&theirs.key()) {
+                Some(&i) => {
+                    let ours = &mut self.targets[i];
+                    ours.pairs += theirs.pairs;
+                    ours.kept += theirs.kept;
+                    ours.complexity_sum += theirs.complexity_sum;
+                    ours.mapq_sum += theirs.mapq_sum;
+                }
+                None => {
+                    index.insert(theirs.key(), self.targets.len());
+                    self.targets.push(theirs.clone());
+                }
+            }
+        }
+        self.off_target_pairs += other.off_target_pairs;
+    }
+
+    pub fn print(&self, format: NumberFormat) {
+        let on_target: u64 = self.targets.iter().map(|target| target.pairs).sum();
+        let empty = self
+            .targets
+            .iter()
+            .filter(|target| target.pairs == 0)
+            .count();
+
+        println!("\n=== Targets ===");
+        println!("Targets: {}", self.targets.len());
+        println!("Targets without pairs: {}", empty);
+        println!("Off-target pairs: {}", format.count(self.off_target_pairs));
+        if on_target == 0 {
+            return;
+        }
+
+        let mut worst: Vec<_> = self
+            .targets
+            .iter()
+            .filter(|target| target.pairs > 0)
+            .collect();
+        worst.sort_by(|a, b| a.pass_rate().total_cmp(&b.pass_rate()));
+        println!("Lowest pass rates:");
+        for target in worst.iter().take(WORST_TARGETS) {
+            println!(
+                "  {:<24} {:>8} pairs {:>8} kept  complexity {:.3}  MAPQ {:.1}",
+                target.name,
+                format.count(target.pairs),
+                format.percent(target.pass_rate() * 100.0),
+                target.mean_complexity(),
+                target.mean_mapq()
+            );
+        }
+    }
+
+    /// Write one row per target, in BED order
+    pub fn write_tsv(&self, path: &str) -> Result<()> {
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Cannot create {}", path))?;
+        let mut out = std::io::BufWriter::new(file);
+
+        writeln!(
+            out,
+            "name\tchrom\tstart\tend\tpairs\tkept\tremoved\tpass_rate\tmean_complexity\tmean_mapq"
+        )?;
+        for target in &self.targets {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.2}",
+                target.name,
+                target.chrom,
+                target.start,
+                target.end,
+                target.pairs,
+                target.kept,
+                target.pairs - target.kept,
+                target.pass_rate(),
+                target.mean_complexity(),
+                target.mean_mapq()
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+//! Synthetic records and in-memory filtering, for tests without fixture files
+//!
+//! Enabled by the `test_utils` feature.
+//!
+//! ```
+//! use filter_bam_pairs::filter::FilterConfig;
+//! use filter_bam_pairs::test_utils::{filter_records, mapped_pair, random_sequence};
+//!
+//! let seq = random_sequence(100, 1);
+//! let (r1, r2) = mapped_pair("read1", &seq, &seq);
+//! let run = filter_records(vec![r1.build(), r2.build()], &FilterConfig::default()).unwrap();
+//! assert_eq!(run.kept.len(), 1);
+//! ```
+
+use crate::filter::{self, FilterConfig, PairVerdict};
+use anyhow::{bail, Result};
+use rust_htslib::bam;
+use rust_htslib::bam::record::{Aux, CigarString};
+
+/// Phred quality given to every base unless set explicitly
+const DEFAULT_QUALITY: u8 = 30;
+
+#[derive(Debug, Clone)]
+enum TagValue {
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+/// Builds a `bam::Record` field by field
+///
+/// Fields default to an unplaced record: flags 0, no CIGAR, `tid`/`pos` -1,
+/// MAPQ 255 and quality 30 for every base.
+#[derive(Debug, Clone)]
+pub struct RecordBuilder {
+    qname: String,
+    seq: String,
+    qual: Option<Vec<u8>>,
+    cigar: Option<String>,
+    flags: u16,
+    tid: i32,
+    pos: i64,
+    mapq: u8,
+    mtid: i32,
+    mpos: i64,
+    insert_size: i64,
+    tags: Vec<([u8; 2], TagValue)>,
+}
+
+impl RecordBuilder {
+    pub fn new(qname: &str) -> Self {
+        RecordBuilder {
+            qname: qname.to_string(),
+            seq: String::new(),
+            qual: None,
+            cigar: None,
+            flags: 0,
+            tid: -1,
+            pos: -1,
+            mapq: 255,
+            mtid: -1,
+            mpos: -1,
+            insert_size: 0,
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn seq(mut self, seq: &str) -> Self {
+        self.seq = seq.to_string();
+        self
+    }
+
+    /// Phred qualities (not ASCII-encoded), one per base
+    pub fn qual(mut self, qual: &[u8]) -> Self {
+        self.qual = Some(qual.to_vec());
+        self
+    }
+
+    /// CIGAR in SAM notation, e.g. `10S90M`
+    pub fn cigar(mut self, cigar: &str) -> Self {
+        self.cigar = Some(cigar.to_string());
+        self
+    }
+
+    /// SAM FLAG, replacing any flags set before
+    pub fn flags(mut self, flags: u16) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Reference index and 0-based position
+    pub fn pos(mut self, tid: i32, pos: i64) -> Self {
+        self.tid = tid;
+        self.pos = pos;
+        self
+    }
+
+    pub fn mapq(mut self, mapq: u8) -> Self {
+        self.mapq = mapq;
+        self
+    }
+
+    /// Mate reference index and 0-based position
+    pub fn mate_pos(mut self, mtid: i32, mpos: i64) -> Self {
+        self.mtid = mtid;
+        self.mpos = mpos;
+        self
+    }
+
+    /// Template length (TLEN)
+    pub fn insert_size(mut self, insert_size: i64) -> Self {
+        self.insert_size = insert_size;
+        self
+    }
+
+    pub fn tag_int(mut self, tag: &[u8; 2], value: i32) -> Self {
+        self.tags.push((*tag, TagValue::Int(value)));
+        self
+    }
+
+    pub fn tag_float(mut self, tag: &[u8; 2], value: f32) -> Self {
+        self.tags.push((*tag, TagValue::Float(value)));
+        self
+    }
+
+    pub fn tag_str(mut self, tag: &[u8; 2], value: &str) -> Self {
+        self.tags.push((*tag, TagValue::String(value.to_string())));
+        self
+    }
+
+    /// Assemble the record
+    ///
+    /// # Panics
+    ///
+    /// On an unparseable CIGAR, a quality string of the wrong length or a
+    /// duplicate tag.
+    pub fn build(&self) -> bam::Record {
+        let cigar = self.cigar.as_deref().map(|cigar| {
+            CigarString::try_from(cigar).unwrap_or_else(|_| panic!("invalid CIGAR {}", cigar))
+        });
+        let qual = match &self.qual {
+            Some(qual) => {
+                assert_eq!(qual.len(), self.seq.len(), "one quality per base");
+                qual.clone()
+            }
+            None => vec![DEFAULT_QUALITY; self.seq.len()],
+        };
+
+        let mut record = bam::Record::new();
+        record.set(
+            self.qname.as_bytes(),
+            cigar.as_ref(),
+            self.seq.as_bytes(),
+            &qual,
+        );
+        record.set_flags(self.flags);
+        record.set_tid(self.tid);
+        record.set_pos(self.pos);
+        record.set_mapq(self.mapq);
+        record.set_mtid(self.mtid);
+        record.set_mpos(self.mpos);
+        record.set_insert_size(self.insert_size);
+        for (tag, value) in &self.tags {
+            let aux = match value {
+                TagValue::Int(v) => Aux::I32(*v),
+                TagValue::Float(v) => Aux::Float(*v),
+                TagValue::String(v) => Aux::String(v),
+            };
+            record
+                .push_aux(tag, aux)
+                .unwrap_or_else(|e| panic!("cannot add tag: {}", e));
+        }
+        record
+    }
+}
+
+/// A properly paired FR pair on reference 0
+///
+/// The first mate maps forward at 1000 and the second reverse at 1200, both
+/// fully matched (`<len>M`) with MAPQ 60.
+pub fn mapped_pair(qname: &str, seq1: &str, seq2: &str) -> (RecordBuilder, RecordBuilder) {
+    let (pos1, pos2) = (1000, 1200);
+    let insert = pos2 + seq2.len() as i64 - pos1;
+    let record1 = RecordBuilder::new(qname)
+        .seq(seq1)
+        .cigar(&format!("{}M", seq1.len()))
+        .flags(0x1 | 0x2 | 0x20 | 0x40)
+        .pos(0, pos1)
+        .mapq(60)
+        .mate_pos(0, pos2)
+        .insert_size(insert);
+    let record2 = RecordBuilder::new(qname)
+        .seq(seq2)
+        .cigar(&format!("{}M", seq2.len()))
+        .flags(0x1 | 0x2 | 0x10 | 0x80)
+        .pos(0, pos2)
+        .mapq(60)
+        .mate_pos(0, pos1)
+        .insert_size(-insert);
+    (record1, record2)
+}
+
+/// A pair with both mates unmapped (FLAG 77 / 141)
+pub fn unmapped_pair(qname: &str, seq1: &str, seq2: &str) -> (RecordBuilder, RecordBuilder) {
+    let record1 = RecordBuilder::new(qname)
+        .seq(seq1)
+        .flags(0x1 | 0x4 | 0x8 | 0x40)
+        .mapq(0);
+    let record2 = RecordBuilder::new(qname)
+        .seq(seq2)
+        .flags(0x1 | 0x4 | 0x8 | 0x80)
+        .mapq(0);
+    (record1, record2)
+}
+
+/// A reproducible pseudo-random ACGT sequence (high complexity for len >> 21)
+pub fn random_sequence(len: usize, seed: u64) -> String {
+    let mut state = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        })
+        .collect()
+}
+
+/// Result of filtering records held in memory
+#[derive(Debug, Default)]
+pub struct InMemoryRun {
+    pub kept: Vec<(bam::Record, bam::Record)>,
+    pub rejected: Vec<(bam::Record, bam::Record)>,
+    /// One verdict per input pair, in input order
+    pub verdicts: Vec<PairVerdict>,
+    /// Metric values taken from cached `xc`/`xm` tags
+    pub cached_metrics: u64,
+}
+
+/// Filter records in input order, two at a time, as the binary does for a BAM
+///
+/// Fails like the binary on mismatched mate names. A trailing record without
+/// a mate, which the binary warns about and leaves out, is an error here, as
+/// in a test it means the records were built wrong.
+pub fn filter_records(records: Vec<bam::Record>, config: &FilterConfig) -> Result<InMemoryRun> {
+    config.validate()?;
+    if !records.len().is_multiple_of(2) {
+        bail!("unpaired read at end of input");
+    }
+
+    let mut run = InMemoryRun::default();
+    let mut records = records.into_iter();
+    while let (Some(record1), Some(record2)) = (records.next(), records.next()) {
+        filter::check_pair_names(&record1, &record2)?;
+        let verdict = config.evaluate(&record1, &record2, &mut run.cached_metrics);
+        run.verdicts.push(verdict);
+        if verdict.keep {
+            run.kept.push((record1, record2));
+        } else {
+            run.rejected.push((record1, record2));
+        }
+    }
+    Ok(run)
+}
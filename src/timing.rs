@@ -0,0 +1,182 @@
+//! Per-stage wall time, CPU time and peak memory of the main loop
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Pipeline stages that time is attributed to
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    /// Extra passes over the input before filtering (e.g. BX counting)
+    Prepass,
+    /// Decoding records from the input
+    Read,
+    /// Computing metrics, filter decisions and statistics
+    Metrics,
+    /// Encoding kept and rejected pairs
+    Write,
+    /// Flushing outputs and joining writer threads
+    Finish,
+}
+
+impl Stage {
+    const ALL: [Stage; 5] = [
+        Stage::Prepass,
+        Stage::Read,
+        Stage::Metrics,
+        Stage::Write,
+        Stage::Finish,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Prepass => "prepass",
+            Stage::Read => "read",
+            Stage::Metrics => "metrics",
+            Stage::Write => "write",
+            Stage::Finish => "finish",
+        }
+    }
+}
+
+/// Time spent in one stage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageTime {
+    pub stage: String,
+    pub wall_seconds: f64,
+    /// CPU time of the main thread; shard writer threads are not included
+    pub cpu_seconds: f64,
+    /// Process peak resident set size at the end of the stage's last lap
+    pub peak_rss_kb: u64,
+}
+
+/// CPU time of the calling thread and the process peak RSS
+#[cfg(unix)]
+fn resource_usage() -> (Duration, u64) {
+    // SAFETY: an all-zero rusage is a valid value for getrusage to overwrite
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    #[cfg(target_os = "linux")]
+    let who = libc::RUSAGE_THREAD;
+    #[cfg(not(target_os = "linux"))]
+    let who = libc::RUSAGE_SELF;
+    // SAFETY: getrusage only writes into the provided struct
+    if unsafe { libc::getrusage(who, &mut usage) } != 0 {
+        return (Duration::ZERO, 0);
+    }
+
+    let cpu = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    // ru_maxrss is in bytes on macOS and in kilobytes elsewhere
+    let rss_kb = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64 / 1024
+    } else {
+        usage.ru_maxrss as u64
+    };
+    (cpu(usage.ru_utime) + cpu(usage.ru_stime), rss_kb)
+}
+
+/// Not measured outside Unix; stages report wall time only
+#[cfg(not(unix))]
+fn resource_usage() -> (Duration, u64) {
+    (Duration::ZERO, 0)
+}
+
+/// Attributes the time between consecutive laps to stages
+///
+/// A disabled timer does nothing, so the loop can call `lap` unconditionally
+/// without paying for the system calls.
+#[derive(Debug)]
+pub struct StageTimer {
+    enabled: bool,
+    last_wall: Instant,
+    last_cpu: Duration,
+    times: [(Duration, Duration, u64); 5],
+}
+
+impl StageTimer {
+    pub fn new(enabled: bool) -> Self {
+        let (cpu, _) = if enabled {
+            resource_usage()
+        } else {
+            (Duration::ZERO, 0)
+        };
+        StageTimer {
+            enabled,
+            last_wall: Instant::now(),
+            last_cpu: cpu,
+            times: [(Duration::ZERO, Duration::ZERO, 0); 5],
+        }
+    }
+
+    /// Charge everything since the previous lap to `stage`
+    pub fn lap(&mut self, stage: Stage) {
+        if !self.enabled {
+            return;
+        }
+        let wall = Instant::now();
+        let (cpu, rss_kb) = resource_usage();
+
+        let entry = &mut self.times[stage as usize];
+        entry.0 += wall - self.last_wall;
+        entry.1 += cpu.saturating_sub(self.last_cpu);
+        entry.2 = entry.2.max(rss_kb);
+
+        self.last_wall = wall;
+        self.last_cpu = cpu;
+    }
+
+    /// Stage times for the report, or `None` when timing is disabled
+    pub fn stage_times(&self) -> Option<Vec<StageTime>> {
+        self.enabled.then(|| {
+            Stage::ALL
+                .iter()
+                .map(|&stage| {
+                    let (wall, cpu, rss_kb) = self.times[stage as usize];
+                    StageTime {
+                        stage: stage.name().to_string(),
+                        wall_seconds: wall.as_secs_f64(),
+                        cpu_seconds: cpu.as_secs_f64(),
+                        peak_rss_kb: rss_kb,
+                    }
+                })
+                .collect()
+        })
+    }
+}
+
+/// Add the stage times of another run, matching stages by name
+///
+/// Times are summed (total work across runs); peak RSS is the largest seen.
+pub fn merge_stage_times(into: &mut Vec<StageTime>, other: &[StageTime]) {
+    for time in other {
+        match into.iter_mut().find(|t| t.stage == time.stage) {
+            Some(t) => {
+                t.wall_seconds += time.wall_seconds;
+                t.cpu_seconds += time.cpu_seconds;
+                t.peak_rss_kb = t.peak_rss_kb.max(time.peak_rss_kb);
+            }
+            None => into.push(time.clone()),
+        }
+    }
+}
+
+pub fn print_stage_times(times: &[StageTime]) {
+    println!("\n=== Stage Timing ===");
+    println!(
+        "{:<10} {:>12} {:>12} {:>8} {:>14}",
+        "Stage", "Wall (s)", "CPU (s)", "CPU %", "Peak RSS (MB)"
+    );
+    for time in times {
+        let cpu_percent = if time.wall_seconds > 0.0 {
+            format!("{:.0}%", time.cpu_seconds / time.wall_seconds * 100.0)
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "{:<10} {:>12.3} {:>12.3} {:>8} {:>14.1}",
+            time.stage,
+            time.wall_seconds,
+            time.cpu_seconds,
+            cpu_percent,
+            time.peak_rss_kb as f64 / 1024.0
+        );
+    }
+}
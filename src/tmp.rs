@@ -0,0 +1,141 @@
+//! Temporary files and process limits for spilling subsystems
+//!
+//! On Windows, htslib opens spill files through the C runtime, which stops
+//! at `MAX_PATH` unless long paths are enabled system-wide, so a work
+//! directory too deep for its spill names is refused up front. A file there
+//! cannot be removed while anything holds it open, so removal is retried
+//! briefly for scanners and indexers that look at files just closed.
+
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Longest path the Windows C runtime opens (`MAX_PATH` less the terminator)
+const WINDOWS_MAX_PATH: usize = 259;
+
+/// Room for the longest file name a subsystem creates in the work directory
+const SPILL_NAME_ROOM: usize = 32;
+
+/// Attempts at removing the work directory
+const REMOVE_ATTEMPTS: u32 = if cfg!(windows) { 10 } else { 1 };
+
+const REMOVE_RETRY: Duration = Duration::from_millis(100);
+
+/// Open files allowed outside Unix, where there is no limit to raise: the
+/// most low-level handles the Windows C runtime hands out
+#[cfg(not(unix))]
+const NON_UNIX_OPEN_FILES: u64 = 8192;
+
+/// Root for temporary files: `--tmp-dir`, else `$TMPDIR`, else the system default
+pub fn tmp_root(tmp_dir: Option<&str>) -> PathBuf {
+    match tmp_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::temp_dir(),
+    }
+}
+
+/// A private scratch directory, removed with its contents when dropped
+///
+/// Dropping happens on normal completion and when a run bails out with an
+/// error, so failed runs don't leave spill files behind on shared nodes.
+pub struct WorkDir {
+    path: PathBuf,
+}
+
+/// The work directory a run would create under `root`
+fn work_dir_path(root: &Path) -> PathBuf {
+    root.join(format!("filter_bam_pairs.{}", std::process::id()))
+}
+
+/// Fail when spill files under `root` would be too deep to open on Windows
+pub fn check_path_length(root: &Path) -> Result<()> {
+    if !cfg!(windows) {
+        return Ok(());
+    }
+    let absolute = std::path::absolute(work_dir_path(root))?;
+    let len = absolute.as_os_str().len() + 1 + SPILL_NAME_ROOM;
+    if len > WINDOWS_MAX_PATH {
+        bail!(
+            "Temporary files under {} would exceed the Windows path limit of {} characters; \
+             pass a shorter --tmp-dir such as C:\\tmp",
+            root.display(),
+            WINDOWS_MAX_PATH
+        );
+    }
+    Ok(())
+}
+
+impl WorkDir {
+    pub fn create(root: &Path) -> Result<Self> {
+        check_path_length(root)?;
+        let path = work_dir_path(root);
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Cannot create temporary directory {}", path.display()))?;
+        Ok(WorkDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        for attempt in 1..=REMOVE_ATTEMPTS {
+            match std::fs::remove_dir_all(&self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound && attempt < REMOVE_ATTEMPTS => {
+                    std::thread::sleep(REMOVE_RETRY)
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Raise the soft open-file limit to `wanted` (capped at the hard limit)
+///
+/// Returns the limit now in effect, which bounds how many spill files may be
+/// open at once.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // rlim_t is not u64 on every platform
+pub fn raise_open_file_limit(wanted: Option<u64>) -> Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes into the provided struct
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Cannot query open-file limit");
+    }
+
+    let Some(wanted) = wanted else {
+        return Ok(limit.rlim_cur as u64);
+    };
+    let target = (wanted as libc::rlim_t).min(limit.rlim_max);
+    if target > limit.rlim_cur {
+        limit.rlim_cur = target;
+        // SAFETY: setrlimit only reads the provided struct
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("Cannot raise open-file limit");
+        }
+    }
+    if (wanted as libc::rlim_t) > limit.rlim_max {
+        eprintln!(
+            "Warning: --max-open-files {} exceeds the hard limit; using {}",
+            wanted, limit.rlim_max
+        );
+    }
+    Ok(limit.rlim_cur as u64)
+}
+
+/// Without file limits to raise, the limit is what the C runtime allows
+#[cfg(not(unix))]
+pub fn raise_open_file_limit(wanted: Option<u64>) -> Result<u64> {
+    if wanted.is_some_and(|wanted| wanted > NON_UNIX_OPEN_FILES) {
+        eprintln!(
+            "Warning: --max-open-files exceeds the {} files this system allows",
+            NON_UNIX_OPEN_FILES
+        );
+    }
+    Ok(NON_UNIX_OPEN_FILES)
+}
@@ -0,0 +1,253 @@
+//! Decision traces for named reads (`--trace-qname`)
+//!
+//! "Why was this read removed?" is hard to answer from the totals. For each
+//! read name given, the run prints to stderr every threshold the pair was
+//! judged on, with each mate's value, the limit it was held to and the
+//! outcome, then the run-level filters (name lists, kmer models, depth,
+//! barcodes, amplicons) and the final verdict. Values are recomputed exactly
+//! for the trace; a threshold that `--short-circuit` or a failure before the
+//! filter expression never reached is marked `not evaluated`.
+
+use crate::filter::{self, FilterConfig, PairVerdict, ShortReadPolicy, Thresholds};
+use crate::names::list_name;
+use crate::{metrics, quality};
+use rust_htslib::bam;
+use std::collections::HashMap;
+
+/// The read names to trace, and whether each was seen
+#[derive(Debug, Default)]
+pub struct Tracer {
+    names: HashMap<Vec<u8>, bool>,
+}
+
+impl Tracer {
+    /// Names as given on the command line; a `/1` or `/2` suffix is dropped
+    pub fn new(names: &[String]) -> Self {
+        Tracer {
+            names: names
+                .iter()
+                .map(|name| (list_name(name.as_bytes()).to_vec(), false))
+                .collect(),
+        }
+    }
+
+    pub fn wants(&self, qname: &[u8]) -> bool {
+        self.names.contains_key(qname)
+    }
+
+    /// Names no record of the input carried
+    pub fn unseen(&self) -> Vec<String> {
+        let mut unseen: Vec<String> = self
+            .names
+            .iter()
+            .filter(|(_, &seen)| !seen)
+            .map(|(name, _)| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        unseen.sort();
+        unseen
+    }
+
+    /// Print the trace of one pair; `records` holds one record for single-end reads
+    ///
+    /// `run_filters` are the filters applied outside [`FilterConfig`], each
+    /// with whether the pair passed it.
+    pub fn trace(
+        &mut self,
+        config: &FilterConfig,
+        records: &[&bam::Record],
+        verdict: &PairVerdict,
+        run_filters: &[(&'static str, bool)],
+        keep: bool,
+    ) {
+        let qname = records[0].qname();
+        if let Some(seen) = self.names.get_mut(qname) {
+            *seen = true;
+        }
+        let (record1, record2) = (records[0], records[records.len() - 1]);
+        let unit = if records.len() == 1 { "read" } else { "pair" };
+        eprintln!(
+            "Trace {} ({}): {}",
+            String::from_utf8_lossy(qname),
+            unit,
+            if keep { "kept" } else { "removed" }
+        );
+
+        let measured = config.measure(record1, record2);
+        let enabled = config.thresholds();
+        for (index, name) in Thresholds::NAMES.into_iter().enumerate() {
+            if !enabled.contains(index) {
+                continue;
+            }
+            let outcome = if verdict.failed.contains(index) {
+                "FAIL"
+            } else if verdict.evaluated.contains(index) {
+                "pass"
+            } else {
+                "not evaluated"
+            };
+            let (values, limit) = describe(config, index, records, &measured);
+            eprintln!("  {:<28} {:<32} {:<16} {}", name, values, limit, outcome);
+        }
+        if verdict.rescued {
+            eprintln!("  (the low-complexity mate was rescued by the other)");
+        }
+        for &(name, pass) in run_filters {
+            eprintln!(
+                "  {:<28} {:<32} {:<16} {}",
+                name,
+                "",
+                "",
+                if pass { "pass" } else { "FAIL" }
+            );
+        }
+
+        let failed: Vec<&str> = verdict
+            .failed
+            .names()
+            .chain(
+                run_filters
+                    .iter()
+                    .filter(|(_, pass)| !pass)
+                    .map(|&(name, _)| name),
+            )
+            .collect();
+        if failed.is_empty() {
+            eprintln!("  Verdict: kept");
+        } else {
+            eprintln!("  Verdict: removed by {}", failed.join(", "));
+        }
+    }
+}
+
+/// Each mate's value of threshold `index` and the limit it is held to
+fn describe(
+    config: &FilterConfig,
+    index: usize,
+    records: &[&bam::Record],
+    measured: &filter::PairMetrics,
+) -> (String, String) {
+    let values = |value: &dyn Fn(usize, &bam::Record) -> String| -> String {
+        match records {
+            [record] => value(0, record),
+            _ => records
+                .iter()
+                .enumerate()
+                .map(|(mate, record)| format!("r{} {}", mate + 1, value(mate, record)))
+                .collect::<Vec<_>>()
+                .join("; "),
+        }
+    };
+    let fraction = |value: Option<f64>| value.map_or("none".to_string(), |v| format!("{:.3}", v));
+    let basis = config.length_basis;
+    match index {
+        Thresholds::SHORT_READ_POLICY => (
+            values(&|_, record| format!("{} bp", record.seq_len())),
+            format!(">= {} bp", config.min_read_length()),
+        ),
+        Thresholds::COMPLEXITY => {
+            let limit = match config.short_reads {
+                ShortReadPolicy::Pass => format!(">= {:.3} (short: pass)", config.complexity),
+                _ => format!(">= {:.3}", config.complexity),
+            };
+            let complexity =
+                |mate: usize, record: &bam::Record| match filter::cached_complexity(record) {
+                    Some(cached) if config.use_cached_metrics => format!("{:.4} (xc)", cached),
+                    _ => format!("{:.4}", measured.complexity[mate]),
+                };
+            (values(&complexity), limit)
+        }
+        Thresholds::MIN_MAPPED => (
+            values(&|mate, _| format!("{} bp", measured.longest_mapped[mate])),
+            format!(">= {} bp", config.min_mapped),
+        ),
+        Thresholds::MIN_MAPPED_FRACTION => (
+            values(&|mate, record| {
+                let length = metrics::read_length(record, basis);
+                let fraction = metrics::fraction(measured.longest_mapped[mate], length);
+                format!("{:.3}", fraction)
+            }),
+            format!(">= {}", config.min_mapped_fraction.unwrap_or_default()),
+        ),
+        Thresholds::MAX_CLIP_FRACTION => (
+            values(&|_, record| {
+                let clipped = metrics::clipped_bases(record, basis);
+                let fraction = metrics::fraction(clipped, metrics::read_length(record, basis));
+                format!("{:.3}", fraction)
+            }),
+            format!("<= {}", config.max_clip_fraction.unwrap_or_default()),
+        ),
+        Thresholds::MIN_GAP_COMPRESSED_IDENTITY => (
+            values(&|_, record| {
+                fraction(metrics::gap_compressed_divergence(record).map(|d| 1.0 - d))
+            }),
+            format!(
+                ">= {}",
+                config.min_gap_compressed_identity.unwrap_or_default()
+            ),
+        ),
+        Thresholds::MAX_DE => (
+            values(&|_, record| fraction(metrics::divergence(record))),
+            format!("<= {}", config.max_divergence.unwrap_or_default()),
+        ),
+        Thresholds::MIN_MAPQ => (
+            values(&|_, record| record.mapq().to_string()),
+            format!(">= {}", config.min_mapq),
+        ),
+        Thresholds::MAX_SPLICE_JUNCTIONS => (
+            values(&|_, record| filter::count_splice_junctions(record).to_string()),
+            format!("<= {}", config.max_splice_junctions.unwrap_or_default()),
+        ),
+        Thresholds::HASH_SAMPLE => {
+            let sample = config.hash_sample.expect("enabled with a sample");
+            (
+                format!("bucket {}", sample.bucket(records[0].qname())),
+                format!(
+                    "{}..={} of {}",
+                    sample.first, sample.last, sample.denominator
+                ),
+            )
+        }
+        Thresholds::MIN_AVG_BASEQ => (
+            values(&|_, record| {
+                quality::mean_base_quality(record)
+                    .map_or("none".to_string(), |q| format!("{:.1}", q))
+            }),
+            format!(">= {}", config.min_avg_baseq.unwrap_or_default()),
+        ),
+        Thresholds::MIN_BASEQ_FRACTION => {
+            let threshold = config.min_baseq_fraction.expect("enabled with a threshold");
+            (
+                values(&|_, record| {
+                    fraction(quality::fraction_at_least(record, threshold.quality))
+                }),
+                format!(">= {} at Q{}", threshold.fraction, threshold.quality),
+            )
+        }
+        Thresholds::INSERT_SIZE => (
+            format!("|TLEN| {}", records[0].insert_size().unsigned_abs()),
+            match (config.min_insert, config.max_insert) {
+                (Some(min), Some(max)) => format!("{}..={}", min, max),
+                (Some(min), None) => format!(">= {}", min),
+                (None, max) => format!("<= {}", max.unwrap_or_default()),
+            },
+        ),
+        Thresholds::SAME_REFERENCE => (
+            values(&|_, record| match record.is_unmapped() {
+                true => "unmapped".to_string(),
+                false => format!("tid {}", record.tid()),
+            }),
+            "same reference".to_string(),
+        ),
+        Thresholds::MAX_NM => (
+            values(&|_, record| {
+                metrics::edit_distance(record).map_or("none".to_string(), |nm| format!("NM {}", nm))
+            }),
+            format!("<= {}", config.max_nm.unwrap_or_default()),
+        ),
+        Thresholds::MAX_ERROR_RATE => (
+            values(&|_, record| fraction(metrics::error_rate(record))),
+            format!("<= {}", config.max_error_rate.unwrap_or_default()),
+        ),
+        _ => (String::new(), "--filter-expr".to_string()),
+    }
+}
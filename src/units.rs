@@ -0,0 +1,82 @@
+//! Number formatting of the console report (`--report-units`, `--report-decimals`)
+//!
+//! Only the printed summary changes. JSON, TSV and SN files always hold plain
+//! digits with `.` as the decimal point, whatever the options or the
+//! system locale, so scripts can parse them.
+
+use clap::ValueEnum;
+
+/// How counts are printed
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ReportUnits {
+    /// Plain digits: 1234567
+    #[default]
+    Raw,
+    /// Digits in groups of three: 1,234,567
+    Grouped,
+    /// Scaled with a k, M, G or T suffix: 1.23M
+    Human,
+}
+
+/// Decimal places of percentages when `--report-decimals` is not given
+pub const DEFAULT_DECIMALS: usize = 2;
+
+const SUFFIXES: [&str; 4] = ["k", "M", "G", "T"];
+
+/// Formats the counts and percentages of the console report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumberFormat {
+    pub units: ReportUnits,
+    /// Decimal places of percentages and scaled counts
+    pub decimals: usize,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            units: ReportUnits::Raw,
+            decimals: DEFAULT_DECIMALS,
+        }
+    }
+}
+
+impl NumberFormat {
+    pub fn count(&self, count: u64) -> String {
+        match self.units {
+            ReportUnits::Raw => count.to_string(),
+            ReportUnits::Grouped => {
+                let digits = count.to_string();
+                let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+                for (i, digit) in digits.chars().enumerate() {
+                    if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                        grouped.push(',');
+                    }
+                    grouped.push(digit);
+                }
+                grouped
+            }
+            ReportUnits::Human => {
+                if count < 1000 {
+                    return count.to_string();
+                }
+                let mut value = count as f64 / 1000.0;
+                let mut suffix = 0;
+                while value >= 1000.0 && suffix < SUFFIXES.len() - 1 {
+                    value /= 1000.0;
+                    suffix += 1;
+                }
+                format!("{:.*}{}", self.decimals, value, SUFFIXES[suffix])
+            }
+        }
+    }
+
+    /// `percent` (0-100) with the configured decimal places and a `%` sign
+    pub fn percent(&self, percent: f64) -> String {
+        format!("{:.*}%", self.decimals, percent)
+    }
+
+    /// `part` as a percentage of `whole`
+    pub fn share(&self, part: u64, whole: u64) -> String {
+        self.percent(part as f64 / whole as f64 * 100.0)
+    }
+}
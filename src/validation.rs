@@ -0,0 +1,264 @@
+//! Checking records for malformed fields (`--validation`)
+//!
+//! Every record the run filters is checked before any filter sees it, the
+//! way picard's `VALIDATION_STRINGENCY` does: a mapped record without a CIGAR
+//! or a position, or with a CIGAR that aligns no base, a position past the
+//! end of its reference, flags that contradict each other, and mates whose
+//! mate fields don't describe each other. `strict` ends the run at the
+//! first violation with the record's name, `lenient` (the default) names the
+//! first few in warnings and counts the rest, and `silent` only counts them.
+//! Either way the record is filtered as it is, and the report counts the
+//! violations by type. A CIGAR that doesn't cover the sequence never gets
+//! this far, as htslib refuses to read the record; that is a read error for
+//! `--on-read-error`.
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use rust_htslib::bam;
+use serde::{Deserialize, Serialize};
+
+/// Violations named in warnings under `lenient` before only counting the rest
+const WARN_VIOLATIONS: u64 = 10;
+
+/// What a malformed record does to the run
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Validation {
+    /// Stop with an error naming the record
+    Strict,
+    /// Warn about the first violations, count them all and go on
+    #[default]
+    Lenient,
+    /// Count violations without a word
+    Silent,
+}
+
+/// Kinds of malformed record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A mapped record whose CIGAR aligns no base to the reference
+    UnalignedCigar,
+    /// A mapped record without a CIGAR
+    MissingCigar,
+    /// A mapped record without a reference or with a negative position
+    InvalidPosition,
+    /// A reference the header doesn't have, or a position past its end
+    OutsideReference,
+    /// Flags that contradict each other
+    InconsistentFlags,
+    /// Mate fields that don't match the mate
+    MateMismatch,
+}
+
+/// Violations so far, by type
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ValidationCounts {
+    pub unaligned_cigar: u64,
+    pub missing_cigar: u64,
+    pub invalid_position: u64,
+    pub outside_reference: u64,
+    pub inconsistent_flags: u64,
+    pub mate_mismatch: u64,
+}
+
+impl ValidationCounts {
+    pub fn total(&self) -> u64 {
+        self.unaligned_cigar
+            + self.missing_cigar
+            + self.invalid_position
+            + self.outside_reference
+            + self.inconsistent_flags
+            + self.mate_mismatch
+    }
+
+    pub fn merge(&mut self, other: &ValidationCounts) {
+        self.unaligned_cigar += other.unaligned_cigar;
+        self.missing_cigar += other.missing_cigar;
+        self.invalid_position += other.invalid_position;
+        self.outside_reference += other.outside_reference;
+        self.inconsistent_flags += other.inconsistent_flags;
+        self.mate_mismatch += other.mate_mismatch;
+    }
+
+    fn count(&mut self, violation: Violation) {
+        *match violation {
+            Violation::UnalignedCigar => &mut self.unaligned_cigar,
+            Violation::MissingCigar => &mut self.missing_cigar,
+            Violation::InvalidPosition => &mut self.invalid_position,
+            Violation::OutsideReference => &mut self.outside_reference,
+            Violation::InconsistentFlags => &mut self.inconsistent_flags,
+            Violation::MateMismatch => &mut self.mate_mismatch,
+        } += 1;
+    }
+}
+
+/// The first violation of `record` and what it is, if any; `target_lengths`
+/// are the reference lengths of the header
+pub fn check_record(record: &bam::Record, target_lengths: &[u64]) -> Option<(Violation, String)> {
+    let flags = record.flags();
+    if !record.is_paired() && flags & (0x2 | 0x8 | 0x20 | 0x40 | 0x80) != 0 {
+        return Some((
+            Violation::InconsistentFlags,
+            format!("FLAG {} sets mate flags without 0x1 (paired)", flags),
+        ));
+    }
+    if record.is_paired() && record.is_first_in_template() == record.is_last_in_template() {
+        return Some((
+            Violation::InconsistentFlags,
+            format!(
+                "FLAG {} is paired but sets {} of 0x40 (first) and 0x80 (last)",
+                flags,
+                if record.is_first_in_template() {
+                    "both"
+                } else {
+                    "neither"
+                }
+            ),
+        ));
+    }
+    if record.is_unmapped() {
+        return None;
+    }
+    let cigar = record.raw_cigar();
+    if cigar.is_empty() {
+        return Some((
+            Violation::MissingCigar,
+            "mapped without a CIGAR".to_string(),
+        ));
+    }
+    if record.tid() < 0 || record.pos() < 0 {
+        return Some((
+            Violation::InvalidPosition,
+            format!(
+                "mapped at reference {} position {}",
+                record.tid(),
+                record.pos() + 1
+            ),
+        ));
+    }
+    match target_lengths.get(record.tid() as usize) {
+        None => {
+            return Some((
+                Violation::OutsideReference,
+                format!(
+                    "reference {} is not in the header, which has {}",
+                    record.tid(),
+                    target_lengths.len()
+                ),
+            ))
+        }
+        Some(&length) if record.pos() as u64 >= length => {
+            return Some((
+                Violation::OutsideReference,
+                format!(
+                    "position {} is past the end of its {} bp reference",
+                    record.pos() + 1,
+                    length
+                ),
+            ))
+        }
+        Some(_) => {}
+    }
+    // htslib itself refuses a CIGAR that doesn't cover the sequence
+    let aligned = record.cigar().iter().any(|op| {
+        matches!(
+            op,
+            bam::record::Cigar::Match(_)
+                | bam::record::Cigar::Equal(_)
+                | bam::record::Cigar::Diff(_)
+        )
+    });
+    if !aligned {
+        return Some((
+            Violation::UnalignedCigar,
+            format!("mapped, but CIGAR {} aligns no base", record.cigar()),
+        ));
+    }
+    None
+}
+
+/// How the mate fields of `record` disagree with `mate`, if they do
+pub fn check_mate(record: &bam::Record, mate: &bam::Record) -> Option<String> {
+    if record.is_mate_unmapped() != mate.is_unmapped() {
+        return Some(format!(
+            "0x8 (mate unmapped) is {}, but the mate is {}",
+            if record.is_mate_unmapped() {
+                "set"
+            } else {
+                "not set"
+            },
+            if mate.is_unmapped() {
+                "unmapped"
+            } else {
+                "mapped"
+            }
+        ));
+    }
+    if !mate.is_unmapped() && (record.mtid(), record.mpos()) != (mate.tid(), mate.pos()) {
+        return Some(format!(
+            "mate recorded at reference {} position {}, but it is at reference {} position {}",
+            record.mtid(),
+            record.mpos() + 1,
+            mate.tid(),
+            mate.pos() + 1
+        ));
+    }
+    None
+}
+
+/// Applies a [`Validation`] level to the records of one input
+pub struct Validator {
+    level: Validation,
+    target_lengths: Vec<u64>,
+    pub counts: ValidationCounts,
+}
+
+impl Validator {
+    pub fn new(level: Validation, header: &bam::HeaderView) -> Self {
+        Validator {
+            level,
+            target_lengths: (0..header.target_count())
+                .map(|tid| header.target_len(tid).unwrap_or(0))
+                .collect(),
+            counts: ValidationCounts::default(),
+        }
+    }
+
+    /// Check a record filtered on its own, or an extra alignment
+    pub fn check(&mut self, record: &bam::Record) -> Result<()> {
+        match check_record(record, &self.target_lengths) {
+            Some((violation, detail)) => self.violated(record, violation, &detail),
+            None => Ok(()),
+        }
+    }
+
+    /// Check both mates of a pair, and their mate fields against each other
+    pub fn check_pair(&mut self, record1: &bam::Record, record2: &bam::Record) -> Result<()> {
+        self.check(record1)?;
+        self.check(record2)?;
+        for (record, mate) in [(record1, record2), (record2, record1)] {
+            if let Some(detail) = check_mate(record, mate) {
+                self.violated(record, Violation::MateMismatch, &detail)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn violated(&mut self, record: &bam::Record, violation: Violation, detail: &str) -> Result<()> {
+        let name = String::from_utf8_lossy(record.qname());
+        if self.level == Validation::Strict {
+            bail!(
+                "Record {} is malformed: {} (--validation strict; lenient counts and goes on)",
+                name,
+                detail
+            );
+        }
+        self.counts.count(violation);
+        if self.level == Validation::Lenient && self.counts.total() <= WARN_VIOLATIONS {
+            eprintln!("Warning: record {} is malformed: {}", name, detail);
+            if self.counts.total() == WARN_VIOLATIONS {
+                eprintln!("  Further malformed records are only counted");
+            }
+        }
+        Ok(())
+    }
+}
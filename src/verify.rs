@@ -0,0 +1,269 @@
+//! `--verify-output`: re-read the written BAMs and check their invariants
+
+use crate::{header, sort};
+use anyhow::{bail, Context, Result};
+use rust_htslib::{bam, bam::Read};
+use std::collections::HashMap;
+use std::io::{Read as _, Seek, SeekFrom};
+
+/// The empty BGZF block that terminates a complete BAM file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Problems listed individually before the rest are only counted
+const MAX_LISTED_PROBLEMS: usize = 10;
+
+/// What the outputs must contain
+pub struct Expectation {
+    /// Pairs written across all outputs
+    pub pairs: u64,
+    /// Secondary and supplementary records written with those pairs
+    pub extra_records: u64,
+    /// @PG lines of this tool each output header must carry (earlier runs plus this one)
+    pub program_records: usize,
+    /// Mates follow each other (false for coordinate-sorted output)
+    pub mates_adjacent: bool,
+}
+
+#[derive(Default)]
+struct Problems {
+    listed: Vec<String>,
+    unlisted: usize,
+}
+
+impl Problems {
+    fn add(&mut self, problem: String) {
+        if self.listed.len() < MAX_LISTED_PROBLEMS {
+            self.listed.push(problem);
+        } else {
+            self.unlisted += 1;
+        }
+    }
+}
+
+fn has_eof_block(path: &str) -> Result<bool> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("Cannot open {}", path))?;
+    if file.metadata()?.len() < BGZF_EOF.len() as u64 {
+        return Ok(false);
+    }
+    let mut tail = [0u8; BGZF_EOF.len()];
+    file.seek(SeekFrom::End(-(BGZF_EOF.len() as i64)))?;
+    file.read_exact(&mut tail)?;
+    Ok(tail == BGZF_EOF)
+}
+
+/// Check one output, returning its record count
+fn verify_file(path: &str, expected: &Expectation, problems: &mut Problems) -> Result<u64> {
+    if !has_eof_block(path)? {
+        problems.add(format!("{}: missing BGZF EOF block (truncated)", path));
+    }
+
+    let mut reader =
+        bam::Reader::from_path(path).with_context(|| format!("Cannot reopen {}", path))?;
+    let header = bam::Header::from_template(reader.header());
+    let runs = header::previous_runs(&header).len();
+    if runs != expected.program_records {
+        problems.add(format!(
+            "{}: header has {} {} @PG lines, expected {}",
+            path,
+            runs,
+            header::PROGRAM_NAME,
+            expected.program_records
+        ));
+    }
+
+    let records = if expected.mates_adjacent {
+        check_adjacent_pairs(&mut reader, path, problems)
+    } else {
+        check_sorted_pairs(&mut reader, path, problems)
+    };
+    Ok(records)
+}
+
+fn is_extra(record: &bam::Record) -> bool {
+    record.is_secondary() || record.is_supplementary()
+}
+
+/// Read the next primary record, counting the secondary and supplementary
+/// ones before it
+fn read_primary(
+    reader: &mut bam::Reader,
+    record: &mut bam::Record,
+    records: &mut u64,
+) -> Option<Result<(), rust_htslib::errors::Error>> {
+    loop {
+        match reader.read(record) {
+            Some(Ok(())) if is_extra(record) => *records += 1,
+            result => return result,
+        }
+    }
+}
+
+/// Mates must be adjacent with one first and one last segment, and a pair's
+/// name must not repeat in the next pair (which would mean it was written
+/// twice). Secondary and supplementary records between pairs are only
+/// counted. Checking adjacent pairs keeps memory constant.
+fn check_adjacent_pairs(reader: &mut bam::Reader, path: &str, problems: &mut Problems) -> u64 {
+    let mut records = 0u64;
+    let mut record1 = bam::Record::new();
+    let mut record2 = bam::Record::new();
+    let mut previous_name: Vec<u8> = Vec::new();
+    loop {
+        match read_primary(reader, &mut record1, &mut records) {
+            None => break,
+            Some(Ok(())) => {}
+            Some(Err(e)) => {
+                problems.add(format!(
+                    "{}: unreadable after {} records: {}",
+                    path, records, e
+                ));
+                break;
+            }
+        }
+        records += 1;
+        match read_primary(reader, &mut record2, &mut records) {
+            None => {
+                problems.add(format!(
+                    "{}: last record {} has no mate",
+                    path,
+                    String::from_utf8_lossy(record1.qname())
+                ));
+                break;
+            }
+            Some(Ok(())) => {}
+            Some(Err(e)) => {
+                problems.add(format!(
+                    "{}: unreadable after {} records: {}",
+                    path, records, e
+                ));
+                break;
+            }
+        }
+        records += 1;
+
+        let name = record1.qname();
+        if name != record2.qname() {
+            problems.add(format!(
+                "{}: adjacent records {} and {} are not mates",
+                path,
+                String::from_utf8_lossy(name),
+                String::from_utf8_lossy(record2.qname())
+            ));
+        } else if record1.is_first_in_template() == record2.is_first_in_template()
+            || record1.is_last_in_template() == record2.is_last_in_template()
+        {
+            problems.add(format!(
+                "{}: pair {} does not have one first and one last mate",
+                path,
+                String::from_utf8_lossy(name)
+            ));
+        }
+        if name == previous_name.as_slice() {
+            problems.add(format!(
+                "{}: pair {} written more than once",
+                path,
+                String::from_utf8_lossy(name)
+            ));
+        }
+        previous_name.clear();
+        previous_name.extend_from_slice(name);
+    }
+
+    records
+}
+
+/// Records must be in coordinate order and every name must be seen exactly
+/// once as first and once as last primary segment. Only names whose mate
+/// hasn't been seen yet are held in memory.
+fn check_sorted_pairs(reader: &mut bam::Reader, path: &str, problems: &mut Problems) -> u64 {
+    let mut records = 0u64;
+    let mut record = bam::Record::new();
+    let mut previous_key = None;
+    // Name -> (first segment seen, last segment seen)
+    let mut pending: HashMap<Vec<u8>, (bool, bool)> = HashMap::new();
+    while let Some(result) = reader.read(&mut record) {
+        if let Err(e) = result {
+            problems.add(format!(
+                "{}: unreadable after {} records: {}",
+                path, records, e
+            ));
+            break;
+        }
+        records += 1;
+
+        let key = sort::coordinate_key(&record);
+        if previous_key.is_some_and(|previous| key < previous) {
+            problems.add(format!(
+                "{}: record {} is out of coordinate order",
+                path,
+                String::from_utf8_lossy(record.qname())
+            ));
+        }
+        previous_key = Some(key);
+        if is_extra(&record) {
+            continue;
+        }
+
+        let seen = pending
+            .entry(record.qname().to_vec())
+            .or_insert((false, false));
+        let slot = if record.is_first_in_template() {
+            &mut seen.0
+        } else {
+            &mut seen.1
+        };
+        if *slot {
+            problems.add(format!(
+                "{}: mate of {} written more than once",
+                path,
+                String::from_utf8_lossy(record.qname())
+            ));
+        }
+        *slot = true;
+        if *seen == (true, true) {
+            pending.remove(record.qname());
+        }
+    }
+
+    let mut unmatched: Vec<_> = pending.into_keys().collect();
+    unmatched.sort_unstable();
+    for name in unmatched {
+        problems.add(format!(
+            "{}: {} has only one mate",
+            path,
+            String::from_utf8_lossy(&name)
+        ));
+    }
+    records
+}
+
+/// Re-read every output and fail if any invariant is broken
+pub fn verify_outputs(paths: &[String], expected: &Expectation) -> Result<()> {
+    let mut problems = Problems::default();
+    let mut records = 0u64;
+    for path in paths {
+        records += verify_file(path, expected, &mut problems)?;
+    }
+    let expected_records = expected.pairs * 2 + expected.extra_records;
+    if records != expected_records {
+        problems.add(format!(
+            "outputs hold {} records, expected {} ({} kept pairs, {} extra records)",
+            records, expected_records, expected.pairs, expected.extra_records
+        ));
+    }
+
+    if problems.listed.is_empty() {
+        return Ok(());
+    }
+    let mut message = String::from("Output verification failed:");
+    for problem in &problems.listed {
+        message.push_str("\n  ");
+        message.push_str(problem);
+    }
+    if problems.unlisted > 0 {
+        message.push_str(&format!("\n  ... and {} more", problems.unlisted));
+    }
+    bail!(message)
+}
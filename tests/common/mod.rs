@@ -0,0 +1,92 @@
+//! Scratch files, headers and inputs shared by the integration tests
+
+// Each test crate uses a different subset
+#![allow(dead_code)]
+
+use filter_bam_pairs::header;
+use filter_bam_pairs::test_utils::{mapped_pair, random_sequence};
+use filter_bam_pairs::verify::Expectation;
+use rust_htslib::bam::{self, header::HeaderRecord};
+use std::path::PathBuf;
+
+/// A scratch directory per test, removed when the test ends
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    pub fn new(test: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "filter_bam_pairs-test.{}.{}",
+            std::process::id(),
+            test
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Scratch(path)
+    }
+
+    pub fn path(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Two references, as in an input BAM
+pub fn reference_header() -> bam::Header {
+    let mut header = bam::Header::new();
+    for name in ["chr1", "chr2"] {
+        header.push_record(
+            HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", name)
+                .push_tag(b"LN", 100_000),
+        );
+    }
+    header
+}
+
+/// The header of an output written by this tool
+pub fn test_header() -> bam::Header {
+    let mut header = reference_header();
+    header::add_program_record(&mut header);
+    header
+}
+
+pub fn expect(pairs: usize, mates_adjacent: bool) -> Expectation {
+    Expectation {
+        pairs: pairs as u64,
+        extra_records: 0,
+        program_records: 1,
+        mates_adjacent,
+    }
+}
+
+/// Write `pairs` pairs as a name-grouped BAM
+pub fn write_input(path: &str, pairs: usize) {
+    let mut writer = bam::Writer::from_path(path, &reference_header(), bam::Format::Bam).unwrap();
+    writer
+        .set_compression_level(bam::CompressionLevel::Fastest)
+        .unwrap();
+    for i in 0..pairs {
+        // Every third pair is repetitive and gets rejected
+        let seq = if i % 3 == 0 {
+            "A".repeat(100)
+        } else {
+            random_sequence(100, (i % 64) as u64)
+        };
+        let (record1, record2) = mapped_pair(&format!("pair{i:07}"), &seq, &seq);
+        writer.write(&record1.build()).unwrap();
+        writer.write(&record2.build()).unwrap();
+    }
+}
+
+/// Write `pairs` as a name-grouped BAM on the two references
+pub fn write_pairs(path: &str, pairs: &[(bam::Record, bam::Record)]) {
+    let mut writer = bam::Writer::from_path(path, &reference_header(), bam::Format::Bam).unwrap();
+    for (record1, record2) in pairs {
+        writer.write(record1).unwrap();
+        writer.write(record2).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+//! Orientation of reads written as FASTQ, kept pairs written as FASTQ
+//! instead of BAM (`--fastq-out`), and paired FASTQ input (`--input-r1`)
+
+mod common;
+
+use common::{reference_header, Scratch};
+use filter_bam_pairs::fastq::{reverse_complement, write_fastq_record, Orientation};
+use filter_bam_pairs::test_utils::{mapped_pair, random_sequence, RecordBuilder};
+use rust_htslib::{bam, bam::Read as _, bgzf};
+use std::io::{Read, Write};
+use std::process::Command;
+
+fn fastq(record: &RecordBuilder, orientation: Orientation) -> String {
+    let mut out = Vec::new();
+    write_fastq_record(&mut out, &record.build(), orientation).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn reverse_strand_reads_are_restored_to_sequencing_orientation() {
+    let record = RecordBuilder::new("r")
+        .seq("AACGT")
+        .qual(&[10, 20, 30, 40, 0])
+        .cigar("5M")
+        .flags(0x10)
+        .pos(0, 100);
+    // Phred + 33: 10 = '+', 20 = '5', 30 = '?', 40 = 'I', 0 = '!'
+    assert_eq!(
+        fastq(&record, Orientation::Sequenced),
+        "@r\nACGTT\n+\n!I?5+\n"
+    );
+    assert_eq!(
+        fastq(&record, Orientation::Aligned),
+        "@r\nAACGT\n+\n+5?I!\n"
+    );
+}
+
+#[test]
+fn forward_strand_reads_are_written_as_stored() {
+    let record = RecordBuilder::new("f")
+        .seq("AACGT")
+        .qual(&[10, 20, 30, 40, 0])
+        .cigar("5M")
+        .pos(0, 100);
+    for orientation in [Orientation::Sequenced, Orientation::Aligned] {
+        assert_eq!(fastq(&record, orientation), "@f\nAACGT\n+\n+5?I!\n");
+    }
+}
+
+#[test]
+fn kept_pairs_are_written_as_fastq_in_sequencing_orientation() {
+    let scratch = Scratch::new("fastq_out");
+    let input = scratch.path("in.bam");
+    let (seq1, seq2) = (random_sequence(100, 1), random_sequence(100, 2));
+    let mut writer = bam::Writer::from_path(&input, &reference_header(), bam::Format::Bam).unwrap();
+    // Mate 2 (reverse strand) first, then a pair that fails complexity
+    let (record1, record2) = mapped_pair("kept", &seq1, &seq2);
+    writer.write(&record2.build()).unwrap();
+    writer.write(&record1.build()).unwrap();
+    let repeat = "A".repeat(100);
+    let (record1, record2) = mapped_pair("repeat", &repeat, &repeat);
+    writer.write(&record1.build()).unwrap();
+    writer.write(&record2.build()).unwrap();
+    drop(writer);
+
+    let prefix = scratch.path("clean");
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "--fastq-out", &prefix])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+
+    let text = |mate: &str| {
+        let mut text = String::new();
+        bgzf::Reader::from_path(format!("{}_{}.fastq.gz", prefix, mate))
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    };
+    let quality = "?".repeat(100);
+    assert_eq!(text("R1"), format!("@kept\n{}\n+\n{}\n", seq1, quality));
+    let restored = String::from_utf8(reverse_complement(seq2.as_bytes())).unwrap();
+    assert_eq!(text("R2"), format!("@kept\n{}\n+\n{}\n", restored, quality));
+
+    // --keep-alignment-orientation only applies to FASTQ outputs
+    let bare = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &scratch.path("out.bam")])
+        .arg("--keep-alignment-orientation")
+        .output()
+        .unwrap();
+    assert!(!bare.status.success());
+    assert!(String::from_utf8_lossy(&bare.stderr).contains("needs a FASTQ output"));
+}
+
+#[test]
+fn fastq_pairs_are_filtered_before_alignment() {
+    let scratch = Scratch::new("fastq_in");
+    let (seq1, seq2) = (random_sequence(100, 3), random_sequence(100, 4));
+    let repeat = "A".repeat(100);
+    let quality = "F".repeat(100);
+    // Mate 1 BGZF-compressed with Illumina comments, mate 2 plain with /2 suffixes
+    let r1 = scratch.path("in_R1.fastq.gz");
+    let mut writer = bgzf::Writer::from_path(&r1).unwrap();
+    for (name, seq) in [("kept", &seq1), ("repeat", &repeat)] {
+        write!(writer, "@{} 1:N:0:ACGT\n{}\n+\n{}\n", name, seq, quality).unwrap();
+    }
+    drop(writer);
+    let r2 = scratch.path("in_R2.fastq");
+    std::fs::write(
+        &r2,
+        format!("@kept/2\n{seq2}\n+\n{quality}\n@repeat/2\n{repeat}\n+\n{quality}\n"),
+    )
+    .unwrap();
+
+    let prefix = scratch.path("clean");
+    let bam = scratch.path("clean.bam");
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["--input-r1", &r1, "--input-r2", &r2, "--fastq-out", &prefix])
+        .args(["-o", &bam])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let text = |mate: &str| {
+        let mut text = String::new();
+        bgzf::Reader::from_path(format!("{}_{}.fastq.gz", prefix, mate))
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    };
+    assert_eq!(text("R1"), format!("@kept\n{}\n+\n{}\n", seq1, quality));
+    assert_eq!(text("R2"), format!("@kept\n{}\n+\n{}\n", seq2, quality));
+    // The BAM holds the pair unaligned
+    let records: Vec<bam::Record> = bam::Reader::from_path(&bam)
+        .unwrap()
+        .records()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(
+        records
+            .iter()
+            .map(|record| record.flags())
+            .collect::<Vec<_>>(),
+        [77, 141]
+    );
+
+    // Alignment filters have nothing to measure
+    let aligned = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["--input-r1", &r1, "--input-r2", &r2, "--fastq-out", &prefix])
+        .args(["--min-mapped", "30"])
+        .output()
+        .unwrap();
+    assert!(!aligned.status.success());
+
+    // Mates must come in the same order
+    let swapped = scratch.path("swapped_R2.fastq");
+    std::fs::write(
+        &swapped,
+        format!("@repeat/2\n{repeat}\n+\n{quality}\n@kept/2\n{seq2}\n+\n{quality}\n"),
+    )
+    .unwrap();
+    let out_of_step = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args([
+            "--input-r1",
+            &r1,
+            "--input-r2",
+            &swapped,
+            "--fastq-out",
+            &prefix,
+        ])
+        .output()
+        .unwrap();
+    assert!(!out_of_step.status.success());
+    assert!(String::from_utf8_lossy(&out_of_step.stderr).contains("Mates out of step at pair 1"));
+}
@@ -0,0 +1,1277 @@
+//! Filter decisions on synthetic pairs built with `test_utils`
+
+mod common;
+
+use common::Scratch;
+use filter_bam_pairs::chain::FilterOrder;
+use filter_bam_pairs::collisions::NameCollisions;
+use filter_bam_pairs::complexity::{dust_complexity, entropy_complexity, ComplexityMethod};
+use filter_bam_pairs::expr::Expr;
+use filter_bam_pairs::fastq::reverse_complement;
+use filter_bam_pairs::filter::{
+    self, FilterConfig, MissingNm, ShortReadPolicy, Thresholds, KMER_SIZE, TAG_COMPLEXITY,
+    TAG_LONGEST_MAPPED,
+};
+use filter_bam_pairs::metric_cache::{tune, MetricCacheReader, MetricCacheWriter};
+use filter_bam_pairs::names::{list_name, NameMatch, NameSet};
+use filter_bam_pairs::nanopore::parse_start_time;
+use filter_bam_pairs::primers::{soft_clip_outside, PrimerScheme};
+use filter_bam_pairs::quality::{self, EncodingProblem, QualitySample};
+use filter_bam_pairs::resync::Resync;
+use filter_bam_pairs::sample::HashSample;
+use filter_bam_pairs::stats::soft_clips;
+use filter_bam_pairs::test_utils::{
+    filter_records, mapped_pair, random_sequence, unmapped_pair, RecordBuilder,
+};
+use filter_bam_pairs::{bases, global_kmers, hic, kmer_db};
+use filter_bam_pairs::{md, metrics};
+use rust_htslib::bam::record::Aux;
+
+fn build((record1, record2): (RecordBuilder, RecordBuilder)) -> Vec<rust_htslib::bam::Record> {
+    vec![record1.build(), record2.build()]
+}
+
+#[test]
+fn complexity_of_random_and_repetitive_sequences() {
+    let random = random_sequence(100, 7);
+    assert!(filter::calculate_kmer_complexity(random.as_bytes(), KMER_SIZE) > 0.95);
+    assert_eq!(
+        filter::calculate_kmer_complexity(&[b'A'; 100], KMER_SIZE),
+        1.0 / 80.0
+    );
+    assert_eq!(filter::calculate_kmer_complexity(b"ACGT", KMER_SIZE), 0.0);
+}
+
+#[test]
+fn canonical_complexity_merges_a_kmer_with_its_reverse_complement() {
+    let half = random_sequence(50, 3);
+    let hairpin = [half.as_bytes(), &reverse_complement(half.as_bytes())].concat();
+    let plain = filter::calculate_kmer_complexity(&hairpin, KMER_SIZE);
+    let canonical = filter::calculate_canonical_kmer_complexity(&hairpin, KMER_SIZE);
+    assert!(plain > 0.95);
+    assert!(canonical < 0.6, "{canonical}");
+
+    // Both strands of a read score the same
+    let seq = random_sequence(50, 4) + &"CA".repeat(25);
+    let reverse = reverse_complement(seq.as_bytes());
+    assert_eq!(
+        filter::calculate_canonical_kmer_complexity(seq.as_bytes(), KMER_SIZE),
+        filter::calculate_canonical_kmer_complexity(&reverse, KMER_SIZE)
+    );
+    for cutoff in [0.3, 0.5, 0.8] {
+        let bounded =
+            filter::calculate_canonical_kmer_complexity_bounded(&hairpin, KMER_SIZE, cutoff);
+        assert_eq!(bounded >= cutoff, canonical >= cutoff);
+    }
+}
+
+#[test]
+fn bounded_complexity_agrees_with_exact_at_the_cutoff() {
+    for seed in 0..50 {
+        // Half random, half poly-A: complexity lands near the middle
+        let seq = random_sequence(50, seed) + &"A".repeat(50);
+        let exact = filter::calculate_kmer_complexity(seq.as_bytes(), KMER_SIZE);
+        for cutoff in [0.3, 0.5, 0.6, 0.8] {
+            let bounded =
+                filter::calculate_kmer_complexity_bounded(seq.as_bytes(), KMER_SIZE, cutoff);
+            assert_eq!(
+                bounded >= cutoff,
+                exact >= cutoff,
+                "seed {seed} cutoff {cutoff}"
+            );
+        }
+    }
+}
+
+#[test]
+fn packed_kmer_counts_match_counting_slices() {
+    // Unique kmers counted the obvious way, as byte slices
+    fn reference(seq: &[u8], k: usize, canonical: bool) -> f64 {
+        if seq.len() < k {
+            return 0.0;
+        }
+        let reverse = reverse_complement(seq);
+        let kmers: std::collections::HashSet<&[u8]> = (0..=seq.len() - k)
+            .map(|i| {
+                let forward = &seq[i..i + k];
+                let end = seq.len() - i;
+                if canonical {
+                    forward.min(&reverse[end - k..end])
+                } else {
+                    forward
+                }
+            })
+            .collect();
+        kmers.len() as f64 / (seq.len() - k + 1) as f64
+    }
+
+    for seed in 0..40 {
+        let mut seq = (random_sequence(60, seed) + &"CAG".repeat(20)).into_bytes();
+        // An N, a lower-case stretch and an IUPAC code in some of the reads
+        if seed % 2 == 0 {
+            seq[(seed as usize * 7) % 120] = b'N';
+        }
+        if seed % 3 == 0 {
+            seq[30..40].make_ascii_lowercase();
+            seq[100] = b'R';
+        }
+        for k in [5, 21, 32, 33] {
+            for canonical in [false, true] {
+                let (complexity, bounded_complexity) = if canonical {
+                    (
+                        filter::calculate_canonical_kmer_complexity as fn(&[u8], usize) -> f64,
+                        filter::calculate_canonical_kmer_complexity_bounded
+                            as fn(&[u8], usize, f64) -> f64,
+                    )
+                } else {
+                    (
+                        filter::calculate_kmer_complexity as fn(&[u8], usize) -> f64,
+                        filter::calculate_kmer_complexity_bounded as fn(&[u8], usize, f64) -> f64,
+                    )
+                };
+                let exact = reference(&seq, k, canonical);
+                assert_eq!(
+                    complexity(&seq, k),
+                    exact,
+                    "seed {seed} k {k} canonical {canonical}"
+                );
+                for cutoff in [0.3, 0.6, 0.9] {
+                    let bounded = bounded_complexity(&seq, k, cutoff);
+                    assert_eq!(bounded >= cutoff, exact >= cutoff, "seed {seed} k {k}");
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn simd_decoding_and_base_codes_match_scalar() {
+    // Lengths around the 16-byte and 32-base steps, odd ones ending on a high nibble
+    let mut state = 17u64;
+    for len in (0..70).chain([149, 150, 151, 1000]) {
+        let encoded: Vec<u8> = (0..len / 2 + 1)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        let (mut simd, mut scalar) = (Vec::new(), Vec::new());
+        bases::decode_into(&encoded, len, &mut simd);
+        bases::scalar::decode_into(&encoded, len, &mut scalar);
+        assert_eq!(simd, scalar, "length {len}");
+        assert_eq!(simd.len(), len);
+
+        let (mut simd_codes, mut scalar_codes) = (Vec::new(), Vec::new());
+        let known = bases::codes_into(&simd, &mut simd_codes);
+        assert_eq!(known, bases::scalar::codes_into(&simd, &mut scalar_codes));
+        assert_eq!(simd_codes, scalar_codes, "length {len}");
+    }
+
+    for seed in 0..20 {
+        let mut seq = random_sequence(150, seed).into_bytes();
+        let mut codes = Vec::new();
+        assert!(bases::codes_into(&seq, &mut codes));
+        assert!(codes
+            .iter()
+            .zip(&seq)
+            .all(|(&code, &base)| b"ACGT"[code as usize] == base));
+        // One ambiguous byte anywhere, in the SIMD steps or the tail
+        seq[seed as usize * 7] = if seed % 2 == 0 { b'N' } else { b'a' };
+        assert!(!bases::codes_into(&seq, &mut codes));
+        assert_eq!(codes[seed as usize * 7], bases::AMBIGUOUS);
+
+        let (record1, _) = mapped_pair("read", &String::from_utf8_lossy(&seq), "ACGT");
+        let record = record1.build();
+        assert_eq!(bases::decode(&record), record.seq().as_bytes());
+    }
+}
+
+#[test]
+fn dust_and_entropy_score_the_least_complex_window() {
+    // Triplets AAA, AAA, AAC: one identical pair of three
+    assert!((dust_complexity(b"AAAAC", 0) - 2.0 / 3.0).abs() < 1e-12);
+    // Two equally common letters of four possible: half the maximum entropy
+    assert!((entropy_complexity(b"AACC", 1, 0) - 0.5).abs() < 1e-12);
+    assert_eq!(dust_complexity(&[b'A'; 100], 64), 0.0);
+    assert_eq!(entropy_complexity(&[b'A'; 100], 5, 64), 0.0);
+    assert_eq!(dust_complexity(b"NNNNNNNN", 0), 0.0);
+
+    let random = random_sequence(150, 9);
+    assert!(dust_complexity(random.as_bytes(), 64) > 0.95);
+    assert!(entropy_complexity(random.as_bytes(), 5, 64) > 0.9);
+
+    // A dinucleotide run in the middle sinks the read's score
+    let seq = random_sequence(60, 10) + &"CA".repeat(40) + &random_sequence(60, 11);
+    let window = 64;
+    for score in [
+        |seq: &[u8], window| dust_complexity(seq, window),
+        |seq: &[u8], window| entropy_complexity(seq, 5, window),
+    ] {
+        let whole = score(seq.as_bytes(), 0);
+        let worst = score(seq.as_bytes(), window);
+        assert!(worst < 0.55 && whole > worst + 0.3, "{worst} {whole}");
+        // The sliding counts agree with scoring every window afresh
+        let fresh = seq
+            .as_bytes()
+            .windows(window)
+            .map(|part| score(part, 0))
+            .fold(f64::INFINITY, f64::min);
+        assert!((worst - fresh).abs() < 1e-9, "{worst} {fresh}");
+    }
+
+    // Every 21-mer of a 30 bp read runs into its random half, so the kmer
+    // ratio misses the poly-A that DUST sees
+    let short = "A".repeat(18) + &random_sequence(12, 12);
+    let (record1, record2) = mapped_pair("short", &short, &short);
+    let (record1, record2) = (record1.build(), record2.build());
+    assert!(
+        FilterConfig::default()
+            .evaluate(&record1, &record2, &mut 0)
+            .keep
+    );
+    let dust = FilterConfig {
+        complexity_method: ComplexityMethod::Dust,
+        ..FilterConfig::default()
+    };
+    assert!(!dust.evaluate(&record1, &record2, &mut 0).keep);
+
+    let canonical = FilterConfig {
+        canonical: true,
+        ..dust.clone()
+    };
+    assert!(canonical.validate().is_err());
+    let narrow = FilterConfig {
+        complexity_method: ComplexityMethod::ShannonEntropy,
+        complexity_window: 4,
+        ..FilterConfig::default()
+    };
+    assert!(narrow.validate().is_err());
+}
+
+#[test]
+fn short_reads_follow_the_kmer_size_and_policy() {
+    let good = random_sequence(100, 1);
+    let short = random_sequence(15, 2);
+    let (record1, record2) = mapped_pair("short", &good, &short);
+    let (record1, record2) = (record1.build(), record2.build());
+    let verdict = |config: FilterConfig| config.evaluate(&record1, &record2, &mut 0);
+
+    let fail = verdict(FilterConfig::default());
+    assert!(!fail.keep && !fail.skipped_short);
+    assert_eq!(fail.complexity[1], 0.0);
+
+    let pass = verdict(FilterConfig {
+        short_reads: ShortReadPolicy::Pass,
+        ..FilterConfig::default()
+    });
+    assert!(pass.keep);
+
+    let skip = verdict(FilterConfig {
+        short_reads: ShortReadPolicy::Skip,
+        ..FilterConfig::default()
+    });
+    assert!(!skip.keep && skip.skipped_short);
+
+    // With k = 11 the short mate has kmers of its own and passes on merit
+    let small_k = verdict(FilterConfig {
+        kmer_size: 11,
+        short_reads: ShortReadPolicy::Skip,
+        ..FilterConfig::default()
+    });
+    assert!(small_k.keep && !small_k.skipped_short);
+    assert_eq!(
+        filter::calculate_kmer_complexity(&[b'A'; 100], 11),
+        1.0 / 90.0
+    );
+}
+
+#[test]
+fn pair_is_removed_when_either_mate_is_low_complexity() {
+    let good = random_sequence(100, 1);
+    let polya = "A".repeat(100);
+    let mut records = build(mapped_pair("good", &good, &good));
+    records.extend(build(mapped_pair("bad", &good, &polya)));
+
+    let run = filter_records(records, &FilterConfig::default()).unwrap();
+    assert_eq!(run.kept.len(), 1);
+    assert_eq!(run.kept[0].0.qname(), b"good");
+    assert_eq!(run.rejected[0].0.qname(), b"bad");
+}
+
+#[test]
+fn mate_rescue_needs_a_complex_confident_nearby_mate() {
+    let good = random_sequence(100, 1);
+    let polya = "A".repeat(100);
+    let (anchor, repeat) = mapped_pair("rescued", &good, &polya);
+    let mut records = vec![anchor.build(), repeat.build()];
+    let (anchor, repeat) = mapped_pair("low_mapq", &good, &polya);
+    records.extend([anchor.mapq(10).build(), repeat.build()]);
+    let (anchor, repeat) = mapped_pair("far", &good, &polya);
+    records.extend([anchor.build(), repeat.pos(0, 90_000).build()]);
+    records.extend(build(mapped_pair("both_low", &polya, &polya)));
+    let rescue = FilterConfig {
+        mate_rescue: Some(filter::MateRescue {
+            min_mapq: 30,
+            max_distance: 1000,
+        }),
+        ..FilterConfig::default()
+    };
+
+    assert!(filter_records(records.clone(), &FilterConfig::default())
+        .unwrap()
+        .kept
+        .is_empty());
+    let run = filter_records(records, &rescue).unwrap();
+    assert_eq!(run.kept.len(), 1);
+    assert_eq!(run.kept[0].0.qname(), b"rescued");
+    let rescued: Vec<bool> = run.verdicts.iter().map(|verdict| verdict.rescued).collect();
+    assert_eq!(rescued, [true, false, false, false]);
+}
+
+#[test]
+fn longest_mapped_stretch_ignores_clips_and_splits_on_indels() {
+    let seq = random_sequence(100, 2);
+    let record = RecordBuilder::new("r")
+        .seq(&seq)
+        .cigar("10S40M2I48M")
+        .build();
+    assert_eq!(filter::get_longest_mapped_bases(&record), 48);
+
+    let spliced = RecordBuilder::new("r")
+        .seq(&seq)
+        .cigar("50M500N50M")
+        .build();
+    assert_eq!(filter::get_longest_mapped_bases(&spliced), 50);
+    assert_eq!(filter::get_longest_spliced_mapped_bases(&spliced), 100);
+    assert_eq!(filter::count_splice_junctions(&spliced), 1);
+}
+
+#[test]
+fn soft_clips_are_assigned_to_the_sequenced_ends() {
+    let seq = random_sequence(100, 3);
+    let forward = RecordBuilder::new("r").seq(&seq).cigar("5H10S85M5S");
+    assert_eq!(soft_clips(&forward.build()), (10, 5));
+    // BAM stores reverse-strand reads reverse-complemented: the leading clip is 3'
+    assert_eq!(soft_clips(&forward.flags(0x10).build()), (5, 10));
+    let unclipped = RecordBuilder::new("r").seq(&seq).cigar("100M").build();
+    assert_eq!(soft_clips(&unclipped), (0, 0));
+}
+
+#[test]
+fn min_mapped_requires_both_mates() {
+    let seq = random_sequence(100, 3);
+    let (record1, record2) = mapped_pair("clipped", &seq, &seq);
+    let records = vec![record1.build(), record2.cigar("40S60M").build()];
+    let config = FilterConfig {
+        min_mapped: 90,
+        ..FilterConfig::default()
+    };
+
+    let run = filter_records(records, &config).unwrap();
+    assert!(run.kept.is_empty());
+    assert_eq!(run.verdicts[0].longest_mapped, Some([100, 60]));
+}
+
+#[test]
+fn gap_compressed_identity_counts_each_indel_once() {
+    let seq = random_sequence(100, 10);
+    // 10 inserted bases and 2 mismatches: (2 + 1) / (90 + 1)
+    let (record1, record2) = mapped_pair("indel", &seq, &seq);
+    let record1 = record1.cigar("45M10I45M").tag_int(b"NM", 12);
+    let divergence = metrics::gap_compressed_divergence(&record1.build()).unwrap();
+    assert!((divergence - 3.0 / 91.0).abs() < 1e-12);
+
+    for (min, kept) in [(0.96, 1), (0.97, 0)] {
+        let config = FilterConfig {
+            min_gap_compressed_identity: Some(min),
+            ..FilterConfig::default()
+        };
+        let records = vec![record1.build(), record2.clone().tag_int(b"NM", 0).build()];
+        assert_eq!(filter_records(records, &config).unwrap().kept.len(), kept);
+    }
+
+    // Without NM the identity is unknown and the pair fails
+    assert_eq!(metrics::gap_compressed_divergence(&record2.build()), None);
+}
+
+#[test]
+fn missing_nm_and_md_are_regenerated_from_the_reference() {
+    let scratch = Scratch::new("regenerate-md");
+    let genome = random_sequence(2000, 31);
+    let fasta = scratch.path("ref.fa");
+    let mut lines = String::from(">chr1\n");
+    for line in genome.as_bytes().chunks(60) {
+        lines.push_str(std::str::from_utf8(line).unwrap());
+        lines.push('\n');
+    }
+    std::fs::write(&fasta, lines).unwrap();
+
+    // A mismatch at 1005, 2 inserted bases and 1030..1033 deleted
+    let mismatch = match genome.as_bytes()[1005] {
+        b'A' => 'C',
+        _ => 'A',
+    };
+    let seq1 = format!(
+        "{}{}{}GG{}{}",
+        &genome[1000..1005],
+        mismatch,
+        &genome[1006..1010],
+        &genome[1010..1030],
+        &genome[1033..1063]
+    );
+    let seq2 = &genome[1200..1300];
+    let (record1, record2) = mapped_pair("calmd", &seq1, seq2);
+    let mut record1 = record1.cigar("10M2I20M3D30M").build();
+    let mut record2 = record2.tag_int(b"NM", 0).build();
+    let (md, nm) = md::compute_md_nm(&record1, &genome.as_bytes()[1000..1063]);
+    assert_eq!(
+        md,
+        format!("5{}24^{}30", &genome[1005..1006], &genome[1030..1033])
+    );
+    assert_eq!(nm, 6);
+
+    let header = rust_htslib::bam::HeaderView::from_header(&common::reference_header());
+    let mut regenerator = md::MdRegenerator::new(&fasta, &header).unwrap();
+    regenerator.fill(&mut record1).unwrap();
+    regenerator.fill(&mut record2).unwrap();
+    assert_eq!(record1.aux(b"NM").unwrap(), Aux::I32(6));
+    assert_eq!(record1.aux(b"MD").unwrap(), Aux::String(&md));
+    // The NM already there is kept
+    assert_eq!(record2.aux(b"NM").unwrap(), Aux::I32(0));
+    assert_eq!(record2.aux(b"MD").unwrap(), Aux::String("100"));
+    regenerator.fill(&mut record2).unwrap();
+    assert_eq!(regenerator.regenerated(), 2);
+
+    // A mapped record with SEQ `*` is left without tags
+    let (no_seq, _) = mapped_pair("noseq", "", "");
+    let mut no_seq = no_seq.cigar("100M").build();
+    regenerator.fill(&mut no_seq).unwrap();
+    assert!(no_seq.aux(b"NM").is_err() && no_seq.aux(b"MD").is_err());
+    assert_eq!(regenerator.regenerated(), 2);
+
+    let config = FilterConfig {
+        min_gap_compressed_identity: Some(0.9),
+        ..FilterConfig::default()
+    };
+    assert_eq!(
+        filter_records(vec![record1, record2], &config)
+            .unwrap()
+            .kept
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn explicit_match_cigars_give_identity_without_nm_or_md() {
+    let seq = random_sequence(100, 12);
+    let (record1, record2) = mapped_pair("eqx", &seq, &seq);
+    // 2 mismatches, 3 inserted and 2 deleted bases
+    let record1 = record1.cigar("4S40=1X20=3I10=2D1X21=").build();
+    let events = metrics::base_events(&record1).unwrap();
+    assert_eq!((events.matches, events.mismatches), (91, 2));
+    assert_eq!((events.inserted, events.deleted, events.gaps), (3, 2, 2));
+    assert_eq!(metrics::edit_distance(&record1), Some(7));
+    assert_eq!(metrics::identity(&record1), Some(91.0 / 98.0));
+    // pos 1000: 40 = then X; after 20 = 3 I 10 = 2 D comes the second X
+    assert_eq!(
+        metrics::mismatch_positions(&record1),
+        Some(vec![(1040, 44), (1073, 78)])
+    );
+    let divergence = metrics::gap_compressed_divergence(&record1).unwrap();
+    assert!((divergence - 4.0 / 95.0).abs() < 1e-12);
+
+    // M leaves mismatches to NM or MD
+    let record2 = record2.build();
+    assert_eq!(metrics::base_events(&record2), None);
+    assert_eq!(metrics::identity(&record2), None);
+
+    let config = FilterConfig {
+        expression: Some(Expr::parse("r1.identity > 0.9 && r1.mismatches <= 2").unwrap()),
+        ..FilterConfig::default()
+    };
+    let kept = filter_records(vec![record1.clone(), record2.clone()], &config).unwrap();
+    assert_eq!(kept.kept.len(), 1);
+    let strict = FilterConfig {
+        expression: Some(Expr::parse("r1.mismatches < 2").unwrap()),
+        ..FilterConfig::default()
+    };
+    assert!(filter_records(vec![record1, record2], &strict)
+        .unwrap()
+        .kept
+        .is_empty());
+}
+
+#[test]
+fn divergence_prefers_the_de_tag() {
+    let seq = random_sequence(100, 11);
+    let (record1, _) = mapped_pair("de", &seq, &seq);
+    let record1 = record1.tag_int(b"NM", 5);
+    assert_eq!(metrics::divergence(&record1.build()), Some(0.05));
+    let tagged = record1.tag_float(b"de", 0.25).build();
+    assert_eq!(metrics::divergence(&tagged), Some(0.25));
+}
+
+#[test]
+fn min_mapq_removes_pairs_with_either_mate_below() {
+    let seq = random_sequence(100, 4);
+    let mut records = build(mapped_pair("mapped", &seq, &seq));
+    records.extend(build(unmapped_pair("unmapped", &seq, &seq)));
+    let (record1, record2) = mapped_pair("one_low", &seq, &seq);
+    let one_low = (record1.build(), record2.mapq(29).build());
+    records.extend([one_low.0.clone(), one_low.1.clone()]);
+    let config = FilterConfig {
+        min_mapq: 30,
+        ..FilterConfig::default()
+    };
+    assert!(config.evaluate(&one_low.0, &one_low.1, &mut 0).low_mapq);
+    assert!(!config.evaluate(&records[0], &records[1], &mut 0).low_mapq);
+
+    let run = filter_records(records, &config).unwrap();
+    assert_eq!(run.kept.len(), 1);
+    assert_eq!(run.rejected[0].0.qname(), b"unmapped");
+    assert_eq!(run.rejected[1].0.qname(), b"one_low");
+}
+
+#[test]
+fn base_quality_filters_judge_each_mate() {
+    let seq = random_sequence(100, 5);
+    // Mean 30.5: 90 bases at Q30, 10 at Q35
+    let mixed: Vec<u8> = (0..100).map(|i| if i < 90 { 30 } else { 35 }).collect();
+    let (record1, record2) = mapped_pair("mixed", &seq, &seq);
+    let (good, mixed) = (
+        record1.qual(&[40; 100]).build(),
+        record2.qual(&mixed).build(),
+    );
+    assert_eq!(quality::mean_base_quality(&mixed), Some(30.5));
+    assert_eq!(quality::fraction_at_least(&mixed, 31), Some(0.1));
+    let (record1, _) = mapped_pair("noqual", &seq, &seq);
+    let noqual = record1.qual(&[0xff; 100]).build();
+    assert_eq!(quality::mean_base_quality(&noqual), None);
+
+    let config = FilterConfig {
+        min_avg_baseq: Some(31.0),
+        ..FilterConfig::default()
+    };
+    let verdict = config.evaluate(&good, &mixed, &mut 0);
+    assert!(!verdict.keep);
+    assert!(verdict.failed.contains(Thresholds::MIN_AVG_BASEQ));
+    assert!(config.evaluate(&good, &good, &mut 0).keep);
+
+    let threshold: quality::BaseqFraction = "30:0.95".parse().unwrap();
+    let config = FilterConfig {
+        min_baseq_fraction: Some(threshold),
+        ..FilterConfig::default()
+    };
+    assert!(config.evaluate(&good, &mixed, &mut 0).keep);
+    assert!(!config.evaluate(&good, &noqual, &mut 0).keep);
+    let strict = FilterConfig {
+        min_baseq_fraction: Some("31:0.5".parse().unwrap()),
+        ..FilterConfig::default()
+    };
+    assert!(strict
+        .evaluate(&good, &mixed, &mut 0)
+        .failed
+        .contains(Thresholds::MIN_BASEQ_FRACTION));
+
+    for bad in ["30", "30:1.5", "x:0.5", "300:0.5"] {
+        assert!(bad.parse::<quality::BaseqFraction>().is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn insert_size_and_same_reference_filters() {
+    let seq = random_sequence(100, 6);
+    // mapped_pair has |TLEN| 300
+    let normal = build(mapped_pair("normal", &seq, &seq));
+    let (record1, record2) = mapped_pair("trans", &seq, &seq);
+    let trans = build((
+        record1.mate_pos(1, 500).insert_size(0),
+        record2.pos(1, 500).insert_size(0),
+    ));
+    let unmapped = build(unmapped_pair("unmapped", &seq, &seq));
+
+    let insert = |min, max| FilterConfig {
+        min_insert: min,
+        max_insert: max,
+        ..FilterConfig::default()
+    };
+    let keep = |config: &FilterConfig, pair: &[rust_htslib::bam::Record]| {
+        config.evaluate(&pair[0], &pair[1], &mut 0).keep
+    };
+    assert!(keep(&insert(Some(300), Some(300)), &normal));
+    assert!(!keep(&insert(Some(301), None), &normal));
+    let short = insert(None, Some(299));
+    let verdict = short.evaluate(&normal[0], &normal[1], &mut 0);
+    assert!(verdict.failed.contains(Thresholds::INSERT_SIZE));
+    // TLEN 0 is within any maximum; only the reference check catches it
+    assert!(keep(&short, &trans));
+    assert!(!keep(&insert(Some(100), None), &trans));
+
+    let same = FilterConfig {
+        require_same_reference: true,
+        ..FilterConfig::default()
+    };
+    assert!(keep(&same, &normal));
+    assert!(!keep(&same, &trans));
+    assert!(!keep(&same, &unmapped));
+
+    assert!(insert(Some(500), Some(100)).validate().is_err());
+}
+
+#[test]
+fn edit_distance_filters_read_nm_and_handle_its_absence() {
+    let seq = random_sequence(100, 7);
+    let tagged = |name: &str, nm1: i32, nm2: i32| {
+        let (record1, record2) = mapped_pair(name, &seq, &seq);
+        build((record1.tag_int(b"NM", nm1), record2.tag_int(b"NM", nm2)))
+    };
+    let clean = tagged("clean", 0, 2);
+    let dirty = tagged("dirty", 0, 8);
+    let untagged = build(mapped_pair("untagged", &seq, &seq));
+    // Without NM, mismatches are counted from an =/X CIGAR
+    let (record1, record2) = mapped_pair("explicit", &seq, &seq);
+    let explicit = build((record1.cigar("95=5X"), record2.cigar("100=")));
+
+    let config = |max_nm, max_error_rate, missing_nm| FilterConfig {
+        max_nm,
+        max_error_rate,
+        missing_nm,
+        ..FilterConfig::default()
+    };
+    let keep = |config: &FilterConfig, pair: &[rust_htslib::bam::Record]| {
+        config.evaluate(&pair[0], &pair[1], &mut 0).keep
+    };
+    let nm = config(Some(5), None, MissingNm::Error);
+    assert!(keep(&nm, &clean));
+    assert!(keep(&nm, &explicit));
+    let verdict = nm.evaluate(&dirty[0], &dirty[1], &mut 0);
+    assert!(verdict.failed.contains(Thresholds::MAX_NM));
+    let rate = config(None, Some(0.05), MissingNm::Error);
+    assert!(keep(&rate, &clean) && keep(&rate, &explicit) && !keep(&rate, &dirty));
+    assert_eq!(metrics::error_rate(&dirty[1]), Some(0.08));
+
+    // A read without NM stops the run, or fails or passes as asked
+    assert!(nm
+        .check_edit_distances(&[&untagged[0], &untagged[1]])
+        .is_err());
+    assert!(nm
+        .check_edit_distances(&[&explicit[0], &explicit[1]])
+        .is_ok());
+    assert!(!keep(&config(Some(5), None, MissingNm::Fail), &untagged));
+    let lenient = config(Some(5), None, MissingNm::Pass);
+    assert!(keep(&lenient, &untagged));
+    assert!(lenient.check_edit_distances(&[&untagged[0]]).is_ok());
+
+    assert!(config(None, Some(1.5), MissingNm::Error)
+        .validate()
+        .is_err());
+}
+
+#[test]
+fn short_circuit_stops_at_the_first_failing_threshold() {
+    let polya = "A".repeat(100);
+    let (record1, record2) = mapped_pair("both", &polya, &polya);
+    let (record1, record2) = (record1.mapq(5).build(), record2.build());
+    let full = FilterConfig {
+        min_mapq: 30,
+        ..FilterConfig::default()
+    };
+    let names = |set: Thresholds| set.names().collect::<Vec<_>>();
+
+    let verdict = full.evaluate(&record1, &record2, &mut 0);
+    assert_eq!(names(verdict.failed), ["complexity", "min_mapq"]);
+    assert_eq!(verdict.evaluated, full.thresholds());
+
+    // MAPQ is checked first, so the poly-A is never hashed
+    let short_circuit = FilterConfig {
+        short_circuit: Some(FilterOrder::default()),
+        ..full.clone()
+    };
+    let verdict = short_circuit.evaluate(&record1, &record2, &mut 0);
+    assert!(!verdict.keep && verdict.low_mapq);
+    assert_eq!(names(verdict.failed), ["min_mapq"]);
+    assert_eq!(names(verdict.evaluated), ["min_mapq"]);
+    assert!(verdict.complexity[0].is_nan());
+
+    let complexity_first = FilterConfig {
+        short_circuit: Some(FilterOrder::parse("complexity").unwrap()),
+        ..full.clone()
+    };
+    let verdict = complexity_first.evaluate(&record1, &record2, &mut 0);
+    assert_eq!(names(verdict.failed), ["complexity"]);
+    assert!(!verdict.low_mapq);
+
+    // The verdicts agree on every pair, whatever the order
+    let good = random_sequence(100, 5);
+    for (seq, mapq) in [(&good, 60), (&good, 5), (&polya, 60), (&polya, 5)] {
+        let (record1, record2) = mapped_pair("pair", seq, seq);
+        let (record1, record2) = (record1.mapq(mapq).build(), record2.build());
+        let keep = full.evaluate(&record1, &record2, &mut 0).keep;
+        for config in [&short_circuit, &complexity_first] {
+            assert_eq!(config.evaluate(&record1, &record2, &mut 0).keep, keep);
+        }
+    }
+
+    assert_eq!(
+        FilterOrder::parse("min-mapq, --complexity")
+            .unwrap()
+            .indices()[..2],
+        [Thresholds::MIN_MAPQ, Thresholds::COMPLEXITY]
+    );
+    for bad in ["mapq", "complexity,complexity", "filter_expr"] {
+        assert!(FilterOrder::parse(bad).is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn cached_metric_tags_replace_computation() {
+    let polya = "A".repeat(100);
+    let (record1, record2) = mapped_pair("cached", &polya, &polya);
+    let records = vec![
+        record1.tag_float(b"xc", 0.99).tag_int(b"xm", 100).build(),
+        record2.tag_float(b"xc", 0.99).tag_int(b"xm", 100).build(),
+    ];
+    assert!(filter::cached_complexity(&records[0]).is_some());
+    assert_eq!(TAG_COMPLEXITY, b"xc");
+    assert_eq!(TAG_LONGEST_MAPPED, b"xm");
+
+    let config = FilterConfig {
+        min_mapped: 90,
+        use_cached_metrics: true,
+        ..FilterConfig::default()
+    };
+    let run = filter_records(records, &config).unwrap();
+    assert_eq!(run.kept.len(), 1);
+    assert_eq!(run.cached_metrics, 4);
+}
+
+#[test]
+fn bisulfite_collapse_keeps_converted_reads() {
+    // After collapsing C->T, a fully converted read and its unconverted
+    // template are the same sequence
+    let template = random_sequence(100, 5);
+    let converted = template.replace('C', "T");
+    let records = build(mapped_pair("bs", &template, &converted));
+
+    let exact = FilterConfig {
+        bisulfite: true,
+        exact_complexity: true,
+        ..FilterConfig::default()
+    };
+    let run = filter_records(records, &exact).unwrap();
+    let [c1, c2] = run.verdicts[0].complexity;
+    assert_eq!(c1, c2);
+}
+
+#[test]
+fn mismatched_mate_names_fail() {
+    let seq = random_sequence(100, 6);
+    let records = vec![
+        RecordBuilder::new("a").seq(&seq).build(),
+        RecordBuilder::new("b").seq(&seq).build(),
+    ];
+    let error = filter_records(records, &FilterConfig::default()).unwrap_err();
+    assert!(error.to_string().contains("not properly name-sorted"));
+}
+
+#[test]
+fn trailing_unpaired_record_fails() {
+    let seq = random_sequence(100, 8);
+    let error = filter_records(
+        vec![RecordBuilder::new("a").seq(&seq).build()],
+        &FilterConfig::default(),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("unpaired"));
+}
+
+#[test]
+fn resync_skips_orphans_and_repairs_the_rest() {
+    let seq = random_sequence(100, 9);
+    let mut resync = Resync::new(3);
+    let mut pairs = Vec::new();
+    // "b" lost its mate; "d" arrives second mate first
+    for (name, flags) in [
+        ("a", 0x41),
+        ("a", 0x81),
+        ("b", 0x41),
+        ("c", 0x41),
+        ("c", 0x81),
+        ("d", 0x81),
+        ("d", 0x41),
+        ("e", 0x41),
+    ] {
+        let record = RecordBuilder::new(name).seq(&seq).flags(flags).build();
+        pairs.extend(resync.push(record));
+    }
+    resync.finish();
+
+    let names: Vec<_> = pairs
+        .iter()
+        .map(|(record1, record2)| {
+            assert!(record1.is_first_in_template() && record2.is_last_in_template());
+            String::from_utf8_lossy(record1.qname()).into_owned()
+        })
+        .collect();
+    assert_eq!(names, ["a", "c", "d"]);
+    assert_eq!(resync.orphans(), 2);
+}
+
+#[test]
+fn global_kmer_model_flags_a_dominant_contaminant() {
+    let seq = random_sequence(60, 3);
+    let revcomp: String = seq
+        .bytes()
+        .rev()
+        .map(|base| match base {
+            b'A' => 'T',
+            b'C' => 'G',
+            b'G' => 'C',
+            _ => 'A',
+        })
+        .collect();
+    let forward: Vec<u64> = global_kmers::canonical_kmers(seq.as_bytes(), 21).collect();
+    let mut reverse: Vec<u64> = global_kmers::canonical_kmers(revcomp.as_bytes(), 21).collect();
+    reverse.reverse();
+    assert_eq!(forward.len(), 40);
+    assert_eq!(forward, reverse);
+    assert_eq!(global_kmers::canonical_kmers(b"ACGTNACGT", 4).count(), 2);
+
+    // A third of the pairs come from one contaminant, complex within each read
+    let scratch = Scratch::new("global-kmers");
+    let path = scratch.path("in.bam");
+    let contaminant = random_sequence(100, 999);
+    let mut writer = rust_htslib::bam::Writer::from_path(
+        &path,
+        &common::reference_header(),
+        rust_htslib::bam::Format::Bam,
+    )
+    .unwrap();
+    for i in 0..300u64 {
+        let seq = if i % 3 == 0 {
+            contaminant.clone()
+        } else {
+            random_sequence(100, i)
+        };
+        for record in build(mapped_pair(&format!("pair{i:03}"), &seq, &seq)) {
+            writer.write(&record).unwrap();
+        }
+    }
+    drop(writer);
+
+    let model = global_kmers::GlobalKmers::build(&path, None, 21, 99.0, 0.5, 1 << 20).unwrap();
+    assert_eq!(model.kmers, 600 * 80);
+    assert!(model.threshold() < 10);
+    let flagged = |seq: &str| !model.passes(&RecordBuilder::new("r").seq(seq).build());
+    assert!(flagged(&contaminant));
+    assert!(!flagged(&random_sequence(100, 5)));
+    assert!(!flagged("ACGT"));
+}
+
+#[test]
+fn kmer_dumps_drive_the_frequency_and_blacklist_filters() {
+    let scratch = Scratch::new("kmer-db");
+    let contaminant = random_sequence(100, 999);
+    let kmers = |seq: &str| -> Vec<String> {
+        (0..=seq.len() - 21)
+            .map(|i| seq[i..i + 21].to_string())
+            .collect()
+    };
+
+    // jellyfish dump -c: the contaminant's kmers are far more frequent
+    let counts = scratch.path("counts.txt");
+    let mut dump = String::new();
+    for kmer in kmers(&contaminant) {
+        dump += &format!("{} 500\n", kmer);
+    }
+    for seed in 0..50 {
+        for kmer in kmers(&random_sequence(100, seed)) {
+            dump += &format!("{}\t3\n", kmer);
+        }
+    }
+    std::fs::write(&counts, dump).unwrap();
+    let model = global_kmers::GlobalKmers::load(&counts, 21, 95.0, 0.5, 1 << 20).unwrap();
+    assert_eq!(model.threshold(), 3);
+    assert!(!model.passes(&RecordBuilder::new("r").seq(&contaminant).build()));
+    assert!(model.passes(
+        &RecordBuilder::new("r")
+            .seq(&random_sequence(100, 7))
+            .build()
+    ));
+
+    // FASTA-style dump of the contaminant's first 30 kmers as a blacklist
+    let list = scratch.path("blacklist.fa");
+    let fasta: String = kmers(&contaminant)[..30]
+        .iter()
+        .map(|kmer| format!(">1\n{}\n", kmer))
+        .collect();
+    std::fs::write(&list, fasta).unwrap();
+    let blacklist = kmer_db::KmerBlacklist::load(&list, 21, 10).unwrap();
+    assert_eq!(blacklist.len(), 30);
+    let record = RecordBuilder::new("r").seq(&contaminant).build();
+    assert_eq!(blacklist.hits(&record), 30);
+    assert!(!blacklist.passes(&record));
+    assert!(blacklist.passes(&RecordBuilder::new("r").seq(&contaminant[40..]).build()));
+
+    std::fs::write(&list, "ACGTACGT 4\n").unwrap();
+    let error = kmer_db::KmerBlacklist::load(&list, 21, 0).err().unwrap();
+    assert!(error.to_string().contains("blacklist.fa:1"));
+}
+
+#[test]
+fn name_collisions_find_reused_names() {
+    let mut collisions = NameCollisions::new(1 << 20);
+    for i in 0..1000 {
+        assert!(!collisions.check(format!("read{i}").as_bytes()));
+    }
+    assert!(collisions.check(b"read17"));
+    assert_eq!(collisions.collisions(), 1);
+    assert!(collisions.false_positive_rate() < 1e-6);
+}
+
+#[test]
+fn hash_sample_buckets_partition_the_pairs() {
+    let mut records = Vec::new();
+    for i in 0..300 {
+        let seq = random_sequence(100, i);
+        records.extend(build(mapped_pair(&format!("read{i}"), &seq, &seq)));
+    }
+    let mut kept = 0;
+    for bucket in ["1/3", "2-3/3"] {
+        let config = FilterConfig {
+            hash_sample: Some(bucket.parse::<HashSample>().unwrap()),
+            ..FilterConfig::default()
+        };
+        let run = filter_records(records.clone(), &config).unwrap();
+        assert!(run.kept.len() > 50, "{bucket}: {}", run.kept.len());
+        kept += run.kept.len();
+    }
+    assert_eq!(kept, 300);
+
+    for invalid in ["0/3", "4/3", "3-2/3", "1", "a/b"] {
+        assert!(invalid.parse::<HashSample>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn invalid_cutoff_is_rejected() {
+    let config = FilterConfig {
+        complexity: 1.5,
+        ..FilterConfig::default()
+    };
+    assert!(filter_records(Vec::new(), &config).is_err());
+}
+
+#[test]
+fn expression_compares_mates() {
+    let seq = random_sequence(100, 9);
+    let (near1, near2) = mapped_pair("near", &seq, &seq);
+    let (far1, far2) = mapped_pair("far", &seq, &seq);
+    let records = vec![
+        near1.build(),
+        near2.build(),
+        far1.build(),
+        far2.pos(0, 50_000).mapq(10).build(),
+    ];
+    let config = FilterConfig {
+        expression: Some(
+            Expr::parse("abs(r1.pos - r2.pos) < 1000 && min(r1.mapq, r2.mapq) >= 20").unwrap(),
+        ),
+        ..FilterConfig::default()
+    };
+
+    let run = filter_records(records, &config).unwrap();
+    assert_eq!(run.kept.len(), 1);
+    assert_eq!(run.kept[0].0.qname(), b"near");
+}
+
+#[test]
+fn mateless_fields_judge_each_read_and_tags_are_readable() {
+    let seq = random_sequence(100, 11);
+    let pair = |name: &str, mapq2: u8, score2: Option<i32>| {
+        let (record1, record2) = mapped_pair(name, &seq, &seq);
+        let record2 = record2.mapq(mapq2);
+        let record2 = match score2 {
+            Some(score) => record2.tag_int(b"AS", score),
+            None => record2,
+        };
+        [record1.build(), record2.build()]
+    };
+    let records: Vec<_> = [
+        pair("confident", 60, None),
+        pair("rescued", 10, Some(120)),
+        pair("weak", 10, Some(40)),
+        pair("untagged", 10, None),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let kept = |source: &str| -> Vec<String> {
+        let config = FilterConfig {
+            expression: Some(Expr::parse(source).unwrap()),
+            ..FilterConfig::default()
+        };
+        filter_records(records.clone(), &config)
+            .unwrap()
+            .kept
+            .iter()
+            .map(|(record1, _)| String::from_utf8(record1.qname().to_vec()).unwrap())
+            .collect()
+    };
+
+    let per_read = "complexity >= 0.8 && (mapq >= 20 || tag.AS >= 100)";
+    assert!(Expr::parse(per_read).unwrap().is_per_read());
+    assert_eq!(kept(per_read), ["confident", "rescued"]);
+    // Mate fields still work beside mate-less ones, and alias names resolve
+    assert_eq!(
+        kept("longest_match >= 100 && r1.mapq >= 20 && !has(r2.tag.AS)"),
+        ["confident", "untagged"]
+    );
+    assert!(!Expr::parse("r1.mapq > r2.mapq").unwrap().is_per_read());
+    assert_eq!(kept("r2.tag.AS < 100"), ["weak"]);
+}
+
+#[test]
+fn expression_errors_name_the_problem() {
+    for (source, message) in [
+        ("r1.mapq >", "unexpected end"),
+        ("r3.mapq > 1", "unknown name"),
+        ("r1.bogus > 1", "unknown field"),
+        ("r1.mapq = 1", "=="),
+        ("1 < 2 < 3", "chained"),
+        ("abs(1, 2)", "argument"),
+        ("tag.N > 1", "not a SAM tag"),
+        ("has(mapq)", "has takes a tag"),
+    ] {
+        let error = Expr::parse(source).unwrap_err().to_string();
+        assert!(error.contains(message), "{source}: {error}");
+    }
+}
+
+#[test]
+fn quality_sample_flags_shifted_missing_and_corrupt_encodings() {
+    let seq = random_sequence(60, 7);
+    let sample_of = |qual: &[u8]| {
+        let mut sample = QualitySample::default();
+        for i in 0..10 {
+            sample.observe(
+                &RecordBuilder::new(&format!("r{i}"))
+                    .seq(&seq)
+                    .qual(qual)
+                    .build(),
+            );
+        }
+        sample.problem()
+    };
+    let ramp = |low: u8| -> Vec<u8> { (0..60).map(|i| low + (i % 40) as u8).collect() };
+
+    assert_eq!(sample_of(&ramp(2)), None);
+    assert_eq!(
+        sample_of(&ramp(33)),
+        Some(EncodingProblem::Phred64 { min: 33, max: 72 })
+    );
+    assert_eq!(sample_of(&[0xff; 60]), Some(EncodingProblem::Missing));
+    assert_eq!(
+        sample_of(&ramp(60)),
+        Some(EncodingProblem::OutOfRange { max: 99 })
+    );
+}
+
+#[test]
+fn nanopore_start_times_parse_with_any_zone() {
+    // 2023-06-01T12:00:00Z
+    let noon = 1_685_620_800;
+    assert_eq!(parse_start_time("2023-06-01T12:00:00Z"), Some(noon));
+    assert_eq!(
+        parse_start_time("2023-06-01T12:00:00.987+00:00"),
+        Some(noon)
+    );
+    assert_eq!(parse_start_time("2023-06-01T14:00:00+02:00"), Some(noon));
+    assert_eq!(parse_start_time("2023-06-01T07:30:00-04:30"), Some(noon));
+    assert_eq!(parse_start_time("1970-01-01T00:00:00Z"), Some(0));
+    assert_eq!(parse_start_time("2023-13-01T00:00:00Z"), None);
+    assert_eq!(parse_start_time("yesterday"), None);
+}
+
+#[test]
+fn name_lists_match_in_every_mode() {
+    assert_eq!(list_name(b"@read7/1 1:N:0:ACGT\n"), b"read7");
+    assert_eq!(list_name(b"read8\n"), b"read8");
+
+    let scratch = Scratch::new("name-lists");
+    let path = scratch.path("names.txt");
+    let listed: Vec<String> = (0..1000).map(|i| format!("pair{i:06}")).collect();
+    std::fs::write(&path, listed.join("\n") + "\n").unwrap();
+
+    for matching in [NameMatch::Exact, NameMatch::Hashed, NameMatch::Bloom] {
+        let set = NameSet::read(&path, matching, 1 << 16).unwrap();
+        assert_eq!(set.names(), 1000);
+        assert!(listed.iter().all(|name| set.contains(name.as_bytes())));
+        let strangers = (1000..11000)
+            .filter(|i| set.contains(format!("pair{i:06}").as_bytes()))
+            .count();
+        // 64 KiB of Bloom filter for 1000 names: about one false positive in 10^7
+        assert_eq!(strangers, 0, "{matching:?}");
+    }
+}
+
+#[test]
+fn metric_cache_reproduces_fresh_verdicts_and_pass_rates() {
+    let scratch = Scratch::new("metric-cache");
+    let path = scratch.path("pairs.fbpm");
+    let pairs: Vec<_> = (0..100u64)
+        .map(|seed| {
+            // Repeats of varying share and lengths of 40-119 bp spread both metrics
+            let length = 40 + (seed as usize * 7) % 80;
+            let random = random_sequence(length - length * (seed as usize % 5) / 5, seed);
+            let seq = random.clone() + &"A".repeat(length - random.len());
+            let (record1, record2) = mapped_pair(&format!("pair{seed:03}"), &seq, &seq);
+            (record1.build(), record2.build())
+        })
+        .collect();
+
+    let measuring = FilterConfig::default();
+    let mut writer = MetricCacheWriter::create(&path, &measuring).unwrap();
+    for (record1, record2) in &pairs {
+        writer
+            .write_pair(record1, &measuring.measure(record1, record2))
+            .unwrap();
+    }
+    writer.finish().unwrap();
+
+    let (cutoffs, mins) = ([0.3, 0.6, 0.9], [0, 60, 100]);
+    let grid = tune(&path, &cutoffs, &mins).unwrap();
+    assert_eq!(grid.pairs, 100);
+    for (row, &complexity) in cutoffs.iter().enumerate() {
+        for (column, &min_mapped) in mins.iter().enumerate() {
+            let config = FilterConfig {
+                complexity,
+                min_mapped,
+                ..FilterConfig::default()
+            };
+            let mut cache = MetricCacheReader::open(&path).unwrap();
+            cache.check_options(&config).unwrap();
+            let mut kept = 0;
+            for (record1, record2) in &pairs {
+                let metrics = cache.next_pair(record1).unwrap();
+                let cached = config.evaluate_measured(record1, record2, &metrics, &mut 0);
+                let fresh = config.evaluate(record1, record2, &mut 0);
+                assert_eq!(cached.keep, fresh.keep);
+                kept += fresh.keep as u64;
+            }
+            cache.finish().unwrap();
+            assert_eq!(grid.kept[row * mins.len() + column], kept);
+        }
+    }
+
+    // Metrics depend on --bisulfite, so a cache without it can't serve such a run
+    let bisulfite = FilterConfig {
+        bisulfite: true,
+        ..FilterConfig::default()
+    };
+    let cache = MetricCacheReader::open(&path).unwrap();
+    assert!(cache.check_options(&bisulfite).is_err());
+}
+
+#[test]
+fn ligation_motifs_match_either_strand_with_n_wildcards() {
+    let seq = random_sequence(100, 9);
+    let read = |insert: &str| {
+        let (record, _) = mapped_pair(
+            "hic",
+            &format!("{}{}{}", &seq[..30], insert, &seq[38..]),
+            &seq,
+        );
+        record.build()
+    };
+
+    // GANTGATC is not palindromic: a reverse-strand read stores GATCANTC
+    let arima = hic::parse_motifs("GATCGATC,gantgatc").unwrap();
+    assert_eq!(arima.to_string(), "GATCGATC,GANTGATC");
+    assert_eq!(arima.find_junction(&read("GATCGATC")), Some(30));
+    assert_eq!(arima.find_junction(&read("GACTGATC")), Some(30));
+    assert_eq!(arima.find_junction(&read("GATCAGTC")), Some(30));
+    assert_eq!(arima.find_junction(&read(&seq[30..38])), None);
+    let dpnii = hic::parse_motifs("GATCGATC").unwrap();
+    assert_eq!(dpnii.find_junction(&read("GACTGATC")), None);
+
+    for (value, error) in [
+        ("GATCGATC,", "expected comma-separated motifs"),
+        ("GATCRATC", "'R' is not a motif base"),
+        ("NNNN", "would match every read"),
+    ] {
+        let message = hic::parse_motifs(value).unwrap_err();
+        assert!(message.contains(error), "{}: {}", value, message);
+    }
+}
+
+#[test]
+fn primers_are_soft_clipped_and_pairs_matched_to_amplicons() {
+    let scratch = Scratch::new("primers");
+    let bed = scratch.path("primers.bed");
+    std::fs::write(
+        &bed,
+        "browser position chr1:1000-1300\n\
+         track name=scheme\n\
+         chr1\t1000\t1025\tscheme_1_LEFT\t1\t+\n\
+         chr1\t1280\t1300\tscheme_1_RIGHT\t1\t-\n\
+         chr1\t1275\t1298\tscheme_1_RIGHT_alt1\t1\t-\n",
+    )
+    .unwrap();
+    let header = rust_htslib::bam::HeaderView::from_header(&common::reference_header());
+    let scheme = PrimerScheme::read_bed(&bed, &header).unwrap();
+    assert_eq!((scheme.primers(), scheme.amplicons()), (3, 1));
+
+    let unnamed = scratch.path("unnamed.bed");
+    std::fs::write(&unnamed, "chr1\t1000\t1025\n").unwrap();
+    assert_eq!(
+        PrimerScheme::read_bed(&unnamed, &header)
+            .unwrap_err()
+            .to_string(),
+        format!("{} line 1: expected a primer name", unnamed)
+    );
+
+    let seq = random_sequence(100, 5);
+    let (record1, record2) = mapped_pair("amplicon", &seq, &seq);
+    let (mut record1, mut record2) = (record1.build(), record2.build());
+    assert_eq!(
+        scheme.amplicon_of(&record1, &record2).unwrap().name,
+        "scheme_1"
+    );
+    assert_eq!(scheme.clip(&mut record1), Some(25));
+    assert_eq!(
+        (record1.pos(), record1.cigar().to_string()),
+        (1025, "25S75M".into())
+    );
+    // The alternative primer extends the right primer region to 1275
+    assert_eq!(scheme.clip(&mut record2), Some(25));
+    assert_eq!(
+        (record2.pos(), record2.cigar().to_string()),
+        (1200, "75M25S".into())
+    );
+    assert_eq!(record1.seq().as_bytes(), seq.as_bytes());
+
+    let (stray1, stray2) = mapped_pair("stray", &seq, &seq);
+    let (stray1, stray2) = (stray1.pos(0, 5000).build(), stray2.build());
+    assert!(scheme.amplicon_of(&stray1, &stray2).is_none());
+}
+
+#[test]
+fn soft_clipping_drops_edge_gaps_and_keeps_the_query() {
+    let seq = random_sequence(102, 9);
+    let mut record = RecordBuilder::new("gapped")
+        .seq(&seq)
+        .cigar("10S30M2I20M3D40M")
+        .pos(0, 1000)
+        .build();
+    assert_eq!(soft_clip_outside(&mut record, 1035, 1050), Some(77));
+    assert_eq!(record.pos(), 1035);
+    assert_eq!(record.cigar().to_string(), "47S15M40S");
+    assert_eq!(record.seq().as_bytes(), seq.as_bytes());
+    // Nothing would stay aligned
+    assert_eq!(soft_clip_outside(&mut record, 2000, 2010), None);
+    assert_eq!(record.cigar().to_string(), "47S15M40S");
+}
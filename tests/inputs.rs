@@ -0,0 +1,1114 @@
+//! Reading inputs: progress through the file, flag selection and grouping
+//! of extra alignments before pairing, read lengths against the length
+//! options, damage on disk (`--on-read-error`), config files, BED files,
+//! reading only `--regions` and pairing the mates of coordinate-sorted inputs
+
+mod common;
+
+use clap::Parser;
+use common::{reference_header, write_input, Scratch};
+use filter_bam_pairs::commands::{self, Cli};
+use filter_bam_pairs::filter::FilterConfig;
+use filter_bam_pairs::flags::parse_flags;
+use filter_bam_pairs::grouping::Grouper;
+use filter_bam_pairs::lengths::{self, LengthSample};
+use filter_bam_pairs::regions::Regions;
+use filter_bam_pairs::report::Report;
+use filter_bam_pairs::test_utils::{mapped_pair, random_sequence, unmapped_pair};
+use filter_bam_pairs::validation::{self, Violation};
+use filter_bam_pairs::{bed, config, contigs, depth, input, prefetch, progress};
+use rust_htslib::bam::{self, Read as _};
+use std::process::{Command, Output};
+
+const PAIRS: usize = 3000;
+
+/// Size of the empty BGZF block that ends every BAM
+const BGZF_EOF_BLOCK: u64 = 28;
+
+fn filter_bam_pairs(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Offsets of the BGZF block headers in a BAM file
+fn block_starts(bytes: &[u8]) -> Vec<usize> {
+    (0..bytes.len().saturating_sub(16))
+        .filter(|&at| {
+            bytes[at..at + 4] == [0x1f, 0x8b, 0x08, 0x04]
+                && bytes[at + 10..at + 16] == [6, 0, b'B', b'C', 2, 0]
+        })
+        .collect()
+}
+
+/// One report line's number
+fn reported(output: &Output, prefix: &str) -> u64 {
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix(prefix))
+        .unwrap_or_else(|| panic!("the run reports {prefix:?}"))
+        .split_whitespace()
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn bytes_read_follow_the_reader_through_the_file() {
+    let scratch = Scratch::new("bytes-read");
+    let path = scratch.path("in.bam");
+    write_input(&path, PAIRS);
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    let mut reader = input::open(&path, 64, None, None).unwrap();
+    let mut record = bam::Record::new();
+    reader.read(&mut record).unwrap().unwrap();
+    let early = progress::bytes_read(&reader).unwrap();
+    assert!(early > 0 && early < size, "{early} of {size}");
+    while let Some(result) = reader.read(&mut record) {
+        result.unwrap();
+    }
+    // At the end, the block being read is the empty one marking EOF
+    assert_eq!(progress::bytes_read(&reader), Some(size - BGZF_EOF_BLOCK));
+}
+
+#[test]
+fn corrupt_block_fails_by_default_and_is_skipped_on_request() {
+    let scratch = Scratch::new("corrupt-block");
+    let input = scratch.path("in.bam");
+    write_input(&input, PAIRS);
+    let mut bytes = std::fs::read(&input).unwrap();
+    let blocks = block_starts(&bytes);
+    assert!(blocks.len() > 4, "the input spans several blocks");
+    for byte in &mut bytes[blocks[2] + 100..blocks[2] + 160] {
+        *byte ^= 0x5a;
+    }
+    let damaged = scratch.path("damaged.bam");
+    std::fs::write(&damaged, bytes).unwrap();
+    let out = scratch.path("out.bam");
+
+    let failed = filter_bam_pairs(&["-i", &damaged, "-o", &out]);
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(
+        stderr.contains(&format!("virtual offset {}:", blocks[2])),
+        "{stderr}"
+    );
+
+    let refused = filter_bam_pairs(&["-i", &damaged, "-o", &out, "--on-read-error", "skip"]);
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("add --resync"));
+
+    let skipped = filter_bam_pairs(&[
+        "-i",
+        &damaged,
+        "-o",
+        &out,
+        "--on-read-error",
+        "skip",
+        "--resync",
+    ]);
+    assert!(
+        skipped.status.success(),
+        "{}",
+        String::from_utf8_lossy(&skipped.stderr)
+    );
+    assert_eq!(reported(&skipped, "Read errors skipped: "), 1);
+    let total = reported(&skipped, "Total pairs: ") as usize;
+    assert!(total < PAIRS && total > PAIRS / 2, "{total} pairs read");
+}
+
+#[test]
+fn prefetched_input_reads_like_the_reader() {
+    let scratch = Scratch::new("prefetch");
+    let path = scratch.path("in.bam");
+    write_input(&path, PAIRS);
+    const {
+        assert!(
+            2 * PAIRS > prefetch::BATCH_RECORDS,
+            "the input spans batches"
+        )
+    };
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    let mut reader = bam::Reader::from_path(&path).unwrap();
+    let expected: Vec<bam::Record> = reader.records().map(Result::unwrap).collect();
+    let mut source = input::Source::new(bam::Reader::from_path(&path).unwrap(), 2);
+    let mut record = bam::Record::new();
+    let mut read = 0;
+    while let Some(result) = source.read(&mut record) {
+        result.unwrap();
+        assert_eq!(record, expected[read]);
+        read += 1;
+    }
+    assert_eq!(read, expected.len());
+    assert_eq!(source.bytes_read(), Some(size - BGZF_EOF_BLOCK));
+
+    // Errors arrive in order, with the reader stopped where they happened
+    let mut bytes = std::fs::read(&path).unwrap();
+    let blocks = block_starts(&bytes);
+    for byte in &mut bytes[blocks[2] + 100..blocks[2] + 160] {
+        *byte ^= 0x5a;
+    }
+    let damaged = scratch.path("damaged.bam");
+    std::fs::write(&damaged, bytes).unwrap();
+    let out = scratch.path("out.bam");
+    let failed = filter_bam_pairs(&["-i", &damaged, "-o", &out, "--prefetch-batches", "2"]);
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(
+        stderr.contains(&format!("virtual offset {}:", blocks[2])),
+        "{stderr}"
+    );
+
+    let skip = [
+        "-i",
+        &damaged,
+        "-o",
+        &out,
+        "--on-read-error",
+        "skip",
+        "--resync",
+    ];
+    let inline = filter_bam_pairs(&skip);
+    let prefetched = filter_bam_pairs(&[&skip[..], &["--prefetch-batches", "2"]].concat());
+    assert!(
+        prefetched.status.success(),
+        "{}",
+        String::from_utf8_lossy(&prefetched.stderr)
+    );
+    assert_eq!(reported(&prefetched, "Read errors skipped: "), 1);
+    assert_eq!(
+        reported(&prefetched, "Total pairs: "),
+        reported(&inline, "Total pairs: ")
+    );
+}
+
+#[test]
+fn flag_values_parse_as_samtools_writes_them() {
+    assert_eq!(parse_flags("2304"), Ok(0x900));
+    assert_eq!(parse_flags("0x900"), Ok(0x900));
+    assert_eq!(parse_flags("04400"), Ok(0x900));
+    assert_eq!(parse_flags("SECONDARY,SUPPLEMENTARY"), Ok(0x900));
+    assert_eq!(parse_flags("secondary, 0x800"), Ok(0x900));
+    assert_eq!(parse_flags("PROPER_PAIR,DUP"), Ok(0x402));
+    assert!(parse_flags("SECUNDARY")
+        .unwrap_err()
+        .contains("SUPPLEMENTARY"));
+    assert!(parse_flags("0x1000").is_err());
+}
+
+#[test]
+fn config_comments_start_at_the_line_or_after_whitespace() {
+    let scratch = Scratch::new("config-comments");
+    let input = scratch.path("in.bam");
+    write_input(&input, 30);
+    let output = scratch.path("kept#1.bam");
+    let rejected = scratch.path("removed #2.bam");
+    let config = scratch.path("run.conf");
+    std::fs::write(
+        &config,
+        format!(
+            "# whole-line comment\n\
+             complexity = 0.8 # after the value\n\
+             \t# indented\n\
+             output = {}\n\
+             rejected-output = \"{}\" # quoted\n",
+            output, rejected
+        ),
+    )
+    .unwrap();
+    let run = filter_bam_pairs(&["--config", &config, "-i", &input]);
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    assert_eq!(reported(&run, "Filtered pairs: "), 20);
+    assert!(std::path::Path::new(&output).exists());
+    assert!(std::path::Path::new(&rejected).exists());
+}
+
+#[test]
+fn config_files_parse_into_the_options_of_the_run_and_of_each_read_group() {
+    let scratch = Scratch::new("config-library");
+    let config = scratch.path("run.conf");
+    std::fs::write(
+        &config,
+        "complexity = 0.6\nmin-mapq = 10\n\n[read-group hifi]\ncomplexity = 0\nmin-mapped = 50\n",
+    )
+    .unwrap();
+    let argv: Vec<String> = ["filter_bam_pairs", "--config", &config, "-i", "in.bam"]
+        .into_iter()
+        .chain(["-o", "out.bam", "--min-mapq", "20"])
+        .map(str::to_string)
+        .collect();
+    let argv = config::expand_config_args(argv, commands::SUBCOMMANDS).unwrap();
+
+    let args = match Cli::try_parse_from(&argv).unwrap() {
+        Cli {
+            command: None,
+            args: Some(args),
+        } => args,
+        cli => panic!("{cli:?}"),
+    };
+    // The command line wins over the config file
+    assert_eq!((args.complexity, args.min_mapq), (0.6, 20));
+
+    let read_groups = commands::read_group_configs(&argv).unwrap();
+    assert_eq!(read_groups.len(), 1);
+    let (read_group, hifi) = &read_groups[0];
+    assert_eq!(read_group, "hifi");
+    // A section wins over both, for its own thresholds only
+    assert_eq!(
+        (hifi.complexity, hifi.min_mapped, hifi.min_mapq),
+        (0.0, 50, 20)
+    );
+}
+
+#[test]
+fn check_config_lists_every_problem_and_fails_only_on_errors() {
+    let scratch = Scratch::new("check-config");
+    let input = scratch.path("in.bam");
+    write_input(&input, 30);
+    let check = |input: &str, args: &[&str]| {
+        let run = filter_bam_pairs(&[&["check-config", "-i", input][..], args].concat());
+        (
+            run.status.code(),
+            String::from_utf8_lossy(&run.stdout).into_owned(),
+            String::from_utf8_lossy(&run.stderr).into_owned(),
+        )
+    };
+    let sampled = "Sampled 60 records: read length min 100, median 100, max 100\n";
+
+    let out = scratch.path("out.bam");
+    let (status, stdout, _) = check(&input, &["-o", &out]);
+    assert_eq!(status, Some(0));
+    assert_eq!(
+        stdout,
+        format!(
+            "{}Checked configuration for {}\nConfiguration OK (0 warnings)\n",
+            sampled, input
+        )
+    );
+    assert!(!std::path::Path::new(&out).exists());
+
+    // Every error is listed before the run fails
+    let names = scratch.path("names.txt");
+    let reference = scratch.path("ref.fa");
+    let (status, stdout, stderr) = check(
+        &input,
+        &[
+            "-o",
+            &scratch.path("missing/out.bam"),
+            "--reference",
+            &reference,
+            "--exclude-names",
+            &names,
+        ],
+    );
+    assert_eq!(status, Some(1));
+    assert_eq!(
+        stdout,
+        format!(
+            "{}Checked configuration for {}\n\
+             \x20 ERROR: Output directory {} does not exist\n\
+             \x20 ERROR: --exclude-names: cannot open {}: No such file or directory (os error 2)\n\
+             \x20 ERROR: Reference {} does not exist\n",
+            sampled,
+            input,
+            scratch.path("missing"),
+            names,
+            reference
+        )
+    );
+    assert!(stderr.starts_with("Error: Configuration has 3 problem(s)"));
+
+    // An input this tool wrote is an error, or a warning with --force
+    let run = filter_bam_pairs(&["-i", &input, "-o", &out]);
+    assert!(run.status.success());
+    let previous = "Input was already filtered by filter_bam_pairs (@PG ID:filter_bam_pairs)";
+    let again = scratch.path("again.bam");
+    let (status, stdout, _) = check(&out, &["-o", &again]);
+    assert_eq!(status, Some(1));
+    assert!(stdout.contains(&format!(
+        "  ERROR: {}; the run would refuse without --force\n",
+        previous
+    )));
+    let (status, stdout, _) = check(&out, &["-o", &again, "--force"]);
+    assert_eq!(status, Some(0));
+    assert!(stdout.contains(&format!("  WARNING: {}\n", previous)));
+    assert!(stdout.ends_with("Configuration OK (1 warnings)\n"));
+}
+
+#[test]
+fn excluded_flags_drop_extra_alignments_before_pairing() {
+    let scratch = Scratch::new("exclude-flags");
+    let input = scratch.path("in.bam");
+    let mut writer = bam::Writer::from_path(&input, &reference_header(), bam::Format::Bam).unwrap();
+    for i in 0..20 {
+        let seq = random_sequence(100, i);
+        let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &seq, &seq);
+        writer.write(&record1.build()).unwrap();
+        // Every other pair has a secondary and a supplementary alignment between the mates
+        if i % 2 == 0 {
+            writer
+                .write(&record1.clone().flags(0x1 | 0x40 | 0x100).build())
+                .unwrap();
+            writer
+                .write(&record1.clone().flags(0x1 | 0x40 | 0x800).build())
+                .unwrap();
+        }
+        writer.write(&record2.build()).unwrap();
+    }
+    drop(writer);
+    let out = scratch.path("out.bam");
+
+    // Unselected, the extra alignments are grouped with their pair
+    let grouped = filter_bam_pairs(&["-i", &input, "-o", &out]);
+    assert_eq!(reported(&grouped, "Total pairs: "), 20);
+    assert_eq!(
+        reported(
+            &grouped,
+            "Secondary/supplementary records carried with their pair: "
+        ),
+        20
+    );
+
+    let primary = filter_bam_pairs(&[
+        "-i",
+        &input,
+        "-o",
+        &out,
+        "--exclude-flags",
+        "SECONDARY,SUPPLEMENTARY",
+    ]);
+    assert!(
+        primary.status.success(),
+        "{}",
+        String::from_utf8_lossy(&primary.stderr)
+    );
+    assert_eq!(reported(&primary, "Total pairs: "), 20);
+    assert_eq!(reported(&primary, "Records skipped by flag: "), 20);
+
+    // Only the primary records are flagged as properly paired here
+    let required = filter_bam_pairs(&["-i", &input, "-o", &out, "--require-flags", "PROPER_PAIR"]);
+    assert_eq!(reported(&required, "Total pairs: "), 20);
+    assert_eq!(reported(&required, "Records skipped by flag: "), 20);
+}
+
+/// 40 pairs in the record order of `bwa mem -M`: every third has a
+/// supplementary record of its first mate before the last mate and a
+/// secondary one of the last mate after it, every fifth starts with a
+/// secondary record, and every seventh is low-complexity
+fn write_extra_alignments(path: &str) -> u64 {
+    let mut writer = bam::Writer::from_path(path, &reference_header(), bam::Format::Bam).unwrap();
+    let mut extras = 0;
+    for i in 0..40 {
+        let seq = if i % 7 == 0 {
+            "A".repeat(100)
+        } else {
+            random_sequence(100, i)
+        };
+        let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &seq, &seq);
+        if i % 5 == 0 {
+            writer
+                .write(&record2.clone().flags(0x1 | 0x80 | 0x100).build())
+                .unwrap();
+            extras += 1;
+        }
+        writer.write(&record1.build()).unwrap();
+        if i % 3 == 0 {
+            writer
+                .write(&record1.clone().flags(0x1 | 0x40 | 0x800).build())
+                .unwrap();
+        }
+        writer.write(&record2.build()).unwrap();
+        if i % 3 == 0 {
+            writer
+                .write(&record2.clone().flags(0x1 | 0x80 | 0x100).build())
+                .unwrap();
+            extras += 2;
+        }
+    }
+    extras
+}
+
+fn count_records(path: &str) -> (u64, u64) {
+    let mut reader = bam::Reader::from_path(path).unwrap();
+    let (mut primary, mut extra) = (0, 0);
+    for record in reader.records() {
+        let record = record.unwrap();
+        if record.is_secondary() || record.is_supplementary() {
+            extra += 1;
+        } else {
+            primary += 1;
+        }
+    }
+    (primary, extra)
+}
+
+#[test]
+fn recycled_buffers_read_the_same_templates() {
+    let scratch = Scratch::new("recycling");
+    let input = scratch.path("in.bam");
+    write_extra_alignments(&input);
+
+    let mut reader = bam::Reader::from_path(&input).unwrap();
+    let mut grouper = Grouper::new();
+    let mut fresh = Vec::new();
+    while let Some(template) = grouper.next_template(&mut reader) {
+        fresh.push(template.unwrap());
+    }
+
+    // Each template is read into the buffers of the one before
+    let mut reader = bam::Reader::from_path(&input).unwrap();
+    let mut grouper = Grouper::new();
+    let mut templates = 0;
+    while let Some(template) = grouper.next_template(&mut reader) {
+        let template = template.unwrap();
+        let expected = &fresh[templates];
+        assert_eq!(template.record1, expected.record1);
+        assert_eq!(template.record2, expected.record2);
+        assert_eq!(template.extras, expected.extras);
+        templates += 1;
+        grouper.recycle(template);
+    }
+    assert_eq!(templates, fresh.len());
+
+    // Shard writers hand their written records back to be copied into
+    let shards = scratch.path("out.{shard}.bam");
+    let run = filter_bam_pairs(&["-i", &input, "-o", &shards, "--shards", "3"]);
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let single = scratch.path("out.bam");
+    assert!(filter_bam_pairs(&["-i", &input, "-o", &single])
+        .status
+        .success());
+    let records = |paths: &[String]| {
+        let mut records: Vec<Vec<u8>> = paths
+            .iter()
+            .flat_map(|path| {
+                let mut reader = bam::Reader::from_path(path).unwrap();
+                reader
+                    .records()
+                    .map(|record| {
+                        let record = record.unwrap();
+                        [
+                            record.qname(),
+                            &record.flags().to_le_bytes(),
+                            &record.seq().as_bytes(),
+                        ]
+                        .concat()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        records.sort();
+        records
+    };
+    let sharded: Vec<String> = (0..3)
+        .map(|i| scratch.path(&format!("out.{i}.bam")))
+        .collect();
+    assert_eq!(records(&sharded), records(&[single]));
+}
+
+#[test]
+fn extra_alignments_are_grouped_with_their_pair() {
+    let scratch = Scratch::new("extra-alignments");
+    let input = scratch.path("in.bam");
+    let extras = write_extra_alignments(&input);
+
+    let mut reader = bam::Reader::from_path(&input).unwrap();
+    let mut grouper = Grouper::new();
+    let mut templates = 0;
+    while let Some(template) = grouper.next_template(&mut reader) {
+        let template = template.unwrap();
+        assert!(template.record1.is_first_in_template() && !template.record1.is_secondary());
+        assert!(template.record2.is_last_in_template() && !template.record2.is_secondary());
+        assert_eq!(template.record1.qname(), template.record2.qname());
+        templates += 1;
+    }
+    grouper.finish().unwrap();
+    assert_eq!((templates, grouper.extras()), (40, extras));
+
+    let out = scratch.path("out.bam");
+    let rejected = scratch.path("rejected.bam");
+    let carried = filter_bam_pairs(&[
+        "-i",
+        &input,
+        "-o",
+        &out,
+        "--rejected-output",
+        &rejected,
+        "--verify-output",
+    ]);
+    assert!(
+        carried.status.success(),
+        "{}",
+        String::from_utf8_lossy(&carried.stderr)
+    );
+    assert_eq!(reported(&carried, "Total pairs: "), 40);
+    let kept = reported(&carried, "Filtered pairs: ");
+    assert_eq!(kept, 34);
+    let (kept_primary, kept_extra) = count_records(&out);
+    let (rejected_primary, rejected_extra) = count_records(&rejected);
+    assert_eq!(
+        (kept_primary, rejected_primary),
+        (2 * kept, 2 * (40 - kept))
+    );
+    assert_eq!(kept_extra + rejected_extra, extras);
+    assert!(rejected_extra > 0);
+
+    let dropped = filter_bam_pairs(&[
+        "-i",
+        &input,
+        "-o",
+        &out,
+        "--extra-alignments",
+        "drop",
+        "--verify-output",
+    ]);
+    assert!(dropped.status.success());
+    assert_eq!(
+        reported(&dropped, "Secondary/supplementary records dropped: "),
+        extras
+    );
+    assert_eq!(count_records(&out), (2 * kept, 0));
+}
+
+#[test]
+fn length_options_longer_than_the_reads_are_reported_or_lowered() {
+    let mut sample = LengthSample::default();
+    for len in [80, 100, 100, 100, 150] {
+        let seq = random_sequence(len, len as u64);
+        sample.observe(&mapped_pair("len", &seq, &seq).0.build());
+    }
+    let read_lengths = sample.lengths().unwrap();
+    assert_eq!(
+        (read_lengths.min, read_lengths.median, read_lengths.max),
+        (80, 100, 150)
+    );
+    let mut config = FilterConfig {
+        kmer_size: 120,
+        min_mapped: 200,
+        ..FilterConfig::default()
+    };
+    let problems = lengths::problems(&config, read_lengths);
+    assert_eq!(problems.len(), 2);
+    assert!(problems[0]
+        .to_string()
+        .contains("most reads will fail complexity"));
+    assert!(problems[1].to_string().contains("no pair can pass"));
+    assert_eq!(lengths::adjust(&mut config, read_lengths).unwrap().len(), 2);
+    assert_eq!((config.kmer_size, config.min_mapped), (50, 50));
+    assert!(lengths::problems(&config, read_lengths).is_empty());
+
+    let scratch = Scratch::new("length-check");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let out = scratch.path("out.bam");
+    let warned = filter_bam_pairs(&["-i", &input, "-o", &out, "--kmer-size", "101"]);
+    assert!(String::from_utf8_lossy(&warned.stderr)
+        .contains("Warning: kmer size 101 is longer than the median read length (100 bp"));
+    assert_eq!(reported(&warned, "Filtered pairs: "), 0);
+
+    // A [read-group] section is checked like the main options
+    let config = scratch.path("run.conf");
+    std::fs::write(&config, "[read-group lane2]\nmin-mapped = 150\n").unwrap();
+    let warned = filter_bam_pairs(&["--config", &config, "-i", &input, "-o", &out]);
+    assert!(String::from_utf8_lossy(&warned.stderr).contains(
+        "Warning: [read-group lane2] --min-mapped 150 is longer than the median read length (100 bp"
+    ));
+
+    let adjusted = filter_bam_pairs(&[
+        "-i",
+        &input,
+        "-o",
+        &out,
+        "--kmer-size",
+        "101",
+        "--length-check",
+        "adjust",
+    ]);
+    assert!(String::from_utf8_lossy(&adjusted.stdout).contains("kmer size 101 -> 50"));
+    assert_eq!(reported(&adjusted, "Filtered pairs: "), 200);
+}
+
+/// Pairs 1000 bases apart on chr1; every fourth has its second mate on chr2
+fn region_pairs() -> Vec<(bam::Record, bam::Record)> {
+    (0..100)
+        .map(|i| {
+            let seq = random_sequence(100, i);
+            let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &seq, &seq);
+            let pos = 1000 * i as i64;
+            let (mtid, mpos) = if i % 4 == 0 {
+                (1, 5000)
+            } else {
+                (0, pos + 300)
+            };
+            (
+                record1.pos(0, pos).mate_pos(mtid, mpos).build(),
+                record2.pos(mtid, mpos).mate_pos(0, pos).build(),
+            )
+        })
+        .collect()
+}
+
+/// [`region_pairs`] in coordinate order, under a header saying so
+fn write_coordinate_sorted(path: &str) {
+    let mut header = bam::Header::new();
+    header.push_record(
+        bam::header::HeaderRecord::new(b"HD")
+            .push_tag(b"VN", "1.6")
+            .push_tag(b"SO", "coordinate"),
+    );
+    for name in ["chr1", "chr2"] {
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", name)
+                .push_tag(b"LN", 100_000),
+        );
+    }
+    let mut records: Vec<bam::Record> = region_pairs()
+        .into_iter()
+        .flat_map(|(record1, record2)| [record1, record2])
+        .collect();
+    records.sort_by_key(|record| (record.tid(), record.pos()));
+    let mut writer = bam::Writer::from_path(path, &header, bam::Format::Bam).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+}
+
+#[test]
+fn regions_parse_merge_and_overlap() {
+    let header = bam::HeaderView::from_header(&reference_header());
+    let regions = Regions::read(
+        None,
+        &[
+            "chr1:1,001-2000".to_string(),
+            "chr1:1500-3000".to_string(),
+            "chr2".to_string(),
+        ],
+        &header,
+    )
+    .unwrap();
+    assert_eq!(regions.intervals(), [(0, 1000, 3000), (1, 0, 100_000)]);
+    assert_eq!(regions.bases(), 102_000);
+    assert!(Regions::read(None, &["chr3:1-10".to_string()], &header).is_err());
+    assert!(Regions::read(None, &["chr1:10-1".to_string()], &header).is_err());
+
+    let pairs = region_pairs();
+    let regions = Regions::read(None, &["chr1:2400-3001".to_string()], &header).unwrap();
+    // Pair 2's second mate ends on the interval's first base, pair 3 starts on its last
+    assert!(!regions.overlaps_pair(&pairs[1].0, &pairs[1].1));
+    assert!(regions.overlaps(&pairs[2].1));
+    assert!(regions.overlaps(&pairs[3].0));
+    assert!(!regions.overlaps(&pairs[4].0));
+}
+
+#[test]
+fn bed_files_skip_header_lines_and_reject_bad_intervals() {
+    let scratch = Scratch::new("bed");
+    let path = scratch.path("intervals.bed");
+    std::fs::write(
+        &path,
+        "browser position chr1:1-1000\n\
+         track name=panel\n\
+         # a comment\n\
+         \n\
+         chr1\t10\t20\tamp1\n\
+         chr2\t30\t40\n\
+         chr2\t50\t60\t \n",
+    )
+    .unwrap();
+    let lines = bed::read(&path).unwrap();
+    assert_eq!(
+        lines
+            .iter()
+            .map(|line| (line.number, line.chrom.as_str(), line.start, line.end))
+            .collect::<Vec<_>>(),
+        [
+            (5, "chr1", 10, 20),
+            (6, "chr2", 30, 40),
+            (7, "chr2", 50, 60)
+        ]
+    );
+    assert_eq!(
+        lines
+            .iter()
+            .map(|line| line.name.as_deref())
+            .collect::<Vec<_>>(),
+        [Some("amp1"), None, None]
+    );
+
+    for (content, error) in [
+        ("chr1\t10\n", "line 1: expected chrom, start and end"),
+        ("chr1\tten\t20\n", "line 1: expected chrom, start and end"),
+        ("#\nchr1\t20\t20\n", "line 2: empty or negative interval"),
+        ("chr1\t-5\t20\n", "line 1: empty or negative interval"),
+    ] {
+        std::fs::write(&path, content).unwrap();
+        assert_eq!(
+            bed::read(&path).unwrap_err().to_string(),
+            format!("{} {}", path, error)
+        );
+    }
+}
+
+#[test]
+fn regions_read_name_sorted_inputs_whole_and_sorted_ones_through_the_index() {
+    let scratch = Scratch::new("regions");
+    let bed = scratch.path("regions.bed");
+    // Pairs 10-19 and, through --region, pair 30
+    std::fs::write(&bed, "chr1\t10000\t20000\n").unwrap();
+    let options = ["--regions", &bed, "--region", "chr1:30001-30100"];
+
+    let by_name = scratch.path("by_name.bam");
+    let mut writer =
+        bam::Writer::from_path(&by_name, &reference_header(), bam::Format::Bam).unwrap();
+    for (record1, record2) in region_pairs() {
+        writer.write(&record1).unwrap();
+        writer.write(&record2).unwrap();
+    }
+    drop(writer);
+
+    let sorted = scratch.path("sorted.bam");
+    write_coordinate_sorted(&sorted);
+
+    let names = |path: &str| {
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        let mut names: Vec<Vec<u8>> = reader
+            .records()
+            .map(|record| record.unwrap().qname().to_vec())
+            .collect();
+        names.sort();
+        names
+    };
+    let expected: Vec<Vec<u8>> = (10..20)
+        .chain([30])
+        .flat_map(|i| std::iter::repeat_n(format!("pair{i:03}").into_bytes(), 2))
+        .collect();
+
+    let out = scratch.path("streamed.bam");
+    let streamed = filter_bam_pairs(&[&["-i", &by_name, "-o", &out][..], &options].concat());
+    assert!(
+        streamed.status.success(),
+        "{}",
+        String::from_utf8_lossy(&streamed.stderr)
+    );
+    assert_eq!(reported(&streamed, "Total pairs: "), 11);
+    assert_eq!(
+        reported(&streamed, "Pairs outside --regions, not considered: "),
+        89
+    );
+    assert_eq!(names(&out), expected);
+
+    // Without an index the sorted input can't be paired
+    let unindexed = filter_bam_pairs(&[&["-i", &sorted, "-o", &out][..], &options].concat());
+    assert!(!unindexed.status.success());
+    assert!(String::from_utf8_lossy(&unindexed.stderr).contains("samtools index"));
+
+    // Mates on chr2, outside every interval, are looked up at their position
+    bam::index::build(&sorted, None, bam::index::Type::Bai, 1).unwrap();
+    let out = scratch.path("indexed.bam");
+    let indexed = filter_bam_pairs(&[&["-i", &sorted, "-o", &out][..], &options].concat());
+    assert!(
+        indexed.status.success(),
+        "{}",
+        String::from_utf8_lossy(&indexed.stderr)
+    );
+    assert!(String::from_utf8_lossy(&indexed.stdout).contains("read through the index"));
+    assert_eq!(reported(&indexed, "Total pairs: "), 11);
+    assert_eq!(names(&out), expected);
+}
+
+#[test]
+fn coordinate_sorted_mates_pair_in_memory_and_through_spills() {
+    let scratch = Scratch::new("coordinate_sorted");
+    let sorted = scratch.path("sorted.bam");
+    write_coordinate_sorted(&sorted);
+
+    // Mates of a pair come out next to each other
+    let pairs = |path: &str| {
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        let records: Vec<bam::Record> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(records.len() % 2, 0);
+        let mut names: Vec<Vec<u8>> = records
+            .chunks(2)
+            .map(|pair| {
+                assert_eq!(pair[0].qname(), pair[1].qname());
+                pair[0].qname().to_vec()
+            })
+            .collect();
+        names.sort();
+        names
+    };
+    let expected: Vec<Vec<u8>> = (0..100)
+        .map(|i| format!("pair{i:03}").into_bytes())
+        .collect();
+
+    let out = scratch.path("unpaired.bam");
+    let unpaired = filter_bam_pairs(&["-i", &sorted, "-o", &out]);
+    assert!(!unpaired.status.success());
+    assert!(String::from_utf8_lossy(&unpaired.stderr).contains("--coordinate-sorted"));
+
+    for (buffer, spills) in [("1024", false), ("0", true)] {
+        let out = scratch.path(&format!("paired.{}.bam", buffer));
+        let paired = filter_bam_pairs(&[
+            "-i",
+            &sorted,
+            "-o",
+            &out,
+            "--coordinate-sorted",
+            "--mate-buffer-memory",
+            buffer,
+        ]);
+        assert!(
+            paired.status.success(),
+            "{}",
+            String::from_utf8_lossy(&paired.stderr)
+        );
+        assert_eq!(reported(&paired, "Total pairs: "), 100);
+        assert_eq!(
+            String::from_utf8_lossy(&paired.stdout).contains("spilled past --mate-buffer-memory"),
+            spills
+        );
+        assert_eq!(pairs(&out), expected);
+    }
+}
+
+#[test]
+fn contigs_are_packed_into_jobs_of_about_a_share() {
+    let sequences: Vec<(String, u64)> = [
+        ("chr1", 800),
+        ("chr2", 0),
+        ("chr3", 50),
+        ("chr4", 60),
+        ("chr5", 40),
+        ("*", 50),
+    ]
+    .iter()
+    .map(|&(name, records)| (name.to_string(), records))
+    .collect();
+    // A share of 1000 / (2 * 4) = 125 records
+    let jobs: Vec<Vec<String>> = contigs::pack(&sequences, 2)
+        .into_iter()
+        .map(|job| job.sequences)
+        .collect();
+    assert_eq!(
+        jobs,
+        [vec!["chr1"], vec!["chr3", "chr4", "chr5"], vec!["*"]]
+    );
+    assert_eq!(contigs::pack(&sequences, 1000).len(), 5);
+}
+
+#[test]
+fn parallel_contigs_keep_the_pairs_of_a_single_run() {
+    let scratch = Scratch::new("parallel_contigs");
+    let sorted = scratch.path("sorted.bam");
+    write_coordinate_sorted(&sorted);
+    // Unplaced pairs go last, as samtools sort leaves them
+    let mut records: Vec<bam::Record> = bam::Reader::from_path(&sorted)
+        .unwrap()
+        .records()
+        .map(Result::unwrap)
+        .collect();
+    for i in 0..5 {
+        let seq = random_sequence(100, 1000 + i);
+        let (record1, record2) = unmapped_pair(&format!("unplaced{i}"), &seq, &seq);
+        records.extend([record1.build(), record2.build()]);
+    }
+    let header = bam::Header::from_template(bam::Reader::from_path(&sorted).unwrap().header());
+    let mut writer = bam::Writer::from_path(&sorted, &header, bam::Format::Bam).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    drop(writer);
+    bam::index::build(&sorted, None, bam::index::Type::Bai, 1).unwrap();
+
+    let names = |path: &str| {
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        let mut names: Vec<Vec<u8>> = reader
+            .records()
+            .map(|record| record.unwrap().qname().to_vec())
+            .collect();
+        names.sort();
+        names
+    };
+    let single = scratch.path("single.bam");
+    let single_run = filter_bam_pairs(&["-i", &sorted, "-o", &single, "--coordinate-sorted"]);
+    assert!(
+        single_run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&single_run.stderr)
+    );
+
+    let out = scratch.path("parallel.bam");
+    let json = scratch.path("parallel.json");
+    let parallel = filter_bam_pairs(&[
+        "-i",
+        &sorted,
+        "-o",
+        &out,
+        "--parallel-contigs",
+        "2",
+        "--stats-json",
+        &json,
+    ]);
+    assert!(
+        parallel.status.success(),
+        "{}",
+        String::from_utf8_lossy(&parallel.stderr)
+    );
+    assert_eq!(reported(&parallel, "Total pairs: "), 105);
+    assert_eq!(
+        reported(&parallel, "Total pairs: "),
+        reported(&single_run, "Total pairs: ")
+    );
+    assert_eq!(
+        reported(&parallel, "Filtered pairs: "),
+        reported(&single_run, "Filtered pairs: ")
+    );
+    assert_eq!(names(&out), names(&single));
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+    assert_eq!(report["total_pairs"], 105);
+
+    // Only indexed, coordinate-sorted inputs split by contig
+    let unsorted = scratch.path("unsorted.bam");
+    write_input(&unsorted, 10);
+    let refused = filter_bam_pairs(&["-i", &unsorted, "-o", &out, "--parallel-contigs", "2"]);
+    assert!(!refused.status.success());
+}
+
+#[test]
+fn validation_levels_fail_warn_or_count_malformed_records() {
+    let lengths = [100_000, 100_000];
+    let seq = random_sequence(100, 7);
+    let (record1, record2) = mapped_pair("good", &seq, &seq);
+    assert_eq!(validation::check_record(&record1.build(), &lengths), None);
+    let (unaligned, _) = mapped_pair("unaligned", &seq, &seq);
+    let (violation, detail) =
+        validation::check_record(&unaligned.cigar("100S").build(), &lengths).unwrap();
+    assert_eq!(violation, Violation::UnalignedCigar);
+    assert!(detail.contains("CIGAR 100S aligns no base"), "{detail}");
+    let (past_end, _) = mapped_pair("past", &seq, &seq);
+    assert_eq!(
+        validation::check_record(&past_end.pos(1, 100_000).build(), &lengths)
+            .unwrap()
+            .0,
+        Violation::OutsideReference
+    );
+    let (neither, _) = mapped_pair("neither", &seq, &seq);
+    assert_eq!(
+        validation::check_record(&neither.flags(0x1 | 0x2).build(), &lengths)
+            .unwrap()
+            .0,
+        Violation::InconsistentFlags
+    );
+    assert_eq!(
+        validation::check_mate(&record1.build(), &record2.build()),
+        None
+    );
+    let (stray1, stray2) = mapped_pair("stray", &seq, &seq);
+    assert!(validation::check_mate(&stray1.mate_pos(0, 5000).build(), &stray2.build()).is_some());
+
+    let scratch = Scratch::new("validation");
+    let input = scratch.path("in.bam");
+    let mut writer = bam::Writer::from_path(&input, &reference_header(), bam::Format::Bam).unwrap();
+    for i in 0..20 {
+        let seq = random_sequence(100, i);
+        let (record1, record2) = mapped_pair(&format!("pair{i:02}"), &seq, &seq);
+        let record1 = match i {
+            5 => record1.cigar("20I80S"),
+            9 => record1.flags(0x1 | 0x2 | 0x8 | 0x20 | 0x40),
+            _ => record1,
+        };
+        writer.write(&record1.build()).unwrap();
+        writer.write(&record2.build()).unwrap();
+    }
+    drop(writer);
+    let out = scratch.path("out.bam");
+
+    let strict = filter_bam_pairs(&["-i", &input, "-o", &out, "--validation", "strict"]);
+    assert!(!strict.status.success());
+    let stderr = String::from_utf8_lossy(&strict.stderr);
+    assert!(stderr.contains("Record pair05 is malformed"), "{stderr}");
+
+    let lenient = filter_bam_pairs(&["-i", &input, "-o", &out]);
+    assert!(lenient.status.success());
+    assert_eq!(reported(&lenient, "Malformed records: "), 2);
+    assert_eq!(reported(&lenient, "Total pairs: "), 20);
+    let stderr = String::from_utf8_lossy(&lenient.stderr);
+    assert!(
+        stderr.contains("Warning: record pair09 is malformed: 0x8"),
+        "{stderr}"
+    );
+
+    let stats = scratch.path("silent.stats.json");
+    let silent = filter_bam_pairs(&[
+        "-i",
+        &input,
+        "-o",
+        &out,
+        "--validation",
+        "silent",
+        "--stats-json",
+        &stats,
+    ]);
+    assert!(silent.status.success());
+    assert!(!String::from_utf8_lossy(&silent.stderr).contains("malformed"));
+    let report = Report::read_json(&stats).unwrap();
+    let counts = report.validation.unwrap();
+    assert_eq!((counts.unaligned_cigar, counts.mate_mismatch), (1, 1));
+}
+
+#[test]
+fn pairs_in_bins_deeper_than_max_region_depth_are_removed() {
+    let scratch = Scratch::new("region-depth");
+    let input = scratch.path("in.bam");
+    let mut writer = bam::Writer::from_path(&input, &reference_header(), bam::Format::Bam).unwrap();
+    // 50 pairs pile onto chr1:1000-1300, 10x over its 1 kb bin; 20 more
+    // each have a bin of their own, at 0.2x
+    for i in 0..70i64 {
+        let seq = random_sequence(100, i as u64);
+        let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &seq, &seq);
+        let at = if i < 50 {
+            1000
+        } else {
+            10_000 + 2000 * (i - 50)
+        };
+        writer
+            .write(&record1.pos(0, at).mate_pos(0, at + 200).build())
+            .unwrap();
+        writer
+            .write(&record2.pos(0, at + 200).mate_pos(0, at).build())
+            .unwrap();
+    }
+    drop(writer);
+
+    let sketch = depth::DepthSketch::build(&input, None, 1000).unwrap();
+    let (record1, _) = mapped_pair("probe", &"A".repeat(100), "");
+    assert_eq!(sketch.max_depth(&record1.build()), 10.0);
+    assert_eq!(sketch.deep_bins(5.0), 1);
+
+    let run = filter_bam_pairs(&[
+        "-i",
+        &input,
+        "-o",
+        &scratch.path("out.bam"),
+        "-c",
+        "0",
+        "--max-region-depth",
+        "5",
+    ]);
+    assert!(run.status.success());
+    assert_eq!(reported(&run, "Pairs in over-deep regions: "), 50);
+    assert_eq!(reported(&run, "Filtered pairs: "), 20);
+}
@@ -0,0 +1,1104 @@
+//! Every output receives both mates of a pair or neither, also when a run
+//! stops early
+
+mod common;
+
+use common::{expect, test_header, write_input, Scratch};
+use filter_bam_pairs::fastq::{FastqPairWriter, Orientation};
+use filter_bam_pairs::filter::{self, FilterConfig};
+use filter_bam_pairs::input;
+use filter_bam_pairs::memory::{self, Pressure, RssGuard};
+use filter_bam_pairs::output::{self, BamOutput, Encoding, OutputFormat};
+use filter_bam_pairs::provenance;
+use filter_bam_pairs::sink::{filter_into, Counter, Only, OutputSink, Tee};
+use filter_bam_pairs::test_utils::{mapped_pair, random_sequence, RecordBuilder};
+use filter_bam_pairs::verify::verify_outputs;
+use filter_bam_pairs::PairFilter;
+use rust_htslib::bam;
+use rust_htslib::bam::Read as _;
+use rust_htslib::bgzf;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Pairs on chr1, chr2 and split across both, in turn
+fn test_pairs(count: usize) -> Vec<(bam::Record, bam::Record)> {
+    (0..count)
+        .map(|i| {
+            let seq = random_sequence(100, i as u64);
+            let (record1, record2) = mapped_pair(&format!("pair{i:05}"), &seq, &seq);
+            let offset = 10 * i as i64;
+            let (tid1, tid2) = [(0, 0), (1, 1), (0, 1)][i % 3];
+            (
+                record1
+                    .pos(tid1, 1000 + offset)
+                    .mate_pos(tid2, 1200 + offset)
+                    .build(),
+                record2
+                    .pos(tid2, 1200 + offset)
+                    .mate_pos(tid1, 1000 + offset)
+                    .build(),
+            )
+        })
+        .collect()
+}
+
+/// Write `written` of the pairs, as a run stopped early would, and check the result
+fn check_bam_output(mut output: BamOutput, written: usize, mates_adjacent: bool) {
+    for (record1, record2) in test_pairs(written) {
+        output.write_pair(&record1, &record2).unwrap();
+    }
+    let paths = output.finish().unwrap();
+    verify_outputs(&paths, &expect(written, mates_adjacent)).unwrap();
+}
+
+#[test]
+fn single_output_holds_whole_pairs() {
+    let scratch = Scratch::new("single");
+    let output = BamOutput::create(
+        &scratch.path("out.bam"),
+        &test_header(),
+        1,
+        &Encoding::default(),
+    )
+    .unwrap();
+    check_bam_output(output, 37, true);
+}
+
+#[test]
+fn every_shard_holds_whole_pairs() {
+    let scratch = Scratch::new("sharded");
+    let output = BamOutput::create(
+        &scratch.path("out.bam"),
+        &test_header(),
+        4,
+        &Encoding::default(),
+    )
+    .unwrap();
+    check_bam_output(output, 37, true);
+}
+
+#[test]
+fn threaded_compression_and_decompression_keep_every_pair() {
+    let scratch = Scratch::new("threads");
+    // Both shards and both readers share the two threads
+    let thread_pool = output::thread_pool(2).unwrap();
+    let encoding = Encoding {
+        thread_pool: thread_pool.clone(),
+        ..Encoding::default()
+    };
+    let output = BamOutput::create(
+        &scratch.path("out.{shard}.bam"),
+        &test_header(),
+        2,
+        &encoding,
+    )
+    .unwrap();
+    check_bam_output(output, 501, true);
+
+    let mut records = 0;
+    for shard in 0..2 {
+        let path = scratch.path(&format!("out.{shard}.bam"));
+        let mut reader =
+            input::open(&path, input::DEFAULT_BUFFER_KIB, None, thread_pool.as_ref()).unwrap();
+        records += reader.records().map(Result::unwrap).count();
+    }
+    assert_eq!(records, 2 * 501);
+}
+
+#[test]
+fn split_outputs_keep_mates_on_other_contigs_together() {
+    let scratch = Scratch::new("split");
+    let template = scratch.path("{contig}/out.bam");
+    let mut output = BamOutput::create(&template, &test_header(), 1, &Encoding::default()).unwrap();
+    for (record1, record2) in test_pairs(37) {
+        output.write_pair(&record1, &record2).unwrap();
+    }
+    let paths = output.finish().unwrap();
+    assert_eq!(paths.len(), 2);
+    verify_outputs(&paths, &expect(37, true)).unwrap();
+
+    // Two references and `unmapped`, but only one file may be open
+    assert_eq!(output::split_files(&template, &test_header()), 3);
+    let mut output = BamOutput::create(&template, &test_header(), 1, &Encoding::default()).unwrap();
+    output.limit_files(1);
+    let refused = test_pairs(37)
+        .iter()
+        .try_for_each(|(record1, record2)| output.write_pair(record1, record2));
+    assert!(refused
+        .unwrap_err()
+        .to_string()
+        .contains("--max-open-files"));
+}
+
+#[test]
+fn sorted_output_holds_both_mates_across_spills() {
+    let scratch = Scratch::new("sorted");
+    let work = scratch.path("work");
+    std::fs::create_dir_all(&work).unwrap();
+    // A 4 KiB budget spills every dozen records or so
+    let output = BamOutput::sorted(
+        &scratch.path("out.bam"),
+        &test_header(),
+        &Encoding::default(),
+        work.as_ref(),
+        4096,
+        Some(16),
+    );
+    check_bam_output(output, 37, false);
+    assert_eq!(
+        std::fs::read_dir(&work).unwrap().count(),
+        0,
+        "spills removed"
+    );
+}
+
+#[test]
+fn indexed_sorted_output_answers_region_queries() {
+    let scratch = Scratch::new("indexed");
+    let input = scratch.path("in.bam");
+    let mut writer =
+        bam::Writer::from_path(&input, &common::reference_header(), bam::Format::Bam).unwrap();
+    for (record1, record2) in test_pairs(300) {
+        writer.write(&record1).unwrap();
+        writer.write(&record2).unwrap();
+    }
+    drop(writer);
+
+    let out = scratch.path("out.bam");
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &out])
+        .args(["--sort-output", "coordinate", "--index-output"])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    assert!(Path::new(&format!("{}.bai", out)).exists());
+
+    // chr1:2001-3000, read through the index and picked from the whole file
+    let (start, end) = (2000, 3000);
+    let mut indexed = bam::IndexedReader::from_path(&out).unwrap();
+    indexed.fetch(("chr1", start, end)).unwrap();
+    let fetched: Vec<(Vec<u8>, i64)> = indexed
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            (record.qname().to_vec(), record.pos())
+        })
+        .collect();
+    let overlapping: Vec<(Vec<u8>, i64)> = bam::Reader::from_path(&out)
+        .unwrap()
+        .records()
+        .map(|record| record.unwrap())
+        .filter(|record| {
+            record.tid() == 0 && record.pos() < end && record.cigar().end_pos() > start
+        })
+        .map(|record| (record.qname().to_vec(), record.pos()))
+        .collect();
+    assert!(!overlapping.is_empty());
+    assert_eq!(fetched, overlapping);
+}
+
+fn fastq_names(path: &str) -> Vec<String> {
+    let mut text = String::new();
+    bgzf::Reader::from_path(path)
+        .unwrap()
+        .read_to_string(&mut text)
+        .unwrap();
+    text.lines()
+        .step_by(4)
+        .map(|line| line.trim_start_matches('@').to_string())
+        .collect()
+}
+
+#[test]
+fn fastq_files_stay_in_step() {
+    let scratch = Scratch::new("fastq");
+    let prefix = scratch.path("failed");
+    let mut output = FastqPairWriter::create(&prefix, Orientation::Sequenced).unwrap();
+    let pairs = test_pairs(37);
+    for (i, (record1, record2)) in pairs.iter().enumerate() {
+        // Mates arriving second-first still go to the right file
+        if i % 2 == 0 {
+            output.write_pair(record1, record2).unwrap();
+        } else {
+            output.write_pair(record2, record1).unwrap();
+        }
+    }
+    output.finish().unwrap();
+
+    let r1 = fastq_names(&format!("{}_R1.fastq.gz", prefix));
+    let r2 = fastq_names(&format!("{}_R2.fastq.gz", prefix));
+    assert_eq!(r1.len(), 37);
+    assert_eq!(r1, r2);
+}
+
+#[test]
+fn interrupted_run_leaves_whole_pairs_in_every_output() {
+    let scratch = Scratch::new("interrupt");
+    let input = scratch.path("in.bam");
+    // Progress is first reported after 100,000 pairs
+    let pairs = 240_000;
+    write_input(&input, pairs);
+    let bytes = std::fs::read(&input).unwrap();
+    let prefix = scratch.path("failed");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", "-", "-o", &scratch.path("out.bam"), "--shards", "2"])
+        .args(["--failed-fastq", &prefix, "--tmp-dir", &scratch.path("")])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // The input arrives in two halves, the second only once the signal is
+    // sent, so the run can't have reached the end of the input before it
+    let mut stdin = child.stdin.take().unwrap();
+    let (signalled, wait_for_signal) = std::sync::mpsc::channel();
+    let feeder = std::thread::spawn(move || {
+        let (first, rest) = bytes.split_at(bytes.len() * 3 / 5);
+        stdin.write_all(first).unwrap();
+        wait_for_signal.recv().unwrap();
+        // The run stops reading once it has seen the signal
+        let _ = stdin.write_all(rest);
+    });
+
+    // The first progress line means the signal handlers are installed
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut kept = None;
+    let mut total = None;
+    let mut line = String::new();
+    while stdout.read_line(&mut line).unwrap() > 0 {
+        if line.starts_with("Processed ") && kept.is_none() {
+            // SAFETY: kill only sends a signal to the child
+            unsafe { libc::kill(child.id() as i32, libc::SIGINT) };
+            signalled.send(()).unwrap();
+            kept = Some(0);
+        }
+        let count = |value: &str| value.trim().replace(',', "").parse::<usize>().unwrap();
+        if let Some(value) = line.strip_prefix("Filtered pairs: ") {
+            kept = Some(count(value));
+        }
+        if let Some(value) = line.strip_prefix("Total pairs: ") {
+            total = Some(count(value));
+        }
+        line.clear();
+    }
+    let status = child.wait().unwrap();
+    feeder.join().unwrap();
+    assert_eq!(status.code(), Some(130), "{status}");
+
+    let total = total.expect("the run reported its pairs");
+    assert!(total < pairs, "the run read all {} pairs", total);
+    let kept = kept.expect("the run reported its kept pairs");
+    let shards = vec![scratch.path("out.0.bam"), scratch.path("out.1.bam")];
+    verify_outputs(&shards, &expect(kept, true)).unwrap();
+    let r1 = fastq_names(&format!("{}_R1.fastq.gz", prefix));
+    let r2 = fastq_names(&format!("{}_R2.fastq.gz", prefix));
+    assert_eq!(r1, r2);
+}
+
+#[test]
+fn preview_run_writes_a_complete_small_output() {
+    let scratch = Scratch::new("preview");
+    let input = scratch.path("in.bam");
+    write_input(&input, 3000);
+    let out = scratch.path("out.bam");
+
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &out, "--preview-pairs", "300"])
+        .output()
+        .unwrap();
+    assert!(run.status.success());
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert!(stdout.contains("=== Preview Complete ==="));
+    assert!(stdout.contains("Total pairs: 300\n"));
+    verify_outputs(&[out], &expect(200, true)).unwrap();
+}
+
+#[test]
+fn dry_run_reports_and_writes_statistics_but_no_reads() {
+    let scratch = Scratch::new("dry-run");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let (stats, histogram) = (
+        scratch.path("run.stats.json"),
+        scratch.path("complexity.tsv"),
+    );
+
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "--dry-run", "--stats-json", &stats])
+        .args(["--complexity-histogram", &histogram])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert!(stdout.contains("Total pairs: 300\n"));
+    assert!(stdout.contains("Filtered pairs: 200\n"), "{stdout}");
+    assert!(stdout.contains("Output BAM: none (dry run)"));
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats).unwrap()).unwrap();
+    assert_eq!(json["kept_pairs"], 200);
+    assert!(std::fs::metadata(&histogram).is_ok());
+    let mut written: Vec<_> = std::fs::read_dir(scratch.path(""))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    written.sort();
+    assert_eq!(written, ["complexity.tsv", "in.bam", "run.stats.json"]);
+
+    let refused = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args([
+            "-i",
+            &input,
+            "--dry-run",
+            "--rejected-output",
+            &scratch.path("r.bam"),
+        ])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+}
+
+#[test]
+fn cram_output_round_trips_with_the_reference() {
+    let scratch = Scratch::new("cram");
+    let reference = scratch.path("ref.fa");
+    let mut fasta = String::new();
+    for (i, name) in ["chr1", "chr2"].iter().enumerate() {
+        fasta += &format!(">{}\n{}\n", name, random_sequence(100_000, 100 + i as u64));
+    }
+    std::fs::write(&reference, fasta).unwrap();
+    assert_eq!(OutputFormat::from_path("out.CRAM"), OutputFormat::Cram);
+    assert_eq!(OutputFormat::from_path("out.sam"), OutputFormat::Sam);
+    assert_eq!(OutputFormat::from_path("out"), OutputFormat::Bam);
+
+    let encoding = Encoding {
+        format: None,
+        reference: Some(reference.clone()),
+        thread_pool: None,
+    };
+    let path = scratch.path("out.cram");
+    let mut output = BamOutput::create(&path, &test_header(), 1, &encoding).unwrap();
+    let pairs = test_pairs(20);
+    for (record1, record2) in &pairs {
+        output.write_pair(record1, record2).unwrap();
+    }
+    output.finish().unwrap();
+
+    let mut reader = bam::Reader::from_path(&path).unwrap();
+    reader.set_reference(&reference).unwrap();
+    let records: Vec<bam::Record> = reader.records().map(|record| record.unwrap()).collect();
+    assert_eq!(records.len(), 40);
+    assert_eq!(records[0].seq().as_bytes(), pairs[0].0.seq().as_bytes());
+
+    // Without a reference, CRAM is refused before anything is written
+    let error = BamOutput::create(&path, &test_header(), 1, &Encoding::default())
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("--reference"));
+}
+
+#[test]
+fn rejected_output_takes_every_pair_the_filters_remove() {
+    let scratch = Scratch::new("rejected-output");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let (out, rejected) = (scratch.path("out.bam"), scratch.path("rejected.bam"));
+
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &out, "--rejected-output", &rejected])
+        .output()
+        .unwrap();
+    assert!(run.status.success());
+    verify_outputs(&[out], &expect(200, true)).unwrap();
+    verify_outputs(std::slice::from_ref(&rejected), &expect(100, true)).unwrap();
+    let mut reader = bam::Reader::from_path(&rejected).unwrap();
+    let record = reader.records().next().unwrap().unwrap();
+    assert_eq!(record.qname(), b"pair0000000");
+}
+
+#[test]
+fn annotated_run_writes_every_pair_tagged_with_its_verdict() {
+    let scratch = Scratch::new("annotate");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let (annotated, retuned) = (scratch.path("annotated.bam"), scratch.path("retuned.bam"));
+    let filter = |args: &[&str]| {
+        let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            run.status.success(),
+            "{}",
+            String::from_utf8_lossy(&run.stderr)
+        );
+        String::from_utf8_lossy(&run.stdout).into_owned()
+    };
+    let stdout = filter(&[
+        "-i",
+        &input,
+        "-o",
+        &annotated,
+        "--annotate",
+        "--verify-output",
+    ]);
+    assert!(stdout.contains("Filtered pairs: 200"), "{stdout}");
+    verify_outputs(std::slice::from_ref(&annotated), &expect(300, true)).unwrap();
+
+    let mut reader = bam::Reader::from_path(&annotated).unwrap();
+    let records: Vec<bam::Record> = reader.records().map(|record| record.unwrap()).collect();
+    let failures = |record: &bam::Record| match record.aux(b"xf") {
+        Ok(bam::record::Aux::String(failures)) => Some(failures.to_string()),
+        _ => None,
+    };
+    // Every third pair is repetitive
+    assert_eq!(failures(&records[0]).as_deref(), Some("complexity"));
+    assert_eq!(failures(&records[2]), None);
+    assert!(matches!(
+        records[2].aux(b"xm"),
+        Ok(bam::record::Aux::I32(100))
+    ));
+    let complexity = |record: &bam::Record| match record.aux(b"xc") {
+        Ok(bam::record::Aux::Float(complexity)) => complexity,
+        other => panic!("xc:f is set, not {other:?}"),
+    };
+    assert!(complexity(&records[0]) < complexity(&records[2]));
+    // Both mates of the 100 pairs a filtering run removes
+    let failed = records.iter().filter(|record| failures(record).is_some());
+    assert_eq!(failed.count(), 200);
+
+    // The tags stand in for the metrics on a second run
+    let stdout = filter(&[
+        "-i",
+        &annotated,
+        "-o",
+        &retuned,
+        "--use-cached-metrics",
+        "--force",
+    ]);
+    assert!(stdout.contains("Filtered pairs: 200"), "{stdout}");
+    let mut expected = expect(200, true);
+    expected.program_records = 2;
+    verify_outputs(&[retuned], &expected).unwrap();
+}
+
+#[test]
+fn provenance_records_the_options_and_input_ends_next_to_the_output() {
+    for (message, digest) in [
+        (
+            &b""[..],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        ),
+        (
+            b"abc",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ),
+        (
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        ),
+    ] {
+        assert_eq!(provenance::to_hex(&provenance::sha256(message)), digest);
+    }
+
+    let scratch = Scratch::new("provenance");
+    let input = scratch.path("in.bam");
+    // Uncompressed, so that the two hashed ends don't overlap
+    let mut writer = bam::Writer::from_path(&input, &test_header(), bam::Format::Bam).unwrap();
+    writer
+        .set_compression_level(bam::CompressionLevel::Uncompressed)
+        .unwrap();
+    for (record1, record2) in test_pairs(6000) {
+        writer.write(&record1).unwrap();
+        writer.write(&record2).unwrap();
+    }
+    drop(writer);
+    let out = scratch.path("out.bam");
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args([
+            "-i",
+            &input,
+            "-o",
+            &out,
+            "--provenance",
+            "-m",
+            "30",
+            "--force",
+        ])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let path = provenance::path_for(&out);
+    let record: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let parameters = &record["parameters"];
+    assert_eq!(parameters["--min-mapped"]["values"][0], "30");
+    assert_eq!(parameters["--min-mapped"]["source"], "command line");
+    assert_eq!(parameters["--complexity"]["source"], "default");
+    assert_eq!(parameters["--stats-json"]["source"], "not given");
+
+    let bytes = std::fs::read(&input).unwrap();
+    let hashed = provenance::HASHED_BYTES as usize;
+    assert!(bytes.len() > hashed, "the input is longer than both ends");
+    let digest = &record["inputs"][0];
+    assert_eq!(digest["size"], bytes.len() as u64);
+    let hex = |bytes: &[u8]| provenance::to_hex(&provenance::sha256(bytes));
+    assert_eq!(digest["head_sha256"], hex(&bytes[..hashed]));
+    assert_eq!(digest["tail_sha256"], hex(&bytes[bytes.len() - hashed..]));
+    assert_eq!(record["outputs"][0], out.as_str());
+    assert_eq!(record["threads"]["htslib"], 0);
+    assert_eq!(record["interrupted"], false);
+
+    let refused = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", "-", "--provenance"])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+}
+
+#[test]
+fn audits_record_exact_complexity_not_early_exit_bounds() {
+    let scratch = Scratch::new("audit-exact");
+    let input = scratch.path("in.bam");
+    write_input(&input, 30);
+    let audit = scratch.path("audit.tsv");
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &scratch.path("out.bam")])
+        .args(["-c", "0.5", "--audit", &audit])
+        .output()
+        .unwrap();
+    assert!(run.status.success());
+
+    let tsv = std::fs::read_to_string(&audit).unwrap();
+    for line in tsv.lines().skip(1) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let i: u64 = fields[0].trim_start_matches("pair").parse().unwrap();
+        // The sequences write_input gives each pair
+        let seq = if i.is_multiple_of(3) {
+            "A".repeat(100)
+        } else {
+            random_sequence(100, i % 64)
+        };
+        let exact = filter::calculate_kmer_complexity(seq.as_bytes(), 21);
+        assert_eq!(fields[2], format!("{:.4}", exact), "{}", line);
+    }
+    assert_eq!(tsv.lines().count(), 1 + 2 * 30);
+}
+
+#[test]
+fn filtering_an_output_again_is_refused_without_force() {
+    let scratch = Scratch::new("refiltered");
+    let input = scratch.path("in.bam");
+    write_input(&input, 30);
+    let (once, twice) = (scratch.path("once.bam"), scratch.path("twice.bam"));
+    let run = |from: &str, to: &str| {
+        Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+            .args(["-i", from, "-o", to])
+            .output()
+            .unwrap()
+    };
+
+    let first = run(&input, &once);
+    assert!(
+        first.status.success(),
+        "{}",
+        String::from_utf8_lossy(&first.stderr)
+    );
+    let second = run(&once, &twice);
+    assert_eq!(second.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&second.stderr);
+    assert!(stderr.contains("Warning: input was already processed by filter_bam_pairs:"));
+    assert!(stderr.contains("  @PG ID:filter_bam_pairs VN:"));
+    assert!(stderr.contains("Refusing to filter the same data twice; pass --force to override"));
+    assert!(!Path::new(&twice).exists());
+}
+
+#[test]
+fn ligation_junctions_are_retagged_when_output_is_filtered_again() {
+    let scratch = Scratch::new("ligation");
+    let input = scratch.path("in.bam");
+    let mut writer =
+        bam::Writer::from_path(&input, &common::reference_header(), bam::Format::Bam).unwrap();
+    for i in 0..10 {
+        let seq = random_sequence(100, i);
+        let junction = format!("{}GATCGATC{}", &seq[..40], &seq[48..]);
+        let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &junction, &seq);
+        writer.write(&record1.build()).unwrap();
+        writer.write(&record2.build()).unwrap();
+    }
+    drop(writer);
+
+    let (once, twice) = (scratch.path("once.bam"), scratch.path("twice.bam"));
+    for (from, to) in [(&input, &once), (&once, &twice)] {
+        let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+            .args([
+                "-i",
+                from,
+                "-o",
+                to,
+                "--force",
+                "--ligation-motif",
+                "GATCGATC",
+            ])
+            .output()
+            .unwrap();
+        assert!(
+            run.status.success(),
+            "{}",
+            String::from_utf8_lossy(&run.stderr)
+        );
+    }
+    let offsets: Vec<Option<i64>> = bam::Reader::from_path(&twice)
+        .unwrap()
+        .records()
+        .map(|record| match record.unwrap().aux(b"xj") {
+            Ok(bam::record::Aux::I32(offset)) => Some(offset as i64),
+            Ok(_) => panic!("xj is not an i32"),
+            Err(_) => None,
+        })
+        .collect();
+    assert_eq!(offsets.len(), 20);
+    for pair in offsets.chunks(2) {
+        assert_eq!(pair, [Some(40), None]);
+    }
+}
+
+#[test]
+fn failed_quality_check_leaves_no_partial_outputs() {
+    let scratch = Scratch::new("quality-fail");
+    let input = scratch.path("in.bam");
+    let mut writer =
+        bam::Writer::from_path(&input, &common::reference_header(), bam::Format::Bam).unwrap();
+    // Phred+64 scores read as Phred+33, past the 1000 reads sampled
+    for i in 0..600 {
+        let seq = random_sequence(100, i);
+        let (record1, record2) = mapped_pair(&format!("pair{i:04}"), &seq, &seq);
+        writer.write(&record1.qual(&[70; 100]).build()).unwrap();
+        writer.write(&record2.qual(&[70; 100]).build()).unwrap();
+    }
+    drop(writer);
+
+    let (out, rejected) = (scratch.path("out.bam"), scratch.path("rejected.bam"));
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &out, "--rejected-output", &rejected])
+        .args(["--quality-check", "fail"])
+        .output()
+        .unwrap();
+    assert!(!run.status.success());
+    assert!(String::from_utf8_lossy(&run.stderr).contains("--quality-check warn"));
+    assert!(!Path::new(&out).exists() && !Path::new(&rejected).exists());
+}
+
+#[test]
+fn decision_file_applied_to_the_input_matches_the_filtered_bam() {
+    let scratch = Scratch::new("decisions");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let (full, decisions) = (scratch.path("full.bam"), scratch.path("run.fbpd"));
+    let filter = |args: &[&str]| {
+        let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            run.status.success(),
+            "{}",
+            String::from_utf8_lossy(&run.stderr)
+        );
+    };
+    filter(&["-i", &input, "-o", &full]);
+    filter(&["-i", &input, "--decisions", &decisions]);
+    assert!(
+        std::fs::metadata(&decisions).unwrap().len() < std::fs::metadata(&full).unwrap().len() / 10
+    );
+
+    let applied = scratch.path("applied.bam");
+    filter(&["apply", "-i", &input, "-d", &decisions, "-o", &applied]);
+    verify_outputs(std::slice::from_ref(&applied), &expect(200, true)).unwrap();
+    let names = |path: &str| -> Vec<Vec<u8>> {
+        bam::Reader::from_path(path)
+            .unwrap()
+            .records()
+            .map(|record| record.unwrap().qname().to_vec())
+            .collect()
+    };
+    assert_eq!(names(&applied), names(&full));
+
+    // Another BAM's decisions don't fit
+    let other = scratch.path("other.bam");
+    write_input(&other, 30);
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["apply", "-i", &other, "-d", &decisions, "-o", &applied])
+        .output()
+        .unwrap();
+    assert!(!run.status.success());
+    assert!(String::from_utf8_lossy(&run.stderr).contains("the BAM it was made from"));
+
+    // Records skipped by flag aren't in the file, so apply would write them
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args([
+            "-i",
+            &input,
+            "--decisions",
+            &decisions,
+            "--exclude-flags",
+            "0x400",
+        ])
+        .output()
+        .unwrap();
+    assert!(!run.status.success());
+    assert!(String::from_utf8_lossy(&run.stderr).contains("--exclude-flags"));
+}
+
+#[test]
+fn read_group_config_sections_override_thresholds() {
+    let scratch = Scratch::new("read-group-config");
+    let input = scratch.path("in.bam");
+    let mut header = common::reference_header();
+    for id in ["hifi", "illumina"] {
+        header.push_record(bam::header::HeaderRecord::new(b"RG").push_tag(b"ID", id));
+    }
+    let mut writer = bam::Writer::from_path(&input, &header, bam::Format::Bam).unwrap();
+    for i in 0..40 {
+        // Half the pairs of each group are repetitive
+        let seq = if i % 4 < 2 {
+            "A".repeat(100)
+        } else {
+            random_sequence(100, i)
+        };
+        let read_group = if i % 2 == 0 { "hifi" } else { "illumina" };
+        let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &seq, &seq);
+        for record in [record1, record2] {
+            writer
+                .write(
+                    &record
+                        .tag_str(b"RG", read_group)
+                        .tag_float(b"de", 0.05)
+                        .build(),
+                )
+                .unwrap();
+        }
+    }
+    drop(writer);
+    let config = scratch.path("run.conf");
+    std::fs::write(
+        &config,
+        "complexity = 0.8\n\n[read-group hifi]\ncomplexity = 0\n",
+    )
+    .unwrap();
+
+    let out = scratch.path("out.bam");
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["--config", &config, "-i", &input, "-o", &out, "-c", "0.9"])
+        .output()
+        .unwrap();
+    assert!(run.status.success());
+    // All 20 hifi pairs, and the 10 complex illumina pairs
+    assert!(String::from_utf8_lossy(&run.stdout).contains("Filtered pairs: 30\n"));
+
+    // Every pair diverges by 0.05, which only the illumina limit refuses
+    std::fs::write(&config, "[read-group illumina]\nmax-de = 0.01\n").unwrap();
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["--config", &config, "-i", &input, "-o", &out, "-c", "0"])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    assert!(String::from_utf8_lossy(&run.stdout).contains("Filtered pairs: 20\n"));
+}
+
+#[test]
+fn single_end_runs_filter_each_read_on_its_own() {
+    let scratch = Scratch::new("single-end");
+    let input = scratch.path("in.bam");
+    let mut writer =
+        bam::Writer::from_path(&input, &common::reference_header(), bam::Format::Bam).unwrap();
+    for i in 0..30 {
+        // Every third read is repetitive
+        let seq = if i % 3 == 0 {
+            "AC".repeat(50)
+        } else {
+            random_sequence(100, i)
+        };
+        let record = RecordBuilder::new(&format!("read{i:03}"))
+            .seq(&seq)
+            .cigar("100M")
+            .pos(0, 1000 + 10 * i as i64)
+            .mapq(60)
+            .build();
+        writer.write(&record).unwrap();
+    }
+    drop(writer);
+
+    let out = scratch.path("out.bam");
+    let run = |extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+            .args(["-i", &input, "-o", &out])
+            .args(extra)
+            .output()
+            .unwrap()
+    };
+    let paired = run(&[]);
+    assert!(!paired.status.success());
+    assert!(String::from_utf8_lossy(&paired.stderr).contains("--single-end"));
+
+    let single = run(&["--single-end"]);
+    assert!(single.status.success());
+    let stdout = String::from_utf8_lossy(&single.stdout);
+    assert!(stdout.contains("Total reads: 30\n"));
+    assert!(stdout.contains("Filtered reads: 20\n"));
+    let mut reader = bam::Reader::from_path(&out).unwrap();
+    let kept: Vec<String> = reader
+        .records()
+        .map(|record| String::from_utf8(record.unwrap().qname().to_vec()).unwrap())
+        .collect();
+    let complex: Vec<String> = (0..30)
+        .filter(|i| i % 3 != 0)
+        .map(|i| format!("read{i:03}"))
+        .collect();
+    assert_eq!(kept, complex);
+}
+
+#[test]
+fn duplex_reads_are_selected_and_routed_by_dx() {
+    let scratch = Scratch::new("duplex");
+    let input = scratch.path("in.bam");
+    let mut writer =
+        bam::Writer::from_path(&input, &common::reference_header(), bam::Format::Bam).unwrap();
+    for i in 0..30 {
+        // Duplex, simplex and duplex parent in turn
+        let dx = [1, 0, -1][i % 3];
+        let record = RecordBuilder::new(&format!("read{i:03}"))
+            .seq(&random_sequence(100, i as u64))
+            .tag_int(b"dx", dx)
+            .build();
+        writer.write(&record).unwrap();
+    }
+    drop(writer);
+
+    let template = scratch.path("{dx}.bam");
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &template, "--single-end"])
+        .args(["--duplex-reads", "no-parents"])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert!(stdout.contains("Reads removed by --duplex-reads: 10\n"));
+    assert!(stdout.contains("=== Duplex and Simplex Reads (dx) ==="));
+
+    for (file, dx) in [("duplex.bam", 1), ("simplex.bam", 0)] {
+        let mut reader = bam::Reader::from_path(scratch.path(file)).unwrap();
+        let classes: Vec<i64> = reader
+            .records()
+            .map(|record| filter_bam_pairs::stats::aux_integer(&record.unwrap(), b"dx").unwrap())
+            .collect();
+        assert_eq!(classes, vec![dx; 10], "{file}");
+    }
+}
+
+#[test]
+fn adaptive_sampling_rejects_are_removed_and_routed() {
+    let scratch = Scratch::new("adaptive");
+    let input = scratch.path("in.bam");
+    let mut writer =
+        bam::Writer::from_path(&input, &common::reference_header(), bam::Format::Bam).unwrap();
+    for i in 0..20 {
+        // Short unmapped, short mostly clipped, short mapped and long unmapped in turn
+        let (name, length, flags, cigar) = [
+            ("stub", 300, 4, None),
+            ("clipped", 300, 0, Some("200S100M")),
+            ("mapped", 300, 0, Some("100S200M")),
+            ("long", 2000, 4, None),
+        ][i % 4];
+        let mut record = RecordBuilder::new(&format!("{name}{i:02}"))
+            .seq(&random_sequence(length, i as u64))
+            .flags(flags);
+        if let Some(cigar) = cigar {
+            record = record.pos(0, 1000).cigar(cigar);
+        }
+        writer.write(&record.build()).unwrap();
+    }
+    drop(writer);
+
+    let (out, rejects) = (scratch.path("out.bam"), scratch.path("rejects.bam"));
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &out, "--single-end"])
+        .args([
+            "--adaptive-sampling-filter",
+            "--adaptive-sampling-output",
+            &rejects,
+        ])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert!(stdout.contains("Reads removed as adaptive-sampling rejects: 10\n"));
+
+    let names = |path: &str| -> Vec<String> {
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        reader
+            .records()
+            .map(|record| String::from_utf8(record.unwrap().qname().to_vec()).unwrap())
+            .collect()
+    };
+    let routed = names(&rejects);
+    assert_eq!(routed.len(), 10);
+    assert!(routed
+        .iter()
+        .all(|name| name.starts_with("stub") || name.starts_with("clipped")));
+    assert!(names(&out)
+        .iter()
+        .all(|name| name.starts_with("mapped") || name.starts_with("long")));
+}
+
+/// A library user's sink: remembers the names it was given
+#[derive(Default)]
+struct Names(Vec<(Vec<u8>, bool)>);
+
+impl OutputSink for Names {
+    fn write_pair(
+        &mut self,
+        record1: &bam::Record,
+        _: &bam::Record,
+        kept: bool,
+    ) -> anyhow::Result<()> {
+        self.0.push((record1.qname().to_vec(), kept));
+        Ok(())
+    }
+}
+
+#[test]
+fn custom_sinks_see_every_pair_beside_the_bam_output() {
+    let scratch = Scratch::new("sinks");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let out = scratch.path("out.bam");
+
+    let mut reader = bam::Reader::from_path(&input).unwrap();
+    let mut header = bam::Header::from_template(bam::Read::header(&reader));
+    filter_bam_pairs::header::add_program_record(&mut header);
+    let mut bam_output = BamOutput::create(&out, &header, 1, &Encoding::default()).unwrap();
+    let (mut names, mut rejected) = (Names::default(), Counter::default());
+    let mut sinks = Tee::default();
+    sinks.push(Only::kept(&mut bam_output));
+    sinks.push(&mut names);
+    sinks.push(Only::rejected(&mut rejected));
+    let pairs = filter_into(&mut reader, &FilterConfig::default(), &mut sinks).unwrap();
+    drop(sinks);
+
+    assert_eq!(pairs, 300);
+    assert_eq!(names.0.len(), 300);
+    let kept = names.0.iter().filter(|(_, kept)| *kept).count();
+    assert_eq!((kept, rejected.rejected, rejected.kept), (200, 100, 0));
+    assert_eq!(bam_output.finish().unwrap(), vec![out.clone()]);
+    verify_outputs(&[out], &expect(200, true)).unwrap();
+}
+
+#[test]
+fn library_filters_combine_the_config_with_a_closure() {
+    let scratch = Scratch::new("pair-filter");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let config = FilterConfig::default();
+    // Every third pair is low-complexity, every second name is odd
+    let even = |record1: &bam::Record, record2: &bam::Record| {
+        record1.qname().last().is_some_and(|digit| digit % 2 == 0) && config.keep(record1, record2)
+    };
+
+    let mut reader = bam::Reader::from_path(&input).unwrap();
+    let mut names = Names::default();
+    assert_eq!(filter_into(&mut reader, &even, &mut names).unwrap(), 300);
+    let kept: Vec<usize> = names
+        .0
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, kept))| *kept)
+        .map(|(i, _)| i)
+        .collect();
+    assert_eq!(kept.len(), 100);
+    assert!(kept.iter().all(|i| i % 2 == 0 && i % 3 != 0));
+
+    let seq = random_sequence(100, 7);
+    let (record1, record2) = mapped_pair("direct", &seq, &seq);
+    let complexity = filter_bam_pairs::calculate_kmer_complexity(seq.as_bytes(), 21);
+    assert_eq!(complexity, 1.0);
+    assert_eq!(
+        filter_bam_pairs::get_longest_mapped_bases(&record1.build()),
+        100
+    );
+    assert!(config.keep(&record1.build(), &record2.build()));
+}
+
+#[test]
+fn memory_pressure_relieves_buffers_then_stops_the_run() {
+    let mut guard = RssGuard::new(100);
+    assert_eq!(guard.judge(50 << 20), Pressure::Fine);
+    assert_eq!(guard.judge(90 << 20), Pressure::Relieve(90 << 20));
+    assert_eq!(guard.judge(100 << 20), Pressure::Exceeded(100 << 20));
+    assert_eq!(guard.reliefs, 1);
+    assert!(memory::resident_bytes().is_some_and(|bytes| bytes > 0));
+
+    // A relieved sort buffer spills, and the output still holds every record
+    let scratch = Scratch::new("max_rss");
+    let work = scratch.path("work");
+    std::fs::create_dir_all(&work).unwrap();
+    let sorted = scratch.path("sorted.bam");
+    let mut output = BamOutput::sorted(
+        &sorted,
+        &test_header(),
+        &Encoding::default(),
+        work.as_ref(),
+        1 << 30,
+        None,
+    );
+    for (record1, record2) in test_pairs(20) {
+        output.write_pair(&record1, &record2).unwrap();
+    }
+    Only::kept(&mut output).relieve_memory().unwrap();
+    assert_eq!(std::fs::read_dir(&work).unwrap().count(), 1);
+    output.finish().unwrap();
+    assert_eq!(
+        bam::Reader::from_path(&sorted).unwrap().records().count(),
+        40
+    );
+
+    // At the limit the run stops between pairs with its outputs finished
+    let input = scratch.path("in.bam");
+    write_input(&input, 100);
+    let out = scratch.path("out.bam");
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &out, "--max-rss", "1"])
+        .output()
+        .unwrap();
+    assert!(!run.status.success());
+    assert!(String::from_utf8_lossy(&run.stderr).contains("reached --max-rss 1 MiB"));
+    assert!(String::from_utf8_lossy(&run.stdout).contains("Interrupted: true"));
+    let mut reader = bam::Reader::from_path(&out).unwrap();
+    assert_eq!(reader.records().map(Result::unwrap).count() % 2, 0);
+}
@@ -0,0 +1,161 @@
+//! Reading the input from pipes, as behind `samtools collate -O` or `<(...)`,
+//! and streaming between two `samtools` commands with `-i -` and `-o -`
+
+mod common;
+
+use common::{expect, write_input, Scratch};
+use filter_bam_pairs::verify::verify_outputs;
+use std::ffi::CString;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+const PAIRS: usize = 3000;
+
+fn filter_bam_pairs(args: &[&str], stdin: Option<Vec<u8>>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let writer = stdin.map(|bytes| {
+        let mut pipe = child.stdin.take().unwrap();
+        std::thread::spawn(move || {
+            // The run may stop reading early; a broken pipe is its business
+            let _ = pipe.write_all(&bytes);
+        })
+    });
+    let output = child.wait_with_output().unwrap();
+    if let Some(writer) = writer {
+        writer.join().unwrap();
+    }
+    output
+}
+
+/// Kept pairs as reported on standard output
+fn kept_pairs(output: &Output) -> usize {
+    reported_pairs(&output.stdout)
+}
+
+fn reported_pairs(report: &[u8]) -> usize {
+    String::from_utf8_lossy(report)
+        .lines()
+        .find_map(|line| line.strip_prefix("Filtered pairs: "))
+        .expect("the run reports its kept pairs")
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn pipe_input_matches_file_input() {
+    let scratch = Scratch::new("pipe-stdin");
+    let input = scratch.path("in.bam");
+    write_input(&input, PAIRS);
+
+    let from_file = filter_bam_pairs(&["-i", &input, "-o", &scratch.path("file.bam")], None);
+    assert!(from_file.status.success());
+
+    let bytes = std::fs::read(&input).unwrap();
+    let out = scratch.path("pipe.bam");
+    let from_pipe = filter_bam_pairs(&["-i", "/dev/stdin", "-o", &out], Some(bytes));
+    assert!(
+        from_pipe.status.success(),
+        "{}",
+        String::from_utf8_lossy(&from_pipe.stderr)
+    );
+    assert!(String::from_utf8_lossy(&from_pipe.stdout).contains("Reading from a pipe"));
+
+    let kept = kept_pairs(&from_pipe);
+    assert_eq!(kept, kept_pairs(&from_file));
+    assert_eq!(kept, PAIRS * 2 / 3);
+    verify_outputs(&[out], &expect(kept, true)).unwrap();
+}
+
+#[test]
+fn dash_streams_from_stdin_to_stdout_with_the_report_on_stderr() {
+    let scratch = Scratch::new("pipe-dash");
+    let input = scratch.path("in.bam");
+    write_input(&input, PAIRS);
+
+    let bytes = std::fs::read(&input).unwrap();
+    let run = filter_bam_pairs(&["-i", "-", "-o", "-"], Some(bytes));
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    assert!(String::from_utf8_lossy(&run.stderr).contains("Output BAM: standard output"));
+    assert_eq!(reported_pairs(&run.stderr), PAIRS * 2 / 3);
+
+    // Standard output holds the BAM and nothing else
+    let out = scratch.path("out.bam");
+    std::fs::write(&out, &run.stdout).unwrap();
+    verify_outputs(&[out], &expect(PAIRS * 2 / 3, true)).unwrap();
+}
+
+#[test]
+fn dash_output_refuses_file_only_options() {
+    let run = filter_bam_pairs(&["-i", "-", "-o", "-", "--verify-output"], None);
+    assert!(!run.status.success());
+    assert!(String::from_utf8_lossy(&run.stderr).contains("--verify-output needs an output file"));
+}
+
+fn make_fifo(path: &str) {
+    let path = CString::new(path).unwrap();
+    // SAFETY: mkfifo only reads the NUL-terminated path
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+}
+
+#[test]
+fn named_pipe_input_is_read_to_the_end() {
+    let scratch = Scratch::new("pipe-fifo");
+    let input = scratch.path("in.bam");
+    write_input(&input, PAIRS);
+    let fifo = scratch.path("in.fifo");
+    make_fifo(&fifo);
+
+    let bytes = std::fs::read(&input).unwrap();
+    let writer_fifo = fifo.clone();
+    let writer = std::thread::spawn(move || {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&writer_fifo)
+            .unwrap()
+            .write_all(&bytes)
+            .unwrap();
+    });
+    let out = scratch.path("out.bam");
+    let run = filter_bam_pairs(&["-i", &fifo, "-o", &out, "--input-buffer", "64"], None);
+    writer.join().unwrap();
+
+    assert!(run.status.success());
+    verify_outputs(&[out], &expect(PAIRS * 2 / 3, true)).unwrap();
+}
+
+#[test]
+fn second_pass_options_refuse_pipes_before_reading() {
+    let scratch = Scratch::new("pipe-refuse");
+    let fifo = scratch.path("in.fifo");
+    make_fifo(&fifo);
+
+    // Nothing opens the write end: a run that tried to read would hang
+    let run = filter_bam_pairs(
+        &[
+            "-i",
+            &fifo,
+            "-o",
+            &scratch.path("out.bam"),
+            "--min-bx-reads",
+            "2",
+        ],
+        None,
+    );
+    assert!(!run.status.success());
+    assert!(String::from_utf8_lossy(&run.stderr).contains("cannot be used with a pipe"));
+}
@@ -0,0 +1,1249 @@
+//! Stats JSON written by one run and read back by `merge-stats`, the
+//! `--stats-out` summary, the complexity histogram and GC profile, the
+//! `--notify-webhook` payload, `--trace-qname` traces, per-barcode and
+//! `--stage-timing` tables, and the number format of the printed report
+
+mod common;
+
+use common::{write_input, write_pairs, Scratch};
+use filter_bam_pairs::duplicates::LibraryComplexity;
+use filter_bam_pairs::filter::{FilterConfig, Thresholds};
+use filter_bam_pairs::histogram::{self, ComplexityHistogram};
+use filter_bam_pairs::report::{Report, SCHEMA_VERSION};
+use filter_bam_pairs::stats::InsertSizeBin;
+use filter_bam_pairs::stats::{fragment_gc, GcStats};
+use filter_bam_pairs::summary::{self, RejectionCounts};
+use filter_bam_pairs::test_utils::{mapped_pair, random_sequence, unmapped_pair, RecordBuilder};
+use filter_bam_pairs::units::{NumberFormat, ReportUnits};
+use rust_htslib::bam::{self, Read as _};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::process::Command;
+
+#[test]
+fn stats_json_carries_its_schema_version() {
+    let scratch = Scratch::new("report-schema");
+    let path = scratch.path("run.stats.json");
+    let report = Report {
+        schema_version: SCHEMA_VERSION,
+        total_pairs: 10,
+        kept_pairs: 7,
+        ..Report::default()
+    };
+    report.write_json(&path).unwrap();
+
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(json["schema_version"], SCHEMA_VERSION);
+    let read = Report::read_json(&path).unwrap();
+    assert_eq!((read.schema_version, read.kept_pairs), (SCHEMA_VERSION, 7));
+
+    // Files from before the field existed have the first layout
+    let mut legacy = json.clone();
+    legacy.as_object_mut().unwrap().remove("schema_version");
+    std::fs::write(&path, legacy.to_string()).unwrap();
+    assert_eq!(Report::read_json(&path).unwrap().schema_version, 1);
+
+    let mut newer = json;
+    newer["schema_version"] = (SCHEMA_VERSION + 1).into();
+    std::fs::write(&path, newer.to_string()).unwrap();
+    let error = format!("{:#}", Report::read_json(&path).unwrap_err());
+    assert!(error.contains("not supported"), "{error}");
+}
+
+#[test]
+fn report_units_change_the_summary_but_not_the_stats_files() {
+    let grouped = NumberFormat {
+        units: ReportUnits::Grouped,
+        decimals: 1,
+    };
+    assert_eq!(grouped.count(999), "999");
+    assert_eq!(grouped.count(1_234_567), "1,234,567");
+    assert_eq!(grouped.share(1, 3), "33.3%");
+    let human = NumberFormat {
+        units: ReportUnits::Human,
+        decimals: 2,
+    };
+    assert_eq!(human.count(950), "950");
+    assert_eq!(human.count(1_234_567), "1.23M");
+    assert_eq!(human.count(4_500_000_000), "4.50G");
+    assert_eq!(NumberFormat::default().count(1_234_567), "1234567");
+
+    let scratch = Scratch::new("report-units");
+    let run = scratch.path("run.stats.json");
+    Report {
+        schema_version: SCHEMA_VERSION,
+        total_pairs: 1_234_567,
+        kept_pairs: 1_000_000,
+        ..Report::default()
+    }
+    .write_json(&run)
+    .unwrap();
+    let merged = scratch.path("merged.stats.json");
+    let output = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["merge-stats", &run, "-o", &merged])
+        .args(["--report-units", "grouped", "--report-decimals", "1"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Total pairs: 1,234,567"), "{stdout}");
+    assert!(stdout.contains("81.0%"), "{stdout}");
+
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&merged).unwrap()).unwrap();
+    assert_eq!(json["total_pairs"], 1_234_567);
+}
+
+#[test]
+fn complexity_percentiles_come_from_the_histogram() {
+    let mut histogram = ComplexityHistogram::new(summary::PERCENTILE_BINS);
+    assert_eq!(histogram.percentile(50), None);
+    for i in 0..100 {
+        histogram.record(i as f64 / 100.0);
+    }
+    assert_eq!(histogram.percentile(5), Some(0.04));
+    assert_eq!(histogram.percentile(50), Some(0.49));
+    assert_eq!(histogram.percentile(100), Some(0.99));
+}
+
+#[test]
+fn stats_out_counts_failures_per_filter_in_json_and_tsv() {
+    let scratch = Scratch::new("stats-out");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let out = scratch.path("out.bam");
+    let json_path = scratch.path("summary.json");
+    let run = |stats: &str, format: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+            .args(["-i", &input, "-o", &out, "--min-mapq", "30"])
+            .args(["--stats-out", stats, "--stats-format", format])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    };
+
+    run(&json_path, "json");
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+    assert_eq!(json["unit"], "pairs");
+    assert_eq!(
+        (json["total"].as_u64(), json["kept"].as_u64()),
+        (Some(300), Some(200))
+    );
+    assert_eq!(json["pass_rate"].as_f64().unwrap(), 200.0 / 300.0);
+    assert_eq!(
+        json["failed"],
+        serde_json::json!({"complexity": 100, "min_mapq": 0})
+    );
+    let p05 = json["complexity"]["p05"].as_f64().unwrap();
+    let p50 = json["complexity"]["p50"].as_f64().unwrap();
+    assert!(p05 < 0.1 && p50 > 0.8, "{p05} {p50}");
+    assert!(json["runtime_seconds"].as_f64().unwrap() > 0.0);
+
+    let tsv_path = scratch.path("summary.tsv");
+    run(&tsv_path, "tsv");
+    let tsv = std::fs::read_to_string(&tsv_path).unwrap();
+    let lines: Vec<&str> = tsv.lines().collect();
+    assert_eq!(lines[0], "metric\tvalue");
+    assert!(lines.contains(&"total\t300"));
+    assert!(lines.contains(&"failed.complexity\t100"));
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with("complexity.p50\t")));
+}
+
+#[test]
+fn removed_pairs_are_broken_down_by_the_filters_they_failed() {
+    let mut counts = RejectionCounts::default();
+    let config = FilterConfig {
+        min_mapped: 80,
+        ..FilterConfig::default()
+    };
+    let repetitive = "A".repeat(100);
+    let (record1, record2) = mapped_pair("low", &repetitive, &repetitive);
+    let (record1, record2) = (record1.cigar("60M40S").build(), record2.build());
+    let verdict = config.evaluate(&record1, &record2, &mut 0);
+    counts.record(verdict.failed, &[("name_list", true)]);
+    assert_eq!(
+        (
+            counts.complexity_only,
+            counts.mapped_only,
+            counts.complexity_and_mapped
+        ),
+        (0, 0, 1)
+    );
+    assert_eq!(counts.several, 1);
+    counts.record(Thresholds::default(), &[("name_list", false)]);
+    assert_eq!(counts.only.get("name_list"), Some(&1));
+
+    let scratch = Scratch::new("rejections");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let names = scratch.path("names.txt");
+    // pair0000003 is repetitive as well
+    std::fs::write(&names, "pair0000001\npair0000003\n").unwrap();
+    let (out, stats) = (scratch.path("out.bam"), scratch.path("run.stats.json"));
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &out, "--exclude-names", &names])
+        .args(["--stats-json", &stats])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    assert!(String::from_utf8_lossy(&run.stdout).contains("=== Removed Pairs by Filter ==="));
+    let rejections = Report::read_json(&stats).unwrap().rejections.unwrap();
+    assert_eq!(rejections.complexity_only, 100);
+    assert_eq!(rejections.failed.get("complexity"), Some(&100));
+    assert_eq!(rejections.failed.get("name_list"), Some(&2));
+    assert_eq!(rejections.only.get("complexity"), Some(&99));
+    assert_eq!(rejections.only.get("name_list"), Some(&1));
+    assert_eq!(rejections.several, 1);
+
+    let mut merged = Report::read_json(&stats).unwrap();
+    merged.merge(&Report::read_json(&stats).unwrap());
+    let rejections = merged.rejections.unwrap();
+    assert_eq!(rejections.only.get("complexity"), Some(&198));
+    assert_eq!(rejections.several, 2);
+}
+
+/// Run the binary on `input`, failing the test unless it succeeds
+fn run_on(input: &str, args: &[&str]) -> std::process::Output {
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", input])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    run
+}
+
+#[test]
+fn pass_rates_are_stratified_by_insert_size() {
+    let pair = |i: u64, insert: Option<i64>| {
+        let seq = random_sequence(100, i);
+        let (record1, record2) = mapped_pair(&format!("pair{i:02}"), &seq, &seq);
+        match insert {
+            Some(insert) => (record1.insert_size(insert), record2.insert_size(-insert)),
+            None => (record1, record2),
+        }
+    };
+    let poly_a = "A".repeat(100);
+    let (repetitive1, repetitive2) = mapped_pair("pair99", &poly_a, &poly_a);
+    let seq = random_sequence(100, 50);
+    let (trans1, trans2) = mapped_pair("pair50", &seq, &seq);
+    let (unmapped1, unmapped2) = unmapped_pair("pair51", &seq, &seq);
+    let pairs: Vec<_> = [
+        pair(1, Some(50)),
+        pair(2, Some(99)),
+        (repetitive1.insert_size(60), repetitive2.insert_size(-60)),
+        pair(3, Some(100)),
+        pair(4, Some(299)),
+        // Without a TLEN the 200 bp between the mates count
+        pair(5, Some(0)),
+        // mapped_pair spans 300 bp, the lower edge of its bin
+        pair(6, None),
+        pair(7, Some(1000)),
+        pair(8, Some(1001)),
+        (trans1.mate_pos(1, 1200), trans2.pos(1, 1200)),
+        (unmapped1, unmapped2),
+    ]
+    .into_iter()
+    .map(|(record1, record2)| (record1.build(), record2.build()))
+    .collect();
+    let bins: Vec<&str> = pairs
+        .iter()
+        .map(|(record1, record2)| InsertSizeBin::of_pair(record1, record2).label())
+        .collect();
+    assert_eq!(
+        bins,
+        [
+            "<100", "<100", "<100", "100-300", "100-300", "100-300", "300-1000", "300-1000",
+            ">1000", "trans", "unmapped"
+        ]
+    );
+
+    let scratch = Scratch::new("insert-size");
+    let input = scratch.path("in.bam");
+    write_pairs(&input, &pairs);
+    let run = run_on(&input, &["-o", &scratch.path("out.bam")]);
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    let table: Vec<&str> = stdout
+        .lines()
+        .skip_while(|line| *line != "=== Pass Rate by Insert Size ===")
+        .skip(2)
+        .take(6)
+        .collect();
+    assert_eq!(
+        table,
+        [
+            "<100                  3            2            1     66.67%",
+            "100-300               3            3            0    100.00%",
+            "300-1000              2            2            0    100.00%",
+            ">1000                 1            1            0    100.00%",
+            "trans                 1            1            0    100.00%",
+            "unmapped              1            1            0    100.00%",
+        ]
+    );
+}
+
+#[test]
+fn chimera_rates_count_trans_and_abnormally_oriented_pairs_before_and_after_filtering() {
+    let pair = |i: u64| {
+        let seq = random_sequence(100, i);
+        mapped_pair(&format!("pair{i:02}"), &seq, &seq)
+    };
+    let poly_a = "A".repeat(100);
+    let (repetitive1, repetitive2) = mapped_pair("pair99", &poly_a, &poly_a);
+    let seq = random_sequence(100, 50);
+    let (unmapped1, unmapped2) = unmapped_pair("pair50", &seq, &seq);
+    let trans = |(record1, record2): (RecordBuilder, RecordBuilder)| {
+        (record1.mate_pos(1, 1200), record2.pos(1, 1200))
+    };
+    let (forward1, forward2) = pair(3);
+    let (reverse1, reverse2) = pair(4);
+    let pairs: Vec<_> = [
+        pair(1),
+        pair(2),
+        trans(pair(5)),
+        trans((repetitive1, repetitive2)),
+        // FF: both mates forward
+        (forward1, forward2.flags(0x1 | 0x2 | 0x80)),
+        // RF: the leftmost mate reverse, the other forward
+        (
+            reverse1.flags(0x1 | 0x2 | 0x10 | 0x40),
+            reverse2.flags(0x1 | 0x2 | 0x20 | 0x80),
+        ),
+        (unmapped1, unmapped2),
+    ]
+    .into_iter()
+    .map(|(record1, record2)| (record1.build(), record2.build()))
+    .collect();
+
+    let scratch = Scratch::new("chimeras");
+    let input = scratch.path("in.bam");
+    write_pairs(&input, &pairs);
+    let stats = scratch.path("run.stats.json");
+    let run = run_on(
+        &input,
+        &["-o", &scratch.path("out.bam"), "--stats-json", &stats],
+    );
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    let table: Vec<String> = stdout
+        .lines()
+        .skip_while(|line| !line.starts_with("=== Chimeric Pairs"))
+        .skip(2)
+        .take(3)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect();
+    assert_eq!(
+        table,
+        [
+            "Mapped pairs 6 5",
+            "Inter-chromosomal 2 (33.33%) 1 (20.00%)",
+            "Abnormal orientation 2 (33.33%) 2 (40.00%)",
+        ]
+    );
+
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats).unwrap()).unwrap();
+    assert_eq!(json["chimeras"]["input"]["inter_chromosomal"], 2);
+    assert_eq!(json["chimeras"]["kept"]["abnormal_orientation"], 2);
+}
+
+#[test]
+fn library_size_matches_picard_and_the_curve_follows_the_duplicates() {
+    // Picard's EstimateLibraryComplexity gives these for N pairs, C distinct
+    assert_eq!(LibraryComplexity::estimated_library_size(99, 50), Some(63));
+    assert_eq!(
+        LibraryComplexity::estimated_library_size(1000, 900),
+        Some(4660)
+    );
+    assert_eq!(LibraryComplexity::estimated_library_size(10, 5), Some(6));
+    assert_eq!(LibraryComplexity::estimated_library_size(50, 50), None);
+    assert_eq!(LibraryComplexity::estimated_library_size(0, 0), None);
+
+    // 49 fragments seen twice and one once: N = 99, C = 50
+    let pairs: Vec<_> = (0..99)
+        .map(|i| {
+            let seq = random_sequence(100, i);
+            let (record1, record2) = mapped_pair(&format!("pair{i:02}"), &seq, &seq);
+            let offset = 1000 * (i as i64 / 2);
+            (
+                record1
+                    .pos(0, 1000 + offset)
+                    .mate_pos(0, 1200 + offset)
+                    .build(),
+                record2
+                    .pos(0, 1200 + offset)
+                    .mate_pos(0, 1000 + offset)
+                    .build(),
+            )
+        })
+        .collect();
+    let scratch = Scratch::new("library-complexity");
+    let input = scratch.path("in.bam");
+    write_pairs(&input, &pairs);
+    let run = run_on(
+        &input,
+        &["-o", &scratch.path("out.bam"), "--library-complexity"],
+    );
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    let block: Vec<&str> = stdout
+        .lines()
+        .skip_while(|line| !line.starts_with("=== Library Complexity"))
+        .skip(1)
+        .take(9)
+        .collect();
+    assert_eq!(
+        block,
+        [
+            "Observed fragments: 99",
+            "Distinct fragments: 50",
+            "Duplicate fraction: 49.49%",
+            "Estimated library size: 63",
+            "   Depth        Fragments    Expected distinct",
+            "   0.25x               25                   22",
+            "   0.50x               50                   37",
+            "   0.75x               74                   47",
+            "   1.00x               99                   50",
+        ]
+    );
+}
+
+#[test]
+fn sampled_duplicate_rate_keeps_every_copy_of_a_sampled_fragment() {
+    // 200 fragments seen twice, the second copy repetitive and removed
+    let poly_a = "A".repeat(100);
+    let pairs: Vec<_> = (0..400)
+        .map(|i| {
+            let seq = if i % 2 == 0 {
+                random_sequence(100, i)
+            } else {
+                poly_a.clone()
+            };
+            let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &seq, &seq);
+            let offset = 1000 * (i as i64 / 2);
+            (
+                record1
+                    .pos(0, 1000 + offset)
+                    .mate_pos(0, 1200 + offset)
+                    .build(),
+                record2
+                    .pos(0, 1200 + offset)
+                    .mate_pos(0, 1000 + offset)
+                    .build(),
+            )
+        })
+        .collect();
+    let scratch = Scratch::new("duplicate-rate");
+    let input = scratch.path("in.bam");
+    write_pairs(&input, &pairs);
+    let estimate = |rate: &str| {
+        let run = run_on(
+            &input,
+            &[
+                "-o",
+                &scratch.path("out.bam"),
+                "--estimate-duplicates",
+                "--dup-sample-rate",
+                rate,
+            ],
+        );
+        String::from_utf8_lossy(&run.stdout)
+            .lines()
+            .skip_while(|line| !line.starts_with("=== Estimated Duplicate Rate"))
+            .take(4)
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    // Every position sampled: the exact rates
+    assert_eq!(
+        estimate("1"),
+        [
+            "=== Estimated Duplicate Rate (sampled 1/1 positions) ===",
+            "Sampled fragments: 400 input, 200 kept",
+            "Input duplicate rate (estimate): 50.00%",
+            "Kept duplicate rate (estimate): 0.00%",
+        ]
+    );
+    // A sampled position brings both its copies, so the rates stay exact
+    let sampled = estimate("8");
+    assert_eq!(
+        sampled[0],
+        "=== Estimated Duplicate Rate (sampled 1/8 positions) ==="
+    );
+    let counts: Vec<u64> = sampled[1]
+        .trim_start_matches("Sampled fragments: ")
+        .split(", ")
+        .map(|part| part.split(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert!(counts[0] > 0 && counts[0] < 400, "{:?}", counts);
+    assert_eq!(counts[0], 2 * counts[1]);
+    assert_eq!(sampled[2], "Input duplicate rate (estimate): 50.00%");
+    assert_eq!(sampled[3], "Kept duplicate rate (estimate): 0.00%");
+}
+
+#[test]
+fn stats_sn_writes_samtools_summary_numbers_and_the_pair_counts() {
+    let pair = |name: &str, seq: &str, nm: [i32; 2]| {
+        let (record1, record2) = mapped_pair(name, seq, seq);
+        (record1.tag_int(b"NM", nm[0]), record2.tag_int(b"NM", nm[1]))
+    };
+    let poly_a = "A".repeat(100);
+    let (mq0_1, mq0_2) = pair("pair2", &random_sequence(100, 2), [0, 0]);
+    let (trans1, trans2) = pair("pair4", &random_sequence(100, 4), [0, 0]);
+    let seq = random_sequence(100, 5);
+    let pairs: Vec<_> = [
+        pair("pair1", &random_sequence(100, 1), [1, 0]),
+        (mq0_1.mapq(0), mq0_2.mapq(0)),
+        pair("pair3", &poly_a, [0, 0]),
+        (trans1.mate_pos(1, 1200), trans2.pos(1, 1200)),
+        unmapped_pair("pair5", &seq, &seq),
+    ]
+    .into_iter()
+    .map(|(record1, record2)| (record1.build(), record2.build()))
+    .collect();
+
+    let scratch = Scratch::new("stats-sn");
+    let input = scratch.path("in.bam");
+    write_pairs(&input, &pairs);
+    let sn = scratch.path("run.sn.txt");
+    run_on(&input, &["-o", &scratch.path("out.bam"), "--stats-sn", &sn]);
+    assert_eq!(
+        std::fs::read_to_string(&sn).unwrap(),
+        "# Summary Numbers. Use `grep ^SN | cut -f 2-` to extract this part.\n\
+         SN\traw total sequences:\t10\n\
+         SN\t1st fragments:\t5\n\
+         SN\tlast fragments:\t5\n\
+         SN\treads mapped:\t8\n\
+         SN\treads mapped and paired:\t8\n\
+         SN\treads unmapped:\t2\n\
+         SN\treads properly paired:\t8\n\
+         SN\treads paired:\t10\n\
+         SN\treads duplicated:\t0\n\
+         SN\treads MQ0:\t2\n\
+         SN\treads QC failed:\t0\n\
+         SN\ttotal length:\t1000\n\
+         SN\tbases mapped (cigar):\t800\n\
+         SN\tmismatches:\t1\n\
+         SN\terror rate:\t1.250000e-3\n\
+         SN\taverage length:\t100\n\
+         SN\taverage quality:\t30.0\n\
+         SN\tpairs on different chromosomes:\t1\n\
+         SN\tfilter_bam_pairs total pairs:\t5\n\
+         SN\tfilter_bam_pairs kept pairs:\t4\n\
+         SN\tfilter_bam_pairs removed pairs:\t1\n\
+         SN\tfilter_bam_pairs pass rate:\t0.800000\n\
+         SN\tfilter_bam_pairs reads without quality:\t0\n\
+         SN\tfilter_bam_pairs interrupted:\t0\n"
+    );
+
+    // Without NM on every mapped read there is no error rate to give
+    let without_nm: Vec<_> = pairs
+        .iter()
+        .map(|(record1, record2)| {
+            let (mut record1, record2) = (record1.clone(), record2.clone());
+            let _ = record1.remove_aux(b"NM");
+            (record1, record2)
+        })
+        .collect();
+    write_pairs(&input, &without_nm);
+    run_on(&input, &["-o", &scratch.path("out.bam"), "--stats-sn", &sn]);
+    let lines = std::fs::read_to_string(&sn).unwrap();
+    assert!(!lines.contains("SN\tmismatches:"));
+    assert!(!lines.contains("SN\terror rate:"));
+    assert!(lines.contains("SN\tbases mapped (cigar):\t800\n"));
+}
+
+#[test]
+fn barcodes_with_too_few_reads_are_removed_and_counted_per_barcode() {
+    let poly_a = "A".repeat(100);
+    let pairs: Vec<_> = [
+        (Some("AAA-1"), random_sequence(100, 1)),
+        (Some("AAA-1"), random_sequence(100, 2)),
+        (Some("AAA-1"), poly_a),
+        (Some("AAA-1"), random_sequence(100, 3)),
+        (Some("CCC-1"), random_sequence(100, 4)),
+        (None, random_sequence(100, 5)),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, (bx, seq))| {
+        let (record1, record2) = mapped_pair(&format!("pair{i}"), &seq, &seq);
+        match bx {
+            Some(bx) => (
+                record1.tag_str(b"BX", bx).build(),
+                record2.tag_str(b"BX", bx).build(),
+            ),
+            None => (record1.build(), record2.build()),
+        }
+    })
+    .collect();
+
+    let scratch = Scratch::new("barcodes");
+    let input = scratch.path("in.bam");
+    write_pairs(&input, &pairs);
+    let (out, bx_stats, stats_out) = (
+        scratch.path("out.bam"),
+        scratch.path("bx.tsv"),
+        scratch.path("run.stats.tsv"),
+    );
+    // CCC-1 has 2 reads, AAA-1 has 8
+    let run = run_on(
+        &input,
+        &[
+            "-o",
+            &out,
+            "--min-bx-reads",
+            "4",
+            "--bx-stats",
+            &bx_stats,
+            "--stats-out",
+            &stats_out,
+            "--stats-format",
+            "tsv",
+        ],
+    );
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert!(stdout.contains("Filtered pairs: 4\n"), "{stdout}");
+    let block: Vec<&str> = stdout
+        .lines()
+        .skip_while(|line| *line != "=== Barcodes (BX) ===")
+        .skip(1)
+        .take(4)
+        .collect();
+    assert_eq!(
+        block,
+        [
+            "Barcodes seen: 2",
+            "Barcodes with no kept pairs: 1",
+            "Mean per-barcode pass rate: 37.50%",
+            "Pairs without BX tag: 1",
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(&bx_stats).unwrap(),
+        "barcode\tpairs\tkept\tpass_rate\n\
+         AAA-1\t4\t3\t0.7500\n\
+         CCC-1\t1\t0\t0.0000\n"
+    );
+    assert!(std::fs::read_to_string(&stats_out)
+        .unwrap()
+        .lines()
+        .any(|line| line == "failed.min_bx_reads\t1"));
+
+    let kept: Vec<String> = bam::Reader::from_path(&out)
+        .unwrap()
+        .records()
+        .map(|record| String::from_utf8_lossy(record.unwrap().qname()).into_owned())
+        .collect();
+    assert_eq!(
+        kept,
+        ["pair0", "pair0", "pair1", "pair1", "pair3", "pair3", "pair5", "pair5"]
+    );
+}
+
+#[test]
+fn stage_timing_prints_and_stores_every_stage_and_merges_across_runs() {
+    let scratch = Scratch::new("stage-timing");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let (out, stats) = (scratch.path("out.bam"), scratch.path("run.stats.json"));
+    let stages = ["prepass", "read", "metrics", "write", "finish"];
+
+    let untimed = run_on(&input, &["-o", &out, "--stats-json", &stats]);
+    assert!(!String::from_utf8_lossy(&untimed.stdout).contains("=== Stage Timing ==="));
+    assert!(Report::read_json(&stats).unwrap().stages.is_none());
+
+    let timed = run_on(
+        &input,
+        &["-o", &out, "--stats-json", &stats, "--stage-timing"],
+    );
+    let stdout = String::from_utf8_lossy(&timed.stdout);
+    let table: Vec<&str> = stdout
+        .lines()
+        .skip_while(|line| *line != "=== Stage Timing ===")
+        .skip(1)
+        .take(6)
+        .collect();
+    assert_eq!(
+        table[0].split_whitespace().collect::<Vec<_>>(),
+        ["Stage", "Wall", "(s)", "CPU", "(s)", "CPU", "%", "Peak", "RSS", "(MB)"]
+    );
+    let rows: Vec<&str> = table[1..]
+        .iter()
+        .map(|row| row.split_whitespace().next().unwrap())
+        .collect();
+    assert_eq!(rows, stages);
+
+    let report = Report::read_json(&stats).unwrap();
+    let times = report.stages.clone().unwrap();
+    assert_eq!(
+        times.iter().map(|t| t.stage.as_str()).collect::<Vec<_>>(),
+        stages
+    );
+    for time in &times {
+        assert!(time.wall_seconds >= 0.0 && time.cpu_seconds >= 0.0);
+        if cfg!(unix) {
+            assert!(time.peak_rss_kb > 0, "{time:?}");
+        }
+    }
+    let mut merged = report;
+    merged.merge(&Report::read_json(&stats).unwrap());
+    for (merged, time) in merged.stages.unwrap().iter().zip(&times) {
+        assert_eq!(merged.stage, time.stage);
+        assert_eq!(merged.wall_seconds, 2.0 * time.wall_seconds);
+        assert_eq!(merged.cpu_seconds, 2.0 * time.cpu_seconds);
+        assert_eq!(merged.peak_rss_kb, time.peak_rss_kb);
+    }
+}
+
+#[test]
+fn complexity_histogram_counts_reads_in_bins_of_a_hundredth() {
+    let mut histogram = ComplexityHistogram::new(histogram::READ_BINS);
+    for complexity in [0.29, 0.295, 0.3, 1.0] {
+        histogram.record(complexity);
+    }
+    assert_eq!((histogram.count(29), histogram.count(30)), (2, 1));
+    assert_eq!((histogram.count(100), histogram.total()), (1, 4));
+    // The percentiles bin the same values alike, only finer
+    let mut pairs = ComplexityHistogram::new(summary::PERCENTILE_BINS);
+    for complexity in [0.29, 0.295, 0.3, 1.0] {
+        pairs.record(complexity);
+    }
+    assert_eq!((pairs.count(290), pairs.count(295)), (1, 1));
+    assert_eq!(pairs.percentile(50), Some(0.295));
+
+    let scratch = Scratch::new("complexity-histogram");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let path = scratch.path("complexity.tsv");
+    let output = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &scratch.path("out.bam")])
+        .args(["--complexity-histogram", &path])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let tsv = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = tsv.lines().collect();
+    assert_eq!(lines.len(), 102);
+    assert_eq!(lines[0], "complexity\treads\tfraction_below");
+    // Both mates of the 100 poly-A pairs have 1 distinct kmer in 80
+    assert_eq!(lines[2], "0.01\t200\t0.000000");
+    assert_eq!(lines[81], "0.80\t0\t0.333333");
+    assert_eq!(lines[101], "1.00\t400\t0.333333");
+}
+
+#[test]
+fn family_sizes_are_reported_and_small_families_removed() {
+    let scratch = Scratch::new("families");
+    let input = scratch.path("in.bam");
+    let mut writer = rust_htslib::bam::Writer::from_path(
+        &input,
+        &common::reference_header(),
+        rust_htslib::bam::Format::Bam,
+    )
+    .unwrap();
+    // A singleton, a duplex family of one pair per strand, and a family of
+    // three whose last pair is repetitive; the last pair has no MI tag
+    let molecules = [
+        Some("0"),
+        Some("1/A"),
+        Some("1/B"),
+        Some("2"),
+        Some("2"),
+        Some("2"),
+        None,
+    ];
+    for (i, mi) in molecules.into_iter().enumerate() {
+        let seq = if i == 5 {
+            "A".repeat(100)
+        } else {
+            random_sequence(100, i as u64)
+        };
+        let (mut record1, mut record2) = mapped_pair(&format!("pair{i}"), &seq, &seq);
+        if let Some(mi) = mi {
+            record1 = record1.tag_str(b"MI", mi);
+            record2 = record2.tag_str(b"MI", mi);
+        }
+        writer.write(&record1.build()).unwrap();
+        writer.write(&record2.build()).unwrap();
+    }
+    drop(writer);
+
+    let histogram = scratch.path("families.tsv");
+    let run = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &scratch.path("out.bam")])
+        .args([
+            "--min-family-size",
+            "2",
+            "--family-size-histogram",
+            &histogram,
+        ])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert!(stdout.contains("=== Molecular Families (MI) ==="));
+    assert!(stdout.contains("Pairs removed by --min-family-size: 1\n"));
+    assert!(stdout.contains("Pairs without MI tag: 1\n"));
+    assert!(stdout.contains("Filtered pairs: 5\n"));
+
+    let tsv = std::fs::read_to_string(&histogram).unwrap();
+    assert_eq!(
+        tsv,
+        "family_size\tfamilies\tkept_families\n1\t1\t0\n2\t1\t2\n3\t1\t0\n"
+    );
+}
+
+#[test]
+fn gc_profile_compares_kept_and_removed_fragments() {
+    let (record1, record2) = mapped_pair("gc", "GGCCNNAT", "ATAT");
+    let (record1, record2) = (record1.build(), record2.build());
+    // 4 of the 10 called bases of the fragment; Ns don't count
+    assert_eq!(fragment_gc(&[&record1, &record2]), Some(0.4));
+    assert_eq!(fragment_gc(&[&record2]), Some(0.0));
+    let (unknown, _) = mapped_pair("n", "NNNN", "NNNN");
+    assert_eq!(fragment_gc(&[&unknown.build()]), None);
+
+    let mut stats = GcStats::default();
+    stats.record(&[&record1, &record2], true);
+    stats.record(&[&record2], false);
+    // Kept at 40%, the input at 20% on average
+    assert_eq!(stats.mean_shift(), Some(20.0));
+
+    let scratch = Scratch::new("gc-profile");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let path = scratch.path("gc.tsv");
+    let output = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "--dry-run", "--gc-profile", &path])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("=== GC Content of Fragments ==="));
+    assert!(
+        stdout.contains("Filtering moves the mean GC by +"),
+        "{stdout}"
+    );
+    let tsv = std::fs::read_to_string(&path).unwrap();
+    let rows: Vec<Vec<u64>> = tsv
+        .lines()
+        .skip(1)
+        .map(|line| {
+            line.split('\t')
+                .map(|field| field.parse().unwrap())
+                .collect()
+        })
+        .collect();
+    assert_eq!(rows.len(), 101);
+    // The poly-A pairs are all removed, at 0% GC
+    assert_eq!(rows[0], [0, 0, 100]);
+    assert_eq!(rows.iter().map(|row| row[1]).sum::<u64>(), 200);
+}
+
+/// Answer one request with `status`, returning its body
+fn receive_one_post(
+    listener: TcpListener,
+    status: &'static str,
+) -> std::thread::JoinHandle<String> {
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        write!(
+            reader.get_mut(),
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        )
+        .unwrap();
+        String::from_utf8(body).unwrap()
+    })
+}
+
+#[test]
+fn notify_webhook_posts_the_statistics_and_never_fails_the_run() {
+    let scratch = Scratch::new("notify-webhook");
+    let input = scratch.path("in.bam");
+    write_input(&input, 300);
+    let run = |url: &str| {
+        Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+            .args(["-i", &input, "--dry-run", "--notify-webhook", url])
+            .args(["--notify-timeout", "5"])
+            .output()
+            .unwrap()
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let server = receive_one_post(listener, "200 OK");
+    let output = run(&url);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let payload: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+    assert_eq!(payload["status"], "completed");
+    assert_eq!(payload["input"], input.as_str());
+    assert_eq!(payload["stats"]["total_pairs"], 300);
+    assert_eq!(payload["stats"]["kept_pairs"], 200);
+    assert!(payload["text"]
+        .as_str()
+        .unwrap()
+        .contains("kept 200 of 300 pairs (66.67%)"));
+
+    // An error status and an unreachable webhook only warn
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let server = receive_one_post(listener, "500 Internal Server Error");
+    let output = run(&url);
+    server.join().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Warning: --notify-webhook: the webhook answered HTTP 500"));
+
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", closed.local_addr().unwrap());
+    drop(closed);
+    let output = run(&url);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Warning: --notify-webhook:"));
+
+    // A run that fails posts its error, and still fails
+    let broken = scratch.path("broken.bam");
+    std::fs::write(&broken, "not a BAM file\n").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let server = receive_one_post(listener, "200 OK");
+    let output = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &broken, "--dry-run", "--notify-webhook", &url])
+        .args(["--notify-timeout", "5"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let payload: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+    assert_eq!(payload["status"], "failed");
+    assert_eq!(payload["input"], broken.as_str());
+    assert!(payload.get("stats").is_none());
+    let error = payload["error"].as_str().unwrap();
+    assert!(
+        error.starts_with(&format!("Cannot open {}: ", broken)),
+        "{}",
+        error
+    );
+    assert!(payload["text"]
+        .as_str()
+        .unwrap()
+        .ends_with(&format!("failed: {}", error)));
+}
+
+#[test]
+fn trace_qname_shows_each_filter_value_and_the_verdict() {
+    let scratch = Scratch::new("trace-qname");
+    let input = scratch.path("in.bam");
+    write_input(&input, 30);
+    let output = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "--dry-run", "-m", "90"])
+        .args([
+            "--trace-qname",
+            "pair0000000/1",
+            "--trace-qname",
+            "pair0000001",
+        ])
+        .args(["--trace-qname", "missing"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // Pair 0 is poly-A: 80 kmers, one of them unique
+    let removed = stderr
+        .split("Trace ")
+        .find(|trace| trace.starts_with("pair0000000 (pair): removed"))
+        .unwrap_or_else(|| panic!("{stderr}"));
+    assert!(removed.contains("r1 0.0125; r2 0.0125"), "{removed}");
+    assert!(removed.contains("Verdict: removed by complexity\n"));
+    let kept = stderr
+        .split("Trace ")
+        .find(|trace| trace.starts_with("pair0000001 (pair): kept"))
+        .unwrap_or_else(|| panic!("{stderr}"));
+    let mapped = kept
+        .lines()
+        .find(|line| line.contains("min_mapped"))
+        .unwrap();
+    assert!(
+        mapped.contains("r1 100 bp; r2 100 bp") && mapped.contains(">= 90 bp"),
+        "{mapped}"
+    );
+    assert!(mapped.ends_with("pass"));
+    assert!(kept.contains("Verdict: kept"));
+    assert_eq!(stderr.matches("Trace ").count(), 2);
+    assert!(stderr.contains("Warning: --trace-qname missing: no pair of the input has this name"));
+}
+
+#[test]
+fn targets_count_each_pair_once_per_interval_either_mate_overlaps() {
+    let scratch = Scratch::new("targets");
+    let input = scratch.path("in.bam");
+    let mut writer = rust_htslib::bam::Writer::from_path(
+        &input,
+        &common::reference_header(),
+        rust_htslib::bam::Format::Bam,
+    )
+    .unwrap();
+    // 10 pairs at chr1:1000 and 1200, half of them repetitive, then 5 far off
+    for i in 0..15 {
+        let seq = if i % 2 == 0 && i < 10 {
+            "A".repeat(100)
+        } else {
+            random_sequence(100, i)
+        };
+        let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &seq, &seq);
+        let (record1, record2) = if i < 10 {
+            (record1, record2)
+        } else {
+            (
+                record1.pos(0, 50_000).mate_pos(0, 50_200),
+                record2.pos(0, 50_200).mate_pos(0, 50_000),
+            )
+        };
+        writer.write(&record1.build()).unwrap();
+        writer.write(&record2.build()).unwrap();
+    }
+    drop(writer);
+    // The first mates, the second mates, both, and neither
+    let bed = scratch.path("targets.bed");
+    std::fs::write(
+        &bed,
+        "chr1\t900\t1100\tfirst\nchr1\t1250\t1260\tsecond\nchr1\t1050\t1250\tboth\nchr2\t0\t100\n",
+    )
+    .unwrap();
+
+    let tsv = scratch.path("targets.tsv");
+    let output = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &scratch.path("out.bam")])
+        .args(["--targets", &bed, "--target-stats", &tsv])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Off-target pairs: 5\n"), "{}", stdout);
+    assert!(stdout.contains("Targets without pairs: 1\n"), "{}", stdout);
+
+    let rows: Vec<Vec<String>> = std::fs::read_to_string(&tsv)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split('\t').map(str::to_string).collect())
+        .collect();
+    let counts: Vec<(&str, &str, &str, &str)> = rows
+        .iter()
+        .map(|row| {
+            (
+                row[0].as_str(),
+                row[4].as_str(),
+                row[5].as_str(),
+                row[7].as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        counts,
+        [
+            ("first", "10", "5", "0.5000"),
+            ("second", "10", "5", "0.5000"),
+            ("both", "10", "5", "0.5000"),
+            ("chr2:0-100", "0", "0", "0.0000"),
+        ]
+    );
+}
+
+#[test]
+fn sample_stats_sum_read_groups_by_their_header_sample() {
+    let scratch = Scratch::new("sample-stats");
+    let input = scratch.path("in.bam");
+    let mut header = common::reference_header();
+    for (id, sample) in [
+        ("A", Some("s1")),
+        ("B", Some("s1")),
+        ("C", Some("s2")),
+        ("D", None),
+    ] {
+        let mut read_group = rust_htslib::bam::header::HeaderRecord::new(b"RG");
+        read_group.push_tag(b"ID", id);
+        if let Some(sample) = sample {
+            read_group.push_tag(b"SM", sample);
+        }
+        header.push_record(&read_group);
+    }
+    let mut writer =
+        rust_htslib::bam::Writer::from_path(&input, &header, rust_htslib::bam::Format::Bam)
+            .unwrap();
+    // (read group, repetitive): A keeps 2 of 4, B 2 of 2, C 1 of 4, no RG 2 of 2
+    let pairs = [
+        (Some("A"), true),
+        (Some("A"), true),
+        (Some("A"), false),
+        (Some("A"), false),
+        (Some("B"), false),
+        (Some("B"), false),
+        (Some("C"), true),
+        (Some("C"), true),
+        (Some("C"), true),
+        (Some("C"), false),
+        (None, false),
+        (None, false),
+    ];
+    for (i, &(read_group, repetitive)) in pairs.iter().enumerate() {
+        let seq = if repetitive {
+            "A".repeat(100)
+        } else {
+            random_sequence(100, i as u64)
+        };
+        let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &seq, &seq);
+        for record in [record1, record2] {
+            let record = match read_group {
+                Some(read_group) => record.tag_str(b"RG", read_group),
+                None => record,
+            };
+            writer.write(&record.build()).unwrap();
+        }
+    }
+    drop(writer);
+
+    let tsv = scratch.path("samples.tsv");
+    let output = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &scratch.path("out.bam")])
+        .args(["--sample-stats", &tsv])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("3 samples in 5 read groups"), "{}", stdout);
+
+    assert_eq!(
+        std::fs::read_to_string(&tsv).unwrap(),
+        "level\tid\tsample\tpairs\tkept\tpass_rate\n\
+         sample\ts1\ts1\t6\t4\t0.6667\n\
+         sample\ts2\ts2\t4\t1\t0.2500\n\
+         sample\tunknown\tunknown\t2\t2\t1.0000\n\
+         read_group\tA\ts1\t4\t2\t0.5000\n\
+         read_group\tB\ts1\t2\t2\t1.0000\n\
+         read_group\tC\ts2\t4\t1\t0.2500\n\
+         read_group\tD\tunknown\t0\t0\t0.0000\n\
+         read_group\tnone\tunknown\t2\t2\t1.0000\n"
+    );
+}
+
+#[test]
+fn rejection_bedgraph_counts_the_mapped_mates_of_rejected_pairs_per_bin() {
+    let scratch = Scratch::new("rejection-bedgraph");
+    let input = scratch.path("in.bam");
+    let mut writer = rust_htslib::bam::Writer::from_path(
+        &input,
+        &common::reference_header(),
+        rust_htslib::bam::Format::Bam,
+    )
+    .unwrap();
+    // (tid, start of each mate, repetitive), 3 kb bins
+    let pairs = [
+        (0, [1000, 1200], true),
+        (0, [1000, 1200], true),
+        (0, [1000, 1200], false),
+        (0, [2900, 3100], true),
+        (0, [7000, 7200], false),
+        (1, [99_500, 99_700], true),
+    ];
+    for (i, &(tid, [pos1, pos2], repetitive)) in pairs.iter().enumerate() {
+        let seq = if repetitive {
+            "A".repeat(100)
+        } else {
+            random_sequence(100, i as u64)
+        };
+        let (record1, record2) = mapped_pair(&format!("pair{i:03}"), &seq, &seq);
+        let record1 = record1.pos(tid, pos1).mate_pos(tid, pos2);
+        let record2 = record2.pos(tid, pos2).mate_pos(tid, pos1);
+        writer.write(&record1.build()).unwrap();
+        writer.write(&record2.build()).unwrap();
+    }
+    drop(writer);
+
+    let bedgraph = scratch.path("rejected.bedgraph");
+    let output = Command::new(env!("CARGO_BIN_EXE_filter_bam_pairs"))
+        .args(["-i", &input, "-o", &scratch.path("out.bam")])
+        .args([
+            "--rejection-bedgraph",
+            &bedgraph,
+            "--rejection-bin-size",
+            "3000",
+        ])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Mates of one pair count in the bins of their own starts, kept pairs not
+    // at all, and the last bin of chr2 ends with the reference
+    let contents = std::fs::read_to_string(&bedgraph).unwrap();
+    let bins: Vec<&str> = contents.lines().skip(1).collect();
+    assert_eq!(
+        bins,
+        [
+            "chr1\t0\t3000\t5",
+            "chr1\t3000\t6000\t1",
+            "chr2\t99000\t100000\t2",
+        ]
+    );
+}